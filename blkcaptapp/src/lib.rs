@@ -1,42 +1,97 @@
 pub mod slogext;
 use anyhow::Result;
-use libblkcapt::{error_cause, model::BcLogLevel};
+use libblkcapt::{error::error_code, error_cause, model::BcLogLevel};
+use once_cell::sync::Lazy;
 use slog::{debug, error, o, trace, Drain, Level, Logger};
-use slogext::{DedupDrain, SlogLogLogger};
-use std::{future::Future, sync::Arc, time::Duration};
+use slogext::{
+    DedupDrain, LevelOverrides, RecentRecords, RepeatCounters, RuntimeLevelFilter, SlogLogLogger, REPEAT_IDLE_FLUSH,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::runtime::Runtime;
 
-pub fn blkcaptapp_run<M, F>(main: M, log_level: BcLogLevel, slog_drain: slog_atomic::AtomicSwitch<()>) -> i32
-where
-    M: FnOnce(Logger) -> F,
-    F: Future<Output = Result<()>>,
-{
-    let (internal_level, external_level_slog, external_level) = match log_level {
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static INTERNAL_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static EXTERNAL_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static LEVEL_OVERRIDES: Lazy<LevelOverrides> = Lazy::new(Default::default);
+static REPEAT_COUNTERS: RepeatCounters = RepeatCounters::new();
+static RECENT_RECORDS: Lazy<RecentRecords> = Lazy::new(|| RecentRecords::new(200));
+
+fn filter_levels(log_level: BcLogLevel) -> (Level, Level, log::LevelFilter) {
+    match log_level {
         BcLogLevel::Info => (Level::Info, Level::Info, log::LevelFilter::Info),
         BcLogLevel::Debug => (Level::Debug, Level::Info, log::LevelFilter::Info),
         BcLogLevel::Trace => (Level::Trace, Level::Info, log::LevelFilter::Info),
         BcLogLevel::TraceXdebug => (Level::Trace, Level::Debug, log::LevelFilter::Debug),
         BcLogLevel::TraceXtrace => (Level::Trace, Level::Trace, log::LevelFilter::Trace),
-    };
+    }
+}
+
+/// Changes the log level of the running process.
+pub fn set_log_level(log_level: BcLogLevel) {
+    let (internal_level, external_level_slog, external_level) = filter_levels(log_level);
+    INTERNAL_LEVEL.store(internal_level.as_usize(), Ordering::Relaxed);
+    EXTERNAL_LEVEL.store(external_level_slog.as_usize(), Ordering::Relaxed);
+    log::set_max_level(external_level);
+    LOG_LEVEL.store(log_level as usize, Ordering::Relaxed);
+}
+
+pub fn log_level() -> BcLogLevel {
+    LOG_LEVEL.load(Ordering::Relaxed).into()
+}
+
+/// Sets log levels for loggers with a matching `actor` or entity id value, e.g. `restic_container` or a sync id.
+pub fn set_log_level_overrides(overrides: &HashMap<String, BcLogLevel>) {
+    *LEVEL_OVERRIDES.write().expect("level overrides lock is never poisoned") = overrides
+        .iter()
+        .map(|(key, level)| (key.clone(), filter_levels(*level).0))
+        .collect();
+}
+
+/// Counts of repeated log records that were suppressed and summarized.
+pub fn log_repeat_counters() -> &'static RepeatCounters {
+    &REPEAT_COUNTERS
+}
+
+/// The most recently logged records, oldest first.
+pub fn recent_log_records() -> Vec<String> {
+    RECENT_RECORDS.records()
+}
+
+pub fn blkcaptapp_run<M, F>(main: M, log_level: BcLogLevel, slog_drain: slog_atomic::AtomicSwitch<()>) -> i32
+where
+    M: FnOnce(Logger) -> F,
+    F: Future<Output = Result<()>>,
+{
+    let (_, _, external_level) = filter_levels(log_level);
+    set_log_level(log_level);
 
     println!();
 
-    let mut app_succeeded = true;
+    let mut exit_status = 0;
 
     {
         let slog_drain_ctrl = slog_drain.ctrl();
         let slog_drain = slog_drain.map(Arc::new);
 
         {
+            let dedup_drain = Arc::new(DedupDrain::new(Arc::clone(&slog_drain)).with_counters(&REPEAT_COUNTERS));
             let slog_internal_logger = {
-                let drain = DedupDrain::new(Arc::clone(&slog_drain));
-                let drain = drain.filter_level(internal_level).fuse();
+                let drain = slog::Duplicate::new(Arc::clone(&dedup_drain), &*RECENT_RECORDS).fuse();
+                let drain = RuntimeLevelFilter::new(drain, &INTERNAL_LEVEL)
+                    .with_overrides(&LEVEL_OVERRIDES)
+                    .fuse();
                 Logger::root(drain, o!())
             };
 
             let slog_external_logger = {
                 let drain = Arc::clone(&slog_drain);
-                let drain = drain.filter_level(external_level_slog).fuse();
+                let drain = RuntimeLevelFilter::new(drain, &EXTERNAL_LEVEL).fuse();
                 Logger::root(drain, o!())
             };
 
@@ -49,15 +104,26 @@ where
 
             {
                 let runtime = Runtime::new().expect("can create runtime");
+                let flush_drain = Arc::clone(&dedup_drain);
+                runtime.spawn(async move {
+                    let mut interval = tokio::time::interval(REPEAT_IDLE_FLUSH);
+                    loop {
+                        interval.tick().await;
+                        let _ = flush_drain.flush_idle(REPEAT_IDLE_FLUSH);
+                    }
+                });
                 let result = runtime.block_on(main(slog_internal_logger.clone()));
                 if let Err(e) = result {
-                    error!(slog_internal_logger, "{}", e);
+                    let code = error_code(&e);
+                    error!(slog_internal_logger, "{}", e; "code" => %code);
                     error!(slog_internal_logger, "{}", error_cause(&e));
-                    app_succeeded = false;
+                    exit_status = code.exit_status();
                 }
                 runtime.shutdown_timeout(Duration::from_secs(0));
             }
 
+            let _ = dedup_drain.flush_idle(Duration::from_secs(0));
+
             debug!(slog_internal_logger, "process stopping");
 
             slog_scope::set_global_logger(Logger::root(slog::Discard, o!())).cancel_reset();
@@ -68,11 +134,7 @@ where
 
     println!();
 
-    if app_succeeded {
-        0
-    } else {
-        1
-    }
+    exit_status
 }
 
 #[cfg(test)]
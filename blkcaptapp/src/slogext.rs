@@ -1,6 +1,14 @@
 use slog::{b, Drain, Level, Logger, OwnedKVList, Record, KV};
 use slog_term::{timestamp_local, CountingWriter, Decorator, RecordDecorator, Serializer};
-use std::{fmt, io, io::Write, result};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    io::Write,
+    result,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 pub struct SyncDrain<D> {
     inner: std::sync::Arc<std::sync::Mutex<D>>,
@@ -229,27 +237,355 @@ impl<T: slog::KV> slog::KV for DedupKV<T> {
     }
 }
 
-/// slog drain that uses DedupKV to remove duplicate keys from KV lists
+/// Counts of repeated records suppressed by DedupDrain and the summaries logged in their place.
+#[derive(Default)]
+pub struct RepeatCounters {
+    suppressed: AtomicUsize,
+    summaries: AtomicUsize,
+}
+
+impl RepeatCounters {
+    pub const fn new() -> Self {
+        Self {
+            suppressed: AtomicUsize::new(0),
+            summaries: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn suppressed(&self) -> usize {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    pub fn summaries(&self) -> usize {
+        self.summaries.load(Ordering::Relaxed)
+    }
+}
+
+/// A run of identical records, of which all but the first were suppressed.
+struct RepeatRun {
+    key: String,
+    level: Level,
+    values: OwnedKVList,
+    first: Instant,
+    last: Instant,
+    repeats: usize,
+}
+
+/// Records repeated for longer than this are summarized even though the run has not ended.
+const REPEAT_SUMMARY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Runs without a new record for this long are summarized by `DedupDrain::flush_idle`.
+pub const REPEAT_IDLE_FLUSH: Duration = Duration::from_secs(30);
+
+static REPEAT_SUMMARY_LOCATION: slog::RecordLocation = slog::RecordLocation {
+    file: file!(),
+    line: line!(),
+    column: column!(),
+    function: "",
+    module: module_path!(),
+};
+
+/// slog drain that uses DedupKV to remove duplicate keys from KV lists. Consecutive identical records are
+/// suppressed and summarized with a "previous message repeated" record when the run ends. A run followed by silence
+/// is only summarized when `flush_idle` is called, so callers should call it periodically.
 pub struct DedupDrain<D> {
     inner: D,
+    run: Mutex<Option<RepeatRun>>,
+    counters: Option<&'static RepeatCounters>,
 }
 
 impl<D> DedupDrain<D> {
     pub fn new(inner: D) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            run: Mutex::new(None),
+            counters: None,
+        }
+    }
+
+    pub fn with_counters(mut self, counters: &'static RepeatCounters) -> Self {
+        self.counters = Some(counters);
+        self
     }
 }
 
-impl<D: Drain> Drain for DedupDrain<D> {
-    type Ok = D::Ok;
+impl<D: Drain<Ok = ()>> DedupDrain<D> {
+    fn log_summary(&self, run: &mut RepeatRun) -> Result<(), D::Err> {
+        let record_static = slog::RecordStatic {
+            location: &REPEAT_SUMMARY_LOCATION,
+            level: run.level,
+            tag: "",
+        };
+        let duration = run.last.duration_since(run.first);
+        let result = self.inner.log(
+            &Record::new(
+                &record_static,
+                &format_args!(
+                    "previous message repeated {} times over {}s",
+                    run.repeats,
+                    duration.as_secs()
+                ),
+                b!("repeats" => run.repeats),
+            ),
+            &slog::OwnedKV(DedupKV(run.values.clone())).into(),
+        );
+        if let Some(counters) = self.counters {
+            counters.summaries.fetch_add(1, Ordering::Relaxed);
+        }
+        run.first = run.last;
+        run.repeats = 0;
+        result
+    }
+
+    /// Summarizes the current run if it has suppressed repeats and no record has been logged for `idle`.
+    pub fn flush_idle(&self, idle: Duration) -> Result<(), D::Err> {
+        let mut run = self.run.lock().expect("drains have not paniced");
+        match run.as_mut() {
+            Some(run) if run.repeats > 0 && run.last.elapsed() >= idle => self.log_summary(run),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for DedupDrain<D> {
+    type Ok = ();
     type Err = D::Err;
 
     fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let key = record_text(record, values);
+        let now = Instant::now();
+        let mut run = self.run.lock().expect("drains have not paniced");
+
+        if let Some(run) = run.as_mut().filter(|r| r.key == key) {
+            run.repeats += 1;
+            run.last = now;
+            if let Some(counters) = self.counters {
+                counters.suppressed.fetch_add(1, Ordering::Relaxed);
+            }
+            if now.duration_since(run.first) >= REPEAT_SUMMARY_INTERVAL {
+                self.log_summary(run)?;
+            }
+            return Ok(());
+        }
+
+        if let Some(mut ended) = run.take().filter(|r| r.repeats > 0) {
+            self.log_summary(&mut ended)?;
+        }
+        *run = Some(RepeatRun {
+            key,
+            level: record.level(),
+            values: values.clone(),
+            first: now,
+            last: now,
+            repeats: 0,
+        });
+
         let values = slog::OwnedKV(DedupKV(values.clone()));
         self.inner.log(record, &values.into())
     }
 }
 
+/// Formats the level, message and all key-value pairs of a record on one line. Identical records have the same text.
+fn record_text(record: &Record, values: &OwnedKVList) -> String {
+    struct KeySerializer(String);
+
+    impl slog::Serializer for KeySerializer {
+        fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+            use std::fmt::Write as _;
+            let _ = write!(self.0, " {}={}", key, val);
+            Ok(())
+        }
+    }
+
+    let mut serializer = KeySerializer(format!("{} {}", record.level().as_short_str(), record.msg()));
+    let _ = record.kv().serialize(record, &mut serializer);
+    let _ = values.serialize(record, &mut serializer);
+    serializer.0
+}
+
+/// slog drain that keeps the most recent records as text, e.g. for crash reports.
+pub struct RecentRecords {
+    capacity: usize,
+    records: Mutex<VecDeque<String>>,
+}
+
+impl RecentRecords {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn records(&self) -> Vec<String> {
+        let records = self.records.lock().expect("drains have not paniced");
+        records.iter().cloned().collect()
+    }
+}
+
+impl Drain for RecentRecords {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> result::Result<Self::Ok, Self::Err> {
+        let text = format!(
+            "{} {}",
+            chrono::Local::now().format("%F %T%.3f"),
+            record_text(record, values)
+        );
+        let mut records = self.records.lock().expect("drains have not paniced");
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(text);
+        Ok(())
+    }
+}
+
+/// slog-json drain that writes each record as a single line JSON object. Keys are unique in each object, the first
+/// occurrence wins: the timestamp, level, module and message, then the record's values, then the logger's.
+pub struct JsonFormat<W: io::Write>(Mutex<slog_json::Json<W>>);
+
+impl<W: io::Write> JsonFormat<W> {
+    pub fn new(writer: W) -> Self {
+        Self(Mutex::new(
+            slog_json::Json::new(writer).set_newlines(true).set_flush(true).build(),
+        ))
+    }
+}
+
+impl<W: io::Write> Drain for JsonFormat<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> result::Result<Self::Ok, Self::Err> {
+        // slog-json writes every key it's given, so all of them are passed deduplicated as the values of the record.
+        let location = slog::RecordLocation {
+            file: record.file(),
+            line: record.line(),
+            column: record.column(),
+            function: record.function(),
+            module: record.module(),
+        };
+        let record_static = slog::RecordStatic {
+            location: &location,
+            tag: record.tag(),
+            level: record.level(),
+        };
+        let record_values = JsonRecordValues(record, values);
+        let json_record = Record::new(&record_static, record.msg(), slog::BorrowedKV(&record_values));
+        let json = self.0.lock().expect("drains have not paniced");
+        json.log(&json_record, &slog::o!().into())
+    }
+}
+
+struct JsonRecordValues<'a>(&'a Record<'a>, &'a OwnedKVList);
+
+impl KV for JsonRecordValues<'_> {
+    fn serialize(&self, _record: &Record, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        use slog::Serializer as _;
+
+        let JsonRecordValues(record, values) = *self;
+        let mut serializer = DedupSerializer::new(serializer);
+        serializer.emit_str("ts", &chrono::Local::now().to_rfc3339())?;
+        serializer.emit_str("level", record.level().as_str())?;
+        serializer.emit_str("module", record.module())?;
+        serializer.emit_arguments("msg", record.msg())?;
+        record.kv().serialize(record, &mut serializer)?;
+        values.serialize(record, &mut serializer)
+    }
+}
+
+pub type LevelOverrides = RwLock<HashMap<String, Level>>;
+
+/// slog drain that filters by a level that can be changed while the process is running. Optional overrides apply
+/// to records whose `actor` or `*_id` values match an override key.
+pub struct RuntimeLevelFilter<D> {
+    inner: D,
+    level: &'static AtomicUsize,
+    overrides: Option<&'static LevelOverrides>,
+}
+
+impl<D> RuntimeLevelFilter<D> {
+    pub fn new(inner: D, level: &'static AtomicUsize) -> Self {
+        Self {
+            inner,
+            level,
+            overrides: None,
+        }
+    }
+
+    pub fn with_overrides(mut self, overrides: &'static LevelOverrides) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+
+    fn level(&self) -> Level {
+        Level::from_usize(self.level.load(Ordering::Relaxed)).unwrap_or(Level::Trace)
+    }
+
+    fn record_level(&self, record: &Record, values: &OwnedKVList) -> Level {
+        let overrides = match self.overrides {
+            Some(overrides) => overrides.read().expect("level overrides lock is never poisoned"),
+            None => return self.level(),
+        };
+        if overrides.is_empty() {
+            return self.level();
+        }
+
+        let mut matcher = OverrideMatcher {
+            overrides: &overrides,
+            level: None,
+        };
+        let _ = values.serialize(record, &mut matcher);
+        let _ = record.kv().serialize(record, &mut matcher);
+        matcher.level.unwrap_or_else(|| self.level())
+    }
+}
+
+impl<D: Drain> Drain for RuntimeLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.record_level(record, values)) {
+            self.inner.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        let overridden = self.overrides.map_or(false, |o| {
+            o.read()
+                .expect("level overrides lock is never poisoned")
+                .values()
+                .any(|l| level.is_at_least(*l))
+        });
+        (overridden || level.is_at_least(self.level())) && self.inner.is_enabled(level)
+    }
+}
+
+struct OverrideMatcher<'a> {
+    overrides: &'a HashMap<String, Level>,
+    level: Option<Level>,
+}
+
+impl<'a> slog::Serializer for OverrideMatcher<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        if key == "actor" || key.ends_with("_id") {
+            if let Some(level) = self.overrides.get(&value.to_string()) {
+                // The most verbose matching override wins.
+                self.level = Some(
+                    self.level
+                        .map_or(*level, |l| if level.is_at_least(l) { l } else { *level }),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct SlogLogLogger(Logger);
 
 pub fn log_to_slog_level(level: log::Level) -> Level {
@@ -311,7 +647,7 @@ mod tests {
 
     use super::*;
     use mockall::mock;
-    use slog::{info, o, Key, Never, Serializer};
+    use slog::{debug, info, o, Key, Never, Serializer};
 
     mock! {
         pub Drain {}
@@ -360,4 +696,153 @@ mod tests {
         let logger = logger.new(o!("second" => 2));
         info!(logger, "test"; "third" => 3);
     }
+
+    #[test]
+    fn dedup_drain_summarizes_repeats() {
+        static COUNTERS: RepeatCounters = RepeatCounters::new();
+        let buffer = SharedBuffer::default();
+        let drain = DedupDrain::new(JsonFormat::new(buffer.clone()).fuse()).with_counters(&COUNTERS);
+        let logger = Logger::root(drain.fuse(), o!("actor" => "sync"));
+        for _ in 0..3 {
+            info!(logger, "failed"; "attempt" => 1);
+        }
+        info!(logger, "failed"; "attempt" => 2);
+        info!(logger, "recovered");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let messages = output
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .map(|v| v["msg"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "failed",
+                "previous message repeated 2 times over 0s",
+                "failed",
+                "recovered"
+            ]
+        );
+        assert_eq!(COUNTERS.suppressed(), 2);
+        assert_eq!(COUNTERS.summaries(), 1);
+    }
+
+    #[test]
+    fn dedup_drain_flushes_idle_runs() {
+        let buffer = SharedBuffer::default();
+        let drain = std::sync::Arc::new(DedupDrain::new(JsonFormat::new(buffer.clone()).fuse()));
+        let logger = Logger::root(std::sync::Arc::clone(&drain).fuse(), o!());
+        for _ in 0..3 {
+            info!(logger, "failed");
+        }
+
+        drain.flush_idle(Duration::from_secs(60)).unwrap();
+        drain.flush_idle(Duration::from_secs(0)).unwrap();
+        drain.flush_idle(Duration::from_secs(0)).unwrap();
+        info!(logger, "failed");
+        drain.flush_idle(Duration::from_secs(0)).unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let messages = output
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .map(|v| v["msg"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "failed",
+                "previous message repeated 2 times over 0s",
+                "previous message repeated 1 times over 0s"
+            ]
+        );
+    }
+
+    #[test]
+    fn recent_records_keeps_latest() {
+        static RECENT: once_cell::sync::Lazy<RecentRecords> = once_cell::sync::Lazy::new(|| RecentRecords::new(2));
+        let logger = Logger::root(&*RECENT, o!("actor" => "sync"));
+        info!(logger, "first");
+        info!(logger, "second");
+        info!(logger, "third"; "count" => 3);
+
+        let records = RECENT.records();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].ends_with("INFO second actor=sync"));
+        assert!(records[1].ends_with("INFO third count=3 actor=sync"));
+    }
+
+    #[test]
+    fn runtime_level_filter_follows_level() {
+        static LEVEL: AtomicUsize = AtomicUsize::new(0);
+        LEVEL.store(Level::Info.as_usize(), Ordering::Relaxed);
+
+        let mut mock = MockDrain::new();
+        mock.expect_log().times(2).returning(|_, _| Ok(()));
+
+        let logger = Logger::root(RuntimeLevelFilter::new(mock, &LEVEL).fuse(), o!());
+        info!(logger, "logged");
+        debug!(logger, "filtered");
+        LEVEL.store(Level::Debug.as_usize(), Ordering::Relaxed);
+        debug!(logger, "logged");
+    }
+
+    #[test]
+    fn runtime_level_filter_overrides() {
+        static LEVEL: AtomicUsize = AtomicUsize::new(0);
+        static OVERRIDES: once_cell::sync::Lazy<LevelOverrides> = once_cell::sync::Lazy::new(Default::default);
+        LEVEL.store(Level::Info.as_usize(), Ordering::Relaxed);
+        OVERRIDES.write().unwrap().insert(String::from("sync"), Level::Trace);
+
+        let mut mock = MockDrain::new();
+        mock.expect_log().times(2).returning(|_, _| Ok(()));
+
+        let drain = RuntimeLevelFilter::new(mock, &LEVEL).with_overrides(&OVERRIDES);
+        let logger = Logger::root(drain.fuse(), o!());
+        let sync_logger = logger.new(o!("actor" => "sync"));
+        let pool_logger = logger.new(o!("actor" => "pool"));
+        debug!(sync_logger, "logged");
+        debug!(pool_logger, "filtered");
+        info!(pool_logger, "logged");
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_format_writes_object_per_record() {
+        let buffer = SharedBuffer::default();
+        let logger = Logger::root(
+            JsonFormat::new(buffer.clone()).fuse(),
+            o!("actor" => "sync", "count" => 1),
+        );
+        let logger = logger.new(o!("count" => 2));
+        info!(logger, "first"; "ok" => true);
+        info!(logger, "second");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["msg"], "first");
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["actor"], "sync");
+        assert_eq!(lines[0]["count"], 2);
+        assert_eq!(lines[0]["ok"], true);
+        assert_eq!(lines[1]["msg"], "second");
+        assert!(output.lines().all(|l| l.matches("\"count\"").count() == 1));
+    }
 }
@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::{
+    core::restic::{ResticContainerSnapshot, ResticRepository},
+    core::{BtrfsContainer, BtrfsPool},
+    model::{storage, DatasetId, Entity},
+};
+use slog_scope::*;
+use std::{collections::HashSet, fmt::Display, sync::Arc};
+
+use crate::ui::{comfy_id_header, comfy_id_value_full, comfy_name_value, confirm, print_comfy_table};
+
+/// Find data on pools and repositories that belongs to datasets no longer in the configuration
+#[derive(Clap, Debug)]
+pub struct AuditOptions {
+    /// Delete all orphaned data without prompting
+    #[clap(long, conflicts_with("interactive"))]
+    purge: bool,
+
+    /// Prompt to delete each orphaned item
+    #[clap(short, long)]
+    interactive: bool,
+}
+
+pub async fn audit(options: AuditOptions) -> Result<()> {
+    debug!("Command 'audit': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let dataset_ids = entities
+        .datasets()
+        .map(|d| d.entity.dataset_id())
+        .collect::<HashSet<_>>();
+    let mut orphans = Vec::new();
+
+    for pool_model in entities.btrfs_pools.iter() {
+        let pool = Arc::new(
+            BtrfsPool::validate(pool_model.clone())
+                .with_context(|| format!("Failed to validate pool '{}'.", pool_model.name()))?,
+        );
+
+        for dataset_id in pool.snapshot_dataset_ids()? {
+            if !dataset_ids.contains(&dataset_id) {
+                orphans.push(Orphan::DatasetSnapshots(Arc::clone(&pool), dataset_id));
+            }
+        }
+
+        for container_model in pool_model.containers.iter() {
+            let container = Arc::new(
+                BtrfsContainer::validate(&pool, container_model.clone())
+                    .with_context(|| format!("Failed to validate container '{}'.", container_model.name()))?,
+            );
+
+            for dataset_id in container.source_dataset_ids()? {
+                if !dataset_ids.contains(&dataset_id) {
+                    orphans.push(Orphan::ContainerSnapshots(Arc::clone(&container), dataset_id));
+                }
+            }
+        }
+    }
+
+    for restic_model in entities.restic_containers.iter() {
+        let repository = Arc::new(ResticRepository::validate(restic_model.clone())?);
+        let mut unknown = repository
+            .snapshots()
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to list snapshots in restic container '{}'.",
+                    restic_model.name()
+                )
+            })?
+            .into_iter()
+            .filter(|s| !dataset_ids.contains(&s.dataset_id))
+            .collect::<Vec<_>>();
+        unknown.sort_unstable_by_key(|s| s.dataset_id.to_string());
+
+        let mut grouped = Vec::<(DatasetId, Vec<ResticContainerSnapshot>)>::new();
+        for snapshot in unknown {
+            match grouped.last_mut() {
+                Some((dataset_id, snapshots)) if *dataset_id == snapshot.dataset_id => snapshots.push(snapshot),
+                _ => grouped.push((snapshot.dataset_id, vec![snapshot])),
+            }
+        }
+
+        orphans.extend(
+            grouped
+                .into_iter()
+                .map(|(dataset_id, snapshots)| Orphan::ResticSnapshots(Arc::clone(&repository), dataset_id, snapshots)),
+        );
+    }
+
+    if orphans.is_empty() {
+        info!("No orphaned data found");
+        return Ok(());
+    }
+
+    print_comfy_table(
+        vec![
+            comfy_id_header(),
+            Cell::new("Location"),
+            Cell::new("Type"),
+            Cell::new("Snapshots"),
+        ],
+        orphans.iter().map(|o| {
+            vec![
+                comfy_id_value_full(o.dataset_id()),
+                comfy_name_value(o.location()),
+                Cell::new(o.kind()),
+                o.snapshot_count().map_or_else(|| Cell::new("-"), Cell::new),
+            ]
+        }),
+    );
+
+    if !options.purge && !options.interactive {
+        return Ok(());
+    }
+
+    println!();
+    for orphan in orphans {
+        if options.interactive && !confirm(format!("Delete {}?", orphan))? {
+            continue;
+        }
+
+        orphan.purge().await?;
+        info!("Deleted {}", orphan);
+    }
+
+    Ok(())
+}
+
+enum Orphan {
+    DatasetSnapshots(Arc<BtrfsPool>, DatasetId),
+    ContainerSnapshots(Arc<BtrfsContainer>, DatasetId),
+    ResticSnapshots(Arc<ResticRepository>, DatasetId, Vec<ResticContainerSnapshot>),
+}
+
+impl Orphan {
+    fn dataset_id(&self) -> DatasetId {
+        match self {
+            Orphan::DatasetSnapshots(_, id) | Orphan::ContainerSnapshots(_, id) | Orphan::ResticSnapshots(_, id, _) => {
+                *id
+            }
+        }
+    }
+
+    fn location(&self) -> String {
+        match self {
+            Orphan::DatasetSnapshots(pool, _) => pool.to_string(),
+            Orphan::ContainerSnapshots(container, _) => container.to_string(),
+            Orphan::ResticSnapshots(repository, _, _) => repository.model().name().to_owned(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Orphan::DatasetSnapshots(..) => "dataset snapshots",
+            Orphan::ContainerSnapshots(..) => "container snapshots",
+            Orphan::ResticSnapshots(..) => "restic snapshots",
+        }
+    }
+
+    fn snapshot_count(&self) -> Option<usize> {
+        match self {
+            Orphan::ResticSnapshots(_, _, snapshots) => Some(snapshots.len()),
+            _ => None,
+        }
+    }
+
+    async fn purge(&self) -> Result<()> {
+        match self {
+            Orphan::DatasetSnapshots(pool, dataset_id) => pool.purge_dataset_snapshots(*dataset_id),
+            Orphan::ContainerSnapshots(container, dataset_id) => container.purge_dataset(*dataset_id),
+            Orphan::ResticSnapshots(repository, _, snapshots) => {
+                let snapshots = snapshots.iter().collect::<Vec<_>>();
+                repository.forget(&snapshots).start()?.wait().await?;
+                repository.prune().start()?.wait().await
+            }
+        }
+    }
+}
+
+impl Display for Orphan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} for dataset {} in {}",
+            self.kind(),
+            self.dataset_id(),
+            self.location()
+        )
+    }
+}
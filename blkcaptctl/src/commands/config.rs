@@ -0,0 +1,390 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::model::{
+    audit::read_audit_log,
+    bundle::{merge_entities, ConfigBundle},
+    entities::TrashedEntity,
+    storage, Entities, Entity,
+};
+use slog_scope::*;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+};
+
+use super::any_entity_search;
+use crate::ui::{comfy_id_header, comfy_id_value, confirm_or_abort, print_comfy_table};
+
+/// Show the configuration change history
+#[derive(Clap, Debug)]
+pub struct ConfigHistoryOptions {
+    /// Only show this many of the most recent changes
+    #[clap(short('n'), long, value_name("count"))]
+    limit: Option<usize>,
+}
+
+pub fn config_history(options: ConfigHistoryOptions) -> Result<()> {
+    debug!("Command 'config_history': {:?}", options);
+
+    let records = read_audit_log()?;
+    let skip = options.limit.map_or(0, |limit| records.len().saturating_sub(limit));
+
+    print_comfy_table(
+        vec![
+            Cell::new("Time"),
+            Cell::new("User"),
+            Cell::new("Command"),
+            Cell::new("Changes"),
+        ],
+        records.iter().skip(skip).map(|r| {
+            vec![
+                Cell::new(r.time.format("%F %T UTC")),
+                Cell::new(&r.user),
+                Cell::new(&r.command),
+                Cell::new(r.changes.join("\n")),
+            ]
+        }),
+    );
+
+    Ok(())
+}
+
+/// Export the configuration as a single document, to back it up or move it to another machine
+#[derive(Clap, Debug)]
+pub struct ConfigExportOptions {
+    /// Replace secrets with a placeholder, they must be set again after an import
+    #[clap(long)]
+    redact_secrets: bool,
+
+    /// Write the bundle to this file instead of standard output
+    #[clap(short, long, value_name("file"))]
+    output: Option<PathBuf>,
+}
+
+pub fn config_export(options: ConfigExportOptions) -> Result<()> {
+    debug!("Command 'config_export': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let server = storage::load_server_config().context("Failed to load the server configuration.")?;
+    let mut bundle = ConfigBundle::new(entities, server, options.redact_secrets);
+    let encrypted = bundle.encrypted_secrets();
+    if !encrypted.is_empty() {
+        warn!(
+            "The bundle holds {} encrypted secrets, only a machine with the same secrets key can decrypt them",
+            encrypted.len()
+        );
+    }
+    let json = serde_json::to_string_pretty(&bundle)?;
+
+    match options.output {
+        Some(path) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)
+                .with_context(|| format!("Failed to create {}.", path.display()))?;
+            file.write_all(json.as_bytes())?;
+            info!("Exported the configuration to {}", path.display());
+        }
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
+
+/// Import a configuration exported by 'config export', adding its entities to the current configuration
+#[derive(Clap, Debug)]
+pub struct ConfigImportOptions {
+    /// Replace the current configuration, including the server configuration, instead of adding to it
+    #[clap(long, conflicts_with("skip-existing"))]
+    replace: bool,
+
+    /// Leave out entities whose id already exists instead of failing
+    #[clap(long)]
+    skip_existing: bool,
+
+    /// The bundle file, - for standard input
+    #[clap(value_name("file"))]
+    file: PathBuf,
+}
+
+pub fn config_import(options: ConfigImportOptions) -> Result<()> {
+    debug!("Command 'config_import': {:?}", options);
+
+    let mut data = Vec::new();
+    if options.file.as_os_str() == "-" {
+        io::stdin().read_to_end(&mut data)?;
+    } else {
+        data = fs::read(&options.file).with_context(|| format!("Failed to read {}.", options.file.display()))?;
+    }
+    let mut bundle: ConfigBundle = serde_json::from_slice(&data).context("Failed to parse the bundle.")?;
+    bundle.validate_version()?;
+
+    let removed = bundle.remove_redacted_secrets();
+    let undecryptable = bundle.undecryptable_secrets();
+    let missing_mountpoints = bundle
+        .entities
+        .btrfs_pools
+        .iter()
+        .filter(|p| !p.mountpoint_path.exists())
+        .map(|p| p.name().to_owned())
+        .collect::<Vec<_>>();
+
+    let mut entities = storage::load_entity_config()?;
+    let current_empty = entities.btrfs_pools.is_empty()
+        && entities.restic_containers.is_empty()
+        && entities.snapshot_syncs.is_empty()
+        && entities.observers.is_empty()
+        && entities.dataset_groups.is_empty();
+    if options.replace || current_empty {
+        if !current_empty {
+            confirm_or_abort(format!(
+                "Replace the current configuration with the one exported at {}?",
+                bundle.exported.format("%F %T UTC")
+            ))?;
+        }
+        let mut imported = Entities::default();
+        merge_entities(&mut imported, bundle.entities, false)?;
+        imported.machine_id = entities.machine_id.take();
+        storage::store_entity_config(imported)?;
+        storage::store_server_config(bundle.server).context("Failed to store the server configuration.")?;
+    } else {
+        let skipped = merge_entities(&mut entities, bundle.entities, options.skip_existing)?;
+        for entity in skipped {
+            info!("Skipped existing {}", entity);
+        }
+        storage::store_entity_config(entities)?;
+        info!("The server configuration was kept, use --replace to import it");
+    }
+
+    for location in removed.iter().chain(undecryptable.iter()) {
+        warn!(
+            "The secret of {} is not in the bundle or can't be decrypted here, set it again with 'secret set'",
+            location
+        );
+    }
+    for pool in missing_mountpoints {
+        warn!("The mountpoint of pool '{}' does not exist on this machine", pool);
+    }
+    info!("Imported the configuration, applied when the service restarts");
+
+    Ok(())
+}
+
+/// Restore the most recently deleted entity
+#[derive(Clap, Debug)]
+pub struct UndoOptions {}
+
+pub fn undo(options: UndoOptions) -> Result<()> {
+    debug!("Command 'undo': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let latest =
+        latest_trashed(&entities.trash, None).ok_or_else(|| anyhow!("There are no deleted entities to restore."))?;
+    restore_trashed(entities, latest)
+}
+
+/// Restore a deleted entity, or list the entities that can be restored
+#[derive(Clap, Debug)]
+pub struct RestoreEntityOptions {
+    /// The name or id of the deleted entity, omit to list the deleted entities
+    #[clap(value_name("entity|id"))]
+    entity: Option<String>,
+}
+
+pub fn restore_entity(options: RestoreEntityOptions) -> Result<()> {
+    debug!("Command 'restore_entity': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+
+    let query = match options.entity {
+        Some(query) => query,
+        None => {
+            print_comfy_table(
+                vec![
+                    comfy_id_header(),
+                    Cell::new("Type"),
+                    Cell::new("Name"),
+                    Cell::new("Deleted"),
+                    Cell::new("Restorable Until"),
+                ],
+                entities.trash.iter().map(|t| {
+                    let entity = t.entity.as_ref();
+                    vec![
+                        comfy_id_value(entity.id()),
+                        Cell::new(entity.entity_type()),
+                        Cell::new(entity.name()),
+                        Cell::new(t.deleted.format("%F %T UTC")),
+                        Cell::new(t.restorable_until().format("%F %T UTC")),
+                    ]
+                }),
+            );
+            return Ok(());
+        }
+    };
+
+    let index = latest_trashed(&entities.trash, Some(&query))
+        .ok_or_else(|| anyhow!("No deleted entity named or with id '{}'.", query))?;
+    restore_trashed(entities, index)
+}
+
+/// The index of the most recently deleted entity matching the name or id, an entity may be deleted, restored and
+/// deleted again.
+fn latest_trashed(trash: &[TrashedEntity], query: Option<&str>) -> Option<usize> {
+    trash
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| {
+            let entity = t.entity.as_ref();
+            query.map_or(true, |q| entity.name() == q || entity.id().to_string() == q)
+        })
+        .max_by_key(|(_, t)| t.deleted)
+        .map(|(index, _)| index)
+}
+
+fn restore_trashed(mut entities: Entities, index: usize) -> Result<()> {
+    let (entity_type, name) = {
+        let entity = entities.trash[index].entity.as_ref();
+        (entity.entity_type(), entity.name().to_owned())
+    };
+    entities
+        .restore_entity(index)
+        .with_context(|| format!("Failed to restore {} '{}'.", entity_type, name))?;
+
+    storage::store_entity_config(entities)?;
+    info!("Restored {} '{}'", entity_type, name);
+
+    Ok(())
+}
+
+/// Let the worker run a disabled dataset, container, sync or observer again
+#[derive(Clap, Debug)]
+pub struct EnableOptions {
+    /// The name or id of the entity
+    #[clap(value_name("entity|id"))]
+    entity: String,
+}
+
+pub fn enable(options: EnableOptions) -> Result<()> {
+    debug!("Command 'enable': {:?}", options);
+
+    set_disabled(&options.entity, false)
+}
+
+/// Keep the worker from running a dataset, container, sync or observer at all without deleting it, unlike pausing
+/// its features. Syncs from or to a disabled dataset or container are not run either.
+#[derive(Clap, Debug)]
+pub struct DisableOptions {
+    /// The name or id of the entity
+    #[clap(value_name("entity|id"))]
+    entity: String,
+}
+
+pub fn disable(options: DisableOptions) -> Result<()> {
+    debug!("Command 'disable': {:?}", options);
+
+    set_disabled(&options.entity, true)
+}
+
+fn set_disabled(query: &str, disabled: bool) -> Result<()> {
+    let mut entities = storage::load_entity_config()?;
+    let (id, entity_type, name) = any_entity_search(&entities, query)?;
+
+    let state = if disabled { "disabled" } else { "enabled" };
+    let flag = entities
+        .disabled_mut(id)
+        .with_context(|| format!("A {} can't be {}.", entity_type, state))?;
+    if *flag == disabled {
+        info!("{} '{}' is already {}", entity_type, name, state);
+        return Ok(());
+    }
+    *flag = disabled;
+
+    storage::store_entity_config(entities)?;
+    info!(
+        "{} '{}' {}, applied when the service restarts",
+        entity_type, name, state
+    );
+
+    Ok(())
+}
+
+/// Set the labels and notes of an entity, shown by its show command and filtered on by list commands
+#[derive(Clap, Debug)]
+pub struct AnnotateOptions {
+    /// Set a label, an empty value removes it
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("name=value")
+    )]
+    label: Vec<String>,
+
+    /// Set the notes, empty removes them
+    #[clap(long, value_name("text"))]
+    notes: Option<String>,
+
+    /// The name or id of the entity
+    #[clap(value_name("entity|id"))]
+    entity: String,
+}
+
+pub fn annotate(options: AnnotateOptions) -> Result<()> {
+    debug!("Command 'annotate': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let (id, ..) = any_entity_search(&entities, &options.entity)?;
+    let annotations = entities.annotations_mut(id).expect("always exists if found");
+
+    for arg in options.label.iter() {
+        match arg.split_once('=') {
+            Some((name, "")) => {
+                annotations.labels.remove(name);
+            }
+            Some((name, value)) if !name.is_empty() => {
+                annotations.labels.insert(name.to_owned(), value.to_owned());
+            }
+            _ => bail!("Labels must be in the form name=value."),
+        }
+    }
+    if let Some(notes) = options.notes {
+        annotations.notes = Some(notes).filter(|n| !n.is_empty());
+    }
+
+    storage::store_entity_config(entities)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+    use libblkcapt::model::entities::{HealthchecksObserverEntity, TrashedEntityKind};
+
+    fn trashed(name: &str, days_ago: i64) -> TrashedEntity {
+        TrashedEntity {
+            deleted: Utc::now() - Duration::days(days_ago),
+            entity: TrashedEntityKind::Observer(HealthchecksObserverEntity::new(name.to_owned(), Vec::new())),
+        }
+    }
+
+    #[test]
+    fn latest_trashed_picks_most_recent_match() {
+        let trash = vec![trashed("pings", 1), trashed("other", 2), trashed("pings", 3)];
+
+        assert_eq!(latest_trashed(&trash, None), Some(0));
+        assert_eq!(latest_trashed(&trash, Some("pings")), Some(0));
+        assert_eq!(latest_trashed(&trash, Some("other")), Some(1));
+        let id = trash[2].entity.as_ref().id().to_string();
+        assert_eq!(latest_trashed(&trash, Some(&id)), Some(2));
+        assert_eq!(latest_trashed(&trash, Some("missing")), None);
+        assert_eq!(latest_trashed(&[], None), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Clap;
+use libblkcapt::{
+    core::BtrfsPool,
+    model::{entities::SnapshotSyncEntity, storage, Entities},
+    sys::{
+        btrfs::Filesystem,
+        fs::{lookup_mountentry, loop_attach, loop_detach, unmount, DevicePathBuf},
+    },
+    SANDBOX_MARKER,
+};
+use slog_scope::*;
+use uuid::Uuid;
+
+const LOOP_DEVICES_FILE: &str = "loop-devices";
+const SANDBOX_POOLS: [&str; 2] = ["source", "backup"];
+
+#[derive(Clap, Debug)]
+pub struct DevCreateSandboxOptions {
+    /// Directory to build the sandbox in, a new temporary directory by default
+    #[clap(long, value_name("path"))]
+    path: Option<PathBuf>,
+
+    /// Size of each loopback filesystem image in MiB
+    #[clap(long, value_name("MiB"), default_value("512"))]
+    size: u64,
+}
+
+pub fn create_sandbox(options: DevCreateSandboxOptions) -> Result<()> {
+    debug!("Command 'create_sandbox': {:?}", options);
+
+    let root = options
+        .path
+        .unwrap_or_else(|| env::temp_dir().join(format!("blkcapt-sandbox-{}", Uuid::new_v4().to_simple())));
+    if root.join(LOOP_DEVICES_FILE).exists() {
+        bail!("{} already holds a sandbox", root.display());
+    }
+    let created_root = !root.exists();
+    if let Err(error) = build_sandbox(&root, options.size) {
+        if root.join(LOOP_DEVICES_FILE).exists() {
+            if let Err(e) = teardown_sandbox(&root, created_root) {
+                warn!("Failed to clean up the sandbox {}: {:#}", root.display(), e);
+            }
+        }
+        return Err(error);
+    }
+
+    let data_dir = root.join("data");
+    println!("Created sandbox {}", root.display());
+    println!("Use it by setting:");
+    println!("  export BLOCKCAPTAIN_DATA_DIR={}", data_dir.display());
+    println!("  export BLOCKCAPTAIN_RUNTIME_DIR={}", root.join("run").display());
+    println!("Remove it with: blkcapt dev destroy-sandbox {}", root.display());
+    Ok(())
+}
+
+fn build_sandbox(root: &Path, size: u64) -> Result<()> {
+    let images_dir = root.join("images");
+    fs::create_dir_all(&images_dir).context("failed to create the sandbox directory")?;
+
+    let mut entities = Entities::default();
+    let mut loop_devices = Vec::new();
+    for name in SANDBOX_POOLS.iter() {
+        let image = images_dir.join(format!("{}.img", name));
+        File::create(&image)
+            .and_then(|f| f.set_len(size * 1024 * 1024))
+            .with_context(|| format!("failed to create image {}", image.display()))?;
+        let device = loop_attach(&image)?;
+        loop_devices.push(device.to_string());
+        // Recorded as they are attached so a failed build can still be torn down.
+        fs::write(root.join(LOOP_DEVICES_FILE), loop_devices.join("\n"))?;
+
+        info!("Creating pool {} on {}", name, device);
+        let mountpoint = root.join("mnt").join(name);
+        fs::create_dir_all(&mountpoint)?;
+        Filesystem::make(&[device], name, None, None)?.mount(&mountpoint, &[])?;
+        let pool = BtrfsPool::new((*name).to_owned(), mountpoint)?;
+        entities.attach_pool(pool.take_model())?;
+    }
+
+    let source = Arc::new(BtrfsPool::validate(entities.btrfs_pools[0].clone())?);
+    let dataset = source.create_dataset(String::from("data"))?.take_model();
+    let dataset_id = dataset.dataset_id();
+    entities.btrfs_pools[0].attach_dataset(dataset)?;
+
+    let backup = Arc::new(BtrfsPool::validate(entities.btrfs_pools[1].clone())?);
+    let container = backup.create_container(String::from("data-backup"))?.take_model();
+    let container_id = container.container_id();
+    entities.btrfs_pools[1].attach_container(container)?;
+
+    entities.attach_snapshot_sync(SnapshotSyncEntity::new(
+        String::from("data-to-backup"),
+        dataset_id,
+        container_id,
+    ))?;
+
+    let data_dir = root.join("data");
+    storage::write_entity_config(&data_dir, &entities)?;
+    let runtime_dir = root.join("run");
+    fs::create_dir_all(&runtime_dir)?;
+    for dir in [data_dir, runtime_dir].iter() {
+        File::create(dir.join(SANDBOX_MARKER)).context("failed to mark the sandbox directories")?;
+    }
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DevDestroySandboxOptions {
+    /// Directory of the sandbox
+    path: PathBuf,
+}
+
+pub fn destroy_sandbox(options: DevDestroySandboxOptions) -> Result<()> {
+    debug!("Command 'destroy_sandbox': {:?}", options);
+
+    if !options.path.join(LOOP_DEVICES_FILE).exists() {
+        bail!("{} is not a sandbox", options.path.display());
+    }
+
+    teardown_sandbox(&options.path, true)?;
+    println!("Removed sandbox {}", options.path.display());
+    Ok(())
+}
+
+// Unmounts the pools and detaches the loop devices of a sandbox, then removes its directory when `remove_root`.
+fn teardown_sandbox(root: &Path, remove_root: bool) -> Result<()> {
+    for name in SANDBOX_POOLS.iter() {
+        let mountpoint = root.join("mnt").join(name);
+        if lookup_mountentry(&mountpoint).is_some() {
+            unmount(&mountpoint).with_context(|| format!("failed to unmount {}", mountpoint.display()))?;
+        }
+    }
+
+    for device in fs::read_to_string(root.join(LOOP_DEVICES_FILE))?.lines() {
+        if let Err(e) = DevicePathBuf::try_from(device).and_then(|d| loop_detach(&d)) {
+            warn!("Failed to detach loop device {}: {:#}", device, e);
+        }
+    }
+
+    if remove_root {
+        fs::remove_dir_all(root).with_context(|| format!("failed to remove {}", root.display()))?;
+    } else {
+        for dir in ["images", "mnt", "data", "run"].iter() {
+            let _ = fs::remove_dir_all(root.join(dir));
+        }
+        fs::remove_file(root.join(LOOP_DEVICES_FILE))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use clap::Clap;
+use libblkcapt::{
+    core::units::{systemd_units, DEFAULT_WORKER_PATH},
+    model::storage,
+};
+use slog_scope::*;
+use std::{fs, path::PathBuf};
+
+/// Generate systemd service and timer units running the due jobs of each kind on the entities' schedules, instead of
+/// the blockcaptain service. Stop and disable the blockcaptain service before enabling the timers.
+#[derive(Clap, Debug)]
+pub struct GenerateSystemdUnitsOptions {
+    /// Write the unit files to this directory, such as /etc/systemd/system, instead of printing them
+    #[clap(short, long, value_name("dir"))]
+    output_dir: Option<PathBuf>,
+
+    /// Path of the blkcaptd binary run by the services
+    #[clap(long, value_name("path"), default_value(DEFAULT_WORKER_PATH))]
+    worker_path: PathBuf,
+}
+
+pub fn generate_systemd_units(options: GenerateSystemdUnitsOptions) -> Result<()> {
+    debug!("Command 'generate_systemd_units': {:?}", options);
+
+    let entities = storage::load_entity_config()?.without_disabled();
+    let units = systemd_units(&entities, &options.worker_path).context("Failed to generate the systemd units.")?;
+    if units.is_empty() {
+        warn!("No entity has an enabled schedule, no units generated");
+        return Ok(());
+    }
+
+    let output_dir = match options.output_dir {
+        Some(output_dir) => output_dir,
+        None => {
+            for unit in units.iter() {
+                println!("### {}\n{}", unit.name, unit.contents);
+            }
+            return Ok(());
+        }
+    };
+
+    fs::create_dir_all(&output_dir)
+        .with_context(|| format!("Failed to create output directory {}.", output_dir.display()))?;
+    let mut timers = Vec::new();
+    for unit in units.iter() {
+        let path = output_dir.join(&unit.name);
+        fs::write(&path, &unit.contents).with_context(|| format!("Failed to write {}.", path.display()))?;
+        if unit.name.ends_with(".timer") {
+            timers.push(unit.name.as_str());
+        }
+    }
+    info!(
+        "Wrote {} units to {}, enable the timers with: systemctl daemon-reload && systemctl enable --now {}",
+        units.len(),
+        output_dir.display(),
+        timers.join(" ")
+    );
+    Ok(())
+}
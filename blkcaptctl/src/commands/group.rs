@@ -0,0 +1,165 @@
+use super::{dataset_group_search, dataset_search, LabelFilterOptions};
+use crate::ui::*;
+use anyhow::Result;
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::model::{
+    entities::{DatasetGroupEntity, ScheduleModel, TrashedEntityKind},
+    entity_by_id_mut, storage, Entity, EntityPath,
+};
+use slog_scope::*;
+
+#[derive(Clap, Debug)]
+pub struct GroupCreateUpdateOptions {
+    /// Set the schedule for taking snapshots of all datasets in the group
+    #[clap(short('s'), long, value_name("cron"))]
+    snapshot_schedule: Option<ScheduleArg>,
+}
+
+impl GroupCreateUpdateOptions {
+    fn update_snapshots(&self, schedule: &mut Option<ScheduleModel>) {
+        if self.snapshot_schedule.is_some() {
+            *schedule = self.snapshot_schedule.clone().map(|s| s.into());
+        }
+    }
+}
+
+/// Create a group of datasets that are snapshotted together
+#[derive(Clap, Debug)]
+pub struct GroupCreateOptions {
+    /// Name of the group
+    name: String,
+
+    /// The member datasets
+    #[clap(value_name("[pool/]dataset|id"), required(true))]
+    datasets: Vec<String>,
+
+    #[clap(flatten)]
+    shared: GroupCreateUpdateOptions,
+}
+
+pub fn create_group(options: GroupCreateOptions) -> Result<()> {
+    debug!("Command 'create_group': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let dataset_ids = options
+        .datasets
+        .iter()
+        .map(|d| dataset_search(&entities, d).map(|d| d.entity.dataset_id()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut group = DatasetGroupEntity::new(options.name, dataset_ids);
+    options.shared.update_snapshots(&mut group.snapshot_schedule);
+
+    entities.attach_dataset_group(group)?;
+    storage::store_entity_config(entities)?;
+
+    Ok(())
+}
+
+/// Update an existing dataset group
+#[derive(Clap, Debug)]
+pub struct GroupUpdateOptions {
+    /// Prevent starting new snapshot creation jobs on this group
+    #[clap(long, conflicts_with("resume-snapshotting"))]
+    pause_snapshotting: bool,
+
+    #[clap(long)]
+    resume_snapshotting: bool,
+
+    #[clap(flatten)]
+    shared: GroupCreateUpdateOptions,
+
+    /// The group to update
+    #[clap(value_name("group|id"))]
+    group: String,
+}
+
+pub fn update_group(options: GroupUpdateOptions) -> Result<()> {
+    debug!("Command 'update_group': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let group_id = dataset_group_search(&entities, &options.group)?.id();
+    let group = entity_by_id_mut(&mut entities.dataset_groups, group_id).expect("entity exists, found in search");
+
+    options.shared.update_snapshots(&mut group.snapshot_schedule);
+    if options.pause_snapshotting || options.resume_snapshotting {
+        group.pause_snapshotting = options.pause_snapshotting
+    }
+
+    storage::store_entity_config(entities)?;
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct GroupDeleteOptions {
+    /// The group to delete
+    #[clap(value_name("group|id"))]
+    group: String,
+}
+
+pub fn delete_group(options: GroupDeleteOptions) -> Result<()> {
+    debug!("Command 'delete_group': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let (id, name) = {
+        let group = dataset_group_search(&entities, &options.group)?;
+        (group.id(), group.name().to_owned())
+    };
+    confirm_or_abort(format!("Delete group {}?", name))?;
+
+    let position = entities
+        .dataset_groups
+        .iter()
+        .position(|g| g.id() == id)
+        .expect("id always exists");
+    let group = entities.dataset_groups.remove(position);
+    entities.trash_entity(TrashedEntityKind::DatasetGroup(group));
+
+    storage::store_entity_config(entities)?;
+    info!("Deleted group '{}', use 'undo' to restore it", name);
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct GroupListOptions {
+    #[clap(flatten)]
+    labels: LabelFilterOptions,
+}
+
+pub fn list_group(options: GroupListOptions) -> Result<()> {
+    debug!("Command 'list_group': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+
+    print_comfy_table(
+        vec![
+            comfy_id_header(),
+            Cell::new("Group Name"),
+            Cell::new("Datasets"),
+            Cell::new("Snapshotting"),
+        ],
+        entities
+            .dataset_groups
+            .iter()
+            .filter(|g| options.labels.matches(&g.annotations))
+            .map(|g| {
+                vec![
+                    comfy_id_value(g.id()),
+                    comfy_name_value(g.name()),
+                    Cell::new(
+                        g.dataset_ids
+                            .iter()
+                            .map(|id| entities.dataset(*id).map_or_else(|| id.to_string(), |d| d.path()))
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    ),
+                    comfy_feature_state_cell(g.snapshotting_state()),
+                ]
+            }),
+    );
+
+    Ok(())
+}
@@ -1,17 +1,23 @@
-use super::{entity_by_type_lookup, entity_by_type_search, observer_search};
+use super::{
+    entity_by_type_lookup, entity_by_type_search, observer_search, DryRunOptions, LabelFilterOptions,
+    ProxyUpdateOptions,
+};
 use crate::ui::*;
 use anyhow::{bail, Context, Result};
 use clap::Clap;
 use comfy_table::Cell;
 use hyper::Uri;
 use libblkcapt::core::ObservationRouter;
-use libblkcapt::model::{entity_by_id_mut, entity_by_name_or_id, storage, Entity};
-use libblkcapt::{core::ObservableEventStage, model::entities::HealthchecksHeartbeat};
+use libblkcapt::model::{entity_by_id_mut, entity_by_name_or_id, secrets, storage, Entity};
+use libblkcapt::{
+    core::ObservableEventStage,
+    model::entities::{HealthchecksDelivery, HealthchecksHeartbeat},
+};
 use libblkcapt::{
     core::ObservationEmitter,
     model::{
         entities::HealthchecksObserverEntity,
-        entities::{HealthchecksObservation, ObservableEvent, Observation},
+        entities::{HealthchecksObservation, ObservableEvent, Observation, TrashedEntityKind},
         Entities,
     },
 };
@@ -32,6 +38,25 @@ pub struct ObserverCreateUpdateOptions {
     /// Heartbeat frequency
     #[clap(long, value_name("duration"))]
     heartbeat_frequency: Option<humantime::Duration>,
+
+    #[clap(flatten)]
+    proxy: ProxyUpdateOptions,
+
+    /// Longest wait to connect to the healthchecks server
+    #[clap(long, value_name("duration"))]
+    connect_timeout: Option<humantime::Duration>,
+
+    /// Longest wait for the healthchecks server to answer a ping
+    #[clap(long, value_name("duration"))]
+    request_timeout: Option<humantime::Duration>,
+
+    /// Retries after a failed ping, 0 disables retries
+    #[clap(long, value_name("count"))]
+    retries: Option<u32>,
+
+    /// Delay before the first retry, doubled for every further one
+    #[clap(long, value_name("duration"))]
+    retry_backoff: Option<humantime::Duration>,
 }
 
 impl ObserverCreateUpdateOptions {
@@ -49,6 +74,28 @@ impl ObserverCreateUpdateOptions {
             .filter(|s| s != ObservationEmitter::DEFAULT_URL)
     }
 
+    fn update_delivery(&self, delivery: &mut HealthchecksDelivery) -> Result<()> {
+        if let Some(timeout) = self.connect_timeout {
+            delivery.connect_timeout = *timeout;
+        }
+        if let Some(timeout) = self.request_timeout {
+            delivery.request_timeout = *timeout;
+        }
+        if let Some(retries) = self.retries {
+            delivery.retries = retries;
+        }
+        if let Some(backoff) = self.retry_backoff {
+            delivery.retry_backoff = *backoff;
+        }
+        if delivery.connect_timeout.is_zero() || delivery.request_timeout.is_zero() {
+            bail!("timeouts must be greater than zero");
+        }
+        if delivery.retries > HealthchecksDelivery::MAX_RETRIES {
+            bail!("retries must be at most {}", HealthchecksDelivery::MAX_RETRIES);
+        }
+        Ok(())
+    }
+
     fn maybe_frequency(&self) -> Option<Duration> {
         self.heartbeat_frequency.map(|f| *f)
     }
@@ -88,7 +135,7 @@ pub struct ObserverCreateOptions {
 }
 
 pub fn create_observer(options: ObserverCreateOptions) -> Result<()> {
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     if options.observer_type != "healthchecks" {
         bail!("only healthchecks is supported");
@@ -99,10 +146,12 @@ pub fn create_observer(options: ObserverCreateOptions) -> Result<()> {
     let mut observer = HealthchecksObserverEntity::new(options.name.clone(), observations);
     observer.custom_url = options.shared.maybe_custom_url();
     observer.heartbeat = options.shared.maybe_heartbeat_model()?;
+    options.shared.proxy.update_proxy(&mut observer.proxy)?;
+    options.shared.update_delivery(&mut observer.delivery)?;
 
     entities.attach_observer(observer)?;
 
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
 
     Ok(())
 }
@@ -138,16 +187,20 @@ pub struct ObserverUpdateOptions {
 
     #[clap(long, conflicts_with_all(&["heartbeat", "heartbeat-frequency"]))]
     remove_heartbeat: bool,
+
+    #[clap(flatten)]
+    dry_run: DryRunOptions,
 }
 
 pub fn update_observer(options: ObserverUpdateOptions) -> Result<()> {
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let observations = build_observation_models(&entities, &options.add)?;
 
     let observer = observer_search(&entities, &options.observer).map(|o| o.id())?;
     let observer =
         entity_by_id_mut(entities.observers.as_mut_slice(), observer).expect("entity exists, found in search");
+    let previous = observer.clone();
 
     let mut removes = options.remove.clone();
     removes.sort_unstable();
@@ -176,7 +229,13 @@ pub fn update_observer(options: ObserverUpdateOptions) -> Result<()> {
         observer.heartbeat = None;
     }
 
-    storage::store_entity_config(entities);
+    options.shared.proxy.update_proxy(&mut observer.proxy)?;
+    options.shared.update_delivery(&mut observer.delivery)?;
+
+    if options.dry_run.preview(&previous, observer)? {
+        return Ok(());
+    }
+    storage::store_entity_config(entities)?;
 
     Ok(())
 }
@@ -207,17 +266,14 @@ pub struct ObserverTestOptions {
 pub async fn test_observer(options: ObserverTestOptions) -> Result<()> {
     debug!("Command 'create_observer': {:?}", options);
 
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
 
     let observer = observer_search(&entities, &options.observer)?;
 
     let entity = entity_by_type_search(&entities, options.event.entity_type(), &options.entity)?;
     info!("Found {}.", entity.path());
 
-    let emitter = observer
-        .custom_url
-        .clone()
-        .map_or_else(ObservationEmitter::default, ObservationEmitter::new);
+    let emitter = ObservationEmitter::for_observer(observer)?;
 
     if options.heartbeat {
         if let Some(heartbeat_config) = &observer.heartbeat {
@@ -255,12 +311,15 @@ pub async fn test_observer(options: ObserverTestOptions) -> Result<()> {
 }
 
 #[derive(Clap, Debug)]
-pub struct ObserverListOptions {}
+pub struct ObserverListOptions {
+    #[clap(flatten)]
+    labels: LabelFilterOptions,
+}
 
 pub fn list_observer(options: ObserverListOptions) -> Result<()> {
     debug!("Command 'list_pool': {:?}", options);
 
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
 
     if entities.observers.is_empty() {
         info!("No observers configured")
@@ -269,17 +328,23 @@ pub fn list_observer(options: ObserverListOptions) -> Result<()> {
             vec![
                 comfy_id_header(),
                 Cell::new("Observer Name"),
+                Cell::new("State"),
                 Cell::new("Observations"),
                 Cell::new("Heartbeat"),
             ],
-            entities.observers.iter().map(|p| {
-                vec![
-                    comfy_id_value(p.id()),
-                    comfy_name_value(p.name()),
-                    Cell::new(p.observations.len()),
-                    comfy_feature_state_cell(p.heartbeat_state()),
-                ]
-            }),
+            entities
+                .observers
+                .iter()
+                .filter(|p| options.labels.matches(&p.annotations))
+                .map(|p| {
+                    vec![
+                        comfy_id_value(p.id()),
+                        comfy_name_value(p.name()),
+                        comfy_enabled_cell(p.disabled),
+                        Cell::new(p.observations.len()),
+                        comfy_feature_state_cell(p.heartbeat_state()),
+                    ]
+                }),
         );
     }
 
@@ -294,23 +359,25 @@ pub struct ObserverDeleteOptions {
 }
 
 pub fn delete_observer(options: ObserverDeleteOptions) -> Result<()> {
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let (id, name) = {
         let observer = entity_by_name_or_id(entities.observers.iter(), &options.observer)?;
         (observer.id(), observer.name().to_owned())
     };
+    confirm_or_abort(format!("Delete observer {}?", name))?;
 
-    entities.observers.remove(
+    let observer = entities.observers.remove(
         entities
             .observers
             .iter()
             .position(|h| h.id() == id)
             .expect("id always exists"),
     );
+    entities.trash_entity(TrashedEntityKind::Observer(observer));
 
-    storage::store_entity_config(entities);
-    info!("Deleted observer '{}'", name);
+    storage::store_entity_config(entities)?;
+    info!("Deleted observer '{}', use 'undo' to restore it", name);
 
     Ok(())
 }
@@ -323,11 +390,11 @@ pub struct ObserverShowOptions {
 }
 
 pub fn show_observer(options: ObserverShowOptions) -> Result<()> {
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
 
     let observer = observer_search(&entities, &options.observer)?;
 
-    print_comfy_info(vec![
+    let mut rows = vec![
         (comfy_id_header(), comfy_id_value_full(observer.id()).into()),
         (Cell::new("Name"), comfy_name_value(observer.name()).into()),
         (Cell::new("Type"), Cell::new("healthchecks").into()),
@@ -336,7 +403,8 @@ pub fn show_observer(options: ObserverShowOptions) -> Result<()> {
             Cell::new(
                 observer
                     .custom_url
-                    .as_ref()
+                    .as_deref()
+                    .map(secrets::redacted)
                     .unwrap_or(&format!("None (using default: {})", ObservationEmitter::DEFAULT_URL)),
             )
             .into(),
@@ -358,7 +426,20 @@ pub fn show_observer(options: ObserverShowOptions) -> Result<()> {
             )
             .into(),
         ),
-    ]);
+        (
+            Cell::new("Delivery"),
+            Cell::new(format!(
+                "Timeouts {} connect, {} request; {} retries from {}",
+                humantime::Duration::from(observer.delivery.connect_timeout),
+                humantime::Duration::from(observer.delivery.request_timeout),
+                observer.delivery.retries,
+                humantime::Duration::from(observer.delivery.retry_backoff),
+            ))
+            .into(),
+        ),
+    ];
+    rows.extend(comfy_annotation_rows(&observer.annotations));
+    print_comfy_info(rows);
 
     println!();
 
@@ -1,34 +1,67 @@
 use anyhow::{bail, Context, Result};
 use clap::Clap;
-use comfy_table::Cell;
-use dialoguer::Confirm;
+use comfy_table::{Cell, Color};
 use libblkcapt::{
-    core::{BtrfsContainer, BtrfsDataset, BtrfsPool},
-    model::{entity_by_id_mut, entity_by_name_mut, entity_by_name_or_id, storage, Entity},
+    api::{self, SnapshotQuery},
+    core::{
+        adopt::SnapshotNaming,
+        boot::update_boot_menu,
+        clock::{Clock, SystemClock},
+        naming::validate_snapshot_tag,
+        BtrfsContainer, BtrfsDataset, BtrfsPool,
+    },
+    model::{
+        bundle::{recover_pool_entities, ConfigBundle, POOL_REPLICA_PATH},
+        entity_by_id_mut, entity_by_name_mut, entity_by_name_or_id,
+        history::read_scrub_history,
+        storage, Entity,
+    },
 };
 use libblkcapt::{
-    model::entities::ScheduleModel,
+    data_dir,
+    model::entities::{
+        BtrfsDatasetEntity, BtrfsPoolEntity, DedupConfig, DefragCompression, DefragConfig, LuksEncryption, NocowPolicy,
+        QuiesceConfig, RemovableDrive, SpaceGuard, TrashedEntityKind,
+    },
     sys::{
-        btrfs::{add_to_fstab, AllocationMode, Filesystem},
-        fs::{find_mountentry, BlockDeviceIds, BlockDeviceInfo, DevicePathBuf},
+        btrfs::{
+            add_mount_unit, add_to_fstab, remove_from_fstab, remove_mount_unit, AllocationMode, Filesystem,
+            ResizeTarget,
+        },
+        crypt,
+        fs::{block_device_size, find_mountentry, BlockDeviceIds, BlockDeviceInfo, DevicePathBuf, FsPathBuf},
     },
 };
 use slog_scope::*;
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use super::{dataset_search, pool_search, RetentionCreateUpdateOptions, RetentionUpdateOptions};
+use super::{
+    comfy_running_value, container_search, dataset_search, pool_search, print_snapshot_page, restic_search,
+    running_jobs, DryRunOptions, LabelFilterOptions, QuotaCreateUpdateOptions, RestoreDrillUpdateOptions,
+    RetentionCreateUpdateOptions, RetentionUpdateOptions, SnapshotNamingUpdateOptions, SnapshotQueryOptions,
+};
 use crate::ui::{
-    comfy_feature_state_cell, comfy_id_header, comfy_id_value, comfy_id_value_full, comfy_name_value, comfy_value_or,
+    comfy_annotation_rows, comfy_bytes_value, comfy_enabled_cell, comfy_feature_state_cell, comfy_id_header,
+    comfy_id_value, comfy_id_value_full, comfy_name_value, comfy_value_or, confirm_or_abort, format_bytes,
     print_comfy_info, print_comfy_table, ScheduleArg,
 };
 
 #[derive(Clap, Debug)]
-pub struct PoolListOptions {}
+pub struct PoolListOptions {
+    #[clap(flatten)]
+    labels: LabelFilterOptions,
+}
 
 pub fn list_pool(options: PoolListOptions) -> Result<()> {
     debug!("Command 'list_pool': {:?}", options);
 
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
 
     print_comfy_table(
         vec![
@@ -39,14 +72,82 @@ pub fn list_pool(options: PoolListOptions) -> Result<()> {
             Cell::new("Datasets"),
             Cell::new("Containers"),
         ],
-        entities.btrfs_pools.iter().map(|p| {
+        entities
+            .btrfs_pools
+            .iter()
+            .filter(|p| options.labels.matches(&p.annotations))
+            .map(|p| {
+                vec![
+                    comfy_id_value(p.id()),
+                    comfy_name_value(p.name()),
+                    Cell::new(p.uuid),
+                    Cell::new(p.uuid_subs.len()),
+                    Cell::new(p.datasets.len()),
+                    Cell::new(p.containers.len()),
+                ]
+            }),
+    );
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolShowOptions {
+    /// Number of recent scrubs to list
+    #[clap(long, default_value("10"), value_name("count"))]
+    scrubs: usize,
+
+    /// The pool to show
+    #[clap(value_name("pool|id"))]
+    pool: String,
+}
+
+pub fn show_pool(options: PoolShowOptions) -> Result<()> {
+    debug!("Command 'show_pool': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let pool = pool_search(&entities, &options.pool)?;
+    let mut rows = vec![
+        (comfy_id_header(), comfy_id_value_full(pool.id()).into()),
+        (Cell::new("Pool Name"), comfy_name_value(pool.name()).into()),
+        (Cell::new("Filesystem UUID"), Cell::new(pool.uuid).into()),
+        (
+            Cell::new("Mountpoint"),
+            Cell::new(pool.mountpoint_path.display()).into(),
+        ),
+        (
+            Cell::new("Scrubbing"),
+            comfy_feature_state_cell(pool.scrubbing_state()).into(),
+        ),
+    ];
+    rows.extend(comfy_annotation_rows(&pool.annotations));
+    print_comfy_info(rows);
+
+    let pool_id = pool.pool_id();
+    let mut scrubs = read_scrub_history()?
+        .into_iter()
+        .filter(|r| r.pool_id == pool_id)
+        .collect::<Vec<_>>();
+    let skip = scrubs.len().saturating_sub(options.scrubs);
+    scrubs.drain(..skip);
+    print_comfy_table(
+        vec![
+            Cell::new("Scrub Started"),
+            Cell::new("Duration"),
+            Cell::new("Corrected"),
+            Cell::new("Uncorrectable"),
+        ],
+        scrubs.into_iter().rev().map(|r| {
+            let uncorrectable = Cell::new(r.uncorrectable_errors);
             vec![
-                comfy_id_value(p.id()),
-                comfy_name_value(p.name()),
-                Cell::new(p.uuid),
-                Cell::new(p.uuid_subs.len()),
-                Cell::new(p.datasets.len()),
-                Cell::new(p.containers.len()),
+                Cell::new(r.started.format("%F %T UTC")),
+                Cell::new(humantime::format_duration(Duration::from_secs(r.duration.as_secs()))),
+                Cell::new(r.corrected_errors),
+                if r.uncorrectable_errors > 0 {
+                    uncorrectable.fg(Color::Red)
+                } else {
+                    uncorrectable
+                },
             ]
         }),
     );
@@ -76,6 +177,29 @@ pub struct PoolCreateOptions {
     #[clap(short, long)]
     mountpoint: Option<PathBuf>,
 
+    /// Mount option for the filesystem, e.g. compress=zstd, ssd or space_cache=v2. Defaults to noatime.
+    #[clap(
+        short('o'),
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("option")
+    )]
+    mount_option: Vec<String>,
+
+    /// Mount the filesystem at boot with a systemd mount unit instead of an fstab entry.
+    #[clap(long)]
+    mount_unit: bool,
+
+    /// Encrypt the devices with LUKS before creating the filesystem.
+    #[clap(long)]
+    luks: bool,
+
+    /// Existing keyfile used to unlock the LUKS volumes. A new keyfile is generated when omitted.
+    #[clap(long, value_name("path"), requires("luks"))]
+    luks_keyfile: Option<PathBuf>,
+
     /// Devices to format for the filesystem.
     #[clap(required(true))]
     devices: Vec<DevicePathBuf>,
@@ -83,7 +207,7 @@ pub struct PoolCreateOptions {
 
 pub fn create_pool(options: PoolCreateOptions) -> Result<()> {
     debug!("Command 'create_pool': {:?}", options);
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     if options.devices.is_empty() {
         bail!("at least one device is required")
@@ -121,34 +245,118 @@ pub fn create_pool(options: PoolCreateOptions) -> Result<()> {
     );
 
     println!();
-    if !options.force
-        && !Confirm::new()
-            .with_prompt("Are you sure you want to destory all data on the devices above?")
-            .interact()?
-    {
-        println!();
-        bail!("user aborted");
+    if !options.force {
+        confirm_or_abort("Are you sure you want to destory all data on the devices above?")?;
     }
 
     println!();
 
-    let filesystem = Filesystem::make(&options.devices, &options.name, options.data, options.metadata)?;
+    let (devices, luks) = if options.luks {
+        let (devices, luks) = setup_luks(&options.devices, &options.name, options.luks_keyfile.clone())?;
+        (devices, Some(luks))
+    } else {
+        (options.devices.clone(), None)
+    };
+
+    let filesystem = match Filesystem::make(&devices, &options.name, options.data, options.metadata) {
+        Ok(filesystem) => filesystem,
+        Err(error) => {
+            if let Some(luks) = luks.as_ref() {
+                teardown_luks(luks, options.luks_keyfile.is_none());
+            }
+            return Err(error);
+        }
+    };
     let mountpoint = options.mountpoint.clone().unwrap_or_else(|| {
         let mut path = PathBuf::from("/mnt");
         path.push(&options.name);
         path
     });
     std::fs::create_dir_all(&mountpoint)?;
-    let filesystem = filesystem.mount(&mountpoint)?;
-    add_to_fstab(&filesystem)?;
+    let mount_options = options
+        .mount_option
+        .iter()
+        .flat_map(|o| o.split(','))
+        .filter(|o| !o.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let filesystem = filesystem.mount(&mountpoint, &mount_options)?;
+    if options.mount_unit {
+        let unit_path = add_mount_unit(&filesystem, &mount_options)?;
+        info!("Created mount unit {}", unit_path.display());
+    } else {
+        add_to_fstab(&filesystem, &mount_options)?;
+    }
 
     let new_pool = BtrfsPool::new(options.name, mountpoint)?;
-    entities.attach_pool(new_pool.take_model())?;
+    let mut pool_model = new_pool.take_model();
+    pool_model.mount_options = mount_options;
+    pool_model.luks = luks;
+    entities.attach_pool(pool_model)?;
 
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
     Ok(())
 }
 
+fn setup_luks(
+    devices: &[DevicePathBuf], pool_name: &str, keyfile: Option<PathBuf>,
+) -> Result<(Vec<DevicePathBuf>, LuksEncryption)> {
+    let (keyfile, created_keyfile) = match keyfile {
+        // Crypttab is read at boot from another working directory, it needs the absolute path.
+        Some(keyfile) => (
+            keyfile
+                .canonicalize()
+                .with_context(|| format!("LUKS keyfile {} not found.", keyfile.display()))?,
+            false,
+        ),
+        None => {
+            let mut keyfile = data_dir();
+            keyfile.push("luks");
+            keyfile.push(format!("{}.key", pool_name));
+            crypt::create_keyfile(&keyfile)?;
+            println!(
+                "Created LUKS keyfile {}, keep a copy of it somewhere safe.",
+                keyfile.display()
+            );
+            (keyfile, true)
+        }
+    };
+
+    let mut mapped_devices = Vec::with_capacity(devices.len());
+    let mut luks = LuksEncryption {
+        keyfile,
+        luks_uuids: Vec::with_capacity(devices.len()),
+    };
+    for device in devices {
+        let result = crypt::luks_format(device, &luks.keyfile).and_then(|uuid| {
+            let mapped = crypt::luks_open(device, &uuid, &luks.keyfile)?;
+            luks.luks_uuids.push(uuid);
+            mapped_devices.push(mapped);
+            crypt::add_to_crypttab(&uuid, &luks.keyfile)
+        });
+        if let Err(error) = result {
+            teardown_luks(&luks, created_keyfile);
+            return Err(error);
+        }
+    }
+
+    Ok((mapped_devices, luks))
+}
+
+// Undoes a LUKS setup that failed part way, best effort as it only runs on an error already being reported.
+fn teardown_luks(luks: &LuksEncryption, remove_keyfile: bool) {
+    for uuid in luks.luks_uuids.iter() {
+        if let Err(error) = crypt::remove_from_crypttab(uuid).and_then(|_| crypt::luks_close(uuid)) {
+            warn!("Failed to roll back luks volume {}: {:#}", uuid, error);
+        }
+    }
+    if remove_keyfile {
+        if let Err(error) = std::fs::remove_file(&luks.keyfile) {
+            warn!("Failed to remove LUKS keyfile {}: {}", luks.keyfile.display(), error);
+        }
+    }
+}
+
 #[derive(Clap, Debug)]
 pub struct PoolAttachOptions {
     /// Existing mountpoint for the filesystem.
@@ -161,16 +369,560 @@ pub struct PoolAttachOptions {
 
 pub fn attach_pool(options: PoolAttachOptions) -> Result<()> {
     debug!("Command 'attach_pool': {:?}", options);
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let new_pool = BtrfsPool::new(options.name, options.mountpoint)?;
 
     entities.attach_pool(new_pool.take_model())?;
 
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
+    Ok(())
+}
+
+/// Recover a pool with its datasets, containers and syncs from the configuration it replicates
+#[derive(Clap, Debug)]
+pub struct PoolRecoverOptions {
+    /// Mountpoint of the pool's filesystem.
+    mountpoint: PathBuf,
+}
+
+pub fn recover_pool(options: PoolRecoverOptions) -> Result<()> {
+    debug!("Command 'recover_pool': {:?}", options);
+
+    let path = options.mountpoint.join(POOL_REPLICA_PATH);
+    let data =
+        std::fs::read(&path).with_context(|| format!("No replicated configuration found at {}.", path.display()))?;
+    let mut replica: ConfigBundle =
+        serde_json::from_slice(&data).context("Failed to parse the replicated configuration.")?;
+    replica.validate_version()?;
+    let removed = replica.remove_redacted_secrets();
+
+    let pool = replica
+        .entities
+        .btrfs_pools
+        .first_mut()
+        .context("The replicated configuration holds no pool.")?;
+    let mounted = BtrfsPool::new(pool.name().to_owned(), options.mountpoint)?.take_model();
+    if mounted.uuid != pool.uuid {
+        bail!("The replicated configuration is of another filesystem.");
+    }
+    pool.mountpoint_path = mounted.mountpoint_path;
+    pool.uuid_subs = mounted.uuid_subs;
+    let name = pool.name().to_owned();
+
+    let mut entities = storage::load_entity_config()?;
+    let (skipped, left_out) = recover_pool_entities(&mut entities, replica.entities)?;
+    storage::store_entity_config(entities)?;
+
+    for entity in skipped {
+        info!("Skipped existing {}", entity);
+    }
+    for entity in left_out {
+        warn!("Left out {}, it references pools not recovered yet", entity);
+    }
+    for location in removed {
+        warn!(
+            "The secret of {} was not replicated, set it again with 'secret set'",
+            location
+        );
+    }
+    info!(
+        "Recovered pool '{}' from its configuration replicated at {}",
+        name,
+        replica.exported.format("%F %T UTC")
+    );
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolDetachOptions {
+    /// Also remove the fstab entry or systemd mount unit of the pool. Restoring the pool doesn't add it back.
+    #[clap(long)]
+    remove_mount: bool,
+
+    /// The pool to detach
+    #[clap(value_name("pool|id"))]
+    pool: String,
+}
+
+pub fn detach_pool(options: PoolDetachOptions) -> Result<()> {
+    debug!("Command 'detach_pool': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let pool = pool_search(&entities, &options.pool)?.clone();
+
+    let dataset_ids = pool.datasets.iter().map(|d| d.dataset_id()).collect::<Vec<_>>();
+    let container_ids = pool.containers.iter().map(|c| c.container_id()).collect::<Vec<_>>();
+    if let Some(sync) = entities.snapshot_syncs.iter().find(|s| {
+        dataset_ids.contains(&s.dataset_id)
+            || s.container_ids().any(|id| container_ids.contains(&id))
+            || s.source_container_id.map_or(false, |id| container_ids.contains(&id))
+    }) {
+        bail!(
+            "Pool {} is used by sync {}. Delete the sync first.",
+            pool.name(),
+            sync.name()
+        );
+    }
+    if let Some(group) = entities
+        .dataset_groups
+        .iter()
+        .find(|g| g.dataset_ids.iter().any(|id| dataset_ids.contains(id)))
+    {
+        bail!(
+            "Pool {} is used by group {}. Delete the group first.",
+            pool.name(),
+            group.name()
+        );
+    }
+
+    confirm_or_abort(format!(
+        "Detach pool {}? Its data stays on the devices but is no longer managed.",
+        pool.name()
+    ))?;
+    if options.remove_mount {
+        if remove_from_fstab(&pool.uuid, &pool.mountpoint_path)? {
+            info!("Removed fstab entry for pool {}", pool.name());
+        }
+        if remove_mount_unit(&pool.mountpoint_path)? {
+            info!("Removed mount unit for pool {}", pool.name());
+        }
+    }
+
+    entities.btrfs_pools.retain(|p| p.id() != pool.id());
+    let name = pool.name().to_owned();
+    entities.trash_entity(TrashedEntityKind::Pool(pool));
+    storage::store_entity_config(entities)?;
+    info!("Detached pool '{}', use 'undo' to restore it", name);
+    Ok(())
+}
+
+/// Update an existing pool
+#[derive(Clap, Debug)]
+pub struct PoolUpdateOptions {
+    /// The pool lives on a drive that is not always attached
+    #[clap(long, value_name("bool"))]
+    removable: Option<bool>,
+
+    /// How often to check whether a removable pool is attached
+    #[clap(long, value_name("duration"))]
+    probe_interval: Option<humantime::Duration>,
+
+    /// Unmount a removable pool once its pending syncs are complete
+    #[clap(long, value_name("bool"))]
+    detach_after_sync: Option<bool>,
+
+    /// Power down the drive after detaching a removable pool
+    #[clap(long, value_name("bool"))]
+    power_down: Option<bool>,
+
+    /// Monitor the pool's free space
+    #[clap(long, value_name("bool"))]
+    space_guard: Option<bool>,
+
+    /// Free space percentage below which low space is reported
+    #[clap(long, value_name("percent"))]
+    space_warning: Option<u8>,
+
+    /// Free space percentage below which new snapshots and receives are refused
+    #[clap(long, value_name("percent"))]
+    space_critical: Option<u8>,
+
+    /// Snapshots to keep per dataset when pruning below the critical threshold, 0 to disable emergency pruning
+    #[clap(long, value_name("count"))]
+    emergency_keep: Option<u32>,
+
+    /// How often to check the pool's free space
+    #[clap(long, value_name("duration"))]
+    space_check_interval: Option<humantime::Duration>,
+
+    #[clap(flatten)]
+    naming: SnapshotNamingUpdateOptions,
+
+    /// Deduplicate the pool's datasets with duperemove on this schedule, never while it is scrubbed
+    #[clap(long, value_name("schedule"))]
+    dedup_schedule: Option<ScheduleArg>,
+
+    /// Subvolume path relative to the pool to deduplicate instead of every dataset, replaces the previous list
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("path")
+    )]
+    dedup_path: Vec<PathBuf>,
+
+    /// Stop deduplicating the pool
+    #[clap(long, conflicts_with_all(&["dedup-schedule", "dedup-path"]))]
+    no_dedup: bool,
+
+    /// The pool to update
+    #[clap(value_name("pool|id"))]
+    pool: String,
+}
+
+pub fn update_pool(options: PoolUpdateOptions) -> Result<()> {
+    debug!("Command 'update_pool': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let pool_id = pool_search(&entities, &options.pool)?.id();
+    let pool = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("entity exists, found in search");
+
+    match options.removable {
+        Some(false) => pool.removable = None,
+        Some(true) if pool.removable.is_none() => pool.removable = Some(RemovableDrive::default()),
+        _ => {}
+    }
+
+    if let Some(removable) = pool.removable.as_mut() {
+        if let Some(probe_interval) = options.probe_interval {
+            removable.probe_interval = *probe_interval;
+        }
+        if let Some(detach_after_sync) = options.detach_after_sync {
+            removable.detach_after_sync = detach_after_sync;
+        }
+        if let Some(power_down) = options.power_down {
+            removable.power_down = power_down;
+        }
+    } else if options.probe_interval.is_some() || options.detach_after_sync.is_some() || options.power_down.is_some() {
+        bail!("Removable drive options require a removable pool.");
+    }
+
+    match options.space_guard {
+        Some(false) => pool.space_guard = None,
+        Some(true) if pool.space_guard.is_none() => pool.space_guard = Some(SpaceGuard::default()),
+        _ => {}
+    }
+
+    if let Some(guard) = pool.space_guard.as_mut() {
+        if let Some(warning_percent) = options.space_warning {
+            guard.warning_percent = warning_percent;
+        }
+        if let Some(critical_percent) = options.space_critical {
+            guard.critical_percent = critical_percent;
+        }
+        if let Some(emergency_keep) = options.emergency_keep {
+            guard.emergency_keep = NonZeroU32::new(emergency_keep);
+        }
+        if let Some(check_interval) = options.space_check_interval {
+            guard.check_interval = *check_interval;
+        }
+        if guard.warning_percent > 100 || guard.critical_percent > guard.warning_percent {
+            bail!("Space thresholds must satisfy critical <= warning <= 100 percent.");
+        }
+    } else if options.space_warning.is_some()
+        || options.space_critical.is_some()
+        || options.emergency_keep.is_some()
+        || options.space_check_interval.is_some()
+    {
+        bail!("Space options require the space guard to be enabled.");
+    }
+
+    options.naming.update_naming(&mut pool.snapshot_naming)?;
+
+    if options.no_dedup {
+        pool.dedup = None;
+    }
+    if let Some(schedule) = options.dedup_schedule {
+        let schedule = schedule.into_schedule_model();
+        match pool.dedup.as_mut() {
+            Some(dedup) => dedup.schedule = schedule,
+            None => {
+                pool.dedup = Some(DedupConfig {
+                    schedule,
+                    paths: Vec::new(),
+                })
+            }
+        }
+    }
+    if !options.dedup_path.is_empty() {
+        if let Some(path) = options.dedup_path.iter().find(|p| p.is_absolute()) {
+            bail!("Dedup path {} must be relative to the pool.", path.display());
+        }
+        pool.dedup
+            .as_mut()
+            .context("Deduplication must be scheduled with --dedup-schedule first.")?
+            .paths = options.dedup_path.iter().map(FsPathBuf::from).collect();
+    }
+
+    storage::store_entity_config(entities)?;
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolConvertOptions {
+    /// Target allocation profile for data.
+    #[clap(long)]
+    data: Option<AllocationMode>,
+
+    /// Target allocation profile for metadata.
+    #[clap(long)]
+    metadata: Option<AllocationMode>,
+
+    /// The pool to convert
+    #[clap(value_name("pool|id"))]
+    pool: String,
+}
+
+pub async fn convert_pool(options: PoolConvertOptions) -> Result<()> {
+    debug!("Command 'convert_pool': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let pool = BtrfsPool::validate(pool_search(&entities, &options.pool)?.clone())?;
+
+    let balance = pool.convert(options.data, options.metadata)?.start()?;
+    let wait = balance.wait();
+    tokio::pin!(wait);
+    let mut progress_interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        tokio::select! {
+            result = &mut wait => break result?,
+            _ = progress_interval.tick() => {
+                if let Ok(Some(progress)) = pool.balance_status() {
+                    println!(
+                        "{} out of about {} chunks converted, {}% left",
+                        progress.balanced, progress.expected, progress.percent_left
+                    );
+                }
+            }
+        }
+    }
+
+    let profiles = pool.allocation_profiles()?;
+    println!(
+        "Conversion complete. Data: {}, metadata: {}",
+        profiles
+            .data
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        profiles
+            .metadata
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolResizeOptions {
+    /// New size of the filesystem on the device in bytes, or max to fill the device.
+    #[clap(long, value_name("bytes|max"), default_value("max"))]
+    size: ResizeTarget,
+
+    /// Do not prompt for confirmation when shrinking.
+    #[clap(long)]
+    force: bool,
+
+    /// The pool to resize
+    #[clap(value_name("pool|id"))]
+    pool: String,
+
+    /// Devices to resize, as listed by btrfs. All devices of the pool when omitted.
+    devices: Vec<DevicePathBuf>,
+}
+
+pub fn resize_pool(options: PoolResizeOptions) -> Result<()> {
+    debug!("Command 'resize_pool': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let pool = BtrfsPool::validate(pool_search(&entities, &options.pool)?.clone())?;
+
+    let before = pool.device_sizes()?;
+    let devices = if options.devices.is_empty() {
+        if let ResizeTarget::Bytes(_) = options.size {
+            bail!("An explicit size must name the device to resize.");
+        }
+        before.iter().map(|d| d.path.clone()).collect()
+    } else {
+        options.devices
+    };
+    if let ResizeTarget::Bytes(size) = options.size {
+        for device in before.iter().filter(|d| devices.contains(&d.path) && size < d.size) {
+            if !options.force {
+                confirm_or_abort(format!(
+                    "Shrink {} from {} to {}? Data beyond the new size is relocated first.",
+                    device.path,
+                    format_bytes(device.size),
+                    format_bytes(size)
+                ))?;
+            }
+        }
+    }
+
+    let mut rows = Vec::new();
+    for device in &devices {
+        let previous = before.iter().find(|d| d.path == *device).map(|d| d.size);
+        let resized = pool.resize_device(device, options.size)?;
+        let block_size = block_device_size(device)?;
+        rows.push(vec![
+            Cell::new(device),
+            Cell::new(resized.devid),
+            comfy_value_or(previous.map(format_bytes), "unknown"),
+            comfy_bytes_value(resized.size),
+            comfy_bytes_value(block_size),
+        ]);
+    }
+    print_comfy_table(
+        vec![
+            Cell::new("Device"),
+            Cell::new("Devid"),
+            Cell::new("Before"),
+            Cell::new("After"),
+            Cell::new("Block Device"),
+        ],
+        rows.into_iter(),
+    );
+
+    let usage = pool.space_usage()?;
+    print_comfy_info(vec![
+        (Cell::new("Pool Size"), comfy_bytes_value(usage.size).into()),
+        (Cell::new("Data Capacity"), comfy_bytes_value(usage.capacity).into()),
+        (
+            Cell::new("Free (estimated)"),
+            Cell::new(format!("{} ({}%)", format_bytes(usage.free), usage.free_percent())).into(),
+        ),
+    ]);
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolDeviceAddOptions {
+    /// Do not rebalance existing data across the devices afterwards.
+    #[clap(long)]
+    no_balance: bool,
+
+    /// Do not prompt for confirmation.
+    #[clap(long)]
+    force: bool,
+
+    /// The pool to add the device to
+    #[clap(value_name("pool|id"))]
+    pool: String,
+
+    /// Device to format and add to the pool.
+    device: DevicePathBuf,
+}
+
+pub fn add_pool_device(options: PoolDeviceAddOptions) -> Result<()> {
+    debug!("Command 'add_pool_device': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let pool_model = pool_search(&entities, &options.pool)?;
+    let mut pool = BtrfsPool::validate(pool_model.clone())?;
+
+    confirm_device_format(&options.device, options.force)?;
+    let device = prepare_pool_device(pool.model(), &options.device)?;
+    pool.add_device(&device, !options.no_balance)?;
+
+    let pool_model = pool.take_model();
+    *entity_by_id_mut(&mut entities.btrfs_pools, pool_model.id()).expect("entity exists, found in search") = pool_model;
+    storage::store_entity_config(entities)?;
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolDeviceRemoveOptions {
+    /// The pool to remove the device from
+    #[clap(value_name("pool|id"))]
+    pool: String,
+
+    /// Device to remove from the pool, as listed by btrfs.
+    device: DevicePathBuf,
+}
+
+pub fn remove_pool_device(options: PoolDeviceRemoveOptions) -> Result<()> {
+    debug!("Command 'remove_pool_device': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let pool_model = pool_search(&entities, &options.pool)?;
+    let mut pool = BtrfsPool::validate(pool_model.clone())?;
+
+    confirm_or_abort(format!(
+        "Remove {} from pool {}? Its data is moved to the remaining devices.",
+        options.device,
+        pool.model().name()
+    ))?;
+    pool.remove_device(&options.device)?;
+
+    let pool_model = pool.take_model();
+    *entity_by_id_mut(&mut entities.btrfs_pools, pool_model.id()).expect("entity exists, found in search") = pool_model;
+    storage::store_entity_config(entities)?;
     Ok(())
 }
 
+#[derive(Clap, Debug)]
+pub struct PoolDeviceReplaceOptions {
+    /// Do not prompt for confirmation.
+    #[clap(long)]
+    force: bool,
+
+    /// The pool containing the device
+    #[clap(value_name("pool|id"))]
+    pool: String,
+
+    /// Device to replace, as listed by btrfs.
+    source: DevicePathBuf,
+
+    /// Device to format and use in place of the source device.
+    target: DevicePathBuf,
+}
+
+pub fn replace_pool_device(options: PoolDeviceReplaceOptions) -> Result<()> {
+    debug!("Command 'replace_pool_device': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let pool_model = pool_search(&entities, &options.pool)?;
+    let mut pool = BtrfsPool::validate(pool_model.clone())?;
+
+    let source_size = block_device_size(&options.source)?;
+    let target_size = block_device_size(&options.target)?;
+    if target_size < source_size {
+        bail!(
+            "Target device {} is smaller than the device {} it would replace.",
+            options.target,
+            options.source
+        );
+    }
+
+    confirm_device_format(&options.target, options.force)?;
+    let target = prepare_pool_device(pool.model(), &options.target)?;
+    pool.replace_device(&options.source, &target)?;
+    if target_size > source_size {
+        info!("Resized the filesystem to use all of {}.", target);
+    }
+
+    let pool_model = pool.take_model();
+    *entity_by_id_mut(&mut entities.btrfs_pools, pool_model.id()).expect("entity exists, found in search") = pool_model;
+    storage::store_entity_config(entities)?;
+    Ok(())
+}
+
+fn confirm_device_format(device: &DevicePathBuf, force: bool) -> Result<()> {
+    if !force {
+        confirm_or_abort(format!("Are you sure you want to destroy all data on {}?", device))?;
+    }
+    Ok(())
+}
+
+/// Devices of encrypted pools are formatted with LUKS first, the mapped device is what joins the filesystem.
+fn prepare_pool_device(pool_model: &BtrfsPoolEntity, device: &DevicePathBuf) -> Result<DevicePathBuf> {
+    match &pool_model.luks {
+        Some(luks) => {
+            let uuid = crypt::luks_format(device, &luks.keyfile)?;
+            let mapped = crypt::luks_open(device, &uuid, &luks.keyfile)?;
+            crypt::add_to_crypttab(&uuid, &luks.keyfile)?;
+            Ok(mapped)
+        }
+        None => Ok(device.clone()),
+    }
+}
+
 #[derive(Clap, Debug)]
 pub struct DatasetAttachOptions {
     /// Existing path to subvolume to attach to.
@@ -178,12 +930,26 @@ pub struct DatasetAttachOptions {
 
     /// Name of the dataset. [default: path basename]
     name: Option<String>,
+
+    /// btrfs property to set on the dataset subvolume
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("name=value")
+    )]
+    property: Vec<String>,
+
+    /// Adopt existing snapshots of the subvolume made by snapper or btrbk into the dataset
+    #[clap(long, value_name("naming"), possible_values(&["snapper", "btrbk"]))]
+    adopt_snapshots: Option<SnapshotNaming>,
 }
 
 pub fn attach_dataset(options: DatasetAttachOptions) -> Result<()> {
     debug!("Command 'attach_dataset': {:?}", options);
 
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let mountentry =
         find_mountentry(&options.path).context(format!("Failed to detect mountpoint for {:?}.", options.path))?;
@@ -200,10 +966,37 @@ pub fn attach_dataset(options: DatasetAttachOptions) -> Result<()> {
     });
 
     let pool = Arc::new(BtrfsPool::validate(pool_model.clone())?);
-    let dataset = BtrfsDataset::new(&pool, name, options.path)?;
+    let mut dataset = BtrfsDataset::new(&pool, name, options.path)?;
+    let mut properties = BTreeMap::new();
+    update_properties(&options.property, &mut properties)?;
+    dataset.set_properties(properties)?;
+
+    if let Some(naming) = options.adopt_snapshots {
+        let adopted = dataset.adopt_snapshots(naming)?;
+        info!("Adopted {} existing {} snapshots", adopted.len(), naming);
+    }
+
+    let hazards = dataset.cow_hazards()?;
+    for swapfile in &hazards.swapfiles {
+        warn!(
+            "{:?} is an active swapfile, btrfs refuses to snapshot the dataset until it is moved to a subvolume of its \
+             own.",
+            swapfile
+        );
+    }
+    if !hazards.nocow.is_empty() {
+        warn!(
+            "The dataset contains {} paths without copy-on-write, they aren't checksummed and may be stored in full \
+             by every snapshot and backup. Move them to a nested subvolume to leave them out of snapshots.",
+            hazards.nocow.len()
+        );
+        for path in &hazards.nocow {
+            warn!("No copy-on-write: {:?}", path);
+        }
+    }
 
     pool_model.attach_dataset(dataset.take_model())?;
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
 
     Ok(())
 }
@@ -223,22 +1016,90 @@ pub struct DatasetCreateOptions {
 pub fn create_dataset(options: DatasetCreateOptions) -> Result<()> {
     debug!("Command 'create_dataset': {:?}", options);
 
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
     let pool_id = pool_search(&entities, &options.pool)?.id();
     let pool_model = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("always exists if path found");
 
     let pool = Arc::new(BtrfsPool::validate(pool_model.clone())?);
-    let dataset = pool.create_dataset(options.name)?;
+    let mut dataset = pool.create_dataset(options.name)?;
+    let mut properties = BTreeMap::new();
+    update_properties(&options.shared.property, &mut properties)?;
+    dataset.set_properties(properties)?;
 
     let mut dataset = dataset.take_model();
-    options.shared.update_snapshots(&mut dataset.snapshot_schedule);
+    options.shared.update_snapshots(&mut dataset);
+    options.shared.update_quiesce(&mut dataset.quiesce)?;
     options
         .shared
         .retention
         .update_retention(&mut dataset.snapshot_retention);
+    options.shared.retention.update_prune_hooks(&mut dataset.prune_hooks);
+    options.shared.quota.update_quota(&mut dataset.quota);
+    options.shared.naming.update_naming(&mut dataset.snapshot_naming)?;
 
     pool_model.attach_dataset(dataset)?;
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetDiscoverOptions {
+    /// Attach every discovered subvolume as a dataset.
+    #[clap(long)]
+    attach: bool,
+
+    /// Snapshot schedule for the attached datasets
+    #[clap(short('s'), long, value_name("cron"), requires("attach"))]
+    snapshot_schedule: Option<ScheduleArg>,
+
+    /// The pool to search for subvolumes
+    #[clap(value_name("pool|id"))]
+    pool: String,
+}
+
+pub fn discover_dataset(options: DatasetDiscoverOptions) -> Result<()> {
+    debug!("Command 'discover_dataset': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let pool_id = pool_search(&entities, &options.pool)?.id();
+    let pool_model = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("entity exists, found in search");
+
+    let pool = Arc::new(BtrfsPool::validate(pool_model.clone())?);
+    let subvolumes = pool.unattached_subvolumes()?;
+    if subvolumes.is_empty() {
+        println!("No unattached subvolumes found in pool {}.", pool_model.name());
+        return Ok(());
+    }
+
+    print_comfy_table(
+        vec![Cell::new("Subvolume Path"), Cell::new("Subvolume UUID")],
+        subvolumes.iter().map(|s| {
+            vec![
+                comfy_name_value(s.path.as_pathbuf(Path::new("/")).display()),
+                Cell::new(s.uuid),
+            ]
+        }),
+    );
+
+    if !options.attach {
+        return Ok(());
+    }
+
+    for subvolume in subvolumes {
+        let name = subvolume
+            .path
+            .file_name()
+            .expect("subvolume paths always end with a name")
+            .to_string_lossy()
+            .to_string();
+        let mut dataset =
+            BtrfsDataset::new(&pool, name, subvolume.path.as_pathbuf(&pool_model.mountpoint_path))?.take_model();
+        dataset.snapshot_schedule = options.snapshot_schedule.clone().map(|s| s.into());
+        pool_model.attach_dataset(dataset)?;
+    }
+    storage::store_entity_config(entities)?;
+    info!("Attached the discovered subvolumes as datasets");
 
     Ok(())
 }
@@ -253,10 +1114,32 @@ pub struct DatasetShowOptions {
 pub fn show_dataset(options: DatasetShowOptions) -> Result<()> {
     debug!("Command 'show_dataset': {:?}", options);
 
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
     let dataset = dataset_search(&entities, &options.dataset)?;
 
-    print_comfy_info(vec![
+    let drift = if dataset.entity.properties.is_empty() {
+        Vec::new()
+    } else {
+        let pool = Arc::new(BtrfsPool::validate(dataset.parent.clone())?);
+        BtrfsDataset::validate(&pool, dataset.entity.clone())?.property_drift()?
+    };
+    let properties = dataset
+        .entity
+        .properties
+        .iter()
+        .map(|(name, value)| match drift.iter().find(|d| &d.name == name) {
+            Some(d) => Cell::new(format!(
+                "{}={} (actual: {})",
+                name,
+                value,
+                d.actual.as_deref().unwrap_or("unset")
+            ))
+            .fg(Color::Red),
+            None => Cell::new(format!("{}={}", name, value)),
+        })
+        .collect::<Vec<_>>();
+
+    let mut rows = vec![
         (comfy_id_header(), comfy_id_value_full(dataset.id()).into()),
         (Cell::new("Pool Name"), comfy_name_value(dataset.name()).into()),
         (Cell::new("Dataset Name"), comfy_name_value(dataset.name()).into()),
@@ -264,36 +1147,168 @@ pub fn show_dataset(options: DatasetShowOptions) -> Result<()> {
             Cell::new("Snaps"),
             vec![Cell::new("Test1"), Cell::new("Test2"), Cell::new("Test5")].into(),
         ),
-    ]);
+        (Cell::new("Properties"), properties.into()),
+    ];
+    rows.extend(comfy_annotation_rows(&dataset.entity.annotations));
+    print_comfy_info(rows);
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetSnapshotsOptions {
+    #[clap(flatten)]
+    query: SnapshotQueryOptions,
+
+    /// The dataset to list the snapshots of
+    #[clap(value_name("[pool/]dataset|id"))]
+    dataset: String,
+}
+
+pub async fn list_dataset_snapshots(options: DatasetSnapshotsOptions) -> Result<()> {
+    debug!("Command 'list_dataset_snapshots': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let dataset_id = dataset_search(&entities, &options.dataset)?.entity.dataset_id();
+    let query = options.query.query(None);
+    // Listed by the service when it runs, without it the snapshots are listed here.
+    let page = match api::connect_service().await {
+        Ok(client) => api::service_dataset_snapshots(&client, dataset_id, &query).await?,
+        Err(_) => api::query_dataset_snapshots(&entities, dataset_id, &query)?,
+    };
+    print_snapshot_page(&entities, &page, query.offset);
 
     Ok(())
 }
 
+/// Take snapshots of datasets now, returning once they are taken
 #[derive(Clap, Debug)]
-pub struct DatasetListOptions {}
+pub struct DatasetSnapshotOptions {
+    /// Take tagged snapshots, kept apart from the scheduled ones and pruned to the number kept of the tag
+    #[clap(long, value_name("tag"))]
+    tag: Option<String>,
 
-pub fn list_dataset(options: DatasetListOptions) -> Result<()> {
+    /// The datasets to snapshot, with a tag every dataset keeping snapshots of the tag by default
+    #[clap(value_name("[pool/]dataset|id"))]
+    datasets: Vec<String>,
+}
+
+pub fn snapshot_dataset(options: DatasetSnapshotOptions) -> Result<()> {
+    debug!("Command 'snapshot_dataset': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let tag = options.tag.as_deref();
+    let datasets = match tag {
+        Some(tag) if options.datasets.is_empty() => {
+            validate_snapshot_tag(tag)?;
+            let datasets = entities
+                .datasets()
+                .filter(|d| !d.entity.disabled && d.entity.tagged_retention.contains_key(tag))
+                .collect::<Vec<_>>();
+            if datasets.is_empty() {
+                info!("No dataset keeps {} snapshots, none taken", tag);
+            }
+            datasets
+        }
+        None if options.datasets.is_empty() => bail!("A dataset to snapshot is required without --tag."),
+        _ => options
+            .datasets
+            .iter()
+            .map(|d| dataset_search(&entities, d))
+            .collect::<Result<Vec<_>>>()?,
+    };
+
+    let mut failed = 0;
+    for dataset_path in datasets {
+        let result = BtrfsPool::validate(dataset_path.parent.clone()).and_then(|pool| {
+            let dataset = Arc::new(BtrfsDataset::validate(&Arc::new(pool), dataset_path.entity.clone())?);
+            match tag {
+                Some(tag) => {
+                    let snapshot = dataset.create_tagged_snapshot(tag)?;
+                    let pruned = dataset.prune_tagged_snapshots(tag)?;
+                    info!(
+                        "Snapshot {} taken, {} older {} snapshots pruned",
+                        snapshot.canonical_path().display(),
+                        pruned.len(),
+                        tag
+                    );
+                }
+                None => {
+                    let snapshot = dataset.create_local_snapshot()?;
+                    info!("Snapshot {} taken", snapshot.canonical_path().display());
+                }
+            }
+            if dataset.model().os_root {
+                update_boot_menu(&dataset)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to snapshot dataset '{}': {:#}", dataset_path.entity.name(), e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("Failed to snapshot {} datasets.", failed);
+    }
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetListOptions {
+    #[clap(flatten)]
+    labels: LabelFilterOptions,
+}
+
+pub async fn list_dataset(options: DatasetListOptions) -> Result<()> {
     debug!("Command 'list_dataset': {:?}", options);
 
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
+    let running = running_jobs().await;
+    let now = SystemClock.now();
 
     print_comfy_table(
         vec![
             comfy_id_header(),
             Cell::new("Pool Name"),
             Cell::new("Dataset Name"),
+            Cell::new("State"),
             Cell::new("Snapshotting"),
             Cell::new("Pruning"),
+            Cell::new("Last Snapshot"),
+            Cell::new("Next Snapshot"),
+            Cell::new("Job"),
         ],
-        entities.datasets().map(|ds| {
-            vec![
-                comfy_id_value(ds.entity.id()),
-                comfy_name_value(ds.parent.name()),
-                comfy_name_value(ds.entity.name()),
-                comfy_feature_state_cell(ds.entity.snapshotting_state()),
-                comfy_feature_state_cell(ds.entity.pruning_state()),
-            ]
-        }),
+        entities
+            .datasets()
+            .filter(|ds| options.labels.matches(&ds.entity.annotations))
+            .map(|ds| {
+                let last_snapshot = match api::dataset_snapshots(&entities, ds.entity.dataset_id()) {
+                    Ok(snapshots) => snapshots.last().map(|s| s.datetime),
+                    Err(e) => {
+                        debug!("failed to list snapshots of {}: {}", ds.entity.name(), e);
+                        None
+                    }
+                };
+                let next_snapshot = ds
+                    .entity
+                    .snapshot_schedule
+                    .as_ref()
+                    .filter(|_| !ds.entity.pause_snapshotting && !ds.entity.disabled)
+                    .and_then(|s| s.next_after(now).ok().flatten());
+                vec![
+                    comfy_id_value(ds.entity.id()),
+                    comfy_name_value(ds.parent.name()),
+                    comfy_name_value(ds.entity.name()),
+                    comfy_enabled_cell(ds.entity.disabled),
+                    comfy_feature_state_cell(ds.entity.snapshotting_state()),
+                    comfy_feature_state_cell(ds.entity.pruning_state()),
+                    comfy_value_or(last_snapshot, "-"),
+                    comfy_value_or(next_snapshot, "-"),
+                    comfy_running_value(running.as_ref(), ds.entity.id()),
+                ]
+            }),
     );
 
     Ok(())
@@ -305,16 +1320,133 @@ pub struct DatasetCreateUpdateOptions {
     #[clap(short('s'), long, value_name("cron"))]
     snapshot_schedule: Option<ScheduleArg>,
 
+    /// Skip creating a snapshot when nothing changed since the latest one
+    #[clap(long, value_name("bool"))]
+    skip_if_unchanged: Option<bool>,
+
+    /// Also snapshot subvolumes nested inside the dataset
+    #[clap(long, value_name("bool"))]
+    recursive: Option<bool>,
+
+    /// Whether to still snapshot the dataset while it contains files with copy-on-write disabled
+    #[clap(long, value_name("warn|refuse"))]
+    nocow: Option<NocowPolicy>,
+
+    /// Quiesce a database while snapshots of this dataset are taken
+    #[clap(long, possible_values(&["none", "postgres", "mysql"]))]
+    quiesce: Option<String>,
+
+    /// Run the database client as this system user
+    #[clap(long, value_name("user"), requires("quiesce"))]
+    quiesce_run_as: Option<String>,
+
+    /// Database user to connect as
+    #[clap(long, value_name("user"), requires("quiesce"))]
+    quiesce_user: Option<String>,
+
+    /// Postgres host or socket directory, or the mysql socket path
+    #[clap(long, value_name("path"), requires("quiesce"))]
+    quiesce_socket: Option<String>,
+
+    /// Postgres database to connect to
+    #[clap(long, value_name("name"), requires("quiesce"))]
+    quiesce_database: Option<String>,
+
+    /// btrfs property to set on the dataset subvolume, an empty value stops managing it
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("name=value")
+    )]
+    property: Vec<String>,
+
     #[clap(flatten)]
     retention: RetentionCreateUpdateOptions,
+
+    #[clap(flatten)]
+    quota: QuotaCreateUpdateOptions,
+
+    #[clap(flatten)]
+    naming: SnapshotNamingUpdateOptions,
 }
 
 impl DatasetCreateUpdateOptions {
-    fn update_snapshots(&self, schedule: &mut Option<ScheduleModel>) {
+    fn update_snapshots(&self, dataset: &mut BtrfsDatasetEntity) {
         if self.snapshot_schedule.is_some() {
-            *schedule = self.snapshot_schedule.clone().map(|s| s.into());
+            dataset.snapshot_schedule = self.snapshot_schedule.clone().map(|s| s.into());
+        }
+        if let Some(skip_if_unchanged) = self.skip_if_unchanged {
+            dataset.skip_if_unchanged = skip_if_unchanged;
+        }
+        if let Some(recursive) = self.recursive {
+            dataset.recursive = recursive;
+        }
+        if let Some(nocow) = self.nocow {
+            dataset.nocow = nocow;
+        }
+    }
+
+    fn update_quiesce(&self, quiesce: &mut Option<QuiesceConfig>) -> Result<()> {
+        *quiesce = match self.quiesce.as_deref() {
+            None => return Ok(()),
+            Some("postgres") => Some(QuiesceConfig::Postgres {
+                run_as: self.quiesce_run_as.clone(),
+                host: self.quiesce_socket.clone(),
+                port: None,
+                user: self.quiesce_user.clone(),
+                database: self.quiesce_database.clone(),
+            }),
+            Some("mysql") => Some(QuiesceConfig::Mysql {
+                run_as: self.quiesce_run_as.clone(),
+                socket: self
+                    .quiesce_socket
+                    .as_ref()
+                    .map(PathBuf::from)
+                    .context("A socket path is required to quiesce mysql.")?,
+                user: self.quiesce_user.clone(),
+            }),
+            Some(_) => None,
+        };
+        Ok(())
+    }
+}
+
+/// Applies `name=value` property arguments to the configured properties, returns whether any were given.
+fn update_properties(args: &[String], properties: &mut BTreeMap<String, String>) -> Result<bool> {
+    for arg in args {
+        let parts: Vec<_> = arg.splitn(2, '=').collect();
+        match parts.as_slice() {
+            [name, ""] => {
+                properties.remove(*name);
+            }
+            [name, value] if !name.is_empty() => {
+                properties.insert(name.to_string(), value.to_string());
+            }
+            _ => bail!("property definitions must be in the form name=value"),
         }
     }
+    Ok(!args.is_empty())
+}
+
+/// Applies `tag=count` arguments to the numbers of tagged snapshots kept, an empty count stops keeping the tag.
+fn update_tagged_retention(args: &[String], retention: &mut BTreeMap<String, NonZeroU32>) -> Result<()> {
+    for arg in args {
+        let (tag, count) = arg
+            .split_once('=')
+            .context("Tagged snapshot counts must be in the form tag=count.")?;
+        validate_snapshot_tag(tag)?;
+        if count.is_empty() {
+            retention.remove(tag);
+        } else {
+            let count = count
+                .parse()
+                .with_context(|| format!("Invalid number of {} snapshots to keep.", tag))?;
+            retention.insert(tag.to_owned(), count);
+        }
+    }
+    Ok(())
 }
 
 const AFTER_HELP: &str = r"RETENTION
@@ -339,6 +1471,52 @@ pub struct DatasetUpdateOptions {
     #[clap(flatten)]
     retention_update: RetentionUpdateOptions,
 
+    /// Defragment the dataset on this schedule, unsharing extents with its snapshots and reflinked copies
+    #[clap(long, value_name("schedule"))]
+    defrag_schedule: Option<ScheduleArg>,
+
+    /// Rewrite extents smaller than this many bytes when defragmenting
+    #[clap(long, value_name("bytes"))]
+    defrag_target_extent_size: Option<u64>,
+
+    /// Compress extents rewritten by defragmentation
+    #[clap(long, value_name("zlib|lzo|zstd"))]
+    defrag_compression: Option<DefragCompression>,
+
+    /// Path relative to the dataset to leave out of defragmentation, replaces the previous list
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("path")
+    )]
+    defrag_exclude: Vec<PathBuf>,
+
+    /// Stop defragmenting the dataset
+    #[clap(
+        long,
+        conflicts_with_all(&["defrag-schedule", "defrag-target-extent-size", "defrag-compression", "defrag-exclude"])
+    )]
+    no_defrag: bool,
+
+    /// Number of tagged snapshots kept of a tag, such as pre-update, an empty count stops keeping the tag
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("tag=count")
+    )]
+    keep_tagged: Vec<String>,
+
+    /// Whether the dataset is the root filesystem of the OS, allowing rollbacks to its snapshots
+    #[clap(long, value_name("bool"))]
+    os_root: Option<bool>,
+
+    #[clap(flatten)]
+    dry_run: DryRunOptions,
+
     /// The dataset to update
     #[clap(value_name("[pool/]dataset|id"))]
     dataset: String,
@@ -347,7 +1525,7 @@ pub struct DatasetUpdateOptions {
 pub fn update_dataset(options: DatasetUpdateOptions) -> Result<()> {
     debug!("Command 'update_dataset': {:?}", options);
 
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let parts = options.dataset.splitn(2, '/').collect::<Vec<_>>();
     let dataset = if parts.len() == 2 {
@@ -361,20 +1539,90 @@ pub fn update_dataset(options: DatasetUpdateOptions) -> Result<()> {
             entity_by_id_mut(&mut entities.btrfs_pools, dataset_path.parent).expect("always exists if path found");
         entity_by_id_mut(&mut filesystem.datasets, dataset_path.entity).expect("always exists if path found")
     };
+    let previous = dataset.clone();
 
-    options.shared.update_snapshots(&mut dataset.snapshot_schedule);
+    options.shared.update_snapshots(dataset);
+    options.shared.update_quiesce(&mut dataset.quiesce)?;
+    let properties_updated = update_properties(&options.shared.property, &mut dataset.properties)?;
 
     if options.pause_snapshotting || options.resume_snapshotting {
         dataset.pause_snapshotting = options.pause_snapshotting
     }
 
     options.retention_update.update_pruning(&mut dataset.pause_pruning);
+    options.retention_update.clear_prune_hooks(&mut dataset.prune_hooks);
     options
         .shared
         .retention
         .update_retention(&mut dataset.snapshot_retention);
+    options.shared.retention.update_prune_hooks(&mut dataset.prune_hooks);
+    options.shared.quota.update_quota(&mut dataset.quota);
+    options.shared.naming.update_naming(&mut dataset.snapshot_naming)?;
+    update_tagged_retention(&options.keep_tagged, &mut dataset.tagged_retention)?;
+    if let Some(os_root) = options.os_root {
+        dataset.os_root = os_root;
+        if os_root {
+            info!("Run grub-mkconfig after the next snapshot to add the snapshots of the dataset to the boot menu");
+        }
+    }
 
-    storage::store_entity_config(entities);
+    if options.no_defrag {
+        dataset.defrag = None;
+    }
+    if let Some(schedule) = options.defrag_schedule {
+        let schedule = schedule.into_schedule_model();
+        match dataset.defrag.as_mut() {
+            Some(defrag) => defrag.schedule = schedule,
+            None => {
+                if !options.dry_run.is_dry_run() {
+                    confirm_or_abort(format!(
+                        "Defragmenting {} rewrites extents shared with its snapshots and reflinked copies, each \
+                         keeping its own copy of the data until pruned. Enable defragmentation?",
+                        dataset.name()
+                    ))?;
+                }
+                dataset.defrag = Some(DefragConfig {
+                    schedule,
+                    target_extent_size: None,
+                    compression: None,
+                    exclude: Vec::new(),
+                })
+            }
+        }
+    }
+    if options.defrag_target_extent_size.is_some()
+        || options.defrag_compression.is_some()
+        || !options.defrag_exclude.is_empty()
+    {
+        if let Some(path) = options.defrag_exclude.iter().find(|p| p.is_absolute()) {
+            bail!("Defrag exclusion {} must be relative to the dataset.", path.display());
+        }
+        let defrag = dataset
+            .defrag
+            .as_mut()
+            .context("Defragmentation must be scheduled with --defrag-schedule first.")?;
+        if let Some(size) = options.defrag_target_extent_size {
+            defrag.target_extent_size = Some(size);
+        }
+        if let Some(compression) = options.defrag_compression {
+            defrag.compression = Some(compression);
+        }
+        if !options.defrag_exclude.is_empty() {
+            defrag.exclude = options.defrag_exclude;
+        }
+    }
+
+    if options.dry_run.preview(&previous, dataset)? {
+        return Ok(());
+    }
+    if properties_updated {
+        let dataset_id = dataset.dataset_id();
+        let dataset_path = entities.dataset(dataset_id).expect("dataset exists, found in search");
+        let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+        BtrfsDataset::validate(&pool, dataset_path.entity.clone())?.apply_properties()?;
+    }
+
+    storage::store_entity_config(entities)?;
 
     Ok(())
 }
@@ -383,6 +1631,12 @@ pub fn update_dataset(options: DatasetUpdateOptions) -> Result<()> {
 pub struct ContainerCreateUpdateOptions {
     #[clap(flatten)]
     retention: RetentionCreateUpdateOptions,
+
+    #[clap(flatten)]
+    quota: QuotaCreateUpdateOptions,
+
+    #[clap(flatten)]
+    restore_drill: RestoreDrillUpdateOptions,
 }
 
 #[derive(Clap, Debug)]
@@ -397,7 +1651,7 @@ pub struct ContainerAttachOptions {
 pub fn attach_container(options: ContainerAttachOptions) -> Result<()> {
     debug!("Command 'attach_container': {:?}", options);
 
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let mountentry =
         find_mountentry(&options.path).context(format!("Failed to detect mountpoint for {:?}.", options.path))?;
@@ -421,7 +1675,7 @@ pub fn attach_container(options: ContainerAttachOptions) -> Result<()> {
         .context(format!("No pool found for mountpoint {:?}.", mountentry.file))?;
 
     pool.attach_container(container.take_model())?;
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
 
     Ok(())
 }
@@ -441,7 +1695,7 @@ pub struct ContainerCreateOptions {
 pub fn create_container(options: ContainerCreateOptions) -> Result<()> {
     debug!("Command 'create_container': {:?}", options);
 
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
     let pool_id = pool_search(&entities, &options.pool)?.id();
     let pool_model = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("always exists if path found");
 
@@ -452,34 +1706,213 @@ pub fn create_container(options: ContainerCreateOptions) -> Result<()> {
         .shared
         .retention
         .update_retention(&mut container.snapshot_retention);
+    options.shared.retention.update_prune_hooks(&mut container.prune_hooks);
+    options.shared.quota.update_quota(&mut container.quota);
+    options
+        .shared
+        .restore_drill
+        .update_restore_drill(&mut container.restore_drill)?;
 
     pool_model.attach_container(container)?;
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
+
+    Ok(())
+}
+
+/// Update an existing container
+#[derive(Clap, Debug)]
+pub struct ContainerUpdateOptions {
+    #[clap(flatten)]
+    shared: ContainerCreateUpdateOptions,
+
+    #[clap(flatten)]
+    retention_update: RetentionUpdateOptions,
+
+    #[clap(flatten)]
+    dry_run: DryRunOptions,
+
+    /// The container to update
+    #[clap(value_name("[pool/]container|id"))]
+    container: String,
+}
+
+pub fn update_container(options: ContainerUpdateOptions) -> Result<()> {
+    debug!("Command 'update_container': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let container_path = container_search(&entities, &options.container)?.into_id_path();
+    let pool = entity_by_id_mut(&mut entities.btrfs_pools, container_path.parent).expect("always exists if path found");
+    let container = entity_by_id_mut(&mut pool.containers, container_path.entity).expect("always exists if path found");
+    let previous = container.clone();
+
+    options.retention_update.update_pruning(&mut container.pause_pruning);
+    options.retention_update.clear_prune_hooks(&mut container.prune_hooks);
+    options
+        .shared
+        .retention
+        .update_retention(&mut container.snapshot_retention);
+    options.shared.retention.update_prune_hooks(&mut container.prune_hooks);
+    options.shared.quota.update_quota(&mut container.quota);
+    options
+        .shared
+        .restore_drill
+        .update_restore_drill(&mut container.restore_drill)?;
+
+    if options.dry_run.preview(&previous, container)? {
+        return Ok(());
+    }
+    storage::store_entity_config(entities)?;
 
     Ok(())
 }
 
 #[derive(Clap, Debug)]
-pub struct ContainerListOptions {}
+pub struct ContainerListOptions {
+    #[clap(flatten)]
+    labels: LabelFilterOptions,
+}
 
 pub fn list_container(options: ContainerListOptions) -> Result<()> {
     debug!("Command 'list_container': {:?}", options);
 
-    let entities = storage::load_entity_config();
+    let entities = storage::load_entity_config()?;
 
     print_comfy_table(
         vec![
             comfy_id_header(),
             Cell::new("Pool Name"),
             Cell::new("Container Name"),
+            Cell::new("State"),
             Cell::new("Pruning"),
         ],
-        entities.containers().map(|c| {
+        entities
+            .containers()
+            .filter(|c| options.labels.matches(&c.entity.annotations))
+            .map(|c| {
+                vec![
+                    comfy_id_value(c.entity.id()),
+                    comfy_name_value(c.parent.name()),
+                    comfy_name_value(c.entity.name()),
+                    comfy_enabled_cell(c.entity.disabled),
+                    comfy_feature_state_cell(c.entity.pruning_state()),
+                ]
+            }),
+    );
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ContainerSnapshotsOptions {
+    /// Only list the snapshots of this dataset
+    #[clap(long, value_name("[pool/]dataset|id"))]
+    dataset: Option<String>,
+
+    #[clap(flatten)]
+    query: SnapshotQueryOptions,
+
+    /// The btrfs or restic container to list the snapshots held by
+    #[clap(value_name("[pool/]container|restic|id"))]
+    container: String,
+}
+
+pub async fn list_container_snapshots(options: ContainerSnapshotsOptions) -> Result<()> {
+    debug!("Command 'list_container_snapshots': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let container_id = container_search(&entities, &options.container)
+        .map(|c| c.entity.container_id())
+        .or_else(|_| restic_search(&entities, &options.container).map(|r| r.container_id()))?;
+    let dataset_id = options
+        .dataset
+        .as_ref()
+        .map(|d| dataset_search(&entities, d).map(|d| d.entity.dataset_id()))
+        .transpose()?;
+    let query = options.query.query(dataset_id);
+    // Listed by the service when it runs, without it the snapshots are listed here.
+    let page = match api::connect_service().await {
+        Ok(client) => api::service_container_snapshots(&client, container_id, &query).await?,
+        Err(_) => api::query_container_snapshots(&entities, container_id, &query).await?,
+    };
+    print_snapshot_page(&entities, &page, query.offset);
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ContainerShowOptions {
+    /// The btrfs or restic container to show
+    #[clap(value_name("[pool/]container|restic|id"))]
+    container: String,
+}
+
+pub async fn show_container(options: ContainerShowOptions) -> Result<()> {
+    debug!("Command 'show_container': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let (container_id, pool_name, annotations) = match container_search(&entities, &options.container) {
+        Ok(c) => (
+            c.entity.container_id(),
+            Some(c.parent.name().to_owned()),
+            &c.entity.annotations,
+        ),
+        Err(_) => {
+            let restic = restic_search(&entities, &options.container)?;
+            (restic.container_id(), None, &restic.annotations)
+        }
+    };
+    let container = entities
+        .entity(container_id.into())
+        .expect("entity exists, found in search");
+    let mut rows = vec![
+        (comfy_id_header(), comfy_id_value_full(container.id()).into()),
+        (Cell::new("Container Name"), comfy_name_value(container.name()).into()),
+        (
+            Cell::new("Type"),
+            Cell::new(if pool_name.is_some() { "btrfs" } else { "restic" }).into(),
+        ),
+        (Cell::new("Pool Name"), comfy_value_or(pool_name.as_ref(), "-").into()),
+    ];
+    rows.extend(comfy_annotation_rows(annotations));
+    print_comfy_info(rows);
+
+    let page = api::query_container_snapshots(&entities, container_id, &SnapshotQuery::default()).await?;
+    let mut by_dataset = HashMap::<_, Vec<_>>::new();
+    for snapshot in &page.snapshots {
+        by_dataset.entry(snapshot.dataset_id).or_default().push(snapshot);
+    }
+    let mut by_dataset = by_dataset
+        .into_iter()
+        .map(|(dataset_id, snapshots)| {
+            let name = entities
+                .dataset(dataset_id)
+                .map_or_else(|| dataset_id.to_string(), |d| d.entity.name().to_owned());
+            (name, dataset_id, snapshots)
+        })
+        .collect::<Vec<_>>();
+    by_dataset.sort_by(|a, b| a.0.cmp(&b.0));
+
+    print_comfy_table(
+        vec![
+            Cell::new("Dataset Name"),
+            Cell::new("Snapshots"),
+            Cell::new("Oldest"),
+            Cell::new("Newest Received"),
+            Cell::new("Exclusive Size"),
+        ],
+        by_dataset.into_iter().map(|(name, dataset_id, snapshots)| {
+            // Qgroups only account btrfs containers, and only once quotas are enabled on the pool.
+            let exclusive = pool_name.as_ref().and_then(|_| {
+                api::container_exclusive_bytes(&entities, container_id, dataset_id)
+                    .map_err(|e| debug!("failed to get the exclusive size of {}: {}", name, e))
+                    .ok()
+            });
             vec![
-                comfy_id_value(c.entity.id()),
-                comfy_name_value(c.parent.name()),
-                comfy_name_value(c.entity.name()),
-                comfy_feature_state_cell(c.entity.pruning_state()),
+                comfy_name_value(&name),
+                Cell::new(snapshots.len()),
+                comfy_value_or(snapshots.first().map(|s| s.datetime), "-"),
+                comfy_value_or(snapshots.last().map(|s| s.datetime), "-"),
+                comfy_value_or(exclusive.map(format_bytes), "-"),
             ]
         }),
     );
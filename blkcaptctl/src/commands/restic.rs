@@ -1,9 +1,29 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Clap;
-use libblkcapt::model::entities::{ResticContainerEntity, ResticRepository};
-use libblkcapt::model::storage;
+use comfy_table::Cell;
+use libblkcapt::api;
+use libblkcapt::data_dir;
+use libblkcapt::model::entities::{
+    QueueOverflow, ResticCompression, ResticContainerEntity, ResticPassword, ResticRepository,
+};
+use libblkcapt::model::{entity_by_id_mut, secrets, storage, Entity};
+use slog_scope::{debug, info};
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    num::{NonZeroU32, NonZeroUsize},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+};
 
-use super::{RetentionCreateUpdateOptions, RetentionUpdateOptions};
+use super::{
+    restic_search, DryRunOptions, ProxyUpdateOptions, RestoreDrillUpdateOptions, RetentionCreateUpdateOptions,
+    RetentionUpdateOptions,
+};
+use crate::ui::{
+    comfy_annotation_rows, comfy_bytes_value, comfy_id_header, comfy_id_value_full, comfy_name_value, format_bytes,
+    print_comfy_info,
+};
 
 #[derive(Clap, Debug)]
 pub struct ResticCreateUpdateOptions {
@@ -20,6 +40,70 @@ pub struct ResticCreateUpdateOptions {
         value_name("name=value")
     )]
     environment_variable: Vec<String>,
+
+    /// File containing the repository password
+    #[clap(long, value_name("path"), conflicts_with("password-credential"))]
+    password_file: Option<PathBuf>,
+
+    /// Name of a systemd credential containing the repository password
+    #[clap(long, value_name("name"))]
+    password_credential: Option<String>,
+
+    /// Apply retention with restic's keep policies instead of forgetting snapshots by id
+    #[clap(long, value_name("bool"))]
+    native_forget: Option<bool>,
+
+    /// Host to record on snapshots instead of this machine's hostname
+    #[clap(long, value_name("host"))]
+    host: Option<String>,
+
+    /// Additional tag to apply to every snapshot
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("tag")
+    )]
+    tag: Vec<String>,
+
+    /// Restic binary to run instead of the server default
+    #[clap(long, value_name("path"))]
+    restic_path: Option<PathBuf>,
+
+    /// Repository compression mode (restic 0.14 or later)
+    #[clap(long, value_name("auto|off|max"))]
+    compression: Option<ResticCompression>,
+
+    /// Target pack size in MiB (restic 0.14 or later)
+    #[clap(long, value_name("MiB"))]
+    pack_size: Option<NonZeroU32>,
+
+    /// Maximum number of CPUs restic uses at once
+    #[clap(long, value_name("count"))]
+    max_procs: Option<NonZeroU32>,
+
+    /// Number of files read concurrently during backup (restic 0.15 or later)
+    #[clap(long, value_name("count"))]
+    read_concurrency: Option<NonZeroU32>,
+
+    /// Maximum number of backups waiting behind the active ones
+    #[clap(long, value_name("count"))]
+    backup_queue_depth: Option<NonZeroUsize>,
+
+    /// What to do with a new backup when the queue is full
+    #[clap(long, value_name("drop_oldest|coalesce_to_latest|reject"))]
+    backup_queue_overflow: Option<QueueOverflow>,
+
+    /// Maximum number of datasets backed up at once, forget and prune still run alone
+    #[clap(long, value_name("count"))]
+    max_parallel_backups: Option<NonZeroUsize>,
+
+    #[clap(flatten)]
+    proxy: ProxyUpdateOptions,
+
+    #[clap(flatten)]
+    restore_drill: RestoreDrillUpdateOptions,
 }
 
 #[derive(Clap, Debug)]
@@ -35,57 +119,281 @@ pub struct ResticAttachOptions {
     shared: ResticCreateUpdateOptions,
 }
 
+impl ResticCreateUpdateOptions {
+    // Applies the options given, leaving the settings of the others as they are.
+    fn update_restic(self, restic: &mut ResticContainerEntity) -> Result<()> {
+        for definition in self.environment_variable.iter() {
+            let (name, value) = definition
+                .split_once('=')
+                .ok_or_else(|| anyhow!("environment variable definitions must contain '='"))?;
+            restic.custom_environment.insert(name.to_owned(), value.to_owned());
+        }
+        if let Some(tag) = self.tag.iter().find(|t| t.contains(',')) {
+            return Err(anyhow!("restic tags cannot contain ',': {}", tag));
+        }
+        if let Some(path) = self.password_file {
+            restic.password = Some(ResticPassword::File { path });
+        }
+        if let Some(name) = self.password_credential {
+            restic.password = Some(ResticPassword::Credential { name });
+        }
+        if let Some(native_forget) = self.native_forget {
+            restic.native_forget = native_forget;
+        }
+        if self.host.is_some() {
+            restic.host = self.host;
+        }
+        if !self.tag.is_empty() {
+            restic.tags = self.tag;
+        }
+        if self.restic_path.is_some() {
+            restic.restic_path = self.restic_path;
+        }
+        if self.compression.is_some() {
+            restic.compression = self.compression;
+        }
+        if self.pack_size.is_some() {
+            restic.performance.pack_size = self.pack_size;
+        }
+        if self.max_procs.is_some() {
+            restic.performance.max_procs = self.max_procs;
+        }
+        if self.read_concurrency.is_some() {
+            restic.performance.read_concurrency = self.read_concurrency;
+        }
+        if let Some(depth) = self.backup_queue_depth {
+            restic.backup_queue.depth = depth;
+        }
+        if let Some(overflow) = self.backup_queue_overflow {
+            restic.backup_queue.overflow = overflow;
+        }
+        if self.max_parallel_backups.is_some() {
+            restic.max_parallel_backups = self.max_parallel_backups;
+        }
+        self.proxy.update_proxy(&mut restic.proxy)?;
+        self.restore_drill.update_restore_drill(&mut restic.restore_drill)?;
+        self.retention.update_retention(&mut restic.snapshot_retention);
+        Ok(())
+    }
+}
+
 pub fn attach_restic(options: ResticAttachOptions) -> Result<()> {
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
     let repository = options
         .custom
         .ok_or_else(|| anyhow!("only custom is supported"))
         .map(ResticRepository::Custom)?;
     let mut restic = ResticContainerEntity::new(options.name, repository);
-
-    restic.custom_environment = options
-        .shared
-        .environment_variable
-        .iter()
-        .map(|p| {
-            // Simplify with nightly split_once
-            let parts: Vec<_> = p.splitn(2, '=').collect();
-            if parts.len() == 2 {
-                Ok((parts[0].to_owned(), parts[1].to_owned()))
-            } else {
-                Err(anyhow!("environment variable definitions must contain '='"))
-            }
-        })
-        .collect::<Result<_>>()?;
-
-    options
-        .shared
-        .retention
-        .update_retention(&mut restic.snapshot_retention);
+    options.shared.update_restic(&mut restic)?;
 
     entities.restic_containers.push(restic);
 
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
     Ok(())
 }
 
 #[derive(Clap, Debug)]
 pub struct ResticUpdateOptions {
     /// The name or id of the restic container
-    #[clap(value_name("sync|id"))]
-    sync: String,
+    #[clap(value_name("restic|id"))]
+    restic: String,
 
     #[clap(flatten)]
     retention_update: RetentionUpdateOptions,
 
     #[clap(flatten)]
     shared: ResticCreateUpdateOptions,
+
+    #[clap(flatten)]
+    dry_run: DryRunOptions,
+}
+
+pub fn update_restic(options: ResticUpdateOptions) -> Result<()> {
+    debug!("Command 'update_restic': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let restic_id = restic_search(&entities, &options.restic)?.id();
+    let restic = entity_by_id_mut(&mut entities.restic_containers, restic_id).expect("entity exists, found in search");
+    let previous = restic.clone();
+
+    options.retention_update.update_pruning(&mut restic.pause_pruning);
+    options.shared.update_restic(restic)?;
+
+    if options.dry_run.preview(&previous, restic)? {
+        return Ok(());
+    }
+    storage::store_entity_config(entities)?;
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ResticShowOptions {
+    /// The name or id of the restic container
+    #[clap(value_name("restic|id"))]
+    restic: String,
 }
 
-pub fn update_restic(_options: ResticUpdateOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+pub async fn show_restic(options: ResticShowOptions) -> Result<()> {
+    debug!("Command 'show_restic': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let restic = restic_search(&entities, &options.restic)?;
 
-    //storage::store_entity_state(entities);
+    let mut rows = vec![
+        (comfy_id_header(), comfy_id_value_full(restic.id()).into()),
+        (Cell::new("Container Name"), comfy_name_value(restic.name()).into()),
+    ];
+
+    // Statistics are collected periodically by the service, restic is never run from here.
+    let stats = match api::service_state().await {
+        Ok(system) => system
+            .restic_stats
+            .into_iter()
+            .find(|s| s.container_id == restic.container_id()),
+        Err(e) => {
+            debug!("service unavailable: {}", e);
+            None
+        }
+    };
+
+    match stats {
+        Some(stats) => {
+            rows.push((Cell::new("Stats Collected"), Cell::new(stats.collected).into()));
+            rows.push((Cell::new("Stored Size"), comfy_bytes_value(stats.stored_bytes).into()));
+            rows.push((Cell::new("Restore Size"), comfy_bytes_value(stats.restore_bytes).into()));
+            rows.push((
+                Cell::new("Dedup Ratio"),
+                Cell::new(
+                    stats
+                        .dedup_ratio()
+                        .map_or_else(|| String::from("n/a"), |r| format!("{:.2}", r)),
+                )
+                .into(),
+            ));
+            rows.push((
+                Cell::new("Latest Restore Size"),
+                stats
+                    .datasets
+                    .iter()
+                    .map(|d| {
+                        let name = entities
+                            .dataset(d.dataset_id)
+                            .map_or_else(|| d.dataset_id.to_string(), |p| p.entity.name().to_string());
+                        Cell::new(format!("{}: {}", name, format_bytes(d.latest_restore_bytes)))
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ));
+        }
+        None => rows.push((Cell::new("Stats"), Cell::new("not yet collected").into())),
+    }
+
+    rows.extend(comfy_annotation_rows(&restic.annotations));
+    print_comfy_info(rows);
     Ok(())
 }
+
+#[derive(Clap, Debug)]
+pub struct ResticMigratePasswordOptions {
+    /// The name or id of the restic container, all containers if omitted
+    #[clap(value_name("restic|id"))]
+    restic: Option<String>,
+}
+
+/// Moves passwords stored in `custom_environment` out of the world-readable entity config into root-only files.
+pub fn migrate_restic_password(options: ResticMigratePasswordOptions) -> Result<()> {
+    debug!("Command 'migrate_restic_password': {:?}", options);
+    let mut entities = storage::load_entity_config()?;
+
+    let restic_ids = match &options.restic {
+        Some(query) => vec![restic_search(&entities, query)?.id()],
+        None => entities.restic_containers.iter().map(|r| r.id()).collect(),
+    };
+
+    for restic_id in restic_ids {
+        let restic =
+            entity_by_id_mut(&mut entities.restic_containers, restic_id).expect("entity exists, found in search");
+
+        if let Some(path) = migrate_password(restic)? {
+            info!("Moved restic password for {} to {}", restic.name(), path.display());
+        }
+    }
+
+    storage::store_entity_config(entities)?;
+    Ok(())
+}
+
+// Returns the password file written for a password set in the environment.
+fn migrate_password(restic: &mut ResticContainerEntity) -> Result<Option<PathBuf>> {
+    if let Some(path) = restic.custom_environment.remove("RESTIC_PASSWORD_FILE") {
+        restic.password = Some(ResticPassword::File {
+            path: secrets::reveal(&path)?.into(),
+        });
+    }
+    match restic.custom_environment.get("RESTIC_PASSWORD") {
+        Some(password) => {
+            let password = secrets::reveal(password)
+                .with_context(|| format!("failed to reveal restic password for {}", restic.name()))?;
+            let path = write_password_file(restic.id().to_string(), &password)?;
+            restic.custom_environment.remove("RESTIC_PASSWORD");
+            restic.password = Some(ResticPassword::File { path: path.clone() });
+            Ok(Some(path))
+        }
+        None => Ok(None),
+    }
+}
+
+fn write_password_file(name: String, password: &str) -> Result<PathBuf> {
+    let mut path = data_dir();
+    path.push("secrets");
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&path)
+        .context("failed to create secrets directory")?;
+
+    path.push(name + ".restic-password");
+    let temp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .context("failed to create password file")?;
+    file.write_all(password.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path).context("failed to move password file into place")?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn migrate_password_reveals_encrypted_password() {
+        let sandbox = env::temp_dir().join(format!("blkcapt-migrate-password-{}", std::process::id()));
+        fs::create_dir_all(&sandbox).unwrap();
+        fs::write(sandbox.join(libblkcapt::SANDBOX_MARKER), "").unwrap();
+        env::set_var("BLOCKCAPTAIN_DATA_DIR", &sandbox);
+        env::set_var("CREDENTIALS_DIRECTORY", sandbox.join("credentials"));
+        secrets::ensure_key().unwrap();
+
+        let mut restic = ResticContainerEntity::new(
+            String::from("offsite"),
+            ResticRepository::Custom(String::from("/srv/restic")),
+        );
+        restic
+            .custom_environment
+            .insert(String::from("RESTIC_PASSWORD"), secrets::encrypt("hunter2").unwrap());
+        let path = migrate_password(&mut restic).unwrap().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hunter2");
+        assert!(restic.custom_environment.is_empty());
+        assert_eq!(restic.password.as_ref().map(ResticPassword::path), Some(path));
+        assert_eq!(migrate_password(&mut restic).unwrap(), None);
+        fs::remove_dir_all(&sandbox).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use super::dataset_search;
+use crate::ui::confirm_or_abort;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Clap;
+use libblkcapt::{
+    core::{
+        rollback::{self, cmdline_selects_root_subvolume, last_rollback},
+        BtrfsDataset, BtrfsPool, Snapshot,
+    },
+    model::{
+        entities::{BtrfsDatasetEntity, BtrfsPoolEntity},
+        entity_by_id_mut, storage, Entities, Entity, EntityPath2,
+    },
+    sys::fs::FsPathBuf,
+};
+use slog_scope::*;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Boot a writable copy of a snapshot of the OS root dataset from the next boot on, the current root is kept
+#[derive(Clap, Debug)]
+pub struct RollbackOptions {
+    /// Boot the root replaced by the last rollback again
+    #[clap(long, conflicts_with("snapshot"))]
+    undo: bool,
+
+    /// The snapshot to roll back to, by name or time, of the single dataset marked as OS root unless given
+    #[clap(value_name("[dataset@]snapshot"))]
+    snapshot: Option<String>,
+}
+
+pub fn rollback(options: RollbackOptions) -> Result<()> {
+    debug!("Command 'rollback': {:?}", options);
+
+    // Checked before anything changes, the root would otherwise be switched for fstab but not for the kernel.
+    if cmdline_selects_root_subvolume()? {
+        bail!(
+            "The kernel command line selects the root subvolume with rootflags=subvol, which a rollback can't change. \
+            Remove the subvol option from the kernel command line first."
+        );
+    }
+
+    let mut entities = storage::load_entity_config()?;
+    if options.undo {
+        return undo_rollback(entities);
+    }
+
+    let query = options
+        .snapshot
+        .as_deref()
+        .context("A snapshot to roll back to is required.")?;
+    let (dataset_path, snapshot_query) = match query.split_once('@') {
+        Some((dataset, snapshot)) => (dataset_search(&entities, dataset)?, snapshot),
+        None => (os_root_dataset(&entities)?, query),
+    };
+    let mountpoint = dataset_path.parent.mountpoint_path.clone();
+    let dataset = validate_os_root(dataset_path)?;
+
+    let snapshot_time = DateTime::parse_from_rfc3339(snapshot_query)
+        .ok()
+        .map(|t| t.with_timezone(&Utc));
+    let mut snapshots = dataset
+        .snapshots()?
+        .into_iter()
+        .chain(dataset.tagged_snapshots(None)?)
+        .filter(|s| s.path().file_name().map_or(false, |n| n == snapshot_query) || Some(s.datetime()) == snapshot_time)
+        .collect::<Vec<_>>();
+    let snapshot = match snapshots.len() {
+        0 => bail!("Snapshot not found."),
+        1 => snapshots.pop().expect("length checked"),
+        _ => bail!("Snapshot query is ambiguous, use the snapshot name."),
+    };
+
+    confirm_or_abort(format!(
+        "Boot a copy of snapshot {} as the root of {} from the next boot on?",
+        snapshot.canonical_path().display(),
+        dataset
+    ))?;
+    let record = rollback::rollback(&dataset, &snapshot).context("Failed to roll back the dataset.")?;
+    set_dataset_subvolume(&mut entities, &dataset, &record.root_path, record.root_uuid);
+    storage::store_entity_config(entities)?;
+
+    info!(
+        "Rolled back to {}, reboot to boot it, the previous root is kept at {}",
+        record.root_path.as_pathbuf(&mountpoint).display(),
+        record.previous_path.as_pathbuf(&mountpoint).display()
+    );
+    Ok(())
+}
+
+fn undo_rollback(mut entities: Entities) -> Result<()> {
+    let record = last_rollback()?.context("No rollback to undo.")?;
+    let dataset_path = entities
+        .dataset(record.dataset_id)
+        .context("The dataset of the last rollback no longer exists.")?;
+    let mountpoint = dataset_path.parent.mountpoint_path.clone();
+    let dataset = validate_os_root(dataset_path)?;
+
+    confirm_or_abort(format!(
+        "Boot {} again as the root of {} from the next boot on?",
+        record.previous_path.as_pathbuf(&mountpoint).display(),
+        dataset
+    ))?;
+    let record = rollback::undo_rollback(&dataset).context("Failed to undo the rollback.")?;
+    set_dataset_subvolume(&mut entities, &dataset, &record.previous_path, record.previous_uuid);
+    storage::store_entity_config(entities)?;
+
+    info!(
+        "Rollback undone, reboot to boot {}, the rolled back root is kept at {}",
+        record.previous_path.as_pathbuf(&mountpoint).display(),
+        record.root_path.as_pathbuf(&mountpoint).display()
+    );
+    Ok(())
+}
+
+fn os_root_dataset(entities: &Entities) -> Result<EntityPath2<BtrfsDatasetEntity, BtrfsPoolEntity>> {
+    let mut datasets = entities.datasets().filter(|d| d.entity.os_root).collect::<Vec<_>>();
+    match datasets.len() {
+        0 => Err(anyhow!(
+            "No dataset is marked as OS root, set it with 'dataset update --os-root true'."
+        )),
+        1 => Ok(datasets.pop().expect("length checked")),
+        _ => Err(anyhow!(
+            "Multiple datasets are marked as OS root, use dataset@snapshot."
+        )),
+    }
+}
+
+fn validate_os_root(dataset_path: EntityPath2<BtrfsDatasetEntity, BtrfsPoolEntity>) -> Result<Arc<BtrfsDataset>> {
+    if !dataset_path.entity.os_root {
+        bail!("Dataset {} is not marked as OS root.", dataset_path.entity.name());
+    }
+    let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+    Ok(Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?))
+}
+
+fn set_dataset_subvolume(entities: &mut Entities, dataset: &BtrfsDataset, path: &FsPathBuf, uuid: Uuid) {
+    let dataset_path = entities
+        .dataset(dataset.model().dataset_id())
+        .map(|d| d.into_id_path())
+        .expect("always exists if validated");
+    let pool = entity_by_id_mut(&mut entities.btrfs_pools, dataset_path.parent).expect("always exists if path found");
+    let entity = entity_by_id_mut(&mut pool.datasets, dataset_path.entity).expect("always exists if path found");
+    entity.path = path.clone();
+    entity.uuid = uuid;
+}
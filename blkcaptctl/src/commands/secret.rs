@@ -0,0 +1,130 @@
+use anyhow::Result;
+use clap::Clap;
+use dialoguer::Password;
+use libblkcapt::model::{entity_by_id_mut, secrets, storage, Entity, ProxyConfig};
+use libblkcapt::sys::net::HttpProxy;
+use slog_scope::*;
+use std::io::{self, BufRead};
+
+use super::{observer_search, restic_search};
+
+#[derive(Clap, Debug)]
+pub struct SecretSetOptions {
+    /// Read the value from stdin instead of prompting for it
+    #[clap(long)]
+    stdin: bool,
+
+    #[clap(subcommand)]
+    target: SecretTarget,
+}
+
+#[derive(Clap, Debug)]
+pub enum SecretTarget {
+    /// Environment variable for the restic process (e.g. RESTIC_PASSWORD, AWS_SECRET_ACCESS_KEY)
+    ResticEnv(ResticEnvSecretOptions),
+    /// Custom URL of a healthchecks observer
+    ObserverUrl(ObserverUrlSecretOptions),
+    /// HTTP proxy url with credentials, of the service unless a restic container or observer is given
+    ProxyUrl(ProxyUrlSecretOptions),
+}
+
+#[derive(Clap, Debug)]
+pub struct ResticEnvSecretOptions {
+    /// The name or id of the restic container
+    #[clap(value_name("restic|id"))]
+    restic: String,
+
+    /// Name of the environment variable
+    #[clap(value_name("name"))]
+    name: String,
+}
+
+#[derive(Clap, Debug)]
+pub struct ObserverUrlSecretOptions {
+    /// The name or id of the observer
+    #[clap(value_name("observer|id"))]
+    observer: String,
+}
+
+#[derive(Clap, Debug)]
+pub struct ProxyUrlSecretOptions {
+    /// The name or id of the restic container
+    #[clap(long, value_name("restic|id"), conflicts_with("observer"))]
+    restic: Option<String>,
+
+    /// The name or id of the observer
+    #[clap(long, value_name("observer|id"))]
+    observer: Option<String>,
+}
+
+pub fn set_secret(options: SecretSetOptions) -> Result<()> {
+    debug!("Command 'set_secret': {:?}", options.target);
+    let mut entities = storage::load_entity_config()?;
+
+    let key_path = secrets::ensure_key()?;
+    debug!("Using secrets key {}", key_path.display());
+    let value = read_value(options.stdin)?;
+    let encrypted = secrets::encrypt(&value)?;
+
+    match options.target {
+        SecretTarget::ResticEnv(target) => {
+            let restic_id = restic_search(&entities, &target.restic)?.id();
+            let restic =
+                entity_by_id_mut(&mut entities.restic_containers, restic_id).expect("entity exists, found in search");
+            restic.custom_environment.insert(target.name, encrypted);
+        }
+        SecretTarget::ObserverUrl(target) => {
+            let observer_id = observer_search(&entities, &target.observer)?.id();
+            let observer =
+                entity_by_id_mut(&mut entities.observers, observer_id).expect("entity exists, found in search");
+            observer.custom_url = Some(encrypted);
+        }
+        SecretTarget::ProxyUrl(target) => {
+            HttpProxy::new(&value, Vec::new())?;
+            let proxy = if let Some(restic) = &target.restic {
+                let restic_id = restic_search(&entities, restic)?.id();
+                &mut entity_by_id_mut(&mut entities.restic_containers, restic_id)
+                    .expect("entity exists, found in search")
+                    .proxy
+            } else if let Some(observer) = &target.observer {
+                let observer_id = observer_search(&entities, observer)?.id();
+                &mut entity_by_id_mut(&mut entities.observers, observer_id)
+                    .expect("entity exists, found in search")
+                    .proxy
+            } else {
+                let mut config = storage::load_server_config()?;
+                set_proxy_url(&mut config.proxy, encrypted);
+                storage::store_server_config(config)?;
+                info!("Secret stored encrypted.");
+                return Ok(());
+            };
+            set_proxy_url(proxy, encrypted);
+        }
+    }
+
+    storage::store_entity_config(entities)?;
+    info!("Secret stored encrypted.");
+    Ok(())
+}
+
+fn set_proxy_url(proxy: &mut Option<ProxyConfig>, url: String) {
+    match proxy {
+        Some(proxy) => proxy.url = url,
+        None => {
+            *proxy = Some(ProxyConfig {
+                url,
+                no_proxy: Vec::new(),
+            })
+        }
+    }
+}
+
+fn read_value(stdin: bool) -> Result<String> {
+    if stdin {
+        let mut value = String::new();
+        io::stdin().lock().read_line(&mut value)?;
+        Ok(value.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    } else {
+        Ok(Password::new().with_prompt("Secret value").interact()?)
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::model::{history::HistorySummary, storage, Entities, Entity, EntityId};
+use slog_scope::*;
+use std::collections::HashMap;
+
+use crate::ui::{comfy_bytes_value, comfy_name_value, comfy_value_or, format_bytes, print_comfy_table, sparkline};
+
+/// Show snapshot, transfer, job and repository history
+#[derive(Clap, Debug)]
+pub struct StatsOptions {
+    /// Number of days of history to show
+    #[clap(short('d'), long, value_name("count"), default_value("28"))]
+    days: usize,
+
+    /// Show one value per week instead of per day
+    #[clap(short, long)]
+    weekly: bool,
+}
+
+pub fn stats(options: StatsOptions) -> Result<()> {
+    debug!("Command 'stats': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let summary = HistorySummary::load(options.days)?;
+    let bucket = |values: &[u64]| -> Vec<u64> {
+        if options.weekly {
+            let mut weeks = values.rchunks(7).map(|w| w.iter().sum()).collect::<Vec<_>>();
+            weeks.reverse();
+            weeks
+        } else {
+            values.to_vec()
+        }
+    };
+
+    println!("Snapshots");
+    print_comfy_table(
+        vec![Cell::new("Dataset"), Cell::new("Total"), Cell::new("History")],
+        named_series(&entities, &summary.snapshots).map(|(name, values)| {
+            vec![
+                comfy_name_value(name),
+                Cell::new(values.iter().sum::<u64>()),
+                Cell::new(sparkline(&bucket(values))),
+            ]
+        }),
+    );
+
+    println!("Transfers");
+    print_comfy_table(
+        vec![Cell::new("Sync"), Cell::new("Total"), Cell::new("History")],
+        named_series(&entities, &summary.transfers).map(|(name, values)| {
+            vec![
+                comfy_name_value(name),
+                comfy_bytes_value(values.iter().sum()),
+                Cell::new(sparkline(&bucket(values))),
+            ]
+        }),
+    );
+
+    println!("Jobs");
+    let mut jobs = summary.jobs.iter().collect::<Vec<_>>();
+    jobs.sort_by_key(|(event, _)| event.to_string());
+    print_comfy_table(
+        vec![
+            Cell::new("Job"),
+            Cell::new("Runs"),
+            Cell::new("Succeeded"),
+            Cell::new("Success Rate"),
+        ],
+        jobs.into_iter().map(|(event, counts)| {
+            vec![
+                comfy_name_value(event),
+                Cell::new(counts.runs),
+                Cell::new(counts.succeeded),
+                Cell::new(format!("{:.1}%", counts.succeeded as f64 * 100.0 / counts.runs as f64)),
+            ]
+        }),
+    );
+
+    println!("Repositories");
+    let repositories = summary
+        .repositories
+        .iter()
+        .map(|(id, sizes)| (EntityId::from(*id), sizes))
+        .collect::<HashMap<_, _>>();
+    print_comfy_table(
+        vec![
+            Cell::new("Repository"),
+            Cell::new("First"),
+            Cell::new("Last"),
+            Cell::new("Change"),
+            Cell::new("History"),
+        ],
+        named_series(&entities, &repositories).map(|(name, sizes)| {
+            let first = sizes.iter().find_map(|s| *s);
+            let last = sizes.iter().rev().find_map(|s| *s);
+            let change = first.zip(last).map(|(first, last)| {
+                if last >= first {
+                    format!("+{}", format_bytes(last - first))
+                } else {
+                    format!("-{}", format_bytes(first - last))
+                }
+            });
+            let sizes = if options.weekly {
+                let mut weeks = sizes
+                    .rchunks(7)
+                    .map(|w| w.iter().rev().find_map(|s| *s))
+                    .collect::<Vec<_>>();
+                weeks.reverse();
+                weeks
+            } else {
+                sizes.clone()
+            };
+            vec![
+                comfy_name_value(name),
+                comfy_value_or(first.map(format_bytes), "-"),
+                comfy_value_or(last.map(format_bytes), "-"),
+                comfy_value_or(change, "-"),
+                Cell::new(sparkline(
+                    &sizes.into_iter().map(Option::unwrap_or_default).collect::<Vec<_>>(),
+                )),
+            ]
+        }),
+    );
+
+    Ok(())
+}
+
+// The series of each entity sorted by name, entities no longer configured are shown by id.
+fn named_series<'a, T>(entities: &Entities, series: &'a HashMap<EntityId, T>) -> impl Iterator<Item = (String, &'a T)> {
+    let mut named = series
+        .iter()
+        .map(|(id, values)| {
+            let name = entities
+                .entity(*id)
+                .map_or_else(|| id.to_string(), |e| e.name().to_owned());
+            (name, values)
+        })
+        .collect::<Vec<_>>();
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    named.into_iter()
+}
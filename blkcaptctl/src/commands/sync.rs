@@ -1,12 +1,27 @@
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use clap::Clap;
+use comfy_table::Cell;
 use humantime::Duration;
-use libblkcapt::model::entities::{SnapshotSyncEntity, SnapshotSyncMode};
-use libblkcapt::model::{storage, Entity};
+use libblkcapt::{
+    api,
+    core::clock::{Clock, SystemClock},
+    model::cursor::{load_sync_cursor, SyncCursor},
+    model::entities::{ObservableEvent, SnapshotSyncEntity, SnapshotSyncMode, SyncedRetention, TrashedEntityKind},
+    model::{entity_by_id_mut, storage, Entities, Entity},
+};
+use slog_scope::*;
+use std::{num::NonZeroUsize, path::PathBuf};
 
-use crate::ui::ScheduleArg;
+use crate::ui::{
+    comfy_annotation_rows, comfy_enabled_cell, comfy_id_header, comfy_id_value, comfy_name_value, comfy_value_or,
+    confirm_or_abort, print_comfy_table, ScheduleArg,
+};
 
-use super::{container_search, dataset_search, restic_search};
+use super::{
+    comfy_running_value, container_search, dataset_search, last_succeeded_jobs, restic_search, running_jobs,
+    snapshot_sync_search, DryRunOptions, LabelFilterOptions,
+};
 
 #[derive(Clap, Debug)]
 pub struct SyncCreateUpdateOptions {
@@ -21,6 +36,79 @@ pub struct SyncCreateUpdateOptions {
     /// Interval for interval_immediate mode
     #[clap(short, long, value_name("interval"))]
     interval: Option<Duration>,
+
+    /// Only send the first snapshot taken after each firing of this schedule
+    #[clap(long, value_name("schedule"))]
+    filter_schedule: Option<ScheduleArg>,
+
+    /// Only send snapshots at least this far apart
+    #[clap(long, value_name("interval"))]
+    min_spacing: Option<Duration>,
+
+    /// Trim btrfs containers after each transfer: mirror, latest_only, or a maximum snapshot count
+    #[clap(long, value_name("retention"))]
+    container_retention: Option<SyncedRetention>,
+
+    /// Exclude pattern passed to restic backup
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        value_name("pattern")
+    )]
+    restic_exclude: Vec<String>,
+
+    /// Pass --exclude-caches to restic backup
+    #[clap(long, value_name("bool"))]
+    restic_exclude_caches: Option<bool>,
+
+    /// Pass --one-file-system to restic backup
+    #[clap(long, value_name("bool"))]
+    restic_one_file_system: Option<bool>,
+
+    /// Additional argument passed to restic backup
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        takes_value(true),
+        allow_hyphen_values(true),
+        value_name("arg")
+    )]
+    restic_arg: Vec<String>,
+
+    /// Skip sending snapshots that are unchanged from their parent
+    #[clap(long, value_name("bool"))]
+    skip_unchanged: Option<bool>,
+
+    /// Defer incremental transfers until the estimated change reaches this many bytes
+    #[clap(long, value_name("bytes"))]
+    min_transfer_bytes: Option<u64>,
+
+    /// Release a snapshot held for a restic backup after this long, even if the backup is still running
+    #[clap(long, value_name("duration"))]
+    hold_lease: Option<Duration>,
+
+    /// Number of sync cycles kept queued while a transfer is active, older cycles are coalesced into newer ones
+    #[clap(long, value_name("count"))]
+    pending_cycles: Option<NonZeroUsize>,
+
+    /// Send compressed extents without recompressing them, defaults to on when supported
+    #[clap(long, value_name("bool"))]
+    compressed_send: Option<bool>,
+
+    /// Program to run before each transfer
+    #[clap(long, value_name("path"))]
+    pre_sync_hook: Option<PathBuf>,
+
+    /// Program to run after each transfer
+    #[clap(long, value_name("path"))]
+    post_sync_hook: Option<PathBuf>,
+
+    /// Kill sync hooks that run longer than this duration, defaults to 10 minutes
+    #[clap(long, value_name("duration"))]
+    sync_hook_timeout: Option<Duration>,
 }
 
 impl SyncCreateUpdateOptions {
@@ -50,23 +138,42 @@ pub struct SyncCreateOptions {
     #[clap(value_name("dataset|id"))]
     dataset: String,
 
-    /// The name or id of the destination container
-    #[clap(value_name("container|id"))]
-    container: String,
+    /// The names or ids of the destination containers
+    #[clap(value_name("container|id"), required(true))]
+    containers: Vec<String>,
+
+    /// Forward the dataset's snapshots from this container instead of the dataset
+    #[clap(long, value_name("container|id"))]
+    from_container: Option<String>,
 
     #[clap(flatten)]
     shared: SyncCreateUpdateOptions,
 }
 
 pub fn create_sync(options: SyncCreateOptions) -> Result<()> {
-    let mut entities = storage::load_entity_config();
+    let mut entities = storage::load_entity_config()?;
 
-    let dataset_id = dataset_search(&entities, &options.dataset).map(|d| d.id())?;
+    let dataset_id = dataset_search(&entities, &options.dataset).map(|d| d.entity.dataset_id())?;
     // TODO: entity refactor needed. this doesn't error if a container and restic container have
     // the same name so user may accidentally select wrong target.
-    let container_id = container_search(&entities, &options.container)
-        .map(|c| c.id())
-        .or_else(|_| restic_search(&entities, &options.container).map(|c| c.id()))?;
+    let mut container_ids = options
+        .containers
+        .iter()
+        .map(|container| {
+            container_search(&entities, container)
+                .map(|c| c.entity.container_id())
+                .or_else(|_| restic_search(&entities, container).map(|c| c.container_id()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let container_id = container_ids.remove(0);
+    let source_container_id = options
+        .from_container
+        .as_ref()
+        .map(|container| container_search(&entities, container).map(|c| c.entity.container_id()))
+        .transpose()?;
+    if source_container_id.map_or(false, |id| id == container_id || container_ids.contains(&id)) {
+        return Err(anyhow!("source container cannot also be a destination"));
+    }
     let maybe_mode = options
         .shared
         .mode
@@ -75,13 +182,38 @@ pub fn create_sync(options: SyncCreateOptions) -> Result<()> {
         .transpose()?;
 
     let mut sync = SnapshotSyncEntity::new(options.name, dataset_id, container_id);
+    sync.additional_container_ids = container_ids;
+    sync.source_container_id = source_container_id;
     if let Some(mode) = maybe_mode {
         sync.sync_mode = mode;
     }
+    sync.filter.schedule = options.shared.filter_schedule.map(Into::into);
+    sync.filter.min_spacing = options.shared.min_spacing.map(Into::into);
+    sync.container_retention = options.shared.container_retention;
+    sync.restic_backup.excludes = options.shared.restic_exclude;
+    sync.restic_backup.extra_args = options.shared.restic_arg;
+    if let Some(exclude_caches) = options.shared.restic_exclude_caches {
+        sync.restic_backup.exclude_caches = exclude_caches;
+    }
+    if let Some(one_file_system) = options.shared.restic_one_file_system {
+        sync.restic_backup.one_file_system = one_file_system;
+    }
+    if let Some(skip_unchanged) = options.shared.skip_unchanged {
+        sync.skip_unchanged = skip_unchanged;
+    }
+    if options.shared.min_transfer_bytes.is_some() {
+        sync.min_transfer_bytes = options.shared.min_transfer_bytes;
+    }
+    sync.compressed_send = options.shared.compressed_send;
+    sync.hold_lease = options.shared.hold_lease.map(Into::into);
+    sync.pending_cycles = options.shared.pending_cycles;
+    sync.sync_hooks.pre = options.shared.pre_sync_hook;
+    sync.sync_hooks.post = options.shared.post_sync_hook;
+    sync.sync_hooks.timeout = options.shared.sync_hook_timeout.map(Into::into);
 
     entities.snapshot_syncs.push(sync);
 
-    storage::store_entity_config(entities);
+    storage::store_entity_config(entities)?;
     Ok(())
 }
 
@@ -93,25 +225,280 @@ pub struct SyncUpdateOptions {
 
     #[clap(flatten)]
     shared: SyncCreateUpdateOptions,
+
+    /// Send snapshots regardless of when they were taken
+    #[clap(long, conflicts_with("filter-schedule"))]
+    no_filter_schedule: bool,
+
+    /// Send snapshots regardless of how far apart they are
+    #[clap(long, conflicts_with("min-spacing"))]
+    no_min_spacing: bool,
+
+    /// Stop trimming btrfs containers after each transfer
+    #[clap(long, conflicts_with("container-retention"))]
+    no_container_retention: bool,
+
+    /// Remove all exclude patterns passed to restic backup
+    #[clap(long, conflicts_with("restic-exclude"))]
+    no_restic_exclude: bool,
+
+    /// Remove all additional arguments passed to restic backup
+    #[clap(long, conflicts_with("restic-arg"))]
+    no_restic_arg: bool,
+
+    /// Send incremental transfers whatever their estimated size
+    #[clap(long, conflicts_with("min-transfer-bytes"))]
+    no_min_transfer_bytes: bool,
+
+    /// Hold snapshots for restic backups until the backup finishes
+    #[clap(long, conflicts_with("hold-lease"))]
+    no_hold_lease: bool,
+
+    /// Keep only the newest sync cycle queued while a transfer is active
+    #[clap(long, conflicts_with("pending-cycles"))]
+    no_pending_cycles: bool,
+
+    /// Stop running a program before each transfer
+    #[clap(long, conflicts_with("pre-sync-hook"))]
+    no_pre_sync_hook: bool,
+
+    /// Stop running a program after each transfer
+    #[clap(long, conflicts_with("post-sync-hook"))]
+    no_post_sync_hook: bool,
+
+    /// Use the default timeout for sync hooks
+    #[clap(long, conflicts_with("sync-hook-timeout"))]
+    no_sync_hook_timeout: bool,
+
+    #[clap(flatten)]
+    dry_run: DryRunOptions,
 }
 
-pub fn update_sync(_options: SyncUpdateOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+impl SyncUpdateOptions {
+    fn clear_settings(&self, sync: &mut SnapshotSyncEntity) {
+        if self.no_filter_schedule {
+            sync.filter.schedule = None;
+        }
+        if self.no_min_spacing {
+            sync.filter.min_spacing = None;
+        }
+        if self.no_container_retention {
+            sync.container_retention = None;
+        }
+        if self.no_restic_exclude {
+            sync.restic_backup.excludes.clear();
+        }
+        if self.no_restic_arg {
+            sync.restic_backup.extra_args.clear();
+        }
+        if self.no_min_transfer_bytes {
+            sync.min_transfer_bytes = None;
+        }
+        if self.no_hold_lease {
+            sync.hold_lease = None;
+        }
+        if self.no_pending_cycles {
+            sync.pending_cycles = None;
+        }
+        if self.no_pre_sync_hook {
+            sync.sync_hooks.pre = None;
+        }
+        if self.no_post_sync_hook {
+            sync.sync_hooks.post = None;
+        }
+        if self.no_sync_hook_timeout {
+            sync.sync_hooks.timeout = None;
+        }
+    }
+}
+
+pub fn update_sync(options: SyncUpdateOptions) -> Result<()> {
+    debug!("Command 'update_sync': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let sync_id = snapshot_sync_search(&entities, &options.sync)?.id();
+    let sync = entity_by_id_mut(&mut entities.snapshot_syncs, sync_id).expect("entity exists, found in search");
+    let previous = sync.clone();
+
+    options.clear_settings(sync);
+    let shared = options.shared;
+    if shared.mode.is_some() || shared.schedule.is_some() || shared.interval.is_some() {
+        let mode = shared.mode.clone().unwrap_or_else(|| sync.sync_mode.clone());
+        sync.sync_mode = shared.configure_mode(mode)?;
+    }
+    if shared.filter_schedule.is_some() {
+        sync.filter.schedule = shared.filter_schedule.map(Into::into);
+    }
+    if shared.min_spacing.is_some() {
+        sync.filter.min_spacing = shared.min_spacing.map(Into::into);
+    }
+    if shared.container_retention.is_some() {
+        sync.container_retention = shared.container_retention;
+    }
+    if !shared.restic_exclude.is_empty() {
+        sync.restic_backup.excludes = shared.restic_exclude;
+    }
+    if !shared.restic_arg.is_empty() {
+        sync.restic_backup.extra_args = shared.restic_arg;
+    }
+    if let Some(exclude_caches) = shared.restic_exclude_caches {
+        sync.restic_backup.exclude_caches = exclude_caches;
+    }
+    if let Some(one_file_system) = shared.restic_one_file_system {
+        sync.restic_backup.one_file_system = one_file_system;
+    }
+    if let Some(skip_unchanged) = shared.skip_unchanged {
+        sync.skip_unchanged = skip_unchanged;
+    }
+    if shared.min_transfer_bytes.is_some() {
+        sync.min_transfer_bytes = shared.min_transfer_bytes;
+    }
+    if shared.compressed_send.is_some() {
+        sync.compressed_send = shared.compressed_send;
+    }
+    if shared.hold_lease.is_some() {
+        sync.hold_lease = shared.hold_lease.map(Into::into);
+    }
+    if shared.pending_cycles.is_some() {
+        sync.pending_cycles = shared.pending_cycles;
+    }
+    if shared.pre_sync_hook.is_some() {
+        sync.sync_hooks.pre = shared.pre_sync_hook;
+    }
+    if shared.post_sync_hook.is_some() {
+        sync.sync_hooks.post = shared.post_sync_hook;
+    }
+    if shared.sync_hook_timeout.is_some() {
+        sync.sync_hooks.timeout = shared.sync_hook_timeout.map(Into::into);
+    }
 
-    //storage::store_entity_state(entities);
+    if options.dry_run.preview(&previous, sync)? {
+        return Ok(());
+    }
+    storage::store_entity_config(entities)?;
     Ok(())
 }
 
 #[derive(Clap, Debug)]
-pub struct SyncListOptions {}
+pub struct SyncListOptions {
+    #[clap(flatten)]
+    labels: LabelFilterOptions,
+}
 
-pub fn list_sync(_options: SyncListOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+pub async fn list_sync(options: SyncListOptions) -> Result<()> {
+    debug!("Command 'list_sync': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let running = running_jobs().await;
+    let last_syncs = last_succeeded_jobs(ObservableEvent::SnapshotSync);
+
+    print_comfy_table(
+        vec![
+            comfy_id_header(),
+            Cell::new("Sync Name"),
+            Cell::new("Dataset Name"),
+            Cell::new("Containers"),
+            Cell::new("State"),
+            Cell::new("Last Sync"),
+            Cell::new("Next Sync"),
+            Cell::new("Backlog"),
+            Cell::new("Job"),
+        ],
+        entities
+            .snapshot_syncs
+            .iter()
+            .filter(|sync| options.labels.matches(&sync.annotations))
+            .map(|sync| {
+                let containers = sync
+                    .container_ids()
+                    .map(|id| {
+                        entities
+                            .entity(id.into())
+                            .map_or_else(|| id.to_string(), |c| c.name().to_owned())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                vec![
+                    comfy_id_value(sync.id()),
+                    comfy_name_value(sync.name()),
+                    comfy_value_or(
+                        entities.dataset(sync.dataset_id).map(|d| d.entity.name().to_owned()),
+                        "-",
+                    ),
+                    comfy_name_value(containers),
+                    comfy_enabled_cell(sync.disabled),
+                    comfy_value_or(last_syncs.get(&sync.id()).map(|r| r.finished()), "-"),
+                    comfy_value_or(next_sync(&entities, sync, &SystemClock), "-"),
+                    comfy_value_or(sync_backlog(&entities, sync), "-"),
+                    comfy_running_value(running.as_ref(), sync.id()),
+                ]
+            }),
+    );
 
-    //storage::store_entity_state(entities);
     Ok(())
 }
 
+// None when the worker doesn't run the sync, or it waits for snapshots of a dataset whose snapshotting is paused.
+fn next_sync(entities: &Entities, sync: &SnapshotSyncEntity, clock: &dyn Clock) -> Option<String> {
+    let dataset = entities.dataset(sync.dataset_id)?;
+    if sync.disabled || dataset.entity.disabled {
+        return None;
+    }
+    match &sync.sync_mode {
+        SnapshotSyncMode::AllScheduled(schedule) | SnapshotSyncMode::LatestScheduled(schedule) => {
+            schedule.next_after(clock.now()).ok().flatten().map(|t| t.to_string())
+        }
+        SnapshotSyncMode::AllImmediate | SnapshotSyncMode::IntervalImmediate(_) => {
+            Some(String::from("on snapshot")).filter(|_| !dataset.entity.pause_snapshotting)
+        }
+    }
+}
+
+// Dataset snapshots taken after the last one sent to every container, none when the sync doesn't send from the
+// dataset or its progress can't be read.
+fn sync_backlog(entities: &Entities, sync: &SnapshotSyncEntity) -> Option<usize> {
+    if sync.source_container_id.is_some() {
+        return None;
+    }
+    let cursor = load_sync_cursor(sync.sync_id())
+        .map_err(|e| debug!("failed to load the cursor of {}: {}", sync.name(), e))
+        .ok()?;
+    let snapshots = api::dataset_snapshots(entities, sync.dataset_id)
+        .map_err(|e| debug!("failed to list snapshots of {}: {}", sync.name(), e))
+        .ok()?;
+    let oldest_sent = sync
+        .container_ids()
+        .map(|id| {
+            cursor
+                .targets
+                .iter()
+                .find(|t| t.container_id == id)
+                .and_then(|t| t.last_sent)
+        })
+        .min()
+        .flatten();
+    Some(unsent_count(
+        &sync.sync_mode,
+        snapshots.iter().map(|s| s.datetime),
+        oldest_sent,
+    ))
+}
+
+// Latest scheduled syncs only send the newest snapshot, so at most one is waiting however many were taken.
+fn unsent_count(
+    sync_mode: &SnapshotSyncMode, snapshots: impl Iterator<Item = DateTime<Utc>>, oldest_sent: Option<DateTime<Utc>>,
+) -> usize {
+    let unsent = snapshots
+        .filter(|datetime| oldest_sent.map_or(true, |sent| *datetime > sent))
+        .count();
+    match sync_mode {
+        SnapshotSyncMode::LatestScheduled(_) => unsent.min(1),
+        SnapshotSyncMode::AllScheduled(_) | SnapshotSyncMode::AllImmediate | SnapshotSyncMode::IntervalImmediate(_) => {
+            unsent
+        }
+    }
+}
+
 #[derive(Clap, Debug)]
 pub struct SyncShowOptions {
     /// The name or id of the sync
@@ -119,10 +506,96 @@ pub struct SyncShowOptions {
     sync: String,
 }
 
-pub fn show_sync(_options: SyncShowOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+pub async fn show_sync(options: SyncShowOptions) -> Result<()> {
+    debug!("Command 'show_sync': {:?}", options);
+
+    let entities = storage::load_entity_config()?;
+    let sync = snapshot_sync_search(&entities, &options.sync)?;
+    let running = running_jobs().await;
+    let last_sync = last_succeeded_jobs(ObservableEvent::SnapshotSync).remove(&sync.id());
+    let cursor = load_sync_cursor(sync.sync_id()).unwrap_or_else(|e| {
+        debug!("failed to load the cursor of {}: {}", sync.name(), e);
+        SyncCursor::default()
+    });
+
+    // Snapshots are forwarded from the source container when the sync has one.
+    let source_snapshots = match sync.source_container_id {
+        Some(container_id) => api::container_snapshots(&entities, container_id, sync.dataset_id).await,
+        None => api::dataset_snapshots(&entities, sync.dataset_id),
+    };
+    let source_snapshots = source_snapshots
+        .map_err(|e| warn!("failed to list the source snapshots: {}", e))
+        .ok();
+
+    let mut rows = vec![
+        (comfy_id_header(), comfy_id_value_full(sync.id()).into()),
+        (Cell::new("Sync Name"), comfy_name_value(sync.name()).into()),
+        (
+            Cell::new("Dataset Name"),
+            comfy_value_or(
+                entities.dataset(sync.dataset_id).map(|d| d.entity.name().to_owned()),
+                "-",
+            )
+            .into(),
+        ),
+        (
+            Cell::new("Source Snapshots"),
+            comfy_value_or(source_snapshots.as_ref().map(Vec::len), "-").into(),
+        ),
+        (
+            Cell::new("Last Sync"),
+            comfy_value_or(last_sync.map(|r| r.finished()), "-").into(),
+        ),
+        (
+            Cell::new("Next Sync"),
+            comfy_value_or(next_sync(&entities, sync, &SystemClock), "-").into(),
+        ),
+        (
+            Cell::new("Job"),
+            comfy_running_value(running.as_ref(), sync.id()).into(),
+        ),
+    ];
+    rows.extend(comfy_annotation_rows(&sync.annotations));
+    print_comfy_info(rows);
+
+    let mut rows = Vec::new();
+    for container_id in sync.container_ids() {
+        let newest_received = match api::container_snapshots(&entities, container_id, sync.dataset_id).await {
+            Ok(snapshots) => snapshots.last().map(|s| s.datetime),
+            Err(e) => {
+                warn!("failed to list the snapshots of container {}: {}", container_id, e);
+                None
+            }
+        };
+        let pending = source_snapshots.as_ref().map(|snapshots| {
+            snapshots
+                .iter()
+                .filter(|s| newest_received.map_or(true, |received| s.datetime > received))
+                .count()
+        });
+        let target = cursor.targets.iter().find(|t| t.container_id == container_id);
+        let last_failure = target.and_then(|t| t.last_failure.as_ref());
+        rows.push(vec![
+            entities
+                .entity(container_id.into())
+                .map_or_else(|| comfy_id_value(container_id), |c| comfy_name_value(c.name())),
+            comfy_value_or(newest_received, "-"),
+            comfy_value_or(pending, "-"),
+            comfy_value_or(last_failure.map(|f| format!("{}: {}", f.time, f.message)), "-"),
+            comfy_value_or(target.and_then(|t| t.retry_at), "-"),
+        ]);
+    }
+    print_comfy_table(
+        vec![
+            Cell::new("Container Name"),
+            Cell::new("Newest Received"),
+            Cell::new("Pending"),
+            Cell::new("Last Failure"),
+            Cell::new("Retry At"),
+        ],
+        rows.into_iter(),
+    );
 
-    //storage::store_entity_state(entities);
     Ok(())
 }
 
@@ -133,9 +606,133 @@ pub struct SyncDeleteOptions {
     sync: String,
 }
 
-pub fn delete_sync(_options: SyncDeleteOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+pub fn delete_sync(options: SyncDeleteOptions) -> Result<()> {
+    debug!("Command 'delete_sync': {:?}", options);
+
+    let mut entities = storage::load_entity_config()?;
+    let sync = snapshot_sync_search(&entities, &options.sync)?;
+    let id = sync.id();
+    confirm_or_abort(format!("Delete sync {}?", sync.name()))?;
+
+    let position = entities
+        .snapshot_syncs
+        .iter()
+        .position(|s| s.id() == id)
+        .expect("id always exists");
+    let sync = entities.snapshot_syncs.remove(position);
+    let name = sync.name().to_owned();
+    entities.trash_entity(TrashedEntityKind::SnapshotSync(sync));
+
+    storage::store_entity_config(entities)?;
+    info!("Deleted sync '{}', use 'undo' to restore it", name);
 
-    //storage::store_entity_state(entities);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use libblkcapt::{
+        core::clock::ManualClock,
+        model::entities::{BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity},
+    };
+    use uuid::Uuid;
+
+    fn datetime(hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 5, 1).and_hms(hour, 0, 0)
+    }
+
+    fn hourly() -> SnapshotSyncMode {
+        SnapshotSyncMode::LatestScheduled("0 0 * * * *".parse().unwrap())
+    }
+
+    fn home_sync() -> (Entities, SnapshotSyncEntity) {
+        let mut pool = BtrfsPoolEntity::new("tank".to_owned(), "/mnt/tank".into(), Uuid::new_v4(), Vec::new()).unwrap();
+        let dataset = BtrfsDatasetEntity::new("home".to_owned(), "home".into(), Uuid::new_v4()).unwrap();
+        let container = BtrfsContainerEntity::new("local".to_owned(), "local".into(), Uuid::new_v4()).unwrap();
+        let sync = SnapshotSyncEntity::new("home-local".to_owned(), dataset.dataset_id(), container.container_id());
+        pool.attach_dataset(dataset).unwrap();
+        pool.attach_container(container).unwrap();
+        let entities = Entities {
+            btrfs_pools: vec![pool],
+            ..Default::default()
+        };
+        (entities, sync)
+    }
+
+    #[test]
+    fn next_sync_by_mode() {
+        let (entities, mut sync) = home_sync();
+        let clock = ManualClock::new(Utc.ymd(2021, 5, 1).and_hms(10, 30, 0));
+
+        assert_eq!(next_sync(&entities, &sync, &clock).as_deref(), Some("on snapshot"));
+        sync.sync_mode = hourly();
+        assert_eq!(next_sync(&entities, &sync, &clock), Some(datetime(11).to_string()));
+        sync.sync_mode = SnapshotSyncMode::AllScheduled("0 0 0 1 1 * 2020".parse().unwrap());
+        assert_eq!(next_sync(&entities, &sync, &clock), None);
+    }
+
+    #[test]
+    fn next_sync_respects_disabled_and_paused() {
+        let (mut entities, mut sync) = home_sync();
+        let clock = ManualClock::new(datetime(10));
+
+        entities.btrfs_pools[0].datasets[0].pause_snapshotting = true;
+        assert_eq!(next_sync(&entities, &sync, &clock), None);
+        sync.sync_mode = hourly();
+        assert_eq!(next_sync(&entities, &sync, &clock), Some(datetime(11).to_string()));
+
+        sync.disabled = true;
+        assert_eq!(next_sync(&entities, &sync, &clock), None);
+        sync.disabled = false;
+        entities.btrfs_pools[0].datasets[0].disabled = true;
+        assert_eq!(next_sync(&entities, &sync, &clock), None);
+    }
+
+    #[test]
+    fn update_clears_optional_settings() {
+        let (_, mut sync) = home_sync();
+        sync.min_transfer_bytes = Some(1024);
+        sync.hold_lease = Some(std::time::Duration::from_secs(60));
+        sync.restic_backup.excludes = vec!["*.tmp".to_owned()];
+        sync.sync_hooks.pre = Some("/usr/local/bin/wake-nas".into());
+        sync.sync_hooks.post = Some("/usr/local/bin/sleep-nas".into());
+
+        let options = SyncUpdateOptions::try_parse_from(&[
+            "update",
+            "home-local",
+            "--no-min-transfer-bytes",
+            "--no-restic-exclude",
+            "--no-pre-sync-hook",
+        ])
+        .unwrap();
+        options.clear_settings(&mut sync);
+        assert_eq!(sync.min_transfer_bytes, None);
+        assert!(sync.restic_backup.excludes.is_empty());
+        assert_eq!(sync.sync_hooks.pre, None);
+        assert!(sync.hold_lease.is_some());
+        assert!(sync.sync_hooks.post.is_some());
+
+        assert!(SyncUpdateOptions::try_parse_from(&[
+            "update",
+            "home-local",
+            "--no-min-transfer-bytes",
+            "--min-transfer-bytes",
+            "10"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn unsent_count_after_oldest_sent() {
+        let snapshots = || (1..=4).map(datetime);
+        let all = SnapshotSyncMode::AllImmediate;
+
+        assert_eq!(unsent_count(&all, snapshots(), None), 4);
+        assert_eq!(unsent_count(&all, snapshots(), Some(datetime(2))), 2);
+        assert_eq!(unsent_count(&all, snapshots(), Some(datetime(4))), 0);
+        assert_eq!(unsent_count(&hourly(), snapshots(), Some(datetime(2))), 1);
+        assert_eq!(unsent_count(&hourly(), snapshots(), Some(datetime(4))), 0);
+    }
+}
@@ -0,0 +1,518 @@
+use anyhow::{bail, Context, Result};
+use bytes::buf::Buf;
+use clap::Clap;
+use libblkcapt::{
+    api,
+    core::{
+        system::{ActiveState, ActorState, SystemEventStage, SystemState, TerminalState},
+        BtrfsPool,
+    },
+    model::{DatasetId, Entities, Entity, EntityId},
+    sys::{btrfs::SpaceUsage, net::ServiceClient},
+};
+use nix::{
+    libc,
+    sys::termios::{self, LocalFlags, SetArg, Termios},
+};
+use slog_scope::*;
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+use crate::ui::format_bytes;
+
+/// Pool usage runs btrfs for every pool, so it is refreshed less often than the service state.
+const POOL_USAGE_INTERVAL: Duration = Duration::from_secs(30);
+const EVENT_LINES: usize = 8;
+const BAR_WIDTH: usize = 20;
+
+const RESET: &str = "\x1B[0m";
+const BOLD: &str = "\x1B[1m";
+const REVERSE: &str = "\x1B[7m";
+const GREEN: &str = "\x1B[32m";
+const YELLOW: &str = "\x1B[33m";
+const RED: &str = "\x1B[31m";
+const CYAN: &str = "\x1B[36m";
+
+/// Live dashboard of the running service
+#[derive(Clap, Debug)]
+pub struct TopOptions {
+    /// Seconds between refreshes
+    #[clap(short('n'), long, value_name("seconds"), default_value("2"))]
+    interval: u64,
+}
+
+pub async fn top(options: TopOptions) -> Result<()> {
+    debug!("Command 'top': {:?}", options);
+
+    let client = api::connect_service().await?;
+    let _terminal = RawTerminal::enter()?;
+    let mut keys = spawn_key_reader();
+    let mut refresh = tokio::time::interval(Duration::from_secs(options.interval.max(1)));
+    let mut dashboard = Dashboard::default();
+
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => dashboard.refresh(&client).await,
+            key = keys.recv() => match key {
+                None | Some(Key::Quit) => break,
+                Some(key) => dashboard.key(&client, key).await,
+            },
+        }
+        dashboard.draw()?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Quit,
+    Up,
+    Down,
+    Cancel,
+    Snapshot,
+}
+
+// Stdin is read on a thread of its own, a blocking read in the runtime would hold up its shutdown.
+fn spawn_key_reader() -> mpsc::UnboundedReceiver<Key> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buffer = [0u8; 16];
+        let mut parser = KeyParser::default();
+        while let Ok(read) = stdin.read(&mut buffer) {
+            if read == 0 {
+                return;
+            }
+            for key in parser.feed(&buffer[..read]) {
+                if sender.send(key).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// Escape sequences can be split across reads, an incomplete one is kept until the next read completes it.
+#[derive(Default)]
+struct KeyParser {
+    pending: Vec<u8>,
+}
+
+impl KeyParser {
+    fn feed(&mut self, input: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(input);
+        let mut keys = Vec::new();
+        let mut index = 0;
+        while index < self.pending.len() {
+            let (key, length) = match &self.pending[index..] {
+                [0x1B] | [0x1B, b'['] => break,
+                [0x1B, b'[', b'A', ..] => (Some(Key::Up), 3),
+                [0x1B, b'[', b'B', ..] => (Some(Key::Down), 3),
+                [0x1B, b'[', ..] => (None, 3),
+                [0x1B, ..] => (None, 2),
+                [byte, ..] => (
+                    match byte {
+                        b'q' | b'Q' | 0x03 => Some(Key::Quit),
+                        b'k' => Some(Key::Up),
+                        b'j' => Some(Key::Down),
+                        b'c' => Some(Key::Cancel),
+                        b's' => Some(Key::Snapshot),
+                        _ => None,
+                    },
+                    1,
+                ),
+                [] => break,
+            };
+            keys.extend(key);
+            index += length;
+        }
+        self.pending.drain(..index);
+        keys
+    }
+}
+
+/// Switches the terminal to an alternate screen with unbuffered input, restoring it when dropped.
+struct RawTerminal {
+    original: Termios,
+}
+
+impl RawTerminal {
+    fn enter() -> Result<Self> {
+        let original = termios::tcgetattr(libc::STDIN_FILENO).context("top needs an interactive terminal")?;
+        let mut raw = original.clone();
+        // Without signals Ctrl-C arrives as a key, so the terminal is restored before exiting.
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG);
+        termios::tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, &raw)?;
+        print!("\x1B[?1049h\x1B[?25l");
+        io::stdout().flush()?;
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(libc::STDIN_FILENO, SetArg::TCSANOW, &self.original);
+        print!("\x1B[?25h\x1B[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
+
+nix::ioctl_read_bad!(window_size, libc::TIOCGWINSZ, libc::winsize);
+
+fn terminal_size() -> (usize, usize) {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    match unsafe { window_size(libc::STDOUT_FILENO, &mut size) } {
+        Ok(_) if size.ws_col > 0 && size.ws_row > 0 => (size.ws_col as usize, size.ws_row as usize),
+        _ => (80, 24),
+    }
+}
+
+#[derive(Default)]
+struct Dashboard {
+    entities: Entities,
+    state: Option<SystemState>,
+    pools: Vec<(String, Option<SpaceUsage>)>,
+    pools_refreshed: Option<Instant>,
+    error: Option<String>,
+    notice: Option<String>,
+    selected: usize,
+}
+
+impl Dashboard {
+    async fn refresh(&mut self, client: &ServiceClient) {
+        match fetch_state(client).await {
+            Ok(state) => {
+                self.selected = self.selected.min(state.actors.len().saturating_sub(1));
+                self.state = Some(state);
+                self.error = None;
+            }
+            Err(error) => self.error = Some(format!("Failed to get the service status: {:#}", error)),
+        }
+        // Keep showing the last entities that loaded when the config can't be read.
+        match api::load_config() {
+            Ok(entities) => self.entities = entities,
+            Err(error) => self.error = Some(format!("Failed to load the configuration: {:#}", error)),
+        }
+
+        if self
+            .pools_refreshed
+            .map_or(true, |at| at.elapsed() >= POOL_USAGE_INTERVAL)
+        {
+            self.pools = self
+                .entities
+                .btrfs_pools
+                .iter()
+                .map(|pool| {
+                    let usage = BtrfsPool::validate(pool.clone()).and_then(|p| p.space_usage()).ok();
+                    (pool.name().to_owned(), usage)
+                })
+                .collect();
+            self.pools_refreshed = Some(Instant::now());
+        }
+    }
+
+    async fn key(&mut self, client: &ServiceClient, key: Key) {
+        let actor = self
+            .state
+            .as_ref()
+            .and_then(|s| s.actors.get(self.selected))
+            .map(|a| (a.actor_id, a.entity_id));
+        match key {
+            Key::Up => self.selected = self.selected.saturating_sub(1),
+            Key::Down => {
+                let actors = self.state.as_ref().map_or(0, |s| s.actors.len());
+                self.selected = (self.selected + 1).min(actors.saturating_sub(1));
+            }
+            Key::Cancel => {
+                self.notice = Some(match actor {
+                    Some((actor_id, _)) => match stop_actor(client, actor_id).await {
+                        Ok(()) => format!("Stopping actor {}.", actor_id),
+                        Err(error) => format!("Failed to stop actor {}: {:#}", actor_id, error),
+                    },
+                    None => String::from("No actor selected."),
+                })
+            }
+            Key::Snapshot => {
+                let dataset = actor
+                    .and_then(|(_, entity_id)| entity_id)
+                    .and_then(|id| self.entities.datasets().find(|d| d.entity.id() == id));
+                self.notice = Some(match dataset {
+                    Some(dataset) => {
+                        let name = dataset.entity.name().to_owned();
+                        match snapshot_dataset(client, dataset.entity.dataset_id()).await {
+                            Ok(()) => format!("Took a snapshot of dataset {}.", name),
+                            Err(error) => format!("Failed to snapshot dataset {}: {:#}", name, error),
+                        }
+                    }
+                    None => String::from("The selected actor does not belong to a dataset."),
+                })
+            }
+            Key::Quit => {}
+        }
+    }
+
+    fn draw(&self) -> Result<()> {
+        let (width, height) = terminal_size();
+        let mut lines = vec![
+            format!(
+                "{}blockcaptain top{}  q quit  ↑/↓ select  c cancel actor  s snapshot dataset",
+                BOLD, RESET
+            ),
+            self.error
+                .as_ref()
+                .map(|e| format!("{}{}{}", RED, fit(e, width), RESET))
+                .or_else(|| self.notice.as_ref().map(|n| fit(n, width)))
+                .unwrap_or_default(),
+        ];
+
+        lines.push(heading("Pools"));
+        for (name, usage) in self.pools.iter() {
+            lines.push(match usage {
+                Some(usage) => {
+                    let used = usage.capacity.saturating_sub(usage.free);
+                    let fraction = used as f64 / usage.capacity.max(1) as f64;
+                    fit(
+                        &format!(
+                            "  {:<20} {} {:>5.1}%  {} of {}",
+                            name,
+                            bar(fraction),
+                            fraction * 100.0,
+                            format_bytes(used),
+                            format_bytes(usage.capacity)
+                        ),
+                        width,
+                    )
+                }
+                None => fit(&format!("  {:<20} unavailable", name), width),
+            });
+        }
+
+        let state = match &self.state {
+            Some(state) => state,
+            None => return render(&lines, height),
+        };
+
+        lines.push(heading("Jobs"));
+        let now = unix_now();
+        for job in state.jobs.iter() {
+            let elapsed = Duration::from_secs(now.saturating_sub(job.started.timestamp()).max(0) as u64);
+            let progress = match job.typical {
+                // Estimated from earlier runs, so it never shows a running job as complete.
+                Some(typical) => format!(
+                    "{} {} of ~{}",
+                    bar((elapsed.as_secs_f64() / typical.as_secs_f64().max(1.0)).min(0.99)),
+                    humantime::format_duration(elapsed),
+                    humantime::format_duration(Duration::from_secs(typical.as_secs()))
+                ),
+                None => format!(
+                    "{:<width$} {}",
+                    "",
+                    humantime::format_duration(elapsed),
+                    width = BAR_WIDTH + 2
+                ),
+            };
+            lines.push(fit(
+                &format!(
+                    "  {:<28} {:<22} {}",
+                    self.entity_name(job.entity_id),
+                    job.event,
+                    progress
+                ),
+                width,
+            ));
+        }
+
+        let events = state.events.iter().rev().take(EVENT_LINES).collect::<Vec<_>>();
+        let actor_lines = height.saturating_sub(lines.len() + 2 + events.len());
+        lines.push(heading(&format!("Actors ({})", state.actors.len())));
+        let first = self.selected.saturating_sub(actor_lines.saturating_sub(1));
+        for (index, actor) in state.actors.iter().enumerate().skip(first).take(actor_lines) {
+            let (state_color, substate) = actor_state(&actor.actor_state);
+            let text = fit(
+                &format!(
+                    "  {:>5} {:<22} {:<9} {:<36} {}",
+                    actor.actor_id,
+                    actor.actor_type,
+                    actor.actor_state.to_string(),
+                    substate,
+                    actor.entity_id.map(|id| self.entity_name(id)).unwrap_or_default()
+                ),
+                width,
+            );
+            lines.push(if index == self.selected {
+                format!("{}{}{}", REVERSE, text, RESET)
+            } else {
+                format!("{}{}{}", state_color, text, RESET)
+            });
+        }
+
+        lines.push(heading("Events"));
+        for event in events {
+            let color = match event.stage {
+                SystemEventStage::Starting => CYAN,
+                SystemEventStage::Succeeded => GREEN,
+                SystemEventStage::Warning => YELLOW,
+                SystemEventStage::Failed => RED,
+            };
+            let text = fit(
+                &format!(
+                    "  {} {:<28} {:<22} {} {}",
+                    event.time.format("%T UTC"),
+                    self.entity_name(event.entity_id),
+                    event.event,
+                    event.stage,
+                    event.message.as_deref().unwrap_or_default()
+                ),
+                width,
+            );
+            lines.push(format!("{}{}{}", color, text, RESET));
+        }
+
+        render(&lines, height)
+    }
+
+    fn entity_name(&self, id: EntityId) -> String {
+        self.entities
+            .entity(id)
+            .map_or_else(|| id.to_string(), |e| format!("{} {}", e.entity_type(), e.name()))
+    }
+}
+
+async fn fetch_state(client: &ServiceClient) -> Result<SystemState> {
+    let response = client.get("/").await?;
+    let body = hyper::body::aggregate(response).await?;
+    let mut state: SystemState = serde_json::from_reader(body.reader())?;
+    state.actors.sort_by_key(|a| a.actor_id);
+    Ok(state)
+}
+
+async fn stop_actor(client: &ServiceClient, actor_id: u64) -> Result<()> {
+    let response = client
+        .post(&format!("/actors/{}/stop", actor_id), String::new())
+        .await?;
+    if !response.status().is_success() {
+        bail!("the service answered {}", response.status());
+    }
+    Ok(())
+}
+
+// The service takes the snapshot, so it is observed and triggers syncs like a scheduled one.
+async fn snapshot_dataset(client: &ServiceClient, dataset_id: DatasetId) -> Result<()> {
+    let response = client
+        .post(&format!("/datasets/{}/snapshot", dataset_id), String::new())
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = hyper::body::to_bytes(response).await?;
+        bail!("the service answered {}: {}", status, String::from_utf8_lossy(&body));
+    }
+    Ok(())
+}
+
+fn actor_state(state: &ActorState) -> (&'static str, String) {
+    match state {
+        ActorState::Started(ActiveState::Custom(status)) => (GREEN, status.clone()),
+        ActorState::Started(ActiveState::Unresponsive) => (RED, ActiveState::Unresponsive.to_string()),
+        ActorState::Started(ActiveState::Stopping) => (YELLOW, ActiveState::Stopping.to_string()),
+        ActorState::Stopped(terminal) | ActorState::Dropped(terminal) | ActorState::Zombie(terminal) => (
+            match terminal {
+                TerminalState::Succeeded => CYAN,
+                TerminalState::Cancelled => YELLOW,
+                TerminalState::Failed | TerminalState::Faulted | TerminalState::Indeterminate => RED,
+            },
+            terminal.to_string(),
+        ),
+    }
+}
+
+fn render(lines: &[String], height: usize) -> Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1B[H")?;
+    for line in lines.iter().take(height) {
+        write!(stdout, "{}\x1B[K\r\n", line)?;
+    }
+    write!(stdout, "\x1B[J")?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn heading(title: &str) -> String {
+    format!("{}{}{}", BOLD, title, RESET)
+}
+
+fn bar(fraction: f64) -> String {
+    let filled = ((fraction.max(0.0).min(1.0) * BAR_WIDTH as f64).round() as usize).min(BAR_WIDTH);
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+fn fit(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_parse() {
+        let mut parser = KeyParser::default();
+        assert_eq!(
+            parser.feed(b"jkx\x1B[A\x1B[Bcsq"),
+            vec![
+                Key::Down,
+                Key::Up,
+                Key::Up,
+                Key::Down,
+                Key::Cancel,
+                Key::Snapshot,
+                Key::Quit
+            ]
+        );
+        assert_eq!(parser.feed(&[0x03]), vec![Key::Quit]);
+        // Other escape sequences and alt combinations are ignored, not read as their keys.
+        assert_eq!(parser.feed(b"\x1B[Cj\x1Bq"), vec![Key::Down]);
+    }
+
+    #[test]
+    fn keys_escape_sequence_split_across_reads() {
+        let mut parser = KeyParser::default();
+        assert_eq!(parser.feed(b"j\x1B"), vec![Key::Down]);
+        assert_eq!(parser.feed(b"["), Vec::<Key>::new());
+        assert_eq!(parser.feed(b"Ak"), vec![Key::Up, Key::Up]);
+        assert!(parser.pending.is_empty());
+    }
+
+    #[test]
+    fn bar_fills_fraction() {
+        assert_eq!(bar(0.0), format!("[{}]", "-".repeat(BAR_WIDTH)));
+        assert_eq!(bar(0.5), format!("[{}{}]", "#".repeat(10), "-".repeat(10)));
+        assert_eq!(bar(1.5), format!("[{}]", "#".repeat(BAR_WIDTH)));
+        assert_eq!(bar(-1.0), bar(0.0));
+        assert_eq!(bar(f64::NAN), bar(0.0));
+    }
+
+    #[test]
+    fn fit_truncates_characters() {
+        assert_eq!(fit("pool ✓ ok", 6), "pool ✓");
+        assert_eq!(fit("short", 80), "short");
+        assert_eq!(fit("", 3), "");
+    }
+}
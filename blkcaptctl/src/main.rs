@@ -1,29 +1,59 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    io,
     process::exit,
 };
 
 use anyhow::{anyhow, Result};
 use blkcaptapp::{
     blkcaptapp_run,
-    slogext::{CustomFullFormat, SyncDrain},
+    slogext::{CustomFullFormat, JsonFormat, SyncDrain},
 };
-use clap::{crate_version, Clap};
+use clap::{crate_version, AppSettings, Clap};
 mod commands;
 mod ui;
+use commands::audit::*;
+use commands::config::*;
+use commands::dev::*;
+use commands::generate::*;
+use commands::group::*;
 use commands::observer::*;
 use commands::pool::*;
+use commands::report::*;
 use commands::restic::*;
+use commands::rollback::*;
+use commands::secret::*;
 use commands::service::*;
+use commands::stats::*;
 use commands::sync::*;
+use commands::top::*;
+use libblkcapt::{
+    core::run_ping_helper,
+    error::{coded, ErrorCode},
+    model::{storage::load_server_config, BcLogFormat},
+};
 use slog::Drain;
+use ui::set_assume_yes;
 
 fn main() {
+    if let Some(code) = run_ping_helper() {
+        exit(code);
+    }
     let maybe_options = CliOptions::try_parse();
     let vcount = maybe_options.as_ref().map(|o| o.verbose as usize).unwrap_or_default();
 
-    let slog_drain = {
+    let log_format = maybe_options
+        .as_ref()
+        .ok()
+        .and_then(|o| o.log_format)
+        .or_else(|| load_server_config().ok().map(|c| c.log_format))
+        .unwrap_or_default();
+
+    let slog_drain = if log_format == BcLogFormat::Json {
+        let drain = JsonFormat::new(io::stderr()).fuse();
+        slog_atomic::AtomicSwitch::new(drain)
+    } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = CustomFullFormat::new(decorator, false).fuse();
         let drain = SyncDrain::new(drain);
@@ -38,7 +68,7 @@ async fn async_main(options: clap::Result<CliOptions>) -> Result<()> {
         Ok(options) => command_dispath(options).await,
         Err(e) => {
             if e.use_stderr() {
-                Err(anyhow!(ClapErrorWrapper(e)))
+                Err(coded(ErrorCode::ValidationFailed, anyhow!(ClapErrorWrapper(e))))
             } else {
                 println!("{}", e);
                 Ok(())
@@ -48,23 +78,47 @@ async fn async_main(options: clap::Result<CliOptions>) -> Result<()> {
 }
 
 async fn command_dispath(options: CliOptions) -> Result<()> {
+    set_assume_yes(options.yes);
     match options.subcmd {
         TopCommands::Pool(top_options) => match top_options.subcmd {
             PoolSubCommands::Attach(options) => attach_pool(options),
             PoolSubCommands::Create(options) => create_pool(options),
+            PoolSubCommands::Detach(options) => detach_pool(options),
+            PoolSubCommands::Recover(options) => recover_pool(options),
             PoolSubCommands::List(options) => list_pool(options),
+            PoolSubCommands::Show(options) => show_pool(options),
+            PoolSubCommands::Update(options) => update_pool(options),
+            PoolSubCommands::Convert(options) => convert_pool(options).await,
+            PoolSubCommands::Resize(options) => resize_pool(options),
+            PoolSubCommands::Device(device_options) => match device_options.subcmd {
+                PoolDeviceSubCommands::Add(options) => add_pool_device(options),
+                PoolDeviceSubCommands::Remove(options) => remove_pool_device(options),
+                PoolDeviceSubCommands::Replace(options) => replace_pool_device(options),
+            },
         },
         TopCommands::Dataset(top_options) => match top_options.subcmd {
             DatasetSubCommands::Attach(options) => attach_dataset(options),
             DatasetSubCommands::Create(options) => create_dataset(options),
-            DatasetSubCommands::List(options) => list_dataset(options),
+            DatasetSubCommands::List(options) => list_dataset(options).await,
             DatasetSubCommands::Update(options) => update_dataset(options),
             DatasetSubCommands::Show(options) => show_dataset(options),
+            DatasetSubCommands::Discover(options) => discover_dataset(options),
+            DatasetSubCommands::Snapshots(options) => list_dataset_snapshots(options).await,
+            DatasetSubCommands::Snapshot(options) => snapshot_dataset(options),
+        },
+        TopCommands::Group(top_options) => match top_options.subcmd {
+            GroupSubCommands::Create(options) => create_group(options),
+            GroupSubCommands::Update(options) => update_group(options),
+            GroupSubCommands::Delete(options) => delete_group(options),
+            GroupSubCommands::List(options) => list_group(options),
         },
         TopCommands::Container(top_options) => match top_options.subcmd {
             ContainerSubCommands::Attach(options) => attach_container(options),
             ContainerSubCommands::Create(options) => create_container(options),
+            ContainerSubCommands::Update(options) => update_container(options),
             ContainerSubCommands::List(options) => list_container(options),
+            ContainerSubCommands::Show(options) => show_container(options).await,
+            ContainerSubCommands::Snapshots(options) => list_container_snapshots(options).await,
         },
         TopCommands::Observer(top_options) => match top_options.subcmd {
             ObserverSubCommands::Create(options) => create_observer(options),
@@ -78,16 +132,44 @@ async fn command_dispath(options: CliOptions) -> Result<()> {
             SyncSubCommands::Create(options) => create_sync(options),
             SyncSubCommands::Update(options) => update_sync(options),
             SyncSubCommands::Delete(options) => delete_sync(options),
-            SyncSubCommands::Show(options) => show_sync(options),
-            SyncSubCommands::List(options) => list_sync(options),
+            SyncSubCommands::Show(options) => show_sync(options).await,
+            SyncSubCommands::List(options) => list_sync(options).await,
         },
         TopCommands::Restic(top_options) => match top_options.subcmd {
             ResticSubCommands::Attach(options) => attach_restic(options),
             ResticSubCommands::Update(options) => update_restic(options),
+            ResticSubCommands::Show(options) => show_restic(options).await,
+            ResticSubCommands::MigratePassword(options) => migrate_restic_password(options),
+        },
+        TopCommands::Secret(top_options) => match top_options.subcmd {
+            SecretSubCommands::Set(options) => set_secret(options),
         },
         TopCommands::Service(top_options) => match top_options.subcmd {
             ServiceSubCommands::Status(options) => service_status(options).await,
             ServiceSubCommands::Config(options) => service_config(options).await,
+            ServiceSubCommands::LogLevel(options) => service_log_level(options).await,
+        },
+        TopCommands::Audit(options) => audit(options).await,
+        TopCommands::Config(top_options) => match top_options.subcmd {
+            ConfigSubCommands::History(options) => config_history(options),
+            ConfigSubCommands::Export(options) => config_export(options),
+            ConfigSubCommands::Import(options) => config_import(options),
+        },
+        TopCommands::Stats(options) => stats(options),
+        TopCommands::Top(options) => top(options).await,
+        TopCommands::Undo(options) => undo(options),
+        TopCommands::RestoreEntity(options) => restore_entity(options),
+        TopCommands::Enable(options) => enable(options),
+        TopCommands::Disable(options) => disable(options),
+        TopCommands::Annotate(options) => annotate(options),
+        TopCommands::Generate(top_options) => match top_options.subcmd {
+            GenerateSubCommands::SystemdUnits(options) => generate_systemd_units(options),
+        },
+        TopCommands::Rollback(options) => rollback(options),
+        TopCommands::Report(options) => report(options).await,
+        TopCommands::Dev(top_options) => match top_options.subcmd {
+            DevSubCommands::CreateSandbox(options) => create_sandbox(options),
+            DevSubCommands::DestroySandbox(options) => destroy_sandbox(options),
         },
     }
 }
@@ -98,6 +180,12 @@ struct CliOptions {
     /// Enable debug logs. Use twice to enable trace logs.
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
+    /// Log output format, defaults to the service config's log format
+    #[clap(long, value_name("format"))]
+    log_format: Option<BcLogFormat>,
+    /// Answer yes to every confirmation, required to run destructive commands without a terminal
+    #[clap(short('y'), long, global(true))]
+    yes: bool,
     #[clap(subcommand)]
     subcmd: TopCommands,
 }
@@ -106,11 +194,29 @@ struct CliOptions {
 enum TopCommands {
     Pool(PoolCommands),
     Dataset(DatasetCommands),
+    Group(GroupCommands),
     Container(ContainerCommands),
     Observer(ObserverCommands),
     Sync(SyncCommands),
     Restic(ResticCommands),
+    Secret(SecretCommands),
     Service(ServiceCommands),
+    Audit(AuditOptions),
+    Config(ConfigCommands),
+    Stats(StatsOptions),
+    Top(TopOptions),
+    Undo(UndoOptions),
+    RestoreEntity(RestoreEntityOptions),
+    Enable(EnableOptions),
+    Disable(DisableOptions),
+    Annotate(AnnotateOptions),
+    /// Generate deployment files from the configuration
+    Generate(GenerateCommands),
+    Rollback(RollbackOptions),
+    Report(ReportOptions),
+    /// Development tools
+    #[clap(setting = AppSettings::Hidden)]
+    Dev(DevCommands),
 }
 
 #[derive(Clap)]
@@ -123,7 +229,27 @@ struct PoolCommands {
 enum PoolSubCommands {
     Create(PoolCreateOptions),
     Attach(PoolAttachOptions),
+    Detach(PoolDetachOptions),
+    Recover(PoolRecoverOptions),
     List(PoolListOptions),
+    Show(PoolShowOptions),
+    Update(PoolUpdateOptions),
+    Device(PoolDeviceCommands),
+    Convert(PoolConvertOptions),
+    Resize(PoolResizeOptions),
+}
+
+#[derive(Clap)]
+struct PoolDeviceCommands {
+    #[clap(subcommand)]
+    subcmd: PoolDeviceSubCommands,
+}
+
+#[derive(Clap)]
+enum PoolDeviceSubCommands {
+    Add(PoolDeviceAddOptions),
+    Remove(PoolDeviceRemoveOptions),
+    Replace(PoolDeviceReplaceOptions),
 }
 
 #[derive(Clap)]
@@ -140,6 +266,23 @@ enum DatasetSubCommands {
     List(DatasetListOptions),
     Update(DatasetUpdateOptions),
     Show(DatasetShowOptions),
+    Discover(DatasetDiscoverOptions),
+    Snapshots(DatasetSnapshotsOptions),
+    Snapshot(DatasetSnapshotOptions),
+}
+
+#[derive(Clap)]
+struct GroupCommands {
+    #[clap(subcommand)]
+    subcmd: GroupSubCommands,
+}
+
+#[derive(Clap)]
+enum GroupSubCommands {
+    Create(GroupCreateOptions),
+    Update(GroupUpdateOptions),
+    Delete(GroupDeleteOptions),
+    List(GroupListOptions),
 }
 
 #[derive(Clap)]
@@ -152,7 +295,10 @@ struct ContainerCommands {
 enum ContainerSubCommands {
     Attach(ContainerAttachOptions),
     Create(ContainerCreateOptions),
+    Update(ContainerUpdateOptions),
     List(ContainerListOptions),
+    Show(ContainerShowOptions),
+    Snapshots(ContainerSnapshotsOptions),
 }
 
 #[derive(Clap)]
@@ -196,6 +342,19 @@ struct ResticCommands {
 enum ResticSubCommands {
     Attach(ResticAttachOptions),
     Update(ResticUpdateOptions),
+    Show(ResticShowOptions),
+    MigratePassword(ResticMigratePasswordOptions),
+}
+
+#[derive(Clap)]
+struct SecretCommands {
+    #[clap(subcommand)]
+    subcmd: SecretSubCommands,
+}
+
+#[derive(Clap)]
+enum SecretSubCommands {
+    Set(SecretSetOptions),
 }
 
 #[derive(Clap)]
@@ -208,6 +367,43 @@ struct ServiceCommands {
 enum ServiceSubCommands {
     Status(ServiceStatusOptions),
     Config(ServiceConfigOptions),
+    LogLevel(ServiceLogLevelOptions),
+}
+
+#[derive(Clap)]
+struct ConfigCommands {
+    #[clap(subcommand)]
+    subcmd: ConfigSubCommands,
+}
+
+#[derive(Clap)]
+enum ConfigSubCommands {
+    History(ConfigHistoryOptions),
+    Export(ConfigExportOptions),
+    Import(ConfigImportOptions),
+}
+
+#[derive(Clap)]
+struct GenerateCommands {
+    #[clap(subcommand)]
+    subcmd: GenerateSubCommands,
+}
+
+#[derive(Clap)]
+enum GenerateSubCommands {
+    SystemdUnits(GenerateSystemdUnitsOptions),
+}
+
+#[derive(Clap)]
+struct DevCommands {
+    #[clap(subcommand)]
+    subcmd: DevSubCommands,
+}
+
+#[derive(Clap)]
+enum DevSubCommands {
+    CreateSandbox(DevCreateSandboxOptions),
+    DestroySandbox(DevDestroySandboxOptions),
 }
 
 struct ClapErrorWrapper(clap::Error);
@@ -1,14 +1,52 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::*;
+use dialoguer::Confirm;
 use libblkcapt::{
-    model::entities::{FeatureState, ScheduleModel},
+    error::{coded, ErrorCode},
+    model::entities::{Annotations, FeatureState, ScheduleModel},
     parsing::parse_uuid,
 };
+use nix::{libc, unistd::isatty};
 use presets::ASCII_NO_BORDERS;
-use std::{convert::TryInto, str::FromStr};
+use std::{
+    convert::TryInto,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use uuid::Uuid;
 
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Answer every confirmation with yes, set by the global `--yes` flag.
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, Ordering::Relaxed);
+}
+
+/// Asks before a destructive action. Without a terminal to ask on the action is refused unless `--yes` was given.
+pub fn confirm<S: Into<String>>(prompt: S) -> Result<bool> {
+    let prompt = prompt.into();
+    if ASSUME_YES.load(Ordering::Relaxed) {
+        return Ok(true);
+    }
+    if !isatty(libc::STDIN_FILENO).unwrap_or(false) {
+        return Err(coded(
+            ErrorCode::ValidationFailed,
+            anyhow!("Cannot ask '{}' without a terminal, pass --yes to confirm.", prompt),
+        ));
+    }
+    Ok(Confirm::new().with_prompt(prompt).interact()?)
+}
+
+/// Like `confirm`, failing the command when the user declines.
+pub fn confirm_or_abort<S: Into<String>>(prompt: S) -> Result<()> {
+    if !confirm(prompt)? {
+        println!();
+        bail!("user aborted");
+    }
+    Ok(())
+}
+
 pub fn print_comfy_table(header: Vec<Cell>, rows: impl Iterator<Item = Vec<Cell>>) {
     let mut table = Table::new();
     table
@@ -31,6 +69,34 @@ pub fn comfy_feature_state_cell(state: FeatureState) -> Cell {
     })
 }
 
+/// Whether the worker runs the entity at all, independent of its paused features.
+pub fn comfy_enabled_cell(disabled: bool) -> Cell {
+    if disabled {
+        Cell::new("Disabled").fg(comfy_table::Color::Red)
+    } else {
+        Cell::new("Enabled").fg(comfy_table::Color::Green)
+    }
+}
+
+/// Rows for the labels and notes of an entity in show commands.
+pub fn comfy_annotation_rows(annotations: &Annotations) -> Vec<(Cell, CellOrCells)> {
+    vec![
+        (
+            Cell::new("Labels"),
+            annotations
+                .labels
+                .iter()
+                .map(|(name, value)| Cell::new(format!("{}={}", name, value)))
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+        (
+            Cell::new("Notes"),
+            Cell::new(annotations.notes.as_deref().unwrap_or("-")).into(),
+        ),
+    ]
+}
+
 pub fn comfy_id_header() -> Cell {
     comfy_identifier_header("ID")
 }
@@ -65,6 +131,25 @@ pub fn comfy_value_or<T: ToString>(value: Option<T>, default: &str) -> Cell {
         .unwrap_or_else(|| Cell::new(default))
 }
 
+pub fn comfy_bytes_value(bytes: u64) -> Cell {
+    Cell::new(format_bytes(bytes))
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
 pub enum CellOrCells {
     Cell(Cell),
     Cells(Vec<Cell>),
@@ -161,3 +246,28 @@ impl FromStr for ScheduleArg {
         }
     }
 }
+
+pub fn sparkline(values: &[u64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or_default();
+    values
+        .iter()
+        .map(|v| match max {
+            0 => BARS[0],
+            _ => BARS[(*v as u128 * (BARS.len() - 1) as u128 / max as u128) as usize],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparkline_scales_to_maximum() {
+        assert_eq!(sparkline(&[0, 1, 2, 4, 7]), "▁▂▃▅█");
+        assert_eq!(sparkline(&[0, 0, 0]), "▁▁▁");
+        assert_eq!(sparkline(&[]), "");
+        assert_eq!(sparkline(&[u64::MAX, u64::MAX / 2]), "█▄");
+    }
+}
@@ -1,16 +1,19 @@
-use crate::xactorext::{BcActorCtrl, BcContext, BcHandler, TerminalState};
+use crate::{
+    oneshot,
+    xactorext::{BcActorCtrl, BcContext, BcHandler, TerminalState},
+};
 use anyhow::{anyhow, Error, Result};
-use chrono::{DateTime, Utc};
 use cron::Schedule;
 use futures_util::{
     future,
     stream::{FuturesUnordered, StreamExt},
 };
 use libblkcapt::{
+    core::{clock::schedule_next_delay, hooks::Hook},
     error_cause,
-    model::{Entity, EntityId, EntityStatic},
+    model::{EntityStatic, TypedEntity},
 };
-use slog::{debug, error, Logger};
+use slog::{debug, error, info, Logger};
 use std::future::Future;
 use std::{collections::HashMap, time::Duration};
 use xactor::{Actor, Addr, Message};
@@ -41,13 +44,11 @@ pub fn logged_result<T>(log: &Logger, result: Result<T>) -> Result<T> {
     result
 }
 
-fn schedule_next_delay(schedule: &Schedule, after: DateTime<Utc>) -> Option<(DateTime<Utc>, Duration)> {
-    schedule.after(&after).next().map(|next_datetime| {
-        let delay_to_next = (next_datetime - after)
-            .to_std()
-            .expect("time to next schedule can always fit in std duration");
-        (next_datetime, delay_to_next)
-    })
+pub async fn run_hook(hook: Option<Hook>) -> Result<()> {
+    match hook {
+        Some(hook) => hook.run().await,
+        None => Ok(()),
+    }
 }
 
 pub struct ScheduledMessage {}
@@ -56,12 +57,24 @@ impl ScheduledMessage {
     pub fn new<M: Message<Result = ()> + Clone, A: BcHandler<M> + BcActorCtrl, S: Into<String>>(
         schedule: Schedule, what: S, message: M, ctx: &BcContext<'_, A>,
     ) -> Self {
-        let sender = ctx.address().sender();
         let what = what.into();
         let log = ctx.log().clone();
+        if let Some(due) = oneshot::due(&what, ctx.entity_id(), &schedule) {
+            if due {
+                info!(log, "running due {}", what);
+                let addr = ctx.address();
+                oneshot::dispatch(async move {
+                    let _ = addr.call(message).await;
+                });
+            }
+            return Self {};
+        }
+
+        let sender = ctx.address().sender();
+        let clock = ctx.clock().clone();
         tokio::spawn(async move {
             loop {
-                if let Some((next_datetime, interval)) = schedule_next_delay(&schedule, Utc::now()) {
+                if let Some((next_datetime, interval)) = schedule_next_delay(&schedule, clock.as_ref()) {
                     let display_delay = Duration::from_secs(interval.as_secs());
                     debug!(
                         log,
@@ -110,12 +123,12 @@ pub fn state_result_from_result<T>(result: Result<T>) -> (TerminalState, Result<
 
 pub async fn build_child_actors<'a, S, A, M, IM, B, BR>(
     ctx: &BcContext<'_, S>, models: IM, builder: B,
-) -> HashMap<EntityId, Addr<A>>
+) -> HashMap<M::Id, Addr<A>>
 where
     BR: Future<Output = Result<A>>,
     B: Fn(&M) -> BR,
     IM: Iterator<Item = &'a M>,
-    M: 'a + Entity + EntityStatic,
+    M: 'a + TypedEntity + EntityStatic,
     A: Actor,
     S: BcActorCtrl,
 {
@@ -127,7 +140,7 @@ where
                 let maybe_actor = builder(m).await;
                 match maybe_actor {
                     Ok(actor) => match actor.start().await {
-                        Ok(started_actor) => Some((m.id(), started_actor)),
+                        Ok(started_actor) => Some((m.typed_id(), started_actor)),
                         Err(error) => {
                             logged_error(
                                 ctx.log(),
@@ -1,116 +1,544 @@
-use super::{observation::HealthchecksActor, server::ServerActor, sync::SyncActor};
-use super::{pool::PoolActor, restic::ResticContainerActor, sync::SyncToContainer};
+use super::removable::RemovablePoolActor;
+use super::{
+    container::ContainerActor,
+    dataset::DatasetActor,
+    group::DatasetGroupActor,
+    intel::{ActorFault, GetFaultedActorsMessage, IntelActor},
+    pool::{PoolActor, PoolAvailableMessage},
+    restic::ResticContainerActor,
+};
+use super::{
+    observation::{start_observation, warn_observation, HealthchecksActor},
+    server::ServerActor,
+    sync::SyncActor,
+    sync::SyncSource,
+    sync::SyncToContainer,
+};
 use crate::{
     actorbase::build_child_actors,
+    oneshot,
     xactorext::{BcActor, BcActorCtrl, BcContext},
 };
 use crate::{
-    actorbase::logged_result,
+    actorbase::{logged_result, unhandled_error, unhandled_result},
     xactorext::{
         join_all_actors, stop_all_actors, BcHandler, GetActorStatusMessage, GetChildActorMessage, TerminalState,
     },
 };
-use anyhow::{Context as AnyhowContext, Result};
+use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
 use futures_util::future;
 use libblkcapt::{
     create_data_dir,
-    model::{entities::SnapshotSyncEntity, storage, AnyContainer, Entities, Entity, EntityId},
+    model::{
+        entities::{BtrfsPoolEntity, DatasetGroupEntity, ObservableEvent, SnapshotSyncEntity},
+        storage, AnyContainer, ContainerId, DatasetId, Entities, Entity, EntityId, GroupId, ObserverId, PoolId,
+        RestartPolicy, SyncId,
+    },
 };
-use slog::{trace, Logger};
-use std::collections::HashMap;
-use xactor::{Actor, Addr};
+use slog::{info, trace, warn, Logger};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+use xactor::{message, Actor, Addr};
 
 pub struct CaptainActor {
-    healthcheck_actors: HashMap<EntityId, Addr<BcActor<HealthchecksActor>>>,
-    sync_actors: HashMap<EntityId, Addr<BcActor<SyncActor>>>,
-    pool_actors: HashMap<EntityId, Addr<BcActor<PoolActor>>>,
-    restic_actors: HashMap<EntityId, Addr<BcActor<ResticContainerActor>>>,
+    healthcheck_actors: HashMap<ObserverId, Addr<BcActor<HealthchecksActor>>>,
+    sync_actors: HashMap<SyncId, Addr<BcActor<SyncActor>>>,
+    group_actors: HashMap<GroupId, Addr<BcActor<DatasetGroupActor>>>,
+    pool_actors: HashMap<PoolId, Addr<BcActor<PoolActor>>>,
+    removable_actors: HashMap<PoolId, Addr<BcActor<RemovablePoolActor>>>,
+    restic_actors: HashMap<ContainerId, Addr<BcActor<ResticContainerActor>>>,
     server_actor: Option<Addr<BcActor<ServerActor>>>,
+    entities: Entities,
+    restart_policy: RestartPolicy,
+    restarts: HashMap<EntityId, RestartState>,
+}
+
+/// An actor the captain restarts along with the actors that depend on it.
+#[derive(Clone, Copy)]
+enum Supervised {
+    Pool(PoolId),
+    Restic(ContainerId),
+    Group(GroupId),
+    Sync(SyncId),
+}
+
+impl Supervised {
+    fn id(self) -> EntityId {
+        match self {
+            Supervised::Pool(id) => id.into(),
+            Supervised::Restic(id) => id.into(),
+            Supervised::Group(id) => id.into(),
+            Supervised::Sync(id) => id.into(),
+        }
+    }
+
+    fn event(self) -> ObservableEvent {
+        match self {
+            Supervised::Pool(_) => ObservableEvent::PoolRestart,
+            Supervised::Restic(_) => ObservableEvent::ContainerRestart,
+            Supervised::Group(_) => ObservableEvent::DatasetGroupRestart,
+            Supervised::Sync(_) => ObservableEvent::SnapshotSyncRestart,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RestartState {
+    restarts: u32,
+    /// When the restart waiting out its backoff is due.
+    pending: Option<Instant>,
+    last_restart: Option<Instant>,
+    given_up: bool,
+}
+
+impl RestartState {
+    /// Forgets the restarts of an actor that ran without faulting for the healthy period since its last restart, so
+    /// a later fault starts over with the initial backoff.
+    fn reset_if_healthy(&mut self, now: Instant) {
+        let healthy = self.pending.is_none()
+            && !self.given_up
+            && self.last_restart.map_or(false, |restarted| {
+                now.saturating_duration_since(restarted) >= HEALTHY_PERIOD
+            });
+        if healthy {
+            self.restarts = 0;
+            self.last_restart = None;
+        }
+    }
 }
 
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTHY_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+#[message()]
+struct SuperviseMessage;
+
 impl CaptainActor {
     pub fn new(log: &Logger) -> BcActor<Self> {
         BcActor::new(
             Self {
                 healthcheck_actors: Default::default(),
                 sync_actors: Default::default(),
+                group_actors: Default::default(),
                 pool_actors: Default::default(),
+                removable_actors: Default::default(),
                 restic_actors: Default::default(),
                 server_actor: None,
+                entities: Entities::default(),
+                restart_policy: Default::default(),
+                restarts: Default::default(),
             },
             log,
         )
     }
 
-    async fn new_sync_actor(
-        &self, entities: &Entities, model: SnapshotSyncEntity, log: &Logger,
-    ) -> Result<BcActor<SyncActor>> {
+    async fn dataset_actor(&self, entities: &Entities, dataset_id: DatasetId) -> Result<Addr<BcActor<DatasetActor>>> {
         let dataset_pool_id = entities
-            .dataset(model.dataset_id)
-            .map(|p| p.parent.id())
-            .context("source dataset does not exist")?;
+            .dataset(dataset_id)
+            .map(|p| p.parent.pool_id())
+            .context("dataset does not exist")?;
 
         let dataset_pool = self
             .pool_actors
             .get(&dataset_pool_id)
-            .context("source dataset's pool did not start")?;
+            .context("dataset's pool did not start")?;
+
+        dataset_pool
+            .call(GetChildActorMessage::new(dataset_id))
+            .await?
+            .context("dataset did not start")
+    }
+
+    async fn new_group_actor(
+        &self, entities: &Entities, model: DatasetGroupEntity, log: &Logger,
+    ) -> Result<BcActor<DatasetGroupActor>> {
+        let mut datasets = Vec::with_capacity(model.dataset_ids.len());
+        for dataset_id in model.dataset_ids.iter() {
+            datasets.push(self.dataset_actor(entities, *dataset_id).await?);
+        }
+
+        Ok(DatasetGroupActor::new(model, datasets, log))
+    }
+
+    async fn sync_source(&self, entities: &Entities, model: &SnapshotSyncEntity) -> Result<SyncSource> {
+        let source_container_id = match model.source_container_id {
+            Some(id) => id,
+            None => {
+                return self
+                    .dataset_actor(entities, model.dataset_id)
+                    .await
+                    .map(SyncSource::Dataset)
+            }
+        };
+
+        let source_pool = entities
+            .container(source_container_id)
+            .map(|c| c.parent)
+            .context("source container does not exist")?;
+        if source_pool.removable.is_some() {
+            bail!("source container is on a removable pool");
+        }
 
-        let dataset_actor = dataset_pool
-            .call(GetChildActorMessage::new(model.dataset_id))
+        let feeders = entities
+            .snapshot_syncs
+            .iter()
+            .filter(|s| s.dataset_id == model.dataset_id && s.container_ids().any(|c| c == source_container_id))
+            .map(|s| s.id())
+            .collect();
+        let source_pool = self
+            .pool_actors
+            .get(&source_pool.pool_id())
+            .context("source container's pool did not start")?;
+        source_pool
+            .call(GetChildActorMessage::new(source_container_id))
             .await?
-            .context("source dataset did not start")?;
-
-        let container_model = entities
-            .any_container(model.container_id)
-            .context("destination container does not exist")?;
-
-        let to_container_actor = match container_model {
-            AnyContainer::Btrfs(container_model) => {
-                let container_pool = self
-                    .pool_actors
-                    .get(&container_model.parent())
-                    .context("Destination container's pool didn't start.")?;
-                let container_actor = container_pool
-                    .call(GetChildActorMessage::new(model.container_id))
-                    .await?
-                    .context("destination btrfs container did not start")?;
-
-                SyncToContainer::Btrfs(container_actor)
+            .context("source container did not start")
+            .map(|container| SyncSource::Container(container, feeders))
+    }
+
+    async fn new_removable_actor(
+        &self, entities: &Entities, model: BtrfsPoolEntity, log: &Logger,
+    ) -> Result<BcActor<RemovablePoolActor>> {
+        let mut syncs = Vec::new();
+        for sync in entities.snapshot_syncs.iter().filter(|s| {
+            s.container_ids()
+                .any(|id| model.containers.iter().any(|c| c.container_id() == id))
+        }) {
+            syncs.push((
+                sync.clone(),
+                self.sync_source(entities, sync).await?,
+                dataset_name(entities, sync.dataset_id)?,
+            ));
+        }
+
+        Ok(RemovablePoolActor::new(model, syncs, log))
+    }
+
+    async fn new_sync_actor(
+        &self, entities: &Entities, model: SnapshotSyncEntity, log: &Logger,
+    ) -> Result<BcActor<SyncActor>> {
+        let source = self.sync_source(entities, &model).await?;
+
+        let mut containers = Vec::new();
+        for container_id in model.container_ids().filter(|id| !on_removable_pool(entities, *id)) {
+            let container_model = entities
+                .any_container(container_id)
+                .context("destination container does not exist")?;
+
+            let to_container_actor = match container_model {
+                AnyContainer::Btrfs(container_model) => {
+                    let container_pool = self
+                        .pool_actors
+                        .get(&container_model.parent())
+                        .context("Destination container's pool didn't start.")?;
+                    let container_actor = container_pool
+                        .call(GetChildActorMessage::new(container_id))
+                        .await?
+                        .context("destination btrfs container did not start")?;
+
+                    SyncToContainer::Btrfs(container_actor)
+                }
+                AnyContainer::Restic(container_model) => {
+                    let container_actor = self
+                        .restic_actors
+                        .get(&container_model.container_id())
+                        .context("destination restic container did not start")?;
+
+                    SyncToContainer::Restic(container_actor.clone())
+                }
+            };
+            containers.push((container_id, to_container_actor));
+        }
+
+        let dataset_name = dataset_name(entities, model.dataset_id)?;
+        Ok(SyncActor::new(source, containers, model, dataset_name, log))
+    }
+}
+
+impl CaptainActor {
+    async fn faulted_actors(&self, faulted: &HashMap<u64, ActorFault>) -> Vec<(Supervised, ActorFault)> {
+        let mut supervised = Vec::new();
+        for (id, actor) in self.pool_actors.iter() {
+            let fault = match faulted.get(&actor.actor_id()) {
+                Some(fault) => Some(*fault),
+                None => self.pool_children_faulted(*id, actor, faulted).await,
+            };
+            if let Some(fault) = fault {
+                supervised.push((Supervised::Pool(*id), fault));
+            }
+        }
+        supervised.extend(faulted_ids(&self.restic_actors, faulted).map(|(id, f)| (Supervised::Restic(id), f)));
+        supervised.extend(faulted_ids(&self.group_actors, faulted).map(|(id, f)| (Supervised::Group(id), f)));
+        supervised.extend(faulted_ids(&self.sync_actors, faulted).map(|(id, f)| (Supervised::Sync(id), f)));
+        supervised
+    }
+
+    async fn pool_children_faulted(
+        &self, pool_id: PoolId, actor: &Addr<BcActor<PoolActor>>, faulted: &HashMap<u64, ActorFault>,
+    ) -> Option<ActorFault> {
+        let model = self.entities.pool(pool_id)?;
+        for dataset in model.datasets.iter() {
+            let dataset_actor: Option<Addr<BcActor<DatasetActor>>> = actor
+                .call(GetChildActorMessage::new(dataset.dataset_id()))
+                .await
+                .ok()
+                .flatten();
+            if let Some(fault) = dataset_actor.and_then(|a| faulted.get(&a.actor_id())) {
+                return Some(*fault);
+            }
+        }
+        for container in model.containers.iter() {
+            let container_actor: Option<Addr<BcActor<ContainerActor>>> = actor
+                .call(GetChildActorMessage::new(container.container_id()))
+                .await
+                .ok()
+                .flatten();
+            if let Some(fault) = container_actor.and_then(|a| faulted.get(&a.actor_id())) {
+                return Some(*fault);
             }
-            AnyContainer::Restic(container_model) => {
-                let container_actor = self
-                    .restic_actors
-                    .get(&container_model.id())
-                    .context("destination restic container did not start")?;
+        }
+        None
+    }
+
+    async fn supervise(&mut self, ctx: &BcContext<'_, Self>, supervised: Supervised, fault: ActorFault) {
+        let id = supervised.id();
+        let policy = &self.restart_policy;
+        let state = self.restarts.entry(id).or_default();
+        if state.given_up {
+            return;
+        }
+        let now = Instant::now();
+        match state.pending.take() {
+            None if state.restarts >= policy.max_restarts => {
+                warn!(ctx.log(), "actor restart limit reached, leaving it stopped"; "entity_id" => %id);
+                state.given_up = true;
+                start_observation(id, supervised.event())
+                    .await
+                    .failed("restart limit reached");
+            }
+            None => {
+                let backoff = policy.backoff(state.restarts);
+                info!(ctx.log(), "actor faulted or unresponsive, restarting after backoff"; "entity_id" => %id, "fault" => ?fault, "backoff" => ?backoff);
+                state.pending = Some(now + backoff);
+                // A fault shows in the restart observation, an actor that hangs is reported as soon as it is found.
+                if fault == ActorFault::Unresponsive {
+                    let message = format!(
+                        "actor is unresponsive, restarting in {}",
+                        humantime::format_duration(backoff)
+                    );
+                    warn_observation(id, supervised.event(), message).await;
+                }
+            }
+            Some(due) if now < due => state.pending = Some(due),
+            Some(_) => {
+                state.restarts += 1;
+                state.last_restart = Some(now);
+                // Observed from the restart itself, so the job history records each restart and how it went.
+                let observation = start_observation(id, supervised.event()).await;
+                let result = self.restart(ctx, supervised).await;
+                observation.result(&result);
+                unhandled_result(ctx.log(), result);
+            }
+        }
+    }
 
-                SyncToContainer::Restic(container_actor.clone())
+    async fn restart(&mut self, ctx: &BcContext<'_, Self>, supervised: Supervised) -> Result<()> {
+        info!(ctx.log(), "restarting actor"; "entity_id" => %supervised.id());
+        let entities = &self.entities;
+        let restarted = match supervised {
+            Supervised::Pool(id) => {
+                stop_faulted(self.pool_actors.remove(&id));
+                let pools = entities.btrfs_pools.iter().filter(|p| p.pool_id() == id);
+                let pool_actors = build_child_actors(ctx, pools, |m| {
+                    future::ok(PoolActor::new_parkable(m.clone(), ctx.address().sender(), ctx.log()))
+                })
+                .await;
+                let restarted = !pool_actors.is_empty();
+                self.pool_actors.extend(pool_actors);
+                // Dependent actors hold addresses of the pool's dataset and container actors.
+                self.restart_pool_dependents(ctx, id).await;
+                restarted
+            }
+            Supervised::Restic(id) => {
+                stop_faulted(self.restic_actors.remove(&id));
+                let containers = entities.restic_containers.iter().filter(|c| c.container_id() == id);
+                let restic_actors = build_child_actors(ctx, containers, |m| {
+                    future::ok(ResticContainerActor::new(m.clone(), ctx.log()))
+                })
+                .await;
+                let restarted = !restic_actors.is_empty();
+                self.restic_actors.extend(restic_actors);
+                let syncs = entities
+                    .snapshot_syncs
+                    .iter()
+                    .filter(|s| s.container_ids().any(|c| c == id))
+                    .map(|s| s.sync_id())
+                    .collect();
+                self.restart_syncs(ctx, syncs).await;
+                restarted
+            }
+            Supervised::Group(id) => {
+                stop_faulted(self.group_actors.remove(&id));
+                let groups = entities.dataset_groups.iter().filter(|g| g.group_id() == id);
+                let group_actors =
+                    build_child_actors(ctx, groups, |m| self.new_group_actor(entities, m.clone(), ctx.log())).await;
+                let restarted = !group_actors.is_empty();
+                self.group_actors.extend(group_actors);
+                restarted
             }
+            Supervised::Sync(id) => {
+                self.restart_syncs(ctx, vec![id]).await;
+                self.sync_actors.contains_key(&id)
+            }
+        };
+        if restarted {
+            Ok(())
+        } else {
+            Err(anyhow!("actor did not restart"))
+        }
+    }
+
+    async fn restart_pool_dependents(&mut self, ctx: &BcContext<'_, Self>, pool_id: PoolId) {
+        let entities = &self.entities;
+        let pool = match entities.pool(pool_id) {
+            Some(pool) => pool,
+            None => return,
+        };
+        let dataset_on_pool = |id: DatasetId| pool.datasets.iter().any(|d| d.dataset_id() == id);
+        let container_on_pool = |id: ContainerId| pool.containers.iter().any(|c| c.container_id() == id);
+        let sync_on_pool = |s: &SnapshotSyncEntity| {
+            dataset_on_pool(s.dataset_id)
+                || s.source_container_id.map_or(false, container_on_pool)
+                || s.container_ids().any(container_on_pool)
         };
 
-        Ok(SyncActor::new(dataset_actor, to_container_actor, model, log))
+        let groups = entities
+            .dataset_groups
+            .iter()
+            .filter(|g| g.dataset_ids.iter().any(|id| dataset_on_pool(*id)))
+            .collect::<Vec<_>>();
+        for group in groups.iter() {
+            stop_faulted(self.group_actors.remove(&group.group_id()));
+        }
+        let group_actors = build_child_actors(ctx, groups.into_iter(), |m| {
+            self.new_group_actor(entities, m.clone(), ctx.log())
+        })
+        .await;
+
+        let removable_pools = entities
+            .btrfs_pools
+            .iter()
+            .filter(|p| {
+                p.removable.is_some()
+                    && entities
+                        .snapshot_syncs
+                        .iter()
+                        .filter(|s| {
+                            s.container_ids()
+                                .any(|id| p.containers.iter().any(|c| c.container_id() == id))
+                        })
+                        .any(|s| sync_on_pool(s))
+            })
+            .collect::<Vec<_>>();
+        for removable_pool in removable_pools.iter() {
+            stop_faulted(self.removable_actors.remove(&removable_pool.pool_id()));
+        }
+        let removable_actors = build_child_actors(ctx, removable_pools.into_iter(), |m| {
+            self.new_removable_actor(entities, m.clone(), ctx.log())
+        })
+        .await;
+
+        let syncs = entities
+            .snapshot_syncs
+            .iter()
+            .filter(|&s| sync_on_pool(s))
+            .map(|s| s.sync_id())
+            .collect();
+
+        self.group_actors.extend(group_actors);
+        self.removable_actors.extend(removable_actors);
+        self.restart_syncs(ctx, syncs).await;
+    }
+
+    async fn restart_syncs(&mut self, ctx: &BcContext<'_, Self>, sync_ids: Vec<SyncId>) {
+        for id in sync_ids.iter() {
+            stop_faulted(self.sync_actors.remove(id));
+        }
+        let entities = &self.entities;
+        let fixed_syncs = entities.snapshot_syncs.iter().filter(|s| {
+            sync_ids.contains(&s.sync_id()) && s.container_ids().any(|id| !on_removable_pool(entities, id))
+        });
+        let sync_actors = build_child_actors(ctx, fixed_syncs, |m| {
+            self.new_sync_actor(entities, m.clone(), ctx.log())
+        })
+        .await;
+        self.sync_actors.extend(sync_actors);
+    }
+}
+
+fn dataset_name(entities: &Entities, dataset_id: DatasetId) -> Result<String> {
+    entities
+        .dataset(dataset_id)
+        .map(|d| d.entity.name().to_owned())
+        .context("source dataset does not exist")
+}
+
+fn faulted_ids<'a, I: Copy + Eq + Hash, A: Actor>(
+    actors: &'a HashMap<I, Addr<A>>, faulted: &'a HashMap<u64, ActorFault>,
+) -> impl Iterator<Item = (I, ActorFault)> + 'a {
+    actors
+        .iter()
+        .filter_map(move |(id, a)| faulted.get(&a.actor_id()).map(|fault| (*id, *fault)))
+}
+
+/// Stops an actor being replaced without waiting for it, a zombie may never finish stopping.
+fn stop_faulted<A: Actor>(actor: Option<Addr<A>>) {
+    if let Some(mut actor) = actor {
+        let _ = actor.stop(None);
     }
 }
 
+fn on_removable_pool(entities: &Entities, container_id: ContainerId) -> bool {
+    entities
+        .container(container_id)
+        .map_or(false, |c| c.parent.removable.is_some())
+}
+
 #[async_trait::async_trait]
 impl BcActorCtrl for CaptainActor {
     async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
         create_data_dir()?;
 
-        let entities = storage::load_entity_config();
+        let entities = storage::load_entity_config()?.without_disabled();
 
         if !entities.observers.is_empty() {
             trace!(ctx.log(), "building observer actors");
             self.healthcheck_actors = build_child_actors(&ctx, entities.observers.iter(), |m| {
-                future::ok(HealthchecksActor::new(m.clone(), ctx.log()))
+                future::ready(HealthchecksActor::new(m.clone(), ctx.log()))
             })
             .await;
         };
 
         if !entities.btrfs_pools.is_empty() {
             trace!(ctx.log(), "building pool actors");
-            self.pool_actors = build_child_actors(&ctx, entities.btrfs_pools.iter(), |m| {
-                future::ok(PoolActor::new(m.clone(), ctx.log()))
-            })
+            self.pool_actors = build_child_actors(
+                &ctx,
+                entities.btrfs_pools.iter().filter(|p| p.removable.is_none()),
+                |m| future::ok(PoolActor::new_parkable(m.clone(), ctx.address().sender(), ctx.log())),
+            )
+            .await;
+        }
+
+        if entities.btrfs_pools.iter().any(|p| p.removable.is_some()) {
+            trace!(ctx.log(), "building removable pool actors");
+            self.removable_actors = build_child_actors(
+                &ctx,
+                entities.btrfs_pools.iter().filter(|p| p.removable.is_some()),
+                |m| self.new_removable_actor(&entities, m.clone(), ctx.log()),
+            )
             .await;
         }
 
@@ -122,34 +550,64 @@ impl BcActorCtrl for CaptainActor {
             .await;
         };
 
+        if !entities.dataset_groups.is_empty() {
+            trace!(ctx.log(), "building dataset group actors");
+            self.group_actors = build_child_actors(&ctx, entities.dataset_groups.iter(), |m| {
+                self.new_group_actor(&entities, m.clone(), ctx.log())
+            })
+            .await;
+        }
+
         if !entities.snapshot_syncs.is_empty() {
             trace!(ctx.log(), "building sync actors");
-            self.sync_actors = build_child_actors(&ctx, entities.snapshot_syncs.iter(), |m| {
+            // Targets on removable pools are synced by the removable pool actor when the drive attaches.
+            let fixed_syncs = entities
+                .snapshot_syncs
+                .iter()
+                .filter(|s| s.container_ids().any(|id| !on_removable_pool(&entities, id)));
+            self.sync_actors = build_child_actors(&ctx, fixed_syncs, |m| {
                 self.new_sync_actor(&entities, m.clone(), ctx.log())
             })
             .await;
         }
 
+        self.entities = entities;
+
+        self.restart_policy = logged_result(
+            ctx.log(),
+            storage::load_server_config().context("failed to load the restart policy"),
+        )
+        .map(|c| c.restart_policy)
+        .unwrap_or_default();
+        if self.restart_policy.max_restarts > 0 {
+            ctx.send_later(SuperviseMessage, SUPERVISE_INTERVAL);
+        }
+
         self.server_actor = logged_result(
             ctx.log(),
-            ServerActor::new(ctx.log())
+            ServerActor::new(ctx.address(), ctx.log())
                 .start()
                 .await
                 .context("failed to start server actor"),
         )
         .ok();
 
+        oneshot::startup_finished();
         Ok(())
     }
 
     async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
         stop_all_actors(self.healthcheck_actors.values_mut());
         stop_all_actors(self.sync_actors.values_mut());
+        stop_all_actors(self.group_actors.values_mut());
         stop_all_actors(self.pool_actors.values_mut());
+        stop_all_actors(self.removable_actors.values_mut());
         stop_all_actors(self.restic_actors.values_mut());
 
         join_all_actors(self.healthcheck_actors.drain().map(|(_k, v)| v)).await;
         join_all_actors(self.sync_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.group_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.removable_actors.drain().map(|(_k, v)| v)).await;
         join_all_actors(self.pool_actors.drain().map(|(_k, v)| v)).await;
         join_all_actors(self.restic_actors.drain().map(|(_k, v)| v)).await;
 
@@ -162,9 +620,128 @@ impl BcActorCtrl for CaptainActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<PoolAvailableMessage> for CaptainActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PoolAvailableMessage) {
+        // Actors that depend on a parked pool failed to build at startup, so retry everything that is missing.
+        info!(ctx.log(), "starting actors waiting on parked pool"; "pool_id" => %msg.0);
+        let entities = &self.entities;
+
+        let removable_pools = entities
+            .btrfs_pools
+            .iter()
+            .filter(|p| p.removable.is_some() && !self.removable_actors.contains_key(&p.pool_id()));
+        let removable_actors = build_child_actors(&ctx, removable_pools, |m| {
+            self.new_removable_actor(entities, m.clone(), ctx.log())
+        })
+        .await;
+
+        let groups = entities
+            .dataset_groups
+            .iter()
+            .filter(|g| !self.group_actors.contains_key(&g.group_id()));
+        let group_actors =
+            build_child_actors(&ctx, groups, |m| self.new_group_actor(entities, m.clone(), ctx.log())).await;
+
+        let fixed_syncs = entities.snapshot_syncs.iter().filter(|s| {
+            !self.sync_actors.contains_key(&s.sync_id()) && s.container_ids().any(|id| !on_removable_pool(entities, id))
+        });
+        let sync_actors = build_child_actors(&ctx, fixed_syncs, |m| {
+            self.new_sync_actor(entities, m.clone(), ctx.log())
+        })
+        .await;
+
+        self.removable_actors.extend(removable_actors);
+        self.group_actors.extend(group_actors);
+        self.sync_actors.extend(sync_actors);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SuperviseMessage> for CaptainActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SuperviseMessage) {
+        match IntelActor::addr().call(GetFaultedActorsMessage).await {
+            Ok(faulted) => {
+                let faulted = if faulted.is_empty() {
+                    Vec::new()
+                } else {
+                    self.faulted_actors(&faulted).await
+                };
+                let faulted_ids = faulted.iter().map(|(s, _)| s.id()).collect::<HashSet<_>>();
+                let now = Instant::now();
+                for (_, state) in self.restarts.iter_mut().filter(|(id, _)| !faulted_ids.contains(id)) {
+                    state.reset_if_healthy(now);
+                }
+                for (supervised, fault) in faulted {
+                    self.supervise(&ctx, supervised, fault).await;
+                }
+            }
+            Err(error) => unhandled_error(ctx.log(), error),
+        }
+        ctx.send_later(SuperviseMessage, SUPERVISE_INTERVAL);
+    }
+}
+
+/// Finds the actor of a dataset, for requests the service serves.
+#[message(result = "Result<Addr<BcActor<DatasetActor>>>")]
+pub struct GetDatasetActorMessage(pub DatasetId);
+
+#[async_trait::async_trait]
+impl BcHandler<GetDatasetActorMessage> for CaptainActor {
+    async fn handle(
+        &mut self, _ctx: BcContext<'_, Self>, msg: GetDatasetActorMessage,
+    ) -> Result<Addr<BcActor<DatasetActor>>> {
+        self.dataset_actor(&self.entities, msg.0).await
+    }
+}
+
+/// Answered as soon as the captain processes it. Used to verify the actor system is responsive.
+#[message()]
+pub struct PingMessage;
+
+#[async_trait::async_trait]
+impl BcHandler<PingMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: PingMessage) {}
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for CaptainActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
         String::from("idle")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_reset_after_healthy_period() {
+        let restarted = Instant::now();
+        let mut state = RestartState {
+            restarts: 3,
+            last_restart: Some(restarted),
+            ..Default::default()
+        };
+        state.reset_if_healthy(restarted + HEALTHY_PERIOD / 2);
+        assert_eq!(state.restarts, 3);
+
+        state.pending = Some(restarted + HEALTHY_PERIOD);
+        state.reset_if_healthy(restarted + HEALTHY_PERIOD * 2);
+        assert_eq!(state.restarts, 3);
+
+        state.pending = None;
+        state.reset_if_healthy(restarted + HEALTHY_PERIOD);
+        assert_eq!(state.restarts, 0);
+        assert_eq!(state.last_restart, None);
+
+        let mut given_up = RestartState {
+            restarts: 5,
+            last_restart: Some(restarted),
+            given_up: true,
+            ..Default::default()
+        };
+        given_up.reset_if_healthy(restarted + HEALTHY_PERIOD * 2);
+        assert_eq!(given_up.restarts, 5);
+    }
+}
@@ -1,52 +1,72 @@
 use super::{
+    dataset::{
+        DatasetHolderActor, GetSnapshotChangedMessage, GetSnapshotDeltaSizeMessage, GetSnapshotHolderMessage,
+        GetSnapshotSenderMessage, HolderReadyMessage, SenderReadyMessage,
+    },
     localreceiver::{LocalReceiverActor, LocalReceiverStoppedMessage, LocalReceiverStoppedParentMessage},
-    observation::observable_func,
+    localsender::{LocalSenderActor, LocalSenderParentFinishedMessage},
+    observation::{start_observation, StartedObservation},
     pool::PoolActor,
 };
 use crate::{
-    actorbase::{log_result, unhandled_result, ScheduledMessage},
+    actorbase::{log_result, logged_result, run_hook, unhandled_result, ScheduledMessage},
     snapshots::{
-        failed_snapshot_deletes_as_result, prune_btrfs_snapshots, ContainerSnapshotsResponse,
-        GetContainerSnapshotsMessage, PruneMessage,
+        clear_deleted, delete_snapshots, failed_snapshot_deletes_as_result, loaded_snapshots, prune_btrfs_snapshots,
+        ContainerSnapshotsResponse, EmergencyPruneMessage, GetContainerSnapshotsMessage, PruneMessage,
+        RestoreDrillMessage,
     },
     xactorext::{
-        join_all_actors, stop_all_actors, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage,
-        TerminalState,
+        join_all_actors, stop_all_actors, BcActor, BcActorCtrl, BcContext, BcHandler, BoxBcWeakAddr,
+        GetActorStatusMessage, TerminalState,
     },
 };
 use anyhow::{Context as _, Result};
-use futures_util::future::ready;
+use chrono::{DateTime, Utc};
 use libblkcapt::{
-    core::{BtrfsContainer, BtrfsContainerSnapshot, BtrfsPool},
+    api,
+    core::hooks::{Hook, HookJob},
+    core::retention::evaluate_synced_retention,
+    core::verify::{check_sample, list_files, pick_sample, DrillReport},
+    core::{BtrfsContainer, BtrfsContainerSnapshot, BtrfsPool, BtrfsSnapshot},
     core::{Snapshot, SnapshotHandle},
     model::entities::FeatureState,
     model::Entity,
     model::{
-        entities::{BtrfsContainerEntity, ObservableEvent},
-        EntityId,
+        entities::{BtrfsContainerEntity, ObservableEvent, RestoreDrill, SyncedRetention},
+        storage, DatasetId,
     },
 };
-use slog::{debug, o, trace, Logger};
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use slog::{debug, info, o, trace, warn, Logger};
+use std::{collections::HashMap, convert::TryInto, iter::once, sync::Arc};
+use uuid::Uuid;
 use xactor::{message, Actor, Addr, Handler, Sender, WeakAddr};
 
 pub struct ContainerActor {
     pool: Addr<BcActor<PoolActor>>,
     container: Arc<BtrfsContainer>,
-    snapshots: HashMap<EntityId, Vec<BtrfsContainerSnapshot>>,
+    snapshots: Option<ContainerSnapshots>,
     prune_schedule: Option<ScheduledMessage>,
+    restore_drill_schedule: Option<ScheduledMessage>,
     active_receivers: HashMap<u64, ActiveReceiver>,
+    active_sends_holds: Vec<(BoxBcWeakAddr, Uuid, Option<Uuid>)>,
+    // Set while a restore drill runs in the background, with the snapshots it restores.
+    drill: Option<(StartedObservation, Vec<Uuid>)>,
     faulted: bool,
 }
 
+type ContainerSnapshots = HashMap<DatasetId, Vec<BtrfsContainerSnapshot>>;
+
 pub struct ActiveReceiver {
     actor: WeakAddr<BcActor<LocalReceiverActor>>,
-    dataset_id: EntityId,
+    dataset_id: DatasetId,
+    dataset_name: String,
+    snapshot_datetime: DateTime<Utc>,
 }
 
 #[message(result = "Result<()>")]
 pub struct GetSnapshotReceiverMessage {
-    source_dataset_id: EntityId,
+    source_dataset_id: DatasetId,
+    source_dataset_name: String,
     source_snapshot_handle: SnapshotHandle,
     target_ready: Sender<ReceiverReadyMessage>,
     target_finished: Sender<LocalReceiverStoppedMessage>,
@@ -54,13 +74,15 @@ pub struct GetSnapshotReceiverMessage {
 
 impl GetSnapshotReceiverMessage {
     pub fn new<A>(
-        requestor_addr: &Addr<A>, source_dataset_id: EntityId, source_snapshot_handle: SnapshotHandle,
+        requestor_addr: &Addr<A>, source_dataset_id: DatasetId, source_dataset_name: String,
+        source_snapshot_handle: SnapshotHandle,
     ) -> GetSnapshotReceiverMessage
     where
         A: Handler<ReceiverReadyMessage> + Handler<LocalReceiverStoppedMessage>,
     {
         Self {
             source_dataset_id,
+            source_dataset_name,
             source_snapshot_handle,
             target_ready: requestor_addr.sender(),
             target_finished: requestor_addr.sender(),
@@ -71,43 +93,79 @@ impl GetSnapshotReceiverMessage {
 #[message()]
 pub struct ReceiverReadyMessage(pub Result<Addr<BcActor<LocalReceiverActor>>>);
 
+#[message(result = "Result<()>")]
+pub struct TrimSyncedSnapshotsMessage {
+    pub source_dataset_id: DatasetId,
+    pub source_snapshots: Vec<DateTime<Utc>>,
+    pub retention: SyncedRetention,
+}
+
 impl ContainerActor {
     pub fn new(
         pool_actor: Addr<BcActor<PoolActor>>, pool: &Arc<BtrfsPool>, model: BtrfsContainerEntity, log: &Logger,
     ) -> Result<BcActor<Self>> {
         let id = model.id();
-        BtrfsContainer::validate(pool, model)
-            .map(Arc::new)
-            .and_then(|container| {
-                let source_ids = container.source_dataset_ids()?;
-                Ok(BcActor::new(
-                    Self {
-                        pool: pool_actor,
-                        snapshots: source_ids
-                            .iter()
-                            .map(|&source_id| container.snapshots(source_id).map(|snapshots| (source_id, snapshots)))
-                            .collect::<Result<_>>()?,
-                        container,
-                        prune_schedule: None,
-                        active_receivers: Default::default(),
-                        faulted: false,
-                    },
-                    &log.new(o!("container_id" => id.to_string())),
-                ))
-            })
+        BtrfsContainer::validate(pool, model).map(Arc::new).map(|container| {
+            BcActor::new(
+                Self {
+                    pool: pool_actor,
+                    snapshots: None,
+                    container,
+                    prune_schedule: None,
+                    restore_drill_schedule: None,
+                    active_receivers: Default::default(),
+                    active_sends_holds: Default::default(),
+                    drill: None,
+                    faulted: false,
+                },
+                &log.new(o!("container_id" => id.to_string())),
+            )
+            .with_entity(id)
+        })
     }
 }
 
+impl ContainerActor {
+    // Snapshots that must not be deleted, those being sent or used as a parent and those being drilled.
+    fn holds(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.active_sends_holds
+            .iter()
+            .flat_map(|a| once(a.1).chain(a.2.into_iter()))
+            .chain(self.drill.iter().flat_map(|(_, uuids)| uuids.iter().copied()))
+    }
+}
+
+fn container_snapshots<'a>(
+    snapshots: &'a mut Option<ContainerSnapshots>, container: &Arc<BtrfsContainer>,
+) -> Result<&'a mut ContainerSnapshots> {
+    loaded_snapshots(snapshots, || {
+        container
+            .source_dataset_ids()?
+            .into_iter()
+            .map(|source_id| container.snapshots(source_id).map(|snapshots| (source_id, snapshots)))
+            .collect()
+    })
+}
+
+fn snapshot_by_uuid(snapshots: &ContainerSnapshots, uuid: Uuid) -> Result<&BtrfsContainerSnapshot> {
+    snapshots
+        .values()
+        .flatten()
+        .find(|s| s.uuid() == uuid)
+        .context("Snapshot not found.")
+}
+
+fn parent_by_handle<'a>(
+    snapshots: &'a ContainerSnapshots, handle: Option<&SnapshotHandle>,
+) -> Result<Option<&'a BtrfsContainerSnapshot>> {
+    handle
+        .map(|h| snapshot_by_uuid(snapshots, h.uuid).context("Parent snapshot not found."))
+        .transpose()
+}
+
 #[async_trait::async_trait]
 impl BcActorCtrl for ContainerActor {
     async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
-        trace!(
-            ctx.log(),
-            "Starting container with {} snapshots from {} datasets.",
-            self.snapshots.values().fold(0, |acc, v| acc + v.len()),
-            self.snapshots.len()
-        );
-
         if self.container.model().pruning_state() == FeatureState::Enabled {
             self.prune_schedule = self
                 .container
@@ -121,6 +179,17 @@ impl BcActorCtrl for ContainerActor {
                 })?;
         }
 
+        self.restore_drill_schedule = self.container.model().restore_drill.as_ref().map_or(Ok(None), |d| {
+            (&d.schedule).try_into().map(|schedule| {
+                Some(ScheduledMessage::new(
+                    schedule,
+                    "restore_drill",
+                    RestoreDrillMessage,
+                    &ctx,
+                ))
+            })
+        })?;
+
         Ok(())
     }
 
@@ -133,6 +202,11 @@ impl BcActorCtrl for ContainerActor {
             .active_receivers
             .drain()
             .filter_map(|(_, a)| a.actor.upgrade())
+            .chain(
+                self.active_sends_holds
+                    .drain(..)
+                    .filter_map(|(actor, ..)| actor.upgrade()),
+            )
             .collect::<Vec<_>>();
         if !active_actors.is_empty() {
             stop_all_actors(&mut active_actors);
@@ -147,16 +221,20 @@ impl BcActorCtrl for ContainerActor {
 #[async_trait::async_trait]
 impl BcHandler<GetContainerSnapshotsMessage> for ContainerActor {
     async fn handle(
-        &mut self, _ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
+        &mut self, ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
     ) -> ContainerSnapshotsResponse {
+        let snapshots = logged_result(ctx.log(), container_snapshots(&mut self.snapshots, &self.container));
         ContainerSnapshotsResponse {
-            snapshots: self
-                .snapshots
-                .entry(msg.source_dataset_id)
-                .or_default()
-                .iter()
-                .map(|s| s.into())
-                .collect(),
+            snapshots: snapshots.map_or_else(
+                |_| Vec::new(),
+                |s| {
+                    s.entry(msg.source_dataset_id)
+                        .or_default()
+                        .iter()
+                        .map(|s| s.into())
+                        .collect()
+                },
+            ),
         }
     }
 }
@@ -164,6 +242,9 @@ impl BcHandler<GetContainerSnapshotsMessage> for ContainerActor {
 #[async_trait::async_trait]
 impl BcHandler<GetSnapshotReceiverMessage> for ContainerActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotReceiverMessage) -> Result<()> {
+        if self.drill.is_some() {
+            anyhow::bail!("receiver requested while a restore drill runs");
+        }
         if self
             .container
             .snapshot_by_datetime(msg.source_dataset_id, msg.source_snapshot_handle.datetime)
@@ -192,6 +273,8 @@ impl BcHandler<GetSnapshotReceiverMessage> for ContainerActor {
                 ActiveReceiver {
                     actor: addr.downgrade(),
                     dataset_id: msg.source_dataset_id,
+                    dataset_name: msg.source_dataset_name,
+                    snapshot_datetime: msg.source_snapshot_handle.datetime,
                 },
             );
         } else {
@@ -218,16 +301,30 @@ impl BcHandler<LocalReceiverStoppedParentMessage> for ContainerActor {
         if let Some(new_snapshot_name) = maybe_snapshot_name {
             let sealed_snapshot = self
                 .container
-                .seal_snapshot(active_receiver.dataset_id, &new_snapshot_name)
+                .seal_snapshot(
+                    active_receiver.dataset_id,
+                    &new_snapshot_name,
+                    active_receiver.snapshot_datetime,
+                )
                 .with_context(|| format!("received snapshot {} but failed to seal it", new_snapshot_name));
             log_result(ctx.log(), &sealed_snapshot);
             if let Ok(new_snapshot) = sealed_snapshot {
                 debug!(ctx.log(), "container received snapshot {}", new_snapshot.datetime(); "received_uuid" => %new_snapshot.received_uuid());
+                if let Err(e) = self.container.record_provenance(
+                    active_receiver.dataset_id,
+                    &active_receiver.dataset_name,
+                    &new_snapshot,
+                ) {
+                    warn!(ctx.log(), "failed to record snapshot provenance"; "error" => %e);
+                }
 
-                self.snapshots
-                    .entry(active_receiver.dataset_id)
-                    .or_default()
-                    .push(new_snapshot);
+                // Snapshots received before the list is first loaded are picked up by that load.
+                if let Some(snapshots) = self.snapshots.as_mut() {
+                    snapshots
+                        .entry(active_receiver.dataset_id)
+                        .or_default()
+                        .push(new_snapshot);
+                }
             }
         }
     }
@@ -236,30 +333,280 @@ impl BcHandler<LocalReceiverStoppedParentMessage> for ContainerActor {
 #[async_trait::async_trait]
 impl BcHandler<PruneMessage> for ContainerActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
-        let result = observable_func(self.container.model().id(), ObservableEvent::ContainerPrune, || {
-            let rules = self
-                .container
-                .model()
-                .snapshot_retention
-                .as_ref()
-                .expect("retention exist based on message scheduling in started");
+        // The next scheduled prune catches up, the drilled snapshots must stay until the drill finishes.
+        if self.drill.is_some() {
+            info!(ctx.log(), "prune skipped while a restore drill runs");
+            return;
+        }
+        let model = self.container.model();
+        let observation = start_observation(model.id(), ObservableEvent::ContainerPrune).await;
+        let result = match ctx
+            .long_await(run_hook(Hook::pre(&model.prune_hooks, model, HookJob::Prune)))
+            .await
+        {
+            Ok(()) => {
+                let rules = model
+                    .snapshot_retention
+                    .as_ref()
+                    .expect("retention exist based on message scheduling in started");
 
-            let failed_deletes = self.snapshots.iter_mut().fold(0, |acc, (dataset_id, snapshots)| {
-                trace!(ctx.log(), "prune container"; "dataset_id" => %dataset_id);
-                acc + prune_btrfs_snapshots(snapshots, &[], rules, ctx.log())
-            });
-            ready(failed_snapshot_deletes_as_result(failed_deletes))
-        })
-        .await;
+                let holds: Vec<_> = self.holds().collect();
+                container_snapshots(&mut self.snapshots, &self.container)
+                    .map(|snapshots| {
+                        snapshots.iter_mut().fold(0, |acc, (dataset_id, snapshots)| {
+                            trace!(ctx.log(), "prune container"; "dataset_id" => %dataset_id);
+                            acc + prune_btrfs_snapshots(snapshots, &holds, rules, ctx.log())
+                        })
+                    })
+                    .and_then(failed_snapshot_deletes_as_result)
+            }
+            Err(e) => Err(e),
+        };
+        observation.result(&result);
 
+        let post_hook = Hook::post(
+            &model.prune_hooks,
+            model,
+            HookJob::Prune,
+            TerminalState::from(result.as_ref()),
+        );
+        unhandled_result(ctx.log(), ctx.long_await(run_hook(post_hook)).await);
         unhandled_result(ctx.log(), result);
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<RestoreDrillMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: RestoreDrillMessage) {
+        // Receives are refused while a drill copy carrying the same received uuid exists, so don't race them.
+        if self.drill.is_some() || !self.active_receivers.is_empty() {
+            info!(ctx.log(), "skipping restore drill");
+            return;
+        }
+
+        let drill = self
+            .container
+            .model()
+            .restore_drill
+            .clone()
+            .expect("restore drill exists based on message scheduling in started");
+        let observation = start_observation(self.container.model().id(), ObservableEvent::ContainerRestoreDrill).await;
+        let snapshots = match container_snapshots(&mut self.snapshots, &self.container) {
+            Ok(snapshots) => snapshots
+                .iter()
+                .filter_map(|(dataset_id, snapshots)| snapshots.last().map(|s| (*dataset_id, s.clone())))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                observation.error::<anyhow::Error, _>(&e);
+                log_result(ctx.log(), &Err::<(), _>(e));
+                return;
+            }
+        };
+
+        self.drill = Some((observation, snapshots.iter().map(|(_, s)| s.uuid()).collect()));
+        let container = self.container.clone();
+        let seed = ctx.clock().now().timestamp() as u64;
+        let addr = ctx.address();
+        tokio::spawn(async move {
+            let mut reports = Vec::with_capacity(snapshots.len());
+            for (dataset_id, snapshot) in snapshots {
+                let report = drill_snapshot(&snapshot, dataset_id, &drill, seed).await;
+                let report = match container.clear_restore_drill() {
+                    Ok(()) => report,
+                    Err(e) => Err(e.context("failed to remove restore")),
+                };
+                reports.push((dataset_id, snapshot.datetime(), report));
+            }
+            let _ = addr.send(RestoreDrillFinishedMessage(reports));
+        });
+    }
+}
+
+#[message()]
+struct RestoreDrillFinishedMessage(Vec<(DatasetId, DateTime<Utc>, Result<DrillReport>)>);
+
+#[async_trait::async_trait]
+impl BcHandler<RestoreDrillFinishedMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: RestoreDrillFinishedMessage) {
+        let observation = match self.drill.take() {
+            Some((observation, _)) => observation,
+            None => return,
+        };
+        let mut failed = 0;
+        for (dataset_id, datetime, report) in msg.0 {
+            match report {
+                Ok(report) if report.mismatched.is_empty() => {
+                    info!(ctx.log(), "restore drill of snapshot {} {}", datetime, report.summary(); "dataset_id" => %dataset_id)
+                }
+                Ok(report) => {
+                    failed += 1;
+                    warn!(ctx.log(), "restore drill of snapshot {} {}", datetime, report.summary(); "dataset_id" => %dataset_id)
+                }
+                Err(e) => {
+                    failed += 1;
+                    warn!(ctx.log(), "restore drill of snapshot {} failed", datetime; "dataset_id" => %dataset_id, "error" => %e)
+                }
+            }
+        }
+
+        if failed == 0 {
+            observation.succeeded();
+        } else {
+            observation.failed(format!("restore drill failed for {} datasets", failed));
+        }
+    }
+}
+
+// The seed is the drill time, so successive drills of an unchanged snapshot sample other files.
+async fn drill_snapshot(
+    snapshot: &BtrfsContainerSnapshot, dataset_id: DatasetId, drill: &RestoreDrill, seed: u64,
+) -> Result<DrillReport> {
+    let restored = snapshot.restore_drill().await?;
+    let sample = pick_sample(&list_files(&restored)?, drill.sample_files(), seed);
+    // The dataset may be on another pool, or already have pruned the snapshot the copy was received from.
+    let source = if drill.compare {
+        storage::load_entity_config()
+            .and_then(|entities| api::dataset_snapshots(&entities, dataset_id))
+            .ok()
+            .and_then(|s| s.into_iter().find(|s| s.uuid == snapshot.received_uuid()))
+            .and_then(|s| s.path)
+    } else {
+        None
+    };
+    check_sample(&restored, source.as_deref(), &sample)
+}
+
+#[async_trait::async_trait]
+impl BcHandler<EmergencyPruneMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: EmergencyPruneMessage) -> Result<()> {
+        warn!(ctx.log(), "emergency prune"; "keep" => msg.0.get());
+        let holds: Vec<_> = self.holds().collect();
+        let rules = msg.ruleset();
+        let snapshots = container_snapshots(&mut self.snapshots, &self.container)?;
+        let failed_deletes = snapshots.iter_mut().fold(0, |acc, (dataset_id, snapshots)| {
+            trace!(ctx.log(), "emergency prune container"; "dataset_id" => %dataset_id);
+            acc + prune_btrfs_snapshots(snapshots, &holds, &rules, ctx.log())
+        });
+        failed_snapshot_deletes_as_result(failed_deletes)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<TrimSyncedSnapshotsMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: TrimSyncedSnapshotsMessage) -> Result<()> {
+        let holds: Vec<_> = self.holds().collect();
+        let snapshots = container_snapshots(&mut self.snapshots, &self.container)?
+            .entry(msg.source_dataset_id)
+            .or_default();
+        let mut drop_snapshots = evaluate_synced_retention(snapshots, &msg.source_snapshots, msg.retention);
+        drop_snapshots.retain(|s| !holds.contains(&s.uuid()));
+        for snapshot in drop_snapshots.iter() {
+            info!(ctx.log(), "Snapshot {} is being trimmed by synced retention.", snapshot; "retention" => ?msg.retention);
+        }
+
+        let deleted = delete_snapshots(&drop_snapshots, ctx.log());
+        let failed_deletes = drop_snapshots.len() - deleted.len();
+        clear_deleted(snapshots, deleted);
+        failed_snapshot_deletes_as_result(failed_deletes)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotChangedMessage> for ContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetSnapshotChangedMessage) -> Result<bool> {
+        let snapshots = container_snapshots(&mut self.snapshots, &self.container)?;
+        let snapshot = snapshot_by_uuid(snapshots, msg.snapshot_handle.uuid)?;
+        let parent_snapshot =
+            snapshot_by_uuid(snapshots, msg.parent_snapshot_handle.uuid).context("Parent snapshot not found.")?;
+
+        snapshot.changed_since(parent_snapshot)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotDeltaSizeMessage> for ContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetSnapshotDeltaSizeMessage) -> Result<u64> {
+        let snapshots = container_snapshots(&mut self.snapshots, &self.container)?;
+        let snapshot = snapshot_by_uuid(snapshots, msg.snapshot_handle.uuid)?;
+        let parent_snapshot = parent_by_handle(snapshots, msg.parent_snapshot_handle.as_ref())?;
+
+        snapshot.estimate_delta_size(parent_snapshot)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotSenderMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotSenderMessage) -> Result<()> {
+        let snapshots = container_snapshots(&mut self.snapshots, &self.container)?;
+        let send_snapshot = snapshot_by_uuid(snapshots, msg.send_snapshot_handle.uuid)?;
+        let parent_snapshot = parent_by_handle(snapshots, msg.parent_snapshot_handle.as_ref())?;
+        let holds = (send_snapshot.uuid(), parent_snapshot.map(|s| s.uuid()));
+
+        let snapshot_sender = send_snapshot.send(parent_snapshot, msg.compressed)?;
+        let started_sender_actor = LocalSenderActor::new(
+            ctx.address().sender(),
+            msg.target_finished,
+            snapshot_sender,
+            &ctx.log().new(o!("message" => ())),
+        )
+        .start()
+        .await;
+
+        if let Ok(addr) = &started_sender_actor {
+            self.active_sends_holds.push((addr.into(), holds.0, holds.1));
+        }
+        msg.target_ready.send(SenderReadyMessage(started_sender_actor))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotHolderMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotHolderMessage) -> Result<()> {
+        let snapshots = container_snapshots(&mut self.snapshots, &self.container)?;
+        let send_snapshot = snapshot_by_uuid(snapshots, msg.send_snapshot_handle.uuid)?;
+        let parent_snapshot = parent_by_handle(snapshots, msg.parent_snapshot_handle.as_ref())?;
+        let holds = (send_snapshot.uuid(), parent_snapshot.map(|s| s.uuid()));
+        let snapshot_path = send_snapshot.canonical_path();
+        let parent_snapshot_path = parent_snapshot.map(|s| s.canonical_path());
+
+        let started_holder_actor = DatasetHolderActor::new(
+            ctx.log(),
+            ctx.address().sender(),
+            msg.requestor,
+            msg.send_snapshot_handle,
+            msg.parent_snapshot_handle,
+            msg.lease,
+        )
+        .start()
+        .await;
+        if let Ok(addr) = &started_holder_actor {
+            self.active_sends_holds.push((addr.into(), holds.0, holds.1));
+        }
+        msg.target_ready.send(HolderReadyMessage {
+            holder: started_holder_actor,
+            snapshot_path,
+            parent_snapshot_path,
+        })?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<LocalSenderParentFinishedMessage> for ContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: LocalSenderParentFinishedMessage) {
+        self.active_sends_holds.retain(|(x, ..)| x.actor_id() != msg.0);
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for ContainerActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        if self.active_receivers.is_empty() {
+        if self.drill.is_some() {
+            String::from("restore drill")
+        } else if self.active_receivers.is_empty() && self.active_sends_holds.is_empty() {
             String::from("idle")
         } else {
             String::from("active")
@@ -1,21 +1,24 @@
 use super::{
     localsender::{LocalSenderActor, LocalSenderFinishedMessage, LocalSenderParentFinishedMessage},
-    observation::observable_func,
+    observation::{observable_func, start_observation, StartedObservation},
     pool::PoolActor,
 };
 use crate::{
-    actorbase::unhandled_result,
+    actorbase::{logged_result, run_hook, unhandled_result},
     xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler},
 };
 use crate::{
     actorbase::{unhandled_error, ScheduledMessage},
-    snapshots::PruneMessage,
-    snapshots::{failed_snapshot_deletes_as_result, prune_btrfs_snapshots},
-    xactorext::{join_all_actors, stop_all_actors, BoxBcWeakAddr, GetActorStatusMessage, TerminalState},
+    snapshots::{failed_snapshot_deletes_as_result, loaded_snapshots, prune_btrfs_snapshots},
+    snapshots::{EmergencyPruneMessage, PruneMessage},
+    xactorext::{join_all_actors, stop_all_actors, BcAddr, BoxBcWeakAddr, GetActorStatusMessage, TerminalState},
 };
 use anyhow::{Context as AnyhowContext, Result};
-use futures_util::future::ready;
+use chrono::{DateTime, Utc};
+use futures_util::future::{ready, FutureExt};
 use libblkcapt::{
+    core::boot::update_boot_menu,
+    core::hooks::{Hook, HookJob},
     core::{BtrfsDataset, BtrfsDatasetSnapshot, BtrfsPool, BtrfsSnapshot},
     core::{Snapshot, SnapshotHandle},
     model::entities::BtrfsDatasetEntity,
@@ -23,35 +26,76 @@ use libblkcapt::{
     model::entities::ObservableEvent,
     model::Entity,
 };
-use slog::{info, o, Logger};
-use std::{convert::TryInto, iter::once, path::PathBuf, sync::Arc};
+use slog::{info, o, warn, Logger};
+use std::{
+    convert::TryInto,
+    iter::once,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use uuid::Uuid;
 use xactor::{message, Actor, Addr, Handler, Sender};
 
 pub struct DatasetActor {
     pool: Addr<BcActor<PoolActor>>,
     dataset: Arc<BtrfsDataset>,
-    snapshots: Vec<BtrfsDatasetSnapshot>,
+    snapshots: Option<Vec<BtrfsDatasetSnapshot>>,
     snapshot_schedule: Option<ScheduledMessage>,
     prune_schedule: Option<ScheduledMessage>,
+    defrag_schedule: Option<ScheduledMessage>,
+    // Set while a defragmentation runs in the background.
+    defrag: Option<StartedObservation>,
     active_sends_holds: Vec<(BoxBcWeakAddr, Uuid, Option<Uuid>)>,
+    omitted_nested_subvolumes: usize,
 }
 
 #[message()]
 #[derive(Clone)]
 struct SnapshotMessage;
 
+#[message()]
+#[derive(Clone)]
+struct DefragMessage;
+
+#[message()]
+struct DefragFinishedMessage(Result<()>);
+
 #[message(result = "DatasetSnapshotsResponse")]
 pub struct GetDatasetSnapshotsMessage;
 
+/// Takes a snapshot now, outside of the schedule, observed as a scheduled one. Answers when it was taken.
+#[message(result = "Result<DateTime<Utc>>")]
+pub struct TakeSnapshotMessage;
+
 pub struct DatasetSnapshotsResponse {
     pub snapshots: Vec<SnapshotHandle>,
 }
 
+/// Takes the dataset's snapshot of a group snapshot, unless its snapshotting is paused or it is unchanged and skips
+/// unchanged snapshots. Answers whether a snapshot was taken. The group observes the snapshot.
+#[message(result = "Result<bool>")]
+pub struct GroupSnapshotMessage {
+    pub datetime: DateTime<Utc>,
+}
+
+#[message(result = "Result<bool>")]
+pub struct GetSnapshotChangedMessage {
+    pub snapshot_handle: SnapshotHandle,
+    pub parent_snapshot_handle: SnapshotHandle,
+}
+
+#[message(result = "Result<u64>")]
+pub struct GetSnapshotDeltaSizeMessage {
+    pub snapshot_handle: SnapshotHandle,
+    pub parent_snapshot_handle: Option<SnapshotHandle>,
+}
+
 #[message(result = "Result<()>")]
 pub struct GetSnapshotSenderMessage {
     pub send_snapshot_handle: SnapshotHandle,
     pub parent_snapshot_handle: Option<SnapshotHandle>,
+    pub compressed: bool,
     pub target_ready: Sender<SenderReadyMessage>,
     pub target_finished: Sender<LocalSenderFinishedMessage>,
 }
@@ -59,6 +103,7 @@ pub struct GetSnapshotSenderMessage {
 impl GetSnapshotSenderMessage {
     pub fn new<A>(
         requestor_addr: &Addr<A>, send_snapshot_handle: SnapshotHandle, parent_snapshot_handle: Option<SnapshotHandle>,
+        compressed: bool,
     ) -> Self
     where
         A: Handler<SenderReadyMessage> + Handler<LocalSenderFinishedMessage>,
@@ -66,6 +111,7 @@ impl GetSnapshotSenderMessage {
         Self {
             send_snapshot_handle,
             parent_snapshot_handle,
+            compressed,
             target_ready: requestor_addr.sender(),
             target_finished: requestor_addr.sender(),
         }
@@ -75,24 +121,32 @@ impl GetSnapshotSenderMessage {
 #[message()]
 pub struct SenderReadyMessage(pub Result<Addr<BcActor<LocalSenderActor>>>);
 
+/// How often a holder checks that the actor it holds snapshots for is still running.
+const HOLD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 #[message(result = "Result<()>")]
 pub struct GetSnapshotHolderMessage {
     pub send_snapshot_handle: SnapshotHandle,
     pub parent_snapshot_handle: Option<SnapshotHandle>,
     pub target_ready: Sender<HolderReadyMessage>,
+    pub requestor: BoxBcWeakAddr,
+    pub lease: Option<Duration>,
 }
 
 impl GetSnapshotHolderMessage {
     pub fn new<A>(
-        requestor_addr: &Addr<A>, send_snapshot_handle: SnapshotHandle, parent_snapshot_handle: Option<SnapshotHandle>,
+        requestor_addr: &Addr<BcActor<A>>, send_snapshot_handle: SnapshotHandle,
+        parent_snapshot_handle: Option<SnapshotHandle>, lease: Option<Duration>,
     ) -> Self
     where
-        A: Handler<HolderReadyMessage>,
+        A: BcHandler<HolderReadyMessage> + BcActorCtrl,
     {
         Self {
             send_snapshot_handle,
             parent_snapshot_handle,
             target_ready: requestor_addr.sender(),
+            requestor: requestor_addr.into(),
+            lease,
         }
     }
 }
@@ -113,21 +167,71 @@ impl DatasetActor {
             Ok(BcActor::new(
                 DatasetActor {
                     pool: pool_actor,
-                    snapshots: dataset.snapshots()?,
+                    snapshots: None,
                     dataset,
                     snapshot_schedule: None,
                     prune_schedule: None,
+                    defrag_schedule: None,
+                    defrag: None,
                     active_sends_holds: Default::default(),
+                    omitted_nested_subvolumes: 0,
                 },
                 &log.new(o!("dataset_id" => id.to_string())),
-            ))
+            )
+            .with_entity(id))
         })
     }
+
+    fn create_snapshot_if_needed(&mut self, now: DateTime<Utc>) -> Result<Option<BtrfsDatasetSnapshot>> {
+        if self.dataset.model().skip_if_unchanged {
+            if let Some(latest) = dataset_snapshots(&mut self.snapshots, &self.dataset)?.last() {
+                if !self.dataset.changed_since(latest)? {
+                    return Ok(None);
+                }
+            }
+        }
+
+        self.dataset.create_local_snapshot_at(now).map(Some)
+    }
+
+    // Snapshots taken before the list is first loaded are picked up by that load.
+    fn add_snapshot(&mut self, snapshot: BtrfsDatasetSnapshot, log: &Logger) {
+        if let Some(snapshots) = self.snapshots.as_mut() {
+            snapshots.push(snapshot);
+        }
+        self.snapshots_changed(log);
+    }
+
+    fn snapshots_changed(&self, log: &Logger) {
+        if self.dataset.model().os_root {
+            unhandled_result(log, update_boot_menu(&self.dataset));
+        }
+    }
+}
+
+fn dataset_snapshots<'a>(
+    snapshots: &'a mut Option<Vec<BtrfsDatasetSnapshot>>, dataset: &Arc<BtrfsDataset>,
+) -> Result<&'a mut Vec<BtrfsDatasetSnapshot>> {
+    loaded_snapshots(snapshots, || dataset.snapshots())
 }
 
 #[async_trait::async_trait]
 impl BcActorCtrl for DatasetActor {
     async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        if !self.dataset.model().recursive {
+            self.omitted_nested_subvolumes = self.dataset.nested_subvolumes()?.len();
+            if self.omitted_nested_subvolumes > 0 {
+                warn!(
+                    ctx.log(),
+                    "dataset contains nested subvolumes that are not included in its snapshots";
+                    "nested_subvolumes" => self.omitted_nested_subvolumes
+                );
+            }
+        }
+
+        // Also removes the menu of a dataset no longer marked as OS root.
+        unhandled_result(ctx.log(), update_boot_menu(&self.dataset));
+
         if self.dataset.model().snapshotting_state() == FeatureState::Enabled {
             self.snapshot_schedule = self.dataset.model().snapshot_schedule.as_ref().map_or(Ok(None), |s| {
                 s.try_into()
@@ -148,6 +252,12 @@ impl BcActorCtrl for DatasetActor {
                 })?;
         }
 
+        self.defrag_schedule = self.dataset.model().defrag.as_ref().map_or(Ok(None), |d| {
+            (&d.schedule)
+                .try_into()
+                .map(|schedule| Some(ScheduledMessage::new(schedule, "defrag", DefragMessage, &ctx)))
+        })?;
+
         Ok(())
     }
 
@@ -170,14 +280,21 @@ impl BcActorCtrl for DatasetActor {
 #[async_trait::async_trait]
 impl BcHandler<SnapshotMessage> for DatasetActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SnapshotMessage) {
+        let now = ctx.clock().now();
         let result = observable_func(self.dataset.model().id(), ObservableEvent::DatasetSnapshot, || {
-            ready(self.dataset.create_local_snapshot())
+            ready(self.create_snapshot_if_needed(now))
         })
         .await;
         match result {
-            Ok(snapshot) => {
+            Ok(Some(snapshot)) => {
                 info!(ctx.log(), "snapshot created"; "time" => %snapshot.datetime());
-                self.snapshots.push(snapshot);
+                self.add_snapshot(snapshot, ctx.log());
+            }
+            Ok(None) => {
+                info!(
+                    ctx.log(),
+                    "dataset unchanged since the latest snapshot, skipping snapshot"
+                );
             }
             Err(e) => {
                 unhandled_error(ctx.log(), e);
@@ -187,52 +304,216 @@ impl BcHandler<SnapshotMessage> for DatasetActor {
 }
 
 #[async_trait::async_trait]
-impl BcHandler<PruneMessage> for DatasetActor {
-    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
-        let result = observable_func(self.dataset.model().id(), ObservableEvent::DatasetPrune, || {
-            let rules = self
-                .dataset
-                .model()
-                .snapshot_retention
-                .as_ref()
-                .expect("retention exist based on message scheduling in started");
-
-            let holds: Vec<_> = self
-                .active_sends_holds
-                .iter()
-                .flat_map(|a| once(a.1).chain(a.2.into_iter()))
-                .collect();
-            let failed_deletes = prune_btrfs_snapshots(&mut self.snapshots, &holds, rules, ctx.log());
-            ready(failed_snapshot_deletes_as_result(failed_deletes))
+impl BcHandler<TakeSnapshotMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: TakeSnapshotMessage) -> Result<DateTime<Utc>> {
+        let now = ctx.clock().now();
+        let dataset = &self.dataset;
+        let snapshot = observable_func(dataset.model().id(), ObservableEvent::DatasetSnapshot, || {
+            ready(dataset.create_local_snapshot_at(now))
         })
-        .await;
+        .await?;
+        info!(ctx.log(), "requested snapshot created"; "time" => %snapshot.datetime());
+        let datetime = snapshot.datetime();
+        self.add_snapshot(snapshot, ctx.log());
+        Ok(datetime)
+    }
+}
 
+#[async_trait::async_trait]
+impl BcHandler<GroupSnapshotMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GroupSnapshotMessage) -> Result<bool> {
+        if self.dataset.model().pause_snapshotting {
+            info!(ctx.log(), "snapshotting paused, skipping group snapshot");
+            return Ok(false);
+        }
+        match self.create_snapshot_if_needed(msg.datetime)? {
+            Some(snapshot) => {
+                info!(ctx.log(), "group snapshot created"; "time" => %snapshot.datetime());
+                self.add_snapshot(snapshot, ctx.log());
+                Ok(true)
+            }
+            None => {
+                info!(
+                    ctx.log(),
+                    "dataset unchanged since the latest snapshot, skipping group snapshot"
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PruneMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
+        let model = self.dataset.model();
+        let observation = start_observation(model.id(), ObservableEvent::DatasetPrune).await;
+        let result = match ctx
+            .long_await(run_hook(Hook::pre(&model.prune_hooks, model, HookJob::Prune)))
+            .await
+        {
+            Ok(()) => {
+                let rules = model
+                    .snapshot_retention
+                    .as_ref()
+                    .expect("retention exist based on message scheduling in started");
+
+                let holds: Vec<_> = self
+                    .active_sends_holds
+                    .iter()
+                    .flat_map(|a| once(a.1).chain(a.2.into_iter()))
+                    .collect();
+                dataset_snapshots(&mut self.snapshots, &self.dataset)
+                    .map(|snapshots| prune_btrfs_snapshots(snapshots, &holds, rules, ctx.log()))
+                    .and_then(failed_snapshot_deletes_as_result)
+            }
+            Err(e) => Err(e),
+        };
+        observation.result(&result);
+        self.snapshots_changed(ctx.log());
+
+        let post_hook = Hook::post(
+            &model.prune_hooks,
+            model,
+            HookJob::Prune,
+            TerminalState::from(result.as_ref()),
+        );
+        unhandled_result(ctx.log(), ctx.long_await(run_hook(post_hook)).await);
         unhandled_result(ctx.log(), result);
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<EmergencyPruneMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: EmergencyPruneMessage) -> Result<()> {
+        warn!(ctx.log(), "emergency prune"; "keep" => msg.0.get());
+        let holds: Vec<_> = self
+            .active_sends_holds
+            .iter()
+            .flat_map(|a| once(a.1).chain(a.2.into_iter()))
+            .collect();
+        let snapshots = dataset_snapshots(&mut self.snapshots, &self.dataset)?;
+        let failed_deletes = prune_btrfs_snapshots(snapshots, &holds, &msg.ruleset(), ctx.log());
+        self.snapshots_changed(ctx.log());
+        failed_snapshot_deletes_as_result(failed_deletes)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<DefragMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: DefragMessage) {
+        if self.defrag.is_some() {
+            info!(ctx.log(), "skipping defragmentation, the previous one is still running");
+            return;
+        }
+
+        let observation = start_observation(self.dataset.model().id(), ObservableEvent::DatasetDefrag).await;
+        let snapshot_count = match dataset_snapshots(&mut self.snapshots, &self.dataset) {
+            Ok(snapshots) => snapshots.len(),
+            Err(e) => {
+                observation.error::<anyhow::Error, _>(&e);
+                unhandled_error(ctx.log(), e);
+                return;
+            }
+        };
+        if snapshot_count > 0 {
+            warn!(
+                ctx.log(),
+                "defragmenting unshares extents with the dataset snapshots, using additional space until they are pruned";
+                "snapshots" => snapshot_count
+            );
+        }
+
+        let defrag = match self.dataset.defrag().and_then(|d| d.start()) {
+            Ok(defrag) => defrag,
+            Err(e) => {
+                observation.error::<anyhow::Error, _>(&e);
+                unhandled_error(ctx.log(), e);
+                return;
+            }
+        };
+        info!(ctx.log(), "defragmentation started");
+        self.defrag = Some(observation);
+        let addr = ctx.address();
+        tokio::spawn(async move {
+            let _ = addr.send(DefragFinishedMessage(defrag.wait().await));
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<DefragFinishedMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: DefragFinishedMessage) {
+        if let Some(observation) = self.defrag.take() {
+            observation.result(&msg.0);
+        }
+        if msg.0.is_ok() {
+            info!(ctx.log(), "defragmentation complete");
+        }
+        unhandled_result(ctx.log(), msg.0);
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetDatasetSnapshotsMessage> for DatasetActor {
-    async fn handle(
-        &mut self, _ctx: BcContext<'_, Self>, _msg: GetDatasetSnapshotsMessage,
-    ) -> DatasetSnapshotsResponse {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: GetDatasetSnapshotsMessage) -> DatasetSnapshotsResponse {
+        let snapshots = logged_result(ctx.log(), dataset_snapshots(&mut self.snapshots, &self.dataset));
         DatasetSnapshotsResponse {
-            snapshots: self.snapshots.iter().map(|s| s.into()).collect(),
+            snapshots: snapshots.map_or_else(|_| Vec::new(), |s| s.iter().map(|s| s.into()).collect()),
         }
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotChangedMessage> for DatasetActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetSnapshotChangedMessage) -> Result<bool> {
+        let snapshots = dataset_snapshots(&mut self.snapshots, &self.dataset)?;
+        let snapshot = snapshots
+            .iter()
+            .find(|s| s.uuid() == msg.snapshot_handle.uuid)
+            .context("Snapshot not found.")?;
+        let parent_snapshot = snapshots
+            .iter()
+            .find(|s| s.uuid() == msg.parent_snapshot_handle.uuid)
+            .context("Parent snapshot not found.")?;
+
+        snapshot.changed_since(parent_snapshot)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotDeltaSizeMessage> for DatasetActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetSnapshotDeltaSizeMessage) -> Result<u64> {
+        let snapshots = dataset_snapshots(&mut self.snapshots, &self.dataset)?;
+        let snapshot = snapshots
+            .iter()
+            .find(|s| s.uuid() == msg.snapshot_handle.uuid)
+            .context("Snapshot not found.")?;
+        let parent_snapshot = match msg.parent_snapshot_handle {
+            Some(handle) => Some(
+                snapshots
+                    .iter()
+                    .find(|s| s.uuid() == handle.uuid)
+                    .context("Parent snapshot not found.")?,
+            ),
+            None => None,
+        };
+
+        snapshot.estimate_delta_size(parent_snapshot)
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetSnapshotSenderMessage> for DatasetActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotSenderMessage) -> Result<()> {
-        let send_snapshot = self
-            .snapshots
+        let snapshots = dataset_snapshots(&mut self.snapshots, &self.dataset)?;
+        let send_snapshot = snapshots
             .iter()
             .find(|s| s.uuid() == msg.send_snapshot_handle.uuid)
             .context("Snapshot not found.")?;
         let parent_snapshot = match msg.parent_snapshot_handle {
             Some(handle) => Some(
-                self.snapshots
+                snapshots
                     .iter()
                     .find(|s| s.uuid() == handle.uuid)
                     .context("Parent not found")?,
@@ -240,7 +521,7 @@ impl BcHandler<GetSnapshotSenderMessage> for DatasetActor {
             None => None,
         };
 
-        let snapshot_sender = send_snapshot.send(parent_snapshot);
+        let snapshot_sender = send_snapshot.send(parent_snapshot, msg.compressed)?;
         let started_sender_actor = LocalSenderActor::new(
             ctx.address().sender(),
             msg.target_finished,
@@ -263,14 +544,14 @@ impl BcHandler<GetSnapshotSenderMessage> for DatasetActor {
 #[async_trait::async_trait]
 impl BcHandler<GetSnapshotHolderMessage> for DatasetActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotHolderMessage) -> Result<()> {
-        let send_snapshot = self
-            .snapshots
+        let snapshots = dataset_snapshots(&mut self.snapshots, &self.dataset)?;
+        let send_snapshot = snapshots
             .iter()
             .find(|s| s.uuid() == msg.send_snapshot_handle.uuid)
             .context("Snapshot not found.")?;
         let parent_snapshot = match &msg.parent_snapshot_handle {
             Some(handle) => Some(
-                self.snapshots
+                snapshots
                     .iter()
                     .find(|s| s.uuid() == handle.uuid)
                     .context("Parent not found")?,
@@ -281,8 +562,10 @@ impl BcHandler<GetSnapshotHolderMessage> for DatasetActor {
         let started_holder_actor = DatasetHolderActor::new(
             ctx.log(),
             ctx.address().sender(),
+            msg.requestor,
             msg.send_snapshot_handle,
             msg.parent_snapshot_handle,
+            msg.lease,
         )
         .start()
         .await;
@@ -310,22 +593,39 @@ impl BcHandler<LocalSenderParentFinishedMessage> for DatasetActor {
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for DatasetActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        if self.active_sends_holds.is_empty() {
-            String::from("idle")
+        let state = if self.defrag.is_some() {
+            "defragmenting"
+        } else if self.active_sends_holds.is_empty() {
+            "idle"
+        } else {
+            "active"
+        };
+        if self.omitted_nested_subvolumes > 0 {
+            format!(
+                "{} (warning: {} nested subvolumes not snapshotted)",
+                state, self.omitted_nested_subvolumes
+            )
         } else {
-            String::from("active")
+            String::from(state)
         }
     }
 }
 
 pub struct DatasetHolderActor {
     parent: Sender<LocalSenderParentFinishedMessage>,
+    requestor: BoxBcWeakAddr,
+    lease: Option<Duration>,
+    held_since: Instant,
+    released: bool,
 }
 
+#[message()]
+struct CheckHoldMessage;
+
 impl DatasetHolderActor {
-    fn new(
-        log: &Logger, parent: Sender<LocalSenderParentFinishedMessage>, send_handle: SnapshotHandle,
-        parent_handle: Option<SnapshotHandle>,
+    pub fn new(
+        log: &Logger, parent: Sender<LocalSenderParentFinishedMessage>, requestor: BoxBcWeakAddr,
+        send_handle: SnapshotHandle, parent_handle: Option<SnapshotHandle>, lease: Option<Duration>,
     ) -> BcActor<DatasetHolderActor> {
         let snapshot_id = send_handle.uuid.to_string();
         let log = match parent_handle {
@@ -334,15 +634,61 @@ impl DatasetHolderActor {
             }
             None => log.new(o!("snapshot_pinned" => snapshot_id)),
         };
-        BcActor::new(DatasetHolderActor { parent }, &log)
+        BcActor::new(
+            DatasetHolderActor {
+                parent,
+                requestor,
+                lease,
+                held_since: Instant::now(),
+                released: false,
+            },
+            &log,
+        )
+    }
+}
+
+// Why a hold is released without the requestor stopping the holder, if it should be.
+fn hold_release_reason(requestor: &BoxBcWeakAddr, lease: Option<Duration>, held: Duration) -> Option<&'static str> {
+    let requestor_running = requestor
+        .upgrade()
+        .map_or(false, |addr| BcAddr::wait_for_stop(addr).now_or_never().is_none());
+    if !requestor_running {
+        Some("requestor stopped without releasing the hold")
+    } else if lease.map_or(false, |lease| held >= lease) {
+        Some("hold lease expired")
+    } else {
+        None
     }
 }
 
 #[async_trait::async_trait]
 impl BcActorCtrl for DatasetHolderActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        ctx.send_later(CheckHoldMessage, HOLD_CHECK_INTERVAL);
+        Ok(())
+    }
+
     async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
         let _ = self.parent.send(LocalSenderParentFinishedMessage(ctx.actor_id()));
-        TerminalState::Succeeded
+        if self.released {
+            TerminalState::Cancelled
+        } else {
+            TerminalState::Succeeded
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<CheckHoldMessage> for DatasetHolderActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: CheckHoldMessage) {
+        match hold_release_reason(&self.requestor, self.lease, self.held_since.elapsed()) {
+            Some(reason) => {
+                warn!(ctx.log(), "releasing held snapshots"; "reason" => reason, "requestor" => self.requestor.actor_type());
+                self.released = true;
+                ctx.stop(None);
+            }
+            None => ctx.send_later(CheckHoldMessage, HOLD_CHECK_INTERVAL),
+        }
     }
 }
 
@@ -352,3 +698,86 @@ impl BcHandler<GetActorStatusMessage> for DatasetHolderActor {
         String::from("holding")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xactorext::{BcWeakAddr, BoxBcAddr};
+    use futures_util::future::pending;
+
+    #[derive(Clone)]
+    struct FakeRequestor {
+        referenced: bool,
+        running: bool,
+    }
+
+    impl BcWeakAddr for FakeRequestor {
+        fn actor_id(&self) -> u64 {
+            1
+        }
+
+        fn actor_type(&self) -> String {
+            String::from("fake")
+        }
+
+        fn upgrade(&self) -> Option<BoxBcAddr> {
+            if self.referenced {
+                Some(Box::new(self.clone()))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BcAddr for FakeRequestor {
+        fn actor_id(&self) -> u64 {
+            1
+        }
+
+        fn actor_type(&self) -> String {
+            String::from("fake")
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn status(&self) -> Result<String> {
+            Ok(String::new())
+        }
+
+        async fn wait_for_stop(self: Box<Self>) {
+            if self.running {
+                pending::<()>().await;
+            }
+        }
+    }
+
+    fn requestor(referenced: bool, running: bool) -> BoxBcWeakAddr {
+        Box::new(FakeRequestor { referenced, running })
+    }
+
+    #[test]
+    fn hold_kept_while_requestor_runs() {
+        let held = Duration::from_secs(3 * 24 * 60 * 60);
+        assert_eq!(hold_release_reason(&requestor(true, true), None, held), None);
+        assert_eq!(hold_release_reason(&requestor(true, true), Some(held * 2), held), None);
+    }
+
+    #[test]
+    fn hold_released_when_requestor_gone() {
+        let held = Duration::from_secs(60);
+        assert!(hold_release_reason(&requestor(false, false), None, held).is_some());
+        assert!(hold_release_reason(&requestor(true, false), None, held).is_some());
+    }
+
+    #[test]
+    fn hold_released_when_lease_expires() {
+        let lease = Duration::from_secs(60 * 60);
+        assert_eq!(
+            hold_release_reason(&requestor(true, true), Some(lease), lease),
+            Some("hold lease expired")
+        );
+    }
+}
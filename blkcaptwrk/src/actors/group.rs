@@ -0,0 +1,118 @@
+use super::{
+    dataset::{DatasetActor, GroupSnapshotMessage},
+    observation::{observable_func, start_observation},
+};
+use crate::{
+    actorbase::{logged_error, unhandled_result, ScheduledMessage},
+    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+};
+use anyhow::{anyhow, Result};
+use futures_util::future::join_all;
+use libblkcapt::model::{
+    entities::{DatasetGroupEntity, FeatureState, ObservableEvent},
+    Entity,
+};
+use slog::{info, o, Logger};
+use std::convert::TryInto;
+use xactor::{message, Addr};
+
+pub struct DatasetGroupActor {
+    model: DatasetGroupEntity,
+    datasets: Vec<Addr<BcActor<DatasetActor>>>,
+    snapshot_schedule: Option<ScheduledMessage>,
+}
+
+#[message()]
+#[derive(Clone)]
+struct SnapshotMessage;
+
+impl DatasetGroupActor {
+    pub fn new(model: DatasetGroupEntity, datasets: Vec<Addr<BcActor<DatasetActor>>>, log: &Logger) -> BcActor<Self> {
+        let id = model.id();
+        BcActor::new(
+            Self {
+                model,
+                datasets,
+                snapshot_schedule: None,
+            },
+            &log.new(o!("actor" => "dataset_group", "dataset_group_id" => id.to_string())),
+        )
+        .with_entity(id)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for DatasetGroupActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        if self.model.snapshotting_state() == FeatureState::Enabled {
+            self.snapshot_schedule = self.model.snapshot_schedule.as_ref().map_or(Ok(None), |s| {
+                s.try_into()
+                    .map(|schedule| Some(ScheduledMessage::new(schedule, "snapshot", SnapshotMessage, &ctx)))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
+        TerminalState::Succeeded
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SnapshotMessage> for DatasetGroupActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SnapshotMessage) {
+        // Every member is snapshotted with the same captured time so the set can be matched up later.
+        let datetime = ctx.clock().now();
+        let datasets = &self.datasets;
+        let dataset_ids = &self.model.dataset_ids;
+        let log = ctx.log();
+        let result = observable_func(self.model.id(), ObservableEvent::DatasetGroupSnapshot, || async move {
+            // The member snapshots are observed until the whole set is taken, so the syncs reacting to them send the
+            // group's snapshots as one set.
+            let mut observations = Vec::with_capacity(dataset_ids.len());
+            for dataset_id in dataset_ids {
+                observations.push(start_observation((*dataset_id).into(), ObservableEvent::DatasetSnapshot).await);
+            }
+            let results = join_all(
+                datasets
+                    .iter()
+                    .map(|dataset| dataset.call(GroupSnapshotMessage { datetime })),
+            )
+            .await;
+
+            let failed = observations
+                .into_iter()
+                .zip(results)
+                .filter_map(|(observation, result)| {
+                    let result = result.and_then(|r| r);
+                    observation.result(&result);
+                    result.err()
+                })
+                .map(|e| logged_error(log, e))
+                .count();
+            if failed == 0 {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "{} of {} dataset snapshots in the group failed",
+                    failed,
+                    datasets.len()
+                ))
+            }
+        })
+        .await;
+
+        if result.is_ok() {
+            info!(ctx.log(), "group snapshot created"; "time" => %datetime);
+        }
+        unhandled_result(ctx.log(), result);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for DatasetGroupActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
+        String::from("idle")
+    }
+}
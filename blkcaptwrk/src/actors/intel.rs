@@ -1,26 +1,101 @@
-use crate::xactorext::{BcActor, BcActorCtrl, BoxBcWeakAddr, TerminalState};
+use crate::{
+    actorbase::unhandled_result,
+    actors::observation::ObservableEventMessage,
+    xactorext::{ActorActivity, BcActor, BcActorCtrl, BoxBcWeakAddr, TerminalState},
+};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures_util::{
-    future::BoxFuture,
     future::FutureExt,
+    future::{join_all, BoxFuture},
     stream::{FuturesUnordered, StreamExt},
 };
-use libblkcapt::core::system;
+use libblkcapt::{
+    core::{restic::ResticRepositoryStats, system, ObservableEventStage},
+    model::{
+        entities::ObservableEvent,
+        history::{
+            append_job_record, append_repository_record, append_scrub_record, read_job_history, JobRecord,
+            RepositoryRecord, ScrubRecord,
+        },
+        storage, ContainerId, EntityId, HealthProbes,
+    },
+};
 use once_cell::sync::OnceCell;
-use slog::{error, trace, warn, Logger};
+use slog::{error, info, trace, warn, Logger};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
     time::{Duration, Instant},
 };
-use xactor::{message, Actor, Addr, Context, Handler};
+use xactor::{message, Actor, Addr, Broker, Context, Handler, Service};
 
 pub struct IntelActor {
     log: Logger,
     actors: HashMap<u64, Tractor>,
+    restic_stats: HashMap<ContainerId, ResticRepositoryStats>,
+    probes: HealthProbes,
+    running_jobs: HashMap<JobKey, RunningJob>,
+    job_durations: HashMap<JobKey, JobDuration>,
+    recent_events: VecDeque<system::SystemEvent>,
+}
+
+/// The number of latest events kept for the service state.
+const RECENT_EVENTS: usize = 50;
+
+type JobKey = (EntityId, ObservableEvent);
+
+/// A job is anomalous once it runs this many times longer than its typical duration.
+const ANOMALY_FACTOR: u32 = 3;
+/// Successful runs needed before a typical duration is assumed.
+const ANOMALY_MIN_SAMPLES: u32 = 5;
+/// The number of recent runs the typical duration roughly averages over.
+const ANOMALY_WINDOW: u32 = 10;
+/// Shorter jobs are never anomalous, which also leaves out observations that start and stop at once.
+const ANOMALY_MIN_DURATION: Duration = Duration::from_secs(60);
+
+struct RunningJob {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    bytes: Option<u64>,
+    estimated_bytes: Option<u64>,
+    warned: bool,
+}
+
+#[derive(Default)]
+struct JobDuration {
+    average: Duration,
+    samples: u32,
+}
+
+impl JobDuration {
+    fn add(&mut self, duration: Duration) {
+        self.samples = self.samples.saturating_add(1);
+        let weight = self.samples.min(ANOMALY_WINDOW);
+        self.average = (self.average * (weight - 1) + duration) / weight;
+    }
+
+    fn anomalous(&self, duration: Duration) -> bool {
+        self.samples >= ANOMALY_MIN_SAMPLES
+            && duration >= ANOMALY_MIN_DURATION
+            && duration > self.average * ANOMALY_FACTOR
+    }
+}
+
+/// The typical durations of the successful jobs in the history, so they survive a restart.
+fn history_job_durations(records: &[JobRecord]) -> HashMap<JobKey, JobDuration> {
+    let mut durations = HashMap::<_, JobDuration>::new();
+    for record in records.iter().filter(|r| r.succeeded) {
+        durations
+            .entry((record.entity_id, record.event))
+            .or_default()
+            .add(record.duration);
+    }
+    durations
 }
 
 #[message]
-pub struct ActorStartMessage(u64, BoxBcWeakAddr);
+pub struct ActorStartMessage(u64, BoxBcWeakAddr, Arc<ActorActivity>, Option<EntityId>);
 
 #[derive(Clone)]
 enum ActorState {
@@ -30,9 +105,33 @@ enum ActorState {
     Zombie,
 }
 
+#[message]
+pub struct ResticStatsMessage(pub ResticRepositoryStats);
+
+#[message]
+pub struct ScrubResultMessage(pub ScrubRecord);
+
+/// Bytes moved by a running job, recorded in the job history when it finishes.
+#[message]
+pub struct JobBytesMessage {
+    pub source: EntityId,
+    pub event: ObservableEvent,
+    pub bytes: u64,
+}
+
+/// The estimated size of a running job, recorded in the job history when it finishes.
+#[message]
+pub struct JobEstimatedBytesMessage {
+    pub source: EntityId,
+    pub event: ObservableEvent,
+    pub bytes: u64,
+}
+
 impl ActorStartMessage {
-    pub fn new<T: BcActorCtrl>(actor_id: u64, actor_address: Addr<BcActor<T>>) -> Self {
-        Self(actor_id, actor_address.into())
+    pub fn new<T: BcActorCtrl>(
+        actor_id: u64, actor_address: Addr<BcActor<T>>, activity: Arc<ActorActivity>, entity_id: Option<EntityId>,
+    ) -> Self {
+        Self(actor_id, actor_address.into(), activity, entity_id)
     }
 }
 
@@ -59,6 +158,11 @@ impl IntelActor {
         Self {
             log: log.clone(),
             actors: Default::default(),
+            restic_stats: Default::default(),
+            probes: Default::default(),
+            running_jobs: Default::default(),
+            job_durations: Default::default(),
+            recent_events: Default::default(),
         }
     }
 
@@ -78,6 +182,46 @@ impl IntelActor {
     pub fn addr() -> Addr<IntelActor> {
         INTEL_ACTOR_SINGLETON.get().expect("intel actor always started").clone()
     }
+
+    fn record_job(&self, key: JobKey, job: &RunningJob, duration: Duration, succeeded: bool) {
+        let record = JobRecord {
+            entity_id: key.0,
+            event: key.1,
+            started: job.started_at,
+            duration,
+            succeeded,
+            bytes: job.bytes,
+            estimated_bytes: job.estimated_bytes,
+        };
+        if let Err(error) = append_job_record(&record) {
+            warn!(self.log, "failed to record job history"; "error" => %error);
+        }
+    }
+
+    async fn warn_job_duration(&self, key: JobKey, elapsed: Duration, typical: Duration) {
+        let (source, event) = key;
+        warn!(self.log, "job is taking unusually long";
+            "entity_id" => %source,
+            "observable_event" => %event,
+            "elapsed_secs" => elapsed.as_secs(),
+            "typical_secs" => typical.as_secs());
+        let message = format!(
+            "{} has run for {}s, its typical duration is {}s",
+            event,
+            elapsed.as_secs(),
+            typical.as_secs()
+        );
+        match Broker::from_registry().await {
+            Ok(mut broker) => {
+                let _ = broker.publish(ObservableEventMessage {
+                    source,
+                    event,
+                    stage: ObservableEventStage::Warning(message),
+                });
+            }
+            Err(error) => error!(self.log, "failed to publish job duration warning"; "error" => %error),
+        }
+    }
 }
 
 static INTEL_ACTOR_SINGLETON: OnceCell<Addr<IntelActor>> = OnceCell::new();
@@ -85,12 +229,19 @@ static INTEL_ACTOR_SINGLETON: OnceCell<Addr<IntelActor>> = OnceCell::new();
 #[derive(Clone)]
 struct Tractor {
     actor: BoxBcWeakAddr,
+    activity: Arc<ActorActivity>,
+    entity_id: Option<EntityId>,
     state: ActorState,
     terminal_state: Option<TerminalState>,
     changed: Instant,
+    failed_probes: u32,
 }
 
 impl Tractor {
+    fn unresponsive(&self, unresponsive_after: u32) -> bool {
+        matches!(self.state, ActorState::Started) && unresponsive_after > 0 && self.failed_probes >= unresponsive_after
+    }
+
     fn system_terminal_state(&self) -> system::TerminalState {
         self.terminal_state.map(|s| s.into()).unwrap_or_default()
     }
@@ -100,19 +251,62 @@ impl Tractor {
 #[derive(Clone)]
 struct Update;
 
+#[message]
+#[derive(Clone)]
+struct Probe;
+
+/// Whether each probed actor answered its status request in time.
+#[message]
+struct ProbeResults(Vec<(u64, bool)>);
+
 #[message(result = "BoxFuture<'static, system::SystemState>")]
 pub struct GetStateMessage;
 
+/// The actors that stopped faulted, became zombies or are unresponsive.
+#[message(result = "HashMap<u64, ActorFault>")]
+pub struct GetFaultedActorsMessage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActorFault {
+    /// Stopped faulted or dropped without stopping.
+    Faulted,
+    /// Still running but not answering health probes.
+    Unresponsive,
+}
+
+/// Stops a started actor, cancelling its work. Answers whether the actor was found running.
+#[message(result = "bool")]
+pub struct StopActorMessage(pub u64);
+
+/// Logs the state and message activity of every tracked actor.
+#[message]
+pub struct DumpActorsMessage;
+
 #[async_trait::async_trait]
 impl Actor for IntelActor {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
         trace!(self.log, "intel actor started");
         ctx.send_interval(Update, Duration::from_secs(60));
+        ctx.subscribe::<ObservableEventMessage>().await?;
+        match storage::load_server_config() {
+            Ok(config) => self.probes = config.health_probes,
+            Err(error) => warn!(self.log, "failed to load health probe config, using defaults"; "error" => %error),
+        }
+        if self.probes.unresponsive_after > 0 {
+            ctx.send_interval(Probe, self.probes.interval);
+        }
+        match read_job_history() {
+            Ok(records) => self.job_durations = history_job_durations(&records),
+            Err(error) => {
+                warn!(self.log, "failed to read job history, typical durations start over"; "error" => %error)
+            }
+        }
         Ok(())
     }
 
-    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+    async fn stopped(&mut self, ctx: &mut Context<Self>) {
         trace!(self.log, "intel actor stopped");
+        let _ = ctx.unsubscribe::<ObservableEventMessage>().await;
 
         for (id, tractor) in self.actors.drain() {
             match tractor.state {
@@ -131,9 +325,12 @@ impl Handler<ActorStartMessage> for IntelActor {
             msg.0,
             Tractor {
                 actor: msg.1,
+                activity: msg.2,
+                entity_id: msg.3,
                 state: ActorState::Started,
                 terminal_state: None,
                 changed: Instant::now(),
+                failed_probes: 0,
             },
         );
     }
@@ -162,6 +359,49 @@ impl Handler<ActorDropMessage> for IntelActor {
     }
 }
 
+#[async_trait::async_trait]
+impl Handler<ResticStatsMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: ResticStatsMessage) {
+        let record = RepositoryRecord {
+            container_id: msg.0.container_id,
+            collected: msg.0.collected,
+            stored_bytes: msg.0.stored_bytes,
+            restore_bytes: msg.0.restore_bytes,
+        };
+        if let Err(error) = append_repository_record(&record) {
+            warn!(self.log, "failed to record repository stats"; "error" => %error);
+        }
+        self.restic_stats.insert(msg.0.container_id, msg.0);
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<ScrubResultMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: ScrubResultMessage) {
+        if let Err(error) = append_scrub_record(&msg.0) {
+            warn!(self.log, "failed to record scrub result"; "error" => %error);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<JobBytesMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: JobBytesMessage) {
+        if let Some(job) = self.running_jobs.get_mut(&(msg.source, msg.event)) {
+            *job.bytes.get_or_insert(0) += msg.bytes;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<JobEstimatedBytesMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: JobEstimatedBytesMessage) {
+        if let Some(job) = self.running_jobs.get_mut(&(msg.source, msg.event)) {
+            job.estimated_bytes = Some(msg.bytes);
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl Handler<Update> for IntelActor {
     async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Update) {
@@ -182,6 +422,135 @@ impl Handler<Update> for IntelActor {
         for id in remove {
             self.actors.remove(&id);
         }
+
+        let mut overdue = vec![];
+        for (key, job) in self.running_jobs.iter_mut() {
+            let elapsed = now - job.started;
+            if let Some(typical) = self.job_durations.get(key).filter(|d| d.anomalous(elapsed)) {
+                if !job.warned {
+                    job.warned = true;
+                    overdue.push((*key, elapsed, typical.average));
+                }
+            }
+        }
+        for (key, elapsed, typical) in overdue {
+            self.warn_job_duration(key, elapsed, typical).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<ObservableEventMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: ObservableEventMessage) {
+        let key = (msg.source, msg.event);
+        let (stage, message) = match &msg.stage {
+            ObservableEventStage::Starting => (system::SystemEventStage::Starting, None),
+            ObservableEventStage::Succeeded => (system::SystemEventStage::Succeeded, None),
+            ObservableEventStage::Failed(message) => (system::SystemEventStage::Failed, Some(message.clone())),
+            ObservableEventStage::Warning(message) => (system::SystemEventStage::Warning, Some(message.clone())),
+        };
+        if self.recent_events.len() == RECENT_EVENTS {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(system::SystemEvent {
+            time: Utc::now(),
+            entity_id: msg.source,
+            event: msg.event,
+            stage,
+            message,
+        });
+
+        match msg.stage {
+            ObservableEventStage::Starting => {
+                self.running_jobs.insert(
+                    key,
+                    RunningJob {
+                        started: Instant::now(),
+                        started_at: Utc::now(),
+                        bytes: None,
+                        estimated_bytes: None,
+                        warned: false,
+                    },
+                );
+            }
+            ObservableEventStage::Succeeded => {
+                if let Some(job) = self.running_jobs.remove(&key) {
+                    let elapsed = job.started.elapsed();
+                    self.record_job(key, &job, elapsed, true);
+                    let durations = self.job_durations.entry(key).or_default();
+                    let typical = durations.average;
+                    let anomalous = durations.anomalous(elapsed);
+                    durations.add(elapsed);
+                    if anomalous && !job.warned {
+                        self.warn_job_duration(key, elapsed, typical).await;
+                    }
+                }
+            }
+            // Failed runs end early or time out, their durations say nothing about the typical one.
+            ObservableEventStage::Failed(_) => {
+                if let Some(job) = self.running_jobs.remove(&key) {
+                    self.record_job(key, &job, job.started.elapsed(), false);
+                }
+            }
+            ObservableEventStage::Warning(_) => {}
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<Probe> for IntelActor {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Probe) {
+        let actors = self
+            .actors
+            .iter()
+            .filter(|(_, tractor)| matches!(tractor.state, ActorState::Started))
+            .map(|(id, tractor)| (*id, tractor.actor.clone(), Arc::clone(&tractor.activity)))
+            .collect::<Vec<_>>();
+        let timeout = self.probes.timeout;
+        let address = ctx.address();
+        // Probing runs apart from the intel actor so that slow actors don't delay its other messages.
+        tokio::spawn(async move {
+            let results = join_all(actors.into_iter().map(|(id, actor, activity)| async move {
+                let answered = match actor.upgrade() {
+                    // A stopping actor fails the request right away rather than hanging. An actor in a long await is
+                    // busy rather than stuck.
+                    Some(actor) => {
+                        tokio::time::timeout(timeout, actor.status()).await.is_ok() || activity.in_long_await()
+                    }
+                    None => true,
+                };
+                (id, answered)
+            }))
+            .await;
+            let _ = address.send(ProbeResults(results));
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<ProbeResults> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: ProbeResults) {
+        let unresponsive_after = self.probes.unresponsive_after;
+        for (id, answered) in msg.0 {
+            let tractor = match self.actors.get_mut(&id) {
+                Some(tractor) if matches!(tractor.state, ActorState::Started) => tractor,
+                _ => continue,
+            };
+            if answered {
+                if tractor.unresponsive(unresponsive_after) {
+                    info!(self.log, "actor is responsive again"; "actor_id" => id);
+                }
+                tractor.failed_probes = 0;
+            } else {
+                tractor.failed_probes += 1;
+                if tractor.failed_probes == unresponsive_after {
+                    warn!(self.log, "actor is unresponsive";
+                        "actor_id" => id,
+                        "actor_type" => tractor.actor.actor_type(),
+                        "failed_probes" => tractor.failed_probes);
+                }
+            }
+        }
     }
 }
 
@@ -190,6 +559,19 @@ impl Handler<GetStateMessage> for IntelActor {
     async fn handle(
         &mut self, _ctx: &mut Context<Self>, _msg: GetStateMessage,
     ) -> BoxFuture<'static, system::SystemState> {
+        let restic_stats = self.restic_stats.values().cloned().collect();
+        let jobs = self
+            .running_jobs
+            .iter()
+            .map(|(key, job)| system::SystemJob {
+                entity_id: key.0,
+                event: key.1,
+                started: job.started_at,
+                typical: self.job_durations.get(key).filter(|d| d.samples > 0).map(|d| d.average),
+            })
+            .collect();
+        let events = self.recent_events.iter().cloned().collect();
+        let unresponsive_after = self.probes.unresponsive_after;
         self.actors
             .clone()
             .into_iter()
@@ -197,6 +579,9 @@ impl Handler<GetStateMessage> for IntelActor {
                 system::SystemActor {
                     actor_id: id,
                     actor_state: match tractor.state {
+                        ActorState::Started if tractor.unresponsive(unresponsive_after) => {
+                            system::ActorState::Started(system::ActiveState::Unresponsive)
+                        }
                         ActorState::Started => {
                             let active_state = match tractor.actor.upgrade() {
                                 Some(actor) => match tokio::time::timeout(Duration::from_secs(3), actor.status()).await
@@ -216,15 +601,108 @@ impl Handler<GetStateMessage> for IntelActor {
                         ActorState::Zombie => system::ActorState::Zombie(tractor.system_terminal_state()),
                     },
                     actor_type: tractor.actor.actor_type(),
+                    entity_id: tractor.entity_id,
                 }
             })
             .collect::<FuturesUnordered<_>>()
             .collect::<Vec<_>>()
-            .map(|actors| system::SystemState { actors })
+            .map(|actors| system::SystemState {
+                actors,
+                restic_stats,
+                jobs,
+                events,
+            })
             .boxed()
     }
 }
 
+#[async_trait::async_trait]
+impl Handler<StopActorMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: StopActorMessage) -> bool {
+        let actor = self
+            .actors
+            .get(&msg.0)
+            .filter(|tractor| matches!(tractor.state, ActorState::Started))
+            .and_then(|tractor| tractor.actor.upgrade());
+        match actor {
+            Some(mut actor) => {
+                info!(self.log, "stopping actor on request"; "actor_id" => msg.0);
+                unhandled_result(&self.log, actor.stop());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<GetFaultedActorsMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: GetFaultedActorsMessage) -> HashMap<u64, ActorFault> {
+        let unresponsive_after = self.probes.unresponsive_after;
+        self.actors
+            .iter()
+            .filter_map(|(id, tractor)| match tractor.state {
+                ActorState::Started if tractor.unresponsive(unresponsive_after) => {
+                    Some((*id, ActorFault::Unresponsive))
+                }
+                ActorState::Stopped | ActorState::Dropped
+                    if matches!(tractor.terminal_state, Some(TerminalState::Faulted)) =>
+                {
+                    Some((*id, ActorFault::Faulted))
+                }
+                ActorState::Zombie => Some((*id, ActorFault::Faulted)),
+                ActorState::Started | ActorState::Stopped | ActorState::Dropped => None,
+            })
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler<DumpActorsMessage> for IntelActor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: DumpActorsMessage) {
+        let mut actors = self.actors.clone().into_iter().collect::<Vec<_>>();
+        actors.sort_by_key(|(id, _)| *id);
+        // The queue depth is only known for actors that answer, a stuck actor never reaches the probe.
+        let states = join_all(actors.iter().map(|(_, tractor)| async move {
+            match tractor.state {
+                ActorState::Started => match tractor.actor.upgrade() {
+                    Some(actor) => {
+                        let started = tractor.activity.started();
+                        match tokio::time::timeout(Duration::from_secs(3), actor.probe_status()).await {
+                            Ok(Ok((status, started_before))) => (
+                                format!("started: {}", status),
+                                Some(started_before.saturating_sub(started)),
+                            ),
+                            Ok(Err(_)) => (String::from("stopping"), None),
+                            Err(_) => (String::from("unresponsive"), None),
+                        }
+                    }
+                    None => (String::from("stopping"), None),
+                },
+                ActorState::Stopped => (format!("stopped: {}", tractor.system_terminal_state()), None),
+                ActorState::Dropped => (format!("dropped: {}", tractor.system_terminal_state()), None),
+                ActorState::Zombie => (format!("zombie: {}", tractor.system_terminal_state()), None),
+            }
+        }))
+        .await;
+
+        info!(self.log, "actor dump"; "actors" => actors.len());
+        for ((id, tractor), (state, queued)) in actors.iter().zip(states) {
+            let (handling, handling_secs) = match tractor.activity.handling() {
+                Some((message, elapsed)) => (message, elapsed.as_secs()),
+                None => (String::from("none"), 0),
+            };
+            info!(self.log, "actor";
+                "actor_id" => id,
+                "actor_type" => tractor.actor.actor_type(),
+                "state" => state,
+                "handling" => handling,
+                "handling_secs" => handling_secs,
+                "queued" => queued);
+        }
+    }
+}
+
 impl Default for IntelActor {
     fn default() -> Self {
         IntelActor::new(&slog_scope::logger())
@@ -241,3 +719,87 @@ impl From<TerminalState> for libblkcapt::core::system::TerminalState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn durations(secs: &[u64]) -> JobDuration {
+        let mut durations = JobDuration::default();
+        for s in secs {
+            durations.add(Duration::from_secs(*s));
+        }
+        durations
+    }
+
+    fn record(entity_id: EntityId, secs: u64, succeeded: bool) -> JobRecord {
+        JobRecord {
+            entity_id,
+            event: ObservableEvent::SnapshotSync,
+            started: Utc::now(),
+            duration: Duration::from_secs(secs),
+            succeeded,
+            bytes: None,
+            estimated_bytes: None,
+        }
+    }
+
+    #[test]
+    fn add_averages_samples() {
+        let durations = durations(&[100, 200, 300]);
+        assert_eq!(durations.samples, 3);
+        assert_eq!(durations.average, Duration::from_secs(200));
+    }
+
+    #[test]
+    fn add_weights_recent_samples_past_the_window() {
+        let mut durations = durations(&[100; ANOMALY_WINDOW as usize]);
+        assert_eq!(durations.average, Duration::from_secs(100));
+        durations.add(Duration::from_secs(1100));
+        assert_eq!(durations.samples, ANOMALY_WINDOW + 1);
+        assert_eq!(durations.average, Duration::from_secs(200));
+    }
+
+    #[test]
+    fn anomalous_needs_samples() {
+        let durations = durations(&[100; ANOMALY_MIN_SAMPLES as usize - 1]);
+        assert!(!durations.anomalous(Duration::from_secs(1000)));
+    }
+
+    #[test]
+    fn anomalous_past_the_factor() {
+        let durations = durations(&[100; ANOMALY_MIN_SAMPLES as usize]);
+        assert!(!durations.anomalous(Duration::from_secs(300)));
+        assert!(durations.anomalous(Duration::from_secs(301)));
+    }
+
+    #[test]
+    fn anomalous_ignores_short_jobs() {
+        let durations = durations(&[1; ANOMALY_MIN_SAMPLES as usize]);
+        assert!(!durations.anomalous(ANOMALY_MIN_DURATION - Duration::from_secs(1)));
+        assert!(durations.anomalous(ANOMALY_MIN_DURATION));
+    }
+
+    #[test]
+    fn history_seeds_successful_durations() {
+        let first: EntityId = Uuid::new_v4().to_string().parse().unwrap();
+        let second: EntityId = Uuid::new_v4().to_string().parse().unwrap();
+        let records = vec![
+            record(first, 100, true),
+            record(first, 5000, false),
+            record(second, 50, true),
+            record(first, 300, true),
+        ];
+
+        let durations = history_job_durations(&records);
+
+        assert_eq!(durations.len(), 2);
+        let first = &durations[&(first, ObservableEvent::SnapshotSync)];
+        assert_eq!(first.samples, 2);
+        assert_eq!(first.average, Duration::from_secs(200));
+        let second = &durations[&(second, ObservableEvent::SnapshotSync)];
+        assert_eq!(second.samples, 1);
+        assert_eq!(second.average, Duration::from_secs(50));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{
     actorbase::{unhandled_result, ScheduledMessage},
+    actors::intel::{IntelActor, JobBytesMessage, JobEstimatedBytesMessage},
+    oneshot,
     xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
 use anyhow::Result;
@@ -51,6 +53,7 @@ pub async fn start_observation(source: EntityId, event: ObservableEvent) -> Star
             stage: ObservableEventStage::Starting,
         })
         .expect("can always publish");
+    oneshot::observation_started();
 
     StartedObservation {
         source,
@@ -60,6 +63,20 @@ pub async fn start_observation(source: EntityId, event: ObservableEvent) -> Star
     }
 }
 
+/// Publishes a warning about an entity, delivered to its observers without starting or ending a job.
+pub async fn warn_observation(source: EntityId, event: ObservableEvent, message: String) {
+    match Broker::from_registry().await {
+        Ok(mut broker) => {
+            let _ = broker.publish(ObservableEventMessage {
+                source,
+                event,
+                stage: ObservableEventStage::Warning(message),
+            });
+        }
+        Err(error) => slog_scope::error!("failed to publish warning"; "error" => %error),
+    }
+}
+
 pub struct StartedObservation {
     source: EntityId,
     event: ObservableEvent,
@@ -68,6 +85,28 @@ pub struct StartedObservation {
 }
 
 impl StartedObservation {
+    pub fn source(&self) -> EntityId {
+        self.source
+    }
+
+    /// Adds to the bytes recorded in the job history for the observed job.
+    pub fn add_bytes(&self, bytes: u64) {
+        let _ = IntelActor::addr().send(JobBytesMessage {
+            source: self.source,
+            event: self.event,
+            bytes,
+        });
+    }
+
+    /// Records the size the observed job estimated before starting its work in the job history.
+    pub fn estimated_bytes(&self, bytes: u64) {
+        let _ = IntelActor::addr().send(JobEstimatedBytesMessage {
+            source: self.source,
+            event: self.event,
+            bytes,
+        });
+    }
+
     pub fn succeeded(self) {
         slog_scope::trace!("observation succeeded"; "entity_id" => %self.source, "observable_event" => %self.event);
         self.stop(ObservableEventStage::Succeeded);
@@ -97,6 +136,11 @@ impl StartedObservation {
     }
 
     fn stop(mut self, stage: ObservableEventStage) {
+        oneshot::observation_finished(
+            self.source,
+            self.event,
+            matches!(stage, ObservableEventStage::Failed(_)),
+        );
         self.broker
             .publish(ObservableEventMessage {
                 source: self.source,
@@ -111,6 +155,7 @@ impl StartedObservation {
 impl Drop for StartedObservation {
     fn drop(&mut self) {
         if !self.stopped {
+            oneshot::observation_finished(self.source, self.event, true);
             let _ = self.broker.publish(ObservableEventMessage {
                 source: self.source,
                 event: self.event,
@@ -128,19 +173,19 @@ pub struct HealthchecksActor {
 }
 
 impl HealthchecksActor {
-    pub fn new(model: HealthchecksObserverEntity, log: &Logger) -> BcActor<Self> {
-        let observer_id = model.id().to_string();
-        BcActor::new(
+    pub fn new(model: HealthchecksObserverEntity, log: &Logger) -> Result<BcActor<Self>> {
+        let id = model.id();
+        let emitter = ObservationEmitter::for_observer(&model)?;
+        Ok(BcActor::new(
             Self {
                 router: ObservationRouter::new(model.observations),
-                emitter: model
-                    .custom_url
-                    .map_or_else(ObservationEmitter::default, ObservationEmitter::new),
+                emitter,
                 heartbeat_config: model.heartbeat,
                 heartbeat_schedule: None,
             },
-            &log.new(o!("observer_id" => observer_id)),
+            &log.new(o!("observer_id" => id.to_string())),
         )
+        .with_entity(id))
     }
 }
 
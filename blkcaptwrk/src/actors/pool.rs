@@ -1,42 +1,60 @@
-use super::{container::ContainerActor, dataset::DatasetActor, observation::start_observation};
-use crate::{
-    actorbase::unhandled_error,
-    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler},
+use super::{
+    container::ContainerActor,
+    dataset::DatasetActor,
+    intel::{IntelActor, ScrubResultMessage},
+    observation::{observable_func, start_observation},
 };
 use crate::{
     actorbase::{build_child_actors, ScheduledMessage},
+    snapshots::EmergencyPruneMessage,
     xactorext::{GetActorStatusMessage, GetChildActorMessage},
 };
-use anyhow::{Context as _, Result};
+use crate::{
+    actorbase::{logged_error, unhandled_error, unhandled_result},
+    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler},
+};
+use anyhow::{anyhow, Context as _, Result};
+use chrono::Utc;
+use dedup::{DedupCompleteMessage, PoolDedupActor};
 use futures_util::future;
 use libblkcapt::{
     core::BtrfsPool,
     model::Entity,
     model::{
-        entities::{BtrfsPoolEntity, FeatureState, ObservableEvent},
-        EntityId,
+        entities::{BtrfsPoolEntity, FeatureState, ObservableEvent, SpaceLevel},
+        history::ScrubRecord,
+        ContainerId, DatasetId, PoolId,
     },
+    sys::btrfs::ScrubResult,
 };
 use scrub::{PoolScrubActor, ScrubCompleteMessage};
-use slog::{info, o, Logger};
-use std::{collections::HashMap, convert::TryInto, mem, sync::Arc};
-use xactor::{message, Actor, Addr};
+use slog::{error, info, o, warn, Logger};
+use std::{collections::HashMap, convert::TryInto, mem, num::NonZeroU32, sync::Arc, time::Duration};
+use xactor::{message, Actor, Addr, Sender};
+
+const PARKED_PROBE_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct PoolActor {
     pool: PoolState,
     scrub_schedule: Option<ScheduledMessage>,
-    datasets: HashMap<EntityId, Addr<BcActor<DatasetActor>>>,
-    containers: HashMap<EntityId, Addr<BcActor<ContainerActor>>>,
+    dedup_schedule: Option<ScheduledMessage>,
+    scrub_deferred: bool,
+    datasets: HashMap<DatasetId, Addr<BcActor<DatasetActor>>>,
+    containers: HashMap<ContainerId, Addr<BcActor<ContainerActor>>>,
+    available: Option<Sender<PoolAvailableMessage>>,
 }
 
 enum PoolState {
     Started(Arc<BtrfsPool>, State),
     Pending(BtrfsPoolEntity),
+    Parked(BtrfsPoolEntity),
     Faulted,
 }
 
+// Scrub and dedup both read the whole pool, at most one of them runs at a time.
 enum State {
     Scrubbing(Addr<BcActor<PoolScrubActor>>),
+    Deduplicating(Addr<BcActor<PoolDedupActor>>),
     Idle,
 }
 
@@ -50,36 +68,58 @@ impl PoolState {
 #[derive(Clone)]
 struct ScrubMessage;
 
+#[message()]
+#[derive(Clone)]
+struct DedupMessage;
+
+#[message()]
+struct ProbeParkedMessage;
+
+#[message()]
+struct SpaceCheckMessage;
+
+/// Sent once a parked pool's filesystem is present and its child actors are started.
+#[message()]
+pub struct PoolAvailableMessage(pub PoolId);
+
 impl PoolActor {
     pub fn new(model: BtrfsPoolEntity, log: &Logger) -> BcActor<Self> {
+        Self::new_inner(model, None, log)
+    }
+
+    /// Creates a pool actor that parks instead of failing when the pool's filesystem is missing.
+    pub fn new_parkable(
+        model: BtrfsPoolEntity, available: Sender<PoolAvailableMessage>, log: &Logger,
+    ) -> BcActor<Self> {
+        Self::new_inner(model, Some(available), log)
+    }
+
+    fn new_inner(
+        model: BtrfsPoolEntity, available: Option<Sender<PoolAvailableMessage>>, log: &Logger,
+    ) -> BcActor<Self> {
         let id = model.id();
         BcActor::new(
             Self {
                 pool: PoolState::Pending(model),
                 scrub_schedule: None,
+                dedup_schedule: None,
+                scrub_deferred: false,
                 datasets: HashMap::<_, _>::default(),
                 containers: HashMap::<_, _>::default(),
+                available,
             },
             &log.new(o!("actor" => "pool", "pool_id" => id.to_string())),
         )
+        .with_entity(id)
     }
-}
-
-#[async_trait::async_trait]
-impl BcActorCtrl for PoolActor {
-    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
-        let pool = if let PoolState::Pending(model) = self.pool.take() {
-            BtrfsPool::validate(model).map(Arc::new)?
-        } else {
-            panic!("pool already started");
-        };
 
-        self.datasets = build_child_actors(&ctx, pool.model().datasets.iter(), |m| {
+    async fn start_pool(&mut self, ctx: &BcContext<'_, Self>, pool: Arc<BtrfsPool>) -> Result<()> {
+        self.datasets = build_child_actors(ctx, pool.model().datasets.iter(), |m| {
             future::ready(DatasetActor::new(ctx.address(), &pool, m.clone(), &ctx.log()))
         })
         .await;
 
-        self.containers = build_child_actors(&ctx, pool.model().containers.iter(), |m| {
+        self.containers = build_child_actors(ctx, pool.model().containers.iter(), |m| {
             future::ready(ContainerActor::new(ctx.address(), &pool, m.clone(), &ctx.log()))
         })
         .await;
@@ -87,28 +127,151 @@ impl BcActorCtrl for PoolActor {
         if pool.model().scrubbing_state() == FeatureState::Enabled {
             self.scrub_schedule = pool.model().scrub_schedule.as_ref().map_or(Ok(None), |s| {
                 s.try_into()
-                    .map(|schedule| Some(ScheduledMessage::new(schedule, "scrub", ScrubMessage, &ctx)))
+                    .map(|schedule| Some(ScheduledMessage::new(schedule, "scrub", ScrubMessage, ctx)))
             })?;
         }
 
+        self.dedup_schedule = pool.model().dedup.as_ref().map_or(Ok(None), |d| {
+            (&d.schedule)
+                .try_into()
+                .map(|schedule| Some(ScheduledMessage::new(schedule, "dedup", DedupMessage, ctx)))
+        })?;
+
+        if pool.model().space_guard.is_some() {
+            ctx.address()
+                .send(SpaceCheckMessage)
+                .expect("send to self is infalliable");
+        }
+
         self.pool = PoolState::Started(pool, State::Idle);
         Ok(())
     }
+
+    // Uncorrectable errors get their own observation, naming the devices with the counters the kernel keeps for them.
+    async fn report_integrity(&self, pool: &BtrfsPool, result: &ScrubResult, log: &Logger) {
+        let observation = start_observation(pool.model().id(), ObservableEvent::PoolIntegrity).await;
+        if result.uncorrectable_errors() == 0 {
+            observation.succeeded();
+            return;
+        }
+
+        let stats = pool.device_stats().unwrap_or_else(|e| {
+            warn!(log, "failed to query device stats"; "error" => %e);
+            Vec::new()
+        });
+        let devices = result
+            .devices
+            .iter()
+            .filter(|d| d.uncorrectable_errors > 0)
+            .map(|d| match stats.iter().find(|s| s.device == d.device) {
+                Some(s) => format!(
+                    "{} ({} uncorrectable, {})",
+                    d.device,
+                    d.uncorrectable_errors,
+                    s.nonzero_summary()
+                ),
+                None => format!("{} ({} uncorrectable)", d.device, d.uncorrectable_errors),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        error!(log, "scrub found uncorrectable errors"; "uncorrectable_errors" => result.uncorrectable_errors(), "devices" => &devices);
+        observation.failed(format!(
+            "scrub found {} uncorrectable errors: {}",
+            result.uncorrectable_errors(),
+            devices
+        ));
+    }
+
+    async fn emergency_prune(&self, keep: NonZeroU32, log: &Logger) -> Result<()> {
+        let message = EmergencyPruneMessage(keep);
+        let mut results = future::join_all(self.datasets.values().map(|d| d.call(message.clone()))).await;
+        results.extend(future::join_all(self.containers.values().map(|c| c.call(message.clone()))).await);
+
+        let total = results.len();
+        let failed = results
+            .into_iter()
+            .filter_map(|r| r.and_then(|r| r).err())
+            .map(|e| logged_error(log, e))
+            .count();
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(anyhow!("{} of {} emergency prunes failed", failed, total))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for PoolActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        let model = if let PoolState::Pending(model) = self.pool.take() {
+            model
+        } else {
+            panic!("pool already started");
+        };
+
+        match BtrfsPool::validate(model.clone()) {
+            Ok(pool) => self.start_pool(&ctx, Arc::new(pool)).await,
+            Err(error) if self.available.is_some() => {
+                warn!(ctx.log(), "pool is unavailable, parking until it appears"; "error" => %error);
+                self.pool = PoolState::Parked(model);
+                ctx.send_later(ProbeParkedMessage, PARKED_PROBE_INTERVAL);
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
 }
 
 #[async_trait::async_trait]
-impl BcHandler<GetChildActorMessage<EntityId, BcActor<DatasetActor>>> for PoolActor {
+impl BcHandler<ProbeParkedMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ProbeParkedMessage) {
+        let model = match self.pool.take() {
+            PoolState::Parked(model) => model,
+            state => {
+                self.pool = state;
+                return;
+            }
+        };
+
+        match BtrfsPool::validate(model.clone()) {
+            Ok(pool) => {
+                let id = model.pool_id();
+                match self.start_pool(&ctx, Arc::new(pool)).await {
+                    Ok(()) => {
+                        info!(ctx.log(), "parked pool is available");
+                        if let Some(available) = self.available.as_ref() {
+                            unhandled_result(ctx.log(), available.send(PoolAvailableMessage(id)));
+                        }
+                    }
+                    Err(error) => {
+                        unhandled_error(ctx.log(), error);
+                        self.pool = PoolState::Faulted;
+                        ctx.stop(None);
+                    }
+                }
+            }
+            Err(_) => {
+                self.pool = PoolState::Parked(model);
+                ctx.send_later(ProbeParkedMessage, PARKED_PROBE_INTERVAL);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetChildActorMessage<DatasetId, BcActor<DatasetActor>>> for PoolActor {
     async fn handle(
-        &mut self, _ctx: BcContext<'_, Self>, msg: GetChildActorMessage<EntityId, BcActor<DatasetActor>>,
+        &mut self, _ctx: BcContext<'_, Self>, msg: GetChildActorMessage<DatasetId, BcActor<DatasetActor>>,
     ) -> Option<Addr<BcActor<DatasetActor>>> {
         self.datasets.get(&msg.0).cloned()
     }
 }
 
 #[async_trait::async_trait]
-impl BcHandler<GetChildActorMessage<EntityId, BcActor<ContainerActor>>> for PoolActor {
+impl BcHandler<GetChildActorMessage<ContainerId, BcActor<ContainerActor>>> for PoolActor {
     async fn handle(
-        &mut self, _ctx: BcContext<'_, Self>, msg: GetChildActorMessage<EntityId, BcActor<ContainerActor>>,
+        &mut self, _ctx: BcContext<'_, Self>, msg: GetChildActorMessage<ContainerId, BcActor<ContainerActor>>,
     ) -> Option<Addr<BcActor<ContainerActor>>> {
         self.containers.get(&msg.0).cloned()
     }
@@ -118,6 +281,10 @@ impl BcHandler<GetChildActorMessage<EntityId, BcActor<ContainerActor>>> for Pool
 impl BcHandler<ScrubMessage> for PoolActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ScrubMessage) {
         self.pool = match self.pool.take() {
+            PoolState::Started(pool, State::Idle) if pool.balance_status().map_or(false, |b| b.is_some()) => {
+                info!(ctx.log(), "skipping scrub. balance running");
+                PoolState::Started(pool, State::Idle)
+            }
             PoolState::Started(pool, State::Idle) => {
                 let observation = start_observation(pool.model().id(), ObservableEvent::PoolScrub).await;
                 let scrub = pool.scrub();
@@ -138,6 +305,54 @@ impl BcHandler<ScrubMessage> for PoolActor {
                 info!(ctx.log(), "skipping scrub. scrub already running");
                 PoolState::Started(pool, State::Scrubbing(actor))
             }
+            PoolState::Started(pool, State::Deduplicating(actor)) => {
+                info!(ctx.log(), "deferring scrub until dedup completes");
+                self.scrub_deferred = true;
+                PoolState::Started(pool, State::Deduplicating(actor))
+            }
+            PoolState::Parked(model) => PoolState::Parked(model),
+            PoolState::Pending(_) | PoolState::Faulted => {
+                ctx.stop(None);
+                PoolState::Faulted
+            }
+        };
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<DedupMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: DedupMessage) {
+        self.pool = match self.pool.take() {
+            PoolState::Started(pool, State::Idle) if pool.balance_status().map_or(false, |b| b.is_some()) => {
+                info!(ctx.log(), "skipping dedup. balance running");
+                PoolState::Started(pool, State::Idle)
+            }
+            PoolState::Started(pool, State::Idle) => {
+                let observation = start_observation(pool.model().id(), ObservableEvent::PoolDedup).await;
+                let state = match pool.dedup() {
+                    Ok(dedup) => {
+                        let dedup_actor = PoolDedupActor::new(ctx.address().downgrade(), dedup, observation, ctx.log());
+                        match dedup_actor.start().await.context("failed to start dedup actor") {
+                            Ok(actor) => State::Deduplicating(actor),
+                            Err(err) => {
+                                unhandled_error(ctx.log(), err);
+                                State::Idle
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        observation.failed(err.to_string());
+                        unhandled_error(ctx.log(), err);
+                        State::Idle
+                    }
+                };
+                PoolState::Started(pool, state)
+            }
+            PoolState::Started(pool, state) => {
+                info!(ctx.log(), "skipping dedup. scrub or dedup running");
+                PoolState::Started(pool, state)
+            }
+            PoolState::Parked(model) => PoolState::Parked(model),
             PoolState::Pending(_) | PoolState::Faulted => {
                 ctx.stop(None);
                 PoolState::Faulted
@@ -146,12 +361,93 @@ impl BcHandler<ScrubMessage> for PoolActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<SpaceCheckMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SpaceCheckMessage) {
+        let pool = match &self.pool {
+            PoolState::Started(pool, _) => pool.clone(),
+            _ => return,
+        };
+        let guard = pool
+            .model()
+            .space_guard
+            .clone()
+            .expect("space guard exists based on message scheduling in start_pool");
+
+        let observation = start_observation(pool.model().id(), ObservableEvent::PoolSpace).await;
+        let level = match pool.space_usage() {
+            Ok(usage) => {
+                let free_percent = usage.free_percent();
+                let level = guard.level(free_percent);
+                if level == SpaceLevel::Normal {
+                    observation.succeeded();
+                } else {
+                    warn!(ctx.log(), "pool free space is low"; "free_percent" => free_percent, "level" => ?level);
+                    observation.failed(format!("{}% free space remaining", free_percent));
+                }
+                Some(level)
+            }
+            Err(error) => {
+                observation.failed(error.to_string());
+                unhandled_error(ctx.log(), error);
+                None
+            }
+        };
+
+        if let (Some(SpaceLevel::Critical), Some(keep)) = (level, guard.emergency_keep) {
+            let log = ctx.log();
+            let result = observable_func(pool.model().id(), ObservableEvent::PoolEmergencyPrune, || {
+                self.emergency_prune(keep, log)
+            })
+            .await;
+            unhandled_result(ctx.log(), result);
+        }
+
+        ctx.send_later(SpaceCheckMessage, guard.check_interval);
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<ScrubCompleteMessage> for PoolActor {
-    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ScrubCompleteMessage) {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ScrubCompleteMessage) {
         self.pool = match self.pool.take() {
-            PoolState::Started(pool, State::Scrubbing(_)) => PoolState::Started(pool, State::Idle),
-            PoolState::Pending(_) | PoolState::Started(..) | PoolState::Faulted => {
+            PoolState::Started(pool, State::Scrubbing(_)) => {
+                if let Some(result) = msg.result {
+                    let record = ScrubRecord {
+                        pool_id: pool.model().pool_id(),
+                        started: msg.started,
+                        duration: (Utc::now() - msg.started).to_std().unwrap_or_default(),
+                        corrected_errors: result.corrected_errors(),
+                        uncorrectable_errors: result.uncorrectable_errors(),
+                    };
+                    info!(ctx.log(), "scrub finished"; "corrected_errors" => record.corrected_errors, "uncorrectable_errors" => record.uncorrectable_errors);
+                    unhandled_result(ctx.log(), IntelActor::addr().send(ScrubResultMessage(record)));
+                    self.report_integrity(&pool, &result, ctx.log()).await;
+                }
+                PoolState::Started(pool, State::Idle)
+            }
+            PoolState::Pending(_) | PoolState::Parked(_) | PoolState::Started(..) | PoolState::Faulted => {
+                ctx.stop(None);
+                PoolState::Faulted
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<DedupCompleteMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: DedupCompleteMessage) {
+        self.pool = match self.pool.take() {
+            PoolState::Started(pool, State::Deduplicating(_)) => {
+                if let Some(result) = msg.result {
+                    info!(ctx.log(), "dedup finished"; "deduplicated_bytes" => result.deduplicated_bytes);
+                }
+                if mem::take(&mut self.scrub_deferred) {
+                    unhandled_result(ctx.log(), ctx.address().send(ScrubMessage));
+                }
+                PoolState::Started(pool, State::Idle)
+            }
+            PoolState::Pending(_) | PoolState::Parked(_) | PoolState::Started(..) | PoolState::Faulted => {
                 ctx.stop(None);
                 PoolState::Faulted
             }
@@ -162,7 +458,10 @@ impl BcHandler<ScrubCompleteMessage> for PoolActor {
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for PoolActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("idle")
+        match self.pool {
+            PoolState::Parked(_) => String::from("parked, filesystem missing"),
+            _ => String::from("idle"),
+        }
     }
 }
 
@@ -173,6 +472,7 @@ mod scrub {
         tasks::{WorkerCompleteMessage, WorkerTask},
         xactorext::TerminalState,
     };
+    use chrono::DateTime;
     use libblkcapt::sys::btrfs::{PoolScrub, ScrubError};
     use strum_macros::Display;
     use xactor::WeakAddr;
@@ -183,7 +483,7 @@ mod scrub {
     enum State {
         Created(PoolScrub, StartedObservation),
         Scrubbing(WorkerTask, StartedObservation),
-        Scrubbed(Result<(), ScrubError>),
+        Scrubbed(Result<ScrubResult, ScrubError>),
         Faulted,
     }
 
@@ -196,26 +496,34 @@ mod scrub {
     pub struct PoolScrubActor {
         parent: WeakAddr<BcActor<PoolActor>>,
         state: State,
+        started: DateTime<Utc>,
     }
 
     impl PoolScrubActor {
         pub fn new(
             pool: WeakAddr<BcActor<PoolActor>>, scrub: PoolScrub, observation: StartedObservation, log: &Logger,
         ) -> BcActor<Self> {
+            let entity_id = observation.source();
             BcActor::new(
                 Self {
                     parent: pool,
                     state: State::Created(scrub, observation),
+                    started: Utc::now(),
                 },
                 log,
             )
+            .with_entity(entity_id)
         }
     }
 
+    /// Carries the scrub result when the scrub ran to completion.
     #[message]
-    pub struct ScrubCompleteMessage;
+    pub struct ScrubCompleteMessage {
+        pub started: DateTime<Utc>,
+        pub result: Option<ScrubResult>,
+    }
 
-    type ScrubWorkerCompleteMessage = WorkerCompleteMessage<Result<(), ScrubError>>;
+    type ScrubWorkerCompleteMessage = WorkerCompleteMessage<Result<ScrubResult, ScrubError>>;
 
     #[async_trait::async_trait]
     impl BcActorCtrl for PoolScrubActor {
@@ -237,17 +545,27 @@ mod scrub {
         }
 
         async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+            let mut scrub_result = None;
             let terminal_state = match self.state.take() {
                 State::Created(_, observation) | State::Scrubbing(_, observation) => {
                     observation.cancelled();
                     TerminalState::Cancelled
                 }
-                State::Scrubbed(result) => logged_result(ctx.log(), result.context("scrubbing failed")).into(),
+                State::Scrubbed(result) => {
+                    scrub_result = match &result {
+                        Ok(result) | Err(ScrubError::UncorrectableErrors(result)) => Some(result.clone()),
+                        Err(ScrubError::Unknown(_)) => None,
+                    };
+                    logged_result(ctx.log(), result.context("scrubbing failed")).into()
+                }
                 State::Faulted => TerminalState::Faulted,
             };
 
             if let Some(actor) = self.parent.upgrade() {
-                let pool_notify_result = actor.send(ScrubCompleteMessage);
+                let pool_notify_result = actor.send(ScrubCompleteMessage {
+                    started: self.started,
+                    result: scrub_result,
+                });
                 if !matches!(terminal_state, TerminalState::Cancelled) {
                     unhandled_result(ctx.log(), pool_notify_result);
                 }
@@ -279,3 +597,132 @@ mod scrub {
         }
     }
 }
+
+mod dedup {
+    use crate::{
+        actorbase::{logged_result, unhandled_result},
+        actors::observation::StartedObservation,
+        tasks::{WorkerCompleteMessage, WorkerTask},
+        xactorext::TerminalState,
+    };
+    use libblkcapt::sys::btrfs::{DedupResult, PoolDedup};
+    use strum_macros::Display;
+    use xactor::WeakAddr;
+
+    use super::*;
+
+    #[derive(Display)]
+    enum State {
+        Created(PoolDedup, StartedObservation),
+        Deduplicating(WorkerTask, StartedObservation),
+        Deduplicated(Result<DedupResult>),
+        Faulted,
+    }
+
+    impl State {
+        fn take(&mut self) -> Self {
+            mem::replace(self, State::Faulted)
+        }
+    }
+
+    pub struct PoolDedupActor {
+        parent: WeakAddr<BcActor<PoolActor>>,
+        state: State,
+    }
+
+    impl PoolDedupActor {
+        pub fn new(
+            pool: WeakAddr<BcActor<PoolActor>>, dedup: PoolDedup, observation: StartedObservation, log: &Logger,
+        ) -> BcActor<Self> {
+            let entity_id = observation.source();
+            BcActor::new(
+                Self {
+                    parent: pool,
+                    state: State::Created(dedup, observation),
+                },
+                log,
+            )
+            .with_entity(entity_id)
+        }
+    }
+
+    /// Carries the dedup result when duperemove ran to completion.
+    #[message]
+    pub struct DedupCompleteMessage {
+        pub result: Option<DedupResult>,
+    }
+
+    type DedupWorkerCompleteMessage = WorkerCompleteMessage<Result<DedupResult>>;
+
+    #[async_trait::async_trait]
+    impl BcActorCtrl for PoolDedupActor {
+        async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+            if let State::Created(dedup, observation) = self.state.take() {
+                let dedup = match dedup.start() {
+                    Ok(dedup) => dedup,
+                    result => {
+                        observation.result(&result);
+                        return result.map(|_| ());
+                    }
+                };
+                let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move { dedup.wait().await.into() });
+                self.state = State::Deduplicating(task, observation);
+                Ok(())
+            } else {
+                panic!("multiple starts")
+            }
+        }
+
+        async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+            let mut dedup_result = None;
+            let terminal_state = match self.state.take() {
+                State::Created(_, observation) | State::Deduplicating(_, observation) => {
+                    observation.cancelled();
+                    TerminalState::Cancelled
+                }
+                State::Deduplicated(result) => {
+                    dedup_result = result.as_ref().ok().cloned();
+                    logged_result(ctx.log(), result.context("dedup failed")).into()
+                }
+                State::Faulted => TerminalState::Faulted,
+            };
+
+            if let Some(actor) = self.parent.upgrade() {
+                let pool_notify_result = actor.send(DedupCompleteMessage { result: dedup_result });
+                if !matches!(terminal_state, TerminalState::Cancelled) {
+                    unhandled_result(ctx.log(), pool_notify_result);
+                }
+            }
+
+            terminal_state
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<DedupWorkerCompleteMessage> for PoolDedupActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: DedupWorkerCompleteMessage) {
+            ctx.stop(None);
+            let result = msg.0;
+            self.state = match self.state.take() {
+                State::Deduplicating(_, observation) => {
+                    if let Ok(DedupResult {
+                        deduplicated_bytes: Some(bytes),
+                    }) = &result
+                    {
+                        observation.add_bytes(*bytes);
+                    }
+                    observation.result(&result);
+                    State::Deduplicated(result)
+                }
+                State::Faulted | State::Deduplicated(_) | State::Created(..) => State::Faulted,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<GetActorStatusMessage> for PoolDedupActor {
+        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
+            self.state.to_string()
+        }
+    }
+}
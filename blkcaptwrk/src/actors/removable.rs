@@ -0,0 +1,304 @@
+use super::{
+    container::ContainerActor,
+    observation::{observable_func, start_observation, StartedObservation},
+    pool::PoolActor,
+    sync::{GetSyncActiveMessage, StartSnapshotSyncCycleMessage, SyncActor, SyncSource, SyncToContainer},
+};
+use crate::{
+    actorbase::unhandled_result,
+    xactorext::{
+        join_all_actors, stop_all_actors, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage,
+        GetChildActorMessage, TerminalState,
+    },
+};
+use anyhow::{Context as _, Result};
+use futures_util::future::ready;
+use libblkcapt::{
+    core::BtrfsPool,
+    model::{
+        entities::{BtrfsPoolEntity, ObservableEvent, RemovableDrive, SnapshotSyncEntity},
+        Entity,
+    },
+    sys::btrfs::Filesystem,
+};
+use slog::{debug, info, o, Logger};
+use std::mem;
+use xactor::{message, Actor, Addr};
+
+pub struct RemovablePoolActor {
+    model: BtrfsPoolEntity,
+    settings: RemovableDrive,
+    syncs: Vec<(SnapshotSyncEntity, SyncSource, String)>,
+    state: AttachState,
+}
+
+enum AttachState {
+    Absent,
+    Attached(AttachedPool),
+    Released,
+    Faulted,
+}
+
+struct AttachedPool {
+    pool: BtrfsPool,
+    pool_actor: Addr<BcActor<PoolActor>>,
+    sync_actors: Vec<Addr<BcActor<SyncActor>>>,
+    pending_sync: Option<StartedObservation>,
+}
+
+impl AttachState {
+    fn take(&mut self) -> Self {
+        mem::replace(self, AttachState::Faulted)
+    }
+
+    fn kind(&self) -> AttachKind {
+        match self {
+            AttachState::Absent => AttachKind::Absent,
+            AttachState::Attached(attached) => AttachKind::Attached {
+                syncing: attached.pending_sync.is_some(),
+            },
+            AttachState::Released => AttachKind::Released,
+            AttachState::Faulted => AttachKind::Faulted,
+        }
+    }
+}
+
+/// An attach state without the attached pool's actors.
+#[derive(Clone, Copy)]
+enum AttachKind {
+    Absent,
+    Attached { syncing: bool },
+    Released,
+    Faulted,
+}
+
+impl AttachKind {
+    /// What a probe does in this state, given whether the pool's devices are present.
+    fn probed(self, present: bool) -> ProbeAction {
+        match self {
+            AttachKind::Absent | AttachKind::Faulted if present => ProbeAction::Attach,
+            AttachKind::Attached { .. } if !present => ProbeAction::Lost,
+            AttachKind::Attached { syncing: true } => ProbeAction::CheckSyncs,
+            AttachKind::Released if !present => ProbeAction::Removed,
+            _ => ProbeAction::Wait,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ProbeAction {
+    Attach,
+    Lost,
+    CheckSyncs,
+    Removed,
+    Wait,
+}
+
+#[message()]
+struct ProbeMessage;
+
+impl RemovablePoolActor {
+    pub fn new(
+        model: BtrfsPoolEntity, syncs: Vec<(SnapshotSyncEntity, SyncSource, String)>, log: &Logger,
+    ) -> BcActor<Self> {
+        let id = model.id();
+        BcActor::new(
+            Self {
+                settings: model.removable.clone().unwrap_or_default(),
+                model,
+                syncs,
+                state: AttachState::Absent,
+            },
+            &log.new(o!("actor" => "removable_pool", "pool_id" => id.to_string())),
+        )
+        .with_entity(id)
+    }
+
+    async fn attach(&self, log: &Logger) -> Result<AttachedPool> {
+        let pool = BtrfsPool::attach(self.model.clone())?.context("pool devices disappeared")?;
+        let pool_actor = PoolActor::new(self.model.clone(), log).start().await?;
+
+        let mut sync_actors = Vec::with_capacity(self.syncs.len());
+        for (sync_model, source, dataset_name) in self.syncs.iter() {
+            let mut containers = Vec::new();
+            for container_id in sync_model
+                .container_ids()
+                .filter(|id| self.model.containers.iter().any(|c| c.container_id() == *id))
+            {
+                let container_actor: Addr<BcActor<ContainerActor>> = pool_actor
+                    .call(GetChildActorMessage::new(container_id))
+                    .await?
+                    .context("destination btrfs container did not start")?;
+                containers.push((container_id, SyncToContainer::Btrfs(container_actor)));
+            }
+            let sync_actor = SyncActor::new(
+                source.clone(),
+                containers,
+                sync_model.clone(),
+                dataset_name.clone(),
+                log,
+            )
+            .start()
+            .await?;
+            sync_actors.push(sync_actor);
+        }
+
+        Ok(AttachedPool {
+            pool,
+            pool_actor,
+            sync_actors,
+            pending_sync: None,
+        })
+    }
+
+    async fn sync_active(attached: &AttachedPool) -> Result<bool> {
+        for sync_actor in attached.sync_actors.iter() {
+            if sync_actor.call(GetSyncActiveMessage).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+async fn stop_attached(attached: &mut AttachedPool) {
+    if let Some(observation) = attached.pending_sync.take() {
+        observation.cancelled();
+    }
+    stop_all_actors(attached.sync_actors.iter_mut());
+    join_all_actors(attached.sync_actors.drain(..)).await;
+    stop_all_actors(std::iter::once(&mut attached.pool_actor));
+    join_all_actors(std::iter::once(attached.pool_actor.clone())).await;
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for RemovablePoolActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        ctx.address().send(ProbeMessage).expect("send to self is infalliable");
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
+        if let AttachState::Attached(mut attached) = self.state.take() {
+            stop_attached(&mut attached).await;
+        }
+        TerminalState::Succeeded
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ProbeMessage> for RemovablePoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ProbeMessage) {
+        let present = Filesystem::query_uuid(&self.model.uuid).is_ok();
+        let action = self.state.kind().probed(present);
+        self.state = match (action, self.state.take()) {
+            (ProbeAction::Attach, _) => {
+                let observation = start_observation(self.model.id(), ObservableEvent::PoolAttach).await;
+                let result = self.attach(ctx.log()).await;
+                observation.result(&result);
+
+                match result {
+                    Ok(mut attached) => {
+                        info!(ctx.log(), "removable pool attached");
+                        attached.pending_sync =
+                            Some(start_observation(self.model.id(), ObservableEvent::PoolPendingSync).await);
+                        for sync_actor in attached.sync_actors.iter() {
+                            unhandled_result(ctx.log(), sync_actor.send(StartSnapshotSyncCycleMessage));
+                        }
+                        AttachState::Attached(attached)
+                    }
+                    Err(e) => {
+                        unhandled_result(ctx.log(), Err(e));
+                        AttachState::Faulted
+                    }
+                }
+            }
+            (ProbeAction::Lost, AttachState::Attached(mut attached)) => {
+                info!(ctx.log(), "removable pool disappeared while attached");
+                stop_attached(&mut attached).await;
+                AttachState::Absent
+            }
+            (ProbeAction::CheckSyncs, AttachState::Attached(mut attached)) => {
+                match Self::sync_active(&attached).await {
+                    Ok(true) => {
+                        debug!(ctx.log(), "waiting for pending syncs to complete");
+                        AttachState::Attached(attached)
+                    }
+                    Ok(false) if self.settings.detach_after_sync => {
+                        if let Some(observation) = attached.pending_sync.take() {
+                            observation.succeeded();
+                        }
+                        stop_attached(&mut attached).await;
+                        let power_down = self.settings.power_down;
+                        let pool = &attached.pool;
+                        let result = observable_func(self.model.id(), ObservableEvent::PoolDetach, || {
+                            ready(pool.detach(power_down))
+                        })
+                        .await;
+                        if result.is_ok() {
+                            info!(ctx.log(), "removable pool detached"; "power_down" => power_down);
+                        }
+                        unhandled_result(ctx.log(), result);
+                        AttachState::Released
+                    }
+                    Ok(false) => {
+                        if let Some(observation) = attached.pending_sync.take() {
+                            observation.succeeded();
+                        }
+                        AttachState::Attached(attached)
+                    }
+                    Err(e) => {
+                        unhandled_result(ctx.log(), Err(e));
+                        AttachState::Attached(attached)
+                    }
+                }
+            }
+            (ProbeAction::Removed, _) => AttachState::Absent,
+            (_, state) => state,
+        };
+
+        ctx.send_later(ProbeMessage, self.settings.probe_interval);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for RemovablePoolActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
+        match self.state.kind() {
+            AttachKind::Absent => String::from("absent"),
+            AttachKind::Attached { syncing: true } => String::from("attached, syncing"),
+            AttachKind::Attached { syncing: false } => String::from("attached"),
+            AttachKind::Released => String::from("released, waiting for removal"),
+            AttachKind::Faulted => String::from("faulted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_attaches_present_pools() {
+        assert_eq!(AttachKind::Absent.probed(true), ProbeAction::Attach);
+        assert_eq!(AttachKind::Faulted.probed(true), ProbeAction::Attach);
+        assert_eq!(AttachKind::Absent.probed(false), ProbeAction::Wait);
+        assert_eq!(AttachKind::Faulted.probed(false), ProbeAction::Wait);
+    }
+
+    #[test]
+    fn probe_follows_attached_pools() {
+        let syncing = AttachKind::Attached { syncing: true };
+        let idle = AttachKind::Attached { syncing: false };
+        assert_eq!(syncing.probed(false), ProbeAction::Lost);
+        assert_eq!(idle.probed(false), ProbeAction::Lost);
+        assert_eq!(syncing.probed(true), ProbeAction::CheckSyncs);
+        assert_eq!(idle.probed(true), ProbeAction::Wait);
+    }
+
+    #[test]
+    fn probe_waits_for_released_pools_to_be_removed() {
+        assert_eq!(AttachKind::Released.probed(true), ProbeAction::Wait);
+        assert_eq!(AttachKind::Released.probed(false), ProbeAction::Removed);
+    }
+}
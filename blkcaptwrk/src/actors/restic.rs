@@ -2,11 +2,11 @@ use super::{
     dataset::{DatasetHolderActor, HolderReadyMessage},
     transfer::TransferComplete,
 };
-use crate::actorbase::log_result;
+use crate::actorbase::{log_result, logged_result};
 use crate::xactorext::{BcContext, BoxBcAddr};
 use crate::{
     actorbase::unhandled_result,
-    snapshots::{ContainerSnapshotsResponse, GetContainerSnapshotsMessage, PruneMessage},
+    snapshots::{ContainerSnapshotsResponse, GetContainerSnapshotsMessage, PruneMessage, RestoreDrillMessage},
     tasks::WorkerCompleteMessage,
     tasks::WorkerTask,
     xactorext::{BcActor, BcActorCtrl, BcHandler, GetActorStatusMessage, TerminalState},
@@ -18,16 +18,16 @@ use derive_more::From;
 use libblkcapt::model::entities::FeatureState;
 use libblkcapt::{
     core::restic::ResticContainerSnapshot,
-    core::restic::{ResticBackup, ResticRepository},
+    core::restic::{CompletedResticBackup, ResticBackup, ResticBackupProgress, ResticRepository},
     core::SnapshotHandle,
     model::entities::ResticContainerEntity,
-    model::Entity,
 };
 use prune::{PruneCompleteMessage, ResticPruneActor};
 use slog::{debug, error, warn};
 use slog::{o, trace, Logger};
 use std::convert::TryInto;
-use std::{collections::HashMap, hash::Hash, mem, panic, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, mem, panic, path::PathBuf, sync::Arc};
+use tokio::sync::watch;
 use transfer::ParentTransferComplete;
 pub use transfer::ResticTransferActor;
 use xactor::{message, Addr, Sender};
@@ -36,26 +36,60 @@ mod container {
     use std::collections::{HashSet, VecDeque};
 
     use chrono::{DateTime, Utc};
+    use futures_util::future::{BoxFuture, FutureExt, Shared};
     use libblkcapt::{
-        core::retention::evaluate_retention,
-        model::{entities::ObservableEvent, EntityId},
+        api,
+        core::{
+            restic::ResticRepositoryStats,
+            retention::evaluate_retention,
+            verify::{check_sample, pick_sample, DrillReport},
+        },
+        data_dir,
+        model::{
+            entities::{BackupQueue, ObservableEvent, QueueOverflow, ResticBackupOptions, RestoreDrill},
+            storage, ContainerId, DatasetId,
+        },
         runtime_dir,
     };
     use slog::info;
+    use std::{fs, io, path::Path, time::Duration};
     use xactor::{Actor, WeakAddr};
 
-    use crate::{actorbase::ScheduledMessage, actors::observation::start_observation, snapshots::clear_deleted};
+    use crate::{
+        actorbase::ScheduledMessage,
+        actors::{
+            intel::{IntelActor, ResticStatsMessage},
+            observation::{start_observation, StartedObservation},
+        },
+        snapshots::clear_deleted,
+    };
 
     use super::*;
 
     pub struct ResticContainerActor {
-        container_id: EntityId,
+        container_id: ContainerId,
         repository: RepositoryState,
-        snapshots: HashMap<EntityId, Vec<ResticContainerSnapshot>>,
+        // None until the background listing started with the actor completes or a message needs the snapshots.
+        snapshots: Option<SnapshotCache>,
+        // The listing in flight, messages needing the snapshots meanwhile await it instead of listing again.
+        listing: Option<SnapshotListing>,
         prune_schedule: Option<ScheduledMessage>,
+        restore_drill_schedule: Option<ScheduledMessage>,
         state: State,
+        collecting_stats: bool,
+        // Set while a restore drill runs in the background.
+        drill: Option<StartedObservation>,
+        queue_saturated: bool,
     }
 
+    /// Only handles are cached, the restic ids needed to forget snapshots are listed again when pruning.
+    type SnapshotCache = HashMap<DatasetId, Vec<SnapshotHandle>>;
+
+    type SnapshotListing = Shared<BoxFuture<'static, Result<Arc<Vec<ResticContainerSnapshot>>, Arc<anyhow::Error>>>>;
+
+    const STATS_DELAY: Duration = Duration::from_secs(5 * 60);
+    const STATS_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
     enum RepositoryState {
         Started(Arc<ResticRepository>),
         Pending(ResticContainerEntity),
@@ -80,17 +114,23 @@ mod container {
     }
 
     enum Active {
-        Transfer {
-            actor: WeakAddr<BcActor<ResticTransferActor>>,
-            dataset_id: EntityId,
+        Transfers {
+            transfers: Vec<ActiveTransfer>,
+            // Set while prune waits for the running backups to finish, new backups stay queued meanwhile.
             prune_pending: bool,
         },
         Prune {
             actor: Addr<BcActor<ResticPruneActor>>,
-            forgets: Vec<(EntityId, HashSet<DateTime<Utc>>)>,
+            // None when restic chose the snapshots itself, the snapshot cache is reloaded instead.
+            forgets: Option<Vec<(DatasetId, HashSet<DateTime<Utc>>)>>,
         },
     }
 
+    struct ActiveTransfer {
+        actor: WeakAddr<BcActor<ResticTransferActor>>,
+        dataset_id: DatasetId,
+    }
+
     impl State {
         fn take(&mut self) -> Self {
             mem::replace(self, State::Faulted)
@@ -99,22 +139,36 @@ mod container {
 
     #[message(result = "Result<()>")]
     pub struct GetBackupMessage {
-        source_dataset_id: EntityId,
+        source_dataset_id: DatasetId,
         source_snapshot_handle: SnapshotHandle,
+        options: ResticBackupOptions,
         target: WeakAddr<BcActor<ResticTransferActor>>,
     }
 
     #[message]
     pub struct BackupReadyMessage(pub Result<ResticBackup>);
 
+    #[message]
+    struct SnapshotsLoadedMessage;
+
+    #[message]
+    struct CollectStatsMessage;
+
+    #[message]
+    struct StatsCollectedMessage(Result<ResticRepositoryStats>);
+
+    #[message]
+    struct RestoreDrillFinishedMessage(Vec<(DatasetId, Result<DrillReport>)>);
+
     impl GetBackupMessage {
         pub fn new(
-            requestor_addr: &Addr<BcActor<ResticTransferActor>>, source_dataset_id: EntityId,
-            source_snapshot_handle: SnapshotHandle,
+            requestor_addr: &Addr<BcActor<ResticTransferActor>>, source_dataset_id: DatasetId,
+            source_snapshot_handle: SnapshotHandle, options: ResticBackupOptions,
         ) -> Self {
             Self {
                 source_dataset_id,
                 source_snapshot_handle,
+                options,
                 target: requestor_addr.downgrade(),
             }
         }
@@ -122,45 +176,117 @@ mod container {
 
     impl ResticContainerActor {
         pub fn new(model: ResticContainerEntity, log: &Logger) -> BcActor<Self> {
-            let id = model.id();
+            let id = model.container_id();
             BcActor::new(
                 Self {
                     container_id: id,
                     repository: RepositoryState::Pending(model),
-                    snapshots: Default::default(),
+                    snapshots: None,
+                    listing: None,
                     prune_schedule: None,
+                    restore_drill_schedule: None,
                     state: State::Idle,
+                    collecting_stats: false,
+                    drill: None,
+                    queue_saturated: false,
                 },
                 &log.new(o!("container_id" => id.to_string())),
             )
+            .with_entity(id)
         }
 
         async fn process_waiting(&mut self, ctx: &BcContext<'_, Self>) {
-            let mut state = mem::replace(&mut self.state, State::Idle);
+            let (mut transfers, prune_pending, mut waiting) = match self.state.take() {
+                State::Active {
+                    active:
+                        Active::Transfers {
+                            transfers,
+                            prune_pending,
+                        },
+                    waiting,
+                } => (transfers, prune_pending, waiting),
+                State::Active {
+                    active: Active::Prune { .. },
+                    waiting,
+                } => (Vec::new(), false, waiting),
+                state => {
+                    self.state = state;
+                    self.backup_queue_drained(ctx).await;
+                    return;
+                }
+            };
 
-            if let State::Active { active, waiting } = &mut state {
-                let prune_pending = matches!(active, Active::Transfer { prune_pending, .. } if *prune_pending);
+            // Prune takes the exclusive repository lock, so it starts once every running backup has finished.
+            if prune_pending {
+                if !transfers.is_empty() {
+                    self.state = State::Active {
+                        active: Active::Transfers {
+                            transfers,
+                            prune_pending,
+                        },
+                        waiting,
+                    };
+                    return;
+                }
 
-                if prune_pending {
-                    if let Some(active_prune) = self.start_prune(ctx).await {
-                        *active = active_prune;
-                        self.state = state;
-                        return;
-                    }
+                if let Some(active) = self.start_prune(ctx).await {
+                    self.state = State::Active { active, waiting };
+                    return;
                 }
+            }
 
-                while let Some(waiter) = waiting.pop_front() {
-                    if let Ok(active_transfer) = self.start_backup(waiter).await {
-                        *active = active_transfer;
-                        self.state = state;
-                        return;
-                    }
+            let parallelism = self.repository.get().model().backup_parallelism();
+            while transfers.len() < parallelism {
+                let waiter = match next_startable(&mut waiting, |w| {
+                    transfers.iter().any(|t| t.dataset_id == w.source_dataset_id)
+                }) {
+                    Some(waiter) => waiter,
+                    None => break,
+                };
+                if let Ok(transfer) = self.start_backup(waiter).await {
+                    transfers.push(transfer);
                 }
             }
+
+            let drained = waiting.is_empty();
+            if !transfers.is_empty() {
+                self.state = State::Active {
+                    active: Active::Transfers {
+                        transfers,
+                        prune_pending: false,
+                    },
+                    waiting,
+                };
+            } else {
+                self.state = State::Idle;
+            }
+            if drained {
+                self.backup_queue_drained(ctx).await;
+            }
+        }
+
+        async fn backup_queue_saturated(&mut self, ctx: &BcContext<'_, Self>, overflow: QueueOverflow) {
+            if !self.queue_saturated {
+                self.queue_saturated = true;
+                warn!(ctx.log(), "backup queue is full"; "overflow" => %overflow);
+                start_observation(self.container_id.into(), ObservableEvent::ContainerBackupQueue)
+                    .await
+                    .failed(format!("backup queue is full, overflow policy {}", overflow));
+            }
         }
 
-        async fn start_prune(&self, ctx: &BcContext<'_, Self>) -> Option<Active> {
-            let observation = start_observation(self.container_id, ObservableEvent::ContainerPrune).await;
+        async fn backup_queue_drained(&mut self, ctx: &BcContext<'_, Self>) {
+            if self.queue_saturated {
+                self.queue_saturated = false;
+                info!(ctx.log(), "backup queue drained");
+                start_observation(self.container_id.into(), ObservableEvent::ContainerBackupQueue)
+                    .await
+                    .succeeded();
+            }
+        }
+
+        async fn start_prune(&mut self, ctx: &BcContext<'_, Self>) -> Option<Active> {
+            let observation = start_observation(self.container_id.into(), ObservableEvent::ContainerPrune).await;
             let repository = self.repository.get();
             let rules = repository
                 .model()
@@ -168,9 +294,24 @@ mod container {
                 .as_ref()
                 .expect("retention exist based on message scheduling in started");
 
+            if repository.model().native_forget {
+                return self.start_policy_prune(ctx, observation).await;
+            }
+
+            let snapshots = match ctx
+                .long_await(cached_snapshots(&mut self.snapshots, &mut self.listing, repository))
+                .await
+            {
+                Ok(snapshots) => snapshots,
+                Err(e) => {
+                    observation.error::<anyhow::Error, _>(&e);
+                    log_result(ctx.log(), &Err::<(), _>(e));
+                    return None;
+                }
+            };
+
             // create forget process
-            let evals = self
-                .snapshots
+            let evals = snapshots
                 .iter()
                 .map(|(dataset_id, snapshots)| {
                     trace!(ctx.log(), "prune container"; "dataset_id" => %dataset_id);
@@ -178,16 +319,28 @@ mod container {
                 })
                 .collect::<Vec<_>>();
 
-            let forgets = evals
+            let drops = evals
                 .iter()
-                .flat_map(|(_, eval)| eval.drop_snapshots.iter().cloned())
-                .collect::<Vec<_>>();
+                .flat_map(|(dataset_id, eval)| eval.drop_snapshots.iter().map(move |s| (*dataset_id, s.datetime)))
+                .collect::<HashSet<_>>();
 
-            if forgets.is_empty() {
+            if drops.is_empty() {
                 observation.succeeded();
                 return None;
             }
 
+            let details = match ctx.long_await(repository.snapshots()).await {
+                Ok(details) => details,
+                Err(e) => {
+                    observation.error::<anyhow::Error, _>(&e);
+                    log_result(ctx.log(), &Err::<(), _>(e));
+                    return None;
+                }
+            };
+            let forgets = details
+                .iter()
+                .filter(|s| drops.contains(&(s.dataset_id, s.datetime)))
+                .collect::<Vec<_>>();
             let forget = repository.forget(&forgets);
 
             // create prune process
@@ -202,22 +355,94 @@ mod container {
             actor_result
                 .map(|actor| Active::Prune {
                     actor,
-                    forgets: evals
-                        .into_iter()
-                        .map(|(id, eval)| (id, eval.drop_snapshots.into_iter().map(|s| s.datetime).collect()))
-                        .collect(),
+                    forgets: Some(
+                        evals
+                            .into_iter()
+                            .map(|(id, eval)| (id, eval.drop_snapshots.into_iter().map(|s| s.datetime).collect()))
+                            .collect(),
+                    ),
                 })
                 .ok()
         }
 
-        async fn start_backup(&self, msg: GetBackupMessage) -> Result<Active> {
-            let bind_path = {
-                let mut p = runtime_dir();
-                p.push("restic_bind");
-                p.push(self.container_id.to_string());
-                p.push(msg.source_dataset_id.to_string());
-                p
+        async fn start_policy_prune(
+            &mut self, ctx: &BcContext<'_, Self>, observation: StartedObservation,
+        ) -> Option<Active> {
+            let repository = self.repository.get();
+            let rules = repository
+                .model()
+                .snapshot_retention
+                .as_ref()
+                .expect("retention exist based on message scheduling in started");
+
+            let dataset_ids = match ctx
+                .long_await(cached_snapshots(&mut self.snapshots, &mut self.listing, repository))
+                .await
+            {
+                Ok(snapshots) => snapshots.keys().copied().collect::<Vec<_>>(),
+                Err(e) => {
+                    observation.error::<anyhow::Error, _>(&e);
+                    log_result(ctx.log(), &Err::<(), _>(e));
+                    return None;
+                }
+            };
+            let paths = dataset_ids.into_iter().map(|id| self.bind_path(id)).collect::<Vec<_>>();
+            if paths.is_empty() {
+                observation.succeeded();
+                return None;
+            }
+
+            let forget = match repository.forget_by_policy(rules, &paths) {
+                Ok(forget) => forget,
+                Err(e) => {
+                    observation.error::<anyhow::Error, _>(&e);
+                    log_result(ctx.log(), &Err::<(), _>(e));
+                    return None;
+                }
             };
+            let prune = repository.prune();
+
+            let actor_result = ResticPruneActor::new(ctx.address(), forget, prune, observation, ctx.log())
+                .start()
+                .await
+                .context("failed to start prune actor");
+            log_result(ctx.log(), &actor_result);
+            actor_result.map(|actor| Active::Prune { actor, forgets: None }).ok()
+        }
+
+        fn bind_path(&self, dataset_id: DatasetId) -> PathBuf {
+            let mut p = runtime_dir();
+            p.push("restic_bind");
+            p.push(self.container_id.to_string());
+            p.push(dataset_id.to_string());
+            p
+        }
+
+        fn can_start_backup(&self, dataset_id: DatasetId) -> bool {
+            match &self.state {
+                State::Active {
+                    active:
+                        Active::Transfers {
+                            transfers,
+                            prune_pending,
+                        },
+                    ..
+                } => {
+                    !prune_pending
+                        && transfers.len() < self.repository.get().model().backup_parallelism()
+                        && !transfers.iter().any(|t| t.dataset_id == dataset_id)
+                }
+                State::Active {
+                    active: Active::Prune { .. },
+                    ..
+                }
+                | State::Faulted => false,
+                State::Idle => true,
+            }
+        }
+
+        async fn start_backup(&self, msg: GetBackupMessage) -> Result<ActiveTransfer> {
+            let bind_path = self.bind_path(msg.source_dataset_id);
 
             let repository = &self.repository.get();
             let existing_snapshot = repository
@@ -233,12 +458,16 @@ mod container {
                 )
             }
 
-            let snapshot_backup = repository.backup(bind_path, msg.source_dataset_id, msg.source_snapshot_handle);
+            let snapshot_backup = repository.backup(
+                bind_path,
+                msg.source_dataset_id,
+                msg.source_snapshot_handle,
+                &msg.options,
+            );
             let addr = msg.target.upgrade().context("transfer is no longer alive")?;
             let _ = addr.send(BackupReadyMessage(Ok(snapshot_backup)));
-            Ok(Active::Transfer {
+            Ok(ActiveTransfer {
                 dataset_id: msg.source_dataset_id,
-                prune_pending: false,
                 actor: addr.downgrade(),
             })
         }
@@ -249,13 +478,18 @@ mod container {
         async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
             if let RepositoryState::Pending(model) = &self.repository {
                 let repository = ResticRepository::validate(model.clone()).map(Arc::new)?;
-                self.snapshots = group_by(repository.snapshots().await?, |s| &s.dataset_id);
-                trace!(
-                    ctx.log(),
-                    "Starting container with {} snapshots from {} datasets.",
-                    self.snapshots.values().fold(0, |acc, v| acc + v.len()),
-                    self.snapshots.len()
-                );
+                let version = repository.detect_version().await?;
+                info!(ctx.log(), "restic version detected"; "version" => %version);
+
+                // Listing a large repository takes a while, the actor is ready before it completes.
+                let listing = start_listing(&repository);
+                let pending = listing.clone();
+                let addr = ctx.address();
+                tokio::spawn(async move {
+                    let _ = pending.await;
+                    let _ = addr.send(SnapshotsLoadedMessage);
+                });
+                self.listing = Some(listing);
                 self.repository = RepositoryState::Started(repository);
             } else {
                 bail!("Pool already started.");
@@ -275,17 +509,39 @@ mod container {
                     })?;
             }
 
+            self.restore_drill_schedule =
+                self.repository
+                    .get()
+                    .model()
+                    .restore_drill
+                    .as_ref()
+                    .map_or(Ok(None), |d| {
+                        (&d.schedule).try_into().map(|schedule| {
+                            Some(ScheduledMessage::new(
+                                schedule,
+                                "restore_drill",
+                                RestoreDrillMessage,
+                                &ctx,
+                            ))
+                        })
+                    })?;
+
+            ctx.send_later(CollectStatsMessage, STATS_DELAY);
+
             Ok(())
         }
 
         async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
             match self.state.take() {
                 State::Active { active, waiting } => {
-                    let maybe_actor: Option<BoxBcAddr> = match active {
-                        Active::Transfer { actor, .. } => actor.upgrade().map(|a| a.into()),
-                        Active::Prune { actor, .. } => Some(actor.into()),
+                    let actors: Vec<BoxBcAddr> = match active {
+                        Active::Transfers { transfers, .. } => transfers
+                            .into_iter()
+                            .filter_map(|t| t.actor.upgrade().map(|a| a.into()))
+                            .collect(),
+                        Active::Prune { actor, .. } => vec![actor.into()],
                     };
-                    if let Some(mut actor) = maybe_actor {
+                    for mut actor in actors {
                         let _ = actor.stop();
                         actor.wait_for_stop().await;
                     }
@@ -305,47 +561,168 @@ mod container {
     #[async_trait::async_trait]
     impl BcHandler<GetContainerSnapshotsMessage> for ResticContainerActor {
         async fn handle(
-            &mut self, _ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
+            &mut self, ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
         ) -> ContainerSnapshotsResponse {
+            let snapshots = logged_result(
+                ctx.log(),
+                ctx.long_await(cached_snapshots(
+                    &mut self.snapshots,
+                    &mut self.listing,
+                    self.repository.get(),
+                ))
+                .await,
+            );
             ContainerSnapshotsResponse {
-                snapshots: self
-                    .snapshots
-                    .get(&msg.source_dataset_id)
-                    .map(|v| v.iter().map(|s| s.into()).collect())
+                snapshots: snapshots
+                    .ok()
+                    .and_then(|s| s.get(&msg.source_dataset_id).cloned())
                     .unwrap_or_default(),
             }
         }
     }
 
     #[async_trait::async_trait]
-    impl BcHandler<GetBackupMessage> for ResticContainerActor {
-        async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetBackupMessage) -> Result<()> {
-            match &mut self.state {
-                State::Active { waiting, .. } => {
-                    waiting.push_back(msg);
-                    Ok(())
+    impl BcHandler<SnapshotsLoadedMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SnapshotsLoadedMessage) {
+            // Gone when a message already took the listing, or when a prune discarded it for a newer one.
+            let result = match self.listing.clone().and_then(|l| l.now_or_never()) {
+                Some(result) => result,
+                None => return,
+            };
+            self.listing = None;
+
+            match result {
+                Ok(snapshots) => {
+                    let cache = snapshot_cache(&snapshots);
+                    trace!(
+                        ctx.log(),
+                        "Loaded {} snapshots from {} datasets.",
+                        cache.values().fold(0, |acc, v| acc + v.len()),
+                        cache.len()
+                    );
+                    self.snapshots = Some(cache);
                 }
-                State::Idle => match self.start_backup(msg).await {
-                    Ok(active) => {
+                Err(e) => error!(ctx.log(), "failed to list snapshots"; "error" => %e),
+            }
+        }
+    }
+
+    fn start_listing(repository: &Arc<ResticRepository>) -> SnapshotListing {
+        let repository = Arc::clone(repository);
+        async move { repository.snapshots().await.map(Arc::new).map_err(Arc::new) }
+            .boxed()
+            .shared()
+    }
+
+    async fn cached_snapshots<'a>(
+        snapshots: &'a mut Option<SnapshotCache>, listing: &mut Option<SnapshotListing>,
+        repository: &Arc<ResticRepository>,
+    ) -> Result<&'a mut SnapshotCache> {
+        if snapshots.is_none() {
+            let listed = listing
+                .take()
+                .unwrap_or_else(|| start_listing(repository))
+                .await
+                .map_err(|e| anyhow!("failed to list snapshots: {:#}", e))?;
+            *snapshots = Some(snapshot_cache(&listed));
+        }
+        Ok(snapshots.as_mut().expect("snapshots were just loaded"))
+    }
+
+    fn snapshot_cache(snapshots: &[ResticContainerSnapshot]) -> SnapshotCache {
+        let mut cache = SnapshotCache::new();
+        for snapshot in snapshots.iter() {
+            cache.entry(snapshot.dataset_id).or_default().push(snapshot.into());
+        }
+        cache
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<GetBackupMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetBackupMessage) -> Result<()> {
+            if self.can_start_backup(msg.source_dataset_id) {
+                let transfer = self.start_backup(msg).await?;
+                match &mut self.state {
+                    State::Active {
+                        active: Active::Transfers { transfers, .. },
+                        ..
+                    } => transfers.push(transfer),
+                    _ => {
                         self.state = State::Active {
-                            active,
+                            active: Active::Transfers {
+                                transfers: vec![transfer],
+                                prune_pending: false,
+                            },
                             waiting: Default::default(),
-                        };
-                        Ok(())
+                        }
                     }
-                    Err(e) => Err(e),
-                },
+                }
+                return Ok(());
+            }
+
+            match &mut self.state {
+                State::Active { waiting, .. } => {
+                    let queue = &self.repository.get().model().backup_queue;
+                    let overflow = queue.overflow;
+                    let queued = enqueue_backup(waiting, msg, queue);
+                    if !matches!(queued, Ok(false)) {
+                        self.backup_queue_saturated(&ctx, overflow).await;
+                    }
+                    queued.map(|_| ())
+                }
+                State::Idle => unreachable!("an idle actor starts every backup"),
                 State::Faulted => Err(anyhow!("actor faulted")),
             }
         }
     }
 
+    // Takes the oldest queued backup whose dataset has no backup running. Snapshots of a dataset are backed up in
+    // order, one at a time.
+    fn next_startable<T>(waiting: &mut VecDeque<T>, running: impl Fn(&T) -> bool) -> Option<T> {
+        let index = waiting.iter().position(|w| !running(w))?;
+        waiting.remove(index)
+    }
+
+    /// Queues a backup behind the active ones. Returns whether the queue overflowed.
+    fn enqueue_backup(
+        waiting: &mut VecDeque<GetBackupMessage>, msg: GetBackupMessage, queue: &BackupQueue,
+    ) -> Result<bool> {
+        let dropped = make_room(waiting, queue, |w| w.source_dataset_id == msg.source_dataset_id)?;
+        let overflowed = dropped.is_some();
+        if let Some(addr) = dropped.and_then(|d| d.target.upgrade()) {
+            let _ = addr.send(BackupReadyMessage(Err(anyhow!(
+                "backup dropped from the full backup queue"
+            ))));
+        }
+        waiting.push_back(msg);
+        Ok(overflowed)
+    }
+
+    // Removes the entry the overflow policy drops when the queue is full, `same_dataset` matches the entries a new one
+    // replaces when coalescing.
+    fn make_room<T>(
+        waiting: &mut VecDeque<T>, queue: &BackupQueue, same_dataset: impl Fn(&T) -> bool,
+    ) -> Result<Option<T>> {
+        if waiting.len() < queue.depth.get() {
+            return Ok(None);
+        }
+
+        Ok(match queue.overflow {
+            QueueOverflow::Reject => bail!("backup queue is full"),
+            QueueOverflow::DropOldest => waiting.pop_front(),
+            QueueOverflow::CoalesceToLatest => {
+                let index = waiting.iter().position(same_dataset).unwrap_or(0);
+                waiting.remove(index)
+            }
+        })
+    }
+
     #[async_trait::async_trait]
     impl BcHandler<PruneMessage> for ResticContainerActor {
         async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
             match &mut self.state {
                 State::Active {
-                    active: Active::Transfer { prune_pending, .. },
+                    active: Active::Transfers { prune_pending, .. },
                     ..
                 } => {
                     *prune_pending = true;
@@ -376,12 +753,38 @@ mod container {
         async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ParentTransferComplete) {
             match &mut self.state {
                 State::Active {
-                    active: Active::Transfer { dataset_id, .. },
+                    active: Active::Transfers { transfers, .. },
                     ..
                 } => {
-                    if let Some(snapshot) = msg.0 {
+                    let ParentTransferComplete(transfer_id, snapshot) = msg;
+                    let index = match transfers.iter().position(|t| t.actor.actor_id() == transfer_id) {
+                        Some(index) => index,
+                        None => {
+                            warn!(ctx.log(), "received transfer complete for an unknown transfer");
+                            return;
+                        }
+                    };
+                    let dataset_id = transfers.remove(index).dataset_id;
+
+                    if let Some(snapshot) = snapshot {
                         info!(ctx.log(), "snapshot received"; "dataset_id" => %dataset_id, "time" => %snapshot.datetime);
-                        self.snapshots.entry(*dataset_id).or_default().push(snapshot);
+                        // A listing loaded just now already includes the new snapshot.
+                        match ctx
+                            .long_await(cached_snapshots(
+                                &mut self.snapshots,
+                                &mut self.listing,
+                                self.repository.get(),
+                            ))
+                            .await
+                        {
+                            Ok(cache) => {
+                                let snapshots = cache.entry(dataset_id).or_default();
+                                if !snapshots.iter().any(|s| s.datetime == snapshot.datetime) {
+                                    snapshots.push((&snapshot).into());
+                                }
+                            }
+                            Err(e) => log_result(ctx.log(), &Err::<(), _>(e)),
+                        }
                     }
 
                     self.process_waiting(&ctx).await;
@@ -408,18 +811,34 @@ mod container {
                     ..
                 } => {
                     let PruneCompleteMessage(forgot) = msg;
-                    if forgot {
-                        for (dataset_id, snapshots) in forgets {
-                            if let Some(cache) = self.snapshots.get_mut(dataset_id) {
-                                clear_deleted(cache, mem::take(snapshots));
+                    match forgets {
+                        Some(forgets) if forgot => {
+                            for (dataset_id, snapshots) in forgets {
+                                if let Some(cache) = self.snapshots.as_mut().and_then(|c| c.get_mut(dataset_id)) {
+                                    clear_deleted(cache, mem::take(snapshots));
+                                }
                             }
                         }
+                        None if forgot => {
+                            // Reloaded on next use if listing fails now. A listing in flight predates the prune.
+                            self.snapshots = None;
+                            self.listing = None;
+                            let reloaded = ctx
+                                .long_await(cached_snapshots(
+                                    &mut self.snapshots,
+                                    &mut self.listing,
+                                    self.repository.get(),
+                                ))
+                                .await;
+                            log_result(ctx.log(), &reloaded);
+                        }
+                        _ => {}
                     }
 
                     self.process_waiting(&ctx).await;
                 }
                 State::Active {
-                    active: Active::Transfer { .. },
+                    active: Active::Transfers { .. },
                     ..
                 }
                 | State::Idle => {
@@ -431,20 +850,322 @@ mod container {
         }
     }
 
+    #[async_trait::async_trait]
+    impl BcHandler<CollectStatsMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: CollectStatsMessage) {
+            ctx.send_later(CollectStatsMessage, STATS_INTERVAL);
+
+            // restic stats cannot run while prune holds the exclusive repository lock
+            if self.collecting_stats
+                || matches!(
+                    self.state,
+                    State::Active {
+                        active: Active::Prune { .. },
+                        ..
+                    }
+                )
+            {
+                debug!(ctx.log(), "skipping stats collection");
+                return;
+            }
+
+            let dataset_ids = match ctx
+                .long_await(cached_snapshots(
+                    &mut self.snapshots,
+                    &mut self.listing,
+                    self.repository.get(),
+                ))
+                .await
+            {
+                Ok(snapshots) => snapshots.keys().copied().collect::<Vec<_>>(),
+                Err(e) => {
+                    log_result(ctx.log(), &Err::<(), _>(e));
+                    return;
+                }
+            };
+
+            self.collecting_stats = true;
+            let repository = self.repository.get().clone();
+            let datasets = dataset_ids
+                .into_iter()
+                .map(|id| (id, self.bind_path(id)))
+                .collect::<Vec<_>>();
+            let addr = ctx.address();
+            tokio::spawn(async move {
+                let result = repository.stats(&datasets).await;
+                let _ = addr.send(StatsCollectedMessage(result));
+            });
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<StatsCollectedMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: StatsCollectedMessage) {
+            self.collecting_stats = false;
+            match msg.0 {
+                Ok(stats) => {
+                    debug!(ctx.log(), "repository stats collected"; "stored_bytes" => stats.stored_bytes, "restore_bytes" => stats.restore_bytes);
+                    unhandled_result(ctx.log(), IntelActor::addr().send(ResticStatsMessage(stats)));
+                }
+                Err(e) => log_result(
+                    ctx.log(),
+                    &Err::<(), _>(e.context("failed to collect repository stats")),
+                ),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<RestoreDrillMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: RestoreDrillMessage) {
+            // restic restore cannot run while prune holds the exclusive repository lock
+            if self.drill.is_some()
+                || matches!(
+                    self.state,
+                    State::Active {
+                        active: Active::Prune { .. },
+                        ..
+                    }
+                )
+            {
+                info!(ctx.log(), "skipping restore drill");
+                return;
+            }
+
+            let observation = start_observation(self.container_id.into(), ObservableEvent::ContainerRestoreDrill).await;
+            let dataset_ids = match ctx
+                .long_await(cached_snapshots(
+                    &mut self.snapshots,
+                    &mut self.listing,
+                    self.repository.get(),
+                ))
+                .await
+            {
+                Ok(snapshots) => snapshots.keys().copied().collect::<Vec<_>>(),
+                Err(e) => {
+                    observation.error::<anyhow::Error, _>(&e);
+                    log_result(ctx.log(), &Err::<(), _>(e));
+                    return;
+                }
+            };
+
+            self.drill = Some(observation);
+            let repository = self.repository.get().clone();
+            let drill = repository
+                .model()
+                .restore_drill
+                .clone()
+                .expect("restore drill exists based on message scheduling in started");
+            let datasets = dataset_ids
+                .into_iter()
+                .map(|id| (id, self.bind_path(id)))
+                .collect::<Vec<_>>();
+            let target = data_dir().join("restore-drill").join(self.container_id.to_string());
+            let seed = ctx.clock().now().timestamp() as u64;
+            let addr = ctx.address();
+            tokio::spawn(async move {
+                let mut reports = Vec::with_capacity(datasets.len());
+                for (dataset_id, bind_path) in datasets {
+                    let report = drill_dataset(&repository, dataset_id, &bind_path, &drill, &target, seed).await;
+                    if let Err(e) = fs::remove_dir_all(&target) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            reports.push((
+                                dataset_id,
+                                Err(anyhow::Error::new(e).context("failed to remove restore")),
+                            ));
+                            continue;
+                        }
+                    }
+                    reports.push((dataset_id, report));
+                }
+                let _ = addr.send(RestoreDrillFinishedMessage(reports));
+            });
+        }
+    }
+
+    // The seed is the drill time, so successive drills of an unchanged snapshot sample other files.
+    async fn drill_dataset(
+        repository: &Arc<ResticRepository>, dataset_id: DatasetId, bind_path: &Path, drill: &RestoreDrill,
+        target: &Path, seed: u64,
+    ) -> Result<DrillReport> {
+        let snapshot = repository
+            .snapshots()
+            .await?
+            .into_iter()
+            .filter(|s| s.dataset_id == dataset_id)
+            .max_by_key(|s| s.datetime)
+            .context("no snapshot to restore")?;
+        let sample = pick_sample(
+            &repository.list_files(&snapshot.uuid, bind_path).await?,
+            drill.sample_files(),
+            seed,
+        );
+        let restored = repository
+            .restore_files(&snapshot.uuid, bind_path, &sample, target)
+            .await?;
+        // The source snapshot may already be pruned from the dataset.
+        let source = if drill.compare {
+            storage::load_entity_config()
+                .and_then(|entities| api::dataset_snapshots(&entities, dataset_id))
+                .ok()
+                .and_then(|s| s.into_iter().find(|s| s.uuid == snapshot.received_uuid))
+                .and_then(|s| s.path)
+        } else {
+            None
+        };
+        check_sample(&restored, source.as_deref(), &sample)
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<RestoreDrillFinishedMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: RestoreDrillFinishedMessage) {
+            let observation = match self.drill.take() {
+                Some(observation) => observation,
+                None => return,
+            };
+            let mut failed = 0;
+            for (dataset_id, report) in msg.0 {
+                match report {
+                    Ok(report) if report.mismatched.is_empty() => {
+                        info!(ctx.log(), "restore drill {}", report.summary(); "dataset_id" => %dataset_id)
+                    }
+                    Ok(report) => {
+                        failed += 1;
+                        warn!(ctx.log(), "restore drill {}", report.summary(); "dataset_id" => %dataset_id)
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        warn!(ctx.log(), "restore drill failed"; "dataset_id" => %dataset_id, "error" => %e)
+                    }
+                }
+            }
+
+            if failed == 0 {
+                observation.succeeded();
+            } else {
+                observation.failed(format!("restore drill failed for {} datasets", failed));
+            }
+        }
+    }
+
     #[async_trait::async_trait]
     impl BcHandler<GetActorStatusMessage> for ResticContainerActor {
         async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
             match &self.state {
                 State::Active { active, .. } => match active {
-                    Active::Transfer { .. } => "transferring",
+                    Active::Transfers { .. } => "transferring",
                     Active::Prune { .. } => "pruning",
                 },
+                State::Idle if self.listing.is_some() => "warming",
                 State::Idle => "idle",
                 State::Faulted => "faulted",
             }
             .into()
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::num::NonZeroUsize;
+
+        fn queue(depth: usize, overflow: QueueOverflow) -> BackupQueue {
+            BackupQueue {
+                depth: NonZeroUsize::new(depth).unwrap(),
+                overflow,
+            }
+        }
+
+        // Entries are (dataset, sequence) pairs.
+        fn enqueue(waiting: &mut VecDeque<(u8, u8)>, entry: (u8, u8), queue: &BackupQueue) -> Result<Option<(u8, u8)>> {
+            let dropped = make_room(waiting, queue, |w| w.0 == entry.0)?;
+            waiting.push_back(entry);
+            Ok(dropped)
+        }
+
+        #[test]
+        fn queue_below_depth_keeps_everything() {
+            let queue = queue(2, QueueOverflow::Reject);
+            let mut waiting = VecDeque::new();
+            assert_eq!(enqueue(&mut waiting, (1, 1), &queue).unwrap(), None);
+            assert_eq!(enqueue(&mut waiting, (1, 2), &queue).unwrap(), None);
+            assert_eq!(waiting, [(1, 1), (1, 2)]);
+        }
+
+        #[test]
+        fn full_queue_rejects() {
+            let queue = queue(1, QueueOverflow::Reject);
+            let mut waiting = VecDeque::from(vec![(1, 1)]);
+            assert!(enqueue(&mut waiting, (2, 1), &queue).is_err());
+            assert_eq!(waiting, [(1, 1)]);
+        }
+
+        #[test]
+        fn full_queue_drops_oldest() {
+            let queue = queue(2, QueueOverflow::DropOldest);
+            let mut waiting = VecDeque::from(vec![(1, 1), (2, 1)]);
+            assert_eq!(enqueue(&mut waiting, (2, 2), &queue).unwrap(), Some((1, 1)));
+            assert_eq!(waiting, [(2, 1), (2, 2)]);
+        }
+
+        #[test]
+        fn full_queue_coalesces_same_dataset() {
+            let queue = queue(2, QueueOverflow::CoalesceToLatest);
+            let mut waiting = VecDeque::from(vec![(1, 1), (2, 1)]);
+            assert_eq!(enqueue(&mut waiting, (2, 2), &queue).unwrap(), Some((2, 1)));
+            assert_eq!(waiting, [(1, 1), (2, 2)]);
+
+            assert_eq!(enqueue(&mut waiting, (3, 1), &queue).unwrap(), Some((1, 1)));
+            assert_eq!(waiting, [(2, 2), (3, 1)]);
+        }
+
+        // Starts queued entries like process_waiting until the running ones fill the parallelism.
+        fn schedule(running: &mut Vec<(u8, u8)>, waiting: &mut VecDeque<(u8, u8)>, parallelism: usize) {
+            while running.len() < parallelism {
+                match next_startable(waiting, |w| running.iter().any(|r| r.0 == w.0)) {
+                    Some(next) => running.push(next),
+                    None => break,
+                }
+            }
+        }
+
+        #[test]
+        fn next_startable_skips_running_datasets() {
+            let mut waiting = VecDeque::from(vec![(1, 2), (2, 1), (1, 3)]);
+            assert_eq!(next_startable(&mut waiting, |w| w.0 == 1), Some((2, 1)));
+            assert_eq!(waiting, [(1, 2), (1, 3)]);
+            assert_eq!(next_startable(&mut waiting, |w| w.0 == 1), None);
+            assert_eq!(next_startable(&mut waiting, |_| false), Some((1, 2)));
+        }
+
+        #[test]
+        fn schedule_runs_datasets_in_parallel() {
+            let mut running = Vec::new();
+            let mut waiting = VecDeque::from(vec![(1, 1), (1, 2), (2, 1), (3, 1)]);
+            schedule(&mut running, &mut waiting, 2);
+            assert_eq!(running, [(1, 1), (2, 1)]);
+            assert_eq!(waiting, [(1, 2), (3, 1)]);
+
+            running.retain(|r| r.0 != 2);
+            schedule(&mut running, &mut waiting, 2);
+            assert_eq!(running, [(1, 1), (3, 1)]);
+            assert_eq!(waiting, [(1, 2)]);
+
+            running.clear();
+            schedule(&mut running, &mut waiting, 2);
+            assert_eq!(running, [(1, 2)]);
+            assert!(waiting.is_empty());
+        }
+
+        #[test]
+        fn schedule_runs_one_at_a_time_by_default() {
+            let mut running = Vec::new();
+            let mut waiting = VecDeque::from(vec![(1, 1), (2, 1)]);
+            schedule(&mut running, &mut waiting, 1);
+            assert_eq!(running, [(1, 1)]);
+            assert_eq!(waiting, [(2, 1)]);
+        }
+    }
 }
 
 mod transfer {
@@ -463,7 +1184,12 @@ mod transfer {
 
     enum State {
         WaitingForHoldAndBackup(Option<HolderState>, Option<ResticBackup>, StartedObservation),
-        Transferring(Addr<BcActor<DatasetHolderActor>>, WorkerTask, StartedObservation),
+        Transferring(
+            Addr<BcActor<DatasetHolderActor>>,
+            WorkerTask,
+            StartedObservation,
+            watch::Receiver<Option<ResticBackupProgress>>,
+        ),
         Transferred(Result<ResticContainerSnapshot>),
         Faulted,
     }
@@ -485,7 +1211,7 @@ mod transfer {
         }
     }
 
-    type BackupWorkerCompleteMessage = WorkerCompleteMessage<Result<ResticContainerSnapshot>>;
+    type BackupWorkerCompleteMessage = WorkerCompleteMessage<Result<CompletedResticBackup>>;
 
     #[async_trait::async_trait]
     impl BcActorCtrl for ResticTransferActor {
@@ -495,7 +1221,7 @@ mod transfer {
 
         async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
             let (terminal_state, result) = match self.state.take() {
-                State::Transferring(_holder, worker_task, observation) => {
+                State::Transferring(_holder, worker_task, observation, _) => {
                     warn!(ctx.log(), "cancelled during transfer");
                     worker_task.abort();
                     debug!(ctx.log(), "waiting for worker");
@@ -515,8 +1241,12 @@ mod transfer {
                 }
             };
 
-            let container_notify_result = self.parent.send(ParentTransferComplete(result.ok()));
-            let requestor_notify_result = self.requestor.send(TransferComplete(terminal_state));
+            let container_notify_result = self.parent.send(ParentTransferComplete(ctx.actor_id(), result.ok()));
+            let requestor_notify_result = self.requestor.send(TransferComplete {
+                transfer_id: ctx.actor_id(),
+                state: terminal_state,
+                missing_parent: false,
+            });
             if !matches!(terminal_state, TerminalState::Cancelled) {
                 unhandled_result(ctx.log(), container_notify_result);
                 unhandled_result(ctx.log(), requestor_notify_result);
@@ -530,6 +1260,7 @@ mod transfer {
             parent: Sender<TransferComplete>, container: Addr<BcActor<ResticContainerActor>>,
             observation: StartedObservation, log: &Logger,
         ) -> BcActor<Self> {
+            let entity_id = observation.source();
             BcActor::new(
                 Self {
                     state: State::WaitingForHoldAndBackup(None, None, observation),
@@ -538,6 +1269,7 @@ mod transfer {
                 },
                 log,
             )
+            .with_entity(entity_id)
         }
 
         fn maybe_start_transfer(incoming: State, ctx: &BcContext<'_, Self>) -> State {
@@ -545,9 +1277,10 @@ mod transfer {
                 let started_backup = backup.start(&holder_state.snapshot_path);
                 match started_backup {
                     Ok(started) => {
+                        let progress = started.progress();
                         let task =
                             WorkerTask::run(ctx.address(), ctx.log(), |_| async move { started.wait().await.into() });
-                        State::Transferring(holder_state.holder, task, observation)
+                        State::Transferring(holder_state.holder, task, observation, progress)
                     }
                     Err(e) => {
                         ctx.stop(None);
@@ -594,7 +1327,7 @@ mod transfer {
     }
 
     #[message]
-    pub struct ParentTransferComplete(pub Option<ResticContainerSnapshot>);
+    pub struct ParentTransferComplete(pub u64, pub Option<ResticContainerSnapshot>);
 
     #[async_trait::async_trait]
     impl BcHandler<HolderReadyMessage> for ResticTransferActor {
@@ -616,9 +1349,13 @@ mod transfer {
     #[async_trait::async_trait]
     impl BcHandler<BackupWorkerCompleteMessage> for ResticTransferActor {
         async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: BackupWorkerCompleteMessage) {
-            if let State::Transferring(.., observation) = self.state.take() {
-                observation.result(&msg.0);
-                self.state = State::Transferred(msg.0);
+            if let State::Transferring(_, _, observation, _) = self.state.take() {
+                let result = msg.0.map(|backup| {
+                    observation.add_bytes(backup.data_added);
+                    backup.snapshot
+                });
+                observation.result(&result);
+                self.state = State::Transferred(result);
             }
             ctx.stop(None);
         }
@@ -627,7 +1364,15 @@ mod transfer {
     #[async_trait::async_trait]
     impl BcHandler<GetActorStatusMessage> for ResticTransferActor {
         async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-            String::from("ok")
+            match &self.state {
+                State::WaitingForHoldAndBackup(..) => String::from("waiting"),
+                State::Transferring(.., progress) => match *progress.borrow() {
+                    Some(progress) => format!("transferring {}", progress),
+                    None => String::from("transferring"),
+                },
+                State::Transferred(_) => String::from("transferred"),
+                State::Faulted => String::from("faulted"),
+            }
         }
     }
 }
@@ -663,6 +1408,7 @@ mod prune {
             container: Addr<BcActor<ResticContainerActor>>, forget: ResticForget, prune: ResticPrune,
             observation: StartedObservation, log: &Logger,
         ) -> BcActor<Self> {
+            let entity_id = observation.source();
             BcActor::new(
                 Self {
                     state: State::Created(forget, prune, observation),
@@ -671,6 +1417,7 @@ mod prune {
                 },
                 log,
             )
+            .with_entity(entity_id)
         }
     }
 
@@ -776,21 +1523,3 @@ mod prune {
         }
     }
 }
-
-pub fn group_by<I, F, K, T>(xs: I, mut key_fn: F) -> HashMap<K, Vec<T>>
-where
-    I: IntoIterator<Item = T>,
-    F: FnMut(&T) -> &K,
-    K: Hash + Eq + Clone,
-{
-    let mut groups = HashMap::<K, Vec<T>>::new();
-    for item in xs {
-        let key = key_fn(&item);
-        if let Some(group) = groups.get_mut(key) {
-            group.push(item);
-        } else {
-            groups.insert(key.clone(), vec![item]);
-        }
-    }
-    groups
-}
@@ -1,54 +1,168 @@
-use crate::xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState};
-use anyhow::Result;
+use crate::{
+    oneshot,
+    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use blkcaptapp::{log_repeat_counters, set_log_level};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures_util::{FutureExt, TryFutureExt};
-use libblkcapt::runtime_dir;
-use slog::Logger;
-use tokio::{net::UnixListener, sync::oneshot, task::JoinHandle};
+use libblkcapt::{
+    api::{self, SnapshotPage, SnapshotQuery},
+    core::{
+        restic::ResticRepositoryStats,
+        system::{ServiceVersion, SystemState},
+    },
+    model::{BcLogLevel, ContainerId, DatasetId},
+    runtime_dir,
+};
+use libsystemd::activation::{self, IsType};
+use nix::unistd::dup;
+use once_cell::sync::OnceCell;
+use slog::{info, Logger};
+use std::{
+    env,
+    fmt::Write,
+    os::unix::{
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::UnixStream as StdUnixStream,
+    },
+};
+use tokio::{net::UnixListener, sync::oneshot as tokio_oneshot, task::JoinHandle};
 use tokio_stream::wrappers::UnixListenerStream;
-use warp::{Filter, Rejection};
+use warp::{http::StatusCode, Filter, Rejection};
+use xactor::Addr;
 
-use super::intel::{GetStateMessage, IntelActor};
+use super::{
+    captain::{CaptainActor, GetDatasetActorMessage},
+    dataset::TakeSnapshotMessage,
+    intel::{GetStateMessage, IntelActor, StopActorMessage},
+};
 
 pub struct ServerActor {
-    server: Option<(JoinHandle<()>, oneshot::Sender<()>)>,
+    captain: Addr<BcActor<CaptainActor>>,
+    server: Option<(JoinHandle<()>, tokio_oneshot::Sender<()>)>,
 }
 
 impl ServerActor {
-    pub fn new(log: &Logger) -> BcActor<Self> {
-        BcActor::new(Self { server: None }, log)
+    pub fn new(captain: Addr<BcActor<CaptainActor>>, log: &Logger) -> BcActor<Self> {
+        BcActor::new(Self { captain, server: None }, log)
     }
 }
 
 #[async_trait::async_trait]
 impl BcActorCtrl for ServerActor {
-    async fn started(&mut self, _ctx: BcContext<'_, Self>) -> Result<()> {
-        let (sender, receiver) = oneshot::channel::<()>();
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        let (sender, receiver) = tokio_oneshot::channel::<()>();
         let signal = receiver.map(|_| ());
 
-        let runtime_dir = runtime_dir();
-        std::fs::create_dir_all(&runtime_dir)?;
+        let listener = match activated_listener()? {
+            Some(listener) => {
+                info!(ctx.log(), "serving on the socket passed by systemd");
+                listener
+            }
+            None => {
+                let runtime_dir = runtime_dir();
+                std::fs::create_dir_all(&runtime_dir)?;
 
-        let socket_path = {
-            let mut path = runtime_dir;
-            path.push("daemon.sock");
-            path
+                // One-shot runs leave the service socket to the service, or to the systemd socket unit starting it.
+                let socket_path = runtime_dir.join(if oneshot::enabled() {
+                    "oneshot.sock"
+                } else {
+                    "daemon.sock"
+                });
+                if socket_path.exists() {
+                    if StdUnixStream::connect(&socket_path).is_ok() {
+                        bail!("{} is served by another process", socket_path.display());
+                    }
+                    std::fs::remove_file(&socket_path)?;
+                }
+                UnixListener::bind(socket_path)?
+            }
         };
-        if socket_path.exists() {
-            std::fs::remove_file(&socket_path)?;
-        }
-        let listener = UnixListener::bind(socket_path)?;
+        let captain = self.captain.clone();
         let handle = tokio::spawn(async move {
             let incoming = UnixListenerStream::new(listener);
 
-            let routes = warp::any().and_then(|| async {
-                let addr = IntelActor::addr();
-                let state = addr
-                    .call(GetStateMessage)
-                    .and_then(|fut| fut.map(Ok))
-                    .await
-                    .map_err(|_| warp::reject())?;
-                Ok::<_, Rejection>(warp::reply::json(&state))
+            let metrics = warp::path("metrics").and(warp::path::end()).and_then(|| async {
+                let state = system_state().await?;
+                let mut output = restic_metrics(&state.restic_stats);
+                output.push_str(&log_metrics());
+                Ok::<_, Rejection>(output)
             });
+            let version = warp::path("version")
+                .and(warp::path::end())
+                .and(warp::get())
+                .map(|| warp::reply::json(&ServiceVersion::current()));
+            let log_level = warp::path("log-level")
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(warp::body::bytes())
+                .map(|body: Bytes| {
+                    let level = std::str::from_utf8(&body)
+                        .ok()
+                        .and_then(|l| l.trim().parse::<BcLogLevel>().ok());
+                    match level {
+                        Some(level) => {
+                            set_log_level(level);
+                            slog_scope::info!("log level changed"; "level" => ?level);
+                            warp::reply::with_status("", StatusCode::OK)
+                        }
+                        None => warp::reply::with_status("invalid log level", StatusCode::BAD_REQUEST),
+                    }
+                });
+            let dataset_snapshots = warp::path!("datasets" / DatasetId / "snapshots")
+                .and(warp::get())
+                .and(warp::query::<SnapshotQuery>())
+                .and_then(|dataset_id, query: SnapshotQuery| async move {
+                    let page = blocking(move || api::query_dataset_snapshots(&api::load_config()?, dataset_id, &query));
+                    Ok::<_, Rejection>(snapshot_page_reply(page.await))
+                });
+            let container_snapshots = warp::path!("containers" / ContainerId / "snapshots")
+                .and(warp::get())
+                .and(warp::query::<SnapshotQuery>())
+                .and_then(|container_id, query: SnapshotQuery| async move {
+                    let page = match blocking(api::load_config).await {
+                        Ok(entities) => api::query_container_snapshots(&entities, container_id, &query).await,
+                        Err(e) => Err(e),
+                    };
+                    Ok::<_, Rejection>(snapshot_page_reply(page))
+                });
+            let stop_actor = warp::path!("actors" / u64 / "stop")
+                .and(warp::post())
+                .and_then(|actor_id| async move {
+                    let stopped = IntelActor::addr()
+                        .call(StopActorMessage(actor_id))
+                        .await
+                        .map_err(|_| warp::reject())?;
+                    Ok::<_, Rejection>(if stopped {
+                        warp::reply::with_status("", StatusCode::OK)
+                    } else {
+                        warp::reply::with_status("no such running actor", StatusCode::NOT_FOUND)
+                    })
+                });
+            let snapshot_dataset = warp::path!("datasets" / DatasetId / "snapshot")
+                .and(warp::post())
+                .and_then(move |dataset_id| {
+                    let captain = captain.clone();
+                    async move {
+                        Ok::<_, Rejection>(match take_snapshot(&captain, dataset_id).await {
+                            Ok(datetime) => warp::reply::with_status(datetime.to_rfc3339(), StatusCode::OK),
+                            Err(e) => warp::reply::with_status(format!("{:#}", e), StatusCode::INTERNAL_SERVER_ERROR),
+                        })
+                    }
+                });
+            let routes = version
+                .or(metrics)
+                .or(log_level)
+                .or(stop_actor)
+                .or(snapshot_dataset)
+                .or(dataset_snapshots)
+                .or(container_snapshots)
+                .or(warp::any().and_then(|| async {
+                    let state = system_state().await?;
+                    Ok::<_, Rejection>(warp::reply::json(&state))
+                }));
 
             warp::serve(routes)
                 .serve_incoming_with_graceful_shutdown(incoming, signal)
@@ -69,6 +183,112 @@ impl BcActorCtrl for ServerActor {
     }
 }
 
+// The socket systemd passed when the service is socket activated, kept open so a restarted server reuses it.
+static ACTIVATED_SOCKET: OnceCell<Option<RawFd>> = OnceCell::new();
+
+fn activated_listener() -> Result<Option<UnixListener>> {
+    let socket = ACTIVATED_SOCKET.get_or_try_init(|| {
+        if env::var_os("LISTEN_FDS").is_none() {
+            return Ok(None);
+        }
+        let mut descriptors = activation::receive_descriptors(true)
+            .map_err(|e| anyhow!("failed to receive the sockets passed by systemd: {}", e))?;
+        if descriptors.len() != 1 {
+            bail!("expected one socket passed by systemd, received {}", descriptors.len());
+        }
+        let descriptor = descriptors.remove(0);
+        if !descriptor.is_unix() {
+            bail!("the socket passed by systemd must be a unix socket");
+        }
+        Ok(Some(descriptor.into_raw_fd()))
+    })?;
+
+    match socket {
+        Some(fd) => {
+            let fd = dup(*fd).context("failed to duplicate the socket passed by systemd")?;
+            // Safety: the duplicated descriptor is a listening unix socket owned by nothing else.
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Some(UnixListener::from_std(listener)?))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn take_snapshot(captain: &Addr<BcActor<CaptainActor>>, dataset_id: DatasetId) -> Result<DateTime<Utc>> {
+    let dataset = captain.call(GetDatasetActorMessage(dataset_id)).await??;
+    dataset.call(TakeSnapshotMessage).await?
+}
+
+async fn system_state() -> Result<SystemState, Rejection> {
+    IntelActor::addr()
+        .call(GetStateMessage)
+        .and_then(|fut| fut.map(Ok))
+        .await
+        .map_err(|_| warp::reject())
+}
+
+// Snapshot listings are paged by the client instead of being part of the system state.
+// Runs btrfs calls and config I/O off the runtime threads serving the requests.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+fn snapshot_page_reply(page: Result<SnapshotPage>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match page {
+        Ok(page) => warp::reply::with_status(warp::reply::json(&page), StatusCode::OK),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&format!("{:#}", e)),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+fn restic_metrics(stats: &[ResticRepositoryStats]) -> String {
+    let mut output = String::new();
+    let _ = writeln!(output, "# TYPE blkcapt_restic_stored_bytes gauge");
+    for s in stats {
+        let _ = writeln!(
+            output,
+            "blkcapt_restic_stored_bytes{{container_id=\"{}\"}} {}",
+            s.container_id, s.stored_bytes
+        );
+    }
+    let _ = writeln!(output, "# TYPE blkcapt_restic_restore_bytes gauge");
+    for s in stats {
+        let _ = writeln!(
+            output,
+            "blkcapt_restic_restore_bytes{{container_id=\"{}\"}} {}",
+            s.container_id, s.restore_bytes
+        );
+    }
+    let _ = writeln!(output, "# TYPE blkcapt_restic_dataset_restore_bytes gauge");
+    for s in stats {
+        for d in s.datasets.iter() {
+            let _ = writeln!(
+                output,
+                "blkcapt_restic_dataset_restore_bytes{{container_id=\"{}\",dataset_id=\"{}\"}} {}",
+                s.container_id, d.dataset_id, d.latest_restore_bytes
+            );
+        }
+    }
+    output
+}
+
+fn log_metrics() -> String {
+    let counters = log_repeat_counters();
+    let mut output = String::new();
+    let _ = writeln!(output, "# TYPE blkcapt_log_repeats_suppressed_total counter");
+    let _ = writeln!(output, "blkcapt_log_repeats_suppressed_total {}", counters.suppressed());
+    let _ = writeln!(output, "# TYPE blkcapt_log_repeat_summaries_total counter");
+    let _ = writeln!(output, "blkcapt_log_repeat_summaries_total {}", counters.summaries());
+    output
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for ServerActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
@@ -1,18 +1,21 @@
 use super::{
     container::ContainerActor,
-    container::GetSnapshotReceiverMessage,
+    container::{GetSnapshotReceiverMessage, TrimSyncedSnapshotsMessage},
     dataset::DatasetActor,
     dataset::GetDatasetSnapshotsMessage,
-    dataset::{GetSnapshotHolderMessage, GetSnapshotSenderMessage},
-    observation::{start_observation, ObservableEventMessage, StartedObservation},
+    dataset::{
+        GetSnapshotChangedMessage, GetSnapshotDeltaSizeMessage, GetSnapshotHolderMessage, GetSnapshotSenderMessage,
+    },
+    observation::{start_observation, warn_observation, ObservableEventMessage, StartedObservation},
     restic::GetBackupMessage,
     restic::{ResticContainerActor, ResticTransferActor},
     transfer::TransferActor,
     transfer::TransferComplete,
 };
 use crate::{
-    actorbase::{unhandled_result, ScheduledMessage},
-    snapshots::{find_parent, find_ready, FindMode, GetContainerSnapshotsMessage},
+    actorbase::{run_hook, unhandled_result, ScheduledMessage},
+    oneshot,
+    snapshots::{filter_ready, find_parent, find_ready, FindMode, GetContainerSnapshotsMessage},
     xactorext::BoxBcAddr,
     xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
@@ -20,25 +23,45 @@ use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use cron::Schedule;
 use libblkcapt::{
+    core::hooks::{Hook, HookJob},
     core::{ObservableEventStage, SnapshotHandle},
     model::{
+        cursor::{load_sync_cursor, store_sync_cursor, SyncCursor, SyncFailure, TargetCursor},
         entities::{ObservableEvent, SnapshotSyncEntity, SnapshotSyncMode},
-        Entity,
+        ContainerId, DatasetId, Entity, EntityId,
     },
+    sys::btrfs::compressed_send_supported,
+};
+use slog::{debug, info, o, trace, warn, Logger};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryInto,
+    num::NonZeroUsize,
+    time::Duration,
 };
-use slog::{debug, o, trace, Logger};
-use std::{collections::VecDeque, convert::TryInto, time::Duration};
-use xactor::{message, Actor, Addr, Handler};
+use xactor::{message, Actor, Addr, Handler, Message};
+
+const TRANSFER_RETRY_DELAY: Duration = Duration::from_secs(300);
 
 pub struct SyncActor {
-    dataset: Addr<BcActor<DatasetActor>>,
-    container: SyncToContainer,
+    source: SyncSource,
     model: SnapshotSyncEntity,
+    dataset_name: String,
+    targets: Vec<SyncTarget>,
+    sync_cycle_schedule: Option<ScheduledMessage>,
+}
 
+struct SyncTarget {
+    container_id: ContainerId,
+    container: SyncToContainer,
     state_mode: SyncModeState,
     state_active_send: Option<ActiveSend>,
     last_sent: Option<DateTime<Utc>>,
-    sync_cycle_schedule: Option<ScheduledMessage>,
+    full_send_required: bool,
+    skipped: HashSet<DateTime<Utc>>,
+    last_delta_estimate: Option<u64>,
+    last_failure: Option<SyncFailure>,
+    retry_at: Option<DateTime<Utc>>,
 }
 
 struct ActiveSend {
@@ -52,6 +75,51 @@ pub enum SyncToContainer {
     Restic(Addr<BcActor<ResticContainerActor>>),
 }
 
+#[derive(Clone)]
+pub enum SyncSource {
+    Dataset(Addr<BcActor<DatasetActor>>),
+    /// A container, with the syncs that send the dataset's snapshots to it.
+    Container(Addr<BcActor<ContainerActor>>, HashSet<EntityId>),
+}
+
+impl SyncSource {
+    async fn call<M>(&self, msg: M) -> Result<M::Result>
+    where
+        M: Message,
+        BcActor<DatasetActor>: Handler<M>,
+        BcActor<ContainerActor>: Handler<M>,
+    {
+        match self {
+            SyncSource::Dataset(dataset) => dataset.call(msg).await,
+            SyncSource::Container(container, _) => container.call(msg).await,
+        }
+    }
+
+    async fn snapshots(&self, dataset_id: DatasetId) -> Result<Vec<SnapshotHandle>> {
+        match self {
+            SyncSource::Dataset(dataset) => dataset.call(GetDatasetSnapshotsMessage).await.map(|r| r.snapshots),
+            SyncSource::Container(container, _) => container
+                .call(GetContainerSnapshotsMessage {
+                    source_dataset_id: dataset_id,
+                })
+                .await
+                .map(|r| r.snapshots),
+        }
+    }
+
+    /// The events that bring new snapshots to the source. Forwarded syncs are fed by the syncs that land the
+    /// dataset's snapshots in the source container.
+    fn updating_events(&self, dataset_id: DatasetId) -> Vec<(EntityId, ObservableEvent)> {
+        match self {
+            SyncSource::Dataset(_) => vec![(dataset_id.into(), ObservableEvent::DatasetSnapshot)],
+            SyncSource::Container(_, feeders) => feeders
+                .iter()
+                .map(|feeder| (*feeder, ObservableEvent::SnapshotSync))
+                .collect(),
+        }
+    }
+}
+
 enum SyncModeState {
     LatestScheduled(VecDeque<DateTime<Utc>>),
     AllScheduled(Option<DateTime<Utc>>),
@@ -59,6 +127,13 @@ enum SyncModeState {
     LatestImmediate(VecDeque<DateTime<Utc>>, Duration),
 }
 
+// Dispatched, so a one-shot run waits for the retry.
+fn retry_cycle(addr: Addr<BcActor<SyncActor>>, index: usize) {
+    oneshot::dispatch(async move {
+        let _ = addr.call(RetrySnapshotSyncCycleMessage(index)).await;
+    });
+}
+
 fn is_immediate(mode: &SnapshotSyncMode) -> bool {
     matches!(
         mode,
@@ -66,6 +141,53 @@ fn is_immediate(mode: &SnapshotSyncMode) -> bool {
     )
 }
 
+/// Drops the oldest queued cycles beyond the limit, each is covered by a newer cycle sending the latest snapshot.
+fn coalesce_cycles(queue: &mut VecDeque<DateTime<Utc>>, pending_cycles: Option<NonZeroUsize>) -> usize {
+    let limit = pending_cycles.map_or(1, NonZeroUsize::get);
+    let excess = queue.len().saturating_sub(limit);
+    queue.drain(..excess);
+    excess
+}
+
+/// Whether an incremental transfer waits for more changes. Transfers whose delta could not be estimated never wait.
+fn defer_small_delta(min_transfer_bytes: Option<u64>, estimated_delta: Option<u64>) -> bool {
+    matches!((min_transfer_bytes, estimated_delta), (Some(minimum), Some(size)) if size < minimum)
+}
+
+/// Whether restoring a target's cursor needs the snapshots its container holds.
+fn cursor_needs_container(state_mode: &SyncModeState, saved: Option<&TargetCursor>) -> bool {
+    matches!(state_mode, SyncModeState::LatestImmediate(..)) || saved.map_or(false, |c| c.last_sent.is_some())
+}
+
+/// Restores the pending cycles of a saved cursor, dropping those the container already holds. Returns the last sent
+/// snapshot, if the container still holds it.
+fn restore_state(
+    state_mode: &mut SyncModeState, saved: TargetCursor, container_snapshots: &[SnapshotHandle],
+    pending_cycles: Option<NonZeroUsize>,
+) -> Option<DateTime<Utc>> {
+    let last_sent = saved
+        .last_sent
+        .filter(|d| container_snapshots.iter().any(|s| s.datetime == *d));
+    let last_sent = match state_mode {
+        // The interval is measured from the newest snapshot the container holds, whoever sent it.
+        SyncModeState::LatestImmediate(..) => container_snapshots.last().map(|s| s.datetime).max(last_sent),
+        _ => last_sent,
+    };
+
+    let mut pending = saved.pending;
+    pending.sort();
+    pending.retain(|limit| last_sent.map_or(true, |sent| *limit > sent));
+    match state_mode {
+        SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => {
+            queue.extend(pending);
+            coalesce_cycles(queue, pending_cycles);
+        }
+        SyncModeState::AllScheduled(limit) => *limit = pending.pop(),
+        SyncModeState::AllImmediate => {}
+    }
+    last_sent
+}
+
 fn get_schedule(mode: &SnapshotSyncMode) -> Option<Result<Schedule>> {
     match mode {
         SnapshotSyncMode::AllScheduled(model) | SnapshotSyncMode::LatestScheduled(model) => Some(model.try_into()),
@@ -75,43 +197,184 @@ fn get_schedule(mode: &SnapshotSyncMode) -> Option<Result<Schedule>> {
 
 #[message()]
 #[derive(Clone)]
-struct StartSnapshotSyncCycleMessage;
+pub struct StartSnapshotSyncCycleMessage;
+
+#[message(result = "bool")]
+pub struct GetSyncActiveMessage;
 
 #[message()]
-struct RetrySnapshotSyncCycleMessage;
+struct RetrySnapshotSyncCycleMessage(usize);
 
 impl SyncActor {
     pub fn new(
-        dataset: Addr<BcActor<DatasetActor>>, container: SyncToContainer, model: SnapshotSyncEntity, log: &Logger,
+        source: SyncSource, containers: Vec<(ContainerId, SyncToContainer)>, model: SnapshotSyncEntity,
+        dataset_name: String, log: &Logger,
     ) -> BcActor<Self> {
         let dataset_id = model.dataset_id;
-        let container_id = model.container_id;
+        let sync_id = model.sync_id();
+        let log = log.new(o!("sync_id" => sync_id.to_string(), "dataset_id" => dataset_id.to_string()));
+        let log = match model.source_container_id {
+            Some(source_container_id) => log.new(o!("source_container_id" => source_container_id.to_string())),
+            None => log,
+        };
         BcActor::new(
             Self {
-                dataset,
-                container,
-                state_mode: match model.sync_mode {
-                    SnapshotSyncMode::AllScheduled(..) => SyncModeState::AllScheduled(None),
-                    SnapshotSyncMode::LatestScheduled(..) => SyncModeState::LatestScheduled(Default::default()),
-                    SnapshotSyncMode::AllImmediate => SyncModeState::AllImmediate,
-                    SnapshotSyncMode::IntervalImmediate(interval) => {
-                        SyncModeState::LatestImmediate(Default::default(), interval)
-                    }
-                },
-                state_active_send: None,
+                source,
+                targets: containers
+                    .into_iter()
+                    .map(|(container_id, container)| SyncTarget::new(container_id, container, &model.sync_mode))
+                    .collect(),
                 sync_cycle_schedule: None,
-                last_sent: None,
                 model,
+                dataset_name,
             },
-            &log.new(o!("dataset_id" => dataset_id.to_string(), "container_id" => container_id.to_string())),
+            &log,
         )
+        .with_entity(sync_id)
     }
 
-    async fn run_cycle(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
-        let dataset_snapshots = self.get_dataset_snapshots().await?;
-        let container_snapshots = self.get_container_snapshots().await?;
+    async fn run_cycle(&mut self, target: usize, ctx: &BcContext<'_, Self>) -> Result<()> {
+        let Self {
+            source,
+            model,
+            dataset_name,
+            targets,
+            ..
+        } = self;
+        let result = targets[target]
+            .run_cycle(target, source, model, dataset_name, ctx)
+            .await;
+        if let Err(e) = &result {
+            targets[target].last_failure = Some(SyncFailure {
+                time: ctx.clock().now(),
+                message: format!("{:#}", e),
+            });
+        }
+        result
+    }
 
-        let observation = start_observation(self.model.id(), ObservableEvent::SnapshotSync).await;
+    async fn restore_cursor(&mut self, log: &Logger) {
+        let mut cursor = load_sync_cursor(self.model.sync_id()).unwrap_or_else(|e| {
+            warn!(log, "ignoring unreadable sync cursor"; "error" => %e);
+            SyncCursor::default()
+        });
+        for target in self.targets.iter_mut() {
+            let saved = cursor
+                .targets
+                .iter()
+                .position(|c| c.container_id == target.container_id)
+                .map(|i| cursor.targets.swap_remove(i));
+            if !cursor_needs_container(&target.state_mode, saved.as_ref()) {
+                target.restore(saved, &[], &self.model, log);
+                continue;
+            }
+            match target.get_container_snapshots(self.model.dataset_id).await {
+                Ok(container_snapshots) => target.restore(saved, &container_snapshots, &self.model, log),
+                Err(e) => {
+                    warn!(log, "ignoring sync cursor, the container snapshots are unavailable";
+                        "container_id" => %target.container_id, "error" => %e);
+                    target.restore(None, &[], &self.model, log);
+                }
+            }
+        }
+    }
+
+    fn save_cursor(&self, log: &Logger) {
+        let cursor = SyncCursor {
+            targets: self.targets.iter().map(SyncTarget::cursor).collect(),
+        };
+        unhandled_result(log, store_sync_cursor(self.model.sync_id(), &cursor));
+    }
+}
+
+impl SyncTarget {
+    fn new(container_id: ContainerId, container: SyncToContainer, sync_mode: &SnapshotSyncMode) -> Self {
+        Self {
+            container_id,
+            container,
+            state_mode: match sync_mode {
+                SnapshotSyncMode::AllScheduled(..) => SyncModeState::AllScheduled(None),
+                SnapshotSyncMode::LatestScheduled(..) => SyncModeState::LatestScheduled(Default::default()),
+                SnapshotSyncMode::AllImmediate => SyncModeState::AllImmediate,
+                SnapshotSyncMode::IntervalImmediate(interval) => {
+                    SyncModeState::LatestImmediate(Default::default(), *interval)
+                }
+            },
+            state_active_send: None,
+            last_sent: None,
+            full_send_required: false,
+            skipped: HashSet::new(),
+            last_delta_estimate: None,
+            last_failure: None,
+            retry_at: None,
+        }
+    }
+
+    fn cursor(&self) -> TargetCursor {
+        // A limit being sent is still pending until the transfer succeeds.
+        let active_limit = self.state_active_send.as_ref().and_then(|a| a.active_limit);
+        let pending = match &self.state_mode {
+            SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => {
+                active_limit.into_iter().chain(queue.iter().copied()).collect()
+            }
+            SyncModeState::AllScheduled(limit) => limit.iter().copied().collect(),
+            SyncModeState::AllImmediate => Vec::new(),
+        };
+        TargetCursor {
+            container_id: self.container_id,
+            last_sent: self.last_sent,
+            pending,
+            last_failure: self.last_failure.clone(),
+            retry_at: self.retry_at,
+        }
+    }
+
+    /// Restores saved progress, trusting only what the container actually holds.
+    fn restore(
+        &mut self, saved: Option<TargetCursor>, container_snapshots: &[SnapshotHandle], model: &SnapshotSyncEntity,
+        log: &Logger,
+    ) {
+        let mut saved = saved.unwrap_or_else(|| TargetCursor {
+            container_id: self.container_id,
+            last_sent: None,
+            pending: Vec::new(),
+            last_failure: None,
+            retry_at: None,
+        });
+        // The retry scheduled before a restart is not restored, pending cycles are retried once started instead.
+        self.last_failure = saved.last_failure.take();
+        if saved
+            .last_sent
+            .map_or(false, |d| !container_snapshots.iter().any(|s| s.datetime == d))
+        {
+            debug!(log, "saved last sent snapshot is no longer in the container"; "container_id" => %self.container_id);
+        }
+        self.last_sent = restore_state(&mut self.state_mode, saved, container_snapshots, model.pending_cycles);
+        if let SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) = &self.state_mode {
+            if !queue.is_empty() {
+                info!(log, "restored pending sync cycles"; "container_id" => %self.container_id, "count" => queue.len());
+            }
+        }
+    }
+
+    fn log(&self, ctx: &BcContext<'_, SyncActor>) -> Logger {
+        ctx.log().new(o!("container_id" => self.container_id.to_string()))
+    }
+
+    async fn run_cycle(
+        &mut self, index: usize, source: &SyncSource, model: &SnapshotSyncEntity, dataset_name: &str,
+        ctx: &BcContext<'_, SyncActor>,
+    ) -> Result<()> {
+        let log = self.log(ctx);
+        let mut dataset_snapshots = source.snapshots(model.dataset_id).await?;
+        self.skipped
+            .retain(|d| dataset_snapshots.iter().any(|s| s.datetime == *d));
+        dataset_snapshots.retain(|s| !self.skipped.contains(&s.datetime));
+        // The container may be listing its repository, which takes a while for restic.
+        let container_snapshots = ctx.long_await(self.get_container_snapshots(model.dataset_id)).await?;
+        filter_ready(&mut dataset_snapshots, &container_snapshots, &model.filter)?;
+
+        let observation = start_observation(model.id(), ObservableEvent::SnapshotSync).await;
         let mut active_limit = None;
         let to_send = match &mut self.state_mode {
             SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => {
@@ -143,14 +406,89 @@ impl SyncActor {
             }
             handle
         } else {
-            debug!(ctx.log(), "no snapshots ready to send");
+            debug!(log, "no snapshots ready to send");
             observation.succeeded();
             return Ok(());
         };
 
-        let parent = find_parent(to_send, &dataset_snapshots, &container_snapshots);
+        let parent = if self.full_send_required {
+            None
+        } else {
+            find_parent(to_send, &dataset_snapshots, &container_snapshots)
+        };
+        if let (true, Some(parent)) = (model.skip_unchanged, parent) {
+            match Self::snapshot_changed(source, to_send, parent).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!(log, "snapshot is unchanged from its parent, skipping"; "snapshot" => %to_send.datetime);
+                    self.skipped.insert(to_send.datetime);
+                    observation.succeeded();
+                    retry_cycle(ctx.address(), index);
+                    return Ok(());
+                }
+                Err(e) => {
+                    observation.error::<anyhow::Error, _>(&e);
+                    return Err(e);
+                }
+            }
+        }
+
+        match parent {
+            Some(parent) => {
+                self.last_delta_estimate = match Self::estimate_delta_size(source, to_send, parent).await {
+                    Ok(size) => {
+                        observation.estimated_bytes(size);
+                        Some(size)
+                    }
+                    Err(e) => {
+                        warn!(log, "failed to estimate snapshot delta size"; "error" => %e);
+                        None
+                    }
+                };
+
+                if defer_small_delta(model.min_transfer_bytes, self.last_delta_estimate) {
+                    info!(
+                        log, "snapshot delta is below the minimum transfer size, deferring";
+                        "snapshot" => %to_send.datetime, "delta_bytes" => self.last_delta_estimate,
+                        "min_transfer_bytes" => model.min_transfer_bytes
+                    );
+                    self.skipped.insert(to_send.datetime);
+                    observation.succeeded();
+                    retry_cycle(ctx.address(), index);
+                    return Ok(());
+                }
 
-        let actor = self.start_transfer_actor(to_send, parent, observation, &ctx).await?;
+                debug!(
+                    log, "sending incremental snapshot";
+                    "parent" => %parent.datetime, "estimated_delta_bytes" => ?self.last_delta_estimate
+                )
+            }
+            None => {
+                self.last_delta_estimate = None;
+                debug!(log, "no common parent found, sending full snapshot")
+            }
+        }
+
+        if let Err(e) = ctx
+            .long_await(run_hook(Hook::pre(&model.sync_hooks, model, HookJob::Sync)))
+            .await
+        {
+            observation.error::<anyhow::Error, _>(&e);
+            return Err(e);
+        }
+
+        let actor = match self
+            .start_transfer_actor(source, model, dataset_name, to_send, parent, observation, ctx, &log)
+            .await
+        {
+            Ok(actor) => actor,
+            Err(e) => {
+                // the pre-sync hook already ran, give the post-sync hook a chance to undo its work
+                let post_hook = Hook::post(&model.sync_hooks, model, HookJob::Sync, TerminalState::Failed);
+                unhandled_result(&log, ctx.long_await(run_hook(post_hook)).await);
+                return Err(e);
+            }
+        };
         self.state_active_send = Some(ActiveSend {
             actor,
             sending_snapshot: to_send.datetime,
@@ -159,53 +497,89 @@ impl SyncActor {
         Ok(())
     }
 
-    async fn get_container_snapshots(&self) -> Result<Vec<SnapshotHandle>> {
+    async fn trim_container(&self, source: &SyncSource, model: &SnapshotSyncEntity) -> Result<()> {
+        match (&self.container, model.container_retention) {
+            (SyncToContainer::Btrfs(container), Some(retention)) => {
+                let source_snapshots = source.snapshots(model.dataset_id).await?;
+                container
+                    .call(TrimSyncedSnapshotsMessage {
+                        source_dataset_id: model.dataset_id,
+                        source_snapshots: source_snapshots.into_iter().map(|s| s.datetime).collect(),
+                        retention,
+                    })
+                    .await?
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_container_snapshots(&self, dataset_id: DatasetId) -> Result<Vec<SnapshotHandle>> {
         match &self.container {
-            SyncToContainer::Btrfs(c) => self._get_container_snapshots(c).await,
-            SyncToContainer::Restic(c) => self._get_container_snapshots(c).await,
+            SyncToContainer::Btrfs(c) => Self::_get_container_snapshots(c, dataset_id).await,
+            SyncToContainer::Restic(c) => Self::_get_container_snapshots(c, dataset_id).await,
         }
     }
 
     async fn _get_container_snapshots<T: Handler<GetContainerSnapshotsMessage>>(
-        &self, addr: &Addr<T>,
+        addr: &Addr<T>, dataset_id: DatasetId,
     ) -> Result<Vec<SnapshotHandle>> {
         addr.call(GetContainerSnapshotsMessage {
-            source_dataset_id: self.model.dataset_id,
+            source_dataset_id: dataset_id,
         })
         .await
         .map(|r| r.snapshots)
     }
 
-    async fn get_dataset_snapshots(&self) -> Result<Vec<SnapshotHandle>> {
-        self.dataset.call(GetDatasetSnapshotsMessage).await.map(|r| r.snapshots)
+    async fn snapshot_changed(source: &SyncSource, snapshot: &SnapshotHandle, parent: &SnapshotHandle) -> Result<bool> {
+        source
+            .call(GetSnapshotChangedMessage {
+                snapshot_handle: snapshot.clone(),
+                parent_snapshot_handle: parent.clone(),
+            })
+            .await?
     }
 
+    async fn estimate_delta_size(
+        source: &SyncSource, snapshot: &SnapshotHandle, parent: &SnapshotHandle,
+    ) -> Result<u64> {
+        source
+            .call(GetSnapshotDeltaSizeMessage {
+                snapshot_handle: snapshot.clone(),
+                parent_snapshot_handle: Some(parent.clone()),
+            })
+            .await?
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn start_transfer_actor(
-        &self, snapshot: &SnapshotHandle, parent: Option<&SnapshotHandle>, observation: StartedObservation,
-        ctx: &BcContext<'_, Self>,
+        &self, source: &SyncSource, model: &SnapshotSyncEntity, dataset_name: &str, snapshot: &SnapshotHandle,
+        parent: Option<&SnapshotHandle>, observation: StartedObservation, ctx: &BcContext<'_, SyncActor>, log: &Logger,
     ) -> Result<BoxBcAddr> {
         match &self.container {
             SyncToContainer::Btrfs(container) => {
                 let transfer_actor = TransferActor::new(
                     ctx.address().sender::<TransferComplete>(),
                     observation,
-                    &ctx.log().new(o!("message" => ())),
+                    &log.new(o!("message" => ())),
                 );
 
                 let transfer_actor = transfer_actor.start().await?;
 
-                self.dataset
+                let compressed = model.compressed_send.unwrap_or_else(compressed_send_supported);
+                source
                     .call(GetSnapshotSenderMessage::new(
                         &transfer_actor,
                         snapshot.clone(),
                         parent.cloned(),
+                        compressed,
                     ))
                     .await??;
 
                 container
                     .call(GetSnapshotReceiverMessage::new(
                         &transfer_actor,
-                        self.model.dataset_id,
+                        model.dataset_id,
+                        dataset_name.to_owned(),
                         snapshot.clone(),
                     ))
                     .await??;
@@ -217,24 +591,26 @@ impl SyncActor {
                     ctx.address().sender::<TransferComplete>(),
                     container.clone(),
                     observation,
-                    &ctx.log().new(o!("message" => ())),
+                    &log.new(o!("message" => ())),
                 );
 
                 let transfer_actor = transfer_actor.start().await?;
 
-                self.dataset
+                source
                     .call(GetSnapshotHolderMessage::new(
                         &transfer_actor,
                         snapshot.clone(),
                         parent.cloned(),
+                        model.hold_lease,
                     ))
                     .await??;
 
                 container
                     .call(GetBackupMessage::new(
                         &transfer_actor,
-                        self.model.dataset_id,
+                        model.dataset_id,
                         snapshot.clone(),
+                        model.restic_backup.clone(),
                     ))
                     .await??;
 
@@ -249,6 +625,7 @@ impl BcActorCtrl for SyncActor {
     async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
         if is_immediate(&self.model.sync_mode) {
             ctx.subscribe::<ObservableEventMessage>().await?;
+            oneshot::expect_reactions(self.model.id(), self.source.updating_events(self.model.dataset_id));
         }
 
         self.sync_cycle_schedule = get_schedule(&self.model.sync_mode).map_or(Ok(None), |s| {
@@ -262,8 +639,11 @@ impl BcActorCtrl for SyncActor {
             })
         })?;
 
-        if matches!(self.model.sync_mode, SnapshotSyncMode::IntervalImmediate(..)) {
-            self.last_sent = self.get_container_snapshots().await?.last().map(|s| s.datetime);
+        self.restore_cursor(ctx.log()).await;
+        for (index, target) in self.targets.iter().enumerate() {
+            if !target.cursor().pending.is_empty() {
+                retry_cycle(ctx.address(), index);
+            }
         }
 
         Ok(())
@@ -274,26 +654,36 @@ impl BcActorCtrl for SyncActor {
             let _ = ctx.unsubscribe::<ObservableEventMessage>().await;
         }
 
-        if let Some(ActiveSend { mut actor, .. }) = self.state_active_send.take() {
-            let _ = actor.stop();
-            actor.wait_for_stop().await;
-            TerminalState::Cancelled
-        } else {
-            TerminalState::Succeeded
+        self.save_cursor(ctx.log());
+
+        let mut terminal_state = TerminalState::Succeeded;
+        for target in self.targets.iter_mut() {
+            if let Some(ActiveSend { mut actor, .. }) = target.state_active_send.take() {
+                let _ = actor.stop();
+                actor.wait_for_stop().await;
+                terminal_state = TerminalState::Cancelled;
+            }
         }
+        terminal_state
     }
 }
 
 #[async_trait::async_trait]
 impl BcHandler<ObservableEventMessage> for SyncActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ObservableEventMessage) {
-        if msg.source == self.model.dataset_id
-            && msg.event == ObservableEvent::DatasetSnapshot
-            && msg.stage == ObservableEventStage::Succeeded
+        if msg.stage != ObservableEventStage::Succeeded {
+            return;
+        }
+
+        if self
+            .source
+            .updating_events(self.model.dataset_id)
+            .contains(&(msg.source, msg.event))
         {
-            ctx.address()
-                .send(StartSnapshotSyncCycleMessage)
-                .expect("send to self is infalliable");
+            let addr = ctx.address();
+            oneshot::dispatch_reaction(async move {
+                let _ = addr.call(StartSnapshotSyncCycleMessage).await;
+            });
         }
     }
 }
@@ -301,58 +691,90 @@ impl BcHandler<ObservableEventMessage> for SyncActor {
 #[async_trait::async_trait]
 impl BcHandler<StartSnapshotSyncCycleMessage> for SyncActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: StartSnapshotSyncCycleMessage) {
-        let new_limit_time = Utc::now();
-        match &mut self.state_mode {
-            SyncModeState::LatestScheduled(queue) => {
-                trace!(ctx.log(), "adding sync time {} to queue", new_limit_time);
-                queue.push_back(new_limit_time);
-            }
-            SyncModeState::AllScheduled(limit) => {
-                trace!(ctx.log(), "moving limit sync forward to {}", new_limit_time);
-                limit.replace(new_limit_time);
-            }
-            SyncModeState::AllImmediate => {
-                trace!(ctx.log(), "syncing all immediately");
-            }
-            SyncModeState::LatestImmediate(queue, interval) => {
-                if self.last_sent.is_none()
-                    || new_limit_time - self.last_sent.expect("always exists, validated earlier in expr")
-                        > chrono::Duration::from_std(*interval).expect("interval always fits in chrono duration")
-                {
-                    trace!(ctx.log(), "adding sync time {} to queue", new_limit_time);
+        let new_limit_time = ctx.clock().now();
+        for index in 0..self.targets.len() {
+            let target = &mut self.targets[index];
+            let log = target.log(&ctx);
+            match &mut target.state_mode {
+                SyncModeState::LatestScheduled(queue) => {
+                    trace!(log, "adding sync time {} to queue", new_limit_time);
                     queue.push_back(new_limit_time);
-                } else {
-                    trace!(ctx.log(), "sync interval not yet elapsed");
+                    let coalesced = coalesce_cycles(queue, self.model.pending_cycles);
+                    if coalesced > 0 {
+                        debug!(log, "coalesced pending sync cycles"; "count" => coalesced);
+                    }
+                }
+                SyncModeState::AllScheduled(limit) => {
+                    trace!(log, "moving limit sync forward to {}", new_limit_time);
+                    limit.replace(new_limit_time);
+                }
+                SyncModeState::AllImmediate => {
+                    trace!(log, "syncing all immediately");
+                }
+                SyncModeState::LatestImmediate(queue, interval) => {
+                    if target.last_sent.is_none()
+                        || new_limit_time - target.last_sent.expect("always exists, validated earlier in expr")
+                            > chrono::Duration::from_std(*interval).expect("interval always fits in chrono duration")
+                    {
+                        trace!(log, "adding sync time {} to queue", new_limit_time);
+                        queue.push_back(new_limit_time);
+                        let coalesced = coalesce_cycles(queue, self.model.pending_cycles);
+                        if coalesced > 0 {
+                            debug!(log, "coalesced pending sync cycles"; "count" => coalesced);
+                        }
+                    } else {
+                        trace!(log, "sync interval not yet elapsed");
+                    }
                 }
             }
-        }
 
-        if self.state_active_send.is_some() {
-            debug!(ctx.log(), "received snapshot cycle message while in active send state");
-            return;
+            if target.state_active_send.is_some() {
+                debug!(log, "received snapshot cycle message while in active send state");
+                continue;
+            }
+
+            let result = self.run_cycle(index, &ctx).await;
+            unhandled_result(&log, result);
         }
 
-        let result = self.run_cycle(&ctx).await;
-        unhandled_result(ctx.log(), result);
+        self.save_cursor(ctx.log());
     }
 }
 
 #[async_trait::async_trait]
 impl BcHandler<TransferComplete> for SyncActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: TransferComplete) {
-        let transfer = msg.0;
+        let index = match self.targets.iter().position(|t| {
+            t.state_active_send
+                .as_ref()
+                .map_or(false, |a| a.actor.actor_id() == msg.transfer_id)
+        }) {
+            Some(index) => index,
+            None => {
+                warn!(ctx.log(), "received transfer complete for an unknown transfer");
+                return;
+            }
+        };
+
+        let transfer = msg.state;
+        let target = &mut self.targets[index];
+        let log = target.log(&ctx);
         if let Some(ActiveSend {
             sending_snapshot,
             active_limit,
             ..
-        }) = self.state_active_send.take()
+        }) = target.state_active_send.take()
         {
+            let post_hook = Hook::post(&self.model.sync_hooks, &self.model, HookJob::Sync, transfer);
+            unhandled_result(&log, ctx.long_await(run_hook(post_hook)).await);
+
             if transfer.succeeded() {
-                self.last_sent = Some(sending_snapshot);
+                target.last_sent = Some(sending_snapshot);
             } else if let Some(active_limit) = active_limit {
-                match &mut self.state_mode {
+                match &mut target.state_mode {
                     SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => {
                         queue.push_front(active_limit);
+                        coalesce_cycles(queue, self.model.pending_cycles);
                     }
                     SyncModeState::AllScheduled(_) | SyncModeState::AllImmediate => {}
                 };
@@ -360,33 +782,183 @@ impl BcHandler<TransferComplete> for SyncActor {
         }
 
         if transfer.succeeded() {
-            let result = self.run_cycle(&ctx).await;
-            unhandled_result(ctx.log(), result);
+            target.full_send_required = false;
+            target.last_failure = None;
+            unhandled_result(&log, target.trim_container(&self.source, &self.model).await);
+            let result = self.run_cycle(index, &ctx).await;
+            unhandled_result(&log, result);
+        } else if msg.missing_parent && !target.full_send_required {
+            warn!(
+                log,
+                "incremental parent is missing or differs on the dataset or the container, retrying as a full send"
+            );
+            warn_observation(
+                self.model.id(),
+                ObservableEvent::SnapshotSync,
+                format!(
+                    "incremental parent of the send to container {} is missing, retrying as a full send",
+                    target.container_id
+                ),
+            )
+            .await;
+            target.full_send_required = true;
+            let result = self.run_cycle(index, &ctx).await;
+            unhandled_result(&log, result);
         } else {
-            ctx.send_later(RetrySnapshotSyncCycleMessage, Duration::from_secs(300));
+            target.last_failure = Some(SyncFailure {
+                time: ctx.clock().now(),
+                message: format!("transfer {}", transfer),
+            });
+            target.retry_at = Some(
+                ctx.clock().now()
+                    + chrono::Duration::from_std(TRANSFER_RETRY_DELAY)
+                        .expect("retry delay always fits in chrono duration"),
+            );
+            ctx.send_later(RetrySnapshotSyncCycleMessage(index), TRANSFER_RETRY_DELAY);
         }
+
+        self.save_cursor(ctx.log());
     }
 }
 
 #[async_trait::async_trait]
 impl BcHandler<RetrySnapshotSyncCycleMessage> for SyncActor {
-    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: RetrySnapshotSyncCycleMessage) {
-        if self.state_active_send.is_some() {
-            debug!(
-                ctx.log(),
-                "received retry snapshot cycle message while in active send state"
-            );
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: RetrySnapshotSyncCycleMessage) {
+        let index = msg.0;
+        let log = self.targets[index].log(&ctx);
+        self.targets[index].retry_at = None;
+        if self.targets[index].state_active_send.is_some() {
+            debug!(log, "received retry snapshot cycle message while in active send state");
             return;
         }
 
-        let result = self.run_cycle(&ctx).await;
-        unhandled_result(ctx.log(), result);
+        let result = self.run_cycle(index, &ctx).await;
+        unhandled_result(&log, result);
+        self.save_cursor(ctx.log());
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSyncActiveMessage> for SyncActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetSyncActiveMessage) -> bool {
+        self.targets.iter().any(|t| t.state_active_send.is_some())
     }
 }
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for SyncActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("ok")
+        self.targets
+            .iter()
+            .map(|t| match t.last_delta_estimate {
+                Some(size) => format!("{}: ok, last estimated delta {} bytes", t.container_id, size),
+                None => format!("{}: ok", t.container_id),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn datetime(hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 5, 1).and_hms(hour, 0, 0)
+    }
+
+    fn handle(hour: u32) -> SnapshotHandle {
+        SnapshotHandle {
+            datetime: datetime(hour),
+            uuid: Uuid::new_v4(),
+            parent_uuid: None,
+            received_uuid: None,
+        }
+    }
+
+    fn cursor(last_sent: Option<u32>, pending: &[u32]) -> TargetCursor {
+        TargetCursor {
+            container_id: ContainerId::new(),
+            last_sent: last_sent.map(datetime),
+            pending: pending.iter().copied().map(datetime).collect(),
+            last_failure: None,
+            retry_at: None,
+        }
+    }
+
+    #[test]
+    fn defer_small_delta_only_below_known_minimum() {
+        assert!(!defer_small_delta(None, Some(10)));
+        assert!(!defer_small_delta(Some(100), None));
+        assert!(defer_small_delta(Some(100), Some(99)));
+        assert!(!defer_small_delta(Some(100), Some(100)));
+        assert!(!defer_small_delta(Some(100), Some(4096)));
+    }
+
+    #[test]
+    fn cursor_needs_container_only_to_check_last_sent() {
+        let scheduled = SyncModeState::LatestScheduled(Default::default());
+        assert!(!cursor_needs_container(&scheduled, None));
+        assert!(!cursor_needs_container(&scheduled, Some(&cursor(None, &[3]))));
+        assert!(cursor_needs_container(&scheduled, Some(&cursor(Some(2), &[]))));
+        let immediate = SyncModeState::LatestImmediate(Default::default(), Duration::from_secs(60));
+        assert!(cursor_needs_container(&immediate, None));
+    }
+
+    #[test]
+    fn restore_state_drops_cycles_already_sent() {
+        let mut state = SyncModeState::LatestScheduled(Default::default());
+        let last_sent = restore_state(&mut state, cursor(Some(2), &[3, 1, 4]), &[handle(1), handle(2)], None);
+        assert_eq!(last_sent, Some(datetime(2)));
+        match state {
+            SyncModeState::LatestScheduled(queue) => assert_eq!(queue, vec![datetime(4)]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn restore_state_forgets_last_sent_missing_from_container() {
+        let mut state = SyncModeState::AllScheduled(None);
+        let last_sent = restore_state(&mut state, cursor(Some(2), &[1, 3]), &[handle(1)], None);
+        assert_eq!(last_sent, None);
+        match state {
+            SyncModeState::AllScheduled(limit) => assert_eq!(limit, Some(datetime(3))),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn restore_state_degraded_cursor_is_empty() {
+        let mut state = SyncModeState::LatestScheduled(Default::default());
+        let last_sent = restore_state(&mut state, cursor(None, &[]), &[], None);
+        assert_eq!(last_sent, None);
+        match state {
+            SyncModeState::LatestScheduled(queue) => assert!(queue.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn coalesce_cycles_keeps_only_the_newest_by_default() {
+        let mut queue = [1, 2, 3].iter().copied().map(datetime).collect::<VecDeque<_>>();
+        assert_eq!(coalesce_cycles(&mut queue, None), 2);
+        assert_eq!(queue, [datetime(3)]);
+        assert_eq!(coalesce_cycles(&mut queue, None), 0);
+        assert_eq!(queue, [datetime(3)]);
+    }
+
+    #[test]
+    fn coalesce_cycles_keeps_pending_cycles() {
+        let mut queue = [1, 2, 3, 4].iter().copied().map(datetime).collect::<VecDeque<_>>();
+        assert_eq!(coalesce_cycles(&mut queue, NonZeroUsize::new(3)), 1);
+        assert_eq!(queue, [datetime(2), datetime(3), datetime(4)]);
+        assert_eq!(coalesce_cycles(&mut queue, NonZeroUsize::new(5)), 0);
+        assert_eq!(queue.len(), 3);
+
+        let mut queue = VecDeque::new();
+        assert_eq!(coalesce_cycles(&mut queue, None), 0);
+        assert!(queue.is_empty());
     }
 }
@@ -16,21 +16,29 @@ use crate::{
 use anyhow::Result;
 use bytes::BytesMut;
 use derive_more::From;
+use libblkcapt::sys::btrfs::MissingParentError;
 use slog::{debug, error, warn, Logger};
-use std::mem;
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use xactor::{message, Addr, Sender};
 
 pub struct TransferActor {
     requestor: Sender<TransferComplete>,
     state: State,
+    transferred: Arc<AtomicU64>,
 }
 
 #[derive(Default)]
 struct ActorCompletions {
     sender: Option<Result<()>>,
     receiver: Option<Result<()>>,
-    transfer: Option<Result<()>>,
+    transfer: Option<Result<u64>>,
 }
 
 struct Actors(
@@ -56,43 +64,51 @@ impl State {
     }
 }
 
-type TransferWorkerCompleteMessage = WorkerCompleteMessage<Result<()>>;
+type TransferWorkerCompleteMessage = WorkerCompleteMessage<Result<u64>>;
 
 impl TransferActor {
     pub fn new(parent: Sender<TransferComplete>, observation: StartedObservation, log: &Logger) -> BcActor<Self> {
+        let entity_id = observation.source();
         BcActor::new(
             Self {
                 state: State::WaitingForActors(None, None, observation),
                 requestor: parent,
+                transferred: Default::default(),
             },
             log,
         )
+        .with_entity(entity_id)
     }
 
     async fn run_transfer(
         sender_actor: Addr<BcActor<LocalSenderActor>>, receiver_actor: Addr<BcActor<LocalReceiverActor>>,
-    ) -> Result<()> {
+        progress: Arc<AtomicU64>,
+    ) -> Result<u64> {
         let mut reader = sender_actor.call(TakeReaderMessage).await??;
         let mut writer = receiver_actor.call(GetWriterMessage).await??;
 
         let mut buf = BytesMut::with_capacity(1024 * 256);
+        let mut transferred = 0;
         while let Ok(size) = reader.read_buf(&mut buf).await {
             if size == 0 {
                 break;
             }
             writer.write_all(&buf).await?;
+            transferred += size as u64;
+            progress.store(transferred, Ordering::Relaxed);
             buf.clear();
         }
 
-        Ok(())
+        Ok(transferred)
     }
 
-    fn maybe_start_transfer(incoming: State, ctx: &BcContext<'_, Self>) -> State {
+    fn maybe_start_transfer(incoming: State, progress: &Arc<AtomicU64>, ctx: &BcContext<'_, Self>) -> State {
         if let State::WaitingForActors(Some(sender), Some(receiver), observation) = incoming {
             let mv_sender = sender.clone();
             let mv_receiver = receiver.clone();
+            let progress = Arc::clone(progress);
             let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
-                Self::run_transfer(mv_sender, mv_receiver).await.into()
+                Self::run_transfer(mv_sender, mv_receiver, progress).await.into()
             });
             State::Transferring(Default::default(), Actors(task, sender, receiver), observation)
         } else {
@@ -104,11 +120,11 @@ impl TransferActor {
         self.state = match (self.state.take(), input) {
             (State::WaitingForActors(maybe_sender, None, observation), InputReady::Receiver(Ok(receiver))) => {
                 let updated_state = State::WaitingForActors(maybe_sender, Some(receiver), observation);
-                Self::maybe_start_transfer(updated_state, ctx)
+                Self::maybe_start_transfer(updated_state, &self.transferred, ctx)
             }
             (State::WaitingForActors(None, maybe_receiver, observation), InputReady::Sender(Ok(sender))) => {
                 let updated_state = State::WaitingForActors(Some(sender), maybe_receiver, observation);
-                Self::maybe_start_transfer(updated_state, ctx)
+                Self::maybe_start_transfer(updated_state, &self.transferred, ctx)
             }
             (State::WaitingForActors(_, None, observation), InputReady::Receiver(Err(e)))
             | (State::WaitingForActors(None, _, observation), InputReady::Sender(Err(e))) => {
@@ -164,7 +180,15 @@ impl TransferActor {
             log_result(ctx.log(), &transfer);
             log_result(ctx.log(), &sender);
             log_result(ctx.log(), &receiver);
-            let result = transfer.and(sender).and(receiver);
+            if let Ok(bytes) = &transfer {
+                observation.add_bytes(*bytes);
+            }
+            let transfer = transfer.map(|_| ());
+            // A missing parent on either side is what failed, the other side then only reports the broken pipe.
+            let result = match (sender, receiver) {
+                (Err(e), _) | (_, Err(e)) if MissingParentError::is_cause_of(&e) => Err(e),
+                (sender, receiver) => transfer.and(sender).and(receiver),
+            };
             ctx.stop(None);
             observation.result(&result);
             State::Transferred(result)
@@ -183,11 +207,15 @@ enum InputReady {
 enum ResultReady {
     Sender(Result<()>),
     Receiver(Result<()>),
-    Transfer(Result<()>),
+    Transfer(Result<u64>),
 }
 
 #[message()]
-pub struct TransferComplete(pub TerminalState);
+pub struct TransferComplete {
+    pub transfer_id: u64,
+    pub state: TerminalState,
+    pub missing_parent: bool,
+}
 
 #[async_trait::async_trait]
 impl BcActorCtrl for TransferActor {
@@ -196,6 +224,7 @@ impl BcActorCtrl for TransferActor {
     }
 
     async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+        let mut missing_parent = false;
         let terminal_state = match self.state.take() {
             State::Transferring(_, mut actors, observation) => {
                 warn!(ctx.log(), "cancelled during transfer");
@@ -212,14 +241,21 @@ impl BcActorCtrl for TransferActor {
                 observation.cancelled();
                 TerminalState::Cancelled
             }
-            State::Transferred(result) => result.as_ref().into(),
+            State::Transferred(result) => {
+                missing_parent = result.as_ref().err().map_or(false, MissingParentError::is_cause_of);
+                result.as_ref().into()
+            }
             State::Faulted => {
                 error!(ctx.log(), "actor faulted");
                 TerminalState::Faulted
             }
         };
 
-        let requestor_notify_result = self.requestor.send(TransferComplete(terminal_state));
+        let requestor_notify_result = self.requestor.send(TransferComplete {
+            transfer_id: ctx.actor_id(),
+            state: terminal_state,
+            missing_parent,
+        });
         if !matches!(terminal_state, TerminalState::Cancelled) {
             unhandled_result(ctx.log(), requestor_notify_result);
         }
@@ -265,6 +301,14 @@ impl BcHandler<LocalReceiverStoppedMessage> for TransferActor {
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for TransferActor {
     async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("ok")
+        match self.state {
+            State::WaitingForActors(..) => String::from("waiting"),
+            State::Transferring(..) => format!(
+                "transferring {} MiB",
+                self.transferred.load(Ordering::Relaxed) / (1024 * 1024)
+            ),
+            State::Transferred(_) => String::from("transferred"),
+            State::Faulted => String::from("faulted"),
+        }
     }
 }
@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Context, Result};
+use blkcaptapp::recent_log_records;
+use chrono::Utc;
+use libblkcapt::data_dir;
+use serde_json::json;
+use slog::{crit, error, Logger};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    fs,
+    future::Future,
+    panic,
+    path::{Path, PathBuf},
+};
+
+thread_local! {
+    static REPORTED_BY_ACTOR: Cell<bool> = Cell::new(false);
+    static PANIC_LOCATION: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Writes a crash report for an actor that panicked or faulted to the crash directory and logs its path.
+pub fn report_crash(log: &Logger, actor: &str, actor_id: u64, state: &str, error: Option<&anyhow::Error>) {
+    match write_crash_report(&data_dir().join("crash"), actor, actor_id, state, error) {
+        Ok(path) => crit!(log, "crash report written"; "path" => %path.display()),
+        Err(e) => error!(log, "writing crash report failed"; "error" => %e),
+    }
+}
+
+/// Installs a panic hook that writes a crash report for panics outside of actors. Actors catch their own panics and
+/// report them along with their status, so the hook only records where those panicked.
+pub fn install_panic_hook(log: Logger) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|l| l.to_string()).unwrap_or_default();
+        if REPORTED_BY_ACTOR.with(Cell::get) {
+            PANIC_LOCATION.with(|l| *l.borrow_mut() = Some(location));
+        } else {
+            let thread = std::thread::current();
+            let error = anyhow!("panic reason: {} at {}", panic_message(info.payload()), location);
+            report_crash(&log, thread.name().unwrap_or("unnamed"), 0, "panicked", Some(&error));
+        }
+        default_hook(info);
+    }));
+}
+
+/// Marks the polls of an actor future, whose panics the actor reports itself.
+pub fn reported_by_actor<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            REPORTED_BY_ACTOR.with(|r| r.set(self.0));
+        }
+    }
+
+    let mut future = Box::pin(future);
+    futures_util::future::poll_fn(move |cx| {
+        let _restore = Restore(REPORTED_BY_ACTOR.with(|r| r.replace(true)));
+        future.as_mut().poll(cx)
+    })
+}
+
+/// Takes the location of the last panic on this thread that was left to an actor to report.
+pub fn take_panic_location() -> Option<String> {
+    PANIC_LOCATION.with(|l| l.borrow_mut().take())
+}
+
+/// The message of a panic payload, which is a &str or String for panics raised with a message.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown")
+}
+
+fn write_crash_report(
+    crash_dir: &Path, actor: &str, actor_id: u64, state: &str, error: Option<&anyhow::Error>,
+) -> Result<PathBuf> {
+    let now = Utc::now();
+    let report = json!({
+        "time": now.to_rfc3339(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "pid": std::process::id(),
+        "actor": actor,
+        "actor_id": actor_id,
+        "state": state,
+        "errors": error.map(|e| e.chain().map(|c| c.to_string()).collect::<Vec<_>>()).unwrap_or_default(),
+        "recent_log": recent_log_records(),
+    });
+
+    fs::create_dir_all(crash_dir).context("failed to create the crash report directory")?;
+    let path = crash_dir.join(format!("{}-{}-{}.json", now.format("%Y%m%dT%H%M%S"), actor, actor_id));
+    fs::write(&path, serde_json::to_vec_pretty(&report)?)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn write_crash_report_writes_json() {
+        let crash_dir = std::env::temp_dir().join(format!("blkcapt-crash-{}", Uuid::new_v4()));
+        let error = anyhow!("inner failure").context("outer failure");
+
+        let path = write_crash_report(&crash_dir, "sync_actor", 7, "idle", Some(&error)).unwrap();
+
+        assert_eq!(path.parent(), Some(crash_dir.as_path()));
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .ends_with("-sync_actor-7.json"));
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(report["actor"], "sync_actor");
+        assert_eq!(report["actor_id"], 7);
+        assert_eq!(report["state"], "idle");
+        assert_eq!(report["pid"], std::process::id());
+        assert_eq!(report["errors"], json!(["outer failure", "inner failure"]));
+        assert!(report["recent_log"].is_array());
+
+        fs::remove_dir_all(&crash_dir).unwrap();
+    }
+
+    #[test]
+    fn write_crash_report_without_error() {
+        let crash_dir = std::env::temp_dir().join(format!("blkcapt-crash-{}", Uuid::new_v4()));
+
+        let path = write_crash_report(&crash_dir, "pool_actor", 1, "faulted", None).unwrap();
+
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(report["errors"], json!([]));
+
+        fs::remove_dir_all(&crash_dir).unwrap();
+    }
+
+    #[test]
+    fn panic_message_reads_payloads() {
+        let payload = panic::catch_unwind(|| panic!("static reason")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "static reason");
+        let payload = panic::catch_unwind(|| panic!("formatted {}", 1)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "formatted 1");
+        let payload = panic::catch_unwind(|| panic::panic_any(1)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "unknown");
+    }
+}
@@ -2,17 +2,21 @@ pub mod actors {
     pub mod captain;
     pub mod container;
     pub mod dataset;
+    pub mod group;
     pub mod intel;
     pub mod localreceiver;
     pub mod localsender;
     pub mod observation;
     pub mod pool;
+    pub mod removable;
     pub mod restic;
     pub mod server;
     pub mod sync;
     pub mod transfer;
 }
 mod actorbase;
+pub mod crash;
+pub mod oneshot;
 pub mod slogext;
 mod snapshots;
 mod tasks;
@@ -1,76 +1,255 @@
-use anyhow::Result;
-use blkcaptapp::{blkcaptapp_run, slogext::CustomFullFormat};
+use anyhow::{anyhow, bail, Context, Result};
+use blkcaptapp::{
+    blkcaptapp_run, log_level, set_log_level, set_log_level_overrides,
+    slogext::{CustomFullFormat, JsonFormat},
+};
 use blkcaptwrk::{
-    actors::{captain::CaptainActor, intel::IntelActor},
-    slogext::JournalDrain,
+    actors::{
+        captain::{CaptainActor, PingMessage},
+        intel::{DumpActorsMessage, IntelActor},
+    },
+    crash::install_panic_hook,
+    oneshot::{self, OneShotJobs},
+    slogext::{CountErrors, JournalDrain, RotatingFile},
+};
+use clap::{crate_version, Clap};
+use libblkcapt::{
+    core::run_ping_helper,
+    model::{
+        storage::{load_entity_config, load_server_config, store_entity_config},
+        BcLogFormat, BcLogLevel, LogFileConfig,
+    },
+    runtime_dir,
+    sys::{btrfs::detect_progs_version, fs::PidLock, host::machine_id, process::set_job_priorities},
 };
-use libblkcapt::model::{storage::load_server_config, BcLogLevel};
 use libsystemd::daemon::{self, NotifyState};
-use slog::{error, info, Drain, Logger};
-use std::{env, process::exit, time::Duration};
+use slog::{crit, error, info, warn, Drain, Logger, Never};
+use slog_atomic::AtomicSwitch;
+use std::{env, io, panic::RefUnwindSafe, process::exit, time::Duration};
 use tokio::signal::unix::{signal, SignalKind};
-use xactor::Actor;
+use xactor::{Actor, Addr, Handler};
+
+#[derive(Clap)]
+#[clap(version = crate_version!(), author = "rebeagle")]
+struct WorkerOptions {
+    /// Enable debug logs. Use twice to enable trace logs.
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: i32,
+    /// Take over a configuration that was created on a different machine
+    #[clap(long)]
+    adopt: bool,
+    /// Run the due jobs of a kind, or of all kinds, once and exit, for cron or systemd timers
+    #[clap(long, value_name("snapshot|sync|prune|scrub|dedup|defrag|restore-drill|all"))]
+    once: Option<OneShotJobs>,
+}
 
 fn main() {
-    let log_level = {
-        let count = std::env::args().fold(0, |a, e| {
-            a + if e.starts_with('-') && e.chars().skip(1).all(|c| c == 'v') {
-                e.len() - 1
-            } else {
-                0
-            }
-        });
-        if count > 0 {
-            count.into()
-        } else {
-            let config = load_server_config();
-            match config {
-                Ok(c) => c.log_level,
-                Err(e) => {
-                    println!("reading server config failed: {:?}", e);
-                    BcLogLevel::Info
-                }
-            }
+    if let Some(code) = run_ping_helper() {
+        exit(code);
+    }
+    let options = WorkerOptions::parse();
+    let config = match load_server_config() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("reading server config failed: {:?}", e);
+            Default::default()
         }
     };
+    let log_level = if options.verbose > 0 {
+        (options.verbose as usize).into()
+    } else {
+        config.log_level
+    };
+    set_log_level_overrides(&config.log_level_overrides);
+    set_job_priorities(config.job_priorities.clone());
 
-    let slog_drain = if use_journal() {
+    let file_drain = config.log_file.as_ref().and_then(|c| match file_drain(c) {
+        Ok(d) => Some(d),
+        Err(e) => {
+            println!("opening log file failed: {:?}", e);
+            None
+        }
+    });
+
+    let slog_drain = if config.log_format == BcLogFormat::Json {
+        let drain = JsonFormat::new(io::stderr()).fuse();
+        let drain = slog_async::Async::new(drain).build().fuse();
+        with_file_drain(drain, file_drain)
+    } else if use_journal() {
         println!("logging to journald");
         let drain = JournalDrain.fuse();
         let drain = slog_async::Async::new(drain).build().fuse();
-        slog_atomic::AtomicSwitch::new(drain)
+        with_file_drain(drain, file_drain)
     } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = CustomFullFormat::new(decorator, true).fuse();
         let drain = slog_async::Async::new(drain).build().fuse();
-        slog_atomic::AtomicSwitch::new(drain)
+        with_file_drain(drain, file_drain)
     };
 
-    exit(blkcaptapp_run(async_main, log_level, slog_drain));
+    exit(blkcaptapp_run(|log| async_main(log, options), log_level, slog_drain));
 }
 
-async fn async_main(log: Logger) -> Result<()> {
-    let mut intel = IntelActor::start_default_and_register().await?;
+async fn async_main(log: Logger, options: WorkerOptions) -> Result<()> {
+    install_panic_hook(log.clone());
+    let _instance_lock = PidLock::acquire(&runtime_dir().join("blkcaptwrk.pid"))
+        .context("failed to acquire the single instance lock")?;
+    bind_machine(&log, options.adopt)?;
+    let one_shot = options.once;
+    if let Some(jobs) = one_shot {
+        oneshot::enable(jobs)?;
+        info!(log, "running due jobs once"; "jobs" => ?jobs);
+    }
+    let (progs_major, progs_minor) = detect_progs_version()?;
+    info!(log, "btrfs-progs detected"; "version" => format!("{}.{}", progs_major, progs_minor));
+    let intel = IntelActor::start_default_and_register().await?;
     {
         let mut captain = CaptainActor::new(&log).start().await?;
         let mut sigint_stream = signal(SignalKind::interrupt())?;
         let mut sigterm_stream = signal(SignalKind::terminate())?;
+        if one_shot.is_some() {
+            let result = tokio::select! {
+                _ = oneshot::wait_idle() => oneshot::finish().map(|finished| {
+                    info!(log, "due jobs finished"; "jobs" => finished);
+                }),
+                _ = sigint_stream.recv() => Err(anyhow!("interrupted before the due jobs finished")),
+                _ = sigterm_stream.recv() => Err(anyhow!("terminated before the due jobs finished")),
+            };
+            let _ = captain.stop(None);
+            captain.wait_for_stop().await;
+            return stop_intel(intel).await.and(result);
+        }
         systemd_notify(&log, &[NotifyState::Ready]);
-        let signal = tokio::select! {
-            _ = sigint_stream.recv() => "interrupt",
-            _ = sigterm_stream.recv() => "terminate"
+        let watchdog = daemon::watchdog_enabled(false).map(|timeout| {
+            info!(log, "systemd watchdog enabled"; "timeout" => ?timeout);
+            tokio::spawn(run_watchdog(captain.clone(), timeout, log.clone()))
+        });
+        let mut sigusr1_stream = signal(SignalKind::user_defined1())?;
+        let mut sigquit_stream = signal(SignalKind::quit())?;
+        let signal = loop {
+            tokio::select! {
+                _ = sigint_stream.recv() => break "interrupt",
+                _ = sigterm_stream.recv() => break "terminate",
+                _ = sigusr1_stream.recv() => toggle_debug_logging(&log),
+                _ = sigquit_stream.recv() => {
+                    if let Err(error) = intel.send(DumpActorsMessage) {
+                        warn!(log, "actor dump failed"; "error" => %error);
+                    }
+                }
+            }
         };
         info!(log, "process {} signal received", signal);
         systemd_notify(&log, &[NotifyState::Stopping]);
+        if let Some(watchdog) = watchdog {
+            watchdog.abort();
+        }
         let _ = captain.stop(None);
         captain.wait_for_stop().await;
     }
+    stop_intel(intel).await
+}
+
+async fn stop_intel(mut intel: Addr<IntelActor>) -> Result<()> {
+    // Lets the intel actor record the final observations.
     tokio::time::sleep(Duration::from_millis(100)).await;
     intel.stop(None)?;
     intel.wait_for_stop().await;
     Ok(())
 }
 
+// A configuration cloned to another machine along with its disks would snapshot and sync the same pools twice.
+fn bind_machine(log: &Logger, adopt: bool) -> Result<()> {
+    let machine_id = match machine_id() {
+        Ok(id) => id,
+        Err(error) => {
+            warn!(log, "machine id unavailable, skipping the configuration machine check"; "error" => %error);
+            return Ok(());
+        }
+    };
+
+    let mut entities = load_entity_config()?;
+    match machine_binding(entities.machine_id.as_deref(), &machine_id, adopt) {
+        MachineBinding::Bound => return Ok(()),
+        MachineBinding::Foreign(bound) => {
+            crit!(
+                log,
+                "configuration was created on a different machine, start with --adopt to take it over";
+                "config_machine_id" => bound, "machine_id" => &machine_id
+            );
+            bail!("configuration belongs to machine {}", bound);
+        }
+        MachineBinding::Adopt(bound) => {
+            warn!(log, "adopting configuration from a different machine"; "config_machine_id" => bound, "machine_id" => &machine_id)
+        }
+        MachineBinding::Unbound => info!(log, "binding configuration to this machine"; "machine_id" => &machine_id),
+    }
+    entities.machine_id = Some(machine_id);
+    store_entity_config(entities).context("failed to bind the configuration to this machine")
+}
+
+#[derive(Debug, PartialEq)]
+enum MachineBinding<'a> {
+    Bound,
+    Unbound,
+    Adopt(&'a str),
+    Foreign(&'a str),
+}
+
+fn machine_binding<'a>(bound: Option<&'a str>, machine_id: &str, adopt: bool) -> MachineBinding<'a> {
+    match bound {
+        Some(bound) if bound == machine_id => MachineBinding::Bound,
+        Some(bound) if adopt => MachineBinding::Adopt(bound),
+        Some(bound) => MachineBinding::Foreign(bound),
+        None => MachineBinding::Unbound,
+    }
+}
+
+async fn run_watchdog<A: Handler<PingMessage>>(captain: Addr<A>, timeout: Duration, log: Logger) {
+    // Pinging at half the timeout leaves systemd a full period of slack for a single slow response.
+    let period = timeout / 2;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match tokio::time::timeout(period, captain.call(PingMessage)).await {
+            Ok(Ok(())) => systemd_notify(&log, &[NotifyState::Watchdog]),
+            Ok(Err(error)) => warn!(log, "watchdog ping failed"; "error" => %error),
+            Err(_) => warn!(log, "watchdog ping timed out, captain is unresponsive"),
+        }
+    }
+}
+
+// SIGUSR1 switches between info and debug logging.
+fn toggle_debug_logging(log: &Logger) {
+    let level = match log_level() {
+        BcLogLevel::Info => BcLogLevel::Debug,
+        _ => BcLogLevel::Info,
+    };
+    set_log_level(level);
+    info!(log, "log level changed"; "level" => ?level);
+}
+
+fn file_drain(config: &LogFileConfig) -> Result<slog::IgnoreResult<slog_async::Async>> {
+    let file = RotatingFile::open(&config.path, config.max_size, config.rotate_count)
+        .with_context(|| format!("failed to open {}", config.path.display()))?;
+    let drain = if config.json {
+        slog_async::Async::new(CountErrors::new(JsonFormat::new(file))).build()
+    } else {
+        let decorator = slog_term::PlainSyncDecorator::new(file);
+        slog_async::Async::new(CountErrors::new(CustomFullFormat::new(decorator, true))).build()
+    };
+    Ok(drain.ignore_res())
+}
+
+fn with_file_drain<D>(drain: D, file_drain: Option<slog::IgnoreResult<slog_async::Async>>) -> AtomicSwitch
+where
+    D: Drain<Ok = (), Err = Never> + Send + Sync + RefUnwindSafe + 'static,
+{
+    match file_drain {
+        Some(file_drain) => AtomicSwitch::new(slog::Duplicate::new(drain, file_drain).fuse()),
+        None => AtomicSwitch::new(drain),
+    }
+}
+
 fn systemd_notify(log: &Logger, state: &[NotifyState]) {
     if let Err(error) = daemon::notify(false, state) {
         error!(log, "failed to notify systemd"; "error" => %error);
@@ -80,3 +259,36 @@ fn systemd_notify(log: &Logger, state: &[NotifyState]) {
 fn use_journal() -> bool {
     env::var("JOURNAL_STREAM").is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_options_parse() {
+        let options = WorkerOptions::try_parse_from(&["blkcaptwrk", "-vv", "--adopt", "--once", "sync"]).unwrap();
+        assert_eq!(options.verbose, 2);
+        assert!(options.adopt);
+        assert_eq!(options.once, Some(OneShotJobs::Sync));
+
+        let options = WorkerOptions::try_parse_from(&["blkcaptwrk"]).unwrap();
+        assert_eq!(options.verbose, 0);
+        assert!(!options.adopt);
+        assert_eq!(options.once, None);
+    }
+
+    #[test]
+    fn worker_options_reject_invalid_arguments() {
+        assert!(WorkerOptions::try_parse_from(&["blkcaptwrk", "--once"]).is_err());
+        assert!(WorkerOptions::try_parse_from(&["blkcaptwrk", "--once", "weekly"]).is_err());
+        assert!(WorkerOptions::try_parse_from(&["blkcaptwrk", "--adpot"]).is_err());
+    }
+
+    #[test]
+    fn machine_binding_requires_adopt_for_foreign_config() {
+        assert_eq!(machine_binding(None, "a", false), MachineBinding::Unbound);
+        assert_eq!(machine_binding(Some("a"), "a", false), MachineBinding::Bound);
+        assert_eq!(machine_binding(Some("b"), "a", false), MachineBinding::Foreign("b"));
+        assert_eq!(machine_binding(Some("b"), "a", true), MachineBinding::Adopt("b"));
+    }
+}
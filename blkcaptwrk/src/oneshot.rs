@@ -0,0 +1,362 @@
+//! One-shot mode for running the worker from cron or a systemd timer: the scheduled jobs that came due since the
+//! previous one-shot run start right away, none are scheduled for later, and the worker exits once all jobs finished.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use libblkcapt::{
+    data_dir,
+    error::{ErrorCode, ErrorCodeExt},
+    model::{entities::ObservableEvent, EntityId},
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OneShotJobs {
+    Snapshot,
+    Sync,
+    Prune,
+    Scrub,
+    Dedup,
+    Defrag,
+    RestoreDrill,
+    /// Every scheduled job, including heartbeats.
+    All,
+}
+
+impl OneShotJobs {
+    fn includes(self, what: &str) -> bool {
+        match self {
+            OneShotJobs::Snapshot => what == "snapshot",
+            OneShotJobs::Sync => what == "sync_cycle",
+            OneShotJobs::Prune => what == "prune",
+            OneShotJobs::Scrub => what == "scrub",
+            OneShotJobs::Dedup => what == "dedup",
+            OneShotJobs::Defrag => what == "defrag",
+            OneShotJobs::RestoreDrill => what == "restore_drill",
+            OneShotJobs::All => true,
+        }
+    }
+}
+
+impl FromStr for OneShotJobs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(OneShotJobs::Snapshot),
+            "sync" => Ok(OneShotJobs::Sync),
+            "prune" => Ok(OneShotJobs::Prune),
+            "scrub" => Ok(OneShotJobs::Scrub),
+            "dedup" => Ok(OneShotJobs::Dedup),
+            "defrag" => Ok(OneShotJobs::Defrag),
+            "restore-drill" => Ok(OneShotJobs::RestoreDrill),
+            "all" => Ok(OneShotJobs::All),
+            _ => Err(anyhow!(
+                "--once takes snapshot, sync, prune, scrub, dedup, defrag, restore-drill or all, not {}",
+                s
+            )),
+        }
+    }
+}
+
+struct OneShot {
+    jobs: OneShotJobs,
+    started: DateTime<Utc>,
+    // The last run of each scheduled job, by its run key.
+    last_runs: BTreeMap<String, DateTime<Utc>>,
+    // The run keys of the scheduled jobs this run evaluated, due or not.
+    evaluated: Mutex<BTreeSet<String>>,
+    // The run keys of the scheduled jobs that failed, which stay due for the next run.
+    failed: Mutex<BTreeSet<String>>,
+    // The events each reacting actor starts a job for when they succeed, by the id of the actor's entity.
+    reactors: Mutex<HashMap<EntityId, Vec<(EntityId, ObservableEvent)>>>,
+}
+
+impl OneShot {
+    fn due(&self, what: &str, entity: Option<EntityId>, schedule: &Schedule) -> bool {
+        if !self.jobs.includes(what) {
+            return false;
+        }
+        let key = run_key(what, entity);
+        let last_run = self.last_runs.get(&key);
+        self.evaluated
+            .lock()
+            .expect("evaluated lock is never poisoned")
+            .insert(key);
+        match last_run {
+            Some(last_run) => schedule
+                .after(last_run)
+                .next()
+                .map_or(false, |next| next <= self.started),
+            None => true,
+        }
+    }
+
+    fn updated_last_runs(&self) -> BTreeMap<String, DateTime<Utc>> {
+        let failed = self.failed.lock().expect("failed lock is never poisoned");
+        let mut last_runs = self.last_runs.clone();
+        for key in self
+            .evaluated
+            .lock()
+            .expect("evaluated lock is never poisoned")
+            .iter()
+            .filter(|key| !failed.contains(*key))
+        {
+            last_runs.insert(key.clone(), self.started);
+        }
+        last_runs
+    }
+}
+
+// Identifies a scheduled job across runs, so each entity keeps its own last run.
+fn run_key(what: &str, entity: Option<EntityId>) -> String {
+    match entity {
+        Some(entity) => format!("{}/{}", what, entity),
+        None => what.to_owned(),
+    }
+}
+
+// The kind of scheduled job an observed event belongs to, none for events no schedule starts.
+fn job_kind(event: ObservableEvent) -> Option<&'static str> {
+    match event {
+        ObservableEvent::DatasetSnapshot | ObservableEvent::DatasetGroupSnapshot => Some("snapshot"),
+        ObservableEvent::DatasetPrune | ObservableEvent::ContainerPrune => Some("prune"),
+        ObservableEvent::DatasetDefrag => Some("defrag"),
+        ObservableEvent::SnapshotSync => Some("sync_cycle"),
+        ObservableEvent::PoolScrub => Some("scrub"),
+        ObservableEvent::PoolDedup => Some("dedup"),
+        ObservableEvent::ContainerRestoreDrill => Some("restore_drill"),
+        _ => None,
+    }
+}
+
+static ONE_SHOT: OnceCell<OneShot> = OnceCell::new();
+static STARTUP: OnceCell<Notify> = OnceCell::new();
+// Due jobs whose message handler has not returned yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_OBSERVATIONS: AtomicUsize = AtomicUsize::new(0);
+static FINISHED_OBSERVATIONS: AtomicUsize = AtomicUsize::new(0);
+static FAILED_OBSERVATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn last_runs_path() -> PathBuf {
+    data_dir().join("oneshot.json")
+}
+
+/// Switches scheduling to one-shot mode, must be called before any actor starts.
+pub fn enable(jobs: OneShotJobs) -> Result<()> {
+    let last_runs = match fs::read(last_runs_path()) {
+        Ok(data) => serde_json::from_slice(&data).context("failed to parse the one-shot run times")?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e).context("failed to read the one-shot run times"),
+    };
+    ONE_SHOT
+        .set(OneShot {
+            jobs,
+            started: Utc::now(),
+            last_runs,
+            evaluated: Mutex::new(BTreeSet::new()),
+            failed: Mutex::new(BTreeSet::new()),
+            reactors: Mutex::new(HashMap::new()),
+        })
+        .map_err(|_| anyhow!("one-shot mode is already enabled"))
+}
+
+pub fn enabled() -> bool {
+    ONE_SHOT.get().is_some()
+}
+
+/// Whether a scheduled job of an entity runs in this one-shot run, none outside of one-shot mode. A job is due when
+/// its schedule fired since the previous run that evaluated it, or it never ran.
+pub(crate) fn due(what: &str, entity: Option<EntityId>, schedule: &Schedule) -> Option<bool> {
+    ONE_SHOT.get().map(|one_shot| one_shot.due(what, entity, schedule))
+}
+
+/// Runs a due job, counting it as pending until its handler returned.
+pub(crate) fn dispatch<F: std::future::Future<Output = ()> + Send + 'static>(job: F) {
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(async move {
+        job.await;
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Registers the events an actor starts a job in reaction to, replacing those of a previous start of the actor. A
+/// reaction counts as pending from when the event succeeded until the actor dispatched its job.
+pub(crate) fn expect_reactions(reactor: EntityId, events: Vec<(EntityId, ObservableEvent)>) {
+    if let Some(one_shot) = ONE_SHOT.get() {
+        one_shot
+            .reactors
+            .lock()
+            .expect("reactors lock is never poisoned")
+            .insert(reactor, events);
+    }
+}
+
+/// Runs a job started in reaction to an event registered with `expect_reactions`.
+pub(crate) fn dispatch_reaction<F: std::future::Future<Output = ()> + Send + 'static>(job: F) {
+    dispatch(job);
+    if ONE_SHOT.get().is_some() {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn observation_started() {
+    ACTIVE_OBSERVATIONS.fetch_add(1, Ordering::SeqCst);
+}
+
+pub(crate) fn observation_finished(source: EntityId, event: ObservableEvent, failed: bool) {
+    if let Some(one_shot) = ONE_SHOT.get() {
+        if failed {
+            if let Some(kind) = job_kind(event) {
+                one_shot
+                    .failed
+                    .lock()
+                    .expect("failed lock is never poisoned")
+                    .insert(run_key(kind, Some(source)));
+            }
+        } else {
+            let reactions = one_shot
+                .reactors
+                .lock()
+                .expect("reactors lock is never poisoned")
+                .values()
+                .filter(|events| events.contains(&(source, event)))
+                .count();
+            PENDING.fetch_add(reactions, Ordering::SeqCst);
+        }
+    }
+    FINISHED_OBSERVATIONS.fetch_add(1, Ordering::SeqCst);
+    if failed {
+        FAILED_OBSERVATIONS.fetch_add(1, Ordering::SeqCst);
+    }
+    ACTIVE_OBSERVATIONS.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Marks the actors as started, the due jobs they dispatched while starting are pending from then on.
+pub fn startup_finished() {
+    STARTUP.get_or_init(Notify::new).notify_one();
+}
+
+/// Waits for the startup to finish, then until neither a due job nor any observed job runs.
+pub async fn wait_idle() {
+    STARTUP.get_or_init(Notify::new).notified().await;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if PENDING.load(Ordering::SeqCst) == 0 && ACTIVE_OBSERVATIONS.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+    }
+}
+
+/// Records the run so the same schedule firings are not due again, except for the jobs that failed. Fails
+/// when any job failed, with the number of finished jobs otherwise.
+pub fn finish() -> Result<usize> {
+    let one_shot = ONE_SHOT.get().expect("one-shot mode is enabled before finishing");
+    let last_runs = one_shot.updated_last_runs();
+    let path = last_runs_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, serde_json::to_vec(&last_runs)?).context("failed to store the one-shot run times")?;
+
+    let finished = FINISHED_OBSERVATIONS.load(Ordering::SeqCst);
+    match FAILED_OBSERVATIONS.load(Ordering::SeqCst) {
+        0 => Ok(finished),
+        failed => Err(anyhow!("{} of {} jobs failed", failed, finished)).code(ErrorCode::JobFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn one_shot(jobs: OneShotJobs, last_runs: &[(&str, DateTime<Utc>)]) -> OneShot {
+        OneShot {
+            jobs,
+            started: Utc.ymd(2021, 5, 2).and_hms(12, 0, 0),
+            last_runs: last_runs.iter().map(|(what, time)| (what.to_string(), *time)).collect(),
+            evaluated: Mutex::new(BTreeSet::new()),
+            failed: Mutex::new(BTreeSet::new()),
+            reactors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn jobs_parse_and_include() {
+        assert_eq!("sync".parse::<OneShotJobs>().unwrap(), OneShotJobs::Sync);
+        assert_eq!("all".parse::<OneShotJobs>().unwrap(), OneShotJobs::All);
+        assert_eq!(
+            "restore-drill".parse::<OneShotJobs>().unwrap(),
+            OneShotJobs::RestoreDrill
+        );
+        assert!("heartbeat".parse::<OneShotJobs>().is_err());
+
+        assert!(OneShotJobs::Sync.includes("sync_cycle"));
+        assert!(!OneShotJobs::Sync.includes("snapshot"));
+        assert!(OneShotJobs::Snapshot.includes("snapshot"));
+        assert!(!OneShotJobs::Prune.includes("scrub"));
+        assert!(OneShotJobs::RestoreDrill.includes("restore_drill"));
+        assert!(OneShotJobs::All.includes("scrub"));
+    }
+
+    #[test]
+    fn due_since_last_run() {
+        let hourly = Schedule::from_str("0 0 * * * *").unwrap();
+        let one_shot = one_shot(
+            OneShotJobs::All,
+            &[
+                ("snapshot", Utc.ymd(2021, 5, 2).and_hms(10, 30, 0)),
+                ("prune", Utc.ymd(2021, 5, 2).and_hms(11, 30, 0)),
+            ],
+        );
+        assert!(one_shot.due("snapshot", None, &hourly));
+        assert!(!one_shot.due("prune", None, &hourly));
+        assert!(one_shot.due("scrub", None, &hourly));
+
+        let snapshots_only = self::one_shot(OneShotJobs::Snapshot, &[]);
+        assert!(!snapshots_only.due("prune", None, &hourly));
+        assert!(snapshots_only.evaluated.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_jobs_stay_due_per_entity() {
+        let hourly = Schedule::from_str("0 0 * * * *").unwrap();
+        let earlier = Utc.ymd(2021, 5, 2).and_hms(10, 30, 0);
+        let entity = || uuid::Uuid::new_v4().to_string().parse::<EntityId>().unwrap();
+        let (home, media, offline) = (entity(), entity(), entity());
+        let keys = [
+            run_key("snapshot", Some(home)),
+            run_key("snapshot", Some(media)),
+            run_key("snapshot", Some(offline)),
+        ];
+        let one_shot = OneShot {
+            last_runs: keys.iter().map(|key| (key.clone(), earlier)).collect(),
+            ..one_shot(OneShotJobs::All, &[])
+        };
+        assert!(one_shot.due("snapshot", Some(home), &hourly));
+        assert!(one_shot.due("snapshot", Some(media), &hourly));
+        one_shot.failed.lock().unwrap().insert(run_key("snapshot", Some(media)));
+
+        let last_runs = one_shot.updated_last_runs();
+        assert_eq!(last_runs[&keys[0]], one_shot.started);
+        assert_eq!(last_runs[&keys[1]], earlier);
+        assert_eq!(last_runs[&keys[2]], earlier);
+        assert_eq!(job_kind(ObservableEvent::ContainerPrune), Some("prune"));
+        assert_eq!(job_kind(ObservableEvent::PoolSpace), None);
+    }
+}
@@ -1,7 +1,13 @@
-use std::fmt;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use libsystemd::logging::{journal_send, Priority};
-use slog::{Drain, Key, OwnedKVList, Record, Serializer, KV};
+use slog::{Drain, Key, Never, OwnedKVList, Record, Serializer, KV};
 pub struct JournalDrain;
 
 impl Drain for JournalDrain {
@@ -63,3 +69,179 @@ impl JournalSerializer {
         }
     }
 }
+
+/// Drops the records a drain fails to write instead of panicking, reporting the failures on stderr. A full disk
+/// must not take down the daemon with its log file.
+pub struct CountErrors<D> {
+    drain: D,
+    errors: AtomicU64,
+}
+
+impl<D> CountErrors<D> {
+    pub fn new(drain: D) -> Self {
+        Self {
+            drain,
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+impl<D: Drain> Drain for CountErrors<D>
+where
+    D::Err: fmt::Display,
+{
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+        if let Err(error) = self.drain.log(record, values) {
+            let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+            // Reporting every failure would flood stderr while the disk stays full.
+            if errors.is_power_of_two() {
+                eprintln!("failed to write log file, {} records dropped: {}", errors, error);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A log file that is rotated to `<path>.1` up to `<path>.<keep>` once it would exceed its maximum size.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = Self::open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn open_append(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = Self::open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, Logger};
+    use uuid::Uuid;
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("blkcapt-rotating-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn rotating_file_rotates_and_keeps() {
+        let dir = test_dir();
+        let path = dir.join("worker.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("worker.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("worker.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("worker.log.3").exists());
+
+        // Reopening continues with the size already written.
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_all(b"fifth\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fifth\n");
+        assert_eq!(fs::read_to_string(dir.join("worker.log.1")).unwrap(), "fourth\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_file_truncates_without_keep() {
+        let dir = test_dir();
+        let path = dir.join("worker.log");
+        let mut file = RotatingFile::open(&path, 10, 0).unwrap();
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert!(!dir.join("worker.log.1").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct FailingDrain;
+
+    impl Drain for FailingDrain {
+        type Ok = ();
+        type Err = io::Error;
+
+        fn log(&self, _: &Record, _: &OwnedKVList) -> Result<(), io::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "no space left on device"))
+        }
+    }
+
+    #[test]
+    fn count_errors_drops_failed_records() {
+        let drain = std::sync::Arc::new(CountErrors::new(FailingDrain));
+        let log = Logger::root(std::sync::Arc::clone(&drain), o!());
+        slog::info!(log, "first");
+        slog::info!(log, "second");
+        assert_eq!(drain.errors(), 2);
+    }
+}
@@ -1,18 +1,23 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
+use cron::Schedule;
 use libblkcapt::{
     core::{
+        batch_delete_snapshots,
         retention::{evaluate_retention, RetentionEvaluation},
         BtrfsSnapshot, Snapshot, SnapshotHandle,
     },
-    model::{entities::RetentionRuleset, EntityId},
+    model::{
+        entities::{RetentionRuleset, SnapshotSyncFilter},
+        storage, DatasetId,
+    },
 };
 use slog::{debug, info, trace, Logger};
-use std::collections::HashSet;
+use std::{collections::HashSet, convert::TryFrom, num::NonZeroU32};
 use uuid::Uuid;
 use xactor::message;
 
-use crate::actorbase::log_result;
+use crate::actorbase::{log_result, logged_result};
 
 pub fn find_ready<'a>(
     dataset_snapshots: &'a [SnapshotHandle], container_snapshots: &[SnapshotHandle], find_mode: FindMode,
@@ -55,34 +60,96 @@ pub enum FindMode {
 pub fn find_parent<'a>(
     child_snapshot: &SnapshotHandle, dataset_snapshots: &'a [SnapshotHandle], container_snapshots: &[SnapshotHandle],
 ) -> Option<&'a SnapshotHandle> {
-    if dataset_snapshots.is_empty() {
-        return None;
-    }
+    // A dataset snapshot can only be used as a parent if the container holds a copy of it. The container copy is
+    // identified by its received uuid, which is either the dataset snapshot's own uuid or, if the dataset snapshot
+    // was itself received (e.g. restored from a container), the uuid it was originally received from.
+    let received = container_snapshots
+        .iter()
+        .filter(|s| s.datetime < child_snapshot.datetime)
+        .filter_map(|s| s.received_uuid)
+        .collect::<HashSet<_>>();
 
-    if container_snapshots.is_empty() {
-        return None;
+    let common = dataset_snapshots
+        .iter()
+        .rev()
+        .filter(|s| s.datetime < child_snapshot.datetime)
+        .filter(|s| received.contains(&s.uuid) || s.received_uuid.map_or(false, |u| received.contains(&u)))
+        .collect::<Vec<_>>();
+
+    // Prefer ancestors from the same lineage as the child, then restore points the lineage may have started from.
+    // Snapshots taken before a dataset was restored are still valid parents, but produce a much larger delta.
+    common
+        .iter()
+        .find(|s| s.parent_uuid.is_some() && s.parent_uuid == child_snapshot.parent_uuid)
+        .or_else(|| common.iter().find(|s| s.received_uuid.is_some()))
+        .or_else(|| common.first())
+        .copied()
+}
+
+pub fn filter_ready(
+    dataset_snapshots: &mut Vec<SnapshotHandle>, container_snapshots: &[SnapshotHandle], filter: &SnapshotSyncFilter,
+) -> Result<()> {
+    let schedule = filter.schedule.as_ref().map(Schedule::try_from).transpose()?;
+    let min_spacing = filter
+        .min_spacing
+        .map(|d| chrono::Duration::from_std(d).expect("spacing always fits in chrono duration"));
+    if schedule.is_none() && min_spacing.is_none() {
+        return Ok(());
     }
 
-    // logic needs to handle walking source snapshots for restore chains.
+    let mut previous: Option<DateTime<Utc>> = None;
+    let mut last_kept: Option<DateTime<Utc>> = None;
+    dataset_snapshots.retain(|s| {
+        let on_container = container_snapshots.iter().any(|c| c.datetime == s.datetime);
+        let tagged = match (&schedule, previous) {
+            (Some(schedule), Some(previous)) => schedule.after(&previous).next().map_or(false, |t| t <= s.datetime),
+            _ => true,
+        };
+        let spaced = match min_spacing {
+            Some(min_spacing) => container_snapshots
+                .iter()
+                .map(|c| c.datetime)
+                .filter(|d| *d < s.datetime)
+                .max()
+                .max(last_kept)
+                .map_or(true, |anchor| s.datetime - anchor >= min_spacing),
+            None => true,
+        };
 
-    let eligbile_source = dataset_snapshots
-        .iter()
-        .map(|s| s.datetime)
-        .filter(|d| d < &child_snapshot.datetime)
-        .collect::<HashSet<_>>();
-    let eligbile_destination = container_snapshots
-        .iter()
-        .map(|s| s.datetime)
-        .filter(|d| d < &child_snapshot.datetime)
-        .collect::<HashSet<_>>();
-    let eligbile = eligbile_source.intersection(&eligbile_destination).last();
-    eligbile.and_then(|d| dataset_snapshots.iter().find(|s| &s.datetime == d))
+        let keep = on_container || (tagged && spaced);
+        previous = Some(s.datetime);
+        if keep {
+            last_kept = Some(s.datetime);
+        }
+        keep
+    });
+    Ok(())
 }
 
 #[message()]
 #[derive(Clone)]
 pub struct PruneMessage;
 
+#[message()]
+#[derive(Clone)]
+pub struct RestoreDrillMessage;
+
+/// Prunes down to the newest snapshots, ignoring the configured retention. Sent when a pool is critically low on
+/// free space.
+#[message(result = "Result<()>")]
+#[derive(Clone)]
+pub struct EmergencyPruneMessage(pub NonZeroU32);
+
+impl EmergencyPruneMessage {
+    pub fn ruleset(&self) -> RetentionRuleset {
+        RetentionRuleset {
+            interval: Vec::new(),
+            newest_count: self.0,
+            ..RetentionRuleset::default()
+        }
+    }
+}
+
 pub fn log_evaluation<T: Snapshot>(evaluation: &RetentionEvaluation<T>, log: &Logger) {
     for snapshot in evaluation.keep_interval_buckets.iter().flat_map(|b| b.snapshots.iter()) {
         trace!(log, "Keeping snapshot {} reason: in retention interval.", snapshot);
@@ -101,16 +168,30 @@ pub fn log_evaluation<T: Snapshot>(evaluation: &RetentionEvaluation<T>, log: &Lo
 }
 
 pub fn delete_snapshots<T: BtrfsSnapshot>(snapshots: &[&T], log: &Logger) -> HashSet<DateTime<Utc>> {
+    let config = logged_result(
+        log,
+        storage::load_server_config().context("failed to load the snapshot delete config"),
+    )
+    .map(|c| c.snapshot_delete)
+    .unwrap_or_default();
     snapshots
         .iter()
-        .filter_map(|s| {
-            let result = s.delete();
+        .zip(batch_delete_snapshots(snapshots, &config))
+        .filter_map(|(s, result)| {
             log_result(log, &result);
             result.map(|_| s.datetime()).ok()
         })
         .collect()
 }
 
+/// Lists snapshots on first use rather than when an actor is created, so a worker with many snapshots starts quickly.
+pub fn loaded_snapshots<T>(snapshots: &mut Option<T>, load: impl FnOnce() -> Result<T>) -> Result<&mut T> {
+    if snapshots.is_none() {
+        *snapshots = Some(load()?);
+    }
+    Ok(snapshots.as_mut().expect("snapshots were just loaded"))
+}
+
 pub fn clear_deleted<T: Snapshot>(snapshots: &mut Vec<T>, deleted: HashSet<DateTime<Utc>>) {
     snapshots.retain(|s| !deleted.contains(&s.datetime()));
 }
@@ -146,9 +227,142 @@ pub fn failed_snapshot_deletes_as_result(failed_count: usize) -> Result<()> {
 
 #[message(result = "ContainerSnapshotsResponse")]
 pub struct GetContainerSnapshotsMessage {
-    pub source_dataset_id: EntityId,
+    pub source_dataset_id: DatasetId,
 }
 
 pub struct ContainerSnapshotsResponse {
     pub snapshots: Vec<SnapshotHandle>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+
+    fn handle(hour: u32, parent_uuid: Option<Uuid>) -> SnapshotHandle {
+        SnapshotHandle {
+            datetime: Utc.ymd(2021, 5, 1).and_hms(hour, 0, 0),
+            uuid: Uuid::new_v4(),
+            parent_uuid,
+            received_uuid: None,
+        }
+    }
+
+    fn received(source: &SnapshotHandle) -> SnapshotHandle {
+        SnapshotHandle {
+            datetime: source.datetime,
+            uuid: Uuid::new_v4(),
+            parent_uuid: None,
+            received_uuid: Some(source.received_uuid.unwrap_or(source.uuid)),
+        }
+    }
+
+    #[test]
+    fn find_parent_requires_container_copy() {
+        let lineage = Some(Uuid::new_v4());
+        let dataset = vec![handle(1, lineage), handle(2, lineage), handle(3, lineage)];
+
+        assert!(find_parent(&dataset[2], &dataset, &[]).is_none());
+        let container = vec![received(&dataset[0]), received(&dataset[1])];
+        assert_eq!(
+            find_parent(&dataset[2], &dataset, &container).unwrap().uuid,
+            dataset[1].uuid
+        );
+        // Copies of the child itself or newer snapshots are no parents.
+        assert_eq!(
+            find_parent(&dataset[1], &dataset, &container).unwrap().uuid,
+            dataset[0].uuid
+        );
+        let container = vec![received(&dataset[2])];
+        assert!(find_parent(&dataset[2], &dataset, &container).is_none());
+    }
+
+    #[test]
+    fn find_parent_matches_received_dataset_snapshots() {
+        // The dataset was restored from a container, its snapshot was received from the original snapshot.
+        let original = handle(1, None);
+        let restored = SnapshotHandle {
+            received_uuid: Some(original.uuid),
+            ..handle(1, None)
+        };
+        let child = handle(2, Some(Uuid::new_v4()));
+        let dataset = vec![restored.clone(), child.clone()];
+
+        let container = vec![received(&original)];
+        assert_eq!(find_parent(&child, &dataset, &container).unwrap().uuid, restored.uuid);
+    }
+
+    #[test]
+    fn find_parent_prefers_lineage_then_restore_points() {
+        let old_lineage = Some(Uuid::new_v4());
+        let new_lineage = Some(Uuid::new_v4());
+        let old = handle(1, old_lineage);
+        let restore_point = SnapshotHandle {
+            received_uuid: Some(Uuid::new_v4()),
+            ..handle(2, None)
+        };
+        let same = handle(3, new_lineage);
+        let newer_old = handle(4, old_lineage);
+        let child = handle(5, new_lineage);
+        let dataset = vec![
+            old.clone(),
+            restore_point.clone(),
+            same.clone(),
+            newer_old.clone(),
+            child.clone(),
+        ];
+
+        let all = dataset.iter().take(4).map(received).collect::<Vec<_>>();
+        assert_eq!(find_parent(&child, &dataset, &all).unwrap().uuid, same.uuid);
+        let without_lineage = vec![received(&old), received(&restore_point), received(&newer_old)];
+        assert_eq!(
+            find_parent(&child, &dataset, &without_lineage).unwrap().uuid,
+            restore_point.uuid
+        );
+        let only_old = vec![received(&old), received(&newer_old)];
+        assert_eq!(find_parent(&child, &dataset, &only_old).unwrap().uuid, newer_old.uuid);
+    }
+
+    fn hours(snapshots: &[SnapshotHandle]) -> Vec<u32> {
+        snapshots.iter().map(|s| s.datetime.hour()).collect()
+    }
+
+    fn filtered(container: &[u32], filter: &SnapshotSyncFilter) -> Vec<u32> {
+        let mut dataset = (1..=8).map(|h| handle(h, None)).collect::<Vec<_>>();
+        let container = container
+            .iter()
+            .map(|h| received(&dataset[*h as usize - 1]))
+            .collect::<Vec<_>>();
+        filter_ready(&mut dataset, &container, filter).unwrap();
+        hours(&dataset)
+    }
+
+    #[test]
+    fn filter_ready_without_filters_keeps_all() {
+        assert_eq!(
+            filtered(&[], &SnapshotSyncFilter::default()),
+            (1..=8).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn filter_ready_keeps_first_snapshot_after_each_firing() {
+        let filter = SnapshotSyncFilter {
+            schedule: Some("0 0 0/6 * * *".parse().unwrap()),
+            min_spacing: None,
+        };
+        assert_eq!(filtered(&[], &filter), [1, 6]);
+        // Snapshots already on the container are never filtered out.
+        assert_eq!(filtered(&[3], &filter), [1, 3, 6]);
+    }
+
+    #[test]
+    fn filter_ready_spaces_from_kept_and_container_snapshots() {
+        let filter = SnapshotSyncFilter {
+            schedule: None,
+            min_spacing: Some(std::time::Duration::from_secs(3 * 60 * 60)),
+        };
+        assert_eq!(filtered(&[], &filter), [1, 4, 7]);
+        assert_eq!(filtered(&[2], &filter), [1, 2, 5, 8]);
+    }
+}
@@ -1,13 +1,27 @@
 use crate::{
     actorbase::unhandled_result,
     actors::intel::{ActorDropMessage, ActorStartMessage, ActorStopMessage, IntelActor},
+    crash::{panic_message, report_crash, reported_by_actor, take_panic_location},
 };
 use anyhow::{anyhow, Context as _, Result};
 use futures_util::future::{join_all, FutureExt};
 use heck::SnakeCase;
+use libblkcapt::{
+    core::clock::{Clock, SystemClock},
+    model::EntityId,
+};
 use paste::paste;
 use slog::{crit, error, o, trace, Logger};
-use std::{future::Future, marker::PhantomData, panic::AssertUnwindSafe, time::Duration};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use strum_macros::Display;
 use xactor::{message, Actor, Addr, Context, Handler, Message, WeakAddr};
 
@@ -106,6 +120,46 @@ pub struct BcActor<T> {
     inner: T,
     actor_id: u64,
     log: Logger,
+    activity: Arc<ActorActivity>,
+    clock: Arc<dyn Clock>,
+    entity_id: Option<EntityId>,
+}
+
+/// Message handling activity of an actor, readable while the actor is busy.
+#[derive(Default)]
+pub struct ActorActivity {
+    started: AtomicU64,
+    handling: Mutex<Option<(String, Instant)>>,
+    long_awaits: AtomicUsize,
+}
+
+impl ActorActivity {
+    /// Messages the actor started handling so far.
+    pub fn started(&self) -> u64 {
+        self.started.load(Ordering::Relaxed)
+    }
+
+    /// The type of the message being handled and how long ago handling started.
+    pub fn handling(&self) -> Option<(String, Duration)> {
+        let handling = self.handling.lock().expect("activity lock is never poisoned");
+        handling
+            .as_ref()
+            .map(|(message, since)| (message.clone(), since.elapsed()))
+    }
+
+    /// Whether the handler is in a wait declared with [`BcContext::long_await`].
+    pub fn in_long_await(&self) -> bool {
+        self.long_awaits.load(Ordering::Relaxed) > 0
+    }
+
+    fn start(&self, message: String) {
+        *self.handling.lock().expect("activity lock is never poisoned") = Some((message, Instant::now()));
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn finish(&self) {
+        *self.handling.lock().expect("activity lock is never poisoned") = None;
+    }
 }
 
 // Replace with specialization when available?
@@ -130,16 +184,52 @@ impl<T> BcActor<T> {
             inner,
             actor_id: 0,
             log,
+            activity: Default::default(),
+            clock: Arc::new(SystemClock),
+            entity_id: None,
         }
     }
 
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reports the actor as working for the entity, to group it in the service status.
+    pub fn with_entity<I: Into<EntityId>>(mut self, entity_id: I) -> Self {
+        self.entity_id = Some(entity_id.into());
+        self
+    }
+
     notify_impl!(start, ActorStartMessage);
     notify_impl!(stop, ActorStopMessage);
     notify_impl!(drop, ActorDropMessage);
 }
 
+impl<A: BcActorCtrl> BcActor<A> {
+    async fn report_crash(&mut self, ctx: &mut Context<Self>, error: Option<&anyhow::Error>) {
+        let actor_id = ctx.actor_id();
+        let status = self.inner.handle(
+            BcContext {
+                log: &self.log,
+                clock: &self.clock,
+                activity: &self.activity,
+                entity_id: self.entity_id,
+                native: ctx,
+            },
+            GetActorStatusMessage,
+        );
+        let status = AssertUnwindSafe(status)
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| String::from("status unavailable"));
+        report_crash(&self.log, &snek_type_name::<A>(), actor_id, &status, error);
+    }
+}
+
 pub async fn halt_and_catch_fire_on_panic<T>(future: impl Future<Output = T>) -> Result<T> {
     let maybe_output = AssertUnwindSafe(future).catch_unwind().await;
+    let location = take_panic_location();
     if maybe_output.is_err() {
         use nix::sys::signal::{kill, Signal};
         use nix::unistd::Pid;
@@ -147,21 +237,29 @@ pub async fn halt_and_catch_fire_on_panic<T>(future: impl Future<Output = T>) ->
         let _ = kill(Pid::this(), Signal::SIGINT);
     }
 
-    maybe_output.map_err(|payload| {
-        let reason: String = payload
-            .as_ref()
-            .downcast_ref::<&str>()
-            .copied()
-            .or_else(|| payload.as_ref().downcast_ref::<String>().map(|s| s.as_str()))
-            .unwrap_or("unknown")
-            .to_string();
-        anyhow!("panic reason: {}", reason)
+    maybe_output.map_err(|payload| match location {
+        Some(location) => anyhow!("panic reason: {} at {}", panic_message(payload.as_ref()), location),
+        None => anyhow!("panic reason: {}", panic_message(payload.as_ref())),
     })
 }
 
 #[message(result = "String")]
 pub struct GetActorStatusMessage;
 
+/// Answers the actor status along with the count of messages the actor started before this one. The mailbox is
+/// first in, first out, so compared to the count when the probe was sent this is the depth of the queue ahead of it.
+#[message(result = "(String, u64)")]
+pub struct ProbeStatusMessage;
+
+#[async_trait::async_trait]
+impl<A: BcActorCtrl> BcHandler<ProbeStatusMessage> for A {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ProbeStatusMessage) -> (String, u64) {
+        let started_before = ctx.activity.started().saturating_sub(1);
+        let status = BcHandler::<GetActorStatusMessage>::handle(self, ctx, GetActorStatusMessage).await;
+        (status, started_before)
+    }
+}
+
 #[async_trait::async_trait]
 impl<A, M> Handler<M> for BcActor<A>
 where
@@ -169,19 +267,31 @@ where
     M: Message,
 {
     async fn handle(&mut self, ctx: &mut Context<Self>, msg: M) -> M::Result {
-        let log = self.log.new(o!("message" => snek_type_name::<M>()));
+        let message_type = snek_type_name::<M>();
+        let log = self.log.new(o!("message" => message_type.clone()));
         slog::trace!(log, "message received");
+        self.activity.start(message_type);
         let fut = self.inner.handle(
             BcContext {
                 log: &self.log,
+                clock: &self.clock,
+                activity: &self.activity,
+                entity_id: self.entity_id,
                 native: ctx,
             },
             msg,
         );
-        halt_and_catch_fire_on_panic(fut).await.unwrap_or_else(|error| {
-            crit!(self.log, "actor paniced handling message"; "error" => %error);
-            panic!("message handler paniced");
-        })
+        match halt_and_catch_fire_on_panic(reported_by_actor(fut)).await {
+            Ok(result) => {
+                self.activity.finish();
+                result
+            }
+            Err(error) => {
+                crit!(self.log, "actor paniced handling message"; "error" => %error);
+                self.report_crash(ctx, Some(&error)).await;
+                panic!("message handler paniced");
+            }
+        }
     }
 }
 
@@ -195,15 +305,29 @@ where
         trace!(self.log, "actor starting");
         let fut = self.inner.started(BcContext {
             log: &self.log,
+            clock: &self.clock,
+            activity: &self.activity,
+            entity_id: self.entity_id,
             native: ctx,
         });
-        let result = halt_and_catch_fire_on_panic(fut).await.and_then(|r| r);
+        let result = match halt_and_catch_fire_on_panic(reported_by_actor(fut)).await {
+            Ok(result) => result,
+            Err(error) => {
+                self.report_crash(ctx, Some(&error)).await;
+                Err(error)
+            }
+        };
         if let Err(e) = &result {
             error!(self.log, "actor start failed"; "error" => %e);
         } else {
             trace!(self.log, "actor started");
             self.actor_id = ctx.actor_id();
-            self.intel_notify_start(ActorStartMessage::new(ctx.actor_id(), ctx.address()));
+            self.intel_notify_start(ActorStartMessage::new(
+                ctx.actor_id(),
+                ctx.address(),
+                Arc::clone(&self.activity),
+                self.entity_id,
+            ));
         }
         result
     }
@@ -212,14 +336,24 @@ where
         trace!(self.log, "actor stopping");
         let fut = self.inner.stopped(BcContext {
             log: &self.log,
+            clock: &self.clock,
+            activity: &self.activity,
+            entity_id: self.entity_id,
             native: ctx,
         });
 
-        let result = halt_and_catch_fire_on_panic(fut).await;
-        let terminal_state = result.unwrap_or_else(|error| {
-            crit!(self.log, "actor panic on stop"; "error" => %error);
-            TerminalState::Faulted
-        });
+        let terminal_state = match halt_and_catch_fire_on_panic(reported_by_actor(fut)).await {
+            Ok(TerminalState::Faulted) => {
+                self.report_crash(ctx, None).await;
+                TerminalState::Faulted
+            }
+            Ok(terminal_state) => terminal_state,
+            Err(error) => {
+                crit!(self.log, "actor panic on stop"; "error" => %error);
+                self.report_crash(ctx, Some(&error)).await;
+                TerminalState::Faulted
+            }
+        };
         self.intel_notify_stop(ActorStopMessage::new(self.actor_id, terminal_state));
         trace!(self.log, "actor stopped"; "terminal_state" => %terminal_state);
     }
@@ -272,6 +406,7 @@ pub trait BcAddr: Sync + Send {
     fn actor_type(&self) -> String;
     fn stop(&mut self) -> Result<()>;
     async fn status(&self) -> Result<String>;
+    async fn probe_status(&self) -> Result<(String, u64)>;
     async fn wait_for_stop(self: Box<Self>);
 }
 
@@ -317,6 +452,10 @@ impl<T: BcActorCtrl> BcAddr for BcAddrImpl<T> {
         self.0.call(GetActorStatusMessage).await
     }
 
+    async fn probe_status(&self) -> Result<(String, u64)> {
+        self.0.call(ProbeStatusMessage).await
+    }
+
     fn actor_id(&self) -> u64 {
         self.0.actor_id()
     }
@@ -399,6 +538,18 @@ impl AnyAddr for BoxBcAddr {
 pub struct BcContext<'a, A> {
     native: &'a mut Context<BcActor<A>>,
     log: &'a Logger,
+    clock: &'a Arc<dyn Clock>,
+    activity: &'a ActorActivity,
+    entity_id: Option<EntityId>,
+}
+
+// Ends a long await when dropped, also when the awaiting future is cancelled.
+struct LongAwait<'a>(&'a ActorActivity);
+
+impl Drop for LongAwait<'_> {
+    fn drop(&mut self) {
+        self.0.long_awaits.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl<'a, A> BcContext<'a, A>
@@ -440,4 +591,27 @@ where
     pub fn log(&self) -> &Logger {
         self.log
     }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.clock
+    }
+
+    /// The entity the actor works for, if any.
+    pub fn entity_id(&self) -> Option<EntityId> {
+        self.entity_id
+    }
+
+    /// Awaits work that can keep the handler busy for longer than a health probe waits, like a hook or a restic
+    /// listing. Probes left unanswered meanwhile don't count against the actor.
+    pub fn long_await<'f, F: Future + 'f>(&self, future: F) -> impl Future<Output = F::Output> + 'f
+    where
+        'a: 'f,
+    {
+        let activity: &'f ActorActivity = self.activity;
+        async move {
+            activity.long_awaits.fetch_add(1, Ordering::Relaxed);
+            let _long_await = LongAwait(activity);
+            future.await
+        }
+    }
 }
@@ -0,0 +1,367 @@
+//! The stable programmatic interface to blockcaptain, for tools such as web UIs or TUIs that embed its logic.
+//!
+//! Everything defined or re-exported here follows semver with the crate version. The other modules of this crate
+//! are internal to blockcaptain and may change in any release.
+
+use crate::{
+    core::{
+        restic::ResticRepository, BtrfsContainer, BtrfsDataset, BtrfsPool, BtrfsSnapshot, Snapshot, SnapshotHandle,
+    },
+    error::{ErrorCode, ErrorCodeExt},
+    model::storage,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::body::Buf;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+pub use crate::{
+    core::system::{
+        ActiveState, ActorState, ServiceVersion, SystemActor, SystemState, TerminalState, PROTOCOL_VERSION,
+    },
+    model::{
+        entities::{
+            BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity, DatasetGroupEntity, HealthchecksObserverEntity,
+            ResticContainerEntity, SnapshotSyncEntity,
+        },
+        ContainerId, DatasetId, Entities, Entity, EntityId, EntityType, GroupId, ObserverId, PoolId, SyncId,
+    },
+    sys::net::ServiceClient,
+};
+
+/// A snapshot of a dataset, or a copy of one held by a container.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotInfo {
+    pub dataset_id: DatasetId,
+    pub datetime: DateTime<Utc>,
+    pub uuid: Uuid,
+    /// The uuid of the dataset snapshot a container snapshot was received from.
+    pub received_uuid: Option<Uuid>,
+    /// Where a btrfs snapshot is mounted, restic snapshots have none.
+    pub path: Option<PathBuf>,
+}
+
+/// Which snapshots to list and in what order. Deserializes from a URL query, every field is optional.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SnapshotQuery {
+    pub dataset_id: Option<DatasetId>,
+    /// Only snapshots taken at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only snapshots taken before this time.
+    pub until: Option<DateTime<Utc>>,
+    pub newest_first: bool,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// One page of the snapshots matching a query.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotPage {
+    pub snapshots: Vec<SnapshotInfo>,
+    /// The number of matching snapshots across all pages.
+    pub total: usize,
+    /// The offset of the following page, none on the last one.
+    pub next_offset: Option<usize>,
+}
+
+impl SnapshotQuery {
+    /// The URL query the service deserializes back into this query.
+    pub fn url_query(&self) -> String {
+        let mut pairs = vec![
+            format!("newest_first={}", self.newest_first),
+            format!("offset={}", self.offset),
+        ];
+        if let Some(dataset_id) = self.dataset_id {
+            pairs.push(format!("dataset_id={}", dataset_id));
+        }
+        if let Some(since) = self.since {
+            pairs.push(format!("since={}", since.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if let Some(until) = self.until {
+            pairs.push(format!("until={}", until.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if let Some(limit) = self.limit {
+            pairs.push(format!("limit={}", limit));
+        }
+        pairs.join("&")
+    }
+
+    pub fn page(&self, mut snapshots: Vec<SnapshotInfo>) -> SnapshotPage {
+        snapshots.retain(|s| {
+            self.dataset_id.map_or(true, |id| s.dataset_id == id)
+                && self.since.map_or(true, |since| s.datetime >= since)
+                && self.until.map_or(true, |until| s.datetime < until)
+        });
+        snapshots.sort_by_key(|s| s.datetime);
+        if self.newest_first {
+            snapshots.reverse();
+        }
+
+        let total = snapshots.len();
+        let start = self.offset.min(total);
+        let end = self.limit.map_or(total, |limit| start.saturating_add(limit).min(total));
+        SnapshotPage {
+            snapshots: snapshots.drain(start..end).collect(),
+            total,
+            next_offset: Some(end).filter(|end| *end < total),
+        }
+    }
+}
+
+/// Loads the entity configuration, an empty one when none has been stored.
+pub fn load_config() -> Result<Entities> {
+    storage::load_entity_config()
+}
+
+/// Stores the entity configuration, recording the changes in the audit log.
+pub fn store_config(entities: Entities) -> Result<()> {
+    storage::store_entity_config(entities)
+}
+
+/// The datasets of every pool, each with its pool.
+pub fn datasets(entities: &Entities) -> Vec<(&BtrfsDatasetEntity, &BtrfsPoolEntity)> {
+    entities.datasets().map(|p| (p.entity, p.parent)).collect()
+}
+
+/// The btrfs containers of every pool, each with its pool.
+pub fn containers(entities: &Entities) -> Vec<(&BtrfsContainerEntity, &BtrfsPoolEntity)> {
+    entities.containers().map(|p| (p.entity, p.parent)).collect()
+}
+
+/// The local snapshots of a dataset, oldest first.
+pub fn dataset_snapshots(entities: &Entities, dataset_id: DatasetId) -> Result<Vec<SnapshotInfo>> {
+    let dataset = validate_dataset(entities, dataset_id)?;
+    Ok(dataset
+        .snapshots()?
+        .iter()
+        .map(|s| SnapshotInfo {
+            dataset_id,
+            datetime: s.datetime(),
+            uuid: s.uuid(),
+            received_uuid: s.received_uuid(),
+            path: Some(s.canonical_path()),
+        })
+        .collect())
+}
+
+/// A page of the local snapshots of a dataset.
+pub fn query_dataset_snapshots(
+    entities: &Entities, dataset_id: DatasetId, query: &SnapshotQuery,
+) -> Result<SnapshotPage> {
+    Ok(query.page(dataset_snapshots(entities, dataset_id)?))
+}
+
+/// The snapshots of a dataset held by a btrfs or restic container, oldest first.
+pub async fn container_snapshots(
+    entities: &Entities, container_id: ContainerId, dataset_id: DatasetId,
+) -> Result<Vec<SnapshotInfo>> {
+    held_snapshots(entities, container_id, Some(dataset_id)).await
+}
+
+/// A page of the snapshots held by a btrfs or restic container, of every dataset unless the query names one.
+pub async fn query_container_snapshots(
+    entities: &Entities, container_id: ContainerId, query: &SnapshotQuery,
+) -> Result<SnapshotPage> {
+    Ok(query.page(held_snapshots(entities, container_id, query.dataset_id).await?))
+}
+
+async fn held_snapshots(
+    entities: &Entities, container_id: ContainerId, dataset_id: Option<DatasetId>,
+) -> Result<Vec<SnapshotInfo>> {
+    if let Some(restic) = entities.restic_container(container_id) {
+        let repository = Arc::new(ResticRepository::validate(restic.clone())?);
+        return Ok(repository
+            .snapshots()
+            .await?
+            .into_iter()
+            .filter(|s| dataset_id.map_or(true, |id| s.dataset_id == id))
+            .map(|s| {
+                let handle = SnapshotHandle::from(&s);
+                SnapshotInfo {
+                    dataset_id: s.dataset_id,
+                    datetime: handle.datetime,
+                    uuid: handle.uuid,
+                    received_uuid: handle.received_uuid,
+                    path: None,
+                }
+            })
+            .collect());
+    }
+
+    // Listing btrfs subvolumes blocks, which the service must not do on its runtime threads.
+    let path = entities
+        .container(container_id)
+        .ok_or_else(|| anyhow!("container {} not found", container_id))?;
+    let (pool, container) = (path.parent.clone(), path.entity.clone());
+    tokio::task::spawn_blocking(move || {
+        let pool = Arc::new(BtrfsPool::validate(pool)?);
+        let container = Arc::new(BtrfsContainer::validate(&pool, container)?);
+        let dataset_ids = match dataset_id {
+            Some(dataset_id) => vec![dataset_id],
+            None => container.source_dataset_ids()?,
+        };
+        let mut snapshots = Vec::new();
+        for dataset_id in dataset_ids {
+            snapshots.extend(container.snapshots(dataset_id)?.iter().map(|s| SnapshotInfo {
+                dataset_id,
+                datetime: s.datetime(),
+                uuid: s.uuid(),
+                received_uuid: Some(s.received_uuid()),
+                path: Some(s.canonical_path()),
+            }));
+        }
+        Ok(snapshots)
+    })
+    .await?
+}
+
+/// The space used only by the snapshots of a dataset held by a btrfs container, extents they share among themselves
+/// included. Requires quotas enabled on its pool.
+pub fn container_exclusive_bytes(entities: &Entities, container_id: ContainerId, dataset_id: DatasetId) -> Result<u64> {
+    validate_container(entities, container_id)?.snapshots_exclusive_size(dataset_id)
+}
+
+/// Takes a local snapshot of a dataset immediately, outside of its schedule. Retention is left to the service.
+pub fn snapshot_dataset(entities: &Entities, dataset_id: DatasetId) -> Result<SnapshotInfo> {
+    let dataset = validate_dataset(entities, dataset_id)?;
+    let snapshot = dataset.create_local_snapshot()?;
+    Ok(SnapshotInfo {
+        dataset_id,
+        datetime: snapshot.datetime(),
+        uuid: snapshot.uuid(),
+        received_uuid: None,
+        path: Some(snapshot.canonical_path()),
+    })
+}
+
+/// Connects to the running service, failing with what to upgrade when it speaks a different protocol.
+pub async fn connect_service() -> Result<ServiceClient> {
+    let client = ServiceClient::default();
+    let response = client
+        .get("/version")
+        .await
+        .context("failed to connect to the blockcaptain service")
+        .code(ErrorCode::WorkerUnreachable)?;
+    let body = hyper::body::aggregate(response).await?;
+    // Services without the version request answer it with their state instead.
+    let version: ServiceVersion = serde_json::from_reader(body.reader())
+        .map_err(|_| anyhow!("the blockcaptain service predates version checks, upgrade the daemon"))?;
+    version.check_compatible()?;
+    Ok(client)
+}
+
+/// A page of the snapshots of a dataset as the running service lists them.
+pub async fn service_dataset_snapshots(
+    client: &ServiceClient, dataset_id: DatasetId, query: &SnapshotQuery,
+) -> Result<SnapshotPage> {
+    service_snapshot_page(client, &format!("/datasets/{}/snapshots", dataset_id), query).await
+}
+
+/// A page of the snapshots held by a btrfs or restic container as the running service lists them.
+pub async fn service_container_snapshots(
+    client: &ServiceClient, container_id: ContainerId, query: &SnapshotQuery,
+) -> Result<SnapshotPage> {
+    service_snapshot_page(client, &format!("/containers/{}/snapshots", container_id), query).await
+}
+
+async fn service_snapshot_page(client: &ServiceClient, path: &str, query: &SnapshotQuery) -> Result<SnapshotPage> {
+    let response = client.get(&format!("{}?{}", path, query.url_query())).await?;
+    let status = response.status();
+    let body = hyper::body::aggregate(response).await?;
+    if !status.is_success() {
+        // The service answers failures with the error message.
+        let message: String = serde_json::from_reader(body.reader()).unwrap_or_else(|_| status.to_string());
+        return Err(anyhow!(message));
+    }
+    serde_json::from_reader(body.reader()).context("failed to parse the snapshot page")
+}
+
+/// The state of the actors of the running service.
+pub async fn service_state() -> Result<SystemState> {
+    let response = connect_service().await?.get("/").await?;
+    let body = hyper::body::aggregate(response).await?;
+    serde_json::from_reader(body.reader()).context("failed to parse the service state")
+}
+
+fn validate_dataset(entities: &Entities, dataset_id: DatasetId) -> Result<Arc<BtrfsDataset>> {
+    let path = entities
+        .dataset(dataset_id)
+        .ok_or_else(|| anyhow!("dataset {} not found", dataset_id))?;
+    let pool = Arc::new(BtrfsPool::validate(path.parent.clone())?);
+    Ok(Arc::new(BtrfsDataset::validate(&pool, path.entity.clone())?))
+}
+
+fn validate_container(entities: &Entities, container_id: ContainerId) -> Result<Arc<BtrfsContainer>> {
+    let path = entities
+        .container(container_id)
+        .ok_or_else(|| anyhow!("container {} not found", container_id))?;
+    let pool = Arc::new(BtrfsPool::validate(path.parent.clone())?);
+    Ok(Arc::new(BtrfsContainer::validate(&pool, path.entity.clone())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone};
+
+    fn snapshot(dataset_id: DatasetId, day: u32) -> SnapshotInfo {
+        SnapshotInfo {
+            dataset_id,
+            datetime: Utc.ymd(2021, 5, day).and_hms(0, 0, 0),
+            uuid: Uuid::new_v4(),
+            received_uuid: None,
+            path: None,
+        }
+    }
+
+    #[test]
+    fn snapshot_query_page() {
+        let dataset_id = DatasetId::default();
+        let other_id = "5e5e4b02-2d2c-4d27-a2ce-3bb1b86fbe77".parse().unwrap();
+        let snapshots = (1..=10)
+            .map(|day| snapshot(dataset_id, day))
+            .chain(Some(snapshot(other_id, 5)))
+            .collect::<Vec<_>>();
+        let query = SnapshotQuery {
+            dataset_id: Some(dataset_id),
+            since: Some(Utc.ymd(2021, 5, 2).and_hms(0, 0, 0)),
+            until: Some(Utc.ymd(2021, 5, 10).and_hms(0, 0, 0)),
+            newest_first: true,
+            offset: 3,
+            limit: Some(3),
+        };
+
+        let page = query.page(snapshots.clone());
+        assert_eq!(page.total, 8);
+        assert_eq!(page.next_offset, Some(6));
+        assert_eq!(
+            page.snapshots.iter().map(|s| s.datetime.day()).collect::<Vec<_>>(),
+            vec![6, 5, 4]
+        );
+
+        let page = SnapshotQuery { offset: 6, ..query }.page(snapshots);
+        assert_eq!(page.next_offset, None);
+        assert_eq!(page.snapshots.len(), 2);
+    }
+
+    #[test]
+    fn snapshot_query_url_query() {
+        let query = SnapshotQuery {
+            dataset_id: Some("5e5e4b02-2d2c-4d27-a2ce-3bb1b86fbe77".parse().unwrap()),
+            since: Some(Utc.ymd(2021, 5, 2).and_hms(3, 4, 5)),
+            until: None,
+            newest_first: true,
+            offset: 3,
+            limit: Some(10),
+        };
+        assert_eq!(
+            query.url_query(),
+            "newest_first=true&offset=3&dataset_id=5e5e4b02-2d2c-4d27-a2ce-3bb1b86fbe77&\
+             since=2021-05-02T03:04:05Z&limit=10"
+        );
+        assert_eq!(SnapshotQuery::default().url_query(), "newest_first=false&offset=0");
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::{fs, path::Path};
+use strum_macros::{Display, EnumString};
+
+/// Naming schemes of other snapshot tools whose snapshots can be adopted into a dataset's snapshot container.
+#[derive(Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum SnapshotNaming {
+    /// `<subvolume>/.snapshots/<number>/snapshot` with the creation time in the neighbouring `info.xml`.
+    Snapper,
+    /// `<subvolume name>.<timestamp>[_<n>]` with a timestamp in any of the btrbk timestamp formats.
+    Btrbk,
+}
+
+impl SnapshotNaming {
+    /// The creation time of a snapshot at the given absolute path, if the path follows the naming scheme.
+    pub fn snapshot_datetime(&self, path: &Path) -> Option<DateTime<Utc>> {
+        match self {
+            SnapshotNaming::Snapper => {
+                if path.file_name()? != "snapshot" {
+                    return None;
+                }
+                let info = fs::read_to_string(path.parent()?.join("info.xml")).ok()?;
+                parse_snapper_info_date(&info)
+            }
+            SnapshotNaming::Btrbk => parse_btrbk_name(&path.file_name()?.to_string_lossy()),
+        }
+    }
+}
+
+fn parse_snapper_info_date(info: &str) -> Option<DateTime<Utc>> {
+    // Snapper records the date in UTC.
+    let start = info.find("<date>")? + "<date>".len();
+    let end = start + info[start..].find("</date>")?;
+    NaiveDateTime::parse_from_str(info[start..end].trim(), "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|d| DateTime::<Utc>::from_utc(d, Utc))
+}
+
+fn parse_btrbk_name(name: &str) -> Option<DateTime<Utc>> {
+    let (_, timestamp) = name.rsplit_once('.')?;
+    // Duplicate timestamps get a numbered suffix, e.g. home.20210101T1200_1.
+    let timestamp = timestamp.split('_').next()?;
+
+    // The long-iso format carries its offset, the other formats are in local time.
+    if let Ok(datetime) = DateTime::parse_from_str(timestamp, "%Y%m%dT%H%M%S%z") {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(timestamp, "%Y%m%dT%H%M")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(timestamp, "%Y%m%d")
+                .ok()
+                .map(|d| d.and_hms(0, 0, 0))
+        })?;
+    Local
+        .from_local_datetime(&naive)
+        .single()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapper_info_date_parse() {
+        let info = r#"<?xml version="1.0"?>
+<snapshot>
+  <type>single</type>
+  <num>42</num>
+  <date>2021-03-04 05:06:07</date>
+  <cleanup>timeline</cleanup>
+</snapshot>"#;
+        assert_eq!(
+            parse_snapper_info_date(info),
+            Some(Utc.ymd(2021, 3, 4).and_hms(5, 6, 7))
+        );
+        assert_eq!(parse_snapper_info_date("<snapshot></snapshot>"), None);
+    }
+
+    #[test]
+    fn btrbk_name_parse() {
+        assert_eq!(
+            parse_btrbk_name("home.20210304T050607+0100"),
+            Some(Utc.ymd(2021, 3, 4).and_hms(4, 6, 7))
+        );
+        let local = Local.ymd(2021, 3, 4).and_hms(5, 6, 0).with_timezone(&Utc);
+        assert_eq!(parse_btrbk_name("home.20210304T0506"), Some(local));
+        assert_eq!(parse_btrbk_name("home.20210304T0506_1"), Some(local));
+        assert_eq!(
+            parse_btrbk_name("home.20210304"),
+            Some(Local.ymd(2021, 3, 4).and_hms(0, 0, 0).with_timezone(&Utc))
+        );
+        assert_eq!(parse_btrbk_name("home"), None);
+        assert_eq!(parse_btrbk_name("home.backup"), None);
+    }
+}
@@ -0,0 +1,194 @@
+//! GRUB menu entries booting the snapshots of OS root datasets. Every OS root dataset gets a menu file in the GRUB
+//! directory, the `42_blockcaptain` grub.d script sources the files present when grub-mkconfig runs.
+
+use super::{BtrfsDataset, BtrfsDatasetSnapshot};
+use crate::model::Entity;
+use anyhow::{Context, Result};
+use std::{
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use uuid::Uuid;
+
+const GRUB_DIRS: [&str; 2] = ["/boot/grub", "/boot/grub2"];
+/// Kernel arguments replaced by every entry.
+const ROOT_ARGUMENTS: [&str; 3] = ["BOOT_IMAGE=", "root=", "rootflags="];
+
+/// The menu file of the dataset, none without a GRUB directory.
+pub fn boot_menu_path(dataset: &BtrfsDataset) -> Option<PathBuf> {
+    GRUB_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|d| d.is_dir())
+        .map(|d| d.join(format!("blockcaptain-{}.cfg", dataset.model.id())))
+}
+
+/// Rewrites the menu file of an OS root dataset with its current snapshots, removes it of other datasets. Snapshots
+/// without a kernel in their /boot, such as with a separate boot partition, get no entry. Returns the entry count.
+pub fn update_boot_menu(dataset: &Arc<BtrfsDataset>) -> Result<usize> {
+    let path = match boot_menu_path(dataset) {
+        Some(path) => path,
+        None => return Ok(0),
+    };
+    if !dataset.model.os_root {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove boot menu {:?}.", path))
+            }
+            _ => Ok(0),
+        };
+    }
+
+    let mut snapshots = dataset.snapshots()?;
+    snapshots.extend(dataset.tagged_snapshots(None)?);
+    snapshots.sort_unstable_by_key(|s| std::cmp::Reverse(s.datetime));
+
+    let cmdline = fs::read_to_string("/proc/cmdline").context("failed to read the kernel command line")?;
+    let arguments = kernel_arguments(&cmdline);
+    let fs_uuid = dataset.pool.model().uuid;
+    let mut entries = 0;
+    let mut menu = format!(
+        "# Generated by blockcaptain, do not edit.\nsubmenu '{} snapshots' {{\n",
+        grub_quote(&dataset.to_string())
+    );
+    for snapshot in snapshots.iter() {
+        if let Some(entry) = snapshot_entry(snapshot, fs_uuid, &arguments)? {
+            menu.push_str(&entry);
+            entries += 1;
+        }
+    }
+    menu.push_str("}\n");
+    if entries == 0 {
+        menu = String::from("# Generated by blockcaptain, no bootable snapshots.\n");
+    }
+
+    let temp_path = path.with_extension("cfg.tmp");
+    fs::write(&temp_path, menu).with_context(|| format!("Failed to write boot menu {:?}.", temp_path))?;
+    fs::rename(&temp_path, &path).with_context(|| format!("Failed to replace boot menu {:?}.", path))?;
+    Ok(entries)
+}
+
+fn snapshot_entry(snapshot: &BtrfsDatasetSnapshot, fs_uuid: Uuid, arguments: &str) -> Result<Option<String>> {
+    let boot_dir = snapshot.canonical_path().join("boot");
+    let names = match fs::read_dir(&boot_dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {:?}.", boot_dir)),
+    };
+    let (kernel, initrd) = match boot_files(&names) {
+        Some(files) => files,
+        None => return Ok(None),
+    };
+
+    // GRUB paths start at the top level subvolume, which the pool mounts.
+    let subvolume = snapshot.path().as_pathbuf(Path::new("/"));
+    let subvolume = subvolume.to_string_lossy();
+    let name = snapshot
+        .path()
+        .file_name()
+        .expect("snapshot path always has a name")
+        .to_string_lossy();
+    let mut entry = String::new();
+    writeln!(
+        entry,
+        "menuentry '{} ({})' --class snapshot {{",
+        snapshot.datetime.format("%F %T UTC"),
+        grub_quote(&name)
+    )?;
+    writeln!(entry, "\tsearch --no-floppy --fs-uuid --set=root {}", fs_uuid)?;
+    writeln!(
+        entry,
+        "\tlinux {}/boot/{} root=UUID={} rootflags=subvol={} {}",
+        subvolume, kernel, fs_uuid, subvolume, arguments
+    )?;
+    if let Some(initrd) = initrd {
+        writeln!(entry, "\tinitrd {}/boot/{}", subvolume, initrd)?;
+    }
+    entry.push_str("}\n");
+    Ok(Some(entry))
+}
+
+/// The newest kernel in a /boot listing and its initramfs, in the Debian, Fedora, SUSE and Arch namings.
+fn boot_files(names: &[String]) -> Option<(&str, Option<&str>)> {
+    let kernel = names
+        .iter()
+        .filter(|n| n.starts_with("vmlinuz-") && !n.ends_with(".old"))
+        .max_by(|a, b| version_cmp(a, b))?;
+    let version = &kernel["vmlinuz-".len()..];
+    let candidates = [
+        format!("initrd.img-{}", version),
+        format!("initramfs-{}.img", version),
+        format!("initrd-{}", version),
+    ];
+    let initrd = candidates
+        .iter()
+        .find_map(|c| names.iter().find(|n| *n == c))
+        .map(String::as_str);
+    Some((kernel, initrd))
+}
+
+// Compares the numbers in kernel versions numerically, 5.10 is newer than 5.9.
+fn version_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    fn parts(s: &str) -> Vec<Result<u64, &str>> {
+        s.split(|c: char| c == '.' || c == '-')
+            .map(|p| p.parse::<u64>().map_err(|_| p))
+            .collect()
+    }
+    parts(a).cmp(&parts(b))
+}
+
+/// The running kernel's arguments, without those choosing the root.
+fn kernel_arguments(cmdline: &str) -> String {
+    cmdline
+        .split_whitespace()
+        .filter(|arg| !ROOT_ARGUMENTS.iter().any(|r| arg.starts_with(r)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn grub_quote(value: &str) -> String {
+    value.replace('\'', "'\\''")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_files_newest_kernel() {
+        let names = [
+            "vmlinuz-5.9.0-1-amd64",
+            "vmlinuz-5.10.0-8-amd64",
+            "initrd.img-5.10.0-8-amd64",
+            "config-5.10.0-8-amd64",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            boot_files(&names),
+            Some(("vmlinuz-5.10.0-8-amd64", Some("initrd.img-5.10.0-8-amd64")))
+        );
+
+        let names = ["vmlinuz-linux", "initramfs-linux.img", "initramfs-linux-fallback.img"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(boot_files(&names), Some(("vmlinuz-linux", Some("initramfs-linux.img"))));
+
+        assert_eq!(boot_files(&["grub".to_string()]), None);
+    }
+
+    #[test]
+    fn kernel_arguments_drop_root() {
+        assert_eq!(
+            kernel_arguments("BOOT_IMAGE=/@/boot/vmlinuz-5.10 root=UUID=abc ro rootflags=subvol=@ quiet\n"),
+            "ro quiet"
+        );
+    }
+}
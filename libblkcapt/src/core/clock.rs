@@ -0,0 +1,96 @@
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use std::{fmt::Debug, sync::Mutex, time::Duration};
+
+/// The source of the current time, replaced in tests to evaluate schedules at fixed times.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when set or advanced.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().expect("clock lock is never poisoned") = now;
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        *self.now.lock().expect("clock lock is never poisoned") += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().expect("clock lock is never poisoned")
+    }
+}
+
+/// The next time in the schedule and how long until then.
+pub fn schedule_next_delay(schedule: &Schedule, clock: &dyn Clock) -> Option<(DateTime<Utc>, Duration)> {
+    let now = clock.now();
+    schedule.after(&now).next().map(|next_datetime| {
+        let delay_to_next = (next_datetime - now)
+            .to_std()
+            .expect("time to next schedule can always fit in std duration");
+        (next_datetime, delay_to_next)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::str::FromStr;
+
+    #[test]
+    fn manual_clock_advance() {
+        let start = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        let clock = ManualClock::new(start);
+        assert_eq!(clock.now(), start);
+        clock.advance(chrono::Duration::hours(25));
+        assert_eq!(clock.now(), Utc.ymd(2021, 3, 5).and_hms(6, 6, 7));
+    }
+
+    #[test]
+    fn schedule_next_delay_leap_day() {
+        let schedule = Schedule::from_str("0 30 2 29 Feb *").unwrap();
+        let clock = ManualClock::new(Utc.ymd(2021, 3, 1).and_hms(0, 0, 0));
+        let (next, delay) = schedule_next_delay(&schedule, &clock).unwrap();
+        assert_eq!(next, Utc.ymd(2024, 2, 29).and_hms(2, 30, 0));
+        assert_eq!(delay, (next - clock.now()).to_std().unwrap());
+
+        clock.set(next);
+        let (next, _) = schedule_next_delay(&schedule, &clock).unwrap();
+        assert_eq!(next, Utc.ymd(2028, 2, 29).and_hms(2, 30, 0));
+    }
+
+    #[test]
+    fn schedule_next_delay_ignores_local_dst() {
+        // Schedules run in UTC, so the hour skipped by a DST change still gets its run.
+        let schedule = Schedule::from_str("0 0 * * * *").unwrap();
+        let clock = ManualClock::new(Utc.ymd(2021, 3, 28).and_hms(0, 30, 0));
+        for hour in 1..4 {
+            let (next, delay) = schedule_next_delay(&schedule, &clock).unwrap();
+            assert_eq!(next, Utc.ymd(2021, 3, 28).and_hms(hour, 0, 0));
+            assert_eq!(delay, Duration::from_secs(30 * 60));
+            clock.set(next + chrono::Duration::minutes(30));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    model::{entities::JobHooks, Entity},
+    sys::process::output_to_result,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{fmt::Display, path::Path, process::Stdio, time::Duration};
+use strum_macros::Display;
+use tokio::process::Command;
+
+#[derive(Display, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+pub enum HookJob {
+    Sync,
+    Prune,
+}
+
+#[derive(Display, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+enum HookStage {
+    Pre,
+    Post,
+}
+
+/// How long a hook may run when its hooks don't configure a timeout.
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub struct Hook {
+    command: Command,
+    description: String,
+    timeout: Duration,
+}
+
+impl Hook {
+    pub fn pre(hooks: &JobHooks, entity: &dyn Entity, job: HookJob) -> Option<Self> {
+        hooks
+            .pre
+            .as_ref()
+            .map(|path| Self::new(path, hooks, entity, job, HookStage::Pre))
+    }
+
+    pub fn post<O: Display>(hooks: &JobHooks, entity: &dyn Entity, job: HookJob, outcome: O) -> Option<Self> {
+        hooks.post.as_ref().map(|path| {
+            let mut hook = Self::new(path, hooks, entity, job, HookStage::Post);
+            hook.command.env("BLKCAPT_JOB_OUTCOME", outcome.to_string());
+            hook
+        })
+    }
+
+    fn new(path: &Path, hooks: &JobHooks, entity: &dyn Entity, job: HookJob, stage: HookStage) -> Self {
+        let mut command = Command::new(path);
+        command
+            .env("BLKCAPT_HOOK_JOB", job.to_string())
+            .env("BLKCAPT_HOOK_STAGE", stage.to_string())
+            .env("BLKCAPT_ENTITY_ID", entity.id().to_string())
+            .env("BLKCAPT_ENTITY_NAME", entity.name())
+            .env("BLKCAPT_ENTITY_TYPE", entity.entity_type().to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        Self {
+            command,
+            description: format!("{}-{} hook {:?}", stage, job, path),
+            timeout: hooks.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT),
+        }
+    }
+
+    /// Runs the hook, killing it once it runs longer than the timeout.
+    pub async fn run(mut self) -> Result<()> {
+        // Dropping the output future on timeout kills the hook, its command is set to kill on drop.
+        match tokio::time::timeout(self.timeout, self.command.output()).await {
+            Ok(output) => output_to_result(output).with_context(|| format!("{} failed", self.description)),
+            Err(_) => Err(anyhow!(
+                "{} timed out after {}",
+                self.description,
+                humantime::format_duration(self.timeout)
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::entities::HealthchecksObserverEntity;
+    use std::{collections::HashMap, ffi::OsStr, path::PathBuf};
+
+    fn hooks(pre: &str, post: &str) -> JobHooks {
+        JobHooks {
+            pre: Some(PathBuf::from(pre)),
+            post: Some(PathBuf::from(post)),
+            timeout: None,
+        }
+    }
+
+    fn envs(hook: &Hook) -> HashMap<&str, &str> {
+        hook.command
+            .as_std()
+            .get_envs()
+            .filter_map(|(k, v)| Some((k.to_str()?, v.and_then(OsStr::to_str)?)))
+            .collect()
+    }
+
+    #[test]
+    fn hooks_are_optional() {
+        let entity = HealthchecksObserverEntity::new("pings".to_owned(), Vec::new());
+        let hooks = JobHooks::default();
+        assert!(Hook::pre(&hooks, &entity, HookJob::Sync).is_none());
+        assert!(Hook::post(&hooks, &entity, HookJob::Sync, "succeeded").is_none());
+    }
+
+    #[test]
+    fn hooks_describe_job_in_environment() {
+        let entity = HealthchecksObserverEntity::new("pings".to_owned(), Vec::new());
+        let hooks = hooks("/bin/true", "/bin/true");
+        let id = entity.id().to_string();
+
+        let pre = Hook::pre(&hooks, &entity, HookJob::Sync).unwrap();
+        let mut expected = HashMap::new();
+        expected.insert("BLKCAPT_HOOK_JOB", "sync");
+        expected.insert("BLKCAPT_HOOK_STAGE", "pre");
+        expected.insert("BLKCAPT_ENTITY_ID", id.as_str());
+        expected.insert("BLKCAPT_ENTITY_NAME", "pings");
+        expected.insert("BLKCAPT_ENTITY_TYPE", "observer");
+        assert_eq!(envs(&pre), expected);
+
+        let post = Hook::post(&hooks, &entity, HookJob::Prune, "failed").unwrap();
+        expected.insert("BLKCAPT_HOOK_JOB", "prune");
+        expected.insert("BLKCAPT_HOOK_STAGE", "post");
+        expected.insert("BLKCAPT_JOB_OUTCOME", "failed");
+        assert_eq!(envs(&post), expected);
+    }
+
+    #[test]
+    fn run_fails_with_hook_description() {
+        let entity = HealthchecksObserverEntity::new("pings".to_owned(), Vec::new());
+        let hooks = hooks("/bin/true", "/bin/false");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            Hook::pre(&hooks, &entity, HookJob::Prune).unwrap().run().await.unwrap();
+            let error = Hook::post(&hooks, &entity, HookJob::Prune, "succeeded")
+                .unwrap()
+                .run()
+                .await
+                .unwrap_err();
+            assert!(error.to_string().starts_with("post-prune hook"));
+        });
+    }
+
+    #[test]
+    fn run_kills_hook_after_timeout() {
+        let entity = HealthchecksObserverEntity::new("pings".to_owned(), Vec::new());
+        let script = std::env::temp_dir().join(format!("blkcapt-hook-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&script, "#!/bin/sh\nsleep 30\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let hooks = JobHooks {
+            pre: Some(script.clone()),
+            post: None,
+            timeout: Some(Duration::from_millis(200)),
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let error = runtime.block_on(async {
+            Hook::pre(&hooks, &entity, HookJob::Sync)
+                .unwrap()
+                .run()
+                .await
+                .unwrap_err()
+        });
+        std::fs::remove_file(&script).unwrap();
+        assert!(error.to_string().contains("timed out after 200ms"));
+    }
+}
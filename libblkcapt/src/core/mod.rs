@@ -1,37 +1,142 @@
+pub mod adopt;
+pub mod boot;
+pub mod clock;
+pub mod hooks;
+pub mod naming;
+pub mod provenance;
+pub mod quiesce;
 pub mod restic;
 pub mod retention;
+pub mod rollback;
 pub mod system;
-use crate::sys::fs::{lookup_mountentry, BlockDeviceIds, BtrfsMountEntry, FsPathBuf};
+pub mod units;
+pub mod verify;
+use crate::sys::{
+    crypt::{mapper_uuid, remove_from_crypttab},
+    fs::{
+        active_swapfiles, has_nocow_attribute, lookup_mountentry, power_off_device, unmount, BlockDeviceIds,
+        BtrfsMountEntry, DevicePathBuf, FsPathBuf,
+    },
+    host::{hostname, machine_id},
+};
 use crate::{
+    data_dir,
     model::entities::{
-        BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity, HealthchecksObservation, ObservableEvent,
-        SubvolumeEntity,
+        BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity, HealthchecksObservation, HealthchecksObserverEntity,
+        NocowPolicy, ObservableEvent, SnapshotNameFormat, SnapshotQuota, SpaceLevel, SubvolumeEntity,
+    },
+    model::ProxyConfig,
+    model::{secrets, storage},
+    sys::{
+        net::{HttpOptions, HttpProxy, HttpTimeouts, HttpsClient},
+        process::{exit_status_as_result, UnprivilegedUser},
     },
-    sys::net::HttpsClient,
 };
 use crate::{
     model::Entity,
-    sys::btrfs::{PoolScrub, SnapshotReceiver, SnapshotSender},
+    sys::btrfs::{
+        compressed_send_supported, AllocationMode, AllocationProfiles, BalanceProgress, Defrag, DeviceStats,
+        FilesystemDevice, PoolBalance, PoolDedup, PoolScrub, ResizeTarget, SnapshotReceiver, SnapshotSender,
+        SpaceUsage,
+    },
 };
 use crate::{
-    model::EntityId,
-    sys::btrfs::{Filesystem, MountedFilesystem, Subvolume},
+    model::{DatasetId, EntityId, SnapshotDeleteConfig},
+    sys::btrfs::{missing_mount_options, Filesystem, MountedFilesystem, QueriedFilesystem, Subvolume},
 };
+use adopt::SnapshotNaming;
 use anyhow::{anyhow, bail, Context, Result};
 use chrono::{DateTime, NaiveDateTime, Timelike, Utc};
 use derivative::Derivative;
 use hyper::Uri;
-use std::path::PathBuf;
-use std::{convert::TryFrom, str::FromStr, sync::Arc};
-use std::{fmt::Debug, fmt::Display, fs};
+use naming::{
+    format_snapshot_name, format_tagged_snapshot_name, parse_snapshot_name, parse_tagged_snapshot_name,
+    validate_snapshot_tag, DEFAULT_SNAPSHOT_FORMAT,
+};
+use provenance::SnapshotProvenance;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    iter,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use std::{
+    env,
+    fmt::Debug,
+    fmt::Display,
+    fs,
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    process::Stdio,
+};
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
 const BLKCAPT_FS_META_DIR: &str = ".blkcapt";
+/// Snapshots kept of a tag without a configured count.
+pub const DEFAULT_TAGGED_KEEP: u32 = 10;
 
 #[derive(Debug)]
 pub struct BtrfsPool {
     model: BtrfsPoolEntity,
     filesystem: MountedFilesystem,
+    subvolume_cache: SubvolumeCache,
+}
+
+/// Subvolume listings of a pool, reused until a change made through the pool or a newer modification time of the
+/// listed directory or a plain directory below it says otherwise.
+#[derive(Debug, Default)]
+struct SubvolumeCache(Mutex<HashMap<FsPathBuf, (SystemTime, Vec<Subvolume>)>>);
+
+/// Listings with more plain directories below them than this are not cached, checking them would cost about as much.
+const SUBVOLUME_CACHE_MAX_DIRECTORIES: usize = 64;
+
+impl SubvolumeCache {
+    fn get(&self, path: &FsPathBuf, modified: SystemTime) -> Option<Vec<Subvolume>> {
+        let cache = self.0.lock().expect("subvolume cache lock is never poisoned");
+        cache
+            .get(path)
+            .filter(|(cached, _)| *cached == modified)
+            .map(|(_, subvolumes)| subvolumes.clone())
+    }
+
+    fn insert(&self, path: FsPathBuf, modified: SystemTime, subvolumes: Vec<Subvolume>) {
+        let mut cache = self.0.lock().expect("subvolume cache lock is never poisoned");
+        cache.insert(path, (modified, subvolumes));
+    }
+
+    // Drops the listings that could include a subvolume at `changed`, those of its ancestors.
+    fn invalidate(&self, changed: &FsPathBuf) {
+        let mut cache = self.0.lock().expect("subvolume cache lock is never poisoned");
+        cache.retain(|listed, _| changed.strip_prefix(listed).is_none());
+    }
+}
+
+// The newest modification time of the directory at `path` and the plain directories below it, which changes when a
+// subvolume is created or deleted anywhere under it. Subvolumes below are not walked. None when the directory is
+// missing or has too many directories below it to be worth caching.
+fn tree_modified(path: &Path) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).ok()?;
+    let mut newest = metadata.modified().ok()?;
+    let mut directories = 0;
+    let mut complete = true;
+    walk_subvolume(path, metadata.dev(), &mut |_, entry| {
+        if entry.is_dir() {
+            directories += 1;
+            if directories > SUBVOLUME_CACHE_MAX_DIRECTORIES {
+                complete = false;
+                return Ok(false);
+            }
+            newest = newest.max(entry.modified()?);
+        }
+        Ok(true)
+    })
+    .ok()?;
+    Some(newest).filter(|_| complete)
 }
 
 impl BtrfsPool {
@@ -47,35 +152,20 @@ impl BtrfsPool {
             .unwrap_mounted()
             .context("Validated top-level mount point didn't yield a mounted filesystem.")?;
 
-        let device_infos = btrfs_info
-            .filesystem
-            .devices
-            .iter()
-            .map(|d| {
-                BlockDeviceIds::lookup(d).and_then(|ids| ids.ok_or_else(|| anyhow!("missing device ids for {}", d)))
-            })
-            .collect::<Result<Vec<BlockDeviceIds>>>()
-            .context("All devices for a btrfs filesystem should resolve with blkid.")?;
-
-        let device_uuid_subs = device_infos
-            .iter()
-            .map(|d| {
-                d.uuid_sub
-                    .context("All devices for a btrfs filesystem should have a uuid_subs.")
-            })
-            .collect::<Result<Vec<Uuid>>>()?;
+        let device_uuid_subs = device_uuid_subs(&btrfs_info)?;
 
         let meta_dir = FsPathBuf::from(BLKCAPT_FS_META_DIR);
         let mounted_meta_dir = meta_dir.as_pathbuf(&mountpoint);
         if !mounted_meta_dir.exists() {
             slog_scope::info!("Attached to new filesystem. Creating blkcapt dir.");
             fs::create_dir(&mounted_meta_dir)?;
-            btrfs_info.create_subvolume(&meta_dir.join("snapshots"))?;
+            btrfs_info.create_subvolume(&snapshots_meta_path())?;
         }
 
         Ok(Self {
             model: BtrfsPoolEntity::new(name, mountpoint, btrfs_info.filesystem.uuid, device_uuid_subs)?,
             filesystem: btrfs_info,
+            subvolume_cache: Default::default(),
         })
     }
 
@@ -85,12 +175,51 @@ impl BtrfsPool {
             .unwrap_mounted()
             .context("No active top-level mount point found for existing pool.")?;
 
+        if let Some(mountentry) = lookup_mountentry(&btrfs_info.fstree_mountpoint) {
+            let mountentry = BtrfsMountEntry::try_from(mountentry)?;
+            for option in missing_mount_options(&model.mount_options, &mountentry.extra_options()) {
+                slog_scope::warn!(
+                    "Pool {} is configured with mount option {}, but it is not mounted with it.",
+                    model.name(),
+                    option
+                );
+            }
+        }
+
         Ok(Self {
             model,
             filesystem: btrfs_info,
+            subvolume_cache: Default::default(),
         })
     }
 
+    /// Mounts a removable pool if its devices are present. Returns `None` while the pool is not attached.
+    pub fn attach(model: BtrfsPoolEntity) -> Result<Option<Self>> {
+        let btrfs_info = match Filesystem::query_uuid(&model.uuid) {
+            Ok(QueriedFilesystem::Mounted(mounted)) => mounted,
+            Ok(QueriedFilesystem::Unmounted(filesystem)) => filesystem
+                .mount(&model.mountpoint_path, &model.mount_options)
+                .context("failed to mount attached pool")?,
+            Err(_) => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            model,
+            filesystem: btrfs_info,
+            subvolume_cache: Default::default(),
+        }))
+    }
+
+    pub fn detach(&self, power_down: bool) -> Result<()> {
+        unmount(&self.filesystem.fstree_mountpoint)?;
+        if power_down {
+            for device in self.filesystem.filesystem.devices.iter() {
+                power_off_device(device)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn model(&self) -> &BtrfsPoolEntity {
         &self.model
     }
@@ -103,17 +232,459 @@ impl BtrfsPool {
         self.filesystem.scrub()
     }
 
+    pub fn balance_status(&self) -> Result<Option<BalanceProgress>> {
+        self.filesystem.balance_status()
+    }
+
+    /// Deduplicates the configured subvolumes, or every dataset of the pool when none are configured.
+    pub fn dedup(&self) -> Result<PoolDedup> {
+        let config = self.model.dedup.as_ref().context("Deduplication is not configured.")?;
+        self.ensure_no_balance()?;
+        let mountpoint = &self.filesystem.fstree_mountpoint;
+        let paths = if config.paths.is_empty() {
+            self.model
+                .datasets
+                .iter()
+                .map(|d| d.path.as_pathbuf(mountpoint))
+                .collect::<Vec<_>>()
+        } else {
+            config.paths.iter().map(|p| p.as_pathbuf(mountpoint)).collect()
+        };
+        if paths.is_empty() {
+            bail!("Pool {} has no datasets to deduplicate.", self);
+        }
+
+        let mut hashfile = data_dir();
+        hashfile.push("dedup");
+        fs::create_dir_all(&hashfile)?;
+        hashfile.push(format!("{}.hash", self.model.pool_id()));
+        Ok(self.filesystem.dedup(&paths, &hashfile))
+    }
+
+    pub fn space_usage(&self) -> Result<SpaceUsage> {
+        self.filesystem.space_usage()
+    }
+
+    pub fn device_stats(&self) -> Result<Vec<DeviceStats>> {
+        self.filesystem.device_stats()
+    }
+
+    /// The free space level according to the pool's space guard. Always normal without a guard.
+    pub fn space_level(&self) -> Result<SpaceLevel> {
+        match &self.model.space_guard {
+            Some(guard) => Ok(guard.level(self.space_usage()?.free_percent())),
+            None => Ok(SpaceLevel::Normal),
+        }
+    }
+
+    fn ensure_space_for_writes(&self) -> Result<()> {
+        if self.space_level()? == SpaceLevel::Critical {
+            bail!(
+                "Pool {} is critically low on free space. New snapshots are refused.",
+                self
+            );
+        }
+        Ok(())
+    }
+
+    fn ensure_no_balance(&self) -> Result<()> {
+        if self.balance_status()?.is_some() {
+            bail!("A balance is running on pool {}. Wait for it to complete first.", self);
+        }
+        Ok(())
+    }
+
+    pub fn convert(&self, data: Option<AllocationMode>, metadata: Option<AllocationMode>) -> Result<PoolBalance> {
+        if data.is_none() && metadata.is_none() {
+            bail!("At least one of the data or metadata allocation profiles is required.");
+        }
+        self.ensure_no_balance()?;
+
+        let device_count = self.devices().len();
+        for mode in data.iter().chain(metadata.iter()) {
+            if mode.min_devices() > device_count {
+                bail!(
+                    "Allocation profile {} requires {} devices, but pool {} has {}.",
+                    mode,
+                    mode.min_devices(),
+                    self,
+                    device_count
+                );
+            }
+        }
+
+        Ok(self.filesystem.convert(data, metadata))
+    }
+
+    pub fn allocation_profiles(&self) -> Result<AllocationProfiles> {
+        self.filesystem.allocation_profiles()
+    }
+
+    pub fn devices(&self) -> &[DevicePathBuf] {
+        &self.filesystem.filesystem.devices
+    }
+
+    pub fn add_device(&mut self, device: &DevicePathBuf, balance: bool) -> Result<()> {
+        if self.devices().contains(device) {
+            bail!("Device {} is already part of pool {}.", device, self);
+        }
+        self.ensure_no_balance()?;
+
+        self.filesystem.add_device(device)?;
+        self.refresh_devices()?;
+        if balance {
+            slog_scope::info!("Balancing pool {} across {} devices.", self, self.devices().len());
+            self.filesystem.balance()?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_device(&mut self, device: &DevicePathBuf) -> Result<()> {
+        if !self.devices().contains(device) {
+            bail!("Device {} is not part of pool {}.", device, self);
+        }
+        self.ensure_no_balance()?;
+
+        let required = self.filesystem.allocation_profiles()?.min_devices();
+        let remaining = self.devices().len() - 1;
+        if remaining < required {
+            bail!(
+                "Removing {} would leave {} devices, but the allocation profiles of pool {} require {}.",
+                device,
+                remaining,
+                self,
+                required
+            );
+        }
+
+        self.filesystem.remove_device(device)?;
+        self.refresh_devices()
+    }
+
+    pub fn replace_device(&mut self, source: &DevicePathBuf, target: &DevicePathBuf) -> Result<()> {
+        if !self.devices().contains(source) {
+            bail!("Device {} is not part of pool {}.", source, self);
+        }
+        if self.devices().contains(target) {
+            bail!("Device {} is already part of pool {}.", target, self);
+        }
+        self.ensure_no_balance()?;
+        let devid = self
+            .device_sizes()?
+            .into_iter()
+            .find(|d| d.path == *source)
+            .with_context(|| format!("Device {} is not part of pool {}.", source, self))?
+            .devid;
+
+        self.filesystem.replace_device(source, target)?;
+        self.refresh_devices()?;
+        // The replacement keeps the size of the device it replaced, grow it to a larger target.
+        self.filesystem.resize(devid, ResizeTarget::Max)
+    }
+
+    /// The devices of the pool with the sizes the filesystem uses on them.
+    pub fn device_sizes(&self) -> Result<Vec<FilesystemDevice>> {
+        self.filesystem.devices()
+    }
+
+    /// Resizes the filesystem on a device to `target`, e.g. to fill a virtual disk after it was enlarged.
+    pub fn resize_device(&self, device: &DevicePathBuf, target: ResizeTarget) -> Result<FilesystemDevice> {
+        let devid = self
+            .device_sizes()?
+            .into_iter()
+            .find(|d| d.path == *device)
+            .with_context(|| format!("Device {} is not part of pool {}.", device, self))?
+            .devid;
+        self.ensure_no_balance()?;
+
+        self.filesystem.resize(devid, target)?;
+        self.device_sizes()?
+            .into_iter()
+            .find(|d| d.devid == devid)
+            .context("Device disappeared from the pool while resizing.")
+    }
+
+    fn refresh_devices(&mut self) -> Result<()> {
+        self.filesystem = Filesystem::query_uuid(&self.model.uuid)?
+            .unwrap_mounted()
+            .context("Pool lost its top-level mount point while changing devices.")?;
+        self.model.uuid_subs = device_uuid_subs(&self.filesystem)?;
+        if let Some(luks) = self.model.luks.as_mut() {
+            let luks_uuids = self
+                .filesystem
+                .filesystem
+                .devices
+                .iter()
+                .filter_map(mapper_uuid)
+                .collect::<Vec<_>>();
+            for removed in luks.luks_uuids.iter().filter(|u| !luks_uuids.contains(u)) {
+                remove_from_crypttab(removed)?;
+            }
+            luks.luks_uuids = luks_uuids;
+        }
+        Ok(())
+    }
+
     pub fn create_dataset(self: &Arc<Self>, name: String) -> Result<BtrfsDataset> {
         let fs_path = FsPathBuf::from(&name);
-        self.filesystem.create_subvolume(&fs_path)?;
+        self.create_subvolume(&fs_path)?;
         BtrfsDataset::new(self, name, fs_path.as_pathbuf(&self.filesystem.fstree_mountpoint))
     }
 
     pub fn create_container(self: &Arc<Self>, name: String) -> Result<BtrfsContainer> {
         let fs_path = FsPathBuf::from(&name);
-        self.filesystem.create_subvolume(&fs_path)?;
+        self.create_subvolume(&fs_path)?;
         BtrfsContainer::new(self, name, fs_path.as_pathbuf(&self.filesystem.fstree_mountpoint))
     }
+
+    /// Subvolumes that are not attached as a dataset or container, excluding blockcaptain internals. Subvolumes
+    /// nested inside attached ones are not considered.
+    pub fn unattached_subvolumes(&self) -> Result<Vec<Subvolume>> {
+        let meta_dir = FsPathBuf::from(BLKCAPT_FS_META_DIR);
+        let attached = self
+            .model
+            .datasets
+            .iter()
+            .map(|d| d.path())
+            .chain(self.model.containers.iter().map(|c| c.path()))
+            .collect::<Vec<_>>();
+
+        let mut unattached = Vec::new();
+        let mut pending = vec![FsPathBuf::from("")];
+        while let Some(next) = pending.pop() {
+            for subvolume in self.list_subvolumes(&next)? {
+                if subvolume.path.strip_prefix(&meta_dir).is_some() || attached.contains(&&subvolume.path) {
+                    continue;
+                }
+                pending.push(subvolume.path.clone());
+                unattached.push(subvolume);
+            }
+        }
+        unattached.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        Ok(unattached)
+    }
+
+    pub fn snapshot_dataset_ids(&self) -> Result<Vec<DatasetId>> {
+        let snapshots_path = snapshots_meta_path();
+        if !snapshots_path.as_pathbuf(&self.filesystem.fstree_mountpoint).exists() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .list_subvolumes(&snapshots_path)?
+            .into_iter()
+            .filter_map(|s| DatasetId::from_str(&s.path.file_name().unwrap_or_default().to_string_lossy()).ok())
+            .collect::<Vec<_>>())
+    }
+
+    pub fn purge_dataset_snapshots(&self, dataset_id: DatasetId) -> Result<()> {
+        self.delete_subvolume_tree(&dataset_snapshot_container_path(dataset_id))
+    }
+
+    /// Lists the subvolumes below `path` like [`MountedFilesystem::list_subvolumes`], from the cache if nothing
+    /// changed since the last listing.
+    pub fn list_subvolumes(&self, path: &FsPathBuf) -> Result<Vec<Subvolume>> {
+        let modified = tree_modified(&path.as_pathbuf(&self.filesystem.fstree_mountpoint));
+        if let Some(subvolumes) = modified.and_then(|m| self.subvolume_cache.get(path, m)) {
+            return Ok(subvolumes);
+        }
+
+        let subvolumes = self.filesystem.list_subvolumes(path)?;
+        if let Some(modified) = modified {
+            self.subvolume_cache.insert(path.clone(), modified, subvolumes.clone());
+        }
+        Ok(subvolumes)
+    }
+
+    fn invalidate_subvolumes(&self, changed: &FsPathBuf) {
+        self.subvolume_cache.invalidate(changed);
+    }
+
+    fn create_subvolume(&self, path: &FsPathBuf) -> Result<()> {
+        let result = self.filesystem.create_subvolume(path);
+        self.invalidate_subvolumes(path);
+        result
+    }
+
+    fn create_snapshot(&self, subvolume: &Subvolume, path: &FsPathBuf) -> Result<()> {
+        let result = self.filesystem.create_snapshot(subvolume, path);
+        self.invalidate_subvolumes(path);
+        result
+    }
+
+    fn delete_subvolume(&self, path: &FsPathBuf) -> Result<()> {
+        let result = self.filesystem.delete_subvolume(path);
+        self.invalidate_subvolumes(path);
+        result
+    }
+
+    fn delete_subvolumes(&self, paths: &[&FsPathBuf], config: &SnapshotDeleteConfig) -> Result<()> {
+        let result = self
+            .filesystem
+            .delete_subvolumes(paths, config.chunk_size, config.commit);
+        for path in paths {
+            self.invalidate_subvolumes(path);
+        }
+        result
+    }
+
+    fn subvolume_exists(&self, path: &FsPathBuf) -> bool {
+        path.as_pathbuf(&self.filesystem.fstree_mountpoint).exists()
+    }
+
+    fn delete_subvolume_tree(&self, path: &FsPathBuf) -> Result<()> {
+        let result = self.filesystem.delete_subvolume_tree(path);
+        self.invalidate_subvolumes(path);
+        result
+    }
+}
+
+fn device_uuid_subs(mounted: &MountedFilesystem) -> Result<Vec<Uuid>> {
+    let device_infos = mounted
+        .filesystem
+        .devices
+        .iter()
+        .map(|d| BlockDeviceIds::lookup(d).and_then(|ids| ids.ok_or_else(|| anyhow!("missing device ids for {}", d))))
+        .collect::<Result<Vec<BlockDeviceIds>>>()
+        .context("All devices for a btrfs filesystem should resolve with blkid.")?;
+
+    device_infos
+        .iter()
+        .map(|d| {
+            d.uuid_sub
+                .context("All devices for a btrfs filesystem should have a uuid_subs.")
+        })
+        .collect::<Result<Vec<Uuid>>>()
+}
+
+fn snapshots_meta_path() -> FsPathBuf {
+    FsPathBuf::from(BLKCAPT_FS_META_DIR).join("snapshots")
+}
+
+/// Files of a dataset that snapshots and sends handle badly.
+#[derive(Debug, Default)]
+pub struct CowHazards {
+    /// Active swapfiles, btrfs can't snapshot a subvolume containing one.
+    pub swapfiles: Vec<PathBuf>,
+    /// Files and directories with copy-on-write disabled, other than the swapfiles.
+    pub nocow: Vec<PathBuf>,
+}
+
+impl CowHazards {
+    pub fn is_empty(&self) -> bool {
+        self.swapfiles.is_empty() && self.nocow.is_empty()
+    }
+}
+
+// Swapfiles are matched by device and inode, /proc/swaps has paths as mounted which may not be below `root`. Nested
+// subvolumes have a device of their own.
+fn swapfiles_within(root: &Path) -> Result<Vec<PathBuf>> {
+    let device = fs::metadata(root)?.dev();
+    let mut swapfiles = Vec::new();
+    for swapfile in active_swapfiles()? {
+        let metadata = match fs::metadata(&swapfile) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.dev() == device {
+            if let Some(path) = find_inode(root, device, metadata.ino())? {
+                swapfiles.push(path);
+            }
+        }
+    }
+    Ok(swapfiles)
+}
+
+fn find_inode(root: &Path, device: u64, inode: u64) -> Result<Option<PathBuf>> {
+    let mut found = None;
+    walk_subvolume(root, device, &mut |path, metadata| {
+        if metadata.ino() == inode {
+            found = Some(path.to_owned());
+        }
+        Ok(found.is_none())
+    })?;
+    Ok(found)
+}
+
+// Stops after `limit` paths, refusing to snapshot only needs to find one.
+fn nocow_paths(root: &Path, limit: Option<usize>) -> Result<Vec<PathBuf>> {
+    let device = fs::metadata(root)?.dev();
+    let mut paths = Vec::new();
+    walk_subvolume(root, device, &mut |path, _| {
+        match has_nocow_attribute(path) {
+            Ok(true) => paths.push(path.to_owned()),
+            Ok(false) => {}
+            Err(error) if is_not_found(&error) => {}
+            Err(error) => return Err(error),
+        }
+        Ok(limit.map_or(true, |l| paths.len() < l))
+    })?;
+    paths.sort_unstable();
+    Ok(paths)
+}
+
+// Visits the directories and regular files below `root` on `device`, without following symlinks, until `visit`
+// returns false. Entries removed while walking are skipped.
+fn walk_subvolume(root: &Path, device: u64, visit: &mut dyn FnMut(&Path, &fs::Metadata) -> Result<bool>) -> Result<()> {
+    let mut directories = vec![root.to_owned()];
+    while let Some(directory) = directories.pop() {
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound && directory != root => continue,
+            Err(error) => return Err(error).with_context(|| format!("Failed to list {:?}.", directory)),
+        };
+        for entry in entries {
+            let (path, metadata) = match entry.and_then(|e| Ok((e.path(), e.metadata()?))) {
+                Ok(entry) => entry,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            };
+            if metadata.dev() != device || !(metadata.is_dir() || metadata.is_file()) {
+                continue;
+            }
+            if !visit(&path, &metadata)? {
+                return Ok(());
+            }
+            if metadata.is_dir() {
+                directories.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::NotFound)
+}
+
+// The entries to defragment so that `exclude`, relative to `root`, is left out: the directories leading to an
+// exclusion are expanded into their other entries. Symlinks are skipped, defragment would follow them.
+fn defrag_targets(root: &Path, exclude: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut targets = Vec::new();
+    let mut directories = vec![PathBuf::new()];
+    while let Some(relative) = directories.pop() {
+        let directory = root.join(&relative);
+        for entry in fs::read_dir(&directory).with_context(|| format!("Failed to list {:?}.", directory))? {
+            let entry = entry?;
+            let entry_relative = relative.join(entry.file_name());
+            if exclude.iter().any(|e| *e == entry_relative) || entry.file_type()?.is_symlink() {
+                continue;
+            }
+            if exclude.iter().any(|e| e.starts_with(&entry_relative)) {
+                directories.push(entry_relative);
+            } else {
+                targets.push(entry.path());
+            }
+        }
+    }
+    targets.sort_unstable();
+    Ok(targets)
+}
+
+fn dataset_snapshot_container_path(dataset_id: DatasetId) -> FsPathBuf {
+    snapshots_meta_path().join(dataset_id.to_string())
 }
 
 impl Display for BtrfsPool {
@@ -122,6 +693,30 @@ impl Display for BtrfsPool {
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub struct PropertyDrift {
+    pub name: String,
+    pub expected: String,
+    pub actual: Option<String>,
+}
+
+fn property_drift(
+    properties: &BTreeMap<String, String>, mut get_property: impl FnMut(&str) -> Result<Option<String>>,
+) -> Result<Vec<PropertyDrift>> {
+    let mut drift = Vec::new();
+    for (name, expected) in properties.iter() {
+        let actual = get_property(name)?;
+        if actual.as_ref() != Some(expected) {
+            drift.push(PropertyDrift {
+                name: name.clone(),
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(drift)
+}
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct BtrfsDataset {
@@ -147,18 +742,75 @@ impl BtrfsDataset {
             .exists()
         {
             slog_scope::info!("Attached to new dataset. Creating local snap container.");
-            dataset.pool.filesystem.create_subvolume(&snapshot_path)?;
+            dataset.pool.create_subvolume(&snapshot_path)?;
         }
 
         Ok(dataset)
     }
 
     pub fn create_local_snapshot(self: &Arc<Self>) -> Result<BtrfsDatasetSnapshot> {
-        let now = Utc::now();
-        let snapshot_path = self
-            .snapshot_container_path()
-            .join(now.format("%FT%H-%M-%SZ").to_string());
-        self.pool.filesystem.create_snapshot(&self.subvolume, &snapshot_path)?;
+        self.create_local_snapshot_at(Utc::now())
+    }
+
+    pub fn create_local_snapshot_at(self: &Arc<Self>, now: DateTime<Utc>) -> Result<BtrfsDatasetSnapshot> {
+        self.take_snapshot(now, None)
+    }
+
+    /// Takes a snapshot kept apart from the scheduled ones, it is neither synced nor pruned by the retention rules.
+    pub fn create_tagged_snapshot(self: &Arc<Self>, tag: &str) -> Result<BtrfsDatasetSnapshot> {
+        validate_snapshot_tag(tag)?;
+        self.take_snapshot(Utc::now(), Some(tag))
+    }
+
+    fn take_snapshot(self: &Arc<Self>, now: DateTime<Utc>, tag: Option<&str>) -> Result<BtrfsDatasetSnapshot> {
+        self.pool.ensure_space_for_writes()?;
+        self.ensure_snapshottable()?;
+        if let Some(quota) = &self.model.quota {
+            let snapshots = self.snapshots()?;
+            let paths = snapshots.iter().map(|s| s.path()).collect::<Vec<_>>();
+            check_snapshot_quota(
+                &self.pool.filesystem,
+                quota,
+                &format!("Dataset {}", self.model.name()),
+                &self.snapshot_container_path(),
+                &paths,
+            )?;
+        }
+        let quiesced = match &self.model.quiesce {
+            Some(config) => Some(quiesce::quiescer(config).quiesce()?),
+            None => None,
+        };
+        let result = self.create_snapshots(now, tag);
+        match quiesced {
+            Some(quiesced) => {
+                let released = quiesced.release();
+                let snapshot = result?;
+                released.map(|_| snapshot)
+            }
+            None => result,
+        }
+    }
+
+    fn create_snapshots(self: &Arc<Self>, now: DateTime<Utc>, tag: Option<&str>) -> Result<BtrfsDatasetSnapshot> {
+        let snapshot_name = match tag {
+            Some(tag) => format_tagged_snapshot_name(&self.snapshot_naming(), now, tag),
+            None => format_snapshot_name(&self.snapshot_naming(), now),
+        };
+        let snapshot_path = self.snapshot_container_path().join(&snapshot_name);
+        self.pool.create_snapshot(&self.subvolume, &snapshot_path)?;
+
+        if self.model.recursive {
+            for nested in self.nested_subvolumes()? {
+                let relative_path = nested
+                    .path
+                    .strip_prefix(&self.subvolume.path)
+                    .expect("nested subvolumes are always below the dataset");
+                let nested_path = self
+                    .snapshot_container_path()
+                    .join(nested_snapshot_name(&snapshot_name, relative_path));
+                self.pool.create_snapshot(&nested, &nested_path)?;
+            }
+        }
 
         self.pool
             .filesystem
@@ -171,32 +823,62 @@ impl BtrfsDataset {
     }
 
     pub fn snapshots(self: &Arc<Self>) -> Result<Vec<BtrfsDatasetSnapshot>> {
+        let namings = self.configured_snapshot_namings();
         let mut snapshots = self
             .pool
-            .filesystem
             .list_subvolumes(&self.snapshot_container_path())?
             .into_iter()
             .filter_map(|s| {
-                match NaiveDateTime::parse_from_str(
+                match parse_snapshot_name(
+                    &namings,
                     &s.path
                         .file_name()
                         .expect("Snapshot path should never end in ..")
                         .to_string_lossy(),
-                    "%FT%H-%M-%SZ",
                 ) {
-                    Ok(d) => {
+                    Some(datetime) => {
                         if s.parent_uuid.is_none() && s.received_uuid.is_none() {
                             slog_scope::trace!("invalid dataset snapshot. subvolume {} has no parent", s.uuid);
                             None
                         } else {
                             Some(BtrfsDatasetSnapshot {
                                 subvolume: s,
-                                datetime: DateTime::<Utc>::from_utc(d, Utc),
+                                datetime,
                                 dataset: Arc::clone(self),
                             })
                         }
                     }
-                    Err(_) => None,
+                    None => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        snapshots.sort_unstable_by_key(|s| s.datetime);
+        Ok(snapshots)
+    }
+
+    /// The snapshots taken with `tag`, or with any tag when none is given, oldest first.
+    pub fn tagged_snapshots(self: &Arc<Self>, tag: Option<&str>) -> Result<Vec<BtrfsDatasetSnapshot>> {
+        let namings = self.configured_snapshot_namings();
+        let mut snapshots = self
+            .pool
+            .list_subvolumes(&self.snapshot_container_path())?
+            .into_iter()
+            .filter_map(|s| {
+                let name = s
+                    .path
+                    .file_name()
+                    .expect("Snapshot path should never end in ..")
+                    .to_string_lossy()
+                    .into_owned();
+                match parse_tagged_snapshot_name(&namings, &name) {
+                    Some((datetime, snapshot_tag)) if tag.map_or(true, |t| t == snapshot_tag) => {
+                        Some(BtrfsDatasetSnapshot {
+                            subvolume: s,
+                            datetime,
+                            dataset: Arc::clone(self),
+                        })
+                    }
+                    _ => None,
                 }
             })
             .collect::<Vec<_>>();
@@ -204,16 +886,156 @@ impl BtrfsDataset {
         Ok(snapshots)
     }
 
+    /// Deletes the oldest snapshots taken with `tag` beyond the number kept of it, returns the deleted ones.
+    pub fn prune_tagged_snapshots(self: &Arc<Self>, tag: &str) -> Result<Vec<BtrfsDatasetSnapshot>> {
+        let keep = self
+            .model
+            .tagged_retention
+            .get(tag)
+            .map_or(DEFAULT_TAGGED_KEEP, |keep| keep.get());
+        let mut snapshots = self.tagged_snapshots(Some(tag))?;
+        let excess = snapshots.len().saturating_sub(keep as usize);
+        let pruned = snapshots.drain(..excess).collect::<Vec<_>>();
+        for snapshot in pruned.iter() {
+            snapshot.delete()?;
+        }
+        Ok(pruned)
+    }
+
+    /// Moves snapshots of the dataset made by another tool into the snapshot container, renamed to the blockcaptain
+    /// naming scheme so their history and incremental parents are kept. Returns the adopted snapshot paths.
+    pub fn adopt_snapshots(&self, naming: SnapshotNaming) -> Result<Vec<FsPathBuf>> {
+        let filesystem = &self.pool.filesystem;
+        let container_path = self.snapshot_container_path();
+        let snapshot_naming = self.snapshot_naming();
+        let mut adopted = Vec::new();
+        for subvolume in filesystem.list_nested_subvolumes(&FsPathBuf::from(""))? {
+            if subvolume.parent_uuid != Some(self.uuid()) || subvolume.path.strip_prefix(&container_path).is_some() {
+                continue;
+            }
+
+            let source = subvolume.path.as_pathbuf(&filesystem.fstree_mountpoint);
+            let datetime = match naming.snapshot_datetime(&source) {
+                Some(datetime) => datetime,
+                None => continue,
+            };
+            let target_path = container_path.join(format_snapshot_name(&snapshot_naming, datetime));
+            let target = target_path.as_pathbuf(&filesystem.fstree_mountpoint);
+            if target.exists() {
+                slog_scope::warn!(
+                    "Skipping snapshot {:?}, a snapshot for {} already exists.",
+                    subvolume.path,
+                    datetime
+                );
+                continue;
+            }
+
+            fs::rename(&source, &target).with_context(|| format!("Failed to move snapshot {:?}.", subvolume.path))?;
+            adopted.push(target_path);
+        }
+        Ok(adopted)
+    }
+
+    /// Files of the dataset that snapshots and sends handle badly. Walks the whole dataset, nested subvolumes aside.
+    pub fn cow_hazards(&self) -> Result<CowHazards> {
+        let root = self.subvolume.path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        let swapfiles = swapfiles_within(&root)?;
+        let mut nocow = nocow_paths(&root, None)?;
+        nocow.retain(|p| !swapfiles.contains(p));
+        Ok(CowHazards { swapfiles, nocow })
+    }
+
+    fn ensure_snapshottable(&self) -> Result<()> {
+        let root = self.subvolume.path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        if let Some(swapfile) = swapfiles_within(&root)?.first() {
+            bail!(
+                "Dataset {} contains the active swapfile {:?}, which btrfs refuses to snapshot. Move it to a \
+                 subvolume of its own.",
+                self.model.name(),
+                swapfile
+            );
+        }
+        if self.model.nocow == NocowPolicy::Refuse {
+            if let Some(first) = nocow_paths(&root, Some(1))?.first() {
+                bail!(
+                    "Dataset {} contains {:?} without copy-on-write and refuses to snapshot paths without it.",
+                    self.model.name(),
+                    first
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn nested_subvolumes(&self) -> Result<Vec<Subvolume>> {
+        self.pool.filesystem.list_nested_subvolumes(&self.subvolume.path)
+    }
+
+    /// Defragments the dataset subvolume, leaving out the configured exclusions. Snapshots live outside the dataset
+    /// and nested subvolumes are not crossed, so neither is ever rewritten.
+    pub fn defrag(&self) -> Result<Defrag> {
+        let config = self
+            .model
+            .defrag
+            .as_ref()
+            .context("Defragmentation is not configured.")?;
+        self.pool.ensure_space_for_writes()?;
+        let root = self.subvolume.path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        let paths = if config.exclude.is_empty() {
+            vec![root]
+        } else {
+            defrag_targets(&root, &config.exclude)?
+        };
+        if paths.is_empty() {
+            bail!(
+                "Dataset {} has nothing left to defragment after exclusions.",
+                self.model.name()
+            );
+        }
+        let compression = config.compression.map(|c| c.to_string());
+        Ok(self
+            .pool
+            .filesystem
+            .defrag(&paths, config.target_extent_size, compression.as_deref()))
+    }
+
+    pub fn changed_since(&self, snapshot: &BtrfsDatasetSnapshot) -> Result<bool> {
+        let filesystem = &self.pool.filesystem;
+        let generation = filesystem.subvolume_generation(snapshot.path())?;
+        filesystem.subvolume_changed_since(&self.subvolume.path, generation)
+    }
+
     pub fn latest_snapshot(self: &Arc<Self>) -> Result<Option<BtrfsDatasetSnapshot>> {
         let mut snapshots = self.snapshots()?;
         Ok(snapshots.pop())
     }
 
     pub fn snapshot_container_path(&self) -> FsPathBuf {
-        let mut builder = FsPathBuf::from(BLKCAPT_FS_META_DIR);
-        builder.push("snapshots");
-        builder.push(self.model.id().to_string());
-        builder
+        dataset_snapshot_container_path(self.model.dataset_id())
+    }
+
+    /// The dataset's snapshot name format, falling back to the pool's and then the default.
+    pub fn snapshot_naming(&self) -> SnapshotNameFormat {
+        self.model
+            .snapshot_naming
+            .as_ref()
+            .or_else(|| self.pool.model.snapshot_naming.as_ref())
+            .cloned()
+            .unwrap_or_else(|| SnapshotNameFormat {
+                format: DEFAULT_SNAPSHOT_FORMAT.to_owned(),
+                local_time: false,
+            })
+    }
+
+    /// The snapshot name formats configured for the dataset and its pool, so snapshots taken with the pool's format
+    /// are still recognized after the dataset gets its own.
+    fn configured_snapshot_namings(&self) -> Vec<SnapshotNameFormat> {
+        self.model
+            .snapshot_naming
+            .iter()
+            .chain(self.pool.model.snapshot_naming.iter())
+            .cloned()
+            .collect()
     }
 
     pub fn uuid(&self) -> Uuid {
@@ -230,10 +1052,46 @@ impl BtrfsDataset {
             .subvolume_by_uuid(model.uuid())
             .context("Can't locate subvolume for existing dataset.")?;
 
-        Ok(Self {
+        let dataset = Self {
             model,
             subvolume,
             pool: Arc::clone(pool),
+        };
+
+        match dataset.property_drift() {
+            Ok(drift) => {
+                for d in drift {
+                    slog_scope::warn!(
+                        "Dataset {} property {} is {}, but {} is configured.",
+                        dataset.model.name(),
+                        d.name,
+                        d.actual.as_deref().unwrap_or("unset"),
+                        d.expected
+                    );
+                }
+            }
+            Err(e) => slog_scope::warn!("Failed to check properties of dataset {}: {}", dataset.model.name(), e),
+        }
+
+        Ok(dataset)
+    }
+
+    /// Stores the properties in the model and applies them to the subvolume.
+    pub fn set_properties(&mut self, properties: BTreeMap<String, String>) -> Result<()> {
+        self.model.properties = properties;
+        self.apply_properties()
+    }
+
+    pub fn apply_properties(&self) -> Result<()> {
+        for (name, value) in self.model.properties.iter() {
+            self.pool.filesystem.set_property(&self.subvolume.path, name, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn property_drift(&self) -> Result<Vec<PropertyDrift>> {
+        property_drift(&self.model.properties, |name| {
+            self.pool.filesystem.get_property(&self.subvolume.path, name)
         })
     }
 
@@ -253,6 +1111,97 @@ impl BtrfsDataset {
     }
 }
 
+// Nested subvolume snapshots are stored beside their top-level snapshot and named after it.
+fn nested_snapshot_name(snapshot_name: &str, relative_path: &Path) -> String {
+    format!(
+        "{}@{}",
+        snapshot_name,
+        relative_path.to_string_lossy().replace('%', "%25").replace('/', "%2F")
+    )
+}
+
+// Fails when one more snapshot would exceed the quota.
+fn check_snapshot_quota(
+    filesystem: &MountedFilesystem, quota: &SnapshotQuota, owner: &str, container_path: &FsPathBuf,
+    snapshot_paths: &[&FsPathBuf],
+) -> Result<()> {
+    if let Some(max_snapshots) = quota.max_snapshots {
+        if snapshot_paths.len() >= usize::try_from(max_snapshots.get()).expect("u32 always fits in usize") {
+            bail!("{} has reached its quota of {} snapshots.", owner, max_snapshots);
+        }
+    }
+    if let Some(max_bytes) = quota.max_exclusive_bytes {
+        let exclusive = snapshots_exclusive_size(filesystem, container_path, snapshot_paths)?;
+        if exclusive >= max_bytes {
+            bail!(
+                "{} has reached its quota of {} exclusive bytes, {} in use.",
+                owner,
+                max_bytes,
+                exclusive
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The space only the snapshots in a container subvolume use together, extents shared between them included, as
+/// accounted by a level 1 qgroup numbered after the container. Snapshots not in the qgroup yet are added to it, which
+/// requires rescanning the quota.
+fn snapshots_exclusive_size(
+    filesystem: &MountedFilesystem, container_path: &FsPathBuf, snapshot_paths: &[&FsPathBuf],
+) -> Result<u64> {
+    let (container_qgroup, _) = filesystem.subvolume_qgroup(container_path)?;
+    let qgroup_id = format!("1/{}", container_qgroup.trim_start_matches("0/"));
+    let mut qgroups = filesystem.qgroups()?;
+    if !qgroups.contains_key(&qgroup_id) {
+        filesystem.create_qgroup(&qgroup_id)?;
+    }
+
+    let mut assigned = false;
+    for path in snapshot_paths {
+        let (snapshot_qgroup, qgroup) = filesystem.subvolume_qgroup(path)?;
+        if !qgroup.parents.contains(&qgroup_id) {
+            filesystem.assign_qgroup(&snapshot_qgroup, &qgroup_id)?;
+            assigned = true;
+        }
+    }
+    if assigned {
+        filesystem.rescan_quota()?;
+        qgroups = filesystem.qgroups()?;
+    }
+    Ok(qgroups.get(&qgroup_id).map_or(0, |q| q.exclusive))
+}
+
+fn check_compressed_send(compressed: bool) -> Result<()> {
+    if compressed && !compressed_send_supported() {
+        bail!("btrfs send --compressed-data requires btrfs-progs 5.18 and a kernel with send stream version 2");
+    }
+    Ok(())
+}
+
+fn nested_snapshot_prefix(snapshot_path: &FsPathBuf) -> String {
+    // Only the received suffix is stripped, snapshot name formats may contain other dots.
+    let name = snapshot_path
+        .file_name()
+        .expect("Snapshot path always has filename.")
+        .to_string_lossy();
+    format!("{}@", name.strip_suffix(".bcrcv").unwrap_or(&name))
+}
+
+fn nested_snapshots(pool: &BtrfsPool, snapshot_path: &FsPathBuf) -> Result<Vec<Subvolume>> {
+    let prefix = nested_snapshot_prefix(snapshot_path);
+    let container_path = snapshot_path.parent().expect("Snapshot path always has a parent.");
+    Ok(pool
+        .list_subvolumes(&container_path)?
+        .into_iter()
+        .filter(|s| {
+            s.path
+                .file_name()
+                .map_or(false, |n| n.to_string_lossy().starts_with(&prefix))
+        })
+        .collect())
+}
+
 impl Display for BtrfsDataset {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}/{}", self.pool, self.model().name(),))
@@ -272,6 +1221,40 @@ pub trait Snapshot: Display {
 pub trait BtrfsSnapshot: Snapshot {
     fn uuid(&self) -> Uuid;
     fn delete(&self) -> Result<()>;
+    fn pool(&self) -> &BtrfsPool;
+    /// The snapshot's subvolume followed by the nested snapshots taken with it.
+    fn subvolume_paths(&self) -> Result<Vec<FsPathBuf>>;
+}
+
+/// Deletes snapshots of one pool in as few `btrfs subvolume delete` invocations as the config allows, returning a
+/// result for each snapshot in order.
+pub fn batch_delete_snapshots<T: BtrfsSnapshot>(snapshots: &[&T], config: &SnapshotDeleteConfig) -> Vec<Result<()>> {
+    let pool = match snapshots.first() {
+        Some(snapshot) => snapshot.pool(),
+        None => return Vec::new(),
+    };
+    let subvolume_paths = snapshots.iter().map(|s| s.subvolume_paths()).collect::<Vec<_>>();
+    let batch = subvolume_paths
+        .iter()
+        .filter_map(|p| p.as_ref().ok())
+        .flatten()
+        .collect::<Vec<_>>();
+    let batch_result = pool.delete_subvolumes(&batch, config);
+
+    snapshots
+        .iter()
+        .zip(subvolume_paths)
+        .map(|(snapshot, paths)| {
+            let paths = paths?;
+            match &batch_result {
+                // btrfs reports all failures of an invocation at once, what is left shows which snapshots failed.
+                Err(e) if paths.iter().any(|p| pool.subvolume_exists(p)) => {
+                    Err(anyhow!("Failed to delete snapshot {}: {:#}", snapshot, e))
+                }
+                _ => Ok(()),
+            }
+        })
+        .collect()
 }
 
 #[derive(Clone, Derivative)]
@@ -300,11 +1283,52 @@ impl BtrfsDatasetSnapshot {
         self.subvolume.received_uuid
     }
 
-    pub fn send(&self, parent: Option<&BtrfsDatasetSnapshot>) -> SnapshotSender {
-        self.dataset
-            .pool
-            .filesystem
-            .send_subvolume(self.path(), parent.map(|s| s.path()))
+    pub fn changed_since(&self, parent: &BtrfsDatasetSnapshot) -> Result<bool> {
+        let filesystem = &self.dataset.pool.filesystem;
+        let generation = filesystem.subvolume_generation(parent.path())?;
+        filesystem.subvolume_changed_since(self.path(), generation)
+    }
+
+    pub fn estimate_delta_size(&self, parent: Option<&BtrfsDatasetSnapshot>) -> Result<u64> {
+        let filesystem = &self.dataset.pool.filesystem;
+        let generation = match parent {
+            Some(parent) => filesystem.subvolume_generation(parent.path())?,
+            None => 0,
+        };
+        filesystem.subvolume_changed_bytes_since(self.path(), generation)
+    }
+
+    pub fn nested_snapshots(&self) -> Result<Vec<Subvolume>> {
+        nested_snapshots(&self.dataset.pool, self.path())
+    }
+
+    pub fn send(&self, parent: Option<&BtrfsDatasetSnapshot>, compressed: bool) -> Result<SnapshotSender> {
+        check_compressed_send(compressed)?;
+        let filesystem = &self.dataset.pool.filesystem;
+        let nested = self.nested_snapshots()?;
+        if nested.is_empty() {
+            return Ok(filesystem.send_subvolume(self.path(), parent.map(|s| s.path()), compressed));
+        }
+
+        // The set is sent in one stream and btrfs picks the matching parent for each member from the clone
+        // sources. Any member without a counterpart in the parent set forces a full send of the whole set.
+        let parent_nested = parent.map_or_else(|| Ok(Vec::new()), |p| p.nested_snapshots())?;
+        let clone_sources: Vec<&FsPathBuf> = match parent {
+            Some(parent)
+                if nested
+                    .iter()
+                    .all(|n| parent_nested.iter().any(|p| p.parent_uuid == n.parent_uuid)) =>
+            {
+                iter::once(parent.path())
+                    .chain(parent_nested.iter().map(|s| &s.path))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        let paths = iter::once(self.path())
+            .chain(nested.iter().map(|s| &s.path))
+            .collect::<Vec<_>>();
+        Ok(filesystem.send_subvolume_set(&paths, &clone_sources, compressed))
     }
 
     pub fn state(&self) -> BtrfsDatasetSnapshotState {
@@ -328,12 +1352,25 @@ impl BtrfsSnapshot for BtrfsDatasetSnapshot {
     }
 
     fn delete(&self) -> Result<()> {
-        self.dataset.pool.filesystem.delete_subvolume(self.path())
+        for nested in self.nested_snapshots()? {
+            self.dataset.pool.delete_subvolume(&nested.path)?;
+        }
+        self.dataset.pool.delete_subvolume(self.path())
         // .map_err(|e| SnapshotDeleteError {
         //     source: e,
         //     snapshot: self,
         // })
     }
+
+    fn pool(&self) -> &BtrfsPool {
+        &self.dataset.pool
+    }
+
+    fn subvolume_paths(&self) -> Result<Vec<FsPathBuf>> {
+        Ok(iter::once(self.path().clone())
+            .chain(self.nested_snapshots()?.into_iter().map(|s| s.path))
+            .collect())
+    }
 }
 
 impl Snapshot for BtrfsDatasetSnapshot {
@@ -386,16 +1423,40 @@ pub enum BtrfsDatasetSnapshotState {
 pub struct SnapshotHandle {
     pub datetime: DateTime<Utc>,
     pub uuid: Uuid,
+    pub parent_uuid: Option<Uuid>,
+    pub received_uuid: Option<Uuid>,
+}
+
+impl Snapshot for SnapshotHandle {
+    fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
 }
 
-impl<T> From<&T> for SnapshotHandle
-where
-    T: BtrfsSnapshot,
-{
-    fn from(snapshot: &T) -> Self {
+impl Display for SnapshotHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+    }
+}
+
+impl From<&BtrfsDatasetSnapshot> for SnapshotHandle {
+    fn from(snapshot: &BtrfsDatasetSnapshot) -> Self {
         Self {
             datetime: snapshot.datetime(),
             uuid: snapshot.uuid(),
+            parent_uuid: snapshot.parent_uuid(),
+            received_uuid: snapshot.received_uuid(),
+        }
+    }
+}
+
+impl From<&BtrfsContainerSnapshot> for SnapshotHandle {
+    fn from(snapshot: &BtrfsContainerSnapshot) -> Self {
+        Self {
+            datetime: snapshot.datetime(),
+            uuid: snapshot.uuid(),
+            parent_uuid: snapshot.parent_uuid(),
+            received_uuid: Some(snapshot.received_uuid()),
         }
     }
 }
@@ -422,20 +1483,18 @@ impl BtrfsContainer {
         Ok(dataset)
     }
 
-    pub fn source_dataset_ids(&self) -> Result<Vec<EntityId>> {
+    pub fn source_dataset_ids(&self) -> Result<Vec<DatasetId>> {
         Ok(self
             .pool
-            .filesystem
             .list_subvolumes(&self.subvolume.path)?
             .into_iter()
-            .filter_map(|s| EntityId::from_str(&s.path.file_name().unwrap_or_default().to_string_lossy()).ok())
+            .filter_map(|s| DatasetId::from_str(&s.path.file_name().unwrap_or_default().to_string_lossy()).ok())
             .collect::<Vec<_>>())
     }
 
-    pub fn snapshots(self: &Arc<Self>, dataset_id: EntityId) -> Result<Vec<BtrfsContainerSnapshot>> {
+    pub fn snapshots(self: &Arc<Self>, dataset_id: DatasetId) -> Result<Vec<BtrfsContainerSnapshot>> {
         let mut snapshots = self
             .pool
-            .filesystem
             .list_subvolumes(&self.snapshot_container_path(dataset_id))?
             .into_iter()
             .filter(|s| s.path.extension() == Some("bcrcv".as_ref()))
@@ -446,47 +1505,130 @@ impl BtrfsContainer {
     }
 
     pub fn snapshot_by_datetime(
-        self: &Arc<Self>, dataset_id: EntityId, datetime: DateTime<Utc>,
+        self: &Arc<Self>, dataset_id: DatasetId, datetime: DateTime<Utc>,
     ) -> Result<BtrfsContainerSnapshot> {
-        let name = datetime.format("%FT%H-%M-%SZ.bcrcv").to_string();
+        let name = datetime.format(DEFAULT_SNAPSHOT_FORMAT).to_string() + ".bcrcv";
         self.snapshot_by_name(dataset_id, &name)
     }
 
-    pub fn snapshot_container_path(&self, dataset_id: EntityId) -> FsPathBuf {
+    pub fn snapshot_container_path(&self, dataset_id: DatasetId) -> FsPathBuf {
         self.subvolume.path.join(dataset_id.to_string())
     }
 
-    pub fn receive(self: &Arc<Self>, dataset_id: EntityId) -> Result<SnapshotReceiver> {
+    /// The space used only by the snapshots of a dataset. Requires quotas enabled on the filesystem.
+    pub fn snapshots_exclusive_size(self: &Arc<Self>, dataset_id: DatasetId) -> Result<u64> {
+        let snapshots = self.snapshots(dataset_id)?;
+        let paths = snapshots.iter().map(|s| s.path()).collect::<Vec<_>>();
+        snapshots_exclusive_size(&self.pool.filesystem, &self.snapshot_container_path(dataset_id), &paths)
+    }
+
+    pub fn purge_dataset(&self, dataset_id: DatasetId) -> Result<()> {
+        self.pool
+            .delete_subvolume_tree(&self.snapshot_container_path(dataset_id))
+    }
+
+    // Not a dataset id, so source_dataset_ids never lists it.
+    fn restore_drill_path(&self) -> FsPathBuf {
+        self.subvolume.path.join(".restore-drill")
+    }
+
+    /// Deletes what a restore drill received, including copies left behind by an interrupted drill.
+    pub fn clear_restore_drill(&self) -> Result<()> {
+        let path = self.restore_drill_path();
+        if self.pool.subvolume_exists(&path) {
+            self.pool.delete_subvolume_tree(&path)?;
+        }
+        Ok(())
+    }
+
+    pub fn receive(self: &Arc<Self>, dataset_id: DatasetId) -> Result<SnapshotReceiver> {
+        self.pool.ensure_space_for_writes()?;
         let dataset_container_path = self.snapshot_container_path(dataset_id);
         let dataset_container_exists = self.pool.filesystem.subvolume_by_path(&dataset_container_path).is_ok();
 
         if !dataset_container_exists {
-            self.pool.filesystem.create_subvolume(&dataset_container_path)?;
+            self.pool.create_subvolume(&dataset_container_path)?;
+        } else if let Some(quota) = &self.model.quota {
+            let snapshots = self.snapshots(dataset_id)?;
+            let paths = snapshots.iter().map(|s| s.path()).collect::<Vec<_>>();
+            check_snapshot_quota(
+                &self.pool.filesystem,
+                quota,
+                &format!("Container {} for dataset {}", self.model.name(), dataset_id),
+                &dataset_container_path,
+                &paths,
+            )?;
         }
 
         Ok(self.pool.filesystem.receive_subvolume(&dataset_container_path))
     }
 
+    /// Renames a received snapshot to the sealed name of its datetime, whatever name format the dataset uses.
     pub fn seal_snapshot(
-        self: &Arc<Self>, dataset_id: EntityId, incoming_name: &str,
+        self: &Arc<Self>, dataset_id: DatasetId, incoming_name: &str, datetime: DateTime<Utc>,
     ) -> Result<BtrfsContainerSnapshot> {
-        let final_name = incoming_name.to_owned() + ".bcrcv";
+        // Snapshots forwarded from another container arrive with their sealed names.
+        let incoming_name = incoming_name.strip_suffix(".bcrcv").unwrap_or(incoming_name);
+        let sealed_name = datetime.format(DEFAULT_SNAPSHOT_FORMAT).to_string();
+        let final_name = sealed_name.clone() + ".bcrcv";
         let container_path = self
             .snapshot_container_path(dataset_id)
             .as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
 
-        let source_path = container_path.join(incoming_name);
-        let destination_path = container_path.join(&final_name);
-        fs::rename(&source_path, &destination_path).with_context(|| {
-            format!(
-                "Failed to rename the snapshot from '{:?}' to '{:?}' after successfully receiving it.",
-                source_path, destination_path
-            )
-        })?;
+        let nested_names = nested_snapshots(
+            &self.pool,
+            &self.snapshot_container_path(dataset_id).join(incoming_name),
+        )?
+        .into_iter()
+        .filter(|s| s.path.extension() != Some("bcrcv".as_ref()))
+        .filter_map(|s| s.path.file_name().map(|n| n.to_string_lossy().into_owned()));
+        for name in iter::once(incoming_name.to_owned()).chain(nested_names) {
+            let source_path = container_path.join(&name);
+            let destination_path = container_path.join(name.replacen(incoming_name, &sealed_name, 1) + ".bcrcv");
+            // Snapshots forwarded with their sealed names are already in place, anything else missing is an error.
+            if !source_path.exists() && destination_path.exists() {
+                continue;
+            }
+            fs::rename(&source_path, &destination_path).with_context(|| {
+                format!(
+                    "Failed to rename the snapshot from '{:?}' to '{:?}' after successfully receiving it.",
+                    source_path, destination_path
+                )
+            })?;
+        }
+        self.pool
+            .invalidate_subvolumes(&self.snapshot_container_path(dataset_id));
 
         self.snapshot_by_name(dataset_id, &final_name)
     }
 
+    /// Records where a sealed snapshot came from in the provenance file of the dataset's directory.
+    pub fn record_provenance(
+        &self, dataset_id: DatasetId, dataset_name: &str, snapshot: &BtrfsContainerSnapshot,
+    ) -> Result<()> {
+        let dataset_dir = self
+            .snapshot_container_path(dataset_id)
+            .as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        let snapshot_name = snapshot
+            .path()
+            .file_name()
+            .expect("Snapshot path always has filename.")
+            .to_string_lossy();
+        provenance::record_provenance(
+            &dataset_dir,
+            dataset_id,
+            dataset_name,
+            &snapshot_name,
+            SnapshotProvenance {
+                origin_uuid: snapshot.received_uuid(),
+                source_hostname: hostname().ok(),
+                source_machine_id: machine_id().ok(),
+                blockcaptain_version: env!("CARGO_PKG_VERSION").to_owned(),
+                received: Utc::now(),
+            },
+        )
+    }
+
     pub fn validate(pool: &Arc<BtrfsPool>, model: BtrfsContainerEntity) -> Result<Self> {
         let subvolume = pool
             .filesystem
@@ -508,7 +1650,7 @@ impl BtrfsContainer {
         self.model
     }
 
-    fn snapshot_by_name(self: &Arc<Self>, dataset_id: EntityId, name: &str) -> Result<BtrfsContainerSnapshot> {
+    fn snapshot_by_name(self: &Arc<Self>, dataset_id: DatasetId, name: &str) -> Result<BtrfsContainerSnapshot> {
         self.pool
             .filesystem
             .subvolume_by_path(&self.snapshot_container_path(dataset_id).join(name))
@@ -541,7 +1683,7 @@ impl BtrfsContainer {
 }
 
 fn parse_snapshot_label(value: &str) -> Result<DateTime<Utc>> {
-    NaiveDateTime::parse_from_str(value, "%FT%H-%M-%SZ")
+    NaiveDateTime::parse_from_str(value, DEFAULT_SNAPSHOT_FORMAT)
         .map(|naive_datetime| DateTime::<Utc>::from_utc(naive_datetime, Utc))
         .context("unable to parse snapshot label")
 }
@@ -575,6 +1717,81 @@ impl BtrfsContainerSnapshot {
             .received_uuid
             .expect("container snapshots are always received")
     }
+
+    pub fn canonical_path(&self) -> PathBuf {
+        self.path()
+            .as_pathbuf(&self.container.pool.filesystem.fstree_mountpoint)
+    }
+
+    pub fn changed_since(&self, parent: &BtrfsContainerSnapshot) -> Result<bool> {
+        let filesystem = &self.container.pool.filesystem;
+        let generation = filesystem.subvolume_generation(parent.path())?;
+        filesystem.subvolume_changed_since(self.path(), generation)
+    }
+
+    pub fn estimate_delta_size(&self, parent: Option<&BtrfsContainerSnapshot>) -> Result<u64> {
+        let filesystem = &self.container.pool.filesystem;
+        let generation = match parent {
+            Some(parent) => filesystem.subvolume_generation(parent.path())?,
+            None => 0,
+        };
+        filesystem.subvolume_changed_bytes_since(self.path(), generation)
+    }
+
+    /// Receives a full send of the snapshot into a scratch subvolume of the container, the way a restore would, and
+    /// returns where the copy is mounted. The copy carries the received uuid of the snapshot, so remove it with
+    /// [`BtrfsContainer::clear_restore_drill`] before further snapshots are received.
+    pub async fn restore_drill(&self) -> Result<PathBuf> {
+        let container = &self.container;
+        container.clear_restore_drill()?;
+        container.pool.ensure_space_for_writes()?;
+        let drill_path = container.restore_drill_path();
+        container.pool.create_subvolume(&drill_path)?;
+
+        let mut sender = self.send(None, false)?.start()?;
+        let mut receiver = container.pool.filesystem.receive_subvolume(&drill_path).start()?;
+        let copied = {
+            let mut reader = Box::pin(sender.reader());
+            let mut writer = Box::pin(receiver.writer());
+            tokio::io::copy(&mut reader, &mut writer).await
+        };
+        let sent = sender.wait().await;
+        let received = receiver.wait().await;
+        container.pool.invalidate_subvolumes(&drill_path);
+        sent.context("btrfs send of the restore drill failed")?;
+        let name = received.context("btrfs receive of the restore drill failed")?;
+        copied.context("failed to copy the restore drill stream")?;
+
+        Ok(drill_path
+            .join(name)
+            .as_pathbuf(&container.pool.filesystem.fstree_mountpoint))
+    }
+
+    /// Forwards a received snapshot. btrfs sends the received uuid of the source, so the copy on the next
+    /// container keeps the lineage of the original dataset snapshot.
+    pub fn send(&self, parent: Option<&BtrfsContainerSnapshot>, compressed: bool) -> Result<SnapshotSender> {
+        check_compressed_send(compressed)?;
+        let filesystem = &self.container.pool.filesystem;
+        let nested = nested_snapshots(&self.container.pool, self.path())?;
+        if nested.is_empty() {
+            return Ok(filesystem.send_subvolume(self.path(), parent.map(|s| s.path()), compressed));
+        }
+
+        let clone_sources = match parent {
+            Some(parent) => iter::once(parent.path().clone())
+                .chain(
+                    nested_snapshots(&self.container.pool, parent.path())?
+                        .into_iter()
+                        .map(|s| s.path),
+                )
+                .collect(),
+            None => Vec::new(),
+        };
+        let paths = iter::once(self.path())
+            .chain(nested.iter().map(|s| &s.path))
+            .collect::<Vec<_>>();
+        Ok(filesystem.send_subvolume_set(&paths, &clone_sources.iter().collect::<Vec<_>>(), compressed))
+    }
 }
 
 impl BtrfsSnapshot for BtrfsContainerSnapshot {
@@ -583,7 +1800,24 @@ impl BtrfsSnapshot for BtrfsContainerSnapshot {
     }
 
     fn delete(&self) -> Result<()> {
-        self.container.pool.filesystem.delete_subvolume(self.path())
+        for nested in nested_snapshots(&self.container.pool, self.path())? {
+            self.container.pool.delete_subvolume(&nested.path)?;
+        }
+        self.container.pool.delete_subvolume(self.path())
+    }
+
+    fn pool(&self) -> &BtrfsPool {
+        &self.container.pool
+    }
+
+    fn subvolume_paths(&self) -> Result<Vec<FsPathBuf>> {
+        Ok(iter::once(self.path().clone())
+            .chain(
+                nested_snapshots(&self.container.pool, self.path())?
+                    .into_iter()
+                    .map(|s| s.path),
+            )
+            .collect())
     }
 }
 
@@ -616,6 +1850,8 @@ pub enum ObservableEventStage {
     Starting,
     Succeeded,
     Failed(String),
+    /// Noted without changing whether the event succeeded, such as a job running unusually long.
+    Warning(String),
 }
 
 pub struct ObservationRouter {
@@ -638,6 +1874,9 @@ impl ObservationRouter {
 pub struct ObservationEmitter {
     http_client: HttpsClient,
     url: String,
+    retries: u32,
+    retry_backoff: Duration,
+    helper: Option<PingHelper>,
 }
 
 impl ObservationEmitter {
@@ -645,40 +1884,568 @@ impl ObservationEmitter {
 
     pub fn new(custom_url: String) -> Self {
         Self {
-            http_client: HttpsClient::default(),
             url: custom_url,
+            ..Self::default()
         }
     }
 
+    /// The emitter of an observer, connecting with the http options and proxy of the server config. With an
+    /// unprivileged user configured, the pings are sent by a helper process running as that user.
+    pub fn for_observer(model: &HealthchecksObserverEntity) -> Result<Self> {
+        let url = model
+            .custom_url
+            .as_deref()
+            .map(secrets::reveal)
+            .transpose()
+            .context("failed to reveal observer url")?
+            .unwrap_or_else(|| String::from(Self::DEFAULT_URL));
+        let server_config = storage::load_server_config().ok();
+        let proxy = model
+            .proxy
+            .clone()
+            .or_else(|| server_config.as_ref().and_then(|c| c.proxy.clone()))
+            .map(|p| {
+                secrets::reveal(&p.url).map(|url| ProxyConfig {
+                    url,
+                    no_proxy: p.no_proxy,
+                })
+            })
+            .transpose()
+            .context("failed to reveal observer proxy")?;
+        let run_as = server_config
+            .as_ref()
+            .and_then(|c| c.unprivileged_user.as_deref())
+            .map(UnprivilegedUser::lookup)
+            .transpose()?;
+        let options = server_config.map(|c| c.http).unwrap_or_default();
+        let timeouts = HttpTimeouts {
+            connect: model.delivery.connect_timeout,
+            request: model.delivery.request_timeout,
+        };
+        let http_proxy = proxy
+            .as_ref()
+            .map(|p| HttpProxy::new(&p.url, p.no_proxy.clone()))
+            .transpose()
+            .context("invalid observer proxy")?;
+        Ok(Self {
+            http_client: HttpsClient::new(&options, timeouts, http_proxy)?,
+            url,
+            retries: model.delivery.bounded_retries(),
+            retry_backoff: model.delivery.retry_backoff,
+            helper: run_as.map(|user| PingHelper {
+                user,
+                http: options,
+                timeouts,
+                proxy,
+            }),
+        })
+    }
+
     pub async fn emit(&self, healthcheck_id: Uuid, stage: ObservableEventStage) -> Result<()> {
         let suffix = match stage {
             ObservableEventStage::Starting => "/start",
             ObservableEventStage::Succeeded => "",
             ObservableEventStage::Failed(_) => "/fail",
+            ObservableEventStage::Warning(_) => "/log",
         };
         let uri_string = format!("{}{}", &self.url, healthcheck_id.to_hyphenated());
         let uri = Uri::from_str((uri_string + suffix).as_str()).context("parsing healtcheck uri failed")?;
 
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.send(uri.clone(), &stage).await {
+                Err(e) if attempt < self.retries && !PingRejected::is_cause_of(&e) => {
+                    attempt += 1;
+                    slog_scope::debug!(
+                        "Health check attempt {} failed, retrying in {}: {:#}",
+                        attempt,
+                        humantime::format_duration(backoff),
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send(&self, uri: Uri, stage: &ObservableEventStage) -> Result<()> {
         slog_scope::trace!("Emitting health check to url: {}", uri);
-        let result = match stage {
-            ObservableEventStage::Starting | ObservableEventStage::Succeeded => self.http_client.get(uri).await,
-            ObservableEventStage::Failed(error) => self.http_client.post(uri, error).await,
+        let message = match stage {
+            ObservableEventStage::Starting | ObservableEventStage::Succeeded => None,
+            ObservableEventStage::Failed(message) | ObservableEventStage::Warning(message) => Some(message.clone()),
         };
+        match &self.helper {
+            Some(helper) => helper.send(&uri, message).await,
+            None => send_ping(&self.http_client, uri, message).await,
+        }
+    }
+}
 
-        result
-            .context("healthcheck network request failed")
-            .and_then(|r| match r.status() {
-                http::status::StatusCode::OK => Ok(()),
-                e => Err(anyhow!(e).context("healthcheck server responded with unsuccessful status")),
-            })
+/// The argument that starts blkcaptwrk or blkcaptctl as a helper sending one healthcheck ping, read as json from
+/// stdin.
+pub const PING_HELPER_ARG: &str = "--healthcheck-ping-helper";
+
+// The ping helper exits with this when the server rejected the ping, so the daemon doesn't retry it.
+const PING_REJECTED_EXIT_CODE: i32 = 2;
+
+/// Sends the ping read from stdin if the process was started with [`PING_HELPER_ARG`]. Returns the exit code then.
+pub fn run_ping_helper() -> Option<i32> {
+    if env::args().nth(1).as_deref() != Some(PING_HELPER_ARG) {
+        return None;
+    }
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("failed to start the runtime")
+        .and_then(|runtime| {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input).context("failed to read the ping")?;
+            let request = serde_json::from_slice::<PingRequest>(&input).context("invalid ping")?;
+            runtime.block_on(request.send())
+        });
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            if PingRejected::is_cause_of(&e) {
+                PING_REJECTED_EXIT_CODE
+            } else {
+                1
+            }
+        }
+    })
+}
+
+// Sends pings from a process running as the unprivileged user, so the daemon running as root doesn't handle the
+// responses of remote servers itself.
+struct PingHelper {
+    user: UnprivilegedUser,
+    http: HttpOptions,
+    timeouts: HttpTimeouts,
+    proxy: Option<ProxyConfig>,
+}
+
+impl PingHelper {
+    async fn send(&self, uri: &Uri, message: Option<String>) -> Result<()> {
+        let request = PingRequest {
+            uri: uri.to_string(),
+            message,
+            http: self.http.clone(),
+            connect_timeout: self.timeouts.connect,
+            request_timeout: self.timeouts.request,
+            proxy: self.proxy.clone(),
+        };
+        let program = env::current_exe().context("failed to locate the ping helper")?;
+        let mut command = tokio::process::Command::new(program);
+        command
+            .arg(PING_HELPER_ARG)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        self.user.apply(&mut command);
+        let mut child = command.spawn().context("failed to start the ping helper")?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin.write_all(&serde_json::to_vec(&request)?).await?;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+        if output.status.code() == Some(PING_REJECTED_EXIT_CODE) {
+            return Err(PingRejected(stderr).into());
+        }
+        exit_status_as_result(output.status).with_context(|| stderr)
+    }
+}
+
+// A ping with everything needed to send it, its secrets revealed.
+#[derive(Serialize, Deserialize)]
+struct PingRequest {
+    uri: String,
+    message: Option<String>,
+    http: HttpOptions,
+    #[serde(with = "humantime_serde")]
+    connect_timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    request_timeout: Duration,
+    proxy: Option<ProxyConfig>,
+}
+
+impl PingRequest {
+    async fn send(self) -> Result<()> {
+        let timeouts = HttpTimeouts {
+            connect: self.connect_timeout,
+            request: self.request_timeout,
+        };
+        let proxy = self
+            .proxy
+            .map(|p| HttpProxy::new(&p.url, p.no_proxy))
+            .transpose()
+            .context("invalid observer proxy")?;
+        let client = HttpsClient::new(&self.http, timeouts, proxy)?;
+        let uri = Uri::from_str(&self.uri).context("parsing healtcheck uri failed")?;
+        send_ping(&client, uri, self.message).await
+    }
+}
+
+async fn send_ping(client: &HttpsClient, uri: Uri, message: Option<String>) -> Result<()> {
+    let result = match message {
+        None => client.get(uri).await,
+        Some(message) => client.post(uri, message).await,
+    };
+
+    result
+        .context("healthcheck network request failed")
+        .and_then(|r| match r.status() {
+            http::status::StatusCode::OK => Ok(()),
+            e if e.is_server_error() => {
+                Err(anyhow!(e).context("healthcheck server responded with unsuccessful status"))
+            }
+            e => Err(PingRejected(format!("healthcheck server rejected the ping with status {}", e)).into()),
+        })
+}
+
+// A response other than a server error, which a retry of the same ping would get again.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct PingRejected(String);
+
+impl PingRejected {
+    fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<Self>().is_some()
     }
 }
 
 impl Default for ObservationEmitter {
     fn default() -> Self {
+        let delivery = HealthchecksDelivery::default();
         Self {
             http_client: HttpsClient::default(),
             url: String::from(Self::DEFAULT_URL),
+            retries: delivery.retries,
+            retry_backoff: delivery.retry_backoff,
+            helper: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn nested_snapshot_prefix_keeps_dots_in_names() {
+        let prefix = |path: &str| nested_snapshot_prefix(&FsPathBuf::from(path));
+        assert_eq!(prefix(".blkcapt/home/2021-01-02T03:04:05Z"), "2021-01-02T03:04:05Z@");
+        assert_eq!(prefix(".blkcapt/home/2021.01.02-030405"), "2021.01.02-030405@");
+        assert_eq!(
+            prefix("snapshots/home/2021-01-02T03:04:05Z.bcrcv"),
+            "2021-01-02T03:04:05Z@"
+        );
+        assert_eq!(prefix("snapshots/home/2021.01.02-030405.bcrcv"), "2021.01.02-030405@");
+    }
+
+    fn sample_tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("blkcapt-{}-{}", name, Uuid::new_v4()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/file1"), b"1").unwrap();
+        fs::write(root.join("a/b/file2"), b"2").unwrap();
+        fs::write(root.join("c"), b"3").unwrap();
+        symlink(root.join("a"), root.join("link")).unwrap();
+        root
+    }
+
+    fn walked(root: &Path, visit: &mut dyn FnMut(&Path) -> bool) -> Result<Vec<PathBuf>> {
+        let device = fs::metadata(root)?.dev();
+        let mut paths = Vec::new();
+        walk_subvolume(root, device, &mut |path, _| {
+            paths.push(path.strip_prefix(root).unwrap().to_owned());
+            Ok(visit(path))
+        })?;
+        paths.sort_unstable();
+        Ok(paths)
+    }
+
+    #[test]
+    fn walk_subvolume_visits_files_and_directories() {
+        let root = sample_tree("walk");
+        let paths = walked(&root, &mut |_| true).unwrap();
+        assert_eq!(
+            paths,
+            ["a", "a/b", "a/b/file2", "a/file1", "c"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn walk_subvolume_stops_when_visit_declines() {
+        let root = sample_tree("walk-stop");
+        assert_eq!(walked(&root, &mut |_| false).unwrap().len(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn walk_subvolume_skips_removed_entries() {
+        let root = sample_tree("walk-removed");
+        let paths = walked(&root, &mut |path| {
+            if path.ends_with("a") {
+                fs::remove_dir_all(path).unwrap();
+            }
+            true
+        })
+        .unwrap();
+        assert!(paths.contains(&PathBuf::from("a")));
+        assert!(!paths.contains(&PathBuf::from("a/b")));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn walk_subvolume_fails_for_missing_root() {
+        let root = sample_tree("walk-missing");
+        let device = fs::metadata(&root).unwrap().dev();
+        fs::remove_dir_all(&root).unwrap();
+        assert!(walk_subvolume(&root, device, &mut |_, _| Ok(true)).is_err());
+    }
+
+    #[test]
+    fn property_drift_reports_differing_and_unset_properties() {
+        let properties = [("compression", "zstd:3"), ("ro", "false"), ("label", "home")]
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        let drift = property_drift(&properties, |name| {
+            Ok(match name {
+                "compression" => Some(String::from("lzo")),
+                "ro" => Some(String::from("false")),
+                _ => None,
+            })
+        })
+        .unwrap();
+        assert_eq!(
+            drift,
+            [
+                PropertyDrift {
+                    name: String::from("compression"),
+                    expected: String::from("zstd:3"),
+                    actual: Some(String::from("lzo")),
+                },
+                PropertyDrift {
+                    name: String::from("label"),
+                    expected: String::from("home"),
+                    actual: None,
+                },
+            ]
+        );
+
+        assert!(property_drift(&properties, |_| Err(anyhow!("not a btrfs subvolume"))).is_err());
+        assert!(property_drift(&BTreeMap::new(), |_| unreachable!()).unwrap().is_empty());
+    }
+
+    fn relative_targets(root: &Path, exclude: &[&str]) -> Vec<PathBuf> {
+        let exclude = exclude.iter().map(PathBuf::from).collect::<Vec<_>>();
+        defrag_targets(root, &exclude)
+            .unwrap()
+            .into_iter()
+            .map(|path| path.strip_prefix(root).unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn defrag_targets_without_exclusions_are_top_level_entries() {
+        let root = sample_tree("defrag");
+        assert_eq!(relative_targets(&root, &[]), [PathBuf::from("a"), PathBuf::from("c")]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn defrag_targets_descend_around_exclusions() {
+        let root = sample_tree("defrag-exclude");
+        assert_eq!(relative_targets(&root, &["c"]), [PathBuf::from("a")]);
+        assert_eq!(
+            relative_targets(&root, &["a/b"]),
+            [PathBuf::from("a/file1"), PathBuf::from("c")]
+        );
+        assert_eq!(
+            relative_targets(&root, &["a/b/file2"]),
+            [PathBuf::from("a/file1"), PathBuf::from("c")]
+        );
+        assert_eq!(relative_targets(&root, &["a/b", "c"]), [PathBuf::from("a/file1")]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn defrag_targets_fail_for_missing_root() {
+        let root = sample_tree("defrag-missing");
+        fs::remove_dir_all(&root).unwrap();
+        assert!(defrag_targets(&root, &[]).is_err());
+    }
+
+    fn subvolume(path: &str) -> Subvolume {
+        Subvolume {
+            uuid: Uuid::new_v4(),
+            path: FsPathBuf::from(path),
+            parent_uuid: None,
+            received_uuid: None,
+        }
+    }
+
+    #[test]
+    fn subvolume_cache_matches_modification_time() {
+        let cache = SubvolumeCache::default();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let listing = vec![subvolume("containers/local/snap1")];
+        cache.insert(FsPathBuf::from("containers/local"), modified, listing.clone());
+
+        assert_eq!(cache.get(&FsPathBuf::from("containers/local"), modified), Some(listing));
+        assert_eq!(
+            cache.get(&FsPathBuf::from("containers/local"), modified + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(cache.get(&FsPathBuf::from("containers"), modified), None);
+    }
+
+    #[test]
+    fn subvolume_cache_invalidates_ancestors() {
+        let cache = SubvolumeCache::default();
+        let modified = SystemTime::UNIX_EPOCH;
+        for path in ["", "containers", "containers/local", "containers/remote"].iter() {
+            cache.insert(FsPathBuf::from(*path), modified, Vec::new());
+        }
+
+        cache.invalidate(&FsPathBuf::from("containers/local/snap2"));
+
+        assert!(cache.get(&FsPathBuf::from(""), modified).is_none());
+        assert!(cache.get(&FsPathBuf::from("containers"), modified).is_none());
+        assert!(cache.get(&FsPathBuf::from("containers/local"), modified).is_none());
+        assert!(cache.get(&FsPathBuf::from("containers/remote"), modified).is_some());
+    }
+
+    #[test]
+    fn tree_modified_sees_nested_directories() {
+        let root = sample_tree("tree-modified");
+        let before = tree_modified(&root).unwrap();
+
+        std::thread::sleep(Duration::from_millis(20));
+        fs::create_dir(root.join("a/b/new")).unwrap();
+
+        let after = tree_modified(&root).unwrap();
+        assert!(after > before);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn tree_modified_gives_up_on_large_trees() {
+        let root = sample_tree("tree-modified-large");
+        for i in 0..SUBVOLUME_CACHE_MAX_DIRECTORIES {
+            fs::create_dir(root.join(format!("a/b/{}", i))).unwrap();
+        }
+        assert!(tree_modified(&root).is_none());
+        fs::remove_dir_all(&root).unwrap();
+        assert!(tree_modified(&root).is_none());
+    }
+
+    #[test]
+    fn find_inode_in_nested_directory() {
+        let root = sample_tree("find-inode");
+        let device = fs::metadata(&root).unwrap().dev();
+        let inode = fs::metadata(root.join("a/b/file2")).unwrap().ino();
+        assert_eq!(find_inode(&root, device, inode).unwrap(), Some(root.join("a/b/file2")));
+        assert_eq!(find_inode(&root, device, u64::MAX).unwrap(), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn not_found_through_context() {
+        let error = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .context("failed to open")
+            .unwrap_err();
+        assert!(is_not_found(&error));
+        assert!(!is_not_found(&anyhow!("other")));
+    }
+
+    // Answers every request with `status`, counting the requests.
+    async fn healthchecks_server(status: u16) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn emit_retries_only_server_errors() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            for &(status, succeeds, expected_requests) in
+                &[(200, true, 1), (500, false, 3), (503, false, 3), (404, false, 1)]
+            {
+                let (url, requests) = healthchecks_server(status).await;
+                let emitter = ObservationEmitter {
+                    url,
+                    retries: 2,
+                    retry_backoff: Duration::from_millis(1),
+                    ..ObservationEmitter::default()
+                };
+                let result = emitter.emit(Uuid::new_v4(), ObservableEventStage::Succeeded).await;
+                assert_eq!(result.is_ok(), succeeds, "status {}", status);
+                assert_eq!(requests.load(Ordering::SeqCst), expected_requests, "status {}", status);
+            }
+        });
+    }
+
+    #[test]
+    fn emit_retries_transport_errors() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+            drop(listener);
+            let emitter = ObservationEmitter {
+                url,
+                retries: 1,
+                retry_backoff: Duration::from_millis(1),
+                ..ObservationEmitter::default()
+            };
+            let error = emitter
+                .emit(Uuid::new_v4(), ObservableEventStage::Starting)
+                .await
+                .unwrap_err();
+            assert!(!PingRejected::is_cause_of(&error));
+        });
+    }
+
+    #[test]
+    fn delivery_retries_are_bounded() {
+        let delivery = HealthchecksDelivery {
+            retries: u32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(delivery.bounded_retries(), HealthchecksDelivery::MAX_RETRIES);
+    }
+}
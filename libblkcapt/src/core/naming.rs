@@ -0,0 +1,165 @@
+use crate::model::entities::SnapshotNameFormat;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+
+/// The format of dataset snapshot names when none is configured, and always of container snapshot names.
+pub const DEFAULT_SNAPSHOT_FORMAT: &str = "%FT%H-%M-%SZ";
+
+pub fn format_snapshot_name(naming: &SnapshotNameFormat, datetime: DateTime<Utc>) -> String {
+    if naming.local_time {
+        datetime.with_timezone(&Local).format(&naming.format).to_string()
+    } else {
+        datetime.format(&naming.format).to_string()
+    }
+}
+
+/// Parses a snapshot name with each of the configured formats in order, falling back to the default format so
+/// snapshots taken before a format was configured are still recognized.
+pub fn parse_snapshot_name(namings: &[SnapshotNameFormat], name: &str) -> Option<DateTime<Utc>> {
+    namings
+        .iter()
+        .find_map(|naming| parse_with_format(naming, name))
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(name, DEFAULT_SNAPSHOT_FORMAT)
+                .ok()
+                .map(|d| DateTime::<Utc>::from_utc(d, Utc))
+        })
+}
+
+fn parse_with_format(naming: &SnapshotNameFormat, name: &str) -> Option<DateTime<Utc>> {
+    if naming.local_time {
+        // Local-time names record their offset, the same local time repeats when daylight saving time ends. Names
+        // from formats configured before the offset was required are read as the earliest matching time.
+        DateTime::parse_from_str(name, &naming.format)
+            .map(|d| d.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                NaiveDateTime::parse_from_str(name, &naming.format)
+                    .ok()
+                    .and_then(|naive| Local.from_local_datetime(&naive).earliest())
+                    .map(|d| d.with_timezone(&Utc))
+            })
+    } else {
+        NaiveDateTime::parse_from_str(name, &naming.format)
+            .ok()
+            .map(|d| DateTime::<Utc>::from_utc(d, Utc))
+    }
+}
+
+/// Tagged snapshots are named like scheduled ones followed by `~` and the tag, e.g. `2021-01-02T03-04-05Z~pre-update`,
+/// so they never parse as scheduled snapshots.
+pub fn format_tagged_snapshot_name(naming: &SnapshotNameFormat, datetime: DateTime<Utc>, tag: &str) -> String {
+    format!("{}~{}", format_snapshot_name(naming, datetime), tag)
+}
+
+pub fn parse_tagged_snapshot_name<'a>(
+    namings: &[SnapshotNameFormat], name: &'a str,
+) -> Option<(DateTime<Utc>, &'a str)> {
+    let (name, tag) = name.rsplit_once('~')?;
+    validate_snapshot_tag(tag).ok()?;
+    Some((parse_snapshot_name(namings, name)?, tag))
+}
+
+pub fn validate_snapshot_tag(tag: &str) -> Result<()> {
+    if tag.is_empty()
+        || !tag
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    {
+        bail!("snapshot tags must only contain lowercase letters, digits and '-'");
+    }
+    Ok(())
+}
+
+/// Checks that names in the format identify a snapshot to the second and can be parsed back. Local-time names must
+/// record the UTC offset to stay unique when daylight saving time ends.
+pub fn validate_snapshot_format(naming: &SnapshotNameFormat) -> Result<()> {
+    if naming.format.contains('/') || naming.format.contains('@') || naming.format.contains('~') {
+        bail!("snapshot name format must not contain '/', '@' or '~'");
+    }
+    if naming.format.ends_with(".bcrcv") {
+        bail!("snapshot name format must not end with '.bcrcv'");
+    }
+    if naming.local_time && !naming.format.contains("%z") && !naming.format.contains("%:z") {
+        bail!("local time snapshot name format must record the UTC offset with %z");
+    }
+    let sample = Utc.ymd(2021, 1, 2).and_hms(3, 4, 5);
+    let name = format_snapshot_name(naming, sample);
+    if parse_with_format(naming, &name) != Some(sample) {
+        bail!(
+            "snapshot name format must record the full date and time to the second: {}",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naming(format: &str, local_time: bool) -> SnapshotNameFormat {
+        SnapshotNameFormat {
+            format: format.to_owned(),
+            local_time,
+        }
+    }
+
+    #[test]
+    fn snapshot_name_round_trip() {
+        let datetime = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        let utc = naming("%Y-%m-%d_%H%M%S", false);
+        assert_eq!(format_snapshot_name(&utc, datetime), "2021-03-04_050607");
+        assert_eq!(parse_snapshot_name(&[utc], "2021-03-04_050607"), Some(datetime));
+
+        let local = naming("%Y-%m-%d %H.%M.%S%z", true);
+        let name = format_snapshot_name(&local, datetime);
+        assert_eq!(parse_snapshot_name(&[local.clone()], &name), Some(datetime));
+        assert_eq!(
+            parse_snapshot_name(&[local], "2021-03-04 06.06.07+0100"),
+            Some(datetime)
+        );
+    }
+
+    #[test]
+    fn snapshot_name_default_fallback() {
+        let datetime = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        let namings = [naming("%Y%m%d%H%M%S%z", true), naming("%Y-%m-%d_%H%M%S", false)];
+        assert_eq!(parse_snapshot_name(&namings, "2021-03-04T05-06-07Z"), Some(datetime));
+        assert_eq!(parse_snapshot_name(&namings, "2021-03-04_050607"), Some(datetime));
+        assert_eq!(parse_snapshot_name(&namings, "20210304050607+0000"), Some(datetime));
+        assert_eq!(parse_snapshot_name(&namings, "2021-03-04"), None);
+    }
+
+    #[test]
+    fn snapshot_format_validation() {
+        assert!(validate_snapshot_format(&naming(DEFAULT_SNAPSHOT_FORMAT, false)).is_ok());
+        assert!(validate_snapshot_format(&naming("%Y-%m-%d %H:%M:%S%z", true)).is_ok());
+        assert!(validate_snapshot_format(&naming("%Y-%m-%d %H:%M:%S", true)).is_err());
+        assert!(validate_snapshot_format(&naming("%Y-%m-%d %H:%M:%S", false)).is_ok());
+        assert!(validate_snapshot_format(&naming("%Y-%m-%d", false)).is_err());
+        assert!(validate_snapshot_format(&naming("%Y/%m/%d %H%M%S", false)).is_err());
+        assert!(validate_snapshot_format(&naming("%Y%m%d%H%M%S.bcrcv", false)).is_err());
+        assert!(validate_snapshot_format(&naming("%Y%m%d~%H%M%S", false)).is_err());
+    }
+
+    #[test]
+    fn tagged_snapshot_name_round_trip() {
+        let datetime = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+        let utc = naming(DEFAULT_SNAPSHOT_FORMAT, false);
+        let name = format_tagged_snapshot_name(&utc, datetime, "pre-update");
+        assert_eq!(name, "2021-03-04T05-06-07Z~pre-update");
+        let namings = [utc];
+        assert_eq!(
+            parse_tagged_snapshot_name(&namings, &name),
+            Some((datetime, "pre-update"))
+        );
+        assert_eq!(parse_snapshot_name(&namings, &name), None);
+        assert_eq!(parse_tagged_snapshot_name(&namings, "2021-03-04T05-06-07Z"), None);
+        assert_eq!(
+            parse_tagged_snapshot_name(&namings, "2021-03-04T05-06-07Z~pre-update@home"),
+            None
+        );
+        assert!(validate_snapshot_tag("Pre update").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::model::DatasetId;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, path::Path};
+use uuid::Uuid;
+
+/// The metadata file kept in a container's per-dataset directory.
+pub const PROVENANCE_FILE_NAME: &str = "provenance.json";
+
+/// Where the snapshots of a dataset in a container came from, so they can be identified and restored on a machine
+/// that no longer has the original configuration.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DatasetProvenance {
+    pub dataset_id: DatasetId,
+    pub dataset_name: String,
+    /// Keyed by the sealed snapshot name.
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, SnapshotProvenance>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotProvenance {
+    /// The uuid of the snapshot taken of the dataset, which every received copy carries as its received uuid.
+    pub origin_uuid: Uuid,
+    pub source_hostname: Option<String>,
+    pub source_machine_id: Option<String>,
+    pub blockcaptain_version: String,
+    pub received: DateTime<Utc>,
+}
+
+pub fn read_provenance(dataset_dir: &Path) -> Result<Option<DatasetProvenance>> {
+    let path = dataset_dir.join(PROVENANCE_FILE_NAME);
+    match fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(Some)
+            .with_context(|| format!("Failed to parse provenance file {:?}.", path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read provenance file {:?}.", path)),
+    }
+}
+
+/// Adds a received snapshot to the dataset's provenance and drops entries of snapshots no longer in the directory.
+pub fn record_provenance(
+    dataset_dir: &Path, dataset_id: DatasetId, dataset_name: &str, snapshot_name: &str, snapshot: SnapshotProvenance,
+) -> Result<()> {
+    let mut provenance = read_provenance(dataset_dir)?.unwrap_or_else(|| DatasetProvenance {
+        dataset_id,
+        dataset_name: dataset_name.to_owned(),
+        snapshots: BTreeMap::new(),
+    });
+    provenance.dataset_name = dataset_name.to_owned();
+    provenance.snapshots.retain(|name, _| dataset_dir.join(name).exists());
+    provenance.snapshots.insert(snapshot_name.to_owned(), snapshot);
+
+    let path = dataset_dir.join(PROVENANCE_FILE_NAME);
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(&provenance)?)
+        .with_context(|| format!("Failed to write provenance file {:?}.", temp_path))?;
+    fs::rename(&temp_path, &path).with_context(|| format!("Failed to replace provenance file {:?}.", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> SnapshotProvenance {
+        SnapshotProvenance {
+            origin_uuid: Uuid::new_v4(),
+            source_hostname: Some(String::from("blkcaptdev")),
+            source_machine_id: None,
+            blockcaptain_version: String::from("0.1.0"),
+            received: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn provenance_record_prunes_missing() {
+        let dir = std::env::temp_dir().join(format!("blkcapt-provenance-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("a.bcrcv")).unwrap();
+        fs::create_dir_all(dir.join("b.bcrcv")).unwrap();
+        let dataset_id = DatasetId::default();
+
+        record_provenance(&dir, dataset_id, "home", "a.bcrcv", snapshot()).unwrap();
+        record_provenance(&dir, dataset_id, "home", "b.bcrcv", snapshot()).unwrap();
+        fs::remove_dir(dir.join("a.bcrcv")).unwrap();
+        let latest = snapshot();
+        record_provenance(&dir, dataset_id, "home2", "c.bcrcv", latest.clone()).unwrap();
+
+        let provenance = read_provenance(&dir).unwrap().unwrap();
+        assert_eq!(provenance.dataset_name, "home2");
+        assert_eq!(
+            provenance.snapshots.keys().collect::<Vec<_>>(),
+            vec!["b.bcrcv", "c.bcrcv"]
+        );
+        assert_eq!(provenance.snapshots["c.bcrcv"], latest);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
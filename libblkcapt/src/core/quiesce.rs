@@ -0,0 +1,323 @@
+use crate::{model::entities::QuiesceConfig, sys::process::output_to_result};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::{
+    libc,
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use std::{
+    io::{self, Read, Write},
+    os::unix::{io::AsRawFd, process::CommandExt},
+    process::{Child, ChildStdout, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const READY_MARKER: &str = "BLKCAPT_QUIESCED";
+/// Longest wait for the client to confirm the application is quiesced, e.g. while a lock waits on long queries.
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Puts an application into a consistent on-disk state for the duration of a snapshot.
+pub trait Quiescer {
+    fn quiesce(&self) -> Result<Box<dyn Quiesced>>;
+}
+
+/// An active quiesce. The application resumes normal operation when released.
+pub trait Quiesced {
+    fn release(self: Box<Self>) -> Result<()>;
+}
+
+pub fn quiescer(config: &QuiesceConfig) -> Box<dyn Quiescer> {
+    match config {
+        QuiesceConfig::Postgres {
+            run_as,
+            host,
+            port,
+            user,
+            database,
+        } => {
+            let mut args = vec!["-X", "-q", "-A", "-t", "-v", "ON_ERROR_STOP=1"]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            for (flag, value) in [
+                ("-h", host.clone()),
+                ("-p", port.map(|p| p.to_string())),
+                ("-U", user.clone()),
+                ("-d", database.clone()),
+            ]
+            .iter()
+            {
+                if let Some(value) = value {
+                    args.push(flag.to_string());
+                    args.push(value.to_owned());
+                }
+            }
+            Box::new(PostgresQuiescer {
+                client: ClientCommand {
+                    program: "psql",
+                    run_as: run_as.clone(),
+                    args,
+                },
+            })
+        }
+        QuiesceConfig::Mysql { run_as, socket, user } => {
+            // Without --unbuffered the marker stays in the client's output buffer until it exits.
+            let mut args = vec![
+                String::from("--batch"),
+                String::from("--unbuffered"),
+                String::from("--skip-column-names"),
+                format!("--socket={}", socket.to_string_lossy()),
+            ];
+            if let Some(user) = user {
+                args.push(format!("--user={}", user));
+            }
+            Box::new(MysqlQuiescer {
+                client: ClientCommand {
+                    program: "mysql",
+                    run_as: run_as.clone(),
+                    args,
+                },
+            })
+        }
+    }
+}
+
+struct ClientCommand {
+    program: &'static str,
+    run_as: Option<String>,
+    args: Vec<String>,
+}
+
+impl ClientCommand {
+    fn command(&self) -> Command {
+        let mut command = match &self.run_as {
+            Some(os_user) => {
+                let mut command = Command::new("runuser");
+                command.args(&["-u", os_user, "--", self.program]);
+                command
+            }
+            None => Command::new(self.program),
+        };
+        command.args(&self.args);
+        command
+    }
+}
+
+struct PostgresQuiescer {
+    client: ClientCommand,
+}
+
+impl Quiescer for PostgresQuiescer {
+    fn quiesce(&self) -> Result<Box<dyn Quiesced>> {
+        // The backup functions were renamed in PostgreSQL 15.
+        let mut session = ClientSession::start(&self.client, QUIESCE_TIMEOUT)?;
+        session
+            .execute(&format!(
+                "SELECT current_setting('server_version_num')::int >= 150000 AS blkcapt_pg15 \\gset\n\
+                \\if :blkcapt_pg15\n\
+                SELECT pg_backup_start('blockcaptain', true);\n\
+                \\else\n\
+                SELECT pg_start_backup('blockcaptain', true, false);\n\
+                \\endif\n\
+                \\echo {}\n",
+                READY_MARKER
+            ))
+            .context("failed to start postgres backup mode")?;
+        Ok(Box::new(PostgresQuiesced { session }))
+    }
+}
+
+struct PostgresQuiesced {
+    session: ClientSession,
+}
+
+impl Quiesced for PostgresQuiesced {
+    fn release(self: Box<Self>) -> Result<()> {
+        self.session
+            .finish(
+                "\\if :blkcapt_pg15\n\
+                SELECT lsn FROM pg_backup_stop(false);\n\
+                \\else\n\
+                SELECT lsn FROM pg_stop_backup(false, false);\n\
+                \\endif\n\
+                \\q\n",
+            )
+            .context("failed to stop postgres backup mode")
+    }
+}
+
+struct MysqlQuiescer {
+    client: ClientCommand,
+}
+
+impl Quiescer for MysqlQuiescer {
+    fn quiesce(&self) -> Result<Box<dyn Quiesced>> {
+        // The read lock is held only as long as this client session stays connected.
+        let mut session = ClientSession::start(&self.client, QUIESCE_TIMEOUT)?;
+        session
+            .execute(&format!("FLUSH TABLES WITH READ LOCK;\nSELECT '{}';\n", READY_MARKER))
+            .context("failed to lock mysql tables")?;
+        Ok(Box::new(MysqlQuiesced { session }))
+    }
+}
+
+struct MysqlQuiesced {
+    session: ClientSession,
+}
+
+impl Quiesced for MysqlQuiesced {
+    fn release(self: Box<Self>) -> Result<()> {
+        self.session
+            .finish("UNLOCK TABLES;\n")
+            .context("failed to unlock mysql tables")
+    }
+}
+
+struct ClientSession {
+    child: Child,
+    stdout: ChildStdout,
+    timeout: Duration,
+}
+
+impl ClientSession {
+    fn start(client: &ClientCommand, timeout: Duration) -> Result<Self> {
+        let mut command = client.command();
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // Safety: setpgid is async-signal-safe. The own process group lets a hung client be killed together with
+        // the children runuser starts.
+        unsafe {
+            command.pre_exec(|| match libc::setpgid(0, 0) {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            });
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("failed to start {}", client.program))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Self { child, stdout, timeout })
+    }
+
+    fn execute(&mut self, input: &str) -> Result<()> {
+        self.child
+            .stdin
+            .as_mut()
+            .expect("stdin is piped")
+            .write_all(input.as_bytes())?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut pending = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            if !self.wait_readable(deadline)? {
+                self.kill();
+                bail!(
+                    "client did not confirm the application was quiesced within {}",
+                    humantime::format_duration(self.timeout)
+                );
+            }
+            let read = self.stdout.read(&mut buffer)?;
+            if read == 0 {
+                let status = self.child.wait()?;
+                let mut stderr = String::new();
+                if let Some(mut pipe) = self.child.stderr.take() {
+                    pipe.read_to_string(&mut stderr)?;
+                }
+                return Err(anyhow!("{}", stderr.trim()).context(format!(
+                    "client exited before the application was quiesced ({})",
+                    status
+                )));
+            }
+            pending.extend_from_slice(&buffer[..read]);
+            while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                let line = pending.drain(..=end).collect::<Vec<_>>();
+                if String::from_utf8_lossy(&line).trim() == READY_MARKER {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Whether output arrived, or the client exited, before the deadline.
+    fn wait_readable(&self, deadline: Instant) -> Result<bool> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Ok(false);
+            }
+            let mut fds = [PollFd::new(self.stdout.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, remaining.as_millis().max(1).min(i32::MAX as u128) as i32) {
+                Ok(0) => return Ok(false),
+                Ok(_) => return Ok(true),
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => return Err(e).context("failed to wait for client output"),
+            }
+        }
+    }
+
+    fn kill(&mut self) {
+        let _ = killpg(Pid::from_raw(self.child.id() as i32), Signal::SIGKILL);
+        let _ = self.child.wait();
+    }
+
+    fn finish(mut self, input: &str) -> Result<()> {
+        let mut stdin = self.child.stdin.take().expect("stdin is piped");
+        stdin.write_all(input.as_bytes())?;
+        drop(stdin);
+        output_to_result(self.child.wait_with_output())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(script: &str, timeout: Duration) -> ClientSession {
+        let client = ClientCommand {
+            program: "sh",
+            run_as: None,
+            args: vec![String::from("-c"), script.to_owned()],
+        };
+        ClientSession::start(&client, timeout).unwrap()
+    }
+
+    #[test]
+    fn client_session_marker_handshake() {
+        let mut session = session(
+            &format!("read query; printf 'locked\\n{}\\n'; cat > /dev/null", READY_MARKER),
+            Duration::from_secs(10),
+        );
+        session.execute("LOCK;\n").unwrap();
+        session.finish("UNLOCK;\n").unwrap();
+    }
+
+    #[test]
+    fn client_session_marker_split_across_reads() {
+        let mut session = session(
+            "read query; printf 'BLKCAPT_'; sleep 0.2; printf 'QUIESCED\\n'; cat > /dev/null",
+            Duration::from_secs(10),
+        );
+        session.execute("LOCK;\n").unwrap();
+        session.finish("").unwrap();
+    }
+
+    #[test]
+    fn client_session_timeout_kills_client() {
+        let mut session = session("echo waiting; sleep 30", Duration::from_millis(200));
+        let started = Instant::now();
+        assert!(session.execute("LOCK;\n").is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(session.child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn client_session_exit_before_marker() {
+        let mut session = session("read query; echo denied >&2; exit 1", Duration::from_secs(10));
+        let error = session.execute("LOCK;\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("denied"));
+    }
+}
@@ -1,19 +1,27 @@
 use super::{parse_snapshot_label, Snapshot, SnapshotHandle};
 use crate::{
-    model::{entities::ResticContainerEntity, Entity, EntityId},
+    model::{
+        entities::{KeepSpec, ResticBackupOptions, ResticContainerEntity, ResticPerformance, RetentionRuleset},
+        secrets, storage, ContainerId, DatasetId,
+    },
     sys::{
         fs::{bind_mount, unmount},
-        process::exit_status_as_result,
+        process::{exit_status_as_result, JobKind, UnprivilegedUser},
     },
 };
 use anyhow::{anyhow, bail, Context, Error, Result};
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Deserializer};
-use std::{borrow::Borrow, fmt::Display, fs, path::Path, path::PathBuf, process::Stdio, str::FromStr, sync::Arc};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    borrow::Borrow, collections::HashMap, fmt::Display, fs, path::Path, path::PathBuf, process::Stdio, str::FromStr,
+    sync::Arc, time::Duration,
+};
 use tokio::{
     io::AsyncBufReadExt,
+    io::AsyncReadExt,
     io::BufReader,
     process::{Child, ChildStdout, Command},
+    sync::watch,
     task::JoinHandle,
 };
 use uuid::Uuid;
@@ -21,7 +29,7 @@ use uuid::Uuid;
 #[derive(Debug, PartialEq, Eq)]
 pub struct ResticContainerSnapshot {
     pub datetime: DateTime<Utc>,
-    pub dataset_id: EntityId,
+    pub dataset_id: DatasetId,
     pub uuid: ResticId,
     pub received_uuid: Uuid,
 }
@@ -31,6 +39,8 @@ impl From<&ResticContainerSnapshot> for SnapshotHandle {
         Self {
             datetime: snapshot.datetime,
             uuid: snapshot.uuid.low,
+            parent_uuid: None,
+            received_uuid: Some(snapshot.received_uuid),
         }
     }
 }
@@ -83,22 +93,241 @@ impl Display for ResticId {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResticRepositoryStats {
+    pub container_id: ContainerId,
+    pub collected: DateTime<Utc>,
+    /// Bytes stored in the repository for this container's snapshots.
+    pub stored_bytes: u64,
+    /// Bytes required to restore every snapshot of this container.
+    pub restore_bytes: u64,
+    pub datasets: Vec<ResticDatasetStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResticDatasetStats {
+    pub dataset_id: DatasetId,
+    /// Bytes required to restore the dataset's latest snapshot.
+    pub latest_restore_bytes: u64,
+}
+
+impl ResticRepositoryStats {
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        if self.stored_bytes == 0 {
+            None
+        } else {
+            Some(self.restore_bytes as f64 / self.stored_bytes as f64)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResticVersion {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+impl ResticVersion {
+    pub const COMPRESSION: Self = Self::new(0, 14, 0);
+    pub const PACK_SIZE: Self = Self::new(0, 14, 0);
+    pub const READ_CONCURRENCY: Self = Self::new(0, 15, 0);
+
+    const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self { major, minor, patch }
+    }
+
+    fn parse(output: &str) -> Result<Self> {
+        // e.g. "restic 0.16.4 compiled with go1.21.6 on linux/amd64"
+        let version = output
+            .split_whitespace()
+            .nth(1)
+            .filter(|_| output.starts_with("restic "))
+            .ok_or_else(|| anyhow!("unexpected restic version output: {}", output.trim()))?;
+        let mut parts = version.splitn(3, '.').map(|p| {
+            p.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse::<u32>()
+                .with_context(|| format!("invalid restic version: {}", version))
+        });
+        let mut next = || parts.next().unwrap_or(Ok(0));
+        Ok(Self::new(next()?, next()?, next()?))
+    }
+}
+
+impl Display for ResticVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
 pub struct ResticRepository {
     model: ResticContainerEntity,
+    environment: HashMap<String, String>,
+    program: PathBuf,
+    run_as: Option<UnprivilegedUser>,
+    // Read as root for restic run as the unprivileged user, which can't read a root only password file.
+    password: Option<String>,
 }
 
 impl ResticRepository {
     pub fn new(model: ResticContainerEntity) -> Result<Self> {
-        Ok(Self { model })
+        Self::validate(model)
     }
 
     pub fn validate(model: ResticContainerEntity) -> Result<Self> {
-        Ok(Self { model })
+        let mut environment: HashMap<_, _> = model
+            .custom_environment
+            .iter()
+            .map(|(name, value)| {
+                secrets::reveal(value)
+                    .with_context(|| format!("failed to reveal restic environment variable {}", name))
+                    .map(|value| (name.clone(), value))
+            })
+            .collect::<Result<_>>()?;
+        let server_config = storage::load_server_config().ok();
+        let program = model
+            .restic_path
+            .clone()
+            .or_else(|| server_config.as_ref().and_then(|c| c.restic_path.clone()))
+            .unwrap_or_else(|| PathBuf::from("restic"));
+        // The custom environment wins over proxy variables set there by hand.
+        let proxy = model
+            .proxy
+            .clone()
+            .or_else(|| server_config.as_ref().and_then(|c| c.proxy.clone()));
+        if let Some(proxy) = proxy {
+            for (name, value) in proxy.environment().context("failed to reveal restic proxy")? {
+                environment.entry(name.to_owned()).or_insert(value);
+            }
+        }
+        let run_as = server_config
+            .and_then(|c| c.unprivileged_user)
+            .map(|name| UnprivilegedUser::lookup(&name))
+            .transpose()?;
+        let password = match (&run_as, &model.password) {
+            (Some(_), Some(password)) => Some(Self::read_password(&password.path())?),
+            _ => None,
+        };
+        Ok(Self {
+            model,
+            environment,
+            program,
+            run_as,
+            password,
+        })
     }
 
-    pub fn backup(self: &Arc<Self>, bind_at: PathBuf, dataset_id: EntityId, snapshot: SnapshotHandle) -> ResticBackup {
-        let command = self.new_command();
-        ResticBackup::new(command, bind_at, dataset_id, snapshot)
+    // Restic trims the whitespace around passwords read from a file, too.
+    fn read_password(path: &Path) -> Result<String> {
+        fs::read_to_string(path)
+            .map(|password| password.trim().to_owned())
+            .with_context(|| format!("failed to read the restic password file {}", path.display()))
+    }
+
+    /// Runs `restic version` and checks the configured features are supported by it.
+    pub async fn detect_version(&self) -> Result<ResticVersion> {
+        let mut command = Command::new(&self.program);
+        if let Some(user) = &self.run_as {
+            user.apply(&mut command);
+        }
+        let output = command
+            .arg("version")
+            .output()
+            .await
+            .with_context(|| format!("failed to run restic at {}", self.program.display()))?;
+        exit_status_as_result(output.status)?;
+        let version = ResticVersion::parse(&String::from_utf8_lossy(&output.stdout))?;
+        self.check_supported(version)?;
+        Ok(version)
+    }
+
+    fn check_supported(&self, version: ResticVersion) -> Result<()> {
+        let performance = &self.model.performance;
+        for (configured, feature, required) in [
+            (
+                self.model.compression.is_some(),
+                "compression",
+                ResticVersion::COMPRESSION,
+            ),
+            (performance.pack_size.is_some(), "pack size", ResticVersion::PACK_SIZE),
+            (
+                performance.read_concurrency.is_some(),
+                "read concurrency",
+                ResticVersion::READ_CONCURRENCY,
+            ),
+        ]
+        .iter()
+        {
+            if *configured && version < *required {
+                bail!(
+                    "restic {} does not support {}, {} or later is required",
+                    version,
+                    feature,
+                    required
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn backup(
+        self: &Arc<Self>, bind_at: PathBuf, dataset_id: DatasetId, snapshot: SnapshotHandle,
+        options: &ResticBackupOptions,
+    ) -> ResticBackup {
+        let command = self.new_command_with_access(true);
+        let mut backup = ResticBackup::new(command, bind_at, dataset_id, snapshot, options);
+        backup.command.args(self.host_args());
+        backup
+            .command
+            .args(self.model.tags.iter().map(|t| format!("--tag={}", t)));
+        backup
+    }
+
+    /// Lists the regular files of a snapshot, relative to the bind path the dataset was backed up from.
+    pub async fn list_files(self: &Arc<Self>, snapshot: &ResticId, bind_path: &Path) -> Result<Vec<PathBuf>> {
+        let mut command = self.new_command();
+        command.args(&["ls", "--json"]).arg(snapshot.to_string());
+        let output = command.output().await?;
+        exit_status_as_result(output.status)?;
+        Self::parse_ls_files(&output.stdout, bind_path)
+    }
+
+    /// Restores the given files of a snapshot below `target` and returns the directory the bind path was restored to.
+    pub async fn restore_files(
+        self: &Arc<Self>, snapshot: &ResticId, bind_path: &Path, files: &[PathBuf], target: &Path,
+    ) -> Result<PathBuf> {
+        fs::create_dir_all(target)?;
+        if let Some(user) = &self.run_as {
+            user.chown(target)?;
+        }
+        let mut command = self.new_command();
+        command
+            .args(&["restore", "--target"])
+            .arg(target)
+            .arg(snapshot.to_string());
+        for file in files {
+            command.arg("--include").arg(bind_path.join(file));
+        }
+        let output = command.output().await?;
+        exit_status_as_result(output.status)
+            .with_context(|| String::from_utf8_lossy(&output.stderr).trim().to_owned())?;
+        Ok(target.join(bind_path.strip_prefix("/").unwrap_or(bind_path)))
+    }
+
+    fn parse_ls_files(output: &[u8], bind_path: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for line in output.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let node: LsOutputNode = serde_json::from_slice(line).context("unable to parse restic ls output")?;
+            if let (Some("file"), Some(path)) = (node.node_type.as_deref(), node.path) {
+                if let Ok(relative) = path.strip_prefix(bind_path) {
+                    files.push(relative.to_owned());
+                }
+            }
+        }
+        files.sort_unstable();
+        Ok(files)
     }
 
     pub fn prune(self: &Arc<Self>) -> ResticPrune {
@@ -109,8 +338,31 @@ impl ResticRepository {
     pub async fn snapshots(self: &Arc<Self>) -> Result<Vec<ResticContainerSnapshot>> {
         let mut command = self.new_command();
         command.args(&["snapshots", "--json"]);
-        let output = command.output().await?;
-        Self::parse_snapshots(&output.stdout, self.model().id())
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut process = command.spawn().context("spawn restic snapshots process failed")?;
+        let mut stdout = process.stdout.take().expect("stdout is piped");
+        // Read alongside stdout, restic would block on a full stderr pipe.
+        let mut stderr = process.stderr.take().expect("stderr is piped");
+        let stderr_reader = tokio::spawn(async move {
+            let mut output = Vec::new();
+            let _ = stderr.read_to_end(&mut output).await;
+            String::from_utf8_lossy(&output).trim().to_owned()
+        });
+
+        // Large repositories print tens of megabytes, parse as it arrives rather than buffering all of it.
+        let mut parser = SnapshotsParser::new(self.model().container_id());
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            let read = stdout.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            parser.feed(&chunk[..read])?;
+        }
+        let status = process.wait().await?;
+        let stderr = stderr_reader.await.unwrap_or_default();
+        exit_status_as_result(status).with_context(|| stderr)?;
+        parser.finish()
     }
 
     pub async fn snapshot_by_datetime(
@@ -120,8 +372,55 @@ impl ResticRepository {
         let datetime_tag = ResticBackup::datetime_tag(datetime);
         command.args(&["snapshots", "--json", "--tag", &datetime_tag, "--path"]);
         command.arg(&bind_path);
+        command.args(self.host_args());
+        let output = command.output().await?;
+        Self::parse_snapshots(&output.stdout, self.model().container_id()).map(|mut r| r.pop())
+    }
+
+    /// Collects statistics for the snapshots stored under the given dataset bind paths.
+    pub async fn stats(self: &Arc<Self>, datasets: &[(DatasetId, PathBuf)]) -> Result<ResticRepositoryStats> {
+        let mut stats = ResticRepositoryStats {
+            container_id: self.model.container_id(),
+            collected: Utc::now(),
+            stored_bytes: 0,
+            restore_bytes: 0,
+            datasets: Vec::with_capacity(datasets.len()),
+        };
+        if datasets.is_empty() {
+            return Ok(stats);
+        }
+
+        let paths = datasets.iter().map(|(_, p)| p.as_path()).collect::<Vec<_>>();
+        stats.stored_bytes = self.stats_total_size("raw-data", &paths, None).await?;
+        stats.restore_bytes = self.stats_total_size("restore-size", &paths, None).await?;
+        for (dataset_id, path) in datasets {
+            stats.datasets.push(ResticDatasetStats {
+                dataset_id: *dataset_id,
+                latest_restore_bytes: self
+                    .stats_total_size("restore-size", &[path.as_path()], Some("latest"))
+                    .await?,
+            });
+        }
+        Ok(stats)
+    }
+
+    async fn stats_total_size(&self, mode: &str, paths: &[&Path], snapshot: Option<&str>) -> Result<u64> {
+        let mut command = self.new_command();
+        command.args(&["stats", "--json", "--mode", mode]);
+        for path in paths {
+            command.arg("--path").arg(path);
+        }
+        command.args(self.host_args());
+        command.args(snapshot);
         let output = command.output().await?;
-        Self::parse_snapshots(&output.stdout, self.model().id()).map(|mut r| r.pop())
+        exit_status_as_result(output.status)?;
+        Self::parse_stats_total_size(&output.stdout)
+    }
+
+    fn parse_stats_total_size(output: &[u8]) -> Result<u64> {
+        serde_json::from_slice::<StatsOutput>(output)
+            .map(|s| s.total_size)
+            .context("unable to parse restic stats output")
     }
 
     pub fn forget(self: &Arc<Self>, snapshots: &[&ResticContainerSnapshot]) -> ResticForget {
@@ -129,70 +428,130 @@ impl ResticRepository {
         ResticForget::new(command, snapshots)
     }
 
+    /// Forgets snapshots under the given paths using restic's keep policies. The policies approximate the retention
+    /// rules: restic keeps the newest snapshot per calendar interval rather than filling chained interval buckets.
+    pub fn forget_by_policy(self: &Arc<Self>, rules: &RetentionRuleset, paths: &[PathBuf]) -> Result<ResticForget> {
+        let command = self.new_command();
+        ResticForget::with_policy(command, ResticForget::keep_policy_args(rules)?, paths)
+    }
+
     pub fn model(&self) -> &ResticContainerEntity {
         &self.model
     }
 
+    fn host_args(&self) -> Option<String> {
+        self.model.host.as_ref().map(|h| format!("--host={}", h))
+    }
+
     fn new_command(&self) -> Command {
-        let mut command = Command::new("restic");
+        self.new_command_with_access(false)
+    }
+
+    // Only backups read the snapshots, restic run as the unprivileged user gets read access to any file just for them.
+    fn new_command_with_access(&self, read_access: bool) -> Command {
+        let mut command = Command::new(&self.program);
+        JobKind::Restic.apply(&mut command);
         // let repository = match &self.model.repository {
         //     crate::model::entities::ResticRepository::Custom(r) => r,
         // };
         // ^ future with more linkages
         let crate::model::entities::ResticRepository::Custom(repository) = &self.model.repository;
         command.env("RESTIC_REPOSITORY", repository);
-        command.envs(&self.model.custom_environment);
+        command.envs(&self.environment);
+        match (&self.password, &self.model.password) {
+            (Some(password), _) => {
+                command.env("RESTIC_PASSWORD", password);
+            }
+            (None, Some(password)) => {
+                command.env("RESTIC_PASSWORD_FILE", password.path());
+            }
+            (None, None) => {}
+        }
+        match &self.run_as {
+            Some(user) if read_access => user.apply_with_read_access(&mut command),
+            Some(user) => user.apply(&mut command),
+            None => {}
+        }
+        if let Some(compression) = self.model.compression {
+            command.arg(format!("--compression={}", compression));
+        }
+        command.envs(Self::performance_env(&self.model.performance));
         command
     }
 
-    fn parse_snapshots(output: &[u8], expected_container_id: EntityId) -> Result<Vec<ResticContainerSnapshot>> {
-        const UUID_TAG: &str = "uuid=";
-        const TS_TAG: &str = "ts=";
+    fn performance_env(performance: &ResticPerformance) -> Vec<(&'static str, String)> {
+        [
+            ("RESTIC_PACK_SIZE", performance.pack_size),
+            ("GOMAXPROCS", performance.max_procs),
+            ("RESTIC_READ_CONCURRENCY", performance.read_concurrency),
+        ]
+        .iter()
+        .filter_map(|(name, value)| value.map(|v| (*name, v.to_string())))
+        .collect()
+    }
 
-        serde_json::from_slice::<Vec<SnapshotsOutputRecord>>(output)
-            .context("unable to parse restic snapshot output")
-            .map(|v| {
-                v.into_iter()
-                    .filter_map(|r| {
-                        let path = r.paths.get(0);
-
-                        let container_id = path
-                            .and_then(|p| p.parent().and_then(|p| p.file_name()))
-                            .and_then(|f| f.to_str())
-                            .and_then(|s| s.parse::<EntityId>().ok());
-
-                        if container_id.unwrap_or_default() != expected_container_id {
-                            return None;
-                        }
-
-                        let dataset_id = path
-                            .and_then(|p| p.file_name())
-                            .and_then(|f| f.to_str())
-                            .and_then(|s| s.parse().ok());
-
-                        let uuid = r
-                            .tags
-                            .iter()
-                            .find(|t| t.starts_with(UUID_TAG))
-                            .and_then(|t| t[UUID_TAG.len()..].parse().ok());
-                        let ts = r
-                            .tags
-                            .iter()
-                            .find(|t| t.starts_with(TS_TAG))
-                            .and_then(|t| parse_snapshot_label(&t[TS_TAG.len()..]).ok());
-
-                        dataset_id
-                            .zip(uuid)
-                            .zip(ts)
-                            .map(|((dataset_id, received_uuid), datetime)| ResticContainerSnapshot {
-                                uuid: r.id,
-                                datetime,
-                                dataset_id,
-                                received_uuid,
-                            })
-                    })
-                    .collect()
-            })
+    fn parse_snapshots(output: &[u8], expected_container_id: ContainerId) -> Result<Vec<ResticContainerSnapshot>> {
+        let mut parser = SnapshotsParser::new(expected_container_id);
+        parser.feed(output)?;
+        parser.finish()
+    }
+}
+
+/// Incrementally parses the json array printed by `restic snapshots`, keeping only the snapshots of one container.
+struct SnapshotsParser {
+    expected_container_id: ContainerId,
+    pending: Vec<u8>,
+    closed: bool,
+    snapshots: Vec<ResticContainerSnapshot>,
+}
+
+impl SnapshotsParser {
+    fn new(expected_container_id: ContainerId) -> Self {
+        Self {
+            expected_container_id,
+            pending: Vec::new(),
+            closed: false,
+            snapshots: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<()> {
+        self.pending.extend_from_slice(chunk);
+        let mut consumed = 0;
+        loop {
+            // The array punctuation between records is skipped so each record parses as a value of its own.
+            for &b in &self.pending[consumed..] {
+                match b {
+                    b']' => self.closed = true,
+                    b'[' | b',' => {}
+                    b if b.is_ascii_whitespace() => {}
+                    _ => break,
+                }
+                consumed += 1;
+            }
+
+            let mut stream =
+                serde_json::Deserializer::from_slice(&self.pending[consumed..]).into_iter::<SnapshotsOutputRecord>();
+            match stream.next() {
+                Some(Ok(record)) => {
+                    consumed += stream.byte_offset();
+                    self.snapshots
+                        .extend(record.container_snapshot(self.expected_container_id));
+                }
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => return Err(e).context("unable to parse restic snapshot output"),
+                None => break,
+            }
+        }
+        self.pending.drain(..consumed);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<ResticContainerSnapshot>> {
+        if !self.closed || !self.pending.is_empty() {
+            bail!("restic snapshot output is incomplete");
+        }
+        Ok(self.snapshots)
     }
 }
 
@@ -202,14 +561,18 @@ pub struct ResticBackup {
 }
 
 struct SnapshotSource {
-    dataset_id: EntityId,
+    dataset_id: DatasetId,
     snapshot: SnapshotHandle,
     bind_path: PathBuf,
 }
 
 impl ResticBackup {
-    fn new(mut repo_command: Command, bind_path: PathBuf, dataset_id: EntityId, snapshot: SnapshotHandle) -> Self {
+    fn new(
+        mut repo_command: Command, bind_path: PathBuf, dataset_id: DatasetId, snapshot: SnapshotHandle,
+        options: &ResticBackupOptions,
+    ) -> Self {
         repo_command.args(&["backup", "--json", "--tag", Self::snapshot_tags(&snapshot).as_str()]);
+        repo_command.args(Self::option_args(options));
 
         ResticBackup {
             command: repo_command,
@@ -235,24 +598,34 @@ impl ResticBackup {
                 anyhow!(e)
             })
             .map(|mut process| {
-                let message_reader = Self::spawn_message_reader(process.stdout.take().expect("only taken once"));
+                let (progress_sender, progress) = watch::channel(None);
+                let message_reader =
+                    Self::spawn_message_reader(process.stdout.take().expect("only taken once"), progress_sender);
                 StartedResticBackup {
                     process,
                     message_reader,
+                    progress,
                     source: self.source,
                 }
             })
     }
 
-    fn spawn_message_reader(handle: ChildStdout) -> JoinHandle<Result<Option<ResticId>>> {
+    fn spawn_message_reader(
+        handle: ChildStdout, progress: watch::Sender<Option<ResticBackupProgress>>,
+    ) -> JoinHandle<Result<Option<BackupOutputSummaryMessage>>> {
         tokio::spawn(async move {
             const SENTINEL: &str = "\"summary\"";
+            const STATUS_SENTINEL: &str = "\"status\"";
             let mut reader = BufReader::new(handle);
             let mut buffer = String::new();
             let mut result = None;
             while reader.read_line(&mut buffer).await? > 0 {
                 if result.is_none() && buffer.contains(SENTINEL) {
-                    result = Self::try_parse_snapshot_id(&buffer);
+                    result = Self::try_parse_summary(&buffer);
+                } else if buffer.contains(STATUS_SENTINEL) {
+                    if let Some(status) = Self::try_parse_progress(&buffer) {
+                        let _ = progress.send(Some(status));
+                    }
                 }
                 buffer.clear();
             }
@@ -260,11 +633,33 @@ impl ResticBackup {
         })
     }
 
-    fn try_parse_snapshot_id(line: &str) -> Option<ResticId> {
+    fn try_parse_progress(line: &str) -> Option<ResticBackupProgress> {
+        serde_json::from_str::<BackupOutputStatusMessage>(&line)
+            .ok()
+            .filter(|m| m.message_type == "status")
+            .map(|m| m.progress)
+    }
+
+    fn try_parse_summary(line: &str) -> Option<BackupOutputSummaryMessage> {
         serde_json::from_str::<BackupOutputSummaryMessage>(&line)
             .ok()
             .filter(|m| m.message_type == "summary")
-            .map(|m| m.snapshot_id)
+    }
+
+    fn option_args(options: &ResticBackupOptions) -> Vec<String> {
+        let mut args = options
+            .excludes
+            .iter()
+            .map(|e| format!("--exclude={}", e))
+            .collect::<Vec<_>>();
+        if options.exclude_caches {
+            args.push(String::from("--exclude-caches"));
+        }
+        if options.one_file_system {
+            args.push(String::from("--one-file-system"));
+        }
+        args.extend(options.extra_args.iter().cloned());
+        args
     }
 
     pub fn datetime_tag(datetime: DateTime<Utc>) -> String {
@@ -286,28 +681,67 @@ impl ResticBackup {
 
 pub struct StartedResticBackup {
     process: Child,
-    message_reader: JoinHandle<Result<Option<ResticId>>>,
+    message_reader: JoinHandle<Result<Option<BackupOutputSummaryMessage>>>,
+    progress: watch::Receiver<Option<ResticBackupProgress>>,
     source: SnapshotSource,
 }
 
+/// How far a running backup has got, from the latest status restic reported.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResticBackupProgress {
+    #[serde(default)]
+    pub percent_done: f64,
+    #[serde(default)]
+    pub bytes_done: u64,
+    #[serde(default)]
+    pub total_bytes: u64,
+}
+
+impl Display for ResticBackupProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MIB: u64 = 1024 * 1024;
+        write!(
+            f,
+            "{:.1}% ({} of {} MiB)",
+            self.percent_done * 100.0,
+            self.bytes_done / MIB,
+            self.total_bytes / MIB
+        )
+    }
+}
+
 impl StartedResticBackup {
-    pub async fn wait(mut self) -> Result<ResticContainerSnapshot> {
+    /// Follows the progress of the backup, none until restic reports its first status.
+    pub fn progress(&self) -> watch::Receiver<Option<ResticBackupProgress>> {
+        self.progress.clone()
+    }
+
+    pub async fn wait(mut self) -> Result<CompletedResticBackup> {
         let exit_status = self.process.wait().await?;
         let _ = unmount(&self.source.bind_path);
         exit_status_as_result(exit_status)?;
 
         let message_result = self.message_reader.await.expect("task doesn't panic")?;
-        let new_snapshot_id = message_result.context("failed to find new snapshot id")?;
+        let summary = message_result.context("failed to find new snapshot id")?;
 
-        Ok(ResticContainerSnapshot {
-            datetime: self.source.snapshot.datetime,
-            dataset_id: self.source.dataset_id,
-            uuid: new_snapshot_id,
-            received_uuid: self.source.snapshot.uuid,
+        Ok(CompletedResticBackup {
+            snapshot: ResticContainerSnapshot {
+                datetime: self.source.snapshot.datetime,
+                dataset_id: self.source.dataset_id,
+                uuid: summary.snapshot_id,
+                received_uuid: self.source.snapshot.uuid,
+            },
+            data_added: summary.data_added,
         })
     }
 }
 
+pub struct CompletedResticBackup {
+    pub snapshot: ResticContainerSnapshot,
+    /// Bytes the backup added to the repository, after deduplication and compression.
+    pub data_added: u64,
+}
+
 pub struct ResticPrune {
     command: Command,
 }
@@ -348,6 +782,59 @@ impl ResticForget {
         Self { command: repo_command }
     }
 
+    fn with_policy(mut repo_command: Command, policy_args: Vec<String>, paths: &[PathBuf]) -> Result<Self> {
+        if paths.is_empty() {
+            bail!("a policy forget requires at least one path");
+        }
+
+        // Every snapshot carries unique uuid and ts tags, so snapshots are grouped by path (one per dataset) only.
+        repo_command.args(&["forget", "--group-by", "host,paths"]);
+        repo_command.args(policy_args);
+        for path in paths {
+            repo_command.arg("--path").arg(path);
+        }
+
+        Ok(Self { command: repo_command })
+    }
+
+    fn keep_policy_args(rules: &RetentionRuleset) -> Result<Vec<String>> {
+        const HOUR: u64 = 60 * 60;
+        const DAY: u64 = 24 * HOUR;
+
+        let mut args = vec![format!("--keep-last={}", rules.newest_count)];
+        let mut window = Duration::from_secs(0);
+        for spec in rules.interval.iter() {
+            window += spec.duration * spec.repeat.get();
+            match spec.keep {
+                KeepSpec::Newest(count) if count.get() == 1 => {
+                    let unit = match spec.duration.as_secs() {
+                        HOUR => "hourly",
+                        DAY => "daily",
+                        s if s == 7 * DAY => "weekly",
+                        s if s == 30 * DAY || s == 2_630_016 => "monthly",
+                        s if s == 365 * DAY || s == 31_557_600 => "yearly",
+                        _ => bail!(
+                            "retention interval {} has no restic keep policy equivalent",
+                            humantime::format_duration(spec.duration)
+                        ),
+                    };
+                    args.push(format!("--keep-{}={}", unit, spec.repeat));
+                }
+                KeepSpec::Newest(count) => bail!(
+                    "restic keep policies retain one snapshot per interval, but {} were requested",
+                    count
+                ),
+                KeepSpec::All => {
+                    if window.as_secs() % HOUR != 0 {
+                        bail!("retention windows must be whole hours to use restic keep policies");
+                    }
+                    args.push(format!("--keep-within={}h", window.as_secs() / HOUR));
+                }
+            }
+        }
+        Ok(args)
+    }
+
     pub fn start(mut self) -> Result<StartedResticPrune> {
         let process = self.command.spawn().context("spawn restic forget process failed")?;
 
@@ -373,18 +860,185 @@ struct SnapshotsOutputRecord {
     parent: Option<ResticId>,
 }
 
+impl SnapshotsOutputRecord {
+    fn container_snapshot(self, expected_container_id: ContainerId) -> Option<ResticContainerSnapshot> {
+        const UUID_TAG: &str = "uuid=";
+        const TS_TAG: &str = "ts=";
+
+        let path = self.paths.get(0);
+
+        let container_id = path
+            .and_then(|p| p.parent().and_then(|p| p.file_name()))
+            .and_then(|f| f.to_str())
+            .and_then(|s| s.parse::<ContainerId>().ok());
+
+        if container_id.unwrap_or_default() != expected_container_id {
+            return None;
+        }
+
+        let dataset_id = path
+            .and_then(|p| p.file_name())
+            .and_then(|f| f.to_str())
+            .and_then(|s| s.parse().ok());
+
+        let uuid = self
+            .tags
+            .iter()
+            .find(|t| t.starts_with(UUID_TAG))
+            .and_then(|t| t[UUID_TAG.len()..].parse().ok());
+        let ts = self
+            .tags
+            .iter()
+            .find(|t| t.starts_with(TS_TAG))
+            .and_then(|t| parse_snapshot_label(&t[TS_TAG.len()..]).ok());
+
+        let id = self.id;
+        dataset_id
+            .zip(uuid)
+            .zip(ts)
+            .map(|((dataset_id, received_uuid), datetime)| ResticContainerSnapshot {
+                uuid: id,
+                datetime,
+                dataset_id,
+                received_uuid,
+            })
+    }
+}
+
+// The first line of `restic ls --json` describes the snapshot and has neither field.
+#[derive(Deserialize)]
+struct LsOutputNode {
+    #[serde(rename = "type")]
+    node_type: Option<String>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct StatsOutput {
+    total_size: u64,
+}
+
 #[derive(Deserialize)]
 struct BackupOutputSummaryMessage {
     message_type: String,
     snapshot_id: ResticId,
+    #[serde(default)]
+    data_added: u64,
+}
+
+#[derive(Deserialize)]
+struct BackupOutputStatusMessage {
+    message_type: String,
+    #[serde(flatten)]
+    progress: ResticBackupProgress,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::entities::{IntervalSpec, ResticCompression, ResticPassword};
+    use std::num::NonZeroU32;
 
     //mock!(Command);
 
+    #[test]
+    fn restic_password_passed_as_file() {
+        let mut model = ResticContainerEntity::new(
+            String::from("test"),
+            crate::model::entities::ResticRepository::Custom(String::from("/srv/restic")),
+        );
+        model.password = Some(ResticPassword::File {
+            path: PathBuf::from("/etc/blkcapt/restic.pass"),
+        });
+        let repository = ResticRepository {
+            model,
+            environment: HashMap::new(),
+            program: PathBuf::from("restic"),
+            run_as: None,
+            password: None,
+        };
+        let command = repository.new_command();
+        let environment = command.as_std().get_envs().collect::<HashMap<_, _>>();
+        assert_eq!(
+            environment.get(std::ffi::OsStr::new("RESTIC_PASSWORD_FILE")),
+            Some(&Some(std::ffi::OsStr::new("/etc/blkcapt/restic.pass")))
+        );
+        assert!(!environment.contains_key(std::ffi::OsStr::new("RESTIC_PASSWORD")));
+    }
+
+    #[test]
+    fn restic_password_passed_to_unprivileged_user() {
+        let path = std::env::temp_dir().join(format!("blkcapt-restic-pass-{}", Uuid::new_v4()));
+        fs::write(&path, "secret\n").unwrap();
+        let password = ResticRepository::read_password(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(password.unwrap(), "secret");
+        assert!(ResticRepository::read_password(&path).is_err());
+
+        let mut model = custom_model();
+        model.password = Some(ResticPassword::File { path });
+        let repository = ResticRepository {
+            password: Some(String::from("secret")),
+            ..repository(model)
+        };
+        let command = repository.new_command();
+        let environment = command.as_std().get_envs().collect::<HashMap<_, _>>();
+        assert_eq!(
+            environment.get(std::ffi::OsStr::new("RESTIC_PASSWORD")),
+            Some(&Some(std::ffi::OsStr::new("secret")))
+        );
+        assert!(!environment.contains_key(std::ffi::OsStr::new("RESTIC_PASSWORD_FILE")));
+    }
+
+    fn repository(model: ResticContainerEntity) -> ResticRepository {
+        ResticRepository {
+            model,
+            environment: HashMap::new(),
+            program: PathBuf::from("/opt/restic/bin/restic"),
+            run_as: None,
+            password: None,
+        }
+    }
+
+    fn custom_model() -> ResticContainerEntity {
+        ResticContainerEntity::new(
+            String::from("test"),
+            crate::model::entities::ResticRepository::Custom(String::from("/srv/restic")),
+        )
+    }
+
+    #[test]
+    fn restic_command_uses_program_and_compression() {
+        let command = repository(custom_model()).new_command();
+        assert_eq!(command.as_std().get_program(), "/opt/restic/bin/restic");
+        assert_eq!(command.as_std().get_args().count(), 0);
+
+        let mut model = custom_model();
+        model.compression = Some(ResticCompression::Max);
+        let command = repository(model).new_command();
+        assert_eq!(command.as_std().get_args().collect::<Vec<_>>(), ["--compression=max"]);
+    }
+
+    #[test]
+    fn restic_version_supports_configured_features() {
+        let old = ResticVersion::new(0, 13, 1);
+        assert!(repository(custom_model()).check_supported(old).is_ok());
+
+        let mut model = custom_model();
+        model.compression = Some(ResticCompression::Auto);
+        let error = repository(model).check_supported(old).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "restic 0.13.1 does not support compression, 0.14.0 or later is required"
+        );
+
+        let mut model = custom_model();
+        model.performance.read_concurrency = NonZeroU32::new(4);
+        let repository = repository(model);
+        assert!(repository.check_supported(ResticVersion::PACK_SIZE).is_err());
+        assert!(repository.check_supported(ResticVersion::READ_CONCURRENCY).is_ok());
+    }
+
     #[test]
     fn restic_snapshots_parse() {
         const RESTIC_OUTPUT: &[u8] = br#"[{"time":"2020-11-30T04:26:00.737443538Z","parent":"c7c4f0ed86a6a6ab812b41999a8fde92463cacb1673762541d1b5a139e5e0d19","tree":"fa98182915064b51e79bb95d20371696cbbde2d098fd0855521f79175d9e2dab","paths":["/var/lib/blkcapt/restic/e1370910-8805-4b72-b1aa-b007b6acc9cc/b99a584c-72c0-4cbe-9c6d-0c32274563f7"],"hostname":"blkcaptdev","username":"root","tags":["uuid=7f56a00a-2139-4048-96e2-c4946b731914","ts=2020-11-29T21-26-00Z"],"id":"4b0bdb80f692407f90413167a2f8673c2b948ad466e48d10a6072afc69ec7add","short_id":"4b0bdb80"},{"time":"2020-12-01T04:12:06.301970176Z","parent":"8067bdf9d334fcc550ddd9cca4afc382d97c10a583b1c37135508c2377e42ddb","tree":"b6b5f9002e282bb9ab0be82666bb1d6a038c0d71eb7dfda8dd1ee16870b5daa6","paths":["/var/lib/blkcapt/restic/e1370910-8805-4b72-b1aa-b007b6acc9cc/b99a584c-72c0-4cbe-9c6d-0c32274563f7"],"hostname":"blkcaptdev","username":"root","tags":["uuid=57c929a8-61ad-6747-957d-5daa101de0ff","ts=2020-11-30T04-58-00Z"],"id":"40e670db06225d0945b3ab4c0023f823d30f0ba15984df02266b74de29a1b657","short_id":"40e670db"}]"#;
@@ -413,13 +1067,162 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn restic_ls_parse() {
+        const RESTIC_OUTPUT: &[u8] = br#"{"time":"2021-05-01T04:26:00Z","paths":["/run/blockcaptain/restic_bind/c/d"],"id":"4b0bdb80","struct_type":"snapshot"}
+{"name":"d","type":"dir","path":"/run/blockcaptain/restic_bind/c/d","struct_type":"node"}
+{"name":"b.txt","type":"file","path":"/run/blockcaptain/restic_bind/c/d/a/b.txt","size":3,"struct_type":"node"}
+{"name":"link","type":"symlink","path":"/run/blockcaptain/restic_bind/c/d/link","struct_type":"node"}
+{"name":"a.txt","type":"file","path":"/run/blockcaptain/restic_bind/c/d/a.txt","size":1,"struct_type":"node"}
+"#;
+        let files =
+            ResticRepository::parse_ls_files(RESTIC_OUTPUT, Path::new("/run/blockcaptain/restic_bind/c/d")).unwrap();
+        assert_eq!(files, vec![PathBuf::from("a.txt"), PathBuf::from("a/b.txt")]);
+    }
+
+    #[test]
+    fn restic_snapshots_parse_chunked() {
+        const RESTIC_OUTPUT: &[u8] = br#"[
+  {"paths":["/var/lib/blkcapt/restic/e1370910-8805-4b72-b1aa-b007b6acc9cc/b99a584c-72c0-4cbe-9c6d-0c32274563f7"],"tags":["uuid=7f56a00a-2139-4048-96e2-c4946b731914","ts=2020-11-29T21-26-00Z"],"id":"4b0bdb80f692407f90413167a2f8673c2b948ad466e48d10a6072afc69ec7add"},
+  {"paths":["/var/lib/blkcapt/restic/0b4a5cb4-7c1e-4c69-9ae3-2a2ee1d7f1e3/b99a584c-72c0-4cbe-9c6d-0c32274563f7"],"tags":["uuid=57c929a8-61ad-6747-957d-5daa101de0ff","ts=2020-11-30T04-58-00Z"],"id":"40e670db06225d0945b3ab4c0023f823d30f0ba15984df02266b74de29a1b657"}
+]
+"#;
+        let mut parser = SnapshotsParser::new("e1370910-8805-4b72-b1aa-b007b6acc9cc".parse().unwrap());
+        for chunk in RESTIC_OUTPUT.chunks(7) {
+            parser.feed(chunk).unwrap();
+        }
+        let actual = parser.finish().unwrap();
+        assert_eq!(actual.len(), 1);
+        assert_eq!(
+            actual[0].datetime,
+            "2020-11-29T21:26:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+
+        let mut parser = SnapshotsParser::new(ContainerId::default());
+        parser.feed(&RESTIC_OUTPUT[..100]).unwrap();
+        assert!(parser.finish().is_err());
+    }
+
     #[test]
     fn restic_backup_message_parses() {
-        const RESTIC_OUTPUT: &str = r#"{"message_type":"summary","files_new":0,"files_changed":0,"files_unmodified":2,"dirs_new":0,"dirs_changed":0,"dirs_unmodified":4,"data_blobs":0,"tree_blobs":0,"data_added":0,"total_files_processed":2,"total_bytes_processed":8,"total_duration":0.227000569,"snapshot_id":"e4d43442776db0656bff8f674a94285f58ea3c4d5b1e0db9d501138d84d3817d","snapshot_short_id":"e4d43442"}"#;
-        let actual = ResticBackup::try_parse_snapshot_id(RESTIC_OUTPUT).unwrap();
+        const RESTIC_OUTPUT: &str = r#"{"message_type":"summary","files_new":0,"files_changed":0,"files_unmodified":2,"dirs_new":0,"dirs_changed":0,"dirs_unmodified":4,"data_blobs":0,"tree_blobs":0,"data_added":2048,"total_files_processed":2,"total_bytes_processed":8,"total_duration":0.227000569,"snapshot_id":"e4d43442776db0656bff8f674a94285f58ea3c4d5b1e0db9d501138d84d3817d","snapshot_short_id":"e4d43442"}"#;
+        let actual = ResticBackup::try_parse_summary(RESTIC_OUTPUT).unwrap();
         let expected: ResticId = "e4d43442776db0656bff8f674a94285f58ea3c4d5b1e0db9d501138d84d3817d"
             .parse()
             .unwrap();
+        assert_eq!(actual.snapshot_id, expected);
+        assert_eq!(actual.data_added, 2048);
+    }
+
+    #[test]
+    fn restic_backup_status_parses() {
+        const RESTIC_OUTPUT: &str = r#"{"message_type":"status","seconds_elapsed":12,"percent_done":0.25,"total_files":40,"files_done":10,"total_bytes":4194304,"bytes_done":1048576,"current_files":["/data/a"]}"#;
+        let actual = ResticBackup::try_parse_progress(RESTIC_OUTPUT).unwrap();
+        assert_eq!(
+            actual,
+            ResticBackupProgress {
+                percent_done: 0.25,
+                bytes_done: 1048576,
+                total_bytes: 4194304,
+            }
+        );
+        assert_eq!(actual.to_string(), "25.0% (1 of 4 MiB)");
+
+        const SCANNING_OUTPUT: &str = r#"{"message_type":"status","percent_done":0,"total_files":3}"#;
+        assert_eq!(
+            ResticBackup::try_parse_progress(SCANNING_OUTPUT).unwrap(),
+            ResticBackupProgress::default()
+        );
+    }
+
+    #[test]
+    fn restic_version_parse() {
+        let actual = ResticVersion::parse("restic 0.16.4 compiled with go1.21.6 on linux/amd64\n").unwrap();
+        assert_eq!(actual, ResticVersion::new(0, 16, 4));
+        assert!(actual >= ResticVersion::COMPRESSION);
+
+        let actual =
+            ResticVersion::parse("restic 0.9.6 (v0.9.6-0-gee53b3e0) compiled with go1.13.4 on linux/amd64").unwrap();
+        assert!(actual < ResticVersion::COMPRESSION);
+
+        assert!(ResticVersion::parse("rustic 0.5.0").is_err());
+    }
+
+    #[test]
+    fn restic_performance_env() {
+        let performance = ResticPerformance {
+            pack_size: NonZeroU32::new(64),
+            max_procs: NonZeroU32::new(2),
+            read_concurrency: None,
+        };
+        let actual = ResticRepository::performance_env(&performance);
+        let expected = vec![
+            ("RESTIC_PACK_SIZE", String::from("64")),
+            ("GOMAXPROCS", String::from("2")),
+        ];
+        assert_eq!(actual, expected);
+        assert!(ResticRepository::performance_env(&Default::default()).is_empty());
+    }
+
+    #[test]
+    fn restic_stats_parse() {
+        const RESTIC_OUTPUT: &[u8] = br#"{"total_size":7285012,"total_file_count":112,"snapshots_count":4}"#;
+        let actual = ResticRepository::parse_stats_total_size(RESTIC_OUTPUT).unwrap();
+        assert_eq!(actual, 7285012);
+    }
+
+    #[test]
+    fn restic_keep_policy_args() {
+        let rules = RetentionRuleset {
+            interval: vec![
+                IntervalSpec {
+                    repeat: NonZeroU32::new(2).unwrap(),
+                    duration: Duration::from_secs(3600),
+                    keep: KeepSpec::All,
+                },
+                IntervalSpec {
+                    repeat: NonZeroU32::new(7).unwrap(),
+                    duration: Duration::from_secs(86400),
+                    keep: KeepSpec::Newest(NonZeroU32::new(1).unwrap()),
+                },
+            ],
+            newest_count: NonZeroU32::new(3).unwrap(),
+            ..Default::default()
+        };
+        let actual = ResticForget::keep_policy_args(&rules).unwrap();
+        let expected = vec!["--keep-last=3", "--keep-within=2h", "--keep-daily=7"];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn restic_keep_policy_rejects_multiple_per_interval() {
+        let rules = RetentionRuleset {
+            interval: vec![IntervalSpec {
+                repeat: NonZeroU32::new(24).unwrap(),
+                duration: Duration::from_secs(3600),
+                keep: KeepSpec::Newest(NonZeroU32::new(2).unwrap()),
+            }],
+            ..Default::default()
+        };
+        assert!(ResticForget::keep_policy_args(&rules).is_err());
+    }
+
+    #[test]
+    fn restic_backup_option_args() {
+        let options = ResticBackupOptions {
+            excludes: vec![String::from(".cache"), String::from("*.tmp")],
+            exclude_caches: true,
+            one_file_system: true,
+            extra_args: vec![String::from("--no-scan")],
+        };
+        let actual = ResticBackup::option_args(&options);
+        let expected = vec![
+            "--exclude=.cache",
+            "--exclude=*.tmp",
+            "--exclude-caches",
+            "--one-file-system",
+            "--no-scan",
+        ];
         assert_eq!(actual, expected);
     }
 }
@@ -1,6 +1,7 @@
 use super::Snapshot;
 use crate::model::entities::KeepSpec;
 use crate::model::entities::RetentionRuleset;
+use crate::model::entities::SyncedRetention;
 
 use chrono::{DateTime, Utc};
 use std::{cmp::Reverse, collections::HashSet, iter::repeat};
@@ -59,6 +60,30 @@ pub fn evaluate_retention<'a, T: Snapshot>(snapshots: &'a [T], rules: &Retention
         keep_interval_buckets,
     }
 }
+
+/// Selects the container snapshots to drop after a transfer. The newest snapshot is always kept so the next transfer
+/// can be incremental.
+pub fn evaluate_synced_retention<'a, T: Snapshot>(
+    snapshots: &'a [T], source_snapshots: &[DateTime<Utc>], retention: SyncedRetention,
+) -> Vec<&'a T> {
+    let mut snapshots: Vec<_> = snapshots.iter().collect();
+    snapshots.sort_unstable_by_key(|b| Reverse(b.datetime()));
+
+    let keep_count = match retention {
+        SyncedRetention::Mirror | SyncedRetention::LatestOnly => 1,
+        SyncedRetention::MaxCount(n) => usize::try_from(n.get()).expect("u32 always fits in usize"),
+    };
+    snapshots
+        .into_iter()
+        .enumerate()
+        .filter(|(index, snapshot)| match retention {
+            SyncedRetention::Mirror => *index >= keep_count && !source_snapshots.contains(&snapshot.datetime()),
+            SyncedRetention::LatestOnly | SyncedRetention::MaxCount(_) => *index >= keep_count,
+        })
+        .map(|(_, snapshot)| snapshot)
+        .collect()
+}
+
 pub struct RetentionEvaluation<'a, T> {
     pub drop_snapshots: Vec<&'a T>,
     pub keep_minimum_snapshots: Vec<&'a T>,
@@ -91,3 +116,50 @@ impl<'a, T> RetainBucket<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::SnapshotHandle;
+    use chrono::{TimeZone, Timelike};
+    use std::num::NonZeroU32;
+    use uuid::Uuid;
+
+    fn datetime(hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 5, 1).and_hms(hour, 0, 0)
+    }
+
+    fn dropped(source: &[u32], retention: SyncedRetention) -> Vec<u32> {
+        let container = [3, 1, 4, 2]
+            .iter()
+            .map(|h| SnapshotHandle {
+                datetime: datetime(*h),
+                uuid: Uuid::new_v4(),
+                parent_uuid: None,
+                received_uuid: None,
+            })
+            .collect::<Vec<_>>();
+        let source = source.iter().copied().map(datetime).collect::<Vec<_>>();
+        evaluate_synced_retention(&container, &source, retention)
+            .iter()
+            .map(|s| s.datetime.hour())
+            .collect()
+    }
+
+    #[test]
+    fn synced_retention_mirror_drops_snapshots_missing_from_source() {
+        assert_eq!(dropped(&[1, 2, 3, 4], SyncedRetention::Mirror), Vec::<u32>::new());
+        assert_eq!(dropped(&[2, 4], SyncedRetention::Mirror), [3, 1]);
+        // The newest is kept even after the source pruned it.
+        assert_eq!(dropped(&[], SyncedRetention::Mirror), [3, 2, 1]);
+    }
+
+    #[test]
+    fn synced_retention_keeps_newest_count() {
+        assert_eq!(dropped(&[1, 2, 3, 4], SyncedRetention::LatestOnly), [3, 2, 1]);
+        let two = SyncedRetention::MaxCount(NonZeroU32::new(2).unwrap());
+        assert_eq!(dropped(&[1, 2, 3, 4], two), [2, 1]);
+        let many = SyncedRetention::MaxCount(NonZeroU32::new(10).unwrap());
+        assert_eq!(dropped(&[], many), Vec::<u32>::new());
+    }
+}
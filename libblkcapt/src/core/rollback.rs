@@ -0,0 +1,135 @@
+//! Rollback of the OS root dataset: a writable copy of one of its snapshots becomes the root at the next boot.
+
+use super::{BtrfsDataset, BtrfsDatasetSnapshot};
+use crate::{
+    data_dir,
+    model::{DatasetId, Entity},
+    sys::{
+        btrfs::{fstab_root_subvolume, set_fstab_root_subvolume},
+        fs::FsPathBuf,
+    },
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+/// How the root filesystem picks its subvolume at boot.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RootSelection {
+    /// The subvol option of the root's fstab entry.
+    FstabSubvol,
+    /// The default subvolume of the pool.
+    DefaultSubvolume,
+}
+
+/// The last rollback, kept so it can be undone.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RollbackRecord {
+    pub dataset_id: DatasetId,
+    pub time: DateTime<Utc>,
+    pub snapshot: FsPathBuf,
+    pub selection: RootSelection,
+    pub previous_path: FsPathBuf,
+    pub previous_uuid: Uuid,
+    pub root_path: FsPathBuf,
+    pub root_uuid: Uuid,
+}
+
+fn record_path() -> PathBuf {
+    data_dir().join("rollback.json")
+}
+
+pub fn last_rollback() -> Result<Option<RollbackRecord>> {
+    match fs::read(record_path()) {
+        Ok(data) => Ok(Some(
+            serde_json::from_slice(&data).context("failed to parse the rollback record")?,
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("failed to read the rollback record"),
+    }
+}
+
+/// Copies the snapshot next to the dataset and boots the copy from the next boot on. The subvolume of the dataset is
+/// left as it is, so the rollback can be undone.
+pub fn rollback(dataset: &Arc<BtrfsDataset>, snapshot: &BtrfsDatasetSnapshot) -> Result<RollbackRecord> {
+    if !snapshot.nested_snapshots()?.is_empty() {
+        bail!("Rolling back snapshots of nested subvolumes is not supported.");
+    }
+    let selection = root_selection(dataset)?;
+
+    let previous_path = &dataset.subvolume.path;
+    let root_path = previous_path
+        .parent()
+        .expect("dataset path always has a parent")
+        .join(format!(
+            "{}.rollback-{}",
+            previous_path
+                .file_name()
+                .expect("dataset path always has a name")
+                .to_string_lossy(),
+            snapshot.datetime.format("%Y%m%dT%H%M%SZ")
+        ));
+    let filesystem = &dataset.pool.filesystem;
+    filesystem.create_writable_snapshot(snapshot.path(), &root_path)?;
+    dataset.pool.invalidate_subvolumes(&root_path);
+    let root = filesystem.subvolume_by_path(&root_path)?;
+    select_root(dataset, selection, &root_path)?;
+
+    let record = RollbackRecord {
+        dataset_id: dataset.model.dataset_id(),
+        time: Utc::now(),
+        snapshot: snapshot.path().clone(),
+        selection,
+        previous_path: previous_path.clone(),
+        previous_uuid: dataset.subvolume.uuid,
+        root_path,
+        root_uuid: root.uuid,
+    };
+    fs::create_dir_all(data_dir())?;
+    fs::write(record_path(), serde_json::to_vec(&record)?).context("failed to store the rollback record")?;
+    Ok(record)
+}
+
+/// Boots the subvolume the last rollback replaced again from the next boot on, the rolled back root is kept.
+pub fn undo_rollback(dataset: &Arc<BtrfsDataset>) -> Result<RollbackRecord> {
+    let record = last_rollback()?.context("No rollback to undo.")?;
+    if record.dataset_id != dataset.model.dataset_id() {
+        bail!("The last rollback was not of dataset {}.", dataset.model.name());
+    }
+    select_root(dataset, record.selection, &record.previous_path)?;
+    fs::remove_file(record_path()).context("failed to remove the rollback record")?;
+    Ok(record)
+}
+
+/// Whether the kernel command line chooses the root subvolume, which a rollback can't change.
+pub fn cmdline_selects_root_subvolume() -> Result<bool> {
+    let cmdline = fs::read_to_string("/proc/cmdline").context("failed to read the kernel command line")?;
+    Ok(cmdline
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("rootflags="))
+        .any(|flags| flags.split(',').any(|f| f.starts_with("subvol"))))
+}
+
+fn root_selection(dataset: &BtrfsDataset) -> Result<RootSelection> {
+    let path = &dataset.subvolume.path;
+    if fstab_root_subvolume()?.as_ref() == Some(path) {
+        return Ok(RootSelection::FstabSubvol);
+    }
+    if dataset.pool.filesystem.default_subvolume()?.as_ref() == Some(path) {
+        return Ok(RootSelection::DefaultSubvolume);
+    }
+    bail!(
+        "Dataset {} is neither mounted as root by fstab's subvol option nor the default subvolume of its pool.",
+        dataset.model.name()
+    )
+}
+
+fn select_root(dataset: &BtrfsDataset, selection: RootSelection, path: &FsPathBuf) -> Result<()> {
+    match selection {
+        RootSelection::FstabSubvol => set_fstab_root_subvolume(path),
+        RootSelection::DefaultSubvolume => dataset.pool.filesystem.set_default_subvolume(path),
+    }
+}
@@ -1,9 +1,103 @@
+use super::restic::ResticRepositoryStats;
+use crate::model::{entities::ObservableEvent, EntityId};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use strum_macros::Display;
 
+/// The protocol spoken over the service socket, bumped on incompatible changes to its requests or replies.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What the running service reports about itself before any other request is made.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServiceVersion {
+    pub version: String,
+    pub protocol: u32,
+    /// Requests added without a protocol bump, for clients that can do without them.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl ServiceVersion {
+    pub fn current() -> Self {
+        Self {
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            protocol: PROTOCOL_VERSION,
+            capabilities: ["metrics", "log_level", "stop_actor", "snapshots"]
+                .iter()
+                .map(|c| String::from(*c))
+                .collect(),
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Fails with what to upgrade when the service speaks a different protocol than this build.
+    pub fn check_compatible(&self) -> Result<()> {
+        let local = Self::current();
+        if self.protocol < local.protocol {
+            bail!(
+                "the blockcaptain service {} is older than this client {}, upgrade the daemon",
+                self.version,
+                local.version
+            );
+        }
+        if self.protocol > local.protocol {
+            bail!(
+                "the blockcaptain service {} is newer than this client {}, upgrade blkcaptctl",
+                self.version,
+                local.version
+            );
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SystemState {
     pub actors: Vec<SystemActor>,
+    #[serde(default)]
+    pub restic_stats: Vec<ResticRepositoryStats>,
+    #[serde(default)]
+    pub jobs: Vec<SystemJob>,
+    /// The latest observed events, oldest first.
+    #[serde(default)]
+    pub events: Vec<SystemEvent>,
+}
+
+/// A job that has started and not finished yet.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SystemJob {
+    pub entity_id: EntityId,
+    pub event: ObservableEvent,
+    pub started: DateTime<Utc>,
+    /// The average duration of its earlier successful runs, none before the first one.
+    #[serde(default, with = "humantime_serde")]
+    pub typical: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SystemEvent {
+    pub time: DateTime<Utc>,
+    pub entity_id: EntityId,
+    pub event: ObservableEvent,
+    pub stage: SystemEventStage,
+    /// Why the event failed or was warned about.
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Display, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SystemEventStage {
+    Starting,
+    Succeeded,
+    Failed,
+    Warning,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -11,6 +105,9 @@ pub struct SystemActor {
     pub actor_id: u64,
     pub actor_state: ActorState,
     pub actor_type: String,
+    /// The entity the actor works for, none for actors of the whole service.
+    #[serde(default)]
+    pub entity_id: Option<EntityId>,
 }
 
 #[derive(Serialize, Deserialize, Display, Clone)]
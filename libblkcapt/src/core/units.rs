@@ -0,0 +1,486 @@
+//! systemd service and timer units that run the worker in one-shot mode on the schedules of each entity, an
+//! alternative to the long-running service.
+
+use crate::model::{
+    entities::{FeatureState, ScheduleModel, SnapshotSyncEntity, SnapshotSyncMode},
+    AnyContainer, DatasetId, Entities, Entity,
+};
+use anyhow::{anyhow, bail, Result};
+use std::path::Path;
+
+pub const DEFAULT_WORKER_PATH: &str = "/usr/lib/blockcaptain/blkcaptd";
+/// One-shot runs started by different timers wait for each other, the worker refuses to run twice.
+const ONE_SHOT_LOCK_PATH: &str = "/run/lock/blockcaptain-oneshot.lock";
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitFile {
+    pub name: String,
+    pub contents: String,
+}
+
+struct ScheduledJob<'a> {
+    what: &'static str,
+    entity: &'a dyn Entity,
+    schedule: &'a ScheduleModel,
+    mountpoints: Vec<&'a Path>,
+    network: bool,
+}
+
+/// A service and a timer for every kind of scheduled job, the timer firing on the schedules of every entity with a job
+/// of the kind. The worker runs the jobs of the kind that came due, so the service depends on what any of them needs.
+/// Syncs following snapshots immediately run in the snapshot service, heartbeats are not sent.
+pub fn systemd_units(entities: &Entities, worker: &Path) -> Result<Vec<UnitFile>> {
+    let mut jobs = Vec::new();
+    for pool in entities.btrfs_pools.iter() {
+        let mountpoints = vec![pool.mountpoint_path.as_path()];
+        if let (FeatureState::Enabled, Some(schedule)) = (pool.scrubbing_state(), pool.scrub_schedule.as_ref()) {
+            jobs.push(ScheduledJob::new("scrub", pool, schedule, mountpoints.clone()));
+        }
+        if let Some(dedup) = pool.dedup.as_ref() {
+            jobs.push(ScheduledJob::new("dedup", pool, &dedup.schedule, mountpoints.clone()));
+        }
+
+        for dataset in pool.datasets.iter() {
+            if let (FeatureState::Enabled, Some(schedule)) =
+                (dataset.snapshotting_state(), dataset.snapshot_schedule.as_ref())
+            {
+                let mut job = ScheduledJob::new("snapshot", dataset, schedule, mountpoints.clone());
+                job.follow_syncs(entities, &[dataset.dataset_id()]);
+                jobs.push(job);
+            }
+            if let (FeatureState::Enabled, Some(retention)) =
+                (dataset.pruning_state(), dataset.snapshot_retention.as_ref())
+            {
+                jobs.push(ScheduledJob::new(
+                    "prune",
+                    dataset,
+                    &retention.evaluation_schedule,
+                    mountpoints.clone(),
+                ));
+            }
+            if let Some(defrag) = dataset.defrag.as_ref() {
+                jobs.push(ScheduledJob::new(
+                    "defrag",
+                    dataset,
+                    &defrag.schedule,
+                    mountpoints.clone(),
+                ));
+            }
+        }
+
+        for container in pool.containers.iter() {
+            if let (FeatureState::Enabled, Some(retention)) =
+                (container.pruning_state(), container.snapshot_retention.as_ref())
+            {
+                jobs.push(ScheduledJob::new(
+                    "prune",
+                    container,
+                    &retention.evaluation_schedule,
+                    mountpoints.clone(),
+                ));
+            }
+            if let Some(drill) = container.restore_drill.as_ref() {
+                jobs.push(ScheduledJob::new(
+                    "restore-drill",
+                    container,
+                    &drill.schedule,
+                    mountpoints.clone(),
+                ));
+            }
+        }
+    }
+
+    for restic in entities.restic_containers.iter() {
+        if let (FeatureState::Enabled, Some(retention)) = (restic.pruning_state(), restic.snapshot_retention.as_ref()) {
+            let mut job = ScheduledJob::new("prune", restic, &retention.evaluation_schedule, Vec::new());
+            job.network = true;
+            jobs.push(job);
+        }
+        if let Some(drill) = restic.restore_drill.as_ref() {
+            let mut job = ScheduledJob::new("restore-drill", restic, &drill.schedule, Vec::new());
+            job.network = true;
+            jobs.push(job);
+        }
+    }
+
+    for group in entities.dataset_groups.iter() {
+        if let (FeatureState::Enabled, Some(schedule)) = (group.snapshotting_state(), group.snapshot_schedule.as_ref())
+        {
+            let mut job = ScheduledJob::new("snapshot", group, schedule, Vec::new());
+            for dataset_id in group.dataset_ids.iter() {
+                if let Some(dataset) = entities.dataset(*dataset_id) {
+                    job.add_mountpoint(&dataset.parent.mountpoint_path);
+                }
+            }
+            job.follow_syncs(entities, &group.dataset_ids);
+            jobs.push(job);
+        }
+    }
+
+    for sync in entities.snapshot_syncs.iter() {
+        let schedule = match &sync.sync_mode {
+            SnapshotSyncMode::AllScheduled(schedule) | SnapshotSyncMode::LatestScheduled(schedule) => schedule,
+            SnapshotSyncMode::AllImmediate | SnapshotSyncMode::IntervalImmediate(_) => continue,
+        };
+        let mut job = ScheduledJob::new("sync", sync, schedule, Vec::new());
+        job.add_sync(entities, sync);
+        jobs.push(job);
+    }
+
+    let mut kinds = Vec::new();
+    for job in jobs.iter() {
+        if !kinds.contains(&job.what) {
+            kinds.push(job.what);
+        }
+    }
+    let mut units = Vec::new();
+    for what in kinds {
+        units.extend(kind_units(what, jobs.iter().filter(|j| j.what == what), worker)?);
+    }
+    Ok(units)
+}
+
+impl<'a> ScheduledJob<'a> {
+    fn new(
+        what: &'static str, entity: &'a dyn Entity, schedule: &'a ScheduleModel, mountpoints: Vec<&'a Path>,
+    ) -> Self {
+        Self {
+            what,
+            entity,
+            schedule,
+            mountpoints,
+            network: false,
+        }
+    }
+
+    fn add_mountpoint(&mut self, mountpoint: &'a Path) {
+        if !self.mountpoints.contains(&mountpoint) {
+            self.mountpoints.push(mountpoint);
+        }
+    }
+
+    fn add_sync(&mut self, entities: &'a Entities, sync: &SnapshotSyncEntity) {
+        if let Some(dataset) = entities.dataset(sync.dataset_id) {
+            self.add_mountpoint(&dataset.parent.mountpoint_path);
+        }
+        let container_ids = std::iter::once(&sync.container_id)
+            .chain(sync.additional_container_ids.iter())
+            .chain(sync.source_container_id.iter());
+        for container_id in container_ids {
+            match entities.any_container(*container_id) {
+                Some(AnyContainer::Btrfs(_)) => {
+                    if let Some(container) = entities.container(*container_id) {
+                        self.add_mountpoint(&container.parent.mountpoint_path);
+                    }
+                }
+                Some(AnyContainer::Restic(_)) => self.network = true,
+                None => {}
+            }
+        }
+    }
+
+    // Syncs transferring snapshots as soon as they are taken run along with the snapshots.
+    fn follow_syncs(&mut self, entities: &'a Entities, dataset_ids: &[DatasetId]) {
+        for sync in entities.snapshot_syncs.iter() {
+            let immediate = matches!(
+                sync.sync_mode,
+                SnapshotSyncMode::AllImmediate | SnapshotSyncMode::IntervalImmediate(_)
+            );
+            if immediate && dataset_ids.contains(&sync.dataset_id) {
+                self.add_sync(entities, sync);
+            }
+        }
+    }
+}
+
+fn kind_units<'a>(
+    what: &str, jobs: impl Iterator<Item = &'a ScheduledJob<'a>>, worker: &Path,
+) -> Result<Vec<UnitFile>> {
+    let mut mountpoints = Vec::new();
+    let mut network = false;
+    let mut calendars = Vec::new();
+    for job in jobs {
+        for mountpoint in job.mountpoints.iter() {
+            if !mountpoints.contains(mountpoint) {
+                mountpoints.push(*mountpoint);
+            }
+        }
+        network |= job.network;
+        let calendar =
+            on_calendar(job.schedule).map_err(|e| anyhow!("{} schedule of {}: {}", what, job.entity.name(), e))?;
+        if !calendars.contains(&calendar) {
+            calendars.push(calendar);
+        }
+    }
+
+    let name = format!("blockcaptain-{}", what);
+    let description = format!("blockcaptain {}", what.replace('-', " "));
+
+    let mut dependencies = String::new();
+    if !mountpoints.is_empty() {
+        dependencies.push_str("After=local-fs.target\n");
+        for mountpoint in mountpoints.iter() {
+            dependencies.push_str(&format!("RequiresMountsFor={}\n", mountpoint.display()));
+        }
+    }
+    if network {
+        dependencies.push_str("Wants=network-online.target\nAfter=network-online.target\n");
+    }
+
+    let service = format!(
+        "# Generated by blkcaptctl generate systemd-units\n\
+        [Unit]\n\
+        Description={description}\n\
+        {dependencies}\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart=/usr/bin/flock {lock} {worker} --once {what}\n",
+        description = description,
+        dependencies = dependencies,
+        lock = ONE_SHOT_LOCK_PATH,
+        worker = worker.display(),
+        what = what,
+    );
+    let timer = format!(
+        "# Generated by blkcaptctl generate systemd-units\n\
+        [Unit]\n\
+        Description={description} schedule\n\
+        \n\
+        [Timer]\n\
+        {calendars}\
+        Persistent=true\n\
+        \n\
+        [Install]\n\
+        WantedBy=timers.target\n",
+        description = description,
+        calendars = calendars
+            .iter()
+            .map(|c| format!("OnCalendar={}\n", c))
+            .collect::<String>(),
+    );
+
+    Ok(vec![
+        UnitFile {
+            name: format!("{}.service", name),
+            contents: service,
+        },
+        UnitFile {
+            name: format!("{}.timer", name),
+            contents: timer,
+        },
+    ])
+}
+
+/// Converts a cron schedule to a systemd calendar event firing at the same times, cron schedules run in UTC.
+pub fn on_calendar(schedule: &ScheduleModel) -> Result<String> {
+    let expression = match schedule.expression().trim() {
+        "@yearly" | "@annually" => "0 0 0 1 1 * *",
+        "@monthly" => "0 0 0 1 * * *",
+        "@weekly" => "0 0 0 * * 1 *",
+        "@daily" => "0 0 0 * * * *",
+        "@hourly" => "0 0 * * * * *",
+        expression => expression,
+    };
+    let fields = expression.split_whitespace().collect::<Vec<_>>();
+    if fields.len() != 6 && fields.len() != 7 {
+        bail!("expected 6 or 7 fields in cron expression '{}'", expression);
+    }
+
+    let seconds = calendar_field(fields[0], 0, 59, &[])?;
+    let minutes = calendar_field(fields[1], 0, 59, &[])?;
+    let hours = calendar_field(fields[2], 0, 23, &[])?;
+    let days = calendar_field(fields[3], 1, 31, &[])?;
+    let months = calendar_field(fields[4], 1, 12, &MONTHS)?;
+    let years = calendar_field(fields.get(6).copied().unwrap_or("*"), 1970, 2099, &[])?;
+    let weekdays = match fields[5] {
+        "*" | "?" => String::new(),
+        field => {
+            let mut weekdays = Vec::new();
+            for part in field.split(',') {
+                let (start, end, step) = field_range(part, 1, 7, &WEEKDAYS)?;
+                weekdays.extend((start..=end).step_by(step as usize).map(|d| WEEKDAYS[d as usize - 1]));
+            }
+            format!("{} ", weekdays.join(","))
+        }
+    };
+
+    Ok(format!(
+        "{}{}-{}-{} {}:{}:{} UTC",
+        weekdays, years, months, days, hours, minutes, seconds
+    ))
+}
+
+fn calendar_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<String> {
+    if field == "*" || field == "?" {
+        return Ok(String::from("*"));
+    }
+    let mut values = Vec::new();
+    for part in field.split(',') {
+        let (start, end, step) = field_range(part, min, max, names)?;
+        if step == 1 && start == end {
+            values.push(format!("{:02}", start));
+        } else if step == 1 {
+            values.push(format!("{:02}..{:02}", start, end));
+        } else if end == max {
+            values.push(format!("{:02}/{}", start, step));
+        } else {
+            values.extend((start..=end).step_by(step as usize).map(|v| format!("{:02}", v)));
+        }
+    }
+    Ok(values.join(","))
+}
+
+// The first and last value and the step of a part of a cron field.
+fn field_range(part: &str, min: u32, max: u32, names: &[&str]) -> Result<(u32, u32, u32)> {
+    let (range, step) = match part.split_once('/') {
+        Some((range, step)) => (range, Some(step.parse::<u32>().map_err(|_| invalid_part(part))?)),
+        None => (part, None),
+    };
+    let (start, end) = match range.split_once('-') {
+        _ if range == "*" || range == "?" => (min, max),
+        Some((start, end)) => (field_value(start, names)?, field_value(end, names)?),
+        // A single value with a step repeats to the end of the range.
+        None if step.is_some() => (field_value(range, names)?, max),
+        None => {
+            let value = field_value(range, names)?;
+            (value, value)
+        }
+    };
+    if start < min || end > max || start > end || step == Some(0) {
+        return Err(invalid_part(part));
+    }
+    Ok((start, end, step.unwrap_or(1)))
+}
+
+fn field_value(value: &str, names: &[&str]) -> Result<u32> {
+    if let Ok(number) = value.parse() {
+        return Ok(number);
+    }
+    let lowercase = value.to_lowercase();
+    names
+        .iter()
+        .position(|name| lowercase.len() >= 3 && name.to_lowercase().starts_with(&lowercase[..3]))
+        .map(|index| index as u32 + 1)
+        .ok_or_else(|| invalid_part(value))
+}
+
+fn invalid_part(part: &str) -> anyhow::Error {
+    anyhow!("'{}' has no systemd calendar equivalent", part)
+}
+
+// Escapes a name like systemd-escape so it fits in a unit name.
+fn escape_unit_name(name: &str) -> String {
+    let mut escaped = String::new();
+    for (index, byte) in name.bytes().enumerate() {
+        match byte {
+            b'.' if index == 0 => escaped.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' => escaped.push(b as char),
+            b => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::entities::{BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity};
+    use crate::sys::fs::FsPathBuf;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn calendar(expression: &str) -> Result<String> {
+        on_calendar(&expression.parse().unwrap())
+    }
+
+    #[test]
+    fn on_calendar_conversion() {
+        assert_eq!(calendar("0 0/5 * * * * *").unwrap(), "*-*-* *:00/5:00 UTC");
+        assert_eq!(
+            calendar("0 30 2 * * Mon-Fri *").unwrap(),
+            "Mon,Tue,Wed,Thu,Fri *-*-* 02:30:00 UTC"
+        );
+        assert_eq!(
+            calendar("0 0 0 1 Jan,Jul * 2022").unwrap(),
+            "2022-01,07-01 00:00:00 UTC"
+        );
+        assert_eq!(calendar("0 0 1-10/3 * * *").unwrap(), "*-*-* 01,04,07,10:00:00 UTC");
+        assert_eq!(calendar("0 15 8-17 * * * *").unwrap(), "*-*-* 08..17:15:00 UTC");
+        assert_eq!(calendar("@weekly").unwrap(), "Sun *-*-* 00:00:00 UTC");
+        assert!(calendar_field("L", 1, 31, &[]).is_err());
+        assert!(calendar_field("5-2", 0, 59, &[]).is_err());
+    }
+
+    #[test]
+    fn unit_name_escaping() {
+        assert_eq!(escape_unit_name("home"), "home");
+        assert_eq!(escape_unit_name("my data/2"), "my\\x20data\\x2f2");
+        assert_eq!(escape_unit_name("off-site"), "off\\x2dsite");
+    }
+
+    #[test]
+    fn units_per_kind_with_all_dependencies() {
+        let mut entities = Entities::default();
+        let mut first = BtrfsPoolEntity::new(
+            String::from("first"),
+            PathBuf::from("/mnt/first"),
+            Uuid::new_v4(),
+            Vec::new(),
+        )
+        .unwrap();
+        let mut hourly =
+            BtrfsDatasetEntity::new(String::from("hourly"), FsPathBuf::from("hourly"), Uuid::new_v4()).unwrap();
+        hourly.snapshot_schedule = Some("0 0 * * * *".parse().unwrap());
+        let hourly_id = hourly.dataset_id();
+        first.attach_dataset(hourly).unwrap();
+        let mut second = BtrfsPoolEntity::new(
+            String::from("second"),
+            PathBuf::from("/mnt/second"),
+            Uuid::new_v4(),
+            Vec::new(),
+        )
+        .unwrap();
+        let mut daily =
+            BtrfsDatasetEntity::new(String::from("daily"), FsPathBuf::from("daily"), Uuid::new_v4()).unwrap();
+        daily.snapshot_schedule = Some("0 0 2 * * *".parse().unwrap());
+        second.attach_dataset(daily).unwrap();
+        let backups =
+            BtrfsContainerEntity::new(String::from("backups"), FsPathBuf::from("backups"), Uuid::new_v4()).unwrap();
+        let backups_id = backups.container_id();
+        second.attach_container(backups).unwrap();
+        entities.attach_pool(first).unwrap();
+        entities.attach_pool(second).unwrap();
+        entities
+            .attach_snapshot_sync(SnapshotSyncEntity::new(String::from("offsite"), hourly_id, backups_id))
+            .unwrap();
+
+        let units = systemd_units(&entities, Path::new("/usr/bin/blkcaptd")).unwrap();
+        let names = units.iter().map(|u| u.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["blockcaptain-snapshot.service", "blockcaptain-snapshot.timer"]
+        );
+        assert_eq!(
+            units[0].contents,
+            "# Generated by blkcaptctl generate systemd-units\n\
+            [Unit]\n\
+            Description=blockcaptain snapshot\n\
+            After=local-fs.target\n\
+            RequiresMountsFor=/mnt/first\n\
+            RequiresMountsFor=/mnt/second\n\
+            \n\
+            [Service]\n\
+            Type=oneshot\n\
+            ExecStart=/usr/bin/flock /run/lock/blockcaptain-oneshot.lock /usr/bin/blkcaptd --once snapshot\n"
+        );
+        assert!(units[1]
+            .contents
+            .contains("OnCalendar=*-*-* *:00:00 UTC\nOnCalendar=*-*-* 02:00:00 UTC\n"));
+    }
+}
@@ -0,0 +1,193 @@
+//! Restore drills restore a sample of a backup and read it back, optionally comparing it to the source snapshot.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+/// The outcome of the drill of one dataset.
+#[derive(Debug, Default)]
+pub struct DrillReport {
+    pub sampled: usize,
+    /// Sampled files that differ from the source snapshot or are missing from it.
+    pub mismatched: Vec<PathBuf>,
+    /// Whether the sample was compared, the source snapshot may already be pruned.
+    pub compared: bool,
+}
+
+impl DrillReport {
+    pub fn summary(&self) -> String {
+        match (self.compared, self.mismatched.is_empty()) {
+            (false, _) => format!("restored {} files", self.sampled),
+            (true, true) => format!("restored {} files matching the source snapshot", self.sampled),
+            (true, false) => format!(
+                "restored {} files, {} differ from the source snapshot: {}",
+                self.sampled,
+                self.mismatched.len(),
+                self.mismatched
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// The regular files below `root`, relative to it and sorted. Symlinks are not followed.
+pub fn list_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut directories = vec![PathBuf::new()];
+    while let Some(relative) = directories.pop() {
+        let directory = root.join(&relative);
+        for entry in fs::read_dir(&directory).with_context(|| format!("failed to list {}", directory.display()))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(relative.join(entry.file_name()));
+            } else if file_type.is_file() {
+                files.push(relative.join(entry.file_name()));
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Picks up to `count` items spread evenly over `items`, `seed` shifts which ones so successive drills cover others.
+pub fn pick_sample<T: Clone>(items: &[T], count: NonZeroUsize, seed: u64) -> Vec<T> {
+    let count = count.get();
+    if items.len() <= count {
+        return items.to_vec();
+    }
+    let stride = items.len() / count;
+    let offset = (seed % stride as u64) as usize;
+    (0..count).map(|i| items[offset + i * stride].clone()).collect()
+}
+
+/// Reads every sampled file of `restored` in full, comparing it to its counterpart in `source` when given.
+pub fn check_sample(restored: &Path, source: Option<&Path>, sample: &[PathBuf]) -> Result<DrillReport> {
+    let mut report = DrillReport {
+        sampled: sample.len(),
+        mismatched: Vec::new(),
+        compared: source.is_some(),
+    };
+    for relative in sample {
+        let restored_path = restored.join(relative);
+        let matches = match source {
+            Some(source) => files_equal(&restored_path, &source.join(relative)),
+            None => File::open(&restored_path)
+                .and_then(|mut file| io::copy(&mut file, &mut io::sink()))
+                .map(|_| true),
+        }
+        .with_context(|| format!("failed to read restored file {}", restored_path.display()))?;
+        if !matches {
+            report.mismatched.push(relative.clone());
+        }
+    }
+    Ok(report)
+}
+
+// A file missing from the source counts as a mismatch, the restored side must be readable.
+fn files_equal(restored: &Path, source: &Path) -> io::Result<bool> {
+    let mut restored = File::open(restored)?;
+    let mut source = match File::open(source) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if restored.metadata()?.len() != source.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut restored_buffer = vec![0; 64 * 1024];
+    let mut source_buffer = vec![0; 64 * 1024];
+    loop {
+        let read = restored.read(&mut restored_buffer)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        source.read_exact(&mut source_buffer[..read])?;
+        if restored_buffer[..read] != source_buffer[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn sample_dirs(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("blkcapt-{}-{}", name, Uuid::new_v4()));
+        let (restored, source) = (dir.join("restored"), dir.join("source"));
+        fs::create_dir_all(restored.join("sub")).unwrap();
+        fs::create_dir_all(source.join("sub")).unwrap();
+        (restored, source)
+    }
+
+    #[test]
+    fn pick_sample_spreads() {
+        let items = (0..10).collect::<Vec<_>>();
+        let three = NonZeroUsize::new(3).unwrap();
+        assert_eq!(pick_sample(&items, three, 0), vec![0, 3, 6]);
+        assert_eq!(pick_sample(&items, three, 2), vec![2, 5, 8]);
+        assert_eq!(pick_sample(&items, three, 4), vec![1, 4, 7]);
+        assert_eq!(pick_sample(&items[..2], three, 7), vec![0, 1]);
+    }
+
+    #[test]
+    fn files_equal_compares_content() {
+        let (restored, source) = sample_dirs("files-equal");
+        let write = |root: &Path, name: &str, content: &[u8]| fs::write(root.join(name), content).unwrap();
+        let big = vec![7u8; 200 * 1024];
+        let mut big_changed = big.clone();
+        *big_changed.last_mut().unwrap() = 8;
+        write(&restored, "same", &big);
+        write(&source, "same", &big);
+        write(&restored, "longer", b"abcd");
+        write(&source, "longer", b"abc");
+        write(&restored, "changed", &big);
+        write(&source, "changed", &big_changed);
+        write(&restored, "missing", b"abc");
+
+        let equal = |name: &str| files_equal(&restored.join(name), &source.join(name));
+        assert!(equal("same").unwrap());
+        assert!(!equal("longer").unwrap());
+        assert!(!equal("changed").unwrap());
+        assert!(!equal("missing").unwrap());
+        assert!(equal("not-restored").is_err());
+        fs::remove_dir_all(restored.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn check_sample_reports_mismatches() {
+        let (restored, source) = sample_dirs("check-sample");
+        fs::write(restored.join("a"), b"a").unwrap();
+        fs::write(source.join("a"), b"a").unwrap();
+        fs::write(restored.join("sub/b"), b"b").unwrap();
+        fs::write(source.join("sub/b"), b"c").unwrap();
+        fs::write(restored.join("sub/c"), b"c").unwrap();
+        let sample = list_files(&restored).unwrap();
+        assert_eq!(
+            sample,
+            vec![PathBuf::from("a"), PathBuf::from("sub/b"), PathBuf::from("sub/c")]
+        );
+
+        let report = check_sample(&restored, Some(&source), &sample).unwrap();
+        assert!(report.compared);
+        assert_eq!(report.sampled, 3);
+        assert_eq!(report.mismatched, vec![PathBuf::from("sub/b"), PathBuf::from("sub/c")]);
+
+        let report = check_sample(&restored, None, &sample).unwrap();
+        assert!(!report.compared);
+        assert!(report.mismatched.is_empty());
+
+        assert!(check_sample(&restored, None, &[PathBuf::from("gone")]).is_err());
+        fs::remove_dir_all(restored.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! Failure categories reported to wrapper scripts through the process exit status and the `code` log field.
+
+use std::{error::Error, fmt, io};
+use strum_macros::Display;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ErrorCode {
+    /// Any failure not covered by a more specific code.
+    Other,
+    /// A named entity is not in the configuration.
+    ConfigNotFound,
+    /// A name or id prefix matches more than one entity.
+    EntityAmbiguous,
+    /// The blockcaptain service is not running or doesn't answer.
+    WorkerUnreachable,
+    PermissionDenied,
+    /// The command line or configuration was rejected before anything ran.
+    ValidationFailed,
+    /// An external command such as btrfs or restic failed.
+    JobFailed,
+}
+
+impl ErrorCode {
+    pub fn exit_status(self) -> i32 {
+        match self {
+            ErrorCode::Other => 1,
+            ErrorCode::ConfigNotFound => 2,
+            ErrorCode::EntityAmbiguous => 3,
+            ErrorCode::WorkerUnreachable => 4,
+            ErrorCode::PermissionDenied => 5,
+            ErrorCode::ValidationFailed => 6,
+            ErrorCode::JobFailed => 7,
+        }
+    }
+}
+
+/// Tags an error with a code without changing how it is displayed.
+pub struct CodedError {
+    code: ErrorCode,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Debug for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl Error for CodedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub fn coded(code: ErrorCode, error: anyhow::Error) -> anyhow::Error {
+    CodedError { code, error }.into()
+}
+
+pub trait ErrorCodeExt<T> {
+    fn code(self, code: ErrorCode) -> anyhow::Result<T>;
+}
+
+impl<T> ErrorCodeExt<T> for anyhow::Result<T> {
+    fn code(self, code: ErrorCode) -> anyhow::Result<T> {
+        self.map_err(|error| coded(code, error))
+    }
+}
+
+/// The outermost code tagged on the error, otherwise what its causes imply.
+pub fn error_code(error: &anyhow::Error) -> ErrorCode {
+    if let Some(coded) = error.chain().find_map(|e| e.downcast_ref::<CodedError>()) {
+        return coded.code;
+    }
+    let permission_denied = error.chain().any(|e| {
+        e.downcast_ref::<io::Error>()
+            .map_or(false, |e| e.kind() == io::ErrorKind::PermissionDenied)
+            || e.downcast_ref::<nix::Error>().map_or(false, |e| {
+                matches!(
+                    e.as_errno(),
+                    Some(nix::errno::Errno::EACCES) | Some(nix::errno::Errno::EPERM)
+                )
+            })
+    });
+    if permission_denied {
+        ErrorCode::PermissionDenied
+    } else {
+        ErrorCode::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn error_code_outermost_wins() {
+        let error = Err::<(), _>(anyhow!("exit code 1"))
+            .code(ErrorCode::JobFailed)
+            .context("backup failed")
+            .code(ErrorCode::WorkerUnreachable)
+            .unwrap_err();
+        assert_eq!(error_code(&error), ErrorCode::WorkerUnreachable);
+        assert_eq!(error.to_string(), "backup failed");
+        assert_eq!(format!("{:#}", error), "backup failed: exit code 1");
+    }
+
+    #[test]
+    fn error_code_from_causes() {
+        let error = anyhow::Error::new(io::Error::from(io::ErrorKind::PermissionDenied)).context("failed to open");
+        assert_eq!(error_code(&error), ErrorCode::PermissionDenied);
+        assert_eq!(error_code(&anyhow!("failed")), ErrorCode::Other);
+        assert_eq!(ErrorCode::ConfigNotFound.to_string(), "config-not-found");
+    }
+}
@@ -1,16 +1,45 @@
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::{env, path::PathBuf, sync::Once};
+pub mod api;
 pub mod core;
+pub mod error;
 pub mod model;
 pub mod parsing;
 pub mod sys;
 
+/// Marks the directories of a dev sandbox, the only ones the directory overrides may point at.
+pub const SANDBOX_MARKER: &str = ".blkcapt-sandbox";
+
+/// Overridden with `BLOCKCAPTAIN_DATA_DIR` to point the CLI and worker at a dev sandbox.
 pub fn data_dir() -> PathBuf {
-    PathBuf::from("/var/lib/blockcaptain")
+    static WARNING: Once = Once::new();
+    sandbox_override("BLOCKCAPTAIN_DATA_DIR", &WARNING).unwrap_or_else(|| PathBuf::from("/var/lib/blockcaptain"))
 }
 
+/// Overridden with `BLOCKCAPTAIN_RUNTIME_DIR`, so a sandboxed worker doesn't collide with the service.
 pub fn runtime_dir() -> PathBuf {
-    PathBuf::from("/run/blockcaptain")
+    static WARNING: Once = Once::new();
+    sandbox_override("BLOCKCAPTAIN_RUNTIME_DIR", &WARNING).unwrap_or_else(|| PathBuf::from("/run/blockcaptain"))
+}
+
+// A variable left in the environment must not point the service at another configuration, it is ignored unless it
+// names a sandbox directory.
+fn sandbox_override(variable: &str, warning: &Once) -> Option<PathBuf> {
+    let path = PathBuf::from(env::var_os(variable)?);
+    if path.join(SANDBOX_MARKER).is_file() {
+        Some(path)
+    } else {
+        warning.call_once(|| slog_scope::warn!("Ignoring {}, {} is not a sandbox directory", variable, path.display()));
+        None
+    }
+}
+
+/// The directory systemd places `LoadCredential=` credentials in. Outside of the service (e.g. the CLI running as
+/// root) the credentials of the blockcaptain unit are read directly.
+pub fn credentials_dir() -> PathBuf {
+    env::var_os("CREDENTIALS_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/run/credentials/blockcaptain.service"))
 }
 
 pub fn create_data_dir() -> Result<PathBuf> {
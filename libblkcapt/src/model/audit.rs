@@ -0,0 +1,349 @@
+use super::{history::read_lines, Entities, Entity, EntityId, ServerConfig};
+use crate::data_dir;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use nix::unistd::{getuid, Uid, User};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+static AUDIT_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = data_dir();
+    path.push("config");
+    path.push("audit.log");
+    path
+});
+
+/// A configuration change, appended to the audit log whenever the configuration is stored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    pub user: String,
+    pub command: String,
+    pub changes: Vec<String>,
+}
+
+/// A changed setting of an entity, by its dotted path. Values are `None` where the setting didn't exist.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// The settings that differ between two versions of an entity, down to the nested fields that changed.
+pub fn field_changes(previous: &impl Serialize, updated: &impl Serialize) -> Result<Vec<FieldChange>> {
+    let mut changes = Vec::new();
+    nested_changes(
+        String::new(),
+        Some(&serde_json::to_value(previous)?),
+        Some(&serde_json::to_value(updated)?),
+        &mut changes,
+    );
+    Ok(changes)
+}
+
+/// Appends a record of the differences between the stored and the new entity config.
+pub fn audit_entity_change(previous: &Entities, updated: &Entities) -> Result<()> {
+    append_record(entity_changes(previous, updated)?)
+}
+
+fn entity_changes(previous: &Entities, updated: &Entities) -> Result<Vec<String>> {
+    let previous_entities = entity_values(previous)?;
+    let updated_entities = entity_values(updated)?;
+
+    let mut changes = Vec::new();
+    for (id, (kind, name, value)) in updated_entities.iter() {
+        match previous_entities.get(id) {
+            None => changes.push(format!("added {} {}", kind, name)),
+            Some((_, _, previous_value)) if previous_value != value => changes.push(format!(
+                "changed {} {}: {}",
+                kind,
+                name,
+                changed_fields(previous_value, value).join(", ")
+            )),
+            Some(_) => {}
+        }
+    }
+    for (id, (kind, name, _)) in previous_entities.iter() {
+        if !updated_entities.contains_key(id) {
+            changes.push(format!("removed {} {}", kind, name));
+        }
+    }
+    changes.sort();
+    if previous.machine_id != updated.machine_id {
+        changes.push(format!(
+            "bound to machine {}",
+            updated.machine_id.as_deref().unwrap_or("none")
+        ));
+    }
+    Ok(changes)
+}
+
+/// Appends a record of the server config settings that differ from the stored server config.
+pub fn audit_server_change(previous: &ServerConfig, updated: &ServerConfig) -> Result<()> {
+    let changed = changed_fields(&serde_json::to_value(previous)?, &serde_json::to_value(updated)?);
+    let changes = changed.into_iter().map(|f| format!("changed server {}", f)).collect();
+    append_record(changes)
+}
+
+/// Reads the audit log, oldest record first. Records that fail to parse are skipped.
+pub fn read_audit_log() -> Result<Vec<AuditRecord>> {
+    read_lines(&AUDIT_PATH).context("failed to read the audit log")
+}
+
+fn append_record(changes: Vec<String>) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let record = AuditRecord {
+        time: Utc::now(),
+        user: current_user(),
+        command: current_command(),
+        changes,
+    };
+    fs::create_dir_all(AUDIT_PATH.parent().expect("audit log always has a parent directory"))
+        .context("failed to create directory structure for the audit log")?;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&*AUDIT_PATH)
+        .context("failed to open the audit log")?;
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    file.write_all(&line).context("failed to append to the audit log")
+}
+
+// The user who logged in, which sudo and su keep and the environment can't fake, or the process user outside of a
+// login session.
+fn current_user() -> String {
+    let uid = fs::read_to_string("/proc/self/loginuid")
+        .ok()
+        .and_then(|id| id.trim().parse::<u32>().ok())
+        .filter(|&id| id != u32::MAX)
+        .map(Uid::from_raw)
+        .unwrap_or_else(getuid);
+    user_name(uid)
+}
+
+fn user_name(uid: Uid) -> String {
+    match User::from_uid(uid) {
+        Ok(Some(user)) => user.name,
+        _ => format!("uid {}", uid),
+    }
+}
+
+// Only the command words are recorded, option values may hold secrets.
+fn current_command() -> String {
+    env::args()
+        .take_while(|a| !a.starts_with('-'))
+        .map(|a| match PathBuf::from(&a).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => a,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+type EntityValues = HashMap<EntityId, (String, String, Value)>;
+
+fn entity_values(entities: &Entities) -> Result<EntityValues> {
+    let mut values = HashMap::new();
+    let mut insert = |entity: &dyn Entity, value: Value| {
+        values.insert(
+            entity.id(),
+            (entity.entity_type().to_string(), entity.name().to_owned(), value),
+        );
+    };
+
+    for pool in entities.btrfs_pools.iter() {
+        // Datasets and containers are compared on their own.
+        let mut pool_only = serde_json::to_value(pool)?;
+        if let Value::Object(fields) = &mut pool_only {
+            fields.remove("datasets");
+            fields.remove("containers");
+        }
+        insert(pool, pool_only);
+        for dataset in pool.datasets.iter() {
+            insert(dataset, serde_json::to_value(dataset)?);
+        }
+        for container in pool.containers.iter() {
+            insert(container, serde_json::to_value(container)?);
+        }
+    }
+    for restic in entities.restic_containers.iter() {
+        insert(restic, serde_json::to_value(restic)?);
+    }
+    for sync in entities.snapshot_syncs.iter() {
+        insert(sync, serde_json::to_value(sync)?);
+    }
+    for observer in entities.observers.iter() {
+        insert(observer, serde_json::to_value(observer)?);
+    }
+    for group in entities.dataset_groups.iter() {
+        insert(group, serde_json::to_value(group)?);
+    }
+    Ok(values)
+}
+
+fn nested_changes(field: String, previous: Option<&Value>, updated: Option<&Value>, changes: &mut Vec<FieldChange>) {
+    match (previous, updated) {
+        (Some(Value::Object(previous)), Some(Value::Object(updated))) => {
+            let mut keys = previous.keys().chain(updated.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let nested = if field.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", field, key)
+                };
+                nested_changes(nested, previous.get(key), updated.get(key), changes);
+            }
+        }
+        (previous, updated) if previous != updated => changes.push(FieldChange {
+            field,
+            before: previous.cloned(),
+            after: updated.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+// The top-level fields of two serialized configs that differ.
+fn changed_fields(previous: &Value, updated: &Value) -> Vec<String> {
+    match (previous, updated) {
+        (Value::Object(previous), Value::Object(updated)) => updated
+            .iter()
+            .filter(|(key, value)| previous.get(*key) != Some(value))
+            .map(|(key, _)| key.clone())
+            .chain(previous.keys().filter(|k| !updated.contains_key(*k)).cloned())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::entities::{BtrfsDatasetEntity, BtrfsPoolEntity};
+    use uuid::Uuid;
+
+    fn pool(name: &str) -> BtrfsPoolEntity {
+        BtrfsPoolEntity::new(
+            name.to_owned(),
+            format!("/mnt/{}", name).into(),
+            Uuid::new_v4(),
+            Vec::new(),
+        )
+        .unwrap()
+    }
+
+    fn dataset(name: &str) -> BtrfsDatasetEntity {
+        BtrfsDatasetEntity::new(name.to_owned(), name.into(), Uuid::new_v4()).unwrap()
+    }
+
+    fn with_pool(pool: BtrfsPoolEntity) -> Entities {
+        Entities {
+            btrfs_pools: vec![pool],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entity_changes_lists_added_changed_and_removed() {
+        let mut tank = pool("tank");
+        tank.attach_dataset(dataset("home")).unwrap();
+        tank.attach_dataset(dataset("media")).unwrap();
+        let previous = with_pool(tank.clone());
+
+        tank.datasets[0].pause_snapshotting = true;
+        tank.datasets.remove(1);
+        tank.attach_dataset(dataset("backup")).unwrap();
+        let mut updated = with_pool(tank);
+        updated.machine_id = Some("abc".to_owned());
+
+        assert_eq!(
+            entity_changes(&previous, &updated).unwrap(),
+            vec![
+                "added dataset backup",
+                "changed dataset home: pause_snapshotting",
+                "removed dataset media",
+                "bound to machine abc",
+            ]
+        );
+    }
+
+    #[test]
+    fn entity_changes_compares_pools_without_children() {
+        let mut tank = pool("tank");
+        let previous = with_pool(tank.clone());
+        tank.attach_dataset(dataset("home")).unwrap();
+        let updated = with_pool(tank);
+
+        assert_eq!(entity_changes(&previous, &updated).unwrap(), vec!["added dataset home"]);
+        assert!(entity_changes(&updated, &updated).unwrap().is_empty());
+    }
+
+    #[test]
+    fn user_name_falls_back_to_uid() {
+        assert_eq!(user_name(Uid::from_raw(0)), "root");
+        assert_eq!(user_name(Uid::from_raw(u32::MAX - 1)), format!("uid {}", u32::MAX - 1));
+    }
+
+    #[test]
+    fn field_changes_lists_nested_fields() {
+        let mut home = dataset("home");
+        let previous = home.clone();
+        home.pause_snapshotting = true;
+        home.annotations.labels.insert("tier".to_owned(), "gold".to_owned());
+
+        assert_eq!(
+            field_changes(&previous, &home).unwrap(),
+            vec![
+                FieldChange {
+                    field: "annotations.labels.tier".to_owned(),
+                    before: None,
+                    after: Some(Value::from("gold")),
+                },
+                FieldChange {
+                    field: "pause_snapshotting".to_owned(),
+                    before: Some(Value::from(false)),
+                    after: Some(Value::from(true)),
+                },
+            ]
+        );
+        assert!(field_changes(&home, &home).unwrap().is_empty());
+    }
+
+    #[test]
+    fn nested_changes_compares_values() {
+        let previous = serde_json::json!({"a": {"b": 1, "c": [1, 2]}, "d": null, "e": {"f": 1}});
+        let updated = serde_json::json!({"a": {"b": 2, "c": [1, 3]}, "e": 5, "g": {"h": true}});
+        let mut changes = Vec::new();
+        nested_changes(String::new(), Some(&previous), Some(&updated), &mut changes);
+
+        let fields = changes
+            .iter()
+            .map(|c| (c.field.as_str(), c.before.clone(), c.after.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                ("a.b", Some(Value::from(1)), Some(Value::from(2))),
+                ("a.c", Some(serde_json::json!([1, 2])), Some(serde_json::json!([1, 3]))),
+                ("d", Some(Value::Null), None),
+                ("e", Some(serde_json::json!({"f": 1})), Some(Value::from(5))),
+                ("g", None, Some(serde_json::json!({"h": true}))),
+            ]
+        );
+    }
+}
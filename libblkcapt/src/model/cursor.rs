@@ -0,0 +1,58 @@
+use super::{ContainerId, SyncId};
+use crate::data_dir;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf};
+
+/// Sync progress persisted across worker restarts, so a backlog resumes where it left off.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SyncCursor {
+    pub targets: Vec<TargetCursor>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TargetCursor {
+    pub container_id: ContainerId,
+    pub last_sent: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub pending: Vec<DateTime<Utc>>,
+    /// The latest failed cycle or transfer, cleared by the next successful transfer.
+    #[serde(default)]
+    pub last_failure: Option<SyncFailure>,
+    /// When a failed transfer is retried.
+    #[serde(default)]
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncFailure {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+fn cursor_path(sync_id: SyncId) -> PathBuf {
+    data_dir().join("sync").join(format!("{}.json", sync_id))
+}
+
+/// Loads the saved progress of a sync, an empty cursor when none has been saved.
+pub fn load_sync_cursor(sync_id: SyncId) -> Result<SyncCursor> {
+    let path = cursor_path(sync_id);
+    match fs::read(&path) {
+        Ok(contents) => {
+            serde_json::from_slice(&contents).with_context(|| format!("failed to parse sync cursor {}", path.display()))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SyncCursor::default()),
+        Err(e) => Err(e).with_context(|| format!("failed to read sync cursor {}", path.display())),
+    }
+}
+
+pub fn store_sync_cursor(sync_id: SyncId, cursor: &SyncCursor) -> Result<()> {
+    let path = cursor_path(sync_id);
+    fs::create_dir_all(path.parent().expect("cursor path always has a parent"))
+        .context("failed to create the sync cursor directory")?;
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_vec(cursor)?)
+        .with_context(|| format!("failed to write sync cursor {}", temp_path.display()))?;
+    fs::rename(&temp_path, &path).with_context(|| format!("failed to replace sync cursor {}", path.display()))
+}
@@ -0,0 +1,363 @@
+use super::{entities::ObservableEvent, ContainerId, EntityId, PoolId};
+use crate::data_dir;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+static JOB_HISTORY_PATH: Lazy<PathBuf> = Lazy::new(|| history_dir().join("jobs.log"));
+static REPOSITORY_HISTORY_PATH: Lazy<PathBuf> = Lazy::new(|| history_dir().join("repositories.log"));
+static SCRUB_HISTORY_PATH: Lazy<PathBuf> = Lazy::new(|| history_dir().join("scrubs.log"));
+/// A history growing past this size is cut down to its newest half.
+const MAX_HISTORY_BYTES: u64 = 8 * 1024 * 1024;
+
+fn history_dir() -> PathBuf {
+    let mut path = data_dir();
+    path.push("history");
+    path
+}
+
+/// A finished job, appended to the job history by the worker.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobRecord {
+    pub entity_id: EntityId,
+    pub event: ObservableEvent,
+    pub started: DateTime<Utc>,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    pub succeeded: bool,
+    /// Bytes sent by a btrfs transfer or added to the repository by a restic backup.
+    #[serde(default)]
+    pub bytes: Option<u64>,
+    /// Delta size a btrfs transfer estimated before sending, also recorded when the transfer was deferred as too small.
+    #[serde(default)]
+    pub estimated_bytes: Option<u64>,
+}
+
+impl JobRecord {
+    pub fn finished(&self) -> DateTime<Utc> {
+        self.started + ChronoDuration::from_std(self.duration).unwrap_or_else(|_| ChronoDuration::zero())
+    }
+}
+
+/// The size of a restic repository when its stats were collected.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RepositoryRecord {
+    pub container_id: ContainerId,
+    pub collected: DateTime<Utc>,
+    pub stored_bytes: u64,
+    pub restore_bytes: u64,
+}
+
+/// A scrub that ran to completion, including those that found uncorrectable errors.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScrubRecord {
+    pub pool_id: PoolId,
+    pub started: DateTime<Utc>,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    pub corrected_errors: u64,
+    pub uncorrectable_errors: u64,
+}
+
+pub fn append_job_record(record: &JobRecord) -> Result<()> {
+    append_line(&JOB_HISTORY_PATH, record).context("failed to append to the job history")
+}
+
+pub fn append_repository_record(record: &RepositoryRecord) -> Result<()> {
+    append_line(&REPOSITORY_HISTORY_PATH, record).context("failed to append to the repository history")
+}
+
+pub fn append_scrub_record(record: &ScrubRecord) -> Result<()> {
+    append_line(&SCRUB_HISTORY_PATH, record).context("failed to append to the scrub history")
+}
+
+/// Reads the job history, oldest record first.
+pub fn read_job_history() -> Result<Vec<JobRecord>> {
+    read_lines(&JOB_HISTORY_PATH).context("failed to read the job history")
+}
+
+/// Reads the repository history, oldest record first.
+pub fn read_repository_history() -> Result<Vec<RepositoryRecord>> {
+    read_lines(&REPOSITORY_HISTORY_PATH).context("failed to read the repository history")
+}
+
+/// Reads the scrub history, oldest record first.
+pub fn read_scrub_history() -> Result<Vec<ScrubRecord>> {
+    read_lines(&SCRUB_HISTORY_PATH).context("failed to read the scrub history")
+}
+
+fn append_line<T: Serialize>(path: &Path, record: &T) -> Result<()> {
+    fs::create_dir_all(path.parent().expect("history always has a parent directory"))?;
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    if file.metadata()?.len() > MAX_HISTORY_BYTES {
+        truncate_oldest(path, MAX_HISTORY_BYTES / 2)?;
+    }
+    Ok(())
+}
+
+// Drops the oldest lines so that at most `keep` bytes of whole lines remain.
+fn truncate_oldest(path: &Path, keep: u64) -> Result<()> {
+    let contents = fs::read(path)?;
+    let cut = contents.len().saturating_sub(keep as usize);
+    let start = if cut == 0 || contents[cut - 1] == b'\n' {
+        cut
+    } else {
+        contents[cut..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(contents.len(), |newline| cut + newline + 1)
+    };
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, &contents[start..])?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// Lines that fail to parse, e.g. one cut short by a crash, are skipped.
+pub(super) fn read_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice(&line) {
+            Ok(record) => records.push(record),
+            Err(_) => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        slog_scope::warn!("Skipped {} unreadable records of {}", skipped, path.display());
+    }
+    Ok(records)
+}
+
+/// The history of the last `days` days, each series holds one value per day with the current day last.
+#[derive(Clone, Debug)]
+pub struct HistorySummary {
+    pub days: usize,
+    /// Snapshots taken per day of each dataset.
+    pub snapshots: HashMap<EntityId, Vec<u64>>,
+    /// Bytes sent per day by each sync.
+    pub transfers: HashMap<EntityId, Vec<u64>>,
+    pub jobs: HashMap<ObservableEvent, JobCounts>,
+    /// Stored bytes of each restic repository at the end of each day, none before the first stats.
+    pub repositories: HashMap<ContainerId, Vec<Option<u64>>>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JobCounts {
+    pub runs: usize,
+    pub succeeded: usize,
+}
+
+impl HistorySummary {
+    /// Summarizes the recorded history up to now.
+    pub fn load(days: usize) -> Result<Self> {
+        Ok(Self::new(
+            &read_job_history()?,
+            &read_repository_history()?,
+            days,
+            Utc::now(),
+        ))
+    }
+
+    pub fn new(jobs: &[JobRecord], repositories: &[RepositoryRecord], days: usize, now: DateTime<Utc>) -> Self {
+        let days = days.max(1);
+        let first_day = now.date() - ChronoDuration::days(days as i64 - 1);
+        let day_index = |time: DateTime<Utc>| {
+            let index = time.date().signed_duration_since(first_day).num_days();
+            Some(index as usize).filter(|i| index >= 0 && *i < days)
+        };
+
+        let mut summary = Self {
+            days,
+            snapshots: HashMap::new(),
+            transfers: HashMap::new(),
+            jobs: HashMap::new(),
+            repositories: HashMap::new(),
+        };
+        for job in jobs {
+            let day = match day_index(job.started) {
+                Some(day) => day,
+                None => continue,
+            };
+            let counts = summary.jobs.entry(job.event).or_default();
+            counts.runs += 1;
+            if !job.succeeded {
+                continue;
+            }
+            counts.succeeded += 1;
+            match job.event {
+                ObservableEvent::DatasetSnapshot => {
+                    summary.snapshots.entry(job.entity_id).or_insert_with(|| vec![0; days])[day] += 1;
+                }
+                ObservableEvent::SnapshotSync => {
+                    summary.transfers.entry(job.entity_id).or_insert_with(|| vec![0; days])[day] +=
+                        job.bytes.unwrap_or_default();
+                }
+                _ => {}
+            }
+        }
+
+        // The latest size of each day, carried forward over days without stats.
+        let mut latest = HashMap::new();
+        for record in repositories {
+            if record.collected < first_day.and_hms(0, 0, 0) {
+                latest.insert(record.container_id, record.stored_bytes);
+            }
+        }
+        for record in repositories {
+            if let Some(day) = day_index(record.collected) {
+                let sizes = summary.repositories.entry(record.container_id).or_insert_with(|| {
+                    let mut sizes = vec![None; days];
+                    sizes[0] = latest.get(&record.container_id).copied();
+                    sizes
+                });
+                sizes[day] = Some(record.stored_bytes);
+            }
+        }
+        for sizes in summary.repositories.values_mut() {
+            let mut previous = None;
+            for size in sizes.iter_mut() {
+                if size.is_none() {
+                    *size = previous;
+                }
+                previous = *size;
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn job(entity_id: EntityId, event: ObservableEvent, day: u32, succeeded: bool, bytes: Option<u64>) -> JobRecord {
+        JobRecord {
+            entity_id,
+            event,
+            started: Utc.ymd(2021, 3, day).and_hms(6, 0, 0),
+            duration: Duration::from_secs(60),
+            succeeded,
+            bytes,
+            estimated_bytes: None,
+        }
+    }
+
+    fn repository(container_id: ContainerId, day: u32, stored_bytes: u64) -> RepositoryRecord {
+        RepositoryRecord {
+            container_id,
+            collected: Utc.ymd(2021, 3, day).and_hms(6, 0, 0),
+            stored_bytes,
+            restore_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn summary_counts_jobs_per_day() {
+        let (dataset, sync) = (EntityId::new(), EntityId::new());
+        let jobs = [
+            job(dataset, ObservableEvent::DatasetSnapshot, 7, true, None),
+            job(dataset, ObservableEvent::DatasetSnapshot, 10, true, None),
+            job(dataset, ObservableEvent::DatasetSnapshot, 10, false, None),
+            job(sync, ObservableEvent::SnapshotSync, 9, true, Some(100)),
+            job(sync, ObservableEvent::SnapshotSync, 9, true, Some(50)),
+            job(sync, ObservableEvent::SnapshotSync, 10, false, Some(70)),
+        ];
+        let summary = HistorySummary::new(&jobs, &[], 3, Utc.ymd(2021, 3, 10).and_hms(12, 0, 0));
+
+        assert_eq!(summary.days, 3);
+        assert_eq!(summary.snapshots[&dataset], vec![0, 0, 1]);
+        assert_eq!(summary.transfers[&sync], vec![0, 150, 0]);
+        let snapshots = summary.jobs[&ObservableEvent::DatasetSnapshot];
+        assert_eq!((snapshots.runs, snapshots.succeeded), (2, 1));
+        let syncs = summary.jobs[&ObservableEvent::SnapshotSync];
+        assert_eq!((syncs.runs, syncs.succeeded), (3, 2));
+    }
+
+    #[test]
+    fn summary_carries_repository_sizes_forward() {
+        let (earlier, later) = (ContainerId::new(), ContainerId::new());
+        let repositories = [
+            repository(earlier, 6, 10),
+            repository(earlier, 9, 20),
+            repository(later, 10, 5),
+        ];
+        let summary = HistorySummary::new(&[], &repositories, 3, Utc.ymd(2021, 3, 10).and_hms(12, 0, 0));
+
+        assert_eq!(summary.repositories[&earlier], vec![Some(10), Some(20), Some(20)]);
+        assert_eq!(summary.repositories[&later], vec![None, None, Some(5)]);
+    }
+
+    #[test]
+    fn job_records_keep_estimated_bytes() {
+        let mut record = job(EntityId::new(), ObservableEvent::SnapshotSync, 1, true, Some(100));
+        record.estimated_bytes = Some(120);
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            serde_json::from_str::<JobRecord>(&line).unwrap().estimated_bytes,
+            Some(120)
+        );
+
+        let older = line.replace(",\"estimated_bytes\":120", "");
+        assert_ne!(older, line);
+        assert_eq!(serde_json::from_str::<JobRecord>(&older).unwrap().estimated_bytes, None);
+    }
+
+    #[test]
+    fn summary_has_at_least_one_day() {
+        let summary = HistorySummary::new(&[], &[], 0, Utc::now());
+        assert_eq!(summary.days, 1);
+    }
+
+    #[test]
+    fn read_lines_skips_unreadable_records() {
+        let path = std::env::temp_dir().join(format!("blkcapt-history-{}", Uuid::new_v4()));
+        let record = repository(ContainerId::new(), 1, 10);
+        let line = serde_json::to_string(&record).unwrap();
+        fs::write(&path, format!("{}\nnot json\n\n{}\n{}", line, line, &line[..10])).unwrap();
+
+        let records = read_lines::<RepositoryRecord>(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.container_id == record.container_id));
+        fs::remove_file(path).unwrap();
+
+        assert!(read_lines::<RepositoryRecord>(Path::new("/nonexistent/history.log"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn truncate_oldest_keeps_whole_lines() {
+        let path = std::env::temp_dir().join(format!("blkcapt-history-{}", Uuid::new_v4()));
+        fs::write(&path, "aaaa\nbbbb\ncccc\n").unwrap();
+        truncate_oldest(&path, 10).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bbbb\ncccc\n");
+        truncate_oldest(&path, 7).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cccc\n");
+        truncate_oldest(&path, 2).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{credentials_dir, data_dir};
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+};
+
+const SECRET_PREFIX: &str = "blkcapt-secret:v1:";
+const KEY_CREDENTIAL: &str = "blockcaptain-secrets";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Encrypted values are stored inline in string config fields, so any such field can hold either plain or
+/// encrypted text.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(SECRET_PREFIX)
+}
+
+/// Display form of a possibly encrypted value that never exposes the plaintext.
+pub fn redacted(value: &str) -> &str {
+    if is_encrypted(value) {
+        "<encrypted>"
+    } else {
+        value
+    }
+}
+
+pub fn encrypt(plaintext: &str) -> Result<String> {
+    let cipher = cipher(&load_key()?);
+    let nonce = random_bytes(NONCE_LEN)?;
+    let mut sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| anyhow!("failed to encrypt secret"))?;
+
+    let mut payload = nonce;
+    payload.append(&mut sealed);
+    Ok(format!("{}{}", SECRET_PREFIX, base64::encode(payload)))
+}
+
+/// Returns the plaintext of a config value, decrypting it if it was stored encrypted.
+pub fn reveal(value: &str) -> Result<String> {
+    let encoded = match value.strip_prefix(SECRET_PREFIX) {
+        Some(encoded) => encoded,
+        None => return Ok(value.to_owned()),
+    };
+
+    let payload = base64::decode(encoded).context("encrypted secret is not valid base64")?;
+    if payload.len() < NONCE_LEN {
+        return Err(anyhow!("encrypted secret is truncated"));
+    }
+    let (nonce, sealed) = payload.split_at(NONCE_LEN);
+    let plaintext = cipher(&load_key()?)
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| anyhow!("failed to decrypt secret, the secrets key may have changed"))?;
+    String::from_utf8(plaintext).context("decrypted secret is not valid utf-8")
+}
+
+/// Creates the root-only secrets keyfile unless a key is already available.
+pub fn ensure_key() -> Result<PathBuf> {
+    if let Some(path) = existing_key_path() {
+        return Ok(path);
+    }
+
+    let path = keyfile_path();
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path.parent().expect("keyfile always has a parent directory"))
+        .context("failed to create secrets directory")?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .context("failed to create secrets keyfile")?;
+    file.write_all(&random_bytes(KEY_LEN)?)?;
+    file.sync_all()?;
+    Ok(path)
+}
+
+fn keyfile_path() -> PathBuf {
+    let mut path = data_dir();
+    path.push("secrets");
+    path.push("secrets.key");
+    path
+}
+
+fn existing_key_path() -> Option<PathBuf> {
+    let credential = credentials_dir().join(KEY_CREDENTIAL);
+    if credential.exists() {
+        return Some(credential);
+    }
+    Some(keyfile_path()).filter(|p| p.exists())
+}
+
+fn load_key() -> Result<Vec<u8>> {
+    let path = existing_key_path().context("no secrets key is available")?;
+    let key = fs::read(&path).with_context(|| format!("failed to read secrets key {}", path.display()))?;
+    if key.len() != KEY_LEN {
+        return Err(anyhow!("secrets key {} must be {} bytes", path.display(), KEY_LEN));
+    }
+    Ok(key)
+}
+
+fn cipher(key: &[u8]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .context("failed to read random bytes")?;
+    Ok(bytes)
+}
@@ -1,4 +1,11 @@
-use crate::{data_dir, model};
+use crate::{
+    data_dir,
+    model::{
+        self, audit,
+        bundle::{pool_entities, ConfigBundle, POOL_REPLICA_PATH},
+        Entity,
+    },
+};
 use anyhow::{Context, Result};
 use once_cell::sync::Lazy;
 use serde::{de::DeserializeOwned, Serialize};
@@ -25,14 +32,48 @@ static ENTITY_PATH: Lazy<PathBuf> = Lazy::new(|| {
     path
 });
 
-pub fn load_entity_config() -> model::Entities {
-    let mut entities: model::Entities = read_state(&ENTITY_PATH).expect("FIXME");
+/// Loads the entity config, an empty one when none has been stored.
+pub fn load_entity_config() -> Result<model::Entities> {
+    let mut entities: model::Entities = read_state(&ENTITY_PATH).context("failed to read the entity config")?;
     entities.post_deserialize();
-    entities
+    Ok(entities)
+}
+
+pub fn store_entity_config(entities: model::Entities) -> Result<()> {
+    match read_state(&ENTITY_PATH) {
+        Ok(previous) => log_audit_failure(audit::audit_entity_change(&previous, &entities)),
+        Err(e) => log_audit_failure(Err(e)),
+    }
+    write_state(&ENTITY_PATH, &entities).context("failed to write the entity config")?;
+    replicate_entity_config(&entities);
+    Ok(())
+}
+
+/// Writes the part of the entity config each attached pool replicates into its metadata directory, with secrets
+/// redacted, so `pool recover` can rediscover the pool's entities on a rebuilt machine.
+fn replicate_entity_config(entities: &model::Entities) {
+    let mut server = None;
+    for pool in entities.btrfs_pools.iter() {
+        let path = pool.mountpoint_path.join(POOL_REPLICA_PATH);
+        // Pools that aren't mounted have no metadata directory at their mountpoint.
+        if !path.parent().map_or(false, Path::is_dir) {
+            continue;
+        }
+        let replica = pool_entities(entities, pool.pool_id()).expect("pool always exists in its entities");
+        let server = server.get_or_insert_with(|| read_state(&SERVER_PATH).unwrap_or_default());
+        if let Err(e) = write_state(&path, &ConfigBundle::new(replica, server.clone(), true)) {
+            slog_scope::warn!(
+                "Failed to replicate the configuration into pool {}: {:#}",
+                pool.name(),
+                e
+            );
+        }
+    }
 }
 
-pub fn store_entity_config(entities: model::Entities) {
-    write_state(&ENTITY_PATH, &entities).expect("FIXME")
+/// Writes an entity config into another data directory without auditing, e.g. to generate a sandbox config.
+pub fn write_entity_config(data_dir: &Path, entities: &model::Entities) -> Result<()> {
+    write_state(&data_dir.join("config").join("entities.json"), entities)
 }
 
 pub fn load_server_config() -> Result<model::ServerConfig> {
@@ -40,20 +81,40 @@ pub fn load_server_config() -> Result<model::ServerConfig> {
 }
 
 pub fn store_server_config(entities: model::ServerConfig) -> Result<()> {
+    match read_state(&SERVER_PATH) {
+        Ok(previous) => log_audit_failure(audit::audit_server_change(&previous, &entities)),
+        Err(e) => log_audit_failure(Err(e)),
+    }
     write_state(&SERVER_PATH, &entities)
 }
 
-fn write_state(path: &Path, state: &impl Serialize) -> Result<()> {
-    // need the libc renameat2 PR merged to make this transactional.
-    // write new file then swap in to place.
-    if !path.exists() {
-        fs::create_dir_all(path.parent().expect("config file always has a parent directory"))
-            .context("failed to create directory structure for state")?;
+// A failed audit record never blocks a configuration change.
+fn log_audit_failure(result: Result<()>) {
+    if let Err(e) = result {
+        slog_scope::warn!("Failed to record the configuration change in the audit log: {:#}", e);
     }
-    let file = File::create(path).context("failed to create updated json state file")?;
-    let writer = BufWriter::new(file);
+}
 
-    serde_json::to_writer_pretty(writer, state).context("failed to write json state data")
+// Writes a temporary file next to the state and renames it into place, so a crash leaves either the previous or the
+// new state, never a truncated one.
+fn write_state(path: &Path, state: &impl Serialize) -> Result<()> {
+    let parent = path.parent().expect("config file always has a parent directory");
+    if !parent.exists() {
+        fs::create_dir_all(parent).context("failed to create directory structure for state")?;
+    }
+    let temp_path = path.with_extension("blkcapt.tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path).context("failed to create updated json state file")?);
+    serde_json::to_writer_pretty(&mut writer, state).context("failed to write json state data")?;
+    let file = writer.into_inner().context("failed to write json state data")?;
+    file.sync_all().context("failed to write json state data")?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(&temp_path, metadata.permissions()).context("failed to keep json state permissions")?;
+    }
+    fs::rename(&temp_path, path).context("failed to replace json state file")?;
+    // The rename itself is only durable once the directory is synced.
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .context("failed to sync json state directory")
 }
 
 fn read_state<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
@@ -66,3 +127,27 @@ fn read_state<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
 
     serde_json::from_reader(reader).context("failed to read json state data")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, os::unix::fs::PermissionsExt};
+    use uuid::Uuid;
+
+    #[test]
+    fn write_state_replaces_file_and_keeps_permissions() {
+        let dir = std::env::temp_dir().join(format!("blkcapt-storage-{}", Uuid::new_v4()));
+        let path = dir.join("config").join("state.json");
+        let mut state = BTreeMap::new();
+        state.insert("version".to_owned(), 1);
+        write_state(&path, &state).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        state.insert("version".to_owned(), 2);
+        write_state(&path, &state).unwrap();
+        assert_eq!(read_state::<BTreeMap<String, u32>>(&path).unwrap(), state);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!path.with_extension("blkcapt.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
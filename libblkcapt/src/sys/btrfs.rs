@@ -1,19 +1,22 @@
 use super::fs::{BtrfsMountEntry, DevicePathBuf, FsPathBuf};
 use crate::parsing::{parse_key_value_pair_lines, parse_uuid, StringPair};
+use crate::sys::process::JobKind;
 #[mockall_double::double]
 use crate::sys::{fs::double as fs_double, process::double as process_double};
 use anyhow::{anyhow, bail, Context, Result};
 use fs_double::lookup_mountentries_by_devices;
+use nix::mount::MsFlags;
 pub use operations::*;
-use process_double::run_command_as_result;
-use serde::Deserialize;
-use std::{convert::TryFrom, fs::OpenOptions, process::Command, writeln};
-use std::{convert::TryInto, num::NonZeroUsize, string::String};
+use process_double::{run_command, run_command_as_result};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     ffi::OsStr,
     io::Write,
     path::{Path, PathBuf},
 };
+use std::{convert::TryFrom, fmt, fs, process::Command, str::FromStr};
+use std::{convert::TryInto, num::NonZeroUsize, string::String};
 use strum_macros::Display;
 use strum_macros::EnumString;
 use uuid::Uuid;
@@ -29,6 +32,87 @@ macro_rules! once_regex {
     }};
 }
 
+/// The oldest btrfs-progs the parsers here understand, `subvolume show -u` arrived in 4.17.
+pub const MIN_PROGS_VERSION: (u32, u32) = (4, 17);
+
+static DETECTED_PROGS_VERSION: once_cell::sync::OnceCell<(u32, u32)> = once_cell::sync::OnceCell::new();
+
+/// Returns the major and minor version of the installed btrfs-progs.
+pub fn progs_version() -> Result<(u32, u32)> {
+    let output = run_command_as_result({
+        let mut command = btrfs_command();
+        command.arg("--version");
+        command
+    })?;
+    // Versions before 3.14 print "Btrfs v3.12".
+    let captures = once_regex!(r"(?i)\bbtrfs(?:-progs)? v(\d+)\.(\d+)")
+        .captures(&output)
+        .ok_or_else(|| anyhow!("unexpected btrfs version output: {}", output.trim()))?;
+    Ok((captures[1].parse()?, captures[2].parse()?))
+}
+
+/// Probes the installed btrfs-progs and remembers the version for the parsers to adapt to. Fails for versions
+/// older than [`MIN_PROGS_VERSION`].
+pub fn detect_progs_version() -> Result<(u32, u32)> {
+    let version = progs_version()?;
+    if version < MIN_PROGS_VERSION {
+        bail!(
+            "unsupported btrfs-progs version {}.{}, at least {}.{} is required",
+            version.0,
+            version.1,
+            MIN_PROGS_VERSION.0,
+            MIN_PROGS_VERSION.1
+        );
+    }
+    let _ = DETECTED_PROGS_VERSION.set(version);
+    Ok(version)
+}
+
+// Without a detected version the current btrfs-progs output is assumed.
+fn progs_at_least(version: (u32, u32)) -> bool {
+    DETECTED_PROGS_VERSION
+        .get()
+        .map_or(true, |detected| *detected >= version)
+}
+
+fn unexpected_output(command: &str) -> anyhow::Error {
+    match DETECTED_PROGS_VERSION.get() {
+        Some((major, minor)) => anyhow!(
+            "unexpected output of `btrfs {}` from btrfs-progs {}.{}, the version may be unsupported",
+            command,
+            major,
+            minor
+        ),
+        None => anyhow!("unexpected output of `btrfs {}`", command),
+    }
+}
+
+// Unit options for `subvolume show` arrived with its quota output in 5.0.
+fn subvolume_show_command() -> Command {
+    let mut command = btrfs_command();
+    command.args(&["subvolume", "show"]);
+    if progs_at_least((5, 0)) {
+        command.arg("--raw");
+    }
+    command
+}
+
+/// Whether both btrfs-progs and the running kernel support `btrfs send --compressed-data`.
+pub fn compressed_send_supported() -> bool {
+    static SUPPORTED: once_cell::sync::OnceCell<bool> = once_cell::sync::OnceCell::new();
+    *SUPPORTED.get_or_init(|| {
+        let kernel_stream_version = std::fs::read_to_string("/sys/fs/btrfs/features/send_stream_version")
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(1);
+        progs_version().map_or(false, |progs| compressed_send_versions(kernel_stream_version, progs))
+    })
+}
+
+fn compressed_send_versions(kernel_stream_version: u32, progs_version: (u32, u32)) -> bool {
+    kernel_stream_version >= 2 && progs_version >= (5, 18)
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Filesystem {
     pub uuid: Uuid,
@@ -79,18 +163,23 @@ impl Filesystem {
         let uuid_regex = once_regex!(r"(?m)\buuid:\s+(.*?)\s*$");
         let devs_regex = once_regex!(r"(?m)^\s+devid\b.+\bpath\s+(.*?)\s*$");
 
-        let uuid_match = uuid_regex.captures(&output_data);
-        let device_matches = devs_regex.captures_iter(&output_data);
-
-        let devices = device_matches
+        let uuid = uuid_regex
+            .captures(&output_data)
+            .and_then(|m| m.get(1).expect("regex group always exists").as_str().parse().ok())
+            .ok_or_else(|| unexpected_output("filesystem show"))?;
+        let devices = devs_regex
+            .captures_iter(&output_data)
             .map(|m| {
                 m.get(1)
-                    .unwrap()
+                    .expect("regex group always exists")
                     .as_str()
                     .parse()
-                    .expect("Device node path should parse.")
+                    .map_err(|_| unexpected_output("filesystem show"))
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
+        if devices.is_empty() {
+            return Err(unexpected_output("filesystem show"));
+        }
 
         let fstree_mountpoint =
             lookup_mountentries_by_devices(&devices).find_map(|m| match BtrfsMountEntry::try_from(m) {
@@ -98,16 +187,7 @@ impl Filesystem {
                 _ => None,
             });
 
-        let filesystem = Filesystem {
-            uuid: uuid_match
-                .expect("Successful btrfs fi show should have UUID output.")
-                .get(1)
-                .unwrap()
-                .as_str()
-                .parse()
-                .expect("UUID should parse."),
-            devices,
-        };
+        let filesystem = Filesystem { uuid, devices };
 
         Ok(match fstree_mountpoint {
             Some(fstree_mountpoint) => QueriedFilesystem::Mounted(MountedFilesystem {
@@ -154,17 +234,19 @@ impl Filesystem {
         })
     }
 
-    pub fn mount(self, path: &Path) -> Result<MountedFilesystem> {
-        use nix::mount::{mount, MsFlags};
+    pub fn mount(self, path: &Path, options: &[String]) -> Result<MountedFilesystem> {
+        use nix::mount::mount;
 
+        let (flags, data) = split_mount_options(options);
+        let data = data.join(",");
         mount(
             Some(AsRef::<OsStr>::as_ref(
                 self.devices.first().expect("filesystem always has >=1 device"),
             )),
             path,
             Some("btrfs"),
-            MsFlags::MS_NOATIME,
-            Option::<&str>::None,
+            flags,
+            Some(data.as_str()).filter(|d| !d.is_empty()),
         )
         .context("btrfs mount syscall failed")?;
 
@@ -184,22 +266,284 @@ impl Filesystem {
     }
 }
 
-pub fn add_to_fstab(mounted: &MountedFilesystem) -> Result<()> {
-    let line = fstab_line(mounted);
-    let mut file = OpenOptions::new().append(true).create(true).open("/etc/fstab")?;
-    writeln!(file)
-        .and_then(|_| writeln!(file, "{}", line))
-        .context("writing to fstab failed")
+const ATIME_OPTIONS: [&str; 4] = ["atime", "noatime", "relatime", "strictatime"];
+
+/// Splits mount options into generic mount flags and btrfs specific options. Pools default to noatime.
+pub fn split_mount_options(options: &[String]) -> (MsFlags, Vec<&str>) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options.iter().map(|o| o.as_str()) {
+        match option {
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "relatime" => flags |= MsFlags::MS_RELATIME,
+            "strictatime" => flags |= MsFlags::MS_STRICTATIME,
+            "nodiratime" => flags |= MsFlags::MS_NODIRATIME,
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "defaults" | "atime" | "rw" | "suid" | "dev" | "exec" => {}
+            other => data.push(other),
+        }
+    }
+    if !options.iter().any(|o| ATIME_OPTIONS.contains(&o.as_str())) {
+        flags |= MsFlags::MS_NOATIME;
+    }
+    (flags, data)
+}
+
+/// Returns the configured btrfs specific options that are missing from the live mount options.
+pub fn missing_mount_options<'a>(configured: &'a [String], live: &[&str]) -> Vec<&'a str> {
+    // The kernel reports some options with their implied level, e.g. compress=zstd as compress=zstd:3.
+    split_mount_options(configured)
+        .1
+        .into_iter()
+        .filter(|option| {
+            !live
+                .iter()
+                .any(|l| l == option || l.strip_prefix(option).map_or(false, |r| r.starts_with(':')))
+        })
+        .collect()
+}
+
+const FSTAB: &str = "/etc/fstab";
+const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Adds the fstab entry for the filesystem, replacing any existing entry mounting its fstree at the same place.
+pub fn add_to_fstab(mounted: &MountedFilesystem, options: &[String]) -> Result<()> {
+    let contents = read_fstab()?;
+    let line = fstab_line(mounted, options);
+    write_fstab(&fstab_with_entry(
+        &contents,
+        &mounted.filesystem.uuid,
+        &mounted.fstree_mountpoint,
+        Some(&line),
+    ))
+}
+
+/// Removes the fstab entry mounting the filesystem's fstree at the mountpoint. Returns whether an entry was found.
+pub fn remove_from_fstab(uuid: &Uuid, mountpoint: &Path) -> Result<bool> {
+    let contents = read_fstab()?;
+    let updated = fstab_with_entry(&contents, uuid, mountpoint, None);
+    if updated == contents {
+        return Ok(false);
+    }
+    write_fstab(&updated)?;
+    Ok(true)
+}
+
+/// The subvolume the root filesystem's fstab entry mounts with its subvol option, none without the option.
+pub fn fstab_root_subvolume() -> Result<Option<FsPathBuf>> {
+    Ok(fstab_root_subvol(&read_fstab()?).map(|s| FsPathBuf::from(s.trim_start_matches('/'))))
+}
+
+/// Points the subvol option of the root filesystem's fstab entry at another subvolume.
+pub fn set_fstab_root_subvolume(path: &FsPathBuf) -> Result<()> {
+    let contents = read_fstab()?;
+    let subvol = format!("/{}", path.as_pathbuf(Path::new("")).to_string_lossy());
+    write_fstab(&fstab_with_root_subvol(&contents, &subvol))
+}
+
+// Replaces fstab as a whole, an interrupted write must not leave a system that fails to mount its root.
+fn write_fstab(contents: &str) -> Result<()> {
+    let temp_path = Path::new(FSTAB).with_extension("blkcapt.tmp");
+    let mut file = fs::File::create(&temp_path).context("writing to fstab failed")?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .context("writing to fstab failed")?;
+    if let Ok(metadata) = fs::metadata(FSTAB) {
+        fs::set_permissions(&temp_path, metadata.permissions()).context("writing to fstab failed")?;
+    }
+    fs::rename(&temp_path, FSTAB).context("replacing fstab failed")
+}
+
+fn is_fstab_comment(line: &str) -> bool {
+    line.trim_start().starts_with('#')
+}
+
+fn fstab_root_subvol(contents: &str) -> Option<&str> {
+    contents
+        .lines()
+        .filter(|line| !is_fstab_comment(line))
+        .find_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                [_, "/", "btrfs", options, ..] => (*options).split(',').find_map(|o| o.strip_prefix("subvol=")),
+                _ => None,
+            }
+        })
+}
+
+// Replaces the subvol option of the btrfs root entry, dropping a subvolid option that would contradict it.
+fn fstab_with_root_subvol(contents: &str, subvol: &str) -> String {
+    contents
+        .lines()
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                [spec, "/", "btrfs", options, rest @ ..] if !is_fstab_comment(line) => {
+                    let options = options
+                        .split(',')
+                        .filter(|o| !o.starts_with("subvolid="))
+                        .map(|o| match o.strip_prefix("subvol=") {
+                            Some(_) => format!("subvol={}", subvol),
+                            None => o.to_owned(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let mut fields = vec![spec.to_string(), String::from("/"), String::from("btrfs"), options];
+                    fields.extend(rest.iter().map(|f| f.to_string()));
+                    format!("{}\n", fields.join("\t"))
+                }
+                _ => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+fn parse_default_subvolume(output: &str) -> Result<Option<FsPathBuf>> {
+    let default_regex = once_regex!(r"^ID (\d+) (?:\(FS_TREE\)|.*\bpath (.+))$");
+    let captures = default_regex
+        .captures(output.trim())
+        .ok_or_else(|| unexpected_output("subvolume get-default"))?;
+    Ok(captures.get(2).map(|path| FsPathBuf::from(path.as_str())))
+}
+
+fn read_fstab() -> Result<String> {
+    match fs::read_to_string(FSTAB) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        result => result.context("reading fstab failed"),
+    }
+}
+
+// Other entries of the same filesystem, such as the root or home subvolume of the OS, must be left alone.
+fn fstab_with_entry(contents: &str, uuid: &Uuid, mountpoint: &Path, line: Option<&str>) -> String {
+    let spec = format!("UUID={}", uuid.to_hyphenated());
+    let mut updated = contents
+        .lines()
+        .filter(|l| !is_fstree_fstab_entry(l, &spec, mountpoint))
+        .map(|l| format!("{}\n", l))
+        .collect::<String>();
+    if let Some(line) = line {
+        updated.push_str(line);
+        updated.push('\n');
+    }
+    updated
+}
+
+fn is_fstree_fstab_entry(line: &str, spec: &str, mountpoint: &Path) -> bool {
+    if is_fstab_comment(line) {
+        return false;
+    }
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    match fields.as_slice() {
+        [entry_spec, entry_mountpoint, rest @ ..] => {
+            entry_spec.eq_ignore_ascii_case(spec)
+                && Path::new(entry_mountpoint) == mountpoint
+                && !rest.get(1).map_or(false, |options| {
+                    options
+                        .split(',')
+                        .any(|o| o.starts_with("subvol=") || o.starts_with("subvolid="))
+                })
+        }
+        _ => false,
+    }
 }
 
-pub fn fstab_line(mounted: &MountedFilesystem) -> String {
+pub fn fstab_line(mounted: &MountedFilesystem, options: &[String]) -> String {
     format!(
-        "UUID={}\t{}\tbtrfs\tdefaults,noatime\t0\t0",
+        "UUID={}\t{}\tbtrfs\t{}\t0\t0",
         mounted.filesystem.uuid.to_hyphenated(),
-        mounted.fstree_mountpoint.to_string_lossy()
+        mounted.fstree_mountpoint.to_string_lossy(),
+        persistent_mount_options(options)
+    )
+}
+
+fn persistent_mount_options(options: &[String]) -> String {
+    let mut persistent_options = if options.is_empty() {
+        vec![String::from("defaults")]
+    } else {
+        options.to_vec()
+    };
+    if !options.iter().any(|o| ATIME_OPTIONS.contains(&o.as_str())) {
+        persistent_options.push(String::from("noatime"));
+    }
+    persistent_options.join(",")
+}
+
+/// Writes and enables a systemd mount unit for the filesystem as an alternative to an fstab entry.
+pub fn add_mount_unit(mounted: &MountedFilesystem, options: &[String]) -> Result<PathBuf> {
+    let unit_name = mount_unit_name(&mounted.fstree_mountpoint);
+    let unit_path = Path::new(SYSTEMD_UNIT_DIR).join(&unit_name);
+    fs::write(&unit_path, mount_unit_contents(mounted, options)).context("writing mount unit failed")?;
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", &unit_name])?;
+    Ok(unit_path)
+}
+
+/// Disables and deletes the systemd mount unit for the mountpoint. Returns whether a unit was found.
+pub fn remove_mount_unit(mountpoint: &Path) -> Result<bool> {
+    let unit_name = mount_unit_name(mountpoint);
+    let unit_path = Path::new(SYSTEMD_UNIT_DIR).join(&unit_name);
+    if !unit_path.exists() {
+        return Ok(false);
+    }
+    systemctl(&["disable", &unit_name])?;
+    fs::remove_file(&unit_path).context("deleting mount unit failed")?;
+    systemctl(&["daemon-reload"])?;
+    Ok(true)
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    run_command_as_result({
+        let mut command = Command::new("systemctl");
+        command.args(args);
+        command
+    })
+    .with_context(|| format!("systemctl {} failed", args.join(" ")))
+    .map(|_| ())
+}
+
+fn mount_unit_contents(mounted: &MountedFilesystem, options: &[String]) -> String {
+    format!(
+        "[Unit]\n\
+        Description=blockcaptain pool {uuid}\n\
+        \n\
+        [Mount]\n\
+        What=/dev/disk/by-uuid/{uuid}\n\
+        Where={where_}\n\
+        Type=btrfs\n\
+        Options={options}\n\
+        \n\
+        [Install]\n\
+        WantedBy=local-fs.target\n",
+        uuid = mounted.filesystem.uuid.to_hyphenated(),
+        where_ = mounted.fstree_mountpoint.to_string_lossy(),
+        options = persistent_mount_options(options)
     )
 }
 
+/// The unit name systemd requires for a mount unit, equivalent to `systemd-escape --path --suffix=mount`.
+fn mount_unit_name(mountpoint: &Path) -> String {
+    let path = mountpoint.to_string_lossy();
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return String::from("-.mount");
+    }
+
+    let mut name = String::new();
+    for (index, byte) in trimmed.bytes().enumerate() {
+        match byte {
+            b'/' => name.push('-'),
+            b'.' if index == 0 => name.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' => name.push(b as char),
+            b => name.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    name.push_str(".mount");
+    name
+}
+
 #[derive(Clone, Copy, Display, Debug, EnumString, PartialEq, Eq)]
 #[strum(serialize_all = "snake_case")]
 pub enum AllocationMode {
@@ -211,14 +555,348 @@ pub enum AllocationMode {
     Duplicate,
 }
 
+/// When `btrfs subvolume delete` waits for the deletions to be committed, trading speed for durability.
+#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum DeleteCommit {
+    /// Leave the commit to the next periodic transaction.
+    None,
+    /// Commit once after all subvolumes of an invocation are deleted.
+    After,
+    /// Commit after each subvolume.
+    Each,
+}
+
+impl Default for DeleteCommit {
+    fn default() -> Self {
+        DeleteCommit::None
+    }
+}
+
+impl AllocationMode {
+    pub fn min_devices(&self) -> usize {
+        match self {
+            AllocationMode::Single | AllocationMode::Duplicate => 1,
+            AllocationMode::Raid1 => 2,
+            AllocationMode::Raid1c3 => 3,
+            AllocationMode::Raid1c4 => 4,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BalanceProgress {
+    pub balanced: u64,
+    pub expected: u64,
+    pub considered: u64,
+    pub percent_left: u8,
+}
+
+impl BalanceProgress {
+    fn parse(output: &str) -> Result<Option<Self>> {
+        if output.starts_with("No balance found") {
+            return Ok(None);
+        }
+
+        let progress_regex =
+            once_regex!(r"(?m)^(\d+) out of about (\d+) chunks balanced \((\d+) considered\),\s+(\d+)% left");
+        let captures = progress_regex
+            .captures(output)
+            .ok_or_else(|| anyhow!("Unexpected btrfs balance status output: {}", output.trim()))?;
+        let number = |i| captures[i].parse::<u64>().expect("regex only matches digits");
+        Ok(Some(Self {
+            balanced: number(1),
+            expected: number(2),
+            considered: number(3),
+            percent_left: captures[4].parse()?,
+        }))
+    }
+}
+
+/// The error counts a scrub reported for one device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrubDeviceResult {
+    pub device: String,
+    pub corrected_errors: u64,
+    pub uncorrectable_errors: u64,
+}
+
+/// What `btrfs scrub start -BRd` reported, one entry per device.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScrubResult {
+    pub devices: Vec<ScrubDeviceResult>,
+}
+
+impl ScrubResult {
+    fn parse(output: &str) -> Self {
+        let device_regex = once_regex!(r"^scrub device (\S+) \(id \d+\)");
+        let counter_regex = once_regex!(r"^\s*(corrected_errors|uncorrectable_errors):\s*(\d+)");
+        let mut result = Self::default();
+        for line in output.lines() {
+            if let Some(captures) = device_regex.captures(line) {
+                result.devices.push(ScrubDeviceResult {
+                    device: captures[1].to_owned(),
+                    corrected_errors: 0,
+                    uncorrectable_errors: 0,
+                });
+            } else if let (Some(captures), Some(device)) = (counter_regex.captures(line), result.devices.last_mut()) {
+                let count = captures[2].parse().unwrap_or(u64::MAX);
+                match &captures[1] {
+                    "corrected_errors" => device.corrected_errors = count,
+                    _ => device.uncorrectable_errors = count,
+                }
+            }
+        }
+        result
+    }
+
+    pub fn corrected_errors(&self) -> u64 {
+        self.devices.iter().map(|d| d.corrected_errors).sum()
+    }
+
+    pub fn uncorrectable_errors(&self) -> u64 {
+        self.devices.iter().map(|d| d.uncorrectable_errors).sum()
+    }
+}
+
+/// What `duperemove -q` reported in its closing summary.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DedupResult {
+    /// The growth of shared extents across the deduplicated files, absent when duperemove printed no summary.
+    pub deduplicated_bytes: Option<u64>,
+}
+
+impl DedupResult {
+    fn parse(output: &str) -> Self {
+        let summary_regex = once_regex!(r"net change in shared extents of:\s*([\d.]+)\s*([KMGTPE]?)i?B?\s*$");
+        let deduplicated_bytes = output
+            .lines()
+            .filter_map(|line| summary_regex.captures(line.trim_end()))
+            .last()
+            .and_then(|captures| {
+                let value = captures[1].parse::<f64>().ok()?;
+                let exponent = captures[2]
+                    .chars()
+                    .next()
+                    .and_then(|unit| "KMGTPE".find(unit))
+                    .map_or(0, |i| i as i32 + 1);
+                Some((value * 1024f64.powi(exponent)) as u64)
+            });
+        Self { deduplicated_bytes }
+    }
+}
+
+/// The cumulative error counters the kernel keeps for a device, as listed by `btrfs device stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceStats {
+    pub device: String,
+    pub counters: Vec<(String, u64)>,
+}
+
+impl DeviceStats {
+    fn parse(output: &str) -> Result<Vec<Self>> {
+        let counter_regex = once_regex!(r"^\[(.+)\]\.(\w+)\s+(\d+)$");
+        let mut stats: Vec<Self> = Vec::new();
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            let captures = counter_regex
+                .captures(line.trim())
+                .ok_or_else(|| anyhow!("Unexpected btrfs device stats output: {}", line.trim()))?;
+            let counter = (captures[2].to_owned(), captures[3].parse()?);
+            match stats.last_mut().filter(|s| s.device == captures[1]) {
+                Some(device) => device.counters.push(counter),
+                None => stats.push(Self {
+                    device: captures[1].to_owned(),
+                    counters: vec![counter],
+                }),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// The counters that are not zero, formatted as `name count` pairs.
+    pub fn nonzero_summary(&self) -> String {
+        self.counters
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, count)| format!("{} {}", name, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Allocation profiles in use by a filesystem. A type has more than one profile while a conversion is incomplete.
+#[derive(Debug, PartialEq)]
+pub struct AllocationProfiles {
+    pub data: Vec<AllocationMode>,
+    pub metadata: Vec<AllocationMode>,
+}
+
+impl AllocationProfiles {
+    fn parse(output: &str) -> Result<Self> {
+        let profile_regex = once_regex!(r"(?m)^(Data|Metadata|System),\s+(\w+):");
+        let mut profiles = AllocationProfiles {
+            data: Vec::new(),
+            metadata: Vec::new(),
+        };
+        for captures in profile_regex.captures_iter(output) {
+            let mode = captures[2]
+                .to_lowercase()
+                .parse::<AllocationMode>()
+                .map_err(|_| anyhow!("Unsupported btrfs allocation profile {}.", &captures[2]))?;
+            let modes = match &captures[1] {
+                "Data" => &mut profiles.data,
+                _ => &mut profiles.metadata,
+            };
+            if !modes.contains(&mode) {
+                modes.push(mode);
+            }
+        }
+        Ok(profiles)
+    }
+
+    /// The number of devices required to keep every profile in use satisfied.
+    pub fn min_devices(&self) -> usize {
+        self.data
+            .iter()
+            .chain(self.metadata.iter())
+            .map(AllocationMode::min_devices)
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+/// The accounting of a qgroup, as listed by `btrfs qgroup show -p`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qgroup {
+    pub exclusive: u64,
+    /// The ids of the higher level qgroups it is a member of.
+    pub parents: Vec<String>,
+}
+
+impl Qgroup {
+    fn parse_all(output: &str) -> Vec<(String, Self)> {
+        let qgroup_regex = once_regex!(r"(?m)^(\d+/\d+)\s+\d+\s+(\d+)\s+(\S+)");
+        qgroup_regex
+            .captures_iter(output)
+            .filter_map(|c| {
+                let parents = c[3].split(',').filter(|p| p.contains('/')).map(String::from).collect();
+                Some((
+                    c[1].to_owned(),
+                    Self {
+                        exclusive: c[2].parse().ok()?,
+                        parents,
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceUsage {
+    pub size: u64,
+    /// The data the devices hold with the data profile, the device size divided by the copies it keeps.
+    pub capacity: u64,
+    pub free: u64,
+}
+
+impl SpaceUsage {
+    fn parse(output: &str) -> Result<Self> {
+        let size_regex = once_regex!(r"(?m)^\s*Device size:\s+(\d+)");
+        let free_regex = once_regex!(r"(?m)^\s*Free \(estimated\):\s+(\d+)");
+        let ratio_regex = once_regex!(r"(?m)^\s*Data ratio:\s+([\d.]+)");
+        let value = |regex: &regex::Regex, name: &str| -> Result<u64> {
+            regex
+                .captures(output)
+                .and_then(|c| c[1].parse().ok())
+                .ok_or_else(|| anyhow!("Failed to parse {} from btrfs filesystem usage.", name))
+        };
+        let ratio = ratio_regex
+            .captures(output)
+            .and_then(|c| c[1].parse::<f64>().ok())
+            .filter(|r| *r >= 1.0)
+            .ok_or_else(|| anyhow!("Failed to parse data ratio from btrfs filesystem usage."))?;
+        let size = value(size_regex, "device size")?;
+        Ok(Self {
+            size,
+            capacity: (size as f64 / ratio) as u64,
+            free: value(free_regex, "free space")?,
+        })
+    }
+
+    /// The estimated free space relative to the capacity, free space being estimated with the data profile too.
+    pub fn free_percent(&self) -> u8 {
+        if self.capacity == 0 {
+            return 0;
+        }
+        u8::try_from(self.free.min(self.capacity) * 100 / self.capacity).expect("percent always fits in u8")
+    }
+}
+
+/// A device of the filesystem with its sizes in bytes, as listed by `btrfs filesystem show`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemDevice {
+    pub devid: u64,
+    /// The size of the device the filesystem uses, which may be less than the block device after it grew.
+    pub size: u64,
+    pub used: u64,
+    pub path: DevicePathBuf,
+}
+
+impl FilesystemDevice {
+    fn parse_all(output: &str) -> Result<Vec<Self>> {
+        let device_regex = once_regex!(r"(?m)^\s+devid\s+(\d+)\s+size\s+(\d+)\s+used\s+(\d+)\s+path\s+(.*?)\s*$");
+        device_regex
+            .captures_iter(output)
+            .map(|c| {
+                Ok(Self {
+                    devid: c[1].parse()?,
+                    size: c[2].parse()?,
+                    used: c[3].parse()?,
+                    path: c[4].parse().map_err(|_| unexpected_output("filesystem show"))?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The new size of a device for `btrfs filesystem resize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeTarget {
+    /// All of the underlying block device.
+    Max,
+    Bytes(u64),
+}
+
+impl fmt::Display for ResizeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResizeTarget::Max => f.write_str("max"),
+            ResizeTarget::Bytes(bytes) => write!(f, "{}", bytes),
+        }
+    }
+}
+
+impl FromStr for ResizeTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(ResizeTarget::Max),
+            _ => s
+                .parse()
+                .map(ResizeTarget::Bytes)
+                .map_err(|_| anyhow!("Size must be a number of bytes or max.")),
+        }
+    }
+}
+
 impl MountedFilesystem {
     pub fn subvolume_by_uuid(&self, uuid: &Uuid) -> Result<Subvolume> {
         let output_data = run_command_as_result({
-            let mut command = btrfs_command();
-            command
-                .args(&["subvolume", "show", "--raw", "-u"])
-                .arg(uuid.to_string())
-                .arg(&self.fstree_mountpoint);
+            let mut command = subvolume_show_command();
+            command.arg("-u").arg(uuid.to_string()).arg(&self.fstree_mountpoint);
             command
         })?;
         Subvolume::_parse(String::from("path: ") + &output_data)
@@ -259,6 +937,46 @@ impl MountedFilesystem {
         .map(|_| ())
     }
 
+    /// Creates a writable snapshot of `source`, e.g. to boot from.
+    pub fn create_writable_snapshot(&self, source: &FsPathBuf, path: &FsPathBuf) -> Result<()> {
+        let target_path = path.as_pathbuf(&self.fstree_mountpoint);
+        if target_path.exists() {
+            bail!("Path to new snapshot, {:?}, already exists!", &target_path)
+        }
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "snapshot"])
+                .arg(source.as_pathbuf(&self.fstree_mountpoint))
+                .arg(target_path);
+            command
+        })
+        .context(format!("Failed to create btrfs snapshot at {:?}.", path))
+        .map(|_| ())
+    }
+
+    /// The subvolume mounted when no subvol option is given, none when it is the top level.
+    pub fn default_subvolume(&self) -> Result<Option<FsPathBuf>> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["subvolume", "get-default"]).arg(&self.fstree_mountpoint);
+            command
+        })?;
+        parse_default_subvolume(&output_data)
+    }
+
+    pub fn set_default_subvolume(&self, path: &FsPathBuf) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "set-default"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint));
+            command
+        })
+        .context(format!("Failed to set the default subvolume to {:?}.", path))
+        .map(|_| ())
+    }
+
     pub fn delete_subvolume(&self, path: &FsPathBuf) -> Result<()> {
         let target_path = path.as_pathbuf(&self.fstree_mountpoint);
         if !target_path.exists() {
@@ -273,25 +991,98 @@ impl MountedFilesystem {
         .map(|_| ())
     }
 
-    pub fn send_subvolume(&self, path: &FsPathBuf, parent: Option<&FsPathBuf>) -> SnapshotSender {
-        let mut command = tokio::process::Command::new("btrfs");
-        let source_snap_path = path.as_pathbuf(&self.fstree_mountpoint);
-        match parent {
-            Some(parent_snapshot) => {
-                let parent_snap_path = parent_snapshot.as_pathbuf(&self.fstree_mountpoint);
+    /// Deletes subvolumes with one `btrfs subvolume delete` per chunk of paths. A failed chunk doesn't stop the
+    /// remaining ones, btrfs also carries on past paths it fails to delete.
+    pub fn delete_subvolumes(
+        &self, paths: &[&FsPathBuf], chunk_size: NonZeroUsize, commit: DeleteCommit,
+    ) -> Result<()> {
+        let mut failed = Vec::new();
+        for chunk in paths.chunks(chunk_size.get()) {
+            let result = run_command_as_result({
+                let mut command = btrfs_command();
+                command.args(&["subvolume", "delete"]);
+                match commit {
+                    DeleteCommit::None => {}
+                    DeleteCommit::After => {
+                        command.arg("--commit-after");
+                    }
+                    DeleteCommit::Each => {
+                        command.arg("--commit-each");
+                    }
+                }
+                command.args(chunk.iter().map(|p| p.as_pathbuf(&self.fstree_mountpoint)));
                 command
-                    .arg("send")
-                    .arg("-p")
-                    .arg(parent_snap_path)
-                    .arg(source_snap_path)
+            });
+            if let Err(e) = result {
+                failed.push(format!("{:#}", e));
             }
-            None => command.arg("send").arg(source_snap_path),
-        };
-        SnapshotSender::new(command)
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Failed to delete btrfs subvolumes: {}", failed.join("; ")))
+        }
+    }
+
+    pub fn delete_subvolume_tree(&self, path: &FsPathBuf) -> Result<()> {
+        let mut nested = self.list_subvolumes(path)?;
+        // Reverse path order always visits children before their parents.
+        nested.sort_unstable_by(|a, b| b.path.cmp(&a.path));
+        for subvolume in nested {
+            self.delete_subvolume(&subvolume.path)?;
+        }
+        self.delete_subvolume(path)
+    }
+
+    pub fn list_nested_subvolumes(&self, path: &FsPathBuf) -> Result<Vec<Subvolume>> {
+        let mut nested = Vec::new();
+        let mut pending = vec![path.clone()];
+        while let Some(next) = pending.pop() {
+            for subvolume in self.list_subvolumes(&next)? {
+                pending.push(subvolume.path.clone());
+                nested.push(subvolume);
+            }
+        }
+        nested.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        Ok(nested)
+    }
+
+    pub fn send_subvolume_set(
+        &self, paths: &[&FsPathBuf], clone_sources: &[&FsPathBuf], compressed: bool,
+    ) -> SnapshotSender {
+        SnapshotSender::new(self.send_command(paths, None, clone_sources, compressed))
+    }
+
+    pub fn send_subvolume(&self, path: &FsPathBuf, parent: Option<&FsPathBuf>, compressed: bool) -> SnapshotSender {
+        SnapshotSender::new(self.send_command(&[path], parent, &[], compressed))
+    }
+
+    fn send_command(
+        &self, paths: &[&FsPathBuf], parent: Option<&FsPathBuf>, clone_sources: &[&FsPathBuf], compressed: bool,
+    ) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new("btrfs");
+        JobKind::Send.apply(&mut command);
+        command.arg("send");
+        if compressed {
+            command.arg("--compressed-data");
+        }
+        if let Some(parent_snapshot) = parent {
+            command
+                .arg("-p")
+                .arg(parent_snapshot.as_pathbuf(&self.fstree_mountpoint));
+        }
+        for clone_source in clone_sources {
+            command.arg("-c").arg(clone_source.as_pathbuf(&self.fstree_mountpoint));
+        }
+        for path in paths {
+            command.arg(path.as_pathbuf(&self.fstree_mountpoint));
+        }
+        command
     }
 
     pub fn receive_subvolume(&self, into_path: &FsPathBuf) -> SnapshotReceiver {
         let mut command = tokio::process::Command::new("btrfs");
+        JobKind::Receive.apply(&mut command);
         let target_into_path = into_path.as_pathbuf(&self.fstree_mountpoint);
         command.arg("receive").arg(target_into_path);
         SnapshotReceiver::new(command)
@@ -302,17 +1093,306 @@ impl MountedFilesystem {
         Subvolume::list_subvolumes(&target_path)
     }
 
-    pub fn scrub(&self) -> PoolScrub {
-        let mut command = tokio::process::Command::new("btrfs");
-        command.args(&["scrub", "start", "-BRd"]).arg(&self.fstree_mountpoint);
-        PoolScrub::new(command)
+    pub fn subvolume_generation(&self, path: &FsPathBuf) -> Result<u64> {
+        Subvolume::generation(&path.as_pathbuf(&self.fstree_mountpoint))
     }
-}
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-pub struct Subvolume {
-    pub uuid: Uuid,
-    pub path: FsPathBuf,
+    pub fn subvolume_changed_since(&self, path: &FsPathBuf, generation: u64) -> Result<bool> {
+        Subvolume::changed_since(&path.as_pathbuf(&self.fstree_mountpoint), generation)
+    }
+
+    pub fn subvolume_changed_bytes_since(&self, path: &FsPathBuf, generation: u64) -> Result<u64> {
+        Subvolume::changed_bytes_since(&path.as_pathbuf(&self.fstree_mountpoint), generation)
+    }
+
+    pub fn get_property(&self, path: &FsPathBuf, name: &str) -> Result<Option<String>> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["property", "get"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint))
+                .arg(name);
+            command
+        })
+        .context(format!("Failed to get btrfs property {} of {:?}.", name, path))?;
+        let prefix = format!("{}=", name);
+        Ok(output
+            .lines()
+            .find_map(|l| l.strip_prefix(&prefix))
+            .filter(|v| !v.is_empty())
+            .map(String::from))
+    }
+
+    /// The exclusive size of a subvolume as accounted by its qgroup. Requires quotas enabled on the filesystem.
+    pub fn exclusive_size(&self, path: &FsPathBuf) -> Result<u64> {
+        self.subvolume_qgroup(path).map(|(_, qgroup)| qgroup.exclusive)
+    }
+
+    /// The level 0 qgroup of a subvolume with its id. Requires quotas enabled on the filesystem.
+    pub fn subvolume_qgroup(&self, path: &FsPathBuf) -> Result<(String, Qgroup)> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "show", "--raw", "-p", "-f"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint));
+            command
+        })
+        .context(format!(
+            "Failed to query the qgroup of {:?}. Are quotas enabled (btrfs quota enable)?",
+            path
+        ))?;
+        Qgroup::parse_all(&output)
+            .into_iter()
+            .find(|(id, _)| id.starts_with("0/"))
+            .ok_or_else(|| anyhow!("No qgroup found for {:?}.", path))
+    }
+
+    /// Every qgroup of the filesystem by id.
+    pub fn qgroups(&self) -> Result<HashMap<String, Qgroup>> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "show", "--raw", "-p"])
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to list the qgroups. Are quotas enabled (btrfs quota enable)?")?;
+        Ok(Qgroup::parse_all(&output).into_iter().collect())
+    }
+
+    pub fn create_qgroup(&self, qgroup_id: &str) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "create", qgroup_id])
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to create qgroup {}.", qgroup_id))
+        .map(|_| ())
+    }
+
+    /// Makes a qgroup a member of a higher level one. The accounting is inconsistent until the quota is rescanned.
+    pub fn assign_qgroup(&self, member_id: &str, parent_id: &str) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "assign", "--no-rescan", member_id, parent_id])
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to assign qgroup {} to {}.", member_id, parent_id))
+        .map(|_| ())
+    }
+
+    /// Rescans the quota accounting, returning once it is done.
+    pub fn rescan_quota(&self) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["quota", "rescan", "-w"]).arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to rescan the quota.")
+        .map(|_| ())
+    }
+
+    pub fn set_property(&self, path: &FsPathBuf, name: &str, value: &str) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["property", "set"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint))
+                .args(&[name, value]);
+            command
+        })
+        .context(format!("Failed to set btrfs property {} of {:?}.", name, path))
+        .map(|_| ())
+    }
+
+    pub fn allocation_profiles(&self) -> Result<AllocationProfiles> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["filesystem", "df"]).arg(&self.fstree_mountpoint);
+            command
+        })?;
+        AllocationProfiles::parse(&output)
+    }
+
+    pub fn space_usage(&self) -> Result<SpaceUsage> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["filesystem", "usage", "-b"])
+                .arg(&self.fstree_mountpoint);
+            command
+        })?;
+        SpaceUsage::parse(&output)
+    }
+
+    pub fn devices(&self) -> Result<Vec<FilesystemDevice>> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["filesystem", "show", "--raw"])
+                .arg(&self.fstree_mountpoint);
+            command
+        })?;
+        FilesystemDevice::parse_all(&output)
+    }
+
+    /// Resizes the filesystem on one device. Shrinking relocates the data beyond the new size first.
+    pub fn resize(&self, devid: u64, target: ResizeTarget) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["filesystem", "resize"])
+                .arg(format!("{}:{}", devid, target))
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to resize device {} of the filesystem.", devid))
+        .map(|_| ())
+    }
+
+    pub fn add_device(&self, device: &DevicePathBuf) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["device", "add"])
+                .arg(device)
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to add device {} to the filesystem.", device))
+        .map(|_| ())
+    }
+
+    /// Removes the device, relocating its data to the remaining devices first. Blocks until complete.
+    pub fn remove_device(&self, device: &DevicePathBuf) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["device", "remove"])
+                .arg(device)
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to remove device {} from the filesystem.", device))
+        .map(|_| ())
+    }
+
+    /// Replaces the source device with the target device. Blocks until complete.
+    pub fn replace_device(&self, source: &DevicePathBuf, target: &DevicePathBuf) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["replace", "start", "-B"])
+                .arg(source)
+                .arg(target)
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to replace device {} with {}.", source, target))
+        .map(|_| ())
+    }
+
+    /// Rebalances all chunks across the devices of the filesystem. Blocks until complete.
+    pub fn balance(&self) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            JobKind::Balance.apply_std(&mut command);
+            command
+                .args(&["balance", "start", "--full-balance"])
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to balance the filesystem.")
+        .map(|_| ())
+    }
+
+    /// Converts chunks to the given allocation profiles. Chunks already using the target profile are skipped.
+    pub fn convert(&self, data: Option<AllocationMode>, metadata: Option<AllocationMode>) -> PoolBalance {
+        let mut command = tokio::process::Command::new("btrfs");
+        JobKind::Balance.apply(&mut command);
+        command.args(&["balance", "start"]);
+        if let Some(data) = data {
+            command.arg(format!("-dconvert={},soft", data));
+        }
+        if let Some(metadata) = metadata {
+            command.arg(format!("-mconvert={},soft", metadata));
+        }
+        command.arg(&self.fstree_mountpoint);
+        PoolBalance::new(command)
+    }
+
+    /// Deduplicates the extents of the files below `paths` with duperemove, keeping its hashes in `hashfile` so
+    /// later runs only hash changed files.
+    pub fn dedup(&self, paths: &[PathBuf], hashfile: &Path) -> PoolDedup {
+        let mut command = tokio::process::Command::new("duperemove");
+        JobKind::Dedup.apply(&mut command);
+        command
+            .args(&["-d", "-r", "-q"])
+            .arg(format!("--hashfile={}", hashfile.display()))
+            .args(paths);
+        PoolDedup::new(command)
+    }
+
+    /// Defragments the files below `paths`. Recursion stops at nested subvolumes, which are separate filesystems to
+    /// the kernel.
+    pub fn defrag(&self, paths: &[PathBuf], target_extent_size: Option<u64>, compression: Option<&str>) -> Defrag {
+        let mut command = tokio::process::Command::new("btrfs");
+        JobKind::Defrag.apply(&mut command);
+        command.args(&["filesystem", "defragment", "-r"]);
+        if let Some(size) = target_extent_size {
+            command.arg("-t").arg(size.to_string());
+        }
+        if let Some(compression) = compression {
+            command.arg(format!("-c{}", compression));
+        }
+        command.arg("--").args(paths);
+        Defrag::new(command)
+    }
+
+    /// Progress of the balance running on the filesystem, if any.
+    pub fn balance_status(&self) -> Result<Option<BalanceProgress>> {
+        // Balance status exits non-zero while a balance is running, so only the output is considered.
+        let output = run_command({
+            let mut command = btrfs_command();
+            command.args(&["balance", "status"]).arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to query btrfs balance status.")?;
+        BalanceProgress::parse(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    pub fn device_stats(&self) -> Result<Vec<DeviceStats>> {
+        let output = run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["device", "stats"]).arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to query btrfs device stats.")?;
+        DeviceStats::parse(&output)
+    }
+
+    pub fn scrub(&self) -> PoolScrub {
+        let mut command = tokio::process::Command::new("btrfs");
+        JobKind::Scrub.apply(&mut command);
+        command.args(&["scrub", "start", "-BRd"]).arg(&self.fstree_mountpoint);
+        PoolScrub::new(command)
+    }
+}
+
+struct FindNewSummary {
+    generation: u64,
+    changed_extents: usize,
+    changed_bytes: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Subvolume {
+    pub uuid: Uuid,
+    pub path: FsPathBuf,
     #[serde(rename = "parent uuid")]
     pub parent_uuid: Option<Uuid>,
     #[serde(rename = "received uuid")]
@@ -322,14 +1402,29 @@ pub struct Subvolume {
 impl Subvolume {
     pub fn from_path(path: &Path) -> Result<Self> {
         let output_data = run_command_as_result({
-            let mut command = btrfs_command();
-            command.args(&["subvolume", "show", "--raw"]).arg(path);
+            let mut command = subvolume_show_command();
+            command.arg(path);
             command
         })?;
         Self::_parse(String::from("path: ") + &output_data)
     }
 
+    /// Lists through the btrfs ioctls when built with the `btrfs-ioctl` feature, falling back to parsing
+    /// `btrfs subvolume list` where the ioctls fail, e.g. on kernels without them.
     pub fn list_subvolumes(path: &Path) -> Result<Vec<Subvolume>> {
+        #[cfg(feature = "btrfs-ioctl")]
+        match super::btrfs_ioctl::list_subvolumes(path) {
+            Ok(subvolumes) => return Ok(subvolumes),
+            Err(e) => slog_scope::debug!(
+                "ioctl subvolume listing of {} failed, using btrfs-progs: {:#}",
+                path.display(),
+                e
+            ),
+        }
+        Self::list_subvolumes_progs(path)
+    }
+
+    fn list_subvolumes_progs(path: &Path) -> Result<Vec<Subvolume>> {
         let paths_regex =
             once_regex!(r"(?m)\bparent_uuid\s+(.*?)\s+received_uuid\s+(.*?)\s+uuid\s+(.*?)\s+path\s+(.*?)\s*$");
         let output_data = run_command_as_result({
@@ -337,27 +1432,82 @@ impl Subvolume {
             command.args(&["subvolume", "list", "-uqRo"]).arg(path);
             command
         })?;
-        let path_matches = paths_regex.captures_iter(&output_data);
-        let parse_uuid = |m| parse_uuid(m).expect("Should always have parsable UUID in btrfs list.");
-        Ok(path_matches
-            .map(|m| Self {
-                uuid: parse_uuid(m.get(3).unwrap().as_str()),
-                path: FsPathBuf::from(m.get(4).unwrap().as_str()),
-                parent_uuid: match m.get(1).unwrap().as_str() {
-                    "-" => None,
-                    s => Some(parse_uuid(s)),
-                },
-                received_uuid: match m.get(2).unwrap().as_str() {
-                    "-" => None,
-                    s => Some(parse_uuid(s)),
-                },
+        let subvolumes = paths_regex
+            .captures_iter(&output_data)
+            .map(|m| {
+                let parse_uuid =
+                    |i| parse_uuid(m.get(i).unwrap().as_str()).map_err(|_| unexpected_output("subvolume list"));
+                Ok(Self {
+                    uuid: parse_uuid(3)?,
+                    path: FsPathBuf::from(m.get(4).unwrap().as_str()),
+                    parent_uuid: match m.get(1).unwrap().as_str() {
+                        "-" => None,
+                        _ => Some(parse_uuid(1)?),
+                    },
+                    received_uuid: match m.get(2).unwrap().as_str() {
+                        "-" => None,
+                        _ => Some(parse_uuid(2)?),
+                    },
+                })
             })
-            .collect::<Vec<_>>())
+            .collect::<Result<Vec<_>>>()?;
+        // Every line lists a subvolume, so lines without a match mean the format changed.
+        if subvolumes.len() != output_data.lines().filter(|l| !l.trim().is_empty()).count() {
+            return Err(unexpected_output("subvolume list"));
+        }
+        Ok(subvolumes)
+    }
+
+    pub fn generation(path: &Path) -> Result<u64> {
+        // Asking for changes newer than any possible generation yields only the current transid marker.
+        Self::find_new(path, u64::MAX).map(|f| f.generation)
+    }
+
+    pub fn changed_since(path: &Path, generation: u64) -> Result<bool> {
+        Self::find_new(path, generation).map(|f| f.changed_extents > 0)
+    }
+
+    /// Estimate of file data written since `generation`. Metadata and deletions are not counted.
+    pub fn changed_bytes_since(path: &Path, generation: u64) -> Result<u64> {
+        Self::find_new(path, generation).map(|f| f.changed_bytes)
+    }
+
+    fn find_new(path: &Path, generation: u64) -> Result<FindNewSummary> {
+        let marker_regex = once_regex!(r"(?m)^transid marker was (\d+)\s*$");
+        let extent_regex = once_regex!(r"(?m)^inode \d+ file offset \d+ len (\d+)\b");
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "find-new"])
+                .arg(path)
+                .arg(generation.to_string());
+            command
+        })?;
+        let marker = marker_regex
+            .captures(&output_data)
+            .and_then(|c| c.get(1))
+            .context("Failed to find transid marker in btrfs find-new output.")?
+            .as_str()
+            .parse()?;
+        let mut summary = FindNewSummary {
+            generation: marker,
+            changed_extents: 0,
+            changed_bytes: 0,
+        };
+        for extent in extent_regex.captures_iter(&output_data) {
+            summary.changed_extents += 1;
+            summary.changed_bytes += extent
+                .get(1)
+                .expect("regex group always exists")
+                .as_str()
+                .parse::<u64>()?;
+        }
+        Ok(summary)
     }
 
     fn _parse(data: String) -> Result<Self> {
         let kvps = parse_key_value_pair_lines::<_, Vec<StringPair>>(data.lines().take(6), ":")
-            .context("Failed to parse output of btrfs subvolume.")?;
+            .map_err(|_| unexpected_output("subvolume show"))?;
 
         let subvolume = envy::from_iter::<_, Self>(kvps.into_iter().filter_map(|x| {
             if x.1 != "-" {
@@ -366,12 +1516,13 @@ impl Subvolume {
                 None
             }
         }))
-        .context("Failed loading information from btrfs subvolume output.")?;
+        .map_err(|_| unexpected_output("subvolume show"))?;
         Ok(subvolume)
     }
 }
 
 mod operations {
+    use super::{DedupResult, ScrubResult};
     use crate::sys::process::{exit_status_as_result, output_to_result};
     use anyhow::{anyhow, Context as AnyhowContext, Result};
     use std::process::Stdio;
@@ -413,7 +1564,16 @@ mod operations {
         }
 
         pub async fn wait(self) -> Result<()> {
-            output_to_result(self.process.wait_with_output().await)
+            match output_to_result(self.process.wait_with_output().await) {
+                Err(e)
+                    if SENDER_MISSING_PARENT_MESSAGES
+                        .iter()
+                        .any(|m| format!("{:#}", e).contains(m)) =>
+                {
+                    Err(e.context(MissingParentError))
+                }
+                result => result,
+            }
         }
     }
 
@@ -503,12 +1663,36 @@ mod operations {
                     } else {
                         stderr_result.1
                     };
-                    Err(anyhow!(stderr).context(e))
+                    let missing_parent = MISSING_PARENT_MESSAGES.iter().any(|m| stderr.contains(m));
+                    let error = anyhow!(stderr).context(e);
+                    if missing_parent {
+                        Err(error.context(MissingParentError))
+                    } else {
+                        Err(error)
+                    }
                 }
             }
         }
     }
 
+    const MISSING_PARENT_MESSAGES: [&str; 2] = ["cannot find parent subvolume", "did not find source subvol"];
+    // btrfs send fails like this when the parent snapshot is gone or no longer a subvolume on the sender.
+    const SENDER_MISSING_PARENT_MESSAGES: [&str; 3] = [
+        "cannot find parent subvolume",
+        "could not resolve rootid for",
+        "parent determination failed",
+    ];
+
+    #[derive(thiserror::Error, Debug)]
+    #[error("the incremental parent snapshot was not found on the sender or the receiver")]
+    pub struct MissingParentError;
+
+    impl MissingParentError {
+        pub fn is_cause_of(error: &anyhow::Error) -> bool {
+            error.downcast_ref::<Self>().is_some()
+        }
+    }
+
     pub struct PoolScrub {
         command: Command,
     }
@@ -533,16 +1717,114 @@ mod operations {
     }
 
     impl StartedPoolScrub {
-        pub async fn wait(self) -> Result<(), ScrubError> {
+        pub async fn wait(self) -> Result<ScrubResult, ScrubError> {
             let result = self.process.wait_with_output().await;
             if let Ok(output) = &result {
-                if let Some(code) = output.status.code() {
-                    if code == 3 {
-                        return Err(ScrubError::UncorrectableErrors);
-                    }
+                let scrub_result = ScrubResult::parse(&String::from_utf8_lossy(&output.stdout));
+                if output.status.code() == Some(3) {
+                    return Err(ScrubError::UncorrectableErrors(scrub_result));
+                }
+                if output.status.success() {
+                    return Ok(scrub_result);
                 }
             }
-            output_to_result(result).map_err(ScrubError::Unknown)
+            output_to_result(result)
+                .map_err(ScrubError::Unknown)
+                .map(|_| Default::default())
+        }
+    }
+
+    pub struct PoolBalance {
+        command: Command,
+    }
+
+    impl PoolBalance {
+        pub fn new(mut command: Command) -> Self {
+            command.stdout(Stdio::null());
+            command.stderr(Stdio::piped());
+            Self { command }
+        }
+
+        pub fn start(mut self) -> Result<StartedPoolBalance> {
+            self.command
+                .spawn()
+                .map(|process| StartedPoolBalance { process })
+                .context("failed to spawn btrfs balance process")
+        }
+    }
+
+    pub struct StartedPoolBalance {
+        process: Child,
+    }
+
+    impl StartedPoolBalance {
+        pub async fn wait(self) -> Result<()> {
+            output_to_result(self.process.wait_with_output().await).context("balance process failed to complete")
+        }
+    }
+
+    pub struct PoolDedup {
+        command: Command,
+    }
+
+    impl PoolDedup {
+        pub fn new(mut command: Command) -> Self {
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+            Self { command }
+        }
+
+        pub fn start(mut self) -> Result<StartedPoolDedup> {
+            self.command
+                .spawn()
+                .map(|process| StartedPoolDedup { process })
+                .context("failed to spawn duperemove process")
+        }
+    }
+
+    pub struct StartedPoolDedup {
+        process: Child,
+    }
+
+    impl StartedPoolDedup {
+        pub async fn wait(self) -> Result<DedupResult> {
+            let result = self.process.wait_with_output().await;
+            let dedup_result = match &result {
+                Ok(output) => DedupResult::parse(&String::from_utf8_lossy(&output.stdout)),
+                Err(_) => Default::default(),
+            };
+            output_to_result(result)
+                .context("duperemove process failed to complete")
+                .map(|_| dedup_result)
+        }
+    }
+
+    pub struct Defrag {
+        command: Command,
+    }
+
+    impl Defrag {
+        pub fn new(mut command: Command) -> Self {
+            command.stdout(Stdio::null());
+            command.stderr(Stdio::piped());
+            Self { command }
+        }
+
+        pub fn start(mut self) -> Result<StartedDefrag> {
+            self.command
+                .spawn()
+                .map(|process| StartedDefrag { process })
+                .context("failed to spawn defragment process")
+        }
+    }
+
+    pub struct StartedDefrag {
+        process: Child,
+    }
+
+    impl StartedDefrag {
+        pub async fn wait(self) -> Result<()> {
+            output_to_result(self.process.wait_with_output().await).context("defragment process failed to complete")
         }
     }
 
@@ -551,7 +1833,7 @@ mod operations {
         #[error("scrub process failed to complete")]
         Unknown(anyhow::Error),
         #[error("uncorrectable errors were found during scrub")]
-        UncorrectableErrors,
+        UncorrectableErrors(ScrubResult),
     }
 }
 
@@ -626,6 +1908,477 @@ mod filesystem_tests {
         process_ctx
     }
 
+    #[test]
+    #[serial(fakecmd)]
+    fn filesystem_get_property() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(String::from("compression=zstd:3\n")));
+
+        let filesystem = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/data_pool"),
+        };
+        assert_eq!(
+            filesystem
+                .get_property(&FsPathBuf::from("test4"), "compression")
+                .unwrap(),
+            Some(String::from("zstd:3"))
+        );
+    }
+
+    fn send_args(command: tokio::process::Command) -> Vec<String> {
+        command
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn filesystem_send_command() {
+        let filesystem = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/data_pool"),
+        };
+        let snapshot = FsPathBuf::from("snapshots/2");
+        let parent = FsPathBuf::from("snapshots/1");
+
+        assert_eq!(
+            send_args(filesystem.send_command(&[&snapshot], None, &[], false)),
+            ["send", "/mnt/data_pool/snapshots/2"]
+        );
+        assert_eq!(
+            send_args(filesystem.send_command(&[&snapshot], Some(&parent), &[], true)),
+            [
+                "send",
+                "--compressed-data",
+                "-p",
+                "/mnt/data_pool/snapshots/1",
+                "/mnt/data_pool/snapshots/2"
+            ]
+        );
+        let nested = FsPathBuf::from("snapshots/2@home");
+        assert_eq!(
+            send_args(filesystem.send_command(&[&snapshot, &nested], None, &[&parent], false)),
+            [
+                "send",
+                "-c",
+                "/mnt/data_pool/snapshots/1",
+                "/mnt/data_pool/snapshots/2",
+                "/mnt/data_pool/snapshots/2@home"
+            ]
+        );
+    }
+
+    #[test]
+    fn compressed_send_needs_kernel_and_progs_support() {
+        assert!(compressed_send_versions(2, (5, 18)));
+        assert!(compressed_send_versions(3, (6, 6)));
+        assert!(!compressed_send_versions(1, (6, 6)));
+        assert!(!compressed_send_versions(2, (5, 17)));
+        assert!(!compressed_send_versions(2, (4, 20)));
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn filesystem_get_unset_property() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(String::from("compression=\n")));
+
+        let filesystem = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/data_pool"),
+        };
+        assert_eq!(
+            filesystem
+                .get_property(&FsPathBuf::from("test4"), "compression")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn filesystem_set_property() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect()
+            .withf(|command| {
+                format!("{:?}", command).contains(r#""property" "set" "/mnt/data_pool/test4" "compression" "zstd:3""#)
+            })
+            .returning(|_| Ok(String::new()));
+
+        let filesystem = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/data_pool"),
+        };
+        assert!(filesystem
+            .set_property(&FsPathBuf::from("test4"), "compression", "zstd:3")
+            .is_ok());
+
+        ctx.checkpoint();
+        ctx.expect().returning(|_| Err(anyhow!("ERROR: invalid value")));
+        let error = filesystem
+            .set_property(&FsPathBuf::from("test4"), "compression", "bogus")
+            .unwrap_err();
+        assert!(error.to_string().contains("Failed to set btrfs property compression"));
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn filesystem_delete_subvolumes_chunked() {
+        let ctx = process_double::run_command_as_result_context();
+        let mut calls = 0;
+        ctx.expect().times(3).returning(move |_| {
+            calls += 1;
+            if calls == 1 {
+                Err(anyhow!("ERROR: Could not destroy subvolume"))
+            } else {
+                Ok(String::new())
+            }
+        });
+
+        let filesystem = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/data_pool"),
+        };
+        let paths = (0..5)
+            .map(|i| FsPathBuf::from(&format!("snapshots/{}", i)))
+            .collect::<Vec<_>>();
+        let result = filesystem.delete_subvolumes(
+            &paths.iter().collect::<Vec<_>>(),
+            NonZeroUsize::new(2).unwrap(),
+            DeleteCommit::After,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn filesystem_exclusive_size() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| {
+            Ok(String::from(indoc!(
+                r#"
+                qgroupid         rfer         excl parent  
+                --------         ----         ---- ------  
+                0/263        104873984      1064960 ---     
+                "#
+            )))
+        });
+
+        let filesystem = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/data_pool"),
+        };
+        assert_eq!(filesystem.exclusive_size(&FsPathBuf::from("test4")).unwrap(), 1064960);
+    }
+
+    #[test]
+    fn qgroups_parse() {
+        let output = indoc!(
+            r#"
+            qgroupid         rfer         excl parent  
+            --------         ----         ---- ------  
+            0/5             16384        16384 ---     
+            0/263        104873984      1064960 1/257   
+            0/264        104873984        16384 1/257,1/300
+            1/257        104890368      2129920 ---     
+            "#
+        );
+        let qgroups = Qgroup::parse_all(output).into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(qgroups.len(), 4);
+        assert!(qgroups["0/5"].parents.is_empty());
+        assert_eq!(qgroups["0/263"].parents, vec![String::from("1/257")]);
+        assert_eq!(
+            qgroups["0/264"].parents,
+            vec![String::from("1/257"), String::from("1/300")]
+        );
+        assert_eq!(qgroups["1/257"].exclusive, 2129920);
+    }
+
+    #[test]
+    fn allocation_profiles_parse() {
+        let output = indoc!(
+            r#"
+            Data, single: total=8.00MiB, used=0.00B
+            Data, RAID1: total=1.00GiB, used=512.00KiB
+            System, RAID1: total=8.00MiB, used=16.00KiB
+            Metadata, RAID1C3: total=256.00MiB, used=112.00KiB
+            GlobalReserve, single: total=3.25MiB, used=0.00B
+            "#
+        );
+        let profiles = AllocationProfiles::parse(output).unwrap();
+        assert_eq!(profiles.data, vec![AllocationMode::Single, AllocationMode::Raid1]);
+        assert_eq!(profiles.metadata, vec![AllocationMode::Raid1, AllocationMode::Raid1c3]);
+        assert_eq!(profiles.min_devices(), 3);
+
+        assert!(AllocationProfiles::parse("Data, RAID5: total=1.00GiB, used=0.00B\n").is_err());
+    }
+
+    #[test]
+    fn space_usage_parse() {
+        let output = indoc!(
+            r#"
+            Overall:
+                Device size:                 21474836480
+                Device allocated:             2705326080
+                Device unallocated:          18769510400
+                Device missing:                        0
+                Used:                            1179648
+                Free (estimated):            10737025024	(min: 10737025024)
+                Free (statfs, df):           10736025024
+                Data ratio:                         2.00
+            "#
+        );
+        let usage = SpaceUsage::parse(output).unwrap();
+        assert_eq!(
+            usage,
+            SpaceUsage {
+                size: 21474836480,
+                capacity: 10737418240,
+                free: 10737025024,
+            }
+        );
+        assert_eq!(usage.free_percent(), 99);
+
+        assert!(SpaceUsage::parse("Overall:\n").is_err());
+    }
+
+    #[test]
+    fn filesystem_devices_parse() {
+        let output = indoc!(
+            r#"
+            Label: 'nas_mirrored'  uuid: 338a0b41-e857-4e5b-6544-6fd617277722
+            	Total devices 2 FS bytes used 359263784960
+            	devid    1 size 2000398934016 used 381220290560 path /dev/sdb
+            	devid    3 size 4000787030016 used 381220290560 path /dev/mapper/luks-a1"#
+        );
+        let devices = FilesystemDevice::parse_all(output).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].devid, 3);
+        assert_eq!(devices[1].size, 4000787030016);
+        assert_eq!(devices[1].used, 381220290560);
+        assert_eq!(devices[1].path, DevicePathBuf::try_from("/dev/mapper/luks-a1").unwrap());
+
+        assert_eq!("max".parse::<ResizeTarget>().unwrap(), ResizeTarget::Max);
+        assert_eq!("1073741824".parse::<ResizeTarget>().unwrap().to_string(), "1073741824");
+        assert!("10G".parse::<ResizeTarget>().is_err());
+    }
+
+    #[test]
+    fn balance_progress_parse() {
+        assert_eq!(
+            BalanceProgress::parse("No balance found on '/mnt/test'\n").unwrap(),
+            None
+        );
+
+        let output = indoc!(
+            r#"
+            Balance on '/mnt/test' is running
+            2 out of about 10 chunks balanced (3 considered),  80% left
+            "#
+        );
+        assert_eq!(
+            BalanceProgress::parse(output.trim_start()).unwrap(),
+            Some(BalanceProgress {
+                balanced: 2,
+                expected: 10,
+                considered: 3,
+                percent_left: 80,
+            })
+        );
+    }
+
+    #[test]
+    fn scrub_result_parse() {
+        let output = indoc!(
+            r#"
+            scrub device /dev/sdb (id 1) done
+            Scrub started:    Sat May  1 04:00:00 2021
+            Status:           finished
+            Duration:         0:12:34
+            	data_extents_scrubbed: 1024
+            	read_errors: 0
+            	csum_errors: 3
+            	uncorrectable_errors: 1
+            	unverified_errors: 0
+            	corrected_errors: 2
+            	last_physical: 4096
+            scrub device /dev/sdc (id 2) done
+            	scrub started at Sat May  1 04:00:00 2021 and finished after 00:12:30
+            	uncorrectable_errors: 0
+            	corrected_errors: 5
+            "#
+        );
+        let result = ScrubResult::parse(output);
+        assert_eq!(result.devices.len(), 2);
+        assert_eq!(result.devices[0].device, "/dev/sdb");
+        assert_eq!(result.devices[0].uncorrectable_errors, 1);
+        assert_eq!(result.corrected_errors(), 7);
+        assert_eq!(result.uncorrectable_errors(), 1);
+    }
+
+    #[test]
+    fn dedup_result_parse() {
+        let output = indoc!(
+            r#"
+            Found 12 identical extents.
+            Comparison of extent info shows a net change in shared extents of: 1.5M
+            "#
+        );
+        assert_eq!(DedupResult::parse(output).deduplicated_bytes, Some(1_572_864));
+        assert_eq!(
+            DedupResult::parse("net change in shared extents of: 512.0\n").deduplicated_bytes,
+            Some(512)
+        );
+        assert_eq!(DedupResult::parse("").deduplicated_bytes, None);
+    }
+
+    #[test]
+    fn device_stats_parse() {
+        let output = indoc!(
+            r#"
+            [/dev/sdb].write_io_errs    0
+            [/dev/sdb].read_io_errs     2
+            [/dev/sdb].corruption_errs  7
+            [/dev/mapper/crypt-c].write_io_errs    0
+            [/dev/mapper/crypt-c].corruption_errs  0
+            "#
+        );
+        let stats = DeviceStats::parse(output).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].nonzero_summary(), "read_io_errs 2, corruption_errs 7");
+        assert_eq!(stats[1].device, "/dev/mapper/crypt-c");
+        assert_eq!(stats[1].nonzero_summary(), "");
+        assert!(DeviceStats::parse("ERROR: not a btrfs filesystem").is_err());
+    }
+
+    #[test]
+    fn mount_options_split() {
+        let options = vec![
+            String::from("compress=zstd"),
+            String::from("nodiratime"),
+            String::from("space_cache=v2"),
+        ];
+        let (flags, data) = split_mount_options(&options);
+        assert_eq!(flags, MsFlags::MS_NODIRATIME | MsFlags::MS_NOATIME);
+        assert_eq!(data, vec!["compress=zstd", "space_cache=v2"]);
+
+        let (flags, data) = split_mount_options(&[String::from("relatime")]);
+        assert_eq!(flags, MsFlags::MS_RELATIME);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn mount_options_missing() {
+        let configured = vec![
+            String::from("compress=zstd"),
+            String::from("ssd"),
+            String::from("noatime"),
+        ];
+        let live = ["rw", "noatime", "compress=zstd:3", "space_cache=v2", "subvolid=5"];
+        assert_eq!(missing_mount_options(&configured, &live), vec!["ssd"]);
+    }
+
+    #[test]
+    fn fstab_entry_replaced() {
+        let uuid = Uuid::parse_str("338a0b41-e857-4e5b-6544-6fd617277722").unwrap();
+        let mountpoint = Path::new("/mnt/test");
+        let contents = "# /etc/fstab\n\
+            UUID=0f5b0a0e-3c3b-4b3c-9a8e-1c4c3b0e8e11\t/\text4\tdefaults\t0\t1\n\
+            UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/test/\tbtrfs\tdefaults\t0\t0\n";
+        let new_line = "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/test\tbtrfs\tdefaults,noatime\t0\t0";
+
+        let added = fstab_with_entry(contents, &uuid, mountpoint, Some(new_line));
+        assert_eq!(
+            added,
+            format!(
+                "# /etc/fstab\nUUID=0f5b0a0e-3c3b-4b3c-9a8e-1c4c3b0e8e11\t/\text4\tdefaults\t0\t1\n{}\n",
+                new_line
+            )
+        );
+        assert_eq!(fstab_with_entry(&added, &uuid, mountpoint, Some(new_line)), added);
+        assert_eq!(
+            fstab_with_entry(&added, &uuid, mountpoint, None),
+            "# /etc/fstab\nUUID=0f5b0a0e-3c3b-4b3c-9a8e-1c4c3b0e8e11\t/\text4\tdefaults\t0\t1\n"
+        );
+    }
+
+    #[test]
+    fn fstab_entry_keeps_subvolume_mounts_of_same_filesystem() {
+        let uuid = Uuid::parse_str("338a0b41-e857-4e5b-6544-6fd617277722").unwrap();
+        let os_entries = "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/\tbtrfs\tsubvol=/@,noatime\t0\t0\n\
+            UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/home\tbtrfs\tsubvolid=257\t0\t0\n\
+            UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/other\tbtrfs\tdefaults\t0\t0\n\
+            # UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/pool\tbtrfs\tdefaults\t0\t0\n";
+        let pool_entry = "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/pool\tbtrfs\tnoatime\t0\t0\n";
+        let mountpoint = Path::new("/mnt/pool");
+
+        let contents = format!("{}{}", os_entries, pool_entry);
+        assert_eq!(fstab_with_entry(&contents, &uuid, mountpoint, None), os_entries);
+        // A subvolume mounted at the pool's mountpoint is not the pool's fstree entry either.
+        let subvolume_entry = "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/pool\tbtrfs\tsubvol=/data\t0\t0\n";
+        let contents = format!("{}{}", os_entries, subvolume_entry);
+        assert_eq!(fstab_with_entry(&contents, &uuid, mountpoint, None), contents);
+    }
+
+    #[test]
+    fn fstab_root_subvol_replaced() {
+        let contents = "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/\tbtrfs\tsubvol=/@,subvolid=256,noatime\t0\t0\n\
+            UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/home\tbtrfs\tsubvol=/@home\t0\t0\n";
+        assert_eq!(fstab_root_subvol(contents), Some("/@"));
+        assert_eq!(
+            fstab_with_root_subvol(contents, "/@.rollback"),
+            "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/\tbtrfs\tsubvol=/@.rollback,noatime\t0\t0\n\
+            UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/home\tbtrfs\tsubvol=/@home\t0\t0\n"
+        );
+        assert_eq!(fstab_root_subvol("UUID=0f5b\t/\tbtrfs\tdefaults\t0\t0\n"), None);
+
+        let commented = "# UUID=0f5b\t/\tbtrfs\tsubvol=/@old\t0\t0\n\
+            #UUID=0f5b\t/\tbtrfs\tsubvol=/@older\t0\t0\n\
+            UUID=0f5b\t/\tbtrfs\tsubvol=/@\t0\t0\n";
+        assert_eq!(fstab_root_subvol(commented), Some("/@"));
+        assert_eq!(
+            fstab_with_root_subvol(commented, "/@.rollback"),
+            "# UUID=0f5b\t/\tbtrfs\tsubvol=/@old\t0\t0\n\
+            #UUID=0f5b\t/\tbtrfs\tsubvol=/@older\t0\t0\n\
+            UUID=0f5b\t/\tbtrfs\tsubvol=/@.rollback\t0\t0\n"
+        );
+    }
+
+    #[test]
+    fn default_subvolume_parse() {
+        assert_eq!(parse_default_subvolume("ID 5 (FS_TREE)\n").unwrap(), None);
+        assert_eq!(
+            parse_default_subvolume("ID 256 gen 1042 top level 5 path @\n").unwrap(),
+            Some(FsPathBuf::from("@"))
+        );
+        assert!(parse_default_subvolume("unexpected").is_err());
+    }
+
+    #[test]
+    fn mount_unit_name_escaping() {
+        assert_eq!(mount_unit_name(Path::new("/mnt/default")), "mnt-default.mount");
+        assert_eq!(mount_unit_name(Path::new("/mnt/my-pool/")), "mnt-my\\x2dpool.mount");
+        assert_eq!(mount_unit_name(Path::new("/")), "-.mount");
+    }
+
+    #[test]
+    fn fstab_line_options() {
+        let mounted = MountedFilesystem {
+            filesystem: expected_filesystem(),
+            fstree_mountpoint: PathBuf::from("/mnt/test"),
+        };
+        assert_eq!(
+            fstab_line(&mounted, &[]),
+            "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/test\tbtrfs\tdefaults,noatime\t0\t0"
+        );
+        assert_eq!(
+            fstab_line(&mounted, &[String::from("compress=zstd:1"), String::from("relatime")]),
+            "UUID=338a0b41-e857-4e5b-6544-6fd617277722\t/mnt/test\tbtrfs\tcompress=zstd:1,relatime\t0\t0"
+        );
+    }
+
     fn expected_filesystem() -> Filesystem {
         Filesystem {
             uuid: Uuid::parse_str("338a0b41-e857-4e5b-6544-6fd617277722").unwrap(),
@@ -730,4 +2483,83 @@ mod subvolume_tests {
             ]
         );
     }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn subvolume_changed_since() {
+        const BTRFS_DATA: &str = indoc!(
+            r#"
+            inode 257 file offset 0 len 4096 disk start 30539776 offset 0 gen 46 flags NONE data.db
+            transid marker was 48"#
+        );
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(BTRFS_DATA.to_string()));
+
+        assert!(Subvolume::changed_since(&PathBuf::from("/mnt/data_pool/test4"), 45).unwrap());
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn subvolume_changed_bytes_since() {
+        const BTRFS_DATA: &str = indoc!(
+            r#"
+            inode 257 file offset 0 len 4096 disk start 30539776 offset 0 gen 46 flags NONE data.db
+            inode 257 file offset 8192 len 12288 disk start 30543872 offset 0 gen 47 flags NONE data.db
+            inode 258 file offset 0 len 81 disk start 0 offset 0 gen 47 flags INLINE notes.txt
+            transid marker was 48"#
+        );
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(BTRFS_DATA.to_string()));
+
+        assert_eq!(
+            Subvolume::changed_bytes_since(&PathBuf::from("/mnt/data_pool/test4"), 45).unwrap(),
+            16465
+        );
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn progs_version_parse() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| {
+            Ok(String::from(
+                "btrfs-progs v6.6.3\n-EXPERIMENTAL -INJECT -STATIC +LZO +ZSTD\n",
+            ))
+        });
+
+        assert_eq!(progs_version().unwrap(), (6, 6));
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn progs_version_parse_old() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(String::from("Btrfs v3.12\n")));
+
+        assert_eq!(progs_version().unwrap(), (3, 12));
+        assert!(detect_progs_version().is_err());
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn subvolume_list_unexpected() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect()
+            .returning(|_| Ok(String::from("ID 260 gen 48 top level 5 path test4\n")));
+
+        assert!(Subvolume::list_subvolumes(&PathBuf::from("/mnt/data_pool")).is_err());
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn subvolume_generation() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(String::from("transid marker was 48\n")));
+
+        assert_eq!(
+            Subvolume::generation(&PathBuf::from("/mnt/data_pool/test4")).unwrap(),
+            48
+        );
+        assert!(!Subvolume::changed_since(&PathBuf::from("/mnt/data_pool/test4"), 48).unwrap());
+    }
 }
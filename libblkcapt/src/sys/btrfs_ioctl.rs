@@ -0,0 +1,242 @@
+//! Subvolume enumeration through the btrfs tree search ioctls instead of parsing `btrfs subvolume list`.
+
+use super::{btrfs::Subvolume, fs::FsPathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    convert::TryInto,
+    ffi::OsStr,
+    fs::File,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+const ROOT_TREE_OBJECTID: u64 = 1;
+const FS_TREE_OBJECTID: u64 = 5;
+const FIRST_FREE_OBJECTID: u64 = 256;
+const ROOT_ITEM_KEY: u32 = 132;
+const ROOT_BACKREF_KEY: u32 = 144;
+const ROOT_REF_KEY: u32 = 156;
+
+const SEARCH_KEY_SIZE: usize = 104;
+const SEARCH_BUF_SIZE: usize = 4096 - SEARCH_KEY_SIZE;
+const SEARCH_HEADER_SIZE: usize = 32;
+const INO_LOOKUP_PATH_MAX: usize = 4080;
+
+// Written for the kernel, most fields are never read back.
+#[allow(dead_code)]
+#[repr(C)]
+pub struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+#[repr(C)]
+pub struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; SEARCH_BUF_SIZE],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+pub struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; INO_LOOKUP_PATH_MAX],
+}
+
+nix::ioctl_readwrite!(btrfs_tree_search, BTRFS_IOCTL_MAGIC, 17, SearchArgs);
+nix::ioctl_readwrite!(btrfs_ino_lookup, BTRFS_IOCTL_MAGIC, 18, InoLookupArgs);
+
+struct SearchItem {
+    objectid: u64,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// The subvolumes directly below the subvolume containing `path`, like `btrfs subvolume list -o`.
+pub fn list_subvolumes(path: &Path) -> Result<Vec<Subvolume>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let fd = file.as_raw_fd();
+
+    let parent_id = ino_lookup(fd, 0, FIRST_FREE_OBJECTID)?.0;
+    let parent_path = subvolume_path(fd, parent_id)?;
+
+    let mut subvolumes = Vec::new();
+    for reference in tree_search(fd, parent_id, ROOT_REF_KEY)? {
+        let (dirid, name) = parse_root_ref(&reference.data)?;
+        let (_, dir_path) = ino_lookup(fd, parent_id, dirid)?;
+        let root_item = tree_search(fd, reference.offset, ROOT_ITEM_KEY)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("subvolume {} has no root item", reference.offset))?;
+        let (uuid, parent_uuid, received_uuid) = parse_root_item_uuids(&root_item.data);
+        subvolumes.push(Subvolume {
+            uuid,
+            path: FsPathBuf::from(&parent_path.join(dir_path.join(name))),
+            parent_uuid,
+            received_uuid,
+        });
+    }
+    Ok(subvolumes)
+}
+
+// The path of a subvolume relative to the top level subvolume, following the back references up the tree.
+fn subvolume_path(fd: i32, mut subvolume_id: u64) -> Result<PathBuf> {
+    let mut components = Vec::new();
+    while subvolume_id != FS_TREE_OBJECTID {
+        let backref = tree_search(fd, subvolume_id, ROOT_BACKREF_KEY)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("subvolume {} has no back reference", subvolume_id))?;
+        let (dirid, name) = parse_root_ref(&backref.data)?;
+        let (_, dir_path) = ino_lookup(fd, backref.offset, dirid)?;
+        components.push(dir_path.join(name));
+        subvolume_id = backref.offset;
+    }
+    Ok(components.into_iter().rev().collect())
+}
+
+fn tree_search(fd: i32, objectid: u64, item_type: u32) -> Result<Vec<SearchItem>> {
+    let mut items = Vec::new();
+    let mut min_offset = 0;
+    loop {
+        let mut args = SearchArgs {
+            key: SearchKey {
+                tree_id: ROOT_TREE_OBJECTID,
+                min_objectid: objectid,
+                max_objectid: objectid,
+                min_offset,
+                max_offset: u64::MAX,
+                min_transid: 0,
+                max_transid: u64::MAX,
+                min_type: item_type,
+                max_type: item_type,
+                nr_items: u32::MAX,
+                unused: 0,
+                unused1: 0,
+                unused2: 0,
+                unused3: 0,
+                unused4: 0,
+            },
+            buf: [0; SEARCH_BUF_SIZE],
+        };
+        unsafe { btrfs_tree_search(fd, &mut args) }.context("btrfs tree search ioctl failed")?;
+        if args.key.nr_items == 0 {
+            return Ok(items);
+        }
+
+        let mut position = 0;
+        for _ in 0..args.key.nr_items {
+            let header = &args.buf[position..position + SEARCH_HEADER_SIZE];
+            let item_objectid = read_u64(header, 8);
+            let offset = read_u64(header, 16);
+            let len = read_u32(header, 28) as usize;
+            position += SEARCH_HEADER_SIZE;
+            items.push(SearchItem {
+                objectid: item_objectid,
+                offset,
+                data: args.buf[position..position + len].to_vec(),
+            });
+            position += len;
+        }
+
+        let last = items.last().expect("at least one item was just read");
+        if last.objectid != objectid || last.offset == u64::MAX {
+            return Ok(items);
+        }
+        min_offset = last.offset + 1;
+    }
+}
+
+// Returns the subvolume id and the path of the inode within it, with `treeid` 0 meaning the subvolume of `fd`.
+fn ino_lookup(fd: i32, treeid: u64, objectid: u64) -> Result<(u64, PathBuf)> {
+    let mut args = InoLookupArgs {
+        treeid,
+        objectid,
+        name: [0; INO_LOOKUP_PATH_MAX],
+    };
+    unsafe { btrfs_ino_lookup(fd, &mut args) }.context("btrfs inode lookup ioctl failed")?;
+    let len = args.name.iter().position(|b| *b == 0).unwrap_or(INO_LOOKUP_PATH_MAX);
+    Ok((args.treeid, PathBuf::from(OsStr::from_bytes(&args.name[..len]))))
+}
+
+// A root ref or back ref item: the directory holding the subvolume and its name in there.
+fn parse_root_ref(data: &[u8]) -> Result<(u64, PathBuf)> {
+    const ROOT_REF_SIZE: usize = 18;
+    if data.len() < ROOT_REF_SIZE {
+        bail!("root reference item is truncated");
+    }
+    let dirid = u64::from_le_bytes(data[0..8].try_into().expect("slice is eight bytes"));
+    let name_len = u16::from_le_bytes(data[16..18].try_into().expect("slice is two bytes")) as usize;
+    let name = data
+        .get(ROOT_REF_SIZE..ROOT_REF_SIZE + name_len)
+        .ok_or_else(|| anyhow!("root reference name is truncated"))?;
+    Ok((dirid, PathBuf::from(OsStr::from_bytes(name))))
+}
+
+// Root items written by kernels older than 3.6 end before the uuids.
+fn parse_root_item_uuids(data: &[u8]) -> (Uuid, Option<Uuid>, Option<Uuid>) {
+    const UUID_OFFSET: usize = 247;
+    let uuid_at = |offset: usize| {
+        data.get(offset..offset + 16)
+            .map(|b| Uuid::from_slice(b).expect("slice is sixteen bytes"))
+            .filter(|u| !u.is_nil())
+    };
+    (
+        uuid_at(UUID_OFFSET).unwrap_or_else(Uuid::nil),
+        uuid_at(UUID_OFFSET + 16),
+        uuid_at(UUID_OFFSET + 32),
+    )
+}
+
+// The search headers are in native byte order, the items they describe are little endian as on disk.
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(data[offset..offset + 8].try_into().expect("slice is eight bytes"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(data[offset..offset + 4].try_into().expect("slice is four bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_ref_parse() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&256u64.to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(&9u16.to_le_bytes());
+        data.extend_from_slice(b"snapshots");
+        assert_eq!(parse_root_ref(&data).unwrap(), (256, PathBuf::from("snapshots")));
+        assert!(parse_root_ref(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn root_item_uuids_parse() {
+        let uuid = Uuid::new_v4();
+        let received_uuid = Uuid::new_v4();
+        let mut data = vec![0u8; 247];
+        data.extend_from_slice(uuid.as_bytes());
+        data.extend_from_slice(Uuid::nil().as_bytes());
+        data.extend_from_slice(received_uuid.as_bytes());
+        assert_eq!(parse_root_item_uuids(&data), (uuid, None, Some(received_uuid)));
+        assert_eq!(parse_root_item_uuids(&data[..239]), (Uuid::nil(), None, None));
+    }
+}
@@ -0,0 +1,178 @@
+use super::fs::DevicePathBuf;
+#[mockall_double::double]
+use crate::sys::process::double as process_double;
+use anyhow::{Context, Result};
+use process_double::run_command_as_result;
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::Path,
+    process::Command,
+    writeln,
+};
+use uuid::Uuid;
+
+const KEYFILE_LEN: usize = 4096;
+
+fn cryptsetup_command() -> Command {
+    Command::new("cryptsetup")
+}
+
+/// Creates a new root-only keyfile filled with random data. An existing file is never overwritten.
+pub fn create_keyfile(path: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path.parent().context("keyfile path must have a parent directory")?)
+        .context("failed to create keyfile directory")?;
+
+    let mut bytes = vec![0; KEYFILE_LEN];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .context("failed to read random bytes")?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)
+        .with_context(|| format!("failed to create keyfile {}", path.display()))?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Formats the device as a LUKS2 volume unlocked by the keyfile, returning the LUKS UUID.
+pub fn luks_format(device: &DevicePathBuf, keyfile: &Path) -> Result<Uuid> {
+    run_command_as_result({
+        let mut command = cryptsetup_command();
+        command
+            .args(&["luksFormat", "--batch-mode", "--type", "luks2", "--key-file"])
+            .arg(keyfile)
+            .arg(device);
+        command
+    })
+    .with_context(|| format!("failed to format {} with luks", device))?;
+
+    luks_uuid(device)
+}
+
+pub fn luks_uuid(device: &DevicePathBuf) -> Result<Uuid> {
+    let output = run_command_as_result({
+        let mut command = cryptsetup_command();
+        command.arg("luksUUID").arg(device);
+        command
+    })?;
+    output.trim().parse().context("failed to parse luks uuid")
+}
+
+/// Opens the LUKS volume under its conventional mapper name and returns the mapped device.
+pub fn luks_open(device: &DevicePathBuf, uuid: &Uuid, keyfile: &Path) -> Result<DevicePathBuf> {
+    let name = mapper_name(uuid);
+    run_command_as_result({
+        let mut command = cryptsetup_command();
+        command
+            .args(&["open", "--type", "luks", "--key-file"])
+            .arg(keyfile)
+            .arg(device)
+            .arg(&name);
+        command
+    })
+    .with_context(|| format!("failed to open luks volume {}", device))?;
+
+    DevicePathBuf::try_from(&format!("/dev/mapper/{}", name))
+}
+
+/// Closes the LUKS volume opened under its conventional mapper name.
+pub fn luks_close(uuid: &Uuid) -> Result<()> {
+    run_command_as_result({
+        let mut command = cryptsetup_command();
+        command.arg("close").arg(mapper_name(uuid));
+        command
+    })
+    .with_context(|| format!("failed to close luks volume {}", mapper_name(uuid)))
+    .map(|_| ())
+}
+
+pub fn mapper_name(uuid: &Uuid) -> String {
+    format!("luks-{}", uuid.to_hyphenated())
+}
+
+/// The LUKS UUID of a device opened under its conventional mapper name.
+pub fn mapper_uuid(device: &DevicePathBuf) -> Option<Uuid> {
+    device
+        .as_pathbuf()
+        .file_name()?
+        .to_str()?
+        .strip_prefix("luks-")?
+        .parse()
+        .ok()
+}
+
+pub fn add_to_crypttab(uuid: &Uuid, keyfile: &Path) -> Result<()> {
+    let line = crypttab_line(uuid, keyfile);
+    let mut file = OpenOptions::new().append(true).create(true).open("/etc/crypttab")?;
+    writeln!(file, "{}", line).context("writing to crypttab failed")
+}
+
+pub fn remove_from_crypttab(uuid: &Uuid) -> Result<()> {
+    const CRYPTTAB: &str = "/etc/crypttab";
+    let name = mapper_name(uuid);
+    let contents = fs::read_to_string(CRYPTTAB).context("reading crypttab failed")?;
+    let retained = contents
+        .lines()
+        .filter(|l| l.split_whitespace().next() != Some(name.as_str()))
+        .map(|l| format!("{}\n", l))
+        .collect::<String>();
+    fs::write(CRYPTTAB, retained).context("writing to crypttab failed")
+}
+
+pub fn crypttab_line(uuid: &Uuid, keyfile: &Path) -> String {
+    format!(
+        "{}\tUUID={}\t{}\tluks",
+        mapper_name(uuid),
+        uuid.to_hyphenated(),
+        keyfile.to_string_lossy()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::prelude::*;
+
+    #[test]
+    #[serial(fakecmd)]
+    fn luks_uuid_parse() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect()
+            .returning(|_| Ok(String::from("6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49\n")));
+
+        let device = DevicePathBuf::try_from("/dev/sdb").unwrap();
+        assert_eq!(
+            luks_uuid(&device).unwrap(),
+            Uuid::parse_str("6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49").unwrap()
+        );
+    }
+
+    #[test]
+    fn mapper_uuid_parse() {
+        let device = DevicePathBuf::try_from("/dev/mapper/luks-6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49").unwrap();
+        assert_eq!(
+            mapper_uuid(&device),
+            Some(Uuid::parse_str("6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49").unwrap())
+        );
+        assert_eq!(mapper_uuid(&DevicePathBuf::try_from("/dev/sdb").unwrap()), None);
+    }
+
+    #[test]
+    fn crypttab_line_format() {
+        let uuid = Uuid::parse_str("6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49").unwrap();
+        assert_eq!(
+            crypttab_line(&uuid, Path::new("/var/lib/blockcaptain/luks/default.key")),
+            "luks-6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49\tUUID=6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49\t\
+            /var/lib/blockcaptain/luks/default.key\tluks"
+        );
+    }
+}
@@ -4,12 +4,22 @@ use crate::sys::process::double as process_double;
 use crate::sys::process::output_stdout_to_result;
 use anyhow::{anyhow, Context, Error, Result};
 use mnt::{MountEntry, MountIter};
-use nix::mount::{mount, MsFlags};
+use nix::{
+    fcntl::{flock, FlockArg},
+    mount::{mount, MsFlags},
+};
 use process_double::{run_command, run_command_as_result};
 use serde::{Deserialize, Serialize};
 use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
-use std::{collections::HashMap, process::Command};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::raw::c_int,
+    os::unix::io::AsRawFd,
+    process::{self, Command},
+};
 use std::{convert::TryFrom, fmt::Display};
 use std::{ffi::OsStr, process::Stdio};
 use uuid::Uuid;
@@ -41,6 +51,14 @@ impl FsPathBuf {
         Self(self.0.join(path))
     }
 
+    pub fn parent(&self) -> Option<Self> {
+        self.0.parent().map(|p| Self(p.to_path_buf()))
+    }
+
+    pub fn strip_prefix(&self, base: &FsPathBuf) -> Option<&Path> {
+        self.0.strip_prefix(&base.0).ok()
+    }
+
     pub fn push<P: AsRef<Path>>(&mut self, path: P) {
         self.0.push(path);
     }
@@ -151,6 +169,153 @@ pub fn bind_mount(from: &Path, to: &Path) -> Result<()> {
 pub fn unmount(path: &Path) -> Result<()> {
     nix::mount::umount(path).context("unmount syscall failed")
 }
+
+pub fn power_off_device(device: &DevicePathBuf) -> Result<()> {
+    const PROCESS_NAME: &str = "udisksctl";
+    run_command_as_result({
+        let mut command = Command::new(PROCESS_NAME);
+        command.args(&["power-off", "--block-device"]).arg(device.as_pathbuf());
+        command
+    })
+    .map(|_| ())
+    .with_context(|| format!("failed to power off {} with {}", device, PROCESS_NAME))
+}
+
+pub fn block_device_size(device: &DevicePathBuf) -> Result<u64> {
+    const PROCESS_NAME: &str = "blockdev";
+    run_command_as_result({
+        let mut command = Command::new(PROCESS_NAME);
+        command.arg("--getsize64").arg(device.as_pathbuf());
+        command
+    })
+    .with_context(|| format!("failed to get the size of {} with {}", device, PROCESS_NAME))
+    .and_then(|output| output.trim().parse().context("failed to parse device size"))
+}
+
+/// Attaches a file to the first free loop device.
+pub fn loop_attach(file: &Path) -> Result<DevicePathBuf> {
+    const PROCESS_NAME: &str = "losetup";
+    run_command_as_result({
+        let mut command = Command::new(PROCESS_NAME);
+        command.args(&["--find", "--show"]).arg(file);
+        command
+    })
+    .with_context(|| {
+        format!(
+            "failed to attach {} to a loop device with {}",
+            file.display(),
+            PROCESS_NAME
+        )
+    })
+    .and_then(|output| DevicePathBuf::try_from(output.trim()))
+}
+
+pub fn loop_detach(device: &DevicePathBuf) -> Result<()> {
+    const PROCESS_NAME: &str = "losetup";
+    run_command_as_result({
+        let mut command = Command::new(PROCESS_NAME);
+        command.arg("--detach").arg(device.as_pathbuf());
+        command
+    })
+    .map(|_| ())
+    .with_context(|| format!("failed to detach {} with {}", device, PROCESS_NAME))
+}
+
+/// An exclusive lock on a pid file, held until dropped.
+#[derive(Debug)]
+pub struct PidLock {
+    _file: File,
+}
+
+impl PidLock {
+    /// Locks the file and records the current pid in it. Fails naming the holder's pid if another process holds it.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("failed to create the lock directory")?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("failed to open lock file {}", path.display()))?;
+
+        if let Err(error) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            return match holder.trim() {
+                "" => Err(error).with_context(|| format!("failed to lock {}", path.display())),
+                pid => Err(anyhow!("another instance is already running with PID {}", pid)),
+            };
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Files currently in use as swap, as listed in /proc/swaps. Swap partitions are left out.
+pub fn active_swapfiles() -> Result<Vec<PathBuf>> {
+    fs::read_to_string("/proc/swaps")
+        .map(|swaps| parse_swaps(&swaps))
+        .context("failed to read /proc/swaps")
+}
+
+fn parse_swaps(swaps: &str) -> Vec<PathBuf> {
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(path), Some("file")) => Some(PathBuf::from(unescape_octal(path))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// The kernel escapes whitespace and backslashes in paths as \ooo.
+fn unescape_octal(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(index) = rest.find('\\') {
+        result.push_str(&rest[..index]);
+        let escaped = rest.get(index + 1..index + 4);
+        match escaped.and_then(|e| u8::from_str_radix(e, 8).ok()) {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+const FS_NOCOW_FL: c_int = 0x0080_0000;
+nix::ioctl_read_bad!(
+    fs_ioc_getflags,
+    nix::request_code_read!(b'f', 1, std::mem::size_of::<std::os::raw::c_long>()),
+    c_int
+);
+
+/// Whether the file or directory has the no copy-on-write attribute, `chattr +C`. New files in such a directory
+/// inherit it.
+pub fn has_nocow_attribute(path: &Path) -> Result<bool> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut flags: c_int = 0;
+    unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) }
+        .with_context(|| format!("failed to get the attributes of {}", path.display()))?;
+    Ok(flags & FS_NOCOW_FL != 0)
+}
+
 #[derive(Debug)]
 pub struct BtrfsMountEntry(MountEntry);
 
@@ -176,6 +341,18 @@ impl BtrfsMountEntry {
             || subvol_path.unwrap_or_default() == "/"
     }
 
+    /// Filesystem specific mount options, generic flags such as noatime are not included.
+    pub fn extra_options(&self) -> Vec<&str> {
+        self.0
+            .mntops
+            .iter()
+            .filter_map(|x| match x {
+                mnt::MntOps::Extra(extra) => Some(extra.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn keyed_option<T>(&self, key: &str) -> Option<T>
     where
         T: FromStr,
@@ -298,6 +475,36 @@ mod tests {
     use indoc::indoc;
     use serial_test::serial;
 
+    #[test]
+    fn pid_lock_exclusive() {
+        let path = std::env::temp_dir().join(format!("blkcapt-pidlock-{}", Uuid::new_v4()));
+        let lock = PidLock::acquire(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), process::id().to_string());
+
+        let error = PidLock::acquire(&path).unwrap_err().to_string();
+        assert!(error.contains(&format!("PID {}", process::id())));
+
+        drop(lock);
+        assert!(PidLock::acquire(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn swaps_parse() {
+        let swaps = indoc!(
+            r#"
+            Filename				Type		Size		Used		Priority
+            /dev/sda2                               partition	8388604		0		-2
+            /srv/data/swap\040file                  file		2097148		0		-3
+            /swapfile                               file		1048572		0		-4
+            "#
+        );
+        assert_eq!(
+            parse_swaps(swaps),
+            vec![PathBuf::from("/srv/data/swap file"), PathBuf::from("/swapfile")]
+        );
+    }
+
     #[test]
     fn fail_if_not_btrfs() {
         let non_btrfs_mount: MountEntry = "/dev/vda / ext4 rw 0 0".parse().unwrap();
@@ -353,6 +560,64 @@ mod tests {
         BtrfsMountEntry::try_from(mount).unwrap()
     }
 
+    #[test]
+    #[serial(fakecmd)]
+    fn loop_attach_finds_free_device() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect()
+            .withf(|command| format!("{:?}", command).contains(r#""losetup" "--find" "--show" "/tmp/pool.img""#))
+            .returning(|_| Ok(String::from("/dev/loop3\n")));
+
+        assert_eq!(
+            loop_attach(Path::new("/tmp/pool.img")).unwrap(),
+            DevicePathBuf::try_from("/dev/loop3").unwrap()
+        );
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn loop_attach_failure() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Err(anyhow!("no free loop device")));
+
+        let error = loop_attach(Path::new("/tmp/pool.img")).unwrap_err();
+        assert!(format!("{:#}", error).contains("failed to attach /tmp/pool.img to a loop device"));
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn loop_detach_device() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect()
+            .withf(|command| format!("{:?}", command).contains(r#""losetup" "--detach" "/dev/loop3""#))
+            .returning(|_| Ok(String::new()));
+        assert!(loop_detach(&DevicePathBuf::try_from("/dev/loop3").unwrap()).is_ok());
+
+        ctx.checkpoint();
+        ctx.expect().returning(|_| Err(anyhow!("device busy")));
+        let error = loop_detach(&DevicePathBuf::try_from("/dev/loop3").unwrap()).unwrap_err();
+        assert!(error.to_string().contains("failed to detach /dev/loop3"));
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn power_off_device_with_udisks() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect()
+            .withf(|command| {
+                format!("{:?}", command).contains(r#""udisksctl" "power-off" "--block-device" "/dev/sdb""#)
+            })
+            .returning(|_| Ok(String::new()));
+        assert!(power_off_device(&DevicePathBuf::try_from("/dev/sdb").unwrap()).is_ok());
+
+        ctx.checkpoint();
+        ctx.expect().returning(|_| Err(anyhow!("device busy")));
+        let error = power_off_device(&DevicePathBuf::try_from("/dev/sdb").unwrap()).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("failed to power off /dev/sdb with udisksctl"));
+    }
+
     #[test]
     #[serial(fakecmd)]
     fn nonblock_device_info_fails() {
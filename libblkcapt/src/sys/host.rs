@@ -0,0 +1,39 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// The systemd machine id identifying this installation.
+pub fn machine_id() -> Result<String> {
+    let contents = fs::read_to_string(MACHINE_ID_PATH).context("failed to read the machine id")?;
+    parse_machine_id(&contents)
+}
+
+fn parse_machine_id(contents: &str) -> Result<String> {
+    let id = contents.trim();
+    if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("machine id '{}' is not 32 hexadecimal characters", id);
+    }
+    Ok(id.to_ascii_lowercase())
+}
+
+pub fn hostname() -> Result<String> {
+    let mut buffer = [0u8; 256];
+    let name = nix::unistd::gethostname(&mut buffer).context("failed to get the hostname")?;
+    Ok(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_id_parse() {
+        assert_eq!(
+            parse_machine_id("6A4C6CF3C3A54F489BBB0D4F8E3C8A49\n").unwrap(),
+            "6a4c6cf3c3a54f489bbb0d4f8e3c8a49"
+        );
+        assert!(parse_machine_id("").is_err());
+        assert!(parse_machine_id("6a4c6cf3-c3a5-4f48-9bbb-0d4f8e3c8a49").is_err());
+    }
+}
@@ -1,4 +1,8 @@
 pub mod btrfs;
+#[cfg(feature = "btrfs-ioctl")]
+mod btrfs_ioctl;
+pub mod crypt;
 pub mod fs;
+pub mod host;
 pub mod net;
 pub mod process;
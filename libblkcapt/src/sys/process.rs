@@ -1,10 +1,22 @@
-use anyhow::{anyhow, Context as _, Result};
-use std::process::{Command, ExitStatus, Output, Stdio};
+use crate::error::{coded, ErrorCode, ErrorCodeExt};
+use anyhow::{anyhow, bail, Context as _, Result};
+use nix::{
+    libc,
+    unistd::{chown, Gid, Uid, User},
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output, Stdio},
+};
 
 pub fn exit_status_as_result(status: ExitStatus) -> Result<()> {
     match status {
         s if s.success() => Ok(()),
-        s => Err(exit_code_error(s)),
+        s => Err(coded(ErrorCode::JobFailed, exit_code_error(s))),
     }
 }
 
@@ -15,7 +27,9 @@ pub fn output_as_result(output: Output) -> Result<Output> {
             true => anyhow!("unknown error in command. command produced no stderr output"),
             false => anyhow!("{}", stderr_string),
         });
-        return output_error.context(exit_code_error(output.status));
+        return output_error
+            .context(exit_code_error(output.status))
+            .code(ErrorCode::JobFailed);
     }
     Ok(output)
 }
@@ -38,6 +52,259 @@ fn convert_result(result: std::io::Result<Output>) -> Result<Output> {
     result.context("waiting for subprocess result failed")
 }
 
+/// A system user that child processes are started as instead of root.
+#[derive(Debug, Clone)]
+pub struct UnprivilegedUser {
+    uid: Uid,
+    gid: Gid,
+    home: PathBuf,
+}
+
+impl UnprivilegedUser {
+    pub fn lookup(name: &str) -> Result<Self> {
+        let user = User::from_name(name)
+            .with_context(|| format!("failed to look up user {}", name))?
+            .ok_or_else(|| anyhow!("user {} does not exist", name))?;
+        if user.uid.is_root() {
+            bail!("user {} is not unprivileged", name);
+        }
+        Ok(Self {
+            uid: user.uid,
+            gid: user.gid,
+            home: user.dir,
+        })
+    }
+
+    /// Starts the process as the user, without any capabilities.
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        command
+            .uid(self.uid.as_raw())
+            .gid(self.gid.as_raw())
+            .env("HOME", &self.home);
+    }
+
+    /// Starts the process as the user with the ambient `CAP_DAC_READ_SEARCH` capability, so it can read any file of a
+    /// snapshot, but not write them. The capability also covers the rest of the host, so only give it to processes
+    /// that read snapshots. Only the started process gets the capability, not the binary. Call after
+    /// [`JobKind::apply`], the priority can only be raised while still root.
+    pub fn apply_with_read_access(&self, command: &mut tokio::process::Command) {
+        let hook = capabilities::read_access_hook(self.uid.as_raw(), self.gid.as_raw());
+        // Safety: the hook only makes the async-signal-safe prctl, setgroups, setgid, setuid and capset calls, and
+        // doesn't allocate.
+        unsafe {
+            command.pre_exec(hook);
+        }
+        command.env("HOME", &self.home);
+    }
+
+    /// Hands a directory created as root to the user, so processes run as it can write there.
+    pub fn chown(&self, path: &Path) -> Result<()> {
+        chown(path, Some(self.uid), Some(self.gid)).with_context(|| format!("failed to chown {}", path.display()))
+    }
+}
+
+mod capabilities {
+    use nix::libc;
+    use std::{io, ptr};
+
+    const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    const CAP_DAC_READ_SEARCH: u32 = 2;
+
+    #[repr(C)]
+    struct CapUserHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub(super) struct CapUserData {
+        pub(super) effective: u32,
+        pub(super) permitted: u32,
+        pub(super) inheritable: u32,
+    }
+
+    // The two 32 bit words of the version 3 capability sets, with only the given capability in them.
+    pub(super) fn capability_sets(capability: u32) -> [CapUserData; 2] {
+        let mut sets = [CapUserData::default(); 2];
+        let mask = 1 << (capability % 32);
+        sets[(capability / 32) as usize] = CapUserData {
+            effective: mask,
+            permitted: mask,
+            inheritable: mask,
+        };
+        sets
+    }
+
+    fn check(result: libc::c_long) -> io::Result<()> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    // Switches to the user keeping the permitted capabilities, narrows them to CAP_DAC_READ_SEARCH and raises it
+    // in the ambient set, which is the only set that survives the exec of a binary without file capabilities.
+    pub(super) fn read_access_hook(
+        uid: libc::uid_t, gid: libc::gid_t,
+    ) -> impl FnMut() -> io::Result<()> + Send + Sync + 'static {
+        let sets = capability_sets(CAP_DAC_READ_SEARCH);
+        move || unsafe {
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0).into())?;
+            check(libc::setgroups(0, ptr::null()).into())?;
+            check(libc::setgid(gid).into())?;
+            check(libc::setuid(uid).into())?;
+            let header = CapUserHeader {
+                version: CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            check(libc::syscall(
+                libc::SYS_capset,
+                &header as *const CapUserHeader,
+                sets.as_ptr(),
+            ))?;
+            check(
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_RAISE,
+                    libc::c_ulong::from(CAP_DAC_READ_SEARCH),
+                    0,
+                    0,
+                )
+                .into(),
+            )
+        }
+    }
+}
+
+/// The background jobs whose processes can be given their own scheduling priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Send,
+    Receive,
+    Scrub,
+    Balance,
+    Dedup,
+    Defrag,
+    Restic,
+}
+
+/// Scheduling priorities for the processes of each kind of job, by default they inherit the worker's.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct JobPriorities {
+    pub send: ProcessPriority,
+    pub receive: ProcessPriority,
+    pub scrub: ProcessPriority,
+    pub balance: ProcessPriority,
+    pub dedup: ProcessPriority,
+    pub defrag: ProcessPriority,
+    pub restic: ProcessPriority,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessPriority {
+    /// CPU niceness from -20 to 19, as set by `nice`.
+    #[serde(default)]
+    pub nice: Option<i8>,
+    /// IO scheduling class, as set by `ionice -c`.
+    #[serde(default)]
+    pub io_class: Option<IoClass>,
+    /// Priority within the best effort or realtime IO class from 0 (highest) to 7, as set by `ionice -n`.
+    #[serde(default)]
+    pub io_level: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    Realtime = 1,
+    BestEffort = 2,
+    Idle = 3,
+}
+
+static JOB_PRIORITIES: OnceCell<JobPriorities> = OnceCell::new();
+
+/// Sets the priorities of job processes started from now on. Only the first call has an effect.
+pub fn set_job_priorities(priorities: JobPriorities) {
+    let _ = JOB_PRIORITIES.set(priorities);
+}
+
+impl JobKind {
+    pub fn priority(self) -> ProcessPriority {
+        JOB_PRIORITIES
+            .get()
+            .map_or_else(ProcessPriority::default, |p| match self {
+                JobKind::Send => p.send,
+                JobKind::Receive => p.receive,
+                JobKind::Scrub => p.scrub,
+                JobKind::Balance => p.balance,
+                JobKind::Dedup => p.dedup,
+                JobKind::Defrag => p.defrag,
+                JobKind::Restic => p.restic,
+            })
+    }
+
+    /// Sets the priority of the process. Hooks run in the order they are added after any `uid` and `gid` of the
+    /// command are applied, raising the priority fails unless it's applied while the process still runs as root.
+    /// Processes started as an [`UnprivilegedUser`] drop root in their own hook, added after this one.
+    pub fn apply(self, command: &mut tokio::process::Command) {
+        if let Some(hook) = self.priority().pre_exec_hook() {
+            // Safety: the hook only makes the async-signal-safe setpriority and ioprio_set calls, and doesn't
+            // allocate.
+            unsafe {
+                command.pre_exec(hook);
+            }
+        }
+    }
+
+    pub fn apply_std(self, command: &mut Command) {
+        if let Some(hook) = self.priority().pre_exec_hook() {
+            // Safety: as in `apply`.
+            unsafe {
+                command.pre_exec(hook);
+            }
+        }
+    }
+}
+
+impl ProcessPriority {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+    // The ioprio_set value, best effort when only a level is given and the kernel's default level 4 for a bare class.
+    fn ioprio(&self) -> Option<libc::c_long> {
+        let class = self.io_class.or_else(|| self.io_level.map(|_| IoClass::BestEffort))?;
+        let level = match class {
+            IoClass::Idle => 0,
+            _ => self.io_level.unwrap_or(4).min(7),
+        };
+        Some(((class as libc::c_long) << Self::IOPRIO_CLASS_SHIFT) | libc::c_long::from(level))
+    }
+
+    fn pre_exec_hook(self) -> Option<impl FnMut() -> io::Result<()> + Send + Sync + 'static> {
+        if self == Self::default() {
+            return None;
+        }
+
+        let nice = self.nice.map(|n| libc::c_int::from(n.max(-20).min(19)));
+        let ioprio = self.ioprio();
+        Some(move || {
+            if let Some(nice) = nice {
+                if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(ioprio) = ioprio {
+                if unsafe { libc::syscall(libc::SYS_ioprio_set, Self::IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
 fn exit_code_error(status: ExitStatus) -> anyhow::Error {
     match status.code() {
         Some(c) => anyhow!("process exited with exit code: {}", c),
@@ -59,3 +326,40 @@ pub mod double {
         output_stdout_to_result(command.output())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_priority_ioprio() {
+        let priority = |io_class, io_level| ProcessPriority {
+            nice: None,
+            io_class,
+            io_level,
+        };
+        assert_eq!(priority(None, None).ioprio(), None);
+        assert_eq!(priority(None, Some(7)).ioprio(), Some(2 << 13 | 7));
+        assert_eq!(priority(Some(IoClass::BestEffort), None).ioprio(), Some(2 << 13 | 4));
+        assert_eq!(priority(Some(IoClass::Idle), Some(7)).ioprio(), Some(3 << 13));
+    }
+
+    #[test]
+    fn read_access_capability_sets() {
+        let sets = capabilities::capability_sets(2);
+        assert_eq!(
+            (sets[0].effective, sets[0].permitted, sets[0].inheritable),
+            (0b100, 0b100, 0b100)
+        );
+        assert_eq!(sets[1], Default::default());
+        let sets = capabilities::capability_sets(37);
+        assert_eq!(sets[0], Default::default());
+        assert_eq!(sets[1].permitted, 1 << 5);
+    }
+
+    #[test]
+    fn unprivileged_user_lookup_rejects_root() {
+        assert!(UnprivilegedUser::lookup("root").is_err());
+        assert!(UnprivilegedUser::lookup("no-such-blkcapt-user").is_err());
+    }
+}